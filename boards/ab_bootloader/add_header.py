@@ -0,0 +1,31 @@
+#!/usr/bin/env python3
+"""Prepends the header page of an A/B bootloader slot to a kernel binary.
+
+The bootloader only boots a slot with a valid header, so the first kernel
+flashed to a board must carry one. The header marks the kernel confirmed,
+and the output is flashed to the start of the slot. The format of the
+header is described in libraries/tock-boot.
+"""
+
+import argparse
+import struct
+import zlib
+
+STATE_CONFIRMED = 0x0000ffff
+
+parser = argparse.ArgumentParser(description=__doc__)
+parser.add_argument('kernel', help='kernel binary, linked to run from the slot')
+parser.add_argument('output', help='where to write the header page and kernel')
+parser.add_argument('--page-size', type=int, default=512)
+parser.add_argument('--version', type=int, default=1)
+args = parser.parse_args()
+
+with open(args.kernel, 'rb') as f:
+    image = f.read()
+
+header = b'TKRN' + struct.pack('<IIII', args.version, len(image),
+                               zlib.crc32(image), 0)
+header += struct.pack('<II', zlib.crc32(header), STATE_CONFIRMED)
+
+with open(args.output, 'wb') as f:
+    f.write(header + b'\xff' * (args.page_size - len(header)) + image)
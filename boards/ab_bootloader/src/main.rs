@@ -0,0 +1,97 @@
+//! A/B bootloader for the Tock kernel.
+//!
+//! Checks the two kernel slots, boots the newest good image and records the
+//! choice in the handoff for the kernel. If neither slot holds a good image,
+//! it boots nothing and stays in a loop. Images are only checked against
+//! their CRC; boards that sign kernels pass a `tock_boot::Verifier` to
+//! `check_slot`.
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use core::panic::PanicInfo;
+use tock_boot::{Handoff, Header};
+
+extern "C" {
+    // _estack is not really a function, but it makes the types work
+    // You should never actually invoke it!!
+    fn _estack();
+
+    // Defined by layout.ld. Only their addresses are meaningful.
+    static _slot_a: u8;
+    static _slot_b: u8;
+    static _slot_size: u8;
+    static _page_size: u8;
+}
+
+/// Handoff to the kernel, at the start of RAM.
+#[no_mangle]
+#[link_section = ".boot_handoff"]
+pub static mut HANDOFF: Handoff = Handoff::new();
+
+#[link_section = ".vectors"]
+// used Ensures that the symbol is kept until the final binary
+#[used]
+pub static VECTORS: [unsafe extern "C" fn(); 4] = [
+    _estack,
+    reset_handler,
+    fault_handler, // NMI
+    fault_handler, // Hard Fault
+];
+
+unsafe extern "C" fn fault_handler() {
+    loop {}
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn reset_handler() {
+    let slots = [
+        &_slot_a as *const u8 as usize,
+        &_slot_b as *const u8 as usize,
+    ];
+    let page_size = &_page_size as *const u8 as usize;
+    let headers = [check(slots[0]), check(slots[1])];
+    match tock_boot::select(&headers, &mut HANDOFF) {
+        Some(slot) => jump(slots[slot] + page_size),
+        // No slot holds a kernel that passed its checks. Rather than run
+        // whatever is in flash, wait for a good kernel to be flashed.
+        None => loop {},
+    }
+}
+
+unsafe fn check(address: usize) -> Option<Header> {
+    let size = &_slot_size as *const u8 as usize;
+    let slot = core::slice::from_raw_parts(address as *const u8, size);
+    tock_boot::check_slot(slot, address, &_page_size as *const u8 as usize, None)
+}
+
+/// Boots the image whose vector table is at `address`.
+#[cfg(target_os = "none")]
+unsafe fn jump(address: usize) -> ! {
+    const SCB_VTOR: *mut u32 = 0xe000_ed08 as *mut u32;
+
+    let vectors = address as *const u32;
+    SCB_VTOR.write_volatile(address as u32);
+    let stack_pointer = vectors.read_volatile();
+    let reset_handler = vectors.offset(1).read_volatile();
+    asm!(
+    "msr msp, $0
+     bx $1"
+    :
+    : "r"(stack_pointer), "r"(reset_handler)
+    :
+    : "volatile"
+    );
+    loop {}
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe fn jump(_address: usize) -> ! {
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
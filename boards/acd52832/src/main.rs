@@ -437,6 +437,10 @@ pub unsafe fn reset_handler() {
         &nrf52::radio::RADIO,
         ble_radio,
     );
+    kernel::hil::ble_advertising::BleAdvertisementResponder::set_response_client(
+        &nrf52::radio::RADIO,
+        ble_radio,
+    );
     ble_radio_virtual_alarm.set_client(ble_radio);
 
     //
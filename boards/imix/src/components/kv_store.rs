@@ -0,0 +1,81 @@
+//! Component for the key-value store on the imix board.
+//!
+//! This provides one component, KVStoreComponent, which keeps the store in
+//! the last two pages of the on-chip flash. The kernel uses it through
+//! `hil::kv_store`, and apps through a system call interface.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let kv_store = KVStoreComponent::new(board_kernel, mux_flash, dynamic_deferred_call).finalize();
+//! ```
+
+#![allow(dead_code)] // Components are intended to be conditionally included
+
+use capsules::kv_store::KVStore;
+use capsules::virtual_flash::{FlashUser, MuxFlash};
+use kernel::capabilities;
+use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::static_init;
+
+/// First flash page of the store. The SAM4L has 1024 pages of 512 bytes.
+const KV_STORE_PAGE: usize = 1022;
+
+pub struct KVStoreComponent {
+    board_kernel: &'static kernel::Kernel,
+    mux_flash: &'static MuxFlash<'static, sam4l::flashcalw::FLASHCALW>,
+    deferred_caller: &'static DynamicDeferredCall,
+}
+
+impl KVStoreComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        mux_flash: &'static MuxFlash<'static, sam4l::flashcalw::FLASHCALW>,
+        deferred_caller: &'static DynamicDeferredCall,
+    ) -> Self {
+        KVStoreComponent {
+            board_kernel: board_kernel,
+            mux_flash: mux_flash,
+            deferred_caller: deferred_caller,
+        }
+    }
+}
+
+impl Component for KVStoreComponent {
+    type Output = &'static KVStore<'static, FlashUser<'static, sam4l::flashcalw::FLASHCALW>>;
+
+    unsafe fn finalize(&mut self) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        pub static mut KV_PAGE: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+        pub static mut KV_SPARE_PAGE: sam4l::flashcalw::Sam4lPage =
+            sam4l::flashcalw::Sam4lPage::new();
+
+        let kv_flash = static_init!(
+            FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+            FlashUser::new(self.mux_flash)
+        );
+        let kv_store = static_init!(
+            KVStore<'static, FlashUser<'static, sam4l::flashcalw::FLASHCALW>>,
+            KVStore::new(
+                kv_flash,
+                KV_STORE_PAGE,
+                &mut KV_PAGE,
+                &mut KV_SPARE_PAGE,
+                &mut capsules::kv_store::BUFFER,
+                self.board_kernel.create_grant(&grant_cap),
+                self.deferred_caller
+            )
+        );
+        hil::flash::HasClient::set_client(kv_flash, kv_store);
+        kv_store.set_deferred_call_handle(
+            self.deferred_caller
+                .register(kv_store)
+                .expect("no deferred call slot available for the key-value store"),
+        );
+        kv_store
+    }
+}
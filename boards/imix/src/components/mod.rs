@@ -7,6 +7,7 @@ pub mod crc;
 pub mod fxos8700;
 pub mod gpio;
 pub mod isl29035;
+pub mod kv_store;
 pub mod led;
 pub mod nonvolatile_storage;
 pub mod nrf51822;
@@ -15,6 +16,7 @@ pub mod radio;
 pub mod rf233;
 pub mod rng;
 pub mod si7021;
+pub mod sniffer;
 pub mod spi;
 pub mod udp_6lowpan;
 pub mod usb;
@@ -28,6 +30,7 @@ pub use self::crc::CrcComponent;
 pub use self::fxos8700::NineDofComponent;
 pub use self::gpio::GpioComponent;
 pub use self::isl29035::Isl29035Component;
+pub use self::kv_store::KVStoreComponent;
 pub use self::led::LedComponent;
 pub use self::nonvolatile_storage::NonvolatileStorageComponent;
 pub use self::nrf51822::Nrf51822Component;
@@ -36,6 +39,7 @@ pub use self::radio::RadioComponent;
 pub use self::rf233::RF233Component;
 pub use self::rng::RngComponent;
 pub use self::si7021::{HumidityComponent, SI7021Component, TemperatureComponent};
+pub use self::sniffer::SnifferComponent;
 pub use self::spi::SpiComponent;
 pub use self::spi::SpiSyscallComponent;
 pub use self::udp_6lowpan::UDPComponent;
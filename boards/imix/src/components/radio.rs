@@ -2,12 +2,13 @@
 //!
 //! This provides one Component, RadioComponent, which implements a
 //! userspace syscall interface to a full 802.15.4 stack with a
-//! always-on MAC implementation.
+//! always-on MAC implementation, including channel scanning.
 //!
 //! Usage
 //! -----
 //! ```rust
-//! let (radio_driver, mux_mac) = RadioComponent::new(rf233, PAN_ID, 0x1008).finalize();
+//! let (radio_driver, mux_mac) =
+//!     RadioComponent::new(board_kernel, rf233, mux_alarm, PAN_ID, 0x1008).finalize();
 //! ```
 
 // Author: Philip Levis <pal@cs.stanford.edu>
@@ -17,6 +18,8 @@
 
 use capsules::ieee802154::device::MacDevice;
 use capsules::ieee802154::mac::{AwakeMac, Mac};
+use capsules::ieee802154::scan::{Scan, Scanner};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use capsules::virtual_spi::VirtualSpiMasterDevice;
 
 use kernel::capabilities;
@@ -24,6 +27,7 @@ use kernel::component::Component;
 use kernel::create_capability;
 use kernel::hil::radio;
 use kernel::hil::radio::RadioData;
+use kernel::hil::radio::RadioEnergyDetect;
 use kernel::hil::symmetric_encryption;
 use kernel::hil::symmetric_encryption::{AES128, AES128CCM};
 use kernel::static_init;
@@ -35,6 +39,7 @@ type RF233Device =
 pub struct RadioComponent {
     board_kernel: &'static kernel::Kernel,
     rf233: &'static RF233Device,
+    alarm_mux: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
     pan_id: capsules::net::ieee802154::PanID,
     short_addr: u16,
 }
@@ -43,12 +48,14 @@ impl RadioComponent {
     pub fn new(
         board_kernel: &'static kernel::Kernel,
         rf233: &'static RF233Device,
+        alarm_mux: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
         pan_id: capsules::net::ieee802154::PanID,
         addr: u16,
     ) -> RadioComponent {
         RadioComponent {
             board_kernel: board_kernel,
             rf233: rf233,
+            alarm_mux: alarm_mux,
             pan_id: pan_id,
             short_addr: addr,
         }
@@ -59,6 +66,9 @@ impl RadioComponent {
 // for reception.
 static mut RADIO_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
 
+// The buffer used by the scanner to send beacon requests and beacons.
+static mut SCAN_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
+
 // The buffer RF233 packets are received into.
 static mut RF233_RX_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
 
@@ -126,11 +136,37 @@ impl Component for RadioComponent {
 
         mac_device.set_key_procedure(radio_driver);
         mac_device.set_device_procedure(radio_driver);
+        // The driver persists the outgoing frame counter once it has a
+        // key-value store, see `set_config_store`
+        mac_device.set_frame_counter_client(radio_driver);
         radio_mac.set_transmit_client(radio_driver);
         radio_mac.set_receive_client(radio_driver);
         radio_mac.set_pan(self.pan_id);
         radio_mac.set_address(self.short_addr);
 
+        // Channel scans and beacon responses share the MAC with the radio
+        // driver. Energy detection scans measure the energy with the RF233.
+        let scan_mac = static_init!(
+            capsules::ieee802154::virtual_mac::MacUser<'static>,
+            capsules::ieee802154::virtual_mac::MacUser::new(mux_mac)
+        );
+        mux_mac.add_user(scan_mac);
+        let scan_alarm = static_init!(
+            VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let scanner = static_init!(
+            Scanner<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+            Scanner::new(scan_mac, scan_alarm, &mut SCAN_BUF)
+        );
+        scan_mac.set_transmit_client(scanner);
+        scan_mac.set_receive_client(scanner);
+        scan_alarm.set_client(scanner);
+        scanner.set_client(radio_driver);
+        scanner.set_energy_detect(self.rf233);
+        self.rf233.set_energy_client(scanner);
+        radio_driver.set_scanner(scanner);
+
         (radio_driver, mux_mac)
     }
 }
@@ -0,0 +1,84 @@
+//! Component for the 802.15.4 packet sniffer on the imix board.
+//!
+//! This provides one Component, SnifferComponent, which captures the frames
+//! sent and received by the 802.15.4 stack and streams them in pcap format
+//! over a UART. The returned sniffer should be registered with the process
+//! console so that it can be controlled with the `sniff` command.
+//!
+//! The capture is binary, so the UART must not be shared with the console
+//! or debug output.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let sniffer = SnifferComponent::new(sniffer_uart_mux, mux_mac, mux_alarm).finalize();
+//! pconsole.set_command(sniffer);
+//! ```
+
+#![allow(dead_code)] // Components are intended to be conditionally included
+
+use capsules::ieee802154::sniffer::Sniffer;
+use capsules::ieee802154::virtual_mac::{MacUser, MuxMac};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules::virtual_uart::{MuxUart, UartDevice};
+use kernel::component::Component;
+use kernel::hil;
+use kernel::static_init;
+
+// Frames queued while the UART is busy, as pcap records of up to 145 bytes
+static mut QUEUE_BUF: [u8; 1024] = [0; 1024];
+static mut TX_BUF: [u8; 128] = [0; 128];
+
+pub struct SnifferComponent {
+    uart_mux: &'static MuxUart<'static>,
+    mux_mac: &'static MuxMac<'static>,
+    alarm_mux: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
+}
+
+impl SnifferComponent {
+    pub fn new(
+        uart_mux: &'static MuxUart<'static>,
+        mux_mac: &'static MuxMac<'static>,
+        alarm_mux: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
+    ) -> SnifferComponent {
+        SnifferComponent {
+            uart_mux: uart_mux,
+            mux_mac: mux_mac,
+            alarm_mux: alarm_mux,
+        }
+    }
+}
+
+impl Component for SnifferComponent {
+    type Output = &'static Sniffer<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>;
+
+    unsafe fn finalize(&mut self) -> Self::Output {
+        let sniffer_uart = static_init!(UartDevice, UartDevice::new(self.uart_mux, false));
+        sniffer_uart.setup();
+
+        // Only used for timestamps
+        let sniffer_alarm = static_init!(
+            VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+
+        // Used to change the channel
+        let sniffer_mac = static_init!(MacUser<'static>, MacUser::new(self.mux_mac));
+        self.mux_mac.add_user(sniffer_mac);
+
+        let sniffer = static_init!(
+            Sniffer<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>,
+            Sniffer::new(
+                sniffer_uart,
+                sniffer_alarm,
+                sniffer_mac,
+                &mut QUEUE_BUF,
+                &mut TX_BUF
+            )
+        );
+        hil::uart::Transmit::set_transmit_client(sniffer_uart, sniffer);
+        self.mux_mac.set_tap(sniffer);
+
+        sniffer
+    }
+}
@@ -26,6 +26,7 @@ use capsules::net::ipv6::ipv6_recv::IP6Receiver;
 use capsules::net::ipv6::ipv6_send::IP6Sender;
 use capsules::net::sixlowpan::{sixlowpan_compression, sixlowpan_state};
 use capsules::net::udp::udp::UDPHeader;
+use capsules::net::udp::udp_port_table::{UDPPortTable, UDPSocket};
 use capsules::net::udp::udp_recv::UDPReceiver;
 use capsules::net::udp::udp_send::{UDPSendStruct, UDPSender};
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
@@ -49,6 +50,10 @@ static mut RF233_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
 static mut SIXLOWPAN_RX_BUF: [u8; 1280] = [0x00; 1280];
 static mut UDP_DGRAM: [u8; PAYLOAD_LEN - UDP_HDR_SIZE] = [0; PAYLOAD_LEN - UDP_HDR_SIZE];
 
+// Sockets bound by kernel capsules and userland apps
+const NUM_UDP_SOCKETS: usize = 16;
+static mut UDP_SOCKETS: [Option<UDPSocket<'static>>; NUM_UDP_SOCKETS] = [None; NUM_UDP_SOCKETS];
+
 pub struct UDPComponent {
     board_kernel: &'static kernel::Kernel,
     mux_mac: &'static capsules::ieee802154::virtual_mac::MuxMac<'static>,
@@ -173,21 +178,22 @@ impl Component for UDPComponent {
         );
         sixlowpan_state.set_rx_client(ip_receive);
 
-        let udp_recv = static_init!(UDPReceiver<'static>, UDPReceiver::new());
+        let port_table = static_init!(UDPPortTable<'static>, UDPPortTable::new(&mut UDP_SOCKETS));
+        let udp_recv = static_init!(UDPReceiver<'static>, UDPReceiver::new(port_table));
         ip_receive.set_client(udp_recv);
 
         let udp_driver = static_init!(
             capsules::net::udp::UDPDriver<'static>,
             capsules::net::udp::UDPDriver::new(
                 udp_send,
-                udp_recv,
+                port_table,
                 self.board_kernel.create_grant(&grant_cap),
                 self.interface_list,
                 PAYLOAD_LEN
             )
         );
         udp_send.set_client(udp_driver);
-        udp_recv.set_client(udp_driver);
+        port_table.set_app_client(udp_driver);
         udp_driver
     }
 }
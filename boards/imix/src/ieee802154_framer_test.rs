@@ -0,0 +1,60 @@
+//! Test that the IEEE 802.15.4 framer secures and unsecures frames.
+//! To run this test, add this line to the imix boot sequence:
+//! ```
+//!    ieee802154_framer_test::run();
+//! ```
+//! This test takes the place of the MAC layer below the framer, so it should
+//! not be run together with the radio stack. If the test succeeds, the output
+//! is:
+//! ```
+//! IEEE 802.15.4 framer security tests
+//! ieee802154_framer_test passed: (current_test=0)
+//! ieee802154_framer_test passed: (current_test=1)
+//! ...
+//! ieee802154_framer_test passed: (current_test=9)
+//! ```
+
+use capsules::aes_ccm;
+use capsules::ieee802154::device::MacDevice;
+use capsules::ieee802154::framer::{Framer, CRYPT_BUF_SIZE};
+use capsules::ieee802154::mac::Mac;
+use capsules::test::ieee802154_framer::Test;
+use kernel::hil::radio;
+use kernel::hil::symmetric_encryption::{AES128, AES128CCM};
+use kernel::static_init;
+use sam4l::aes::{Aes, AES};
+
+type AESCCM = aes_ccm::AES128CCM<'static, Aes<'static>>;
+type TestFramer = Framer<'static, Test<'static>, AESCCM>;
+
+pub unsafe fn run() {
+    let ccm = static_init_ccm();
+    AES.set_client(ccm);
+
+    let t = static_init_test();
+    let framer = static_init!(TestFramer, Framer::new(t, ccm));
+    ccm.set_client(framer);
+
+    Mac::set_transmit_client(t, framer);
+    Mac::set_receive_client(t, framer);
+    Mac::set_config_client(t, framer);
+    framer.set_key_procedure(t);
+    framer.set_device_procedure(t);
+    framer.set_frame_counter_client(t);
+    MacDevice::set_transmit_client(framer, t);
+    MacDevice::set_receive_client(framer, t);
+    t.set_mac_device(framer);
+
+    t.run();
+}
+
+unsafe fn static_init_ccm() -> &'static mut AESCCM {
+    let crypt_buf = static_init!([u8; CRYPT_BUF_SIZE], [0x00; CRYPT_BUF_SIZE]);
+    static_init!(AESCCM, aes_ccm::AES128CCM::new(&AES, crypt_buf))
+}
+
+unsafe fn static_init_test() -> &'static mut Test<'static> {
+    let tx_buf = static_init!([u8; radio::MAX_BUF_SIZE], [0x00; radio::MAX_BUF_SIZE]);
+    let rx_buf = static_init!([u8; radio::MAX_BUF_SIZE], [0x00; radio::MAX_BUF_SIZE]);
+    static_init!(Test<'static>, Test::new(tx_buf, rx_buf))
+}
@@ -210,10 +210,8 @@ pub unsafe fn initialize_all(
         payload: &mut UDP_DGRAM,
     };
 
-    let mut ip6_dg: IP6Packet = IP6Packet {
-        header: ip6_hdr,
-        payload: ip_pyld,
-    };
+    let mut ip6_dg: IP6Packet = IP6Packet::new(ip_pyld);
+    ip6_dg.header = ip6_hdr;
 
     ip6_dg.set_transport_checksum(); //calculates and sets UDP cksum
 
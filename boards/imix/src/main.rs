@@ -13,10 +13,12 @@ use capsules::alarm::AlarmDriver;
 use capsules::net::ieee802154::MacAddress;
 use capsules::net::ipv6::ip_utils::IPAddr;
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules::virtual_flash::{FlashUser, MuxFlash, ProcessHeaders};
 use capsules::virtual_i2c::MuxI2C;
 use capsules::virtual_spi::{MuxSpiMaster, VirtualSpiMasterDevice};
 use capsules::virtual_uart::MuxUart;
 use kernel::capabilities;
+use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
 use kernel::component::Component;
 use kernel::hil;
 use kernel::hil::radio;
@@ -24,6 +26,7 @@ use kernel::hil::radio;
 use kernel::hil::radio::{RadioConfig, RadioData};
 use kernel::hil::spi::SpiMaster;
 use kernel::hil::Controller;
+use kernel::ReturnCode;
 #[allow(unused_imports)]
 use kernel::{create_capability, debug, debug_gpio, static_init};
 
@@ -36,6 +39,7 @@ use components::crc::CrcComponent;
 use components::fxos8700::NineDofComponent;
 use components::gpio::GpioComponent;
 use components::isl29035::AmbientLightComponent;
+use components::kv_store::KVStoreComponent;
 use components::led::LedComponent;
 use components::nonvolatile_storage::NonvolatileStorageComponent;
 use components::nrf51822::Nrf51822Component;
@@ -72,6 +76,9 @@ mod aes_test;
 #[allow(dead_code)]
 mod aes_ccm_test;
 
+#[allow(dead_code)]
+mod ieee802154_framer_test;
+
 #[allow(dead_code)]
 mod rng_test;
 
@@ -143,6 +150,10 @@ struct Imix {
     >,
     nrf51822: &'static capsules::nrf51822_serialization::Nrf51822Serialization<'static>,
     nonvolatile_storage: &'static capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
+    kv_store: &'static capsules::kv_store::KVStore<
+        'static,
+        FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+    >,
 }
 
 // The RF233 radio stack requires our buffers for its SPI operations:
@@ -158,6 +169,9 @@ static mut RF233_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
 static mut RF233_REG_WRITE: [u8; 2] = [0x00; 2];
 static mut RF233_REG_READ: [u8; 2] = [0x00; 2];
 
+// Buffer for the radio configuration saved in the key-value store.
+static mut RADIO_CONFIG_BUF: [u8; 8] = [0x00; 8];
+
 impl kernel::Platform for Imix {
     fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
     where
@@ -182,6 +196,7 @@ impl kernel::Platform for Imix {
             capsules::net::udp::DRIVER_NUM => f(Some(self.udp_driver)),
             capsules::nrf51822_serialization::DRIVER_NUM => f(Some(self.nrf51822)),
             capsules::nonvolatile_storage_driver::DRIVER_NUM => f(Some(self.nonvolatile_storage)),
+            capsules::kv_store::DRIVER_NUM => f(Some(self.kv_store)),
             capsules::rng::DRIVER_NUM => f(Some(self.rng)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             _ => f(None),
@@ -260,6 +275,26 @@ unsafe fn set_pin_primary_functions() {
     PC[31].configure(None); //... D2          -- GPIO Pin
 }
 
+/// Whether the user button, which is on `pin` and low while pressed, is held
+/// down. The pull-up is enabled first and given time to charge the pin, and
+/// the button only counts as held if it reads low in every one of a few
+/// milliseconds of samples, so that a floating pin or a bounce does not
+/// release the flash locks.
+fn user_button_held(pin: &sam4l::gpio::GPIOPin) -> bool {
+    let wait = || {
+        for _ in 0..1000 {
+            cortexm4::support::nop();
+        }
+    };
+    hil::gpio::PinCtl::set_input_mode(pin, hil::gpio::InputMode::PullUp);
+    hil::gpio::Pin::make_input(pin);
+    (0..10).for_each(|_| wait());
+    (0..100).all(|_| {
+        wait();
+        !hil::gpio::Pin::read(pin)
+    })
+}
+
 /// Reset Handler.
 ///
 /// This symbol is loaded into vector table by the SAM4L chip crate.
@@ -378,10 +413,58 @@ pub unsafe fn reset_handler() {
     // Can this initialize be pushed earlier, or into component? -pal
     rf233.initialize(&mut RF233_BUF, &mut RF233_REG_WRITE, &mut RF233_REG_READ);
     let (radio_driver, mux_mac) =
-        RadioComponent::new(board_kernel, rf233, PAN_ID, serial_num_bottom_16).finalize();
+        RadioComponent::new(board_kernel, rf233, mux_alarm, PAN_ID, serial_num_bottom_16)
+            .finalize();
+
+    let dynamic_deferred_call_clients =
+        static_init!([DynamicDeferredCallClientState; 1], Default::default());
+    let dynamic_deferred_call = static_init!(
+        DynamicDeferredCall,
+        DynamicDeferredCall::new(dynamic_deferred_call_clients)
+    );
+    DynamicDeferredCall::set_global_instance(dynamic_deferred_call);
+
+    // The on-chip flash holds both the nonvolatile storage of apps and the
+    // key-value store.
+    sam4l::flashcalw::FLASH_CONTROLLER.configure();
+    let mux_flash = static_init!(
+        MuxFlash<'static, sam4l::flashcalw::FLASHCALW>,
+        MuxFlash::new(&sam4l::flashcalw::FLASH_CONTROLLER)
+    );
+    hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, mux_flash);
+
+    // No user of the flash may change the bootloader, the kernel code, or the
+    // TBF headers of apps. The kernel code is also locked in hardware, while
+    // app headers are too small for the lock regions of the SAM4L. The lock
+    // bits of the SAM4L are kept in flash and stay set across resets, so the
+    // kernel could not be reflashed by the bootloader. Holding the user button
+    // during reset releases them instead.
+    extern "C" {
+        /// End of the kernel code, where its storage volumes begin.
+        static _sstorage: u8;
+    }
+    let app_headers = static_init!(ProcessHeaders<'static>, ProcessHeaders::new(&PROCESSES));
+    mux_flash.set_read_only(0, &_sstorage as *const u8 as usize / 512);
+    mux_flash.protect_app_headers(app_headers, 0, 512);
+    let result = if user_button_held(&sam4l::gpio::PC[24]) {
+        mux_flash.unlock()
+    } else {
+        mux_flash.lock()
+    };
+    if result != ReturnCode::SUCCESS {
+        debug!("Could not change the flash lock: {:?}", result);
+    }
 
     let usb_driver = UsbComponent::new(board_kernel).finalize();
-    let nonvolatile_storage = NonvolatileStorageComponent::new(board_kernel).finalize();
+    let nonvolatile_storage = NonvolatileStorageComponent::new(board_kernel, mux_flash).finalize();
+    let kv_store = KVStoreComponent::new(board_kernel, mux_flash, dynamic_deferred_call).finalize();
+
+    // Addresses set by apps are kept across reboots, replacing the defaults
+    // above once they are loaded. The outgoing 802.15.4 frame counter is kept
+    // as well, and is restored first so that secured frames never reuse it.
+    radio_driver.set_config_store(kv_store, &mut RADIO_CONFIG_BUF);
+    hil::kv_store::KVStore::set_client(kv_store, radio_driver);
+    radio_driver.load_config();
 
     let local_ip_ifaces = static_init!(
         [IPAddr; 3],
@@ -432,6 +515,7 @@ pub unsafe fn reset_handler() {
         usb_driver,
         nrf51822: nrf_serialization,
         nonvolatile_storage: nonvolatile_storage,
+        kv_store: kv_store,
     };
 
     let chip = static_init!(sam4l::chip::Sam4l, sam4l::chip::Sam4l::new());
@@ -447,6 +531,14 @@ pub unsafe fn reset_handler() {
 
     imix.pconsole.start();
 
+    // Optional 802.15.4 packet capture, controlled with the `sniff` console
+    // command. The capture should be streamed over a UART that carries
+    // nothing else, rather than the console UART.
+    //
+    // let sniffer =
+    //     components::sniffer::SnifferComponent::new(uart_mux, mux_mac, mux_alarm).finalize();
+    // imix.pconsole.set_command(sniffer);
+
     // Optional kernel tests. Note that these might conflict
     // with normal operation (e.g., steal callbacks from drivers, etc.),
     // so do not run these and expect all services/applications to work.
@@ -456,6 +548,7 @@ pub unsafe fn reset_handler() {
     // virtual_uart_rx_test::run_virtual_uart_receive(uart_mux);
     // rng_test::run_entropy32();
     // aes_ccm_test::run();
+    // ieee802154_framer_test::run();
     // aes_test::run_aes128_ctr();
     // aes_test::run_aes128_cbc();
 
@@ -70,6 +70,7 @@ pub struct Platform {
         nrf52::radio::Radio,
         VirtualMuxAlarm<'static, Rtc>,
     >,
+    ble_peripheral: &'static capsules::ble::BlePeripheralDriver<'static>,
     button: &'static capsules::button::Button<'static, nrf5x::gpio::GPIOPin>,
     console: &'static capsules::console::Console<'static>,
     gpio: &'static capsules::gpio::GPIO<'static, nrf5x::gpio::GPIOPin>,
@@ -99,6 +100,7 @@ impl kernel::Platform for Platform {
             capsules::button::DRIVER_NUM => f(Some(self.button)),
             capsules::rng::DRIVER_NUM => f(Some(self.rng)),
             capsules::ble_advertising_driver::DRIVER_NUM => f(Some(self.ble_radio)),
+            capsules::ble::DRIVER_NUM => f(Some(self.ble_peripheral)),
             capsules::temperature::DRIVER_NUM => f(Some(self.temp)),
             capsules::nonvolatile_storage_driver::DRIVER_NUM => {
                 f(self.nonvolatile_storage.map_or(None, |nv| Some(nv)))
@@ -284,8 +286,71 @@ pub unsafe fn setup_board(
         &nrf52::radio::RADIO,
         ble_radio,
     );
+    kernel::hil::ble_advertising::BleAdvertisementResponder::set_response_client(
+        &nrf52::radio::RADIO,
+        ble_radio,
+    );
     ble_radio_virtual_alarm.set_client(ble_radio);
 
+    // BLE connections in the peripheral role. This uses the same radio as the
+    // advertising driver, so each refuses to start while the other uses it.
+    let ble_link_virtual_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let ble_link = static_init!(
+        capsules::ble::link_layer::LinkLayer<
+            'static,
+            nrf52::radio::Radio,
+            VirtualMuxAlarm<'static, Rtc>,
+        >,
+        capsules::ble::link_layer::LinkLayer::new(
+            &nrf52::radio::RADIO,
+            ble_link_virtual_alarm,
+            &mut capsules::ble::link_layer::ADV_BUF,
+            nrf52::ficr::FICR_INSTANCE.ble_address()
+        )
+    );
+    kernel::hil::ble_advertising::BleConnectionDriver::set_connection_client(
+        &nrf52::radio::RADIO,
+        ble_link,
+    );
+    ble_link_virtual_alarm.set_client(ble_link);
+    ble_link.share_radio_with(ble_radio);
+    ble_radio.share_radio_with(ble_link);
+    let ble_l2cap = static_init!(
+        capsules::ble::l2cap::L2cap<'static>,
+        capsules::ble::l2cap::L2cap::new(ble_link)
+    );
+    capsules::ble::link_layer::Peripheral::set_client(ble_link, ble_l2cap);
+    let ble_gatt = static_init!(
+        capsules::ble::gatt::GattServer<'static>,
+        capsules::ble::gatt::GattServer::new(
+            ble_l2cap,
+            b"Tock",
+            // b4f0c7c2-5e36-4c6a-9d1b-2f1f8a3e0001
+            capsules::ble::gatt::Uuid::Uuid128([
+                0x01, 0x00, 0x3e, 0x8a, 0x1f, 0x2f, 0x1b, 0x9d, 0x6a, 0x4c, 0x36, 0x5e, 0xc2, 0xc7,
+                0xf0, 0xb4
+            ]),
+            // b4f0c7c2-5e36-4c6a-9d1b-2f1f8a3e0002
+            capsules::ble::gatt::Uuid::Uuid128([
+                0x02, 0x00, 0x3e, 0x8a, 0x1f, 0x2f, 0x1b, 0x9d, 0x6a, 0x4c, 0x36, 0x5e, 0xc2, 0xc7,
+                0xf0, 0xb4
+            ])
+        )
+    );
+    ble_l2cap.set_att_client(ble_gatt);
+    let ble_peripheral = static_init!(
+        capsules::ble::BlePeripheralDriver<'static>,
+        capsules::ble::BlePeripheralDriver::new(
+            ble_link,
+            ble_gatt,
+            board_kernel.create_grant(&memory_allocation_capability)
+        )
+    );
+    ble_gatt.set_client(ble_peripheral);
+
     let temp = static_init!(
         capsules::temperature::TemperatureSensor<'static>,
         capsules::temperature::TemperatureSensor::new(
@@ -385,6 +450,7 @@ pub unsafe fn setup_board(
                 board_kernel.create_grant(&memory_allocation_capability),
                 0x60000, // Start address for userspace accessible region
                 0x20000, // Length of userspace accessible region
+                0x2000,  // Length of each app's region
                 0,       // Start address of kernel accessible region
                 0x60000, // Length of kernel accessible region
                 &mut capsules::nonvolatile_storage_driver::BUFFER
@@ -419,6 +485,7 @@ pub unsafe fn setup_board(
     let platform = Platform {
         button: button,
         ble_radio: ble_radio,
+        ble_peripheral: ble_peripheral,
         console: console,
         led: led,
         gpio: gpio,
@@ -0,0 +1,1928 @@
+//! Over-the-air updates of an app, with two slots and rollback.
+//!
+//! `AppUpdate` receives a new Tock Binary Format (TBF) image of an app in
+//! chunks and writes it to a staging slot in flash. It then verifies the
+//! image and switches the kernel over to it. The previous image is kept so
+//! that the update can be rolled back. Images come from processes through
+//! `AppUpdateDriver`, over UDP through `UdpUpdate`, and over the USB bulk
+//! endpoints through `UsbUpdate`. These front ends drive any `Update`, so
+//! they also update the kernel through `kernel_update::KernelUpdate`.
+//!
+//! Layout
+//! ------
+//!
+//! The update region is a range of pages of a `hil::flash::Flash`, placed in
+//! the apps region of flash where `load_processes` looks for apps. It holds
+//! a selector page followed by two slots of the same size:
+//!
+//! ```text
+//! +----------+--------------------------+--------------------------+
+//! | selector | slot 0: image | padding  | slot 1: image | padding  |
+//! +----------+--------------------------+--------------------------+
+//! ```
+//!
+//! The selector page starts with a TBF padding header. Its size reaches the
+//! start of the active slot, so the kernel skips to that image when it loads
+//! processes. Every image is followed by a padding header that reaches the
+//! end of the region, so the kernel skips the other slot and continues with
+//! any apps after the region. Switching slots is therefore a single page
+//! write.
+//!
+//! After its padding header, the selector page holds a record with the
+//! length and CRC-32 of the image in each slot. A CRC protects the record.
+//! The slots should be sized and aligned so that the MPU can protect an app
+//! in either of them.
+//!
+//! Updates
+//! -------
+//!
+//! An update is a session:
+//!
+//! 1. `begin` the session with the length and CRC-32 of the image.
+//! 2. `write` the bytes of the image in order.
+//! 3. `install` the image with its signature.
+//!
+//! The image is written to the slot that is not active. `install` reads it
+//! back and checks its TBF header, its CRC and, if the board set a
+//! `tock_boot::Verifier`, its signature. Only then does it rewrite the
+//! selector page. If an operation fails, the session ends. Only one session
+//! runs at a time. `rollback` checks the CRC of the image in the other slot
+//! again and switches back to it.
+//!
+//! The kernel only loads processes at boot, so a new image runs after the
+//! next reset. On flash that erases a page as part of every write, losing
+//! power while the selector page is written leaves no valid selector. The
+//! apps of the region, and any after it, then do not load until the next
+//! update.
+//!
+//! Remote protocol
+//! ---------------
+//!
+//! `UdpUpdate` receives one request per datagram, and `UsbUpdate` one per
+//! bulk transfer, preceded by its length as a 16-bit integer. All integers
+//! are big endian. A request is one of:
+//!
+//! ```text
+//! BEGIN    (1) | length (4) | CRC-32 (4)
+//! DATA     (2) | offset (4) | data...
+//! INSTALL  (3) | signature...
+//! ROLLBACK (4)
+//! ABORT    (5)
+//! ```
+//!
+//! Each request is answered with the request type with its top bit set, the
+//! `ReturnCode` of the request as a signed byte, and the number of bytes of
+//! the image received so far (4 bytes). DATA is accepted at any offset up to
+//! that number, so lost data is simply sent again from there. Only the peer
+//! that began a session can write, install or abort it. The image is
+//! authenticated only by its signature, so boards that take updates from a
+//! network should set a `tock_boot::Verifier`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let update_flash = static_init!(
+//!     capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::virtual_flash::FlashUser::new(mux_flash)
+//! );
+//! let app_update = static_init!(
+//!     capsules::app_update::AppUpdate<
+//!         'static,
+//!         capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+//!     >,
+//!     capsules::app_update::AppUpdate::new(
+//!         update_flash,
+//!         384, // Selector page, in the apps region
+//!         64,  // Pages of each slot
+//!         &mut UPDATE_PAGE,
+//!         dynamic_deferred_call
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(update_flash, app_update);
+//! app_update.set_deferred_call_handle(
+//!     dynamic_deferred_call
+//!         .register(app_update)
+//!         .expect("no deferred call slot available for app updates"),
+//! );
+//!
+//! let app_update_driver = static_init!(
+//!     capsules::app_update::AppUpdateDriver<'static, FlashUser<...>>,
+//!     capsules::app_update::AppUpdateDriver::new(
+//!         app_update,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! app_update.add_client(app_update_driver);
+//!
+//! let udp_update = static_init!(
+//!     capsules::app_update::UdpUpdate<'static, FlashUser<...>>,
+//!     capsules::app_update::UdpUpdate::new(app_update, udp_send, UPDATE_PORT)
+//! );
+//! udp_send.set_client(udp_update);
+//! udp_recv.set_client(udp_update);
+//! udp_port_table.bind_kernel(udp_recv, None, UPDATE_PORT);
+//! app_update.add_client(udp_update);
+//!
+//! let usb_update = static_init!(
+//!     capsules::app_update::UsbUpdate<'static, FlashUser<...>>,
+//!     capsules::app_update::UsbUpdate::new(app_update, &mut capsules::app_update::USB_BUFFER)
+//! );
+//! usb_update.set_usb(usb_client);
+//! usb_client.set_bulk_client(usb_update);
+//! app_update.add_client(usb_update);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use tock_boot::{crc32, Verifier};
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use crate::usbc_client::{BulkClient, BulkResume};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::AppUpdate as usize;
+
+/// Buffer for the requests of `UsbUpdate`.
+pub static mut USB_BUFFER: [u8; 256] = [0; 256];
+
+/// Longest signature of an image, in bytes.
+pub const MAX_SIGNATURE_LENGTH: usize = 64;
+
+/// Most clients of an `AppUpdate`, one for each front end.
+const MAX_CLIENTS: usize = 3;
+
+/// Size of a TBF header without any options, as used for padding.
+const PADDING_HEADER_SIZE: usize = 16;
+const SELECTOR_MAGIC: [u8; 4] = *b"TUPD";
+const RECORD_SIZE: usize = 36;
+
+const REQUEST_BEGIN: u8 = 1;
+const REQUEST_DATA: u8 = 2;
+const REQUEST_INSTALL: u8 = 3;
+const REQUEST_ROLLBACK: u8 = 4;
+const REQUEST_ABORT: u8 = 5;
+const RESPONSE_LENGTH: usize = 6;
+
+fn read_u32(buf: &[u8]) -> u32 {
+    buf[0] as u32 | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24
+}
+
+fn write_u32(buf: &mut [u8], value: u32) {
+    for i in 0..4 {
+        buf[i] = (value >> (8 * i)) as u8;
+    }
+}
+
+/// Receive the results of `AppUpdate` operations. Every client is told about
+/// every operation, so clients should ignore results of operations they did
+/// not start.
+pub trait UpdateClient {
+    /// The session is ready for the image.
+    fn begin_done(&self, result: ReturnCode);
+
+    /// `length` bytes of the image were written.
+    fn write_done(&self, length: usize, result: ReturnCode);
+
+    /// An image was installed or rolled back to.
+    fn install_done(&self, result: ReturnCode);
+}
+
+/// An update in slots that the front ends drive: `AppUpdate`, or
+/// `kernel_update::KernelUpdate` for the kernel itself.
+pub trait Update {
+    /// Bytes of each slot.
+    fn slot_size(&self) -> usize;
+
+    /// Bytes of the image of the session received so far.
+    fn received(&self) -> usize;
+
+    /// Whether a session is in progress.
+    fn in_session(&self) -> bool;
+
+    /// Begins a session for an image of `length` bytes with the given
+    /// CRC-32. `begin_done` follows.
+    fn begin(&self, length: usize, crc: u32) -> ReturnCode;
+
+    /// Writes the next bytes of the image. Only as many bytes as fit in the
+    /// current page are taken; `write_done` reports how many.
+    fn write(&self, data: &[u8]) -> ReturnCode;
+
+    /// Checks the image of the session and switches to it. `install_done`
+    /// follows.
+    fn install(&self, signature: &[u8]) -> ReturnCode;
+
+    /// Switches back to the image in the other slot. `install_done`
+    /// follows.
+    fn rollback(&self) -> ReturnCode;
+
+    /// Ends the session without installing the image.
+    fn abort(&self) -> ReturnCode;
+}
+
+/// Length and CRC of the image in a slot. A length of 0 means no image.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Image {
+    length: u32,
+    crc: u32,
+}
+
+/// The record of the selector page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Record {
+    sequence: u32,
+    active: usize,
+    images: [Image; 2],
+}
+
+fn parse_record(page: &[u8]) -> Option<Record> {
+    let record = &page[PADDING_HEADER_SIZE..PADDING_HEADER_SIZE + RECORD_SIZE];
+    let active = read_u32(&record[8..12]) as usize;
+    if record[0..4] != SELECTOR_MAGIC
+        || read_u32(&record[32..36]) != crc32(0, &record[0..32])
+        || active > 1
+    {
+        return None;
+    }
+    let image = |offset: usize| Image {
+        length: read_u32(&record[offset..offset + 4]),
+        crc: read_u32(&record[offset + 4..offset + 8]),
+    };
+    Some(Record {
+        sequence: read_u32(&record[4..8]),
+        active: active,
+        images: [image(16), image(24)],
+    })
+}
+
+fn write_record(page: &mut [u8], record: &Record) {
+    let buf = &mut page[PADDING_HEADER_SIZE..PADDING_HEADER_SIZE + RECORD_SIZE];
+    buf[0..4].copy_from_slice(&SELECTOR_MAGIC);
+    write_u32(&mut buf[4..8], record.sequence);
+    write_u32(&mut buf[8..12], record.active as u32);
+    write_u32(&mut buf[12..16], 0);
+    for (i, image) in record.images.iter().enumerate() {
+        write_u32(&mut buf[16 + 8 * i..20 + 8 * i], image.length);
+        write_u32(&mut buf[20 + 8 * i..24 + 8 * i], image.crc);
+    }
+    let crc = crc32(0, &buf[0..32]);
+    write_u32(&mut buf[32..36], crc);
+}
+
+/// A TBF padding header covering `size` bytes.
+fn padding_header(size: usize) -> [u8; PADDING_HEADER_SIZE] {
+    let mut header = [0; PADDING_HEADER_SIZE];
+    let first = 2 | (PADDING_HEADER_SIZE as u32) << 16;
+    write_u32(&mut header[0..4], first);
+    write_u32(&mut header[4..8], size as u32);
+    write_u32(&mut header[12..16], first ^ size as u32);
+    header
+}
+
+/// Whether `data`, the start of an image of `length` bytes, begins with a
+/// TBF header of an app that the kernel would load.
+fn valid_header(data: &[u8], length: usize) -> bool {
+    if data.len() < PADDING_HEADER_SIZE {
+        return false;
+    }
+    let version = data[0] as u16 | (data[1] as u16) << 8;
+    let header_size = data[2] as usize | (data[3] as usize) << 8;
+    let total_size = read_u32(&data[4..8]) as usize;
+    if version != 2
+        || header_size <= PADDING_HEADER_SIZE
+        || header_size > data.len()
+        || total_size != length
+    {
+        return false;
+    }
+    checksum(&data[..header_size]) == read_u32(&data[12..16])
+}
+
+/// The checksum of a TBF header: the XOR of its words, except the checksum.
+fn checksum(header: &[u8]) -> u32 {
+    let mut checksum = 0;
+    for (i, word) in header.chunks(4).enumerate() {
+        let mut value = [0; 4];
+        value[..word.len()].copy_from_slice(word);
+        if i != 3 {
+            checksum ^= read_u32(&value);
+        }
+    }
+    checksum
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Reading the selector page.
+    Loading,
+    /// Erasing the page of the slot that the page buffer is written to.
+    Erasing,
+    /// Writing the page buffer to the slot.
+    Writing,
+    /// Reading back a page of the image to check it.
+    Verifying(usize),
+    /// Erasing the selector page.
+    SelectorErasing,
+    /// Writing the selector page.
+    SelectorWriting,
+    /// The selector and slot pages are written, and a deferred call ends the
+    /// session if needed and reports the result to all clients.
+    Completing,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Begin,
+    Write,
+    Install,
+    Rollback,
+}
+
+pub struct AppUpdate<'a, F: hil::flash::Flash + 'static> {
+    flash: &'a F,
+    start_page: usize,
+    slot_pages: usize,
+    page_size: usize,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+    clients: [OptionalCell<&'a UpdateClient>; MAX_CLIENTS],
+    verifier: OptionalCell<&'a Verifier>,
+
+    page: TakeCell<'static, F::Page>,
+    // The record of the selector page, if it has been read, and `None` if
+    // the selector page is not valid.
+    record: Cell<Option<Option<Record>>>,
+
+    state: Cell<State>,
+    operation: Cell<Operation>,
+    result: Cell<ReturnCode>,
+
+    // The image of the session, and the slot it is written to.
+    session: Cell<bool>,
+    slot: Cell<usize>,
+    length: Cell<usize>,
+    crc: Cell<u32>,
+    signature: Cell<[u8; MAX_SIGNATURE_LENGTH]>,
+    signature_length: Cell<usize>,
+    // Bytes of the image received, and of the image and the padding header
+    // after it placed in the page buffer or flash.
+    received: Cell<usize>,
+    placed: Cell<usize>,
+    // The page of the slot that the page buffer holds.
+    index: Cell<usize>,
+    // Bytes taken by the current write.
+    written: Cell<usize>,
+    // CRC of the bytes of the image read back so far.
+    verify_crc: Cell<u32>,
+}
+
+impl<F: hil::flash::Flash> AppUpdate<'a, F> {
+    /// Creates an update region made of the selector page `start_page` and
+    /// two slots of `slot_pages` pages after it. `page` is a buffer for one
+    /// page.
+    pub fn new(
+        flash: &'a F,
+        start_page: usize,
+        slot_pages: usize,
+        page: &'static mut F::Page,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> AppUpdate<'a, F> {
+        let page_size = page.as_mut().len();
+        AppUpdate {
+            flash: flash,
+            start_page: start_page,
+            slot_pages: slot_pages,
+            page_size: page_size,
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            clients: [
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+            ],
+            verifier: OptionalCell::empty(),
+            page: TakeCell::new(page),
+            record: Cell::new(None),
+            state: Cell::new(State::Idle),
+            operation: Cell::new(Operation::Begin),
+            result: Cell::new(ReturnCode::SUCCESS),
+            session: Cell::new(false),
+            slot: Cell::new(0),
+            length: Cell::new(0),
+            crc: Cell::new(0),
+            signature: Cell::new([0; MAX_SIGNATURE_LENGTH]),
+            signature_length: Cell::new(0),
+            received: Cell::new(0),
+            placed: Cell::new(0),
+            index: Cell::new(0),
+            written: Cell::new(0),
+            verify_crc: Cell::new(0),
+        }
+    }
+
+    pub fn set_deferred_call_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Adds a client, such as a front end. Returns ENOMEM if there are
+    /// already `MAX_CLIENTS` clients.
+    pub fn add_client(&self, client: &'a UpdateClient) -> ReturnCode {
+        match self.clients.iter().find(|cell| cell.is_none()) {
+            Some(cell) => {
+                cell.set(client);
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    /// Sets the verifier that checks the signatures of images. Without one,
+    /// images are only checked against their CRC.
+    pub fn set_verifier(&self, verifier: &'a Verifier) {
+        self.verifier.set(verifier);
+    }
+
+    // Run an operation, reading the selector page first if needed.
+    fn start(&self, operation: Operation) -> ReturnCode {
+        self.operation.set(operation);
+        let result = match self.record.get() {
+            Some(_) => self.run(),
+            None => self.page.take().map_or(ReturnCode::ERESERVE, |page| {
+                self.state.set(State::Loading);
+                self.flash.read_page(self.start_page, page)
+            }),
+        };
+        if result != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+            if operation != Operation::Rollback {
+                self.session.set(false);
+            }
+        }
+        result
+    }
+
+    fn run(&self) -> ReturnCode {
+        let record = self.record.get().unwrap_or(None);
+        match self.operation.get() {
+            Operation::Begin => {
+                // Without a valid selector no slot is active, and slot 0 is
+                // used first.
+                self.slot.set(record.map_or(0, |record| 1 - record.active));
+                self.received.set(0);
+                self.placed.set(0);
+                self.index.set(0);
+                self.page.map(|page| {
+                    for byte in page.as_mut().iter_mut() {
+                        *byte = 0xff;
+                    }
+                });
+                self.complete(ReturnCode::SUCCESS)
+            }
+            Operation::Write => self.complete(ReturnCode::FAIL),
+            Operation::Install => self.verify(),
+            Operation::Rollback => match record {
+                Some(record) if record.images[1 - record.active].length != 0 => {
+                    let slot = 1 - record.active;
+                    let image = record.images[slot];
+                    self.slot.set(slot);
+                    self.length.set(image.length as usize);
+                    self.crc.set(image.crc);
+                    self.verify()
+                }
+                _ => self.complete(ReturnCode::EINVAL),
+            },
+        }
+    }
+
+    fn slot_page(&self, slot: usize, index: usize) -> usize {
+        self.start_page + 1 + slot * self.slot_pages + index
+    }
+
+    // Bytes from the start of the slot to the end of the update region.
+    fn to_region_end(&self, slot: usize) -> usize {
+        (2 - slot) * self.slot_size()
+    }
+
+    // Bytes of the image and the padding header after it.
+    fn stream_length(&self) -> usize {
+        if self.length.get() < self.to_region_end(self.slot.get()) {
+            self.length.get() + PADDING_HEADER_SIZE
+        } else {
+            self.length.get()
+        }
+    }
+
+    // Where the page buffer starts in the slot.
+    fn page_start(&self) -> usize {
+        self.index.get() * self.page_size
+    }
+
+    // Place as much of `data` in the page buffer as fits, followed by the
+    // padding header once the image is complete. Returns the number of bytes
+    // of `data` taken.
+    fn place(&self, page: &mut [u8], data: &[u8]) -> usize {
+        let offset = self.placed.get() - self.page_start();
+        let length = cmp::min(
+            cmp::min(data.len(), self.page_size - offset),
+            self.length.get() - self.received.get(),
+        );
+        page[offset..offset + length].copy_from_slice(&data[..length]);
+        self.placed.set(self.placed.get() + length);
+        self.received.set(self.received.get() + length);
+        self.place_padding(page);
+        length
+    }
+
+    // Place as much of the padding header as fits in the page buffer, once
+    // the image is complete.
+    fn place_padding(&self, page: &mut [u8]) {
+        let length = self.length.get();
+        if self.received.get() != length {
+            return;
+        }
+        let padding = padding_header(self.to_region_end(self.slot.get()) - length);
+        let end = cmp::min(self.stream_length(), self.page_start() + self.page_size);
+        for placed in self.placed.get()..end {
+            page[placed - self.page_start()] = padding[placed - length];
+        }
+        self.placed.set(cmp::max(self.placed.get(), end));
+    }
+
+    // Write the page buffer to flash if it is full or holds the end of the
+    // image, or complete the write.
+    fn flush(&self) -> ReturnCode {
+        let placed = self.placed.get();
+        let start = self.page_start();
+        if placed == start + self.page_size || placed > start && placed == self.stream_length() {
+            self.state.set(State::Erasing);
+            self.flash
+                .erase_page(self.slot_page(self.slot.get(), self.index.get()))
+        } else {
+            self.complete(ReturnCode::SUCCESS)
+        }
+    }
+
+    // The page buffer was written to flash, so start the next page. It may
+    // hold the rest of the padding header.
+    fn page_written(&self) -> ReturnCode {
+        self.index.set(self.index.get() + 1);
+        self.page.map(|page| {
+            let page = page.as_mut();
+            for byte in page.iter_mut() {
+                *byte = 0xff;
+            }
+            self.place_padding(page);
+        });
+        self.flush()
+    }
+
+    // Read back the image in the slot to check it.
+    fn verify(&self) -> ReturnCode {
+        self.verify_crc.set(0);
+        self.verifier.map(|verifier| verifier.reset());
+        self.page.take().map_or(ReturnCode::ERESERVE, |page| {
+            self.state.set(State::Verifying(0));
+            self.flash
+                .read_page(self.slot_page(self.slot.get(), 0), page)
+        })
+    }
+
+    // Page `index` of the image was read back.
+    fn page_verified(&self, page: &'static mut F::Page, index: usize) -> ReturnCode {
+        let length = self.length.get();
+        let start = index * self.page_size;
+        let end = cmp::min(start + self.page_size, length);
+        let check_signature = self.operation.get() == Operation::Install;
+        {
+            let data = &page.as_mut()[..end - start];
+            if index == 0 && !valid_header(data, length) {
+                self.page.replace(page);
+                return self.complete(ReturnCode::EINVAL);
+            }
+            self.verify_crc.set(crc32(self.verify_crc.get(), data));
+            if check_signature {
+                self.verifier.map(|verifier| verifier.update(data));
+            }
+        }
+        if end < length {
+            self.state.set(State::Verifying(index + 1));
+            return self
+                .flash
+                .read_page(self.slot_page(self.slot.get(), index + 1), page);
+        }
+        self.page.replace(page);
+
+        let signature = self.signature.get();
+        let signed = !check_signature
+            || self.verifier.map_or(true, |verifier| {
+                verifier.verify(&signature[..self.signature_length.get()])
+            });
+        if self.verify_crc.get() != self.crc.get() || !signed {
+            return self.complete(ReturnCode::FAIL);
+        }
+        self.state.set(State::SelectorErasing);
+        self.flash.erase_page(self.start_page)
+    }
+
+    // The record that makes the slot of the operation the active one.
+    fn next_record(&self) -> Record {
+        let slot = self.slot.get();
+        let mut record = self.record.get().unwrap_or(None).unwrap_or(Record {
+            sequence: 0,
+            active: slot,
+            images: [Image::default(); 2],
+        });
+        record.sequence = record.sequence.wrapping_add(1);
+        record.active = slot;
+        record.images[slot] = Image {
+            length: self.length.get() as u32,
+            crc: self.crc.get(),
+        };
+        record
+    }
+
+    fn write_selector(&self) -> ReturnCode {
+        let record = self.next_record();
+        self.page.take().map_or(ReturnCode::ERESERVE, |page| {
+            let buf = page.as_mut();
+            for byte in buf.iter_mut() {
+                *byte = 0xff;
+            }
+            let skip = self.page_size + record.active * self.slot_size();
+            buf[..PADDING_HEADER_SIZE].copy_from_slice(&padding_header(skip));
+            write_record(buf, &record);
+            self.state.set(State::SelectorWriting);
+            self.flash.write_page(self.start_page, page)
+        })
+    }
+
+    // Report `result` to the clients from a deferred call. Once the selector
+    // is loaded, a `Begin`, or a `Rollback` without an older image, ends
+    // before any flash operation, within the command or message that started
+    // it.
+    fn complete(&self, result: ReturnCode) -> ReturnCode {
+        self.result.set(result);
+        self.state.set(State::Completing);
+        self.handle.map_or(ReturnCode::FAIL, |handle| {
+            self.deferred_caller.set(*handle);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn continue_with(&self, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.complete(result);
+        }
+    }
+
+    // The flash may no longer match the selector record, so read it again
+    // before the next operation.
+    fn failed(&self) {
+        self.record.set(None);
+        self.complete(ReturnCode::FAIL);
+    }
+}
+
+impl<F: hil::flash::Flash> Update for AppUpdate<'a, F> {
+    fn slot_size(&self) -> usize {
+        self.slot_pages * self.page_size
+    }
+
+    fn received(&self) -> usize {
+        self.received.get()
+    }
+
+    fn in_session(&self) -> bool {
+        self.session.get()
+    }
+
+    /// The image must leave either no room or more than 16 bytes free at the
+    /// end of a slot, for the padding header after it.
+    fn begin(&self, length: usize, crc: u32) -> ReturnCode {
+        if self.session.get() || self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let slot_size = self.slot_size();
+        if length <= PADDING_HEADER_SIZE
+            || length % 4 != 0
+            || length > slot_size
+            || slot_size - length == PADDING_HEADER_SIZE
+        {
+            return ReturnCode::EINVAL;
+        }
+        self.session.set(true);
+        self.length.set(length);
+        self.crc.set(crc);
+        self.start(Operation::Begin)
+    }
+
+    fn write(&self, data: &[u8]) -> ReturnCode {
+        if !self.session.get() || data.len() == 0 {
+            return ReturnCode::EINVAL;
+        }
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if self.received.get() == self.length.get() {
+            return ReturnCode::ESIZE;
+        }
+        self.operation.set(Operation::Write);
+        let result = self.page.map_or(ReturnCode::ERESERVE, |page| {
+            let written = self.place(page.as_mut(), data);
+            self.written.set(written);
+            ReturnCode::SUCCESS
+        });
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        let result = self.flush();
+        if result != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+            self.session.set(false);
+        }
+        result
+    }
+
+    /// The signature is checked by the verifier, if the board set one.
+    fn install(&self, signature: &[u8]) -> ReturnCode {
+        if !self.session.get() || self.received.get() != self.length.get() {
+            return ReturnCode::EINVAL;
+        }
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if signature.len() > MAX_SIGNATURE_LENGTH {
+            return ReturnCode::ESIZE;
+        }
+        let mut buffer = [0; MAX_SIGNATURE_LENGTH];
+        buffer[..signature.len()].copy_from_slice(signature);
+        self.signature.set(buffer);
+        self.signature_length.set(signature.len());
+        self.start(Operation::Install)
+    }
+
+    /// The image is checked against its CRC again first.
+    fn rollback(&self) -> ReturnCode {
+        if self.session.get() || self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.start(Operation::Rollback)
+    }
+
+    fn abort(&self) -> ReturnCode {
+        if !self.session.get() {
+            return ReturnCode::EINVAL;
+        }
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.session.set(false);
+        ReturnCode::SUCCESS
+    }
+}
+
+impl<F: hil::flash::Flash> hil::flash::Client<F> for AppUpdate<'a, F> {
+    fn read_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        let state = self.state.get();
+        if error != hil::flash::Error::CommandComplete {
+            self.page.replace(page);
+            return self.failed();
+        }
+        let result = match state {
+            State::Loading => {
+                self.record.set(Some(parse_record(page.as_mut())));
+                self.page.replace(page);
+                self.state.set(State::Idle);
+                self.run()
+            }
+            State::Verifying(index) => self.page_verified(page, index),
+            _ => {
+                self.page.replace(page);
+                ReturnCode::SUCCESS
+            }
+        };
+        self.continue_with(result);
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        self.page.replace(page);
+        if error != hil::flash::Error::CommandComplete {
+            return self.failed();
+        }
+        let result = match self.state.get() {
+            State::Writing => self.page_written(),
+            State::SelectorWriting => {
+                self.record.set(Some(Some(self.next_record())));
+                self.complete(ReturnCode::SUCCESS)
+            }
+            _ => ReturnCode::SUCCESS,
+        };
+        self.continue_with(result);
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        if error != hil::flash::Error::CommandComplete {
+            return self.failed();
+        }
+        let result = match self.state.get() {
+            State::Erasing => self.page.take().map_or(ReturnCode::ERESERVE, |page| {
+                self.state.set(State::Writing);
+                self.flash
+                    .write_page(self.slot_page(self.slot.get(), self.index.get()), page)
+            }),
+            State::SelectorErasing => self.write_selector(),
+            _ => ReturnCode::SUCCESS,
+        };
+        self.continue_with(result);
+    }
+}
+
+impl<F: hil::flash::Flash> DynamicDeferredCallClient for AppUpdate<'a, F> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if self.state.get() != State::Completing {
+            return;
+        }
+        self.state.set(State::Idle);
+        let result = self.result.get();
+        let operation = self.operation.get();
+        // A failed operation or an installed image ends the session.
+        if result != ReturnCode::SUCCESS || operation == Operation::Install {
+            self.session.set(false);
+        }
+        for cell in self.clients.iter() {
+            cell.map(|client| match operation {
+                Operation::Begin => client.begin_done(result),
+                Operation::Write => client.write_done(self.written.get(), result),
+                Operation::Install | Operation::Rollback => client.install_done(result),
+            });
+        }
+    }
+}
+
+/// The response to a request of type `kind` from a remote front end.
+fn response(kind: u8, result: ReturnCode, received: usize) -> [u8; RESPONSE_LENGTH] {
+    let received = received as u32;
+    [
+        kind | 0x80,
+        isize::from(result) as u8,
+        (received >> 24) as u8,
+        (received >> 16) as u8,
+        (received >> 8) as u8,
+        received as u8,
+    ]
+}
+
+fn read_u32_be(buf: &[u8]) -> u32 {
+    (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | buf[3] as u32
+}
+
+/// Starts the request in `message` from a remote front end. `owner` tells
+/// whether the sender of the request owns the session. Returns `None` if the
+/// request is malformed. Otherwise returns its type and, if it is already
+/// done, its result; if not, a callback of `UpdateClient` follows.
+fn start_request<U: Update>(
+    update: &U,
+    message: &[u8],
+    owner: bool,
+) -> Option<(u8, Option<ReturnCode>)> {
+    let kind = *message.get(0)?;
+    let result = match kind {
+        REQUEST_BEGIN if message.len() == 9 => {
+            if owner {
+                // Start over.
+                update.abort();
+            }
+            update.begin(
+                read_u32_be(&message[1..5]) as usize,
+                read_u32_be(&message[5..9]),
+            )
+        }
+        REQUEST_DATA if message.len() > 5 => {
+            let offset = read_u32_be(&message[1..5]) as usize;
+            let data = &message[5..];
+            let received = update.received();
+            if !owner || offset > received {
+                ReturnCode::EINVAL
+            } else if received - offset >= data.len() {
+                // All of it was received before.
+                return Some((kind, Some(ReturnCode::SUCCESS)));
+            } else {
+                update.write(&data[received - offset..])
+            }
+        }
+        REQUEST_INSTALL if owner => update.install(&message[1..]),
+        REQUEST_ROLLBACK if message.len() == 1 => update.rollback(),
+        REQUEST_ABORT if owner => return Some((kind, Some(update.abort()))),
+        REQUEST_INSTALL | REQUEST_ABORT => ReturnCode::EINVAL,
+        _ => return None,
+    };
+    if result == ReturnCode::SUCCESS {
+        Some((kind, None))
+    } else {
+        Some((kind, Some(result)))
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    buffer: Option<AppSlice<Shared, u8>>,
+    signature: Option<AppSlice<Shared, u8>>,
+}
+
+/// Lets a process update an app, such as itself.
+pub struct AppUpdateDriver<'a, U: Update + 'a> {
+    update: &'a U,
+    apps: Grant<App>,
+    // The process that owns the session or started the current operation.
+    owner: OptionalCell<AppId>,
+    busy: Cell<bool>,
+    // The part of the allowed buffer that the current write has written, and
+    // where it ends.
+    offset: Cell<usize>,
+    end: Cell<usize>,
+}
+
+impl<U: Update> AppUpdateDriver<'a, U> {
+    pub fn new(update: &'a U, grant: Grant<App>) -> AppUpdateDriver<'a, U> {
+        AppUpdateDriver {
+            update: update,
+            apps: grant,
+            owner: OptionalCell::empty(),
+            busy: Cell::new(false),
+            offset: Cell::new(0),
+            end: Cell::new(0),
+        }
+    }
+
+    fn is_owner(&self, appid: AppId) -> bool {
+        self.owner.map_or(false, |owner| *owner == appid) && self.update.in_session()
+    }
+
+    // Whether `appid` may begin a session or roll back. A session of a
+    // process that no longer exists is aborted.
+    fn claim(&self, appid: AppId) -> ReturnCode {
+        if self.busy.get() {
+            return ReturnCode::EBUSY;
+        }
+        if let Some(owner) = self.owner.take() {
+            let exists = self.apps.enter(owner, |_, _| ()).is_ok();
+            if owner != appid && exists && self.update.in_session() {
+                self.owner.set(owner);
+                return ReturnCode::EBUSY;
+            }
+            if self.update.in_session() {
+                self.update.abort();
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn started(&self, result: ReturnCode, appid: AppId) -> ReturnCode {
+        if result == ReturnCode::SUCCESS {
+            self.owner.set(appid);
+            self.busy.set(true);
+        }
+        result
+    }
+
+    // Write the rest of the allowed buffer of the owner.
+    fn write_next(&self) -> ReturnCode {
+        self.owner.map_or(ReturnCode::FAIL, |appid| {
+            self.apps
+                .enter(*appid, |app, _| {
+                    app.buffer.as_ref().map_or(ReturnCode::ERESERVE, |buffer| {
+                        if buffer.len() < self.end.get() {
+                            return ReturnCode::ESIZE;
+                        }
+                        self.update
+                            .write(&buffer.as_ref()[self.offset.get()..self.end.get()])
+                    })
+                })
+                .unwrap_or_else(|err| err.into())
+        })
+    }
+
+    fn schedule(&self, result: ReturnCode, event: usize, length: usize) {
+        self.busy.set(false);
+        let owner = if !self.update.in_session() {
+            self.owner.take()
+        } else {
+            self.owner.map(|owner| *owner)
+        };
+        owner.map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.callback.map(|mut cb| {
+                    cb.schedule(usize::from(result), event, length);
+                });
+            });
+        });
+    }
+}
+
+impl<U: Update> UpdateClient for AppUpdateDriver<'a, U> {
+    fn begin_done(&self, result: ReturnCode) {
+        if self.busy.get() {
+            self.schedule(result, 0, 0);
+        }
+    }
+
+    fn write_done(&self, length: usize, result: ReturnCode) {
+        if !self.busy.get() {
+            return;
+        }
+        let mut result = result;
+        if result == ReturnCode::SUCCESS {
+            self.offset.set(self.offset.get() + length);
+            if self.offset.get() < self.end.get() {
+                result = self.write_next();
+                if result == ReturnCode::SUCCESS {
+                    return;
+                }
+                self.update.abort();
+            }
+        }
+        self.schedule(result, 1, self.offset.get());
+    }
+
+    fn install_done(&self, result: ReturnCode) {
+        if self.busy.get() {
+            self.schedule(result, 2, 0);
+        }
+    }
+}
+
+impl<U: Update> Driver for AppUpdateDriver<'a, U> {
+    /// Setup buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer with the next bytes of the image.
+    /// - `1`: Buffer with the signature of the image.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    app.buffer = slice;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.signature = slice;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Callback when an operation completes. Its arguments are the
+    ///        result, the operation (0 for begin, 1 for write and 2 for
+    ///        install or rollback) and, for writes, the number of bytes
+    ///        written.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Update an app.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Begin a session for an image of `arg1` bytes with CRC-32
+    ///        `arg2`.
+    /// - `2`: Write the first `arg1` bytes of the allowed buffer as the next
+    ///        bytes of the image.
+    /// - `3`: Install the image, with the allowed signature.
+    /// - `4`: Roll back to the image in the other slot.
+    /// - `5`: Abort the session.
+    /// - `6`: Size of each slot.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => match self.claim(appid) {
+                ReturnCode::SUCCESS => self.started(self.update.begin(arg1, arg2 as u32), appid),
+                error => error,
+            },
+
+            2 => {
+                if self.busy.get() {
+                    return ReturnCode::EBUSY;
+                }
+                if !self.is_owner(appid) || arg1 == 0 {
+                    return ReturnCode::EINVAL;
+                }
+                self.offset.set(0);
+                self.end.set(arg1);
+                self.started(self.write_next(), appid)
+            }
+
+            3 => {
+                if self.busy.get() {
+                    return ReturnCode::EBUSY;
+                }
+                if !self.is_owner(appid) {
+                    return ReturnCode::EINVAL;
+                }
+                let result = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        let signature = app
+                            .signature
+                            .as_ref()
+                            .map_or(&[][..], |signature| signature.as_ref());
+                        self.update.install(signature)
+                    })
+                    .unwrap_or_else(|err| err.into());
+                self.started(result, appid)
+            }
+
+            4 => match self.claim(appid) {
+                ReturnCode::SUCCESS => self.started(self.update.rollback(), appid),
+                error => error,
+            },
+
+            5 => {
+                if self.busy.get() {
+                    return ReturnCode::EBUSY;
+                }
+                if !self.is_owner(appid) {
+                    return ReturnCode::EINVAL;
+                }
+                self.owner.clear();
+                self.update.abort()
+            }
+
+            6 => ReturnCode::SuccessWithValue {
+                value: self.update.slot_size(),
+            },
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+/// Receives updates in UDP datagrams, on a port bound with `bind_kernel`.
+pub struct UdpUpdate<'a, U: Update + 'a> {
+    update: &'a U,
+    udp_sender: &'a UDPSender<'a>,
+    port: u16,
+    // The peer that owns the session, and the peer whose request is in
+    // progress.
+    owner: OptionalCell<(IPAddr, u16)>,
+    requester: OptionalCell<(IPAddr, u16)>,
+    pending: Cell<Option<u8>>,
+    sending: Cell<bool>,
+}
+
+impl<U: Update> UdpUpdate<'a, U> {
+    pub fn new(update: &'a U, udp_sender: &'a UDPSender<'a>, port: u16) -> UdpUpdate<'a, U> {
+        UdpUpdate {
+            update: update,
+            udp_sender: udp_sender,
+            port: port,
+            owner: OptionalCell::empty(),
+            requester: OptionalCell::empty(),
+            pending: Cell::new(None),
+            sending: Cell::new(false),
+        }
+    }
+
+    // Answer a request. Responses are dropped while another one is sent, and
+    // the peer then sends the request again.
+    fn respond(&self, peer: (IPAddr, u16), kind: u8, result: ReturnCode) {
+        self.pending.set(None);
+        if !self.update.in_session() {
+            self.owner.clear();
+        }
+        if self.sending.get() {
+            return;
+        }
+        let buf = response(kind, result, self.update.received());
+        let result = self.udp_sender.send_to(peer.0, peer.1, self.port, &buf);
+        self.sending.set(result == ReturnCode::SUCCESS);
+    }
+
+    fn done(&self, kinds: &[u8], result: ReturnCode) {
+        if let Some(kind) = self.pending.get() {
+            if kinds.contains(&kind) {
+                if kind == REQUEST_BEGIN && result == ReturnCode::SUCCESS {
+                    self.requester.map(|peer| self.owner.set(*peer));
+                }
+                self.requester
+                    .take()
+                    .map(|peer| self.respond(peer, kind, result));
+            }
+        }
+    }
+}
+
+impl<U: Update> UDPRecvClient for UdpUpdate<'a, U> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        dst_port: u16,
+        payload: &[u8],
+    ) {
+        if dst_port != self.port || self.pending.get().is_some() {
+            return;
+        }
+        let peer = (src_addr, src_port);
+        let owner = self.owner.map_or(false, |owner| *owner == peer) && self.update.in_session();
+        match start_request(self.update, payload, owner) {
+            Some((kind, Some(result))) => self.respond(peer, kind, result),
+            Some((kind, None)) => {
+                self.pending.set(Some(kind));
+                self.requester.set(peer);
+            }
+            None => {}
+        }
+    }
+}
+
+impl<U: Update> UDPSendClient for UdpUpdate<'a, U> {
+    fn send_done(&self, _result: ReturnCode) {
+        self.sending.set(false);
+    }
+}
+
+impl<U: Update> UpdateClient for UdpUpdate<'a, U> {
+    fn begin_done(&self, result: ReturnCode) {
+        self.done(&[REQUEST_BEGIN], result);
+    }
+
+    fn write_done(&self, _length: usize, result: ReturnCode) {
+        self.done(&[REQUEST_DATA], result);
+    }
+
+    fn install_done(&self, result: ReturnCode) {
+        self.done(&[REQUEST_INSTALL, REQUEST_ROLLBACK], result);
+    }
+}
+
+/// Receives updates over the USB bulk endpoints of `usbc_client::Client`.
+pub struct UsbUpdate<'a, U: Update + 'a> {
+    update: &'a U,
+    usb: OptionalCell<&'a BulkResume>,
+    // The current request, preceded by its length, and how much of it has
+    // been received.
+    buffer: TakeCell<'static, [u8]>,
+    received: Cell<usize>,
+    length: Cell<usize>,
+    pending: Cell<Option<u8>>,
+    owner: Cell<bool>,
+    // The next response for the host, preceded by its length.
+    response: Cell<Option<[u8; 2 + RESPONSE_LENGTH]>>,
+}
+
+impl<U: Update> UsbUpdate<'a, U> {
+    pub fn new(update: &'a U, buffer: &'static mut [u8]) -> UsbUpdate<'a, U> {
+        UsbUpdate {
+            update: update,
+            usb: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+            received: Cell::new(0),
+            length: Cell::new(0),
+            pending: Cell::new(None),
+            owner: Cell::new(false),
+            response: Cell::new(None),
+        }
+    }
+
+    pub fn set_usb(&self, usb: &'a BulkResume) {
+        self.usb.set(usb);
+    }
+
+    fn respond(&self, kind: u8, result: ReturnCode) {
+        self.pending.set(None);
+        if !self.update.in_session() {
+            self.owner.set(false);
+        }
+        let mut buf = [0; 2 + RESPONSE_LENGTH];
+        buf[1] = RESPONSE_LENGTH as u8;
+        buf[2..].copy_from_slice(&response(kind, result, self.update.received()));
+        self.response.set(Some(buf));
+        self.usb.map(|usb| {
+            usb.resume_in();
+            usb.resume_out();
+        });
+    }
+
+    // Start the request in the buffer, or continue writing its data.
+    fn handle(&self) {
+        let owner = self.owner.get() && self.update.in_session();
+        let length = self.length.get();
+        let started = self.buffer.map_or(None, |buffer| {
+            if length > buffer.len() - 2 {
+                buffer.get(2).map(|kind| (*kind, Some(ReturnCode::ESIZE)))
+            } else {
+                start_request(self.update, &buffer[2..2 + length], owner)
+            }
+        });
+        match started {
+            Some((kind, Some(result))) => self.respond(kind, result),
+            Some((kind, None)) => self.pending.set(Some(kind)),
+            None => {}
+        }
+    }
+
+    fn done(&self, kinds: &[u8], result: ReturnCode) {
+        if let Some(kind) = self.pending.get() {
+            if kinds.contains(&kind) {
+                if kind == REQUEST_BEGIN && result == ReturnCode::SUCCESS {
+                    self.owner.set(true);
+                }
+                if kind == REQUEST_DATA && result == ReturnCode::SUCCESS {
+                    // Write the rest of the data of the request, if any.
+                    self.handle();
+                } else {
+                    self.respond(kind, result);
+                }
+            }
+        }
+    }
+}
+
+impl<U: Update> BulkClient for UsbUpdate<'a, U> {
+    fn packet_out(&self, packet: &[u8]) -> bool {
+        if self.pending.get().is_some() {
+            return false;
+        }
+        let mut complete = false;
+        self.buffer.map(|buffer| {
+            // A request starts in a new packet, so the rest of the packet
+            // after a request is ignored.
+            for &byte in packet.iter() {
+                let received = self.received.get();
+                if received < buffer.len() {
+                    buffer[received] = byte;
+                }
+                self.received.set(received + 1);
+                if received == 1 {
+                    self.length
+                        .set((buffer[0] as usize) << 8 | buffer[1] as usize);
+                }
+                if received >= 1 && received + 1 == 2 + self.length.get() {
+                    complete = true;
+                    break;
+                }
+            }
+        });
+        if complete {
+            self.received.set(0);
+            self.handle();
+        }
+        true
+    }
+
+    fn packet_in(&self, packet: &mut [u8]) -> usize {
+        match self.response.take() {
+            Some(response) => {
+                packet[..response.len()].copy_from_slice(&response);
+                response.len()
+            }
+            None => 0,
+        }
+    }
+}
+
+impl<U: Update> UpdateClient for UsbUpdate<'a, U> {
+    fn begin_done(&self, result: ReturnCode) {
+        self.done(&[REQUEST_BEGIN], result);
+    }
+
+    fn write_done(&self, _length: usize, result: ReturnCode) {
+        self.done(&[REQUEST_DATA], result);
+    }
+
+    fn install_done(&self, result: ReturnCode) {
+        self.done(&[REQUEST_INSTALL, REQUEST_ROLLBACK], result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::net::udp::udp::UDPHeader;
+    use crate::storage_sim::{leak, Sim, SimFlash, SimPage, WriteMode, PAGE_SIZE};
+    use kernel::hil::flash::HasClient;
+    use std::cell::RefCell;
+    use std::vec;
+    use std::vec::Vec;
+
+    // A selector page, two slots of four pages, and an app after the region.
+    const NUM_PAGES: usize = 10;
+    const SLOT_PAGES: usize = 4;
+    const OTHER_APP: usize = 9 * PAGE_SIZE;
+
+    /// A TBF image of `length` bytes with a main TLV, filled with `fill`.
+    fn image(length: usize, fill: u8) -> Vec<u8> {
+        let mut image = vec![fill; length];
+        let words = [2 | 32 << 16, length as u32, 1, 0, 1 | 12 << 16, 32, 0, 1024];
+        let checksum = words.iter().fold(0, |checksum, word| checksum ^ word);
+        for (i, word) in words.iter().enumerate() {
+            write_u32(&mut image[4 * i..], if i == 3 { checksum } else { *word });
+        }
+        image
+    }
+
+    /// Erased flash with another app after the update region, kept across
+    /// reboots of the updates booted on it.
+    fn sim() -> &'static Sim<SimFlash<'static>> {
+        let mut storage = vec![0xff; PAGE_SIZE * NUM_PAGES];
+        storage[OTHER_APP..].copy_from_slice(&image(PAGE_SIZE, 0x33));
+        Sim::flash(storage, WriteMode::Nor)
+    }
+
+    /// The apps that `load_processes` finds, as offsets and sizes.
+    fn scan(sim: &Sim<SimFlash<'static>>) -> Vec<(usize, usize)> {
+        sim.storage
+            .map_storage(|storage| {
+                let mut apps = Vec::new();
+                let mut offset = 0;
+                while offset + PADDING_HEADER_SIZE <= storage.len() {
+                    let header = &storage[offset..];
+                    let header_size = header[2] as usize | (header[3] as usize) << 8;
+                    let total_size = read_u32(&header[4..8]) as usize;
+                    if header[0..2] != [2, 0]
+                        || header_size < PADDING_HEADER_SIZE
+                        || header_size >= total_size
+                        || checksum(&header[..header_size]) != read_u32(&header[12..16])
+                    {
+                        break;
+                    }
+                    if header_size > PADDING_HEADER_SIZE {
+                        apps.push((offset, total_size));
+                    }
+                    offset += total_size;
+                }
+                apps
+            })
+            .unwrap()
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Begun(ReturnCode),
+        Written(usize, ReturnCode),
+        Installed(ReturnCode),
+    }
+
+    struct TestClient {
+        events: RefCell<Vec<Event>>,
+    }
+
+    impl TestClient {
+        fn last(&self) -> Option<Event> {
+            self.events.borrow_mut().pop()
+        }
+    }
+
+    impl UpdateClient for TestClient {
+        fn begin_done(&self, result: ReturnCode) {
+            self.events.borrow_mut().push(Event::Begun(result));
+        }
+
+        fn write_done(&self, length: usize, result: ReturnCode) {
+            self.events
+                .borrow_mut()
+                .push(Event::Written(length, result));
+        }
+
+        fn install_done(&self, result: ReturnCode) {
+            self.events.borrow_mut().push(Event::Installed(result));
+        }
+    }
+
+    type SimUpdate = AppUpdate<'static, SimFlash<'static>>;
+
+    fn boot(sim: &'static Sim<SimFlash<'static>>) -> (&'static SimUpdate, &'static TestClient) {
+        let update = leak(AppUpdate::new(
+            sim.storage,
+            0,
+            SLOT_PAGES,
+            leak(SimPage::new()),
+            sim.deferred_caller,
+        ));
+        sim.storage.set_client(update);
+        update.set_deferred_call_handle(sim.deferred_caller.register(update).unwrap());
+        let client = leak(TestClient {
+            events: RefCell::new(Vec::new()),
+        });
+        assert_eq!(update.add_client(client), ReturnCode::SUCCESS);
+        (update, client)
+    }
+
+    fn write_image(
+        sim: &Sim<SimFlash<'static>>,
+        update: &SimUpdate,
+        client: &TestClient,
+        image: &[u8],
+        crc: u32,
+    ) {
+        assert_eq!(update.begin(image.len(), crc), ReturnCode::SUCCESS);
+        sim.pump();
+        assert_eq!(client.last(), Some(Event::Begun(ReturnCode::SUCCESS)));
+        let mut position = 0;
+        while position < image.len() {
+            assert_eq!(update.write(&image[position..]), ReturnCode::SUCCESS);
+            sim.pump();
+            match client.last() {
+                Some(Event::Written(length, ReturnCode::SUCCESS)) => position += length,
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+        assert_eq!(update.received(), image.len());
+    }
+
+    fn install(
+        sim: &Sim<SimFlash<'static>>,
+        update: &SimUpdate,
+        client: &TestClient,
+        image: &[u8],
+        signature: &[u8],
+    ) -> Option<Event> {
+        write_image(sim, update, client, image, crc32(0, image));
+        assert_eq!(update.install(signature), ReturnCode::SUCCESS);
+        sim.pump();
+        assert!(!update.in_session());
+        client.last()
+    }
+
+    fn rollback(
+        sim: &Sim<SimFlash<'static>>,
+        update: &SimUpdate,
+        client: &TestClient,
+    ) -> Option<Event> {
+        assert_eq!(update.rollback(), ReturnCode::SUCCESS);
+        sim.pump();
+        client.last()
+    }
+
+    const INSTALLED: Option<Event> = Some(Event::Installed(ReturnCode::SUCCESS));
+    const SLOT_0: usize = PAGE_SIZE;
+    const SLOT_1: usize = PAGE_SIZE + SLOT_PAGES * PAGE_SIZE;
+
+    #[test]
+    fn installs_images_in_alternating_slots() {
+        let sim = sim();
+        let (update, client) = boot(sim);
+        // Without a selector the kernel finds no apps in the region.
+        assert_eq!(scan(sim), []);
+
+        assert_eq!(
+            install(sim, update, client, &image(1000, 1), &[]),
+            INSTALLED
+        );
+        assert_eq!(scan(sim), [(SLOT_0, 1000), (OTHER_APP, PAGE_SIZE)]);
+
+        // An image that ends on a page boundary, with the padding header
+        // in the next page.
+        assert_eq!(
+            install(sim, update, client, &image(1024, 2), &[]),
+            INSTALLED
+        );
+        assert_eq!(scan(sim), [(SLOT_1, 1024), (OTHER_APP, PAGE_SIZE)]);
+
+        // An image that fills its slot, without a padding header.
+        let full = image(SLOT_PAGES * PAGE_SIZE, 3);
+        assert_eq!(install(sim, update, client, &full, &[]), INSTALLED);
+        assert_eq!(scan(sim), [(SLOT_0, full.len()), (OTHER_APP, PAGE_SIZE)]);
+        sim.storage.map_storage(|storage| {
+            assert_eq!(&storage[SLOT_0..SLOT_0 + full.len()], &full[..]);
+        });
+    }
+
+    #[test]
+    fn rolls_back_after_reboot() {
+        let sim = sim();
+        let (update, client) = boot(sim);
+        assert_eq!(
+            rollback(sim, update, client),
+            Some(Event::Installed(ReturnCode::EINVAL))
+        );
+        assert_eq!(install(sim, update, client, &image(600, 1), &[]), INSTALLED);
+        assert_eq!(install(sim, update, client, &image(800, 2), &[]), INSTALLED);
+
+        // The selector is read again after a reboot.
+        let (update, client) = boot(sim);
+        assert_eq!(rollback(sim, update, client), INSTALLED);
+        assert_eq!(scan(sim), [(SLOT_0, 600), (OTHER_APP, PAGE_SIZE)]);
+        assert_eq!(rollback(sim, update, client), INSTALLED);
+        assert_eq!(scan(sim), [(SLOT_1, 800), (OTHER_APP, PAGE_SIZE)]);
+
+        // An aborted update overwrites the start of the previous image, which
+        // then no longer has a valid header.
+        write_image(sim, update, client, &image(700, 3), 0);
+        assert_eq!(update.rollback(), ReturnCode::EBUSY);
+        assert_eq!(update.abort(), ReturnCode::SUCCESS);
+        assert_eq!(update.write(&[0; 4]), ReturnCode::EINVAL);
+        assert_eq!(
+            rollback(sim, update, client),
+            Some(Event::Installed(ReturnCode::EINVAL))
+        );
+        assert_eq!(scan(sim), [(SLOT_1, 800), (OTHER_APP, PAGE_SIZE)]);
+    }
+
+    #[test]
+    fn checks_images_before_installing() {
+        let sim = sim();
+        let (update, client) = boot(sim);
+        let slot_size = SLOT_PAGES * PAGE_SIZE;
+        for &length in [16, 1002, slot_size + 4, slot_size - PADDING_HEADER_SIZE].iter() {
+            assert_eq!(update.begin(length, 0), ReturnCode::EINVAL);
+        }
+        assert_eq!(
+            install(sim, update, client, &image(1000, 1), &[]),
+            INSTALLED
+        );
+
+        // Wrong CRC.
+        let good = image(1200, 2);
+        write_image(sim, update, client, &good, 0x1234);
+        assert_eq!(update.install(&[]), ReturnCode::SUCCESS);
+        sim.pump();
+        assert_eq!(client.last(), Some(Event::Installed(ReturnCode::FAIL)));
+        assert!(!update.in_session());
+
+        // Not a valid TBF header.
+        let mut bad = good.clone();
+        bad[12] ^= 1;
+        assert_eq!(
+            install(sim, update, client, &bad, &[]),
+            Some(Event::Installed(ReturnCode::EINVAL))
+        );
+        assert_eq!(scan(sim), [(SLOT_0, 1000), (OTHER_APP, PAGE_SIZE)]);
+
+        // Incomplete image.
+        assert_eq!(
+            update.begin(good.len(), crc32(0, &good)),
+            ReturnCode::SUCCESS
+        );
+        sim.pump();
+        assert_eq!(update.write(&good[..100]), ReturnCode::SUCCESS);
+        sim.pump();
+        assert_eq!(
+            client.last(),
+            Some(Event::Written(100, ReturnCode::SUCCESS))
+        );
+        assert_eq!(update.install(&[]), ReturnCode::EINVAL);
+    }
+
+    /// Signs images with the sum of their bytes.
+    struct SumVerifier {
+        sum: Cell<u32>,
+    }
+
+    impl Verifier for SumVerifier {
+        fn reset(&self) {
+            self.sum.set(0);
+        }
+
+        fn update(&self, data: &[u8]) {
+            let sum = data.iter().fold(0u32, |sum, byte| sum + *byte as u32);
+            self.sum.set(self.sum.get().wrapping_add(sum));
+        }
+
+        fn verify(&self, signature: &[u8]) -> bool {
+            signature == self.sum.get().to_le_bytes()
+        }
+    }
+
+    #[test]
+    fn checks_signatures() {
+        let sim = sim();
+        let (update, client) = boot(sim);
+        update.set_verifier(leak(SumVerifier { sum: Cell::new(0) }));
+        let image = image(900, 7);
+        let signature = image
+            .iter()
+            .fold(0u32, |sum, byte| sum + *byte as u32)
+            .to_le_bytes();
+        let mut forged = signature;
+        forged[0] ^= 1;
+        assert_eq!(
+            install(sim, update, client, &image, &forged),
+            Some(Event::Installed(ReturnCode::FAIL))
+        );
+        assert_eq!(scan(sim), []);
+        assert_eq!(install(sim, update, client, &image, &signature), INSTALLED);
+        assert_eq!(scan(sim), [(SLOT_0, 900), (OTHER_APP, PAGE_SIZE)]);
+    }
+
+    struct TestSender {
+        sent: RefCell<Vec<(IPAddr, u16, Vec<u8>)>>,
+    }
+
+    impl UDPSender<'static> for TestSender {
+        fn set_client(&self, _client: &'static UDPSendClient) {}
+
+        fn send_to(&self, dest: IPAddr, dst_port: u16, _src_port: u16, buf: &[u8]) -> ReturnCode {
+            self.sent.borrow_mut().push((dest, dst_port, buf.to_vec()));
+            ReturnCode::SUCCESS
+        }
+
+        fn send(&self, _dest: IPAddr, _udp_header: UDPHeader, _buf: &[u8]) -> ReturnCode {
+            ReturnCode::ENOSUPPORT
+        }
+    }
+
+    fn data(offset: usize, bytes: &[u8]) -> Vec<u8> {
+        let mut message = vec![REQUEST_DATA, 0, 0, (offset >> 8) as u8, offset as u8];
+        message.extend_from_slice(bytes);
+        message
+    }
+
+    fn begin(image: &[u8]) -> Vec<u8> {
+        let mut message = vec![REQUEST_BEGIN];
+        for &value in [image.len() as u32, crc32(0, image)].iter() {
+            message.extend_from_slice(&value.to_be_bytes());
+        }
+        message
+    }
+
+    #[test]
+    fn udp_updates() {
+        const PORT: u16 = 4000;
+        let sim = sim();
+        let (update, _) = boot(sim);
+        let sender = leak(TestSender {
+            sent: RefCell::new(Vec::new()),
+        });
+        let udp = leak(UdpUpdate::new(update, sender, PORT));
+        update.add_client(udp);
+        let peer = (IPAddr([1; 16]), 5000);
+        let stranger = (IPAddr([2; 16]), 5000);
+        let request = |from: (IPAddr, u16), message: &[u8]| {
+            udp.receive(from.0, IPAddr([0; 16]), from.1, PORT, message);
+            sim.pump();
+            let (dest, port, response) = sender.sent.borrow_mut().pop().unwrap();
+            assert_eq!((dest, port), from);
+            udp.send_done(ReturnCode::SUCCESS);
+            response
+        };
+        let image = image(1000, 5);
+
+        assert_eq!(request(peer, &begin(&image)), vec![0x81, 0, 0, 0, 0, 0]);
+        // Another request while one is in progress is dropped.
+        udp.receive(peer.0, IPAddr([0; 16]), peer.1, PORT, &[REQUEST_ROLLBACK]);
+        udp.receive(peer.0, IPAddr([0; 16]), peer.1, PORT, &[REQUEST_ROLLBACK]);
+        sim.pump();
+        assert_eq!(sender.sent.borrow_mut().len(), 1);
+        sender.sent.borrow_mut().clear();
+        udp.send_done(ReturnCode::SUCCESS);
+
+        assert_eq!(
+            request(peer, &data(0, &image[..300])),
+            vec![0x82, 0, 0, 0, 1, 44]
+        );
+        // Only the peer that began the session can write to it, and only
+        // from the data received so far.
+        let einval = isize::from(ReturnCode::EINVAL) as u8;
+        assert_eq!(
+            request(stranger, &data(300, &image[300..400])),
+            vec![0x82, einval, 0, 0, 1, 44]
+        );
+        assert_eq!(
+            request(peer, &data(400, &image[400..500])),
+            vec![0x82, einval, 0, 0, 1, 44]
+        );
+        // Data received before is skipped, and a write stops at the end of
+        // a page.
+        assert_eq!(
+            request(peer, &data(200, &image[200..700])),
+            vec![0x82, 0, 0, 0, 2, 0]
+        );
+        assert_eq!(
+            request(peer, &data(512, &image[512..])),
+            vec![0x82, 0, 0, 0, 3, 232]
+        );
+        assert_eq!(
+            request(peer, &data(0, &image[..100])),
+            vec![0x82, 0, 0, 0, 3, 232]
+        );
+        assert_eq!(
+            request(stranger, &[REQUEST_INSTALL]),
+            vec![0x83, einval, 0, 0, 3, 232]
+        );
+        assert_eq!(
+            request(peer, &[REQUEST_INSTALL]),
+            vec![0x83, 0, 0, 0, 3, 232]
+        );
+        assert_eq!(scan(sim), [(SLOT_0, 1000), (OTHER_APP, PAGE_SIZE)]);
+        assert_eq!(
+            request(stranger, &[REQUEST_ROLLBACK]),
+            vec![0x84, einval, 0, 0, 3, 232]
+        );
+    }
+
+    struct TestUsb {
+        resumed: Cell<usize>,
+    }
+
+    impl BulkResume for TestUsb {
+        fn resume_out(&self) {
+            self.resumed.set(self.resumed.get() + 1);
+        }
+
+        fn resume_in(&self) {}
+    }
+
+    #[test]
+    fn usb_updates() {
+        let sim = sim();
+        let (update, _) = boot(sim);
+        let usb = leak(UsbUpdate::new(update, leak([0; 256])));
+        let resume = leak(TestUsb {
+            resumed: Cell::new(0),
+        });
+        usb.set_usb(resume);
+        update.add_client(usb);
+        let transfer = |message: &[u8]| {
+            let mut frame = vec![(message.len() >> 8) as u8, message.len() as u8];
+            frame.extend_from_slice(message);
+            for packet in frame.chunks(8) {
+                assert!(usb.packet_out(packet));
+            }
+            sim.pump();
+            let mut packet = [0; 8];
+            let length = usb.packet_in(&mut packet);
+            assert_eq!(usb.packet_in(&mut packet), 0);
+            packet[..length].to_vec()
+        };
+        let image = image(1000, 9);
+
+        assert_eq!(transfer(&begin(&image)), vec![0, 6, 0x81, 0, 0, 0, 0, 0]);
+        // Requests too long for the buffer are refused.
+        let esize = isize::from(ReturnCode::ESIZE) as u8;
+        assert_eq!(
+            transfer(&data(0, &image[..300])),
+            vec![0, 6, 0x82, esize, 0, 0, 0, 0]
+        );
+        // Writes of requests that cross a page boundary continue on the
+        // next page.
+        for offset in (0..image.len()).step_by(200) {
+            let received = (offset + 200) as u32;
+            let mut expected = vec![0, 6, 0x82, 0];
+            expected.extend_from_slice(&received.to_be_bytes());
+            assert_eq!(
+                transfer(&data(offset, &image[offset..offset + 200])),
+                expected
+            );
+        }
+        assert_eq!(
+            transfer(&[REQUEST_INSTALL]),
+            vec![0, 6, 0x83, 0, 0, 0, 3, 232]
+        );
+        assert_eq!(scan(sim), [(SLOT_0, 1000), (OTHER_APP, PAGE_SIZE)]);
+
+        // Packets are held back while a request is in progress.
+        assert!(usb.packet_out(&[0, 1, REQUEST_ROLLBACK]));
+        assert!(!usb.packet_out(&[0, 1, REQUEST_ROLLBACK]));
+        let resumed = resume.resumed.get();
+        sim.pump();
+        assert_eq!(resume.resumed.get(), resumed + 1);
+        let mut packet = [0; 8];
+        assert_eq!(usb.packet_in(&mut packet), 8);
+        let einval = isize::from(ReturnCode::EINVAL) as u8;
+        assert_eq!(packet, [0, 6, 0x84, einval, 0, 0, 3, 232]);
+    }
+}
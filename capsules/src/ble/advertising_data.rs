@@ -0,0 +1,186 @@
+//! Parsing of advertising data (AD) structures.
+//!
+//! Advertising and scan response data is a sequence of AD structures, each
+//! made of a length byte, an AD type and `length - 1` bytes of data. These
+//! helpers let kernel clients look into received advertisements, e.g. to only
+//! pass on the ones advertising a certain service, without copying every raw
+//! PDU to userspace.
+//!
+//! ```rust
+//! use capsules::ble::advertising_data;
+//!
+//! if let Some(ad) = advertising_data::advertising_data(pdu) {
+//!     if advertising_data::has_service_uuid16(ad, 0x180d) {
+//!         // A heart rate sensor
+//!     }
+//! }
+//! ```
+
+// Bluetooth Assigned Numbers, Generic Access Profile data types
+pub const AD_TYPE_FLAGS: u8 = 0x01;
+pub const AD_TYPE_INCOMPLETE_UUID16: u8 = 0x02;
+pub const AD_TYPE_COMPLETE_UUID16: u8 = 0x03;
+pub const AD_TYPE_INCOMPLETE_UUID128: u8 = 0x06;
+pub const AD_TYPE_COMPLETE_UUID128: u8 = 0x07;
+pub const AD_TYPE_SHORTENED_LOCAL_NAME: u8 = 0x08;
+pub const AD_TYPE_COMPLETE_LOCAL_NAME: u8 = 0x09;
+pub const AD_TYPE_TX_POWER_LEVEL: u8 = 0x0a;
+pub const AD_TYPE_SERVICE_DATA_UUID16: u8 = 0x16;
+pub const AD_TYPE_MANUFACTURER_DATA: u8 = 0xff;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3
+const ADV_IND: u8 = 0b0000;
+const ADV_NONCONN_IND: u8 = 0b0010;
+const SCAN_RSP: u8 = 0b0100;
+const ADV_SCAN_IND: u8 = 0b0110;
+const ADV_HEADER_TYPE_MASK: u8 = 0x0f;
+const ADV_ADDRESS_LEN: usize = 6;
+
+/// A single AD structure.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AdStructure<'a> {
+    pub ad_type: u8,
+    pub data: &'a [u8],
+}
+
+/// Iterator over the AD structures of advertising data. Iteration stops at
+/// the first structure of length 0 (padding) or one that does not fit.
+pub struct AdStructures<'a> {
+    data: &'a [u8],
+}
+
+impl Iterator for AdStructures<'a> {
+    type Item = AdStructure<'a>;
+
+    fn next(&mut self) -> Option<AdStructure<'a>> {
+        let len = *self.data.first()? as usize;
+        if len == 0 || len + 1 > self.data.len() {
+            self.data = &[];
+            return None;
+        }
+        let structure = AdStructure {
+            ad_type: self.data[1],
+            data: &self.data[2..len + 1],
+        };
+        self.data = &self.data[len + 1..];
+        Some(structure)
+    }
+}
+
+pub fn ad_structures(data: &[u8]) -> AdStructures {
+    AdStructures { data: data }
+}
+
+/// The advertising data of an advertising channel PDU (header included) that
+/// carries any, i.e. `ADV_IND`, `ADV_NONCONN_IND`, `ADV_SCAN_IND` and
+/// `SCAN_RSP`.
+pub fn advertising_data(pdu: &[u8]) -> Option<&[u8]> {
+    if pdu.len() < 2 + ADV_ADDRESS_LEN {
+        return None;
+    }
+    match pdu[0] & ADV_HEADER_TYPE_MASK {
+        ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND | SCAN_RSP => {
+            let end = 2 + (pdu[1] as usize & 0x3f);
+            if end < 2 + ADV_ADDRESS_LEN || end > pdu.len() {
+                None
+            } else {
+                Some(&pdu[2 + ADV_ADDRESS_LEN..end])
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The first AD structure of the given type.
+pub fn find(data: &[u8], ad_type: u8) -> Option<&[u8]> {
+    ad_structures(data)
+        .find(|s| s.ad_type == ad_type)
+        .map(|s| s.data)
+}
+
+pub fn flags(data: &[u8]) -> Option<u8> {
+    find(data, AD_TYPE_FLAGS).and_then(|flags| flags.first().map(|f| *f))
+}
+
+/// The local name and whether it is complete rather than shortened.
+pub fn local_name(data: &[u8]) -> Option<(&[u8], bool)> {
+    ad_structures(data).find_map(|s| match s.ad_type {
+        AD_TYPE_COMPLETE_LOCAL_NAME => Some((s.data, true)),
+        AD_TYPE_SHORTENED_LOCAL_NAME => Some((s.data, false)),
+        _ => None,
+    })
+}
+
+pub fn tx_power_level(data: &[u8]) -> Option<i8> {
+    find(data, AD_TYPE_TX_POWER_LEVEL).and_then(|power| power.first().map(|p| *p as i8))
+}
+
+/// The company identifier and data of the manufacturer specific data.
+pub fn manufacturer_data(data: &[u8]) -> Option<(u16, &[u8])> {
+    find(data, AD_TYPE_MANUFACTURER_DATA).and_then(|d| {
+        if d.len() < 2 {
+            None
+        } else {
+            Some((u16::from_le_bytes([d[0], d[1]]), &d[2..]))
+        }
+    })
+}
+
+/// Whether the complete or incomplete list of 16-bit service UUIDs contains
+/// `uuid`.
+pub fn has_service_uuid16(data: &[u8], uuid: u16) -> bool {
+    ad_structures(data)
+        .filter(|s| s.ad_type == AD_TYPE_COMPLETE_UUID16 || s.ad_type == AD_TYPE_INCOMPLETE_UUID16)
+        .any(|s| {
+            s.data
+                .chunks(2)
+                .any(|c| c.len() == 2 && u16::from_le_bytes([c[0], c[1]]) == uuid)
+        })
+}
+
+/// Whether the complete or incomplete list of 128-bit service UUIDs contains
+/// `uuid`, given in little endian.
+pub fn has_service_uuid128(data: &[u8], uuid: &[u8; 16]) -> bool {
+    ad_structures(data)
+        .filter(|s| {
+            s.ad_type == AD_TYPE_COMPLETE_UUID128 || s.ad_type == AD_TYPE_INCOMPLETE_UUID128
+        })
+        .any(|s| s.data.chunks(16).any(|c| c == &uuid[..]))
+}
+
+/// Service data for a 16-bit service UUID.
+pub fn service_data_uuid16(data: &[u8], uuid: u16) -> Option<&[u8]> {
+    ad_structures(data)
+        .filter(|s| s.ad_type == AD_TYPE_SERVICE_DATA_UUID16 && s.data.len() >= 2)
+        .find(|s| u16::from_le_bytes([s.data[0], s.data[1]]) == uuid)
+        .map(|s| &s.data[2..])
+}
+
+/// A simple filter on advertising data.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AdFilter {
+    /// Let everything through
+    None,
+    /// The 16-bit service UUID is listed
+    ServiceUuid16(u16),
+    /// The manufacturer specific data has the company identifier
+    CompanyId(u16),
+    /// All of the flags are set
+    Flags(u8),
+    /// The local name (complete or shortened) is present
+    HasName,
+}
+
+impl AdFilter {
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match *self {
+            AdFilter::None => true,
+            AdFilter::ServiceUuid16(uuid) => has_service_uuid16(data, uuid),
+            AdFilter::CompanyId(company) => {
+                manufacturer_data(data).map_or(false, |(id, _)| id == company)
+            }
+            AdFilter::Flags(mask) => flags(data).map_or(false, |f| f & mask == mask),
+            AdFilter::HasName => local_name(data).is_some(),
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! System call driver for BLE connections in the peripheral role.
+//!
+//! A process can advertise as connectable and, once a central (e.g. a phone)
+//! connects, exchange data through the user characteristic of the GATT
+//! server. Only one process can use the driver at a time; it is claimed by
+//! the first process that starts advertising and released when that process
+//! stops advertising or the connection is closed.
+//!
+//! ### Allow system call
+//!
+//! * 0: Advertising data, as AD structures of at most 31 bytes
+//! * 1: Buffer for the user characteristic value. New values written by the
+//!      peer are copied into it, and command 3 takes the value from it.
+//!
+//! ### Subscribe system call
+//!
+//! * 0: Connection events. The callback receives the event and an argument:
+//!   - `(0, 0)`: a central connected
+//!   - `(1, reason)`: the connection was closed with the given HCI error code
+//!   - `(2, len)`: the peer wrote `len` bytes to the user characteristic
+//!
+//! ### Command system call
+//!
+//! * 0: Driver check
+//! * 1: Start connectable advertising every `data` ms. Returns `EBUSY` while
+//!      the BLE advertising driver uses the radio.
+//! * 2: Stop advertising, or terminate the connection
+//! * 3: Set the user characteristic to the first `data` bytes of buffer 1
+//! * 4: Notify the peer of the current value of the user characteristic
+//! * 5: Get the state: 0 for standby, 1 for advertising and 2 for connected
+
+use crate::ble::gatt::{self, GattClient, GattServer};
+use crate::ble::link_layer::{self, Peripheral};
+use core::cmp;
+use kernel::common::cells::OptionalCell;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::BleConnection as usize;
+
+const EVENT_CONNECTED: usize = 0;
+const EVENT_DISCONNECTED: usize = 1;
+const EVENT_WRITTEN: usize = 2;
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    adv_data: Option<AppSlice<Shared, u8>>,
+    value: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct BlePeripheralDriver<'a> {
+    link: &'a Peripheral<'a>,
+    gatt: &'a GattServer<'a>,
+    apps: Grant<App>,
+    /// The process currently using the driver
+    owner: OptionalCell<AppId>,
+}
+
+impl BlePeripheralDriver<'a> {
+    pub fn new(
+        link: &'a Peripheral<'a>,
+        gatt: &'a GattServer<'a>,
+        grant: Grant<App>,
+    ) -> BlePeripheralDriver<'a> {
+        BlePeripheralDriver {
+            link: link,
+            gatt: gatt,
+            apps: grant,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    /// Whether `appid` may use the driver, claiming it if it is free.
+    fn claim(&self, appid: AppId) -> bool {
+        let owned_by_other = self.owner.map_or(false, |owner| *owner != appid);
+        if owned_by_other {
+            // Release the driver if its owner died
+            let alive = self
+                .owner
+                .map_or(false, |owner| self.apps.enter(*owner, |_, _| ()).is_ok());
+            if alive {
+                return false;
+            }
+        }
+        self.owner.set(appid);
+        true
+    }
+
+    fn is_owner(&self, appid: AppId) -> bool {
+        self.owner.map_or(false, |owner| *owner == appid)
+    }
+
+    fn schedule(&self, event: usize, arg: usize) {
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(*owner, |app, _| {
+                app.callback.map(|mut cb| cb.schedule(event, arg, 0));
+            });
+        });
+    }
+}
+
+impl GattClient for BlePeripheralDriver<'a> {
+    fn connected(&self) {
+        self.schedule(EVENT_CONNECTED, 0);
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.schedule(EVENT_DISCONNECTED, reason as usize);
+        self.owner.clear();
+    }
+
+    fn value_written(&self, value: &[u8]) {
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(*owner, |app, _| {
+                let len = app.value.as_mut().map_or(0, |buf| {
+                    let len = cmp::min(buf.len(), value.len());
+                    buf.as_mut()[..len].copy_from_slice(&value[..len]);
+                    len
+                });
+                app.callback
+                    .map(|mut cb| cb.schedule(EVENT_WRITTEN, len, 0));
+            });
+        });
+    }
+}
+
+impl Driver for BlePeripheralDriver<'a> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            // Advertising data
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    if slice.as_ref().map_or(0, |s| s.len()) > link_layer::MAX_ADV_DATA_LEN {
+                        return ReturnCode::ESIZE;
+                    }
+                    app.adv_data = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // User characteristic value
+            1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.value = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            // Start connectable advertising
+            1 => {
+                if !self.claim(appid) {
+                    return ReturnCode::EBUSY;
+                }
+                let result = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        let adv_data = app.adv_data.as_ref().map_or(&[][..], |d| d.as_ref());
+                        self.link.start_advertising(adv_data, data as u32)
+                    })
+                    .unwrap_or_else(|err| err.into());
+                if result != ReturnCode::SUCCESS && !self.link.is_connected() {
+                    self.owner.clear();
+                }
+                result
+            }
+
+            // Stop advertising or disconnect
+            2 => {
+                if !self.is_owner(appid) {
+                    return ReturnCode::EBUSY;
+                }
+                if self.link.is_connected() {
+                    self.link.disconnect()
+                } else {
+                    self.owner.clear();
+                    self.link.stop_advertising()
+                }
+            }
+
+            // Set the user characteristic value
+            3 => {
+                if self.owner.is_some() && !self.is_owner(appid) {
+                    return ReturnCode::EBUSY;
+                }
+                if data > gatt::MAX_VALUE_LEN {
+                    return ReturnCode::ESIZE;
+                }
+                self.apps
+                    .enter(appid, |app, _| {
+                        app.value.as_ref().map_or(ReturnCode::ENOMEM, |value| {
+                            if data > value.len() {
+                                ReturnCode::EINVAL
+                            } else {
+                                self.gatt.set_value(&value.as_ref()[..data])
+                            }
+                        })
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            // Notify the peer
+            4 => {
+                if !self.is_owner(appid) {
+                    return ReturnCode::EBUSY;
+                }
+                self.gatt.notify()
+            }
+
+            // Get the state
+            5 => {
+                let state = if self.link.is_connected() {
+                    2
+                } else if self.link.is_advertising() {
+                    1
+                } else {
+                    0
+                };
+                ReturnCode::SuccessWithValue { value: state }
+            }
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
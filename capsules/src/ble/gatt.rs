@@ -0,0 +1,579 @@
+//! Minimal GATT server on top of the attribute protocol.
+//!
+//! The attribute database is fixed and contains three services:
+//!
+//! ```text
+//! Handle  Type                        Value
+//!      1  Primary Service             Generic Access (0x1800)
+//!      2  Characteristic              Read, handle 3, Device Name (0x2A00)
+//!      3  Device Name                 name given at construction
+//!      4  Characteristic              Read, handle 5, Appearance (0x2A01)
+//!      5  Appearance                  0x0000 (unknown)
+//!      6  Primary Service             Generic Attribute (0x1801)
+//!      7  Primary Service             user service UUID
+//!      8  Characteristic              Read, Write, Write Without Response,
+//!                                     Notify, handle 9, user characteristic UUID
+//!      9  user characteristic         up to `MAX_VALUE_LEN` bytes
+//!     10  Client Characteristic Configuration
+//! ```
+//!
+//! The value of the user characteristic is read and written by the peer and
+//! by the kernel through `set_value`, `value` and `notify`. A `GattClient` is
+//! told about connections and about writes from the peer.
+//!
+//! The ATT_MTU is fixed to the default of 23 bytes, so every request and
+//! response fits into a single link layer PDU.
+
+use crate::ble::l2cap::{self, ChannelClient, L2cap};
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::ReturnCode;
+
+/// ATT_MTU, BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part G], section 5.2.1
+pub const ATT_MTU: usize = 23;
+/// Maximum length of the user characteristic, as much as fits in a
+/// notification.
+pub const MAX_VALUE_LEN: usize = ATT_MTU - 3;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part F], section 3.4
+const ATT_ERROR_RSP: u8 = 0x01;
+const ATT_EXCHANGE_MTU_REQ: u8 = 0x02;
+const ATT_EXCHANGE_MTU_RSP: u8 = 0x03;
+const ATT_FIND_INFORMATION_REQ: u8 = 0x04;
+const ATT_FIND_INFORMATION_RSP: u8 = 0x05;
+const ATT_FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+const ATT_FIND_BY_TYPE_VALUE_RSP: u8 = 0x07;
+const ATT_READ_BY_TYPE_REQ: u8 = 0x08;
+const ATT_READ_BY_TYPE_RSP: u8 = 0x09;
+const ATT_READ_REQ: u8 = 0x0a;
+const ATT_READ_RSP: u8 = 0x0b;
+const ATT_READ_BLOB_REQ: u8 = 0x0c;
+const ATT_READ_BLOB_RSP: u8 = 0x0d;
+const ATT_READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+const ATT_READ_BY_GROUP_TYPE_RSP: u8 = 0x11;
+const ATT_WRITE_REQ: u8 = 0x12;
+const ATT_WRITE_RSP: u8 = 0x13;
+const ATT_HANDLE_VALUE_NTF: u8 = 0x1b;
+const ATT_WRITE_CMD: u8 = 0x52;
+/// Commands never get a response, not even an error
+const ATT_COMMAND_FLAG: u8 = 0x40;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part F], section 3.4.1.1
+const ATT_ERROR_INVALID_HANDLE: u8 = 0x01;
+const ATT_ERROR_WRITE_NOT_PERMITTED: u8 = 0x03;
+const ATT_ERROR_INVALID_PDU: u8 = 0x04;
+const ATT_ERROR_REQUEST_NOT_SUPPORTED: u8 = 0x06;
+const ATT_ERROR_INVALID_OFFSET: u8 = 0x07;
+const ATT_ERROR_ATTRIBUTE_NOT_FOUND: u8 = 0x0a;
+const ATT_ERROR_INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0d;
+const ATT_ERROR_UNSUPPORTED_GROUP_TYPE: u8 = 0x10;
+
+// Bluetooth Assigned Numbers, GATT declarations, services and characteristics
+const UUID_PRIMARY_SERVICE: u16 = 0x2800;
+const UUID_CHARACTERISTIC: u16 = 0x2803;
+const UUID_CLIENT_CHARACTERISTIC_CONFIGURATION: u16 = 0x2902;
+const UUID_GENERIC_ACCESS: u16 = 0x1800;
+const UUID_GENERIC_ATTRIBUTE: u16 = 0x1801;
+const UUID_DEVICE_NAME: u16 = 0x2a00;
+const UUID_APPEARANCE: u16 = 0x2a01;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part G], section 3.3.1.1
+const PROPERTY_READ: u8 = 0x02;
+const PROPERTY_WRITE_WITHOUT_RESPONSE: u8 = 0x04;
+const PROPERTY_WRITE: u8 = 0x08;
+const PROPERTY_NOTIFY: u8 = 0x10;
+const CCCD_NOTIFICATION: u8 = 0x01;
+
+const HANDLE_GAP_SERVICE: u16 = 1;
+const HANDLE_DEVICE_NAME_DECLARATION: u16 = 2;
+const HANDLE_DEVICE_NAME: u16 = 3;
+const HANDLE_APPEARANCE_DECLARATION: u16 = 4;
+const HANDLE_APPEARANCE: u16 = 5;
+const HANDLE_GATT_SERVICE: u16 = 6;
+const HANDLE_USER_SERVICE: u16 = 7;
+const HANDLE_USER_DECLARATION: u16 = 8;
+const HANDLE_USER_VALUE: u16 = 9;
+const HANDLE_USER_CCCD: u16 = 10;
+const LAST_HANDLE: u16 = HANDLE_USER_CCCD;
+
+/// Bluetooth Base UUID, 00000000-0000-1000-8000-00805F9B34FB, in little
+/// endian
+const BASE_UUID: [u8; 16] = [
+    0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Uuid {
+    Uuid16(u16),
+    /// 128-bit UUID in little endian, as sent on air
+    Uuid128([u8; 16]),
+}
+
+impl Uuid {
+    /// Parse a 16-bit or 128-bit UUID in little endian.
+    pub fn from_le_bytes(bytes: &[u8]) -> Option<Uuid> {
+        match bytes.len() {
+            2 => Some(Uuid::Uuid16(u16::from_le_bytes([bytes[0], bytes[1]]))),
+            16 => {
+                let mut uuid = [0; 16];
+                uuid.copy_from_slice(bytes);
+                Some(Uuid::Uuid128(uuid))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            Uuid::Uuid16(_) => 2,
+            Uuid::Uuid128(_) => 16,
+        }
+    }
+
+    /// Write the UUID in little endian to `buf` and return its length.
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        match *self {
+            Uuid::Uuid16(uuid) => buf[..2].copy_from_slice(&uuid.to_le_bytes()),
+            Uuid::Uuid128(uuid) => buf[..16].copy_from_slice(&uuid),
+        }
+        self.len()
+    }
+
+    fn to_uuid128(&self) -> [u8; 16] {
+        match *self {
+            Uuid::Uuid16(uuid) => {
+                let mut full = BASE_UUID;
+                full[12..14].copy_from_slice(&uuid.to_le_bytes());
+                full
+            }
+            Uuid::Uuid128(uuid) => uuid,
+        }
+    }
+
+    /// Compare two UUIDs, regardless of whether they are given in their 16-bit
+    /// or 128-bit form.
+    pub fn matches(&self, other: &Uuid) -> bool {
+        self.to_uuid128() == other.to_uuid128()
+    }
+}
+
+/// Client of the GATT server, usually a system call driver.
+pub trait GattClient {
+    fn connected(&self);
+    fn disconnected(&self, reason: u8);
+    /// The peer wrote a new value to the user characteristic.
+    fn value_written(&self, value: &[u8]);
+}
+
+pub struct GattServer<'a> {
+    l2cap: &'a L2cap<'a>,
+    client: OptionalCell<&'a GattClient>,
+    device_name: &'static [u8],
+    service: Uuid,
+    characteristic: Uuid,
+    value: MapCell<[u8; MAX_VALUE_LEN]>,
+    value_len: Cell<usize>,
+    notifications_enabled: Cell<bool>,
+    connected: Cell<bool>,
+}
+
+impl GattServer<'a> {
+    pub fn new(
+        l2cap: &'a L2cap<'a>,
+        device_name: &'static [u8],
+        service: Uuid,
+        characteristic: Uuid,
+    ) -> GattServer<'a> {
+        GattServer {
+            l2cap: l2cap,
+            client: OptionalCell::empty(),
+            device_name: device_name,
+            service: service,
+            characteristic: characteristic,
+            value: MapCell::new([0; MAX_VALUE_LEN]),
+            value_len: Cell::new(0),
+            notifications_enabled: Cell::new(false),
+            connected: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a GattClient) {
+        self.client.set(client);
+    }
+
+    /// Set the value of the user characteristic.
+    pub fn set_value(&self, value: &[u8]) -> ReturnCode {
+        if value.len() > MAX_VALUE_LEN {
+            return ReturnCode::ESIZE;
+        }
+        self.value.map(|v| v[..value.len()].copy_from_slice(value));
+        self.value_len.set(value.len());
+        ReturnCode::SUCCESS
+    }
+
+    /// Copy the value of the user characteristic to `buf` and return its
+    /// length.
+    pub fn value(&self, buf: &mut [u8]) -> usize {
+        let len = cmp::min(self.value_len.get(), buf.len());
+        self.value.map(|v| buf[..len].copy_from_slice(&v[..len]));
+        len
+    }
+
+    /// Send the value of the user characteristic to the peer as a
+    /// notification. Returns `EOFF` if the peer has not enabled notifications.
+    pub fn notify(&self) -> ReturnCode {
+        if !self.connected.get() || !self.notifications_enabled.get() {
+            return ReturnCode::EOFF;
+        }
+        let mut ntf = [0; ATT_MTU];
+        ntf[0] = ATT_HANDLE_VALUE_NTF;
+        ntf[1..3].copy_from_slice(&HANDLE_USER_VALUE.to_le_bytes());
+        let len = self.value(&mut ntf[3..]);
+        self.l2cap.send(l2cap::CID_ATT, &ntf[..3 + len])
+    }
+
+    fn attribute_type(&self, handle: u16) -> Option<Uuid> {
+        match handle {
+            HANDLE_GAP_SERVICE | HANDLE_GATT_SERVICE | HANDLE_USER_SERVICE => {
+                Some(Uuid::Uuid16(UUID_PRIMARY_SERVICE))
+            }
+            HANDLE_DEVICE_NAME_DECLARATION
+            | HANDLE_APPEARANCE_DECLARATION
+            | HANDLE_USER_DECLARATION => Some(Uuid::Uuid16(UUID_CHARACTERISTIC)),
+            HANDLE_DEVICE_NAME => Some(Uuid::Uuid16(UUID_DEVICE_NAME)),
+            HANDLE_APPEARANCE => Some(Uuid::Uuid16(UUID_APPEARANCE)),
+            HANDLE_USER_VALUE => Some(self.characteristic),
+            HANDLE_USER_CCCD => Some(Uuid::Uuid16(UUID_CLIENT_CHARACTERISTIC_CONFIGURATION)),
+            _ => None,
+        }
+    }
+
+    /// Last handle of the service starting at `handle`.
+    fn group_end(&self, handle: u16) -> u16 {
+        match handle {
+            HANDLE_GAP_SERVICE => HANDLE_APPEARANCE,
+            HANDLE_GATT_SERVICE => HANDLE_GATT_SERVICE,
+            _ => LAST_HANDLE,
+        }
+    }
+
+    /// Call `f` with the value of the attribute.
+    fn with_value<F, R>(&self, handle: u16, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        // Large enough for a characteristic declaration with a 128-bit UUID
+        let mut buf = [0; MAX_VALUE_LEN];
+        let len = match handle {
+            HANDLE_GAP_SERVICE => Uuid::Uuid16(UUID_GENERIC_ACCESS).encode(&mut buf),
+            HANDLE_GATT_SERVICE => Uuid::Uuid16(UUID_GENERIC_ATTRIBUTE).encode(&mut buf),
+            HANDLE_USER_SERVICE => self.service.encode(&mut buf),
+            // Characteristic declaration: properties (1), value handle (2),
+            // UUID (2 or 16)
+            HANDLE_DEVICE_NAME_DECLARATION => {
+                buf[0] = PROPERTY_READ;
+                buf[1..3].copy_from_slice(&HANDLE_DEVICE_NAME.to_le_bytes());
+                3 + Uuid::Uuid16(UUID_DEVICE_NAME).encode(&mut buf[3..])
+            }
+            HANDLE_APPEARANCE_DECLARATION => {
+                buf[0] = PROPERTY_READ;
+                buf[1..3].copy_from_slice(&HANDLE_APPEARANCE.to_le_bytes());
+                3 + Uuid::Uuid16(UUID_APPEARANCE).encode(&mut buf[3..])
+            }
+            HANDLE_USER_DECLARATION => {
+                buf[0] = PROPERTY_READ
+                    | PROPERTY_WRITE
+                    | PROPERTY_WRITE_WITHOUT_RESPONSE
+                    | PROPERTY_NOTIFY;
+                buf[1..3].copy_from_slice(&HANDLE_USER_VALUE.to_le_bytes());
+                3 + self.characteristic.encode(&mut buf[3..])
+            }
+            HANDLE_DEVICE_NAME => return Some(f(self.device_name)),
+            // Unknown appearance
+            HANDLE_APPEARANCE => 2,
+            HANDLE_USER_VALUE => self.value(&mut buf),
+            HANDLE_USER_CCCD => {
+                if self.notifications_enabled.get() {
+                    buf[0] = CCCD_NOTIFICATION;
+                }
+                2
+            }
+            _ => return None,
+        };
+        Some(f(&buf[..len]))
+    }
+
+    fn write_attribute(&self, handle: u16, value: &[u8]) -> Result<(), u8> {
+        match handle {
+            HANDLE_USER_VALUE => {
+                if value.len() > MAX_VALUE_LEN {
+                    return Err(ATT_ERROR_INVALID_ATTRIBUTE_VALUE_LENGTH);
+                }
+                self.set_value(value);
+                self.client.map(|client| client.value_written(value));
+                Ok(())
+            }
+            HANDLE_USER_CCCD => {
+                if value.len() != 2 {
+                    return Err(ATT_ERROR_INVALID_ATTRIBUTE_VALUE_LENGTH);
+                }
+                self.notifications_enabled
+                    .set(value[0] & CCCD_NOTIFICATION != 0);
+                Ok(())
+            }
+            1...LAST_HANDLE => Err(ATT_ERROR_WRITE_NOT_PERMITTED),
+            _ => Err(ATT_ERROR_INVALID_HANDLE),
+        }
+    }
+
+    /// Parse the handle range of a request, returning the attribute not
+    /// found error for ranges outside the database.
+    fn handle_range(req: &[u8]) -> Result<(u16, u16), (u16, u8)> {
+        let start = u16::from_le_bytes([req[1], req[2]]);
+        let end = u16::from_le_bytes([req[3], req[4]]);
+        if start == 0 || start > end {
+            Err((start, ATT_ERROR_INVALID_HANDLE))
+        } else if start > LAST_HANDLE {
+            Err((start, ATT_ERROR_ATTRIBUTE_NOT_FOUND))
+        } else {
+            Ok((start, cmp::min(end, LAST_HANDLE)))
+        }
+    }
+
+    /// Handle an ATT request and write the response to `rsp`. Returns the
+    /// length of the response, or an error code and the handle it refers to.
+    fn handle_request(&self, req: &[u8], rsp: &mut [u8; ATT_MTU]) -> Result<usize, (u16, u8)> {
+        let opcode = req[0];
+        match opcode {
+            ATT_EXCHANGE_MTU_REQ if req.len() == 3 => {
+                rsp[0] = ATT_EXCHANGE_MTU_RSP;
+                rsp[1..3].copy_from_slice(&(ATT_MTU as u16).to_le_bytes());
+                Ok(3)
+            }
+
+            ATT_FIND_INFORMATION_REQ if req.len() == 5 => {
+                let (start, end) = Self::handle_range(req)?;
+                // All entries must use the same format as the first one
+                let mut format_len = 0;
+                let mut len = 2;
+                for handle in start..=end {
+                    let uuid = match self.attribute_type(handle) {
+                        Some(uuid) => uuid,
+                        None => continue,
+                    };
+                    if format_len == 0 {
+                        format_len = uuid.len();
+                    }
+                    if uuid.len() != format_len || len + 2 + format_len > ATT_MTU {
+                        break;
+                    }
+                    rsp[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+                    uuid.encode(&mut rsp[len + 2..]);
+                    len += 2 + format_len;
+                }
+                if format_len == 0 {
+                    return Err((start, ATT_ERROR_ATTRIBUTE_NOT_FOUND));
+                }
+                rsp[0] = ATT_FIND_INFORMATION_RSP;
+                rsp[1] = if format_len == 2 { 0x01 } else { 0x02 };
+                Ok(len)
+            }
+
+            ATT_FIND_BY_TYPE_VALUE_REQ if req.len() >= 7 => {
+                let (start, end) = Self::handle_range(req)?;
+                let attribute_type = u16::from_le_bytes([req[5], req[6]]);
+                let value = &req[7..];
+                let mut len = 1;
+                // Only the discovery of primary services by UUID is supported
+                if attribute_type == UUID_PRIMARY_SERVICE {
+                    for handle in start..=end {
+                        if self.attribute_type(handle) != Some(Uuid::Uuid16(UUID_PRIMARY_SERVICE)) {
+                            continue;
+                        }
+                        if self.with_value(handle, |v| v == value) != Some(true) {
+                            continue;
+                        }
+                        if len + 4 > ATT_MTU {
+                            break;
+                        }
+                        rsp[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+                        rsp[len + 2..len + 4]
+                            .copy_from_slice(&self.group_end(handle).to_le_bytes());
+                        len += 4;
+                    }
+                }
+                if len == 1 {
+                    return Err((start, ATT_ERROR_ATTRIBUTE_NOT_FOUND));
+                }
+                rsp[0] = ATT_FIND_BY_TYPE_VALUE_RSP;
+                Ok(len)
+            }
+
+            ATT_READ_BY_TYPE_REQ if req.len() == 7 || req.len() == 21 => {
+                let (start, end) = Self::handle_range(req)?;
+                let attribute_type =
+                    Uuid::from_le_bytes(&req[5..]).ok_or((start, ATT_ERROR_INVALID_PDU))?;
+                // All entries must have the same length as the first one
+                let mut entry_len = 0;
+                let mut len = 2;
+                for handle in start..=end {
+                    match self.attribute_type(handle) {
+                        Some(uuid) if uuid.matches(&attribute_type) => {}
+                        _ => continue,
+                    }
+                    let done = self
+                        .with_value(handle, |value| {
+                            let value_len = cmp::min(value.len(), ATT_MTU - 4);
+                            if entry_len == 0 {
+                                entry_len = 2 + value_len;
+                            }
+                            if 2 + value_len != entry_len || len + entry_len > ATT_MTU {
+                                return true;
+                            }
+                            rsp[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+                            rsp[len + 2..len + entry_len].copy_from_slice(&value[..value_len]);
+                            len += entry_len;
+                            false
+                        })
+                        .unwrap_or(true);
+                    if done {
+                        break;
+                    }
+                }
+                if entry_len == 0 {
+                    return Err((start, ATT_ERROR_ATTRIBUTE_NOT_FOUND));
+                }
+                rsp[0] = ATT_READ_BY_TYPE_RSP;
+                rsp[1] = entry_len as u8;
+                Ok(len)
+            }
+
+            ATT_READ_REQ | ATT_READ_BLOB_REQ => {
+                let (handle, offset) = match (opcode, req.len()) {
+                    (ATT_READ_REQ, 3) => (u16::from_le_bytes([req[1], req[2]]), 0),
+                    (ATT_READ_BLOB_REQ, 5) => (
+                        u16::from_le_bytes([req[1], req[2]]),
+                        u16::from_le_bytes([req[3], req[4]]) as usize,
+                    ),
+                    _ => return Err((0, ATT_ERROR_INVALID_PDU)),
+                };
+                let len = self
+                    .with_value(handle, |value| {
+                        if offset > value.len() {
+                            return Err((handle, ATT_ERROR_INVALID_OFFSET));
+                        }
+                        let len = cmp::min(value.len() - offset, ATT_MTU - 1);
+                        rsp[1..1 + len].copy_from_slice(&value[offset..offset + len]);
+                        Ok(len)
+                    })
+                    .unwrap_or(Err((handle, ATT_ERROR_INVALID_HANDLE)))?;
+                rsp[0] = if opcode == ATT_READ_REQ {
+                    ATT_READ_RSP
+                } else {
+                    ATT_READ_BLOB_RSP
+                };
+                Ok(1 + len)
+            }
+
+            ATT_READ_BY_GROUP_TYPE_REQ if req.len() == 7 || req.len() == 21 => {
+                let (start, end) = Self::handle_range(req)?;
+                let group_type =
+                    Uuid::from_le_bytes(&req[5..]).ok_or((start, ATT_ERROR_INVALID_PDU))?;
+                if !group_type.matches(&Uuid::Uuid16(UUID_PRIMARY_SERVICE)) {
+                    return Err((start, ATT_ERROR_UNSUPPORTED_GROUP_TYPE));
+                }
+                // Entries: handle (2), end group handle (2), service UUID,
+                // all of the same length
+                let mut entry_len = 0;
+                let mut len = 2;
+                for handle in start..=end {
+                    if self.attribute_type(handle) != Some(Uuid::Uuid16(UUID_PRIMARY_SERVICE)) {
+                        continue;
+                    }
+                    let done = self
+                        .with_value(handle, |value| {
+                            if entry_len == 0 {
+                                entry_len = 4 + value.len();
+                            }
+                            if 4 + value.len() != entry_len || len + entry_len > ATT_MTU {
+                                return true;
+                            }
+                            rsp[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+                            rsp[len + 2..len + 4]
+                                .copy_from_slice(&self.group_end(handle).to_le_bytes());
+                            rsp[len + 4..len + entry_len].copy_from_slice(value);
+                            len += entry_len;
+                            false
+                        })
+                        .unwrap_or(true);
+                    if done {
+                        break;
+                    }
+                }
+                if entry_len == 0 {
+                    return Err((start, ATT_ERROR_ATTRIBUTE_NOT_FOUND));
+                }
+                rsp[0] = ATT_READ_BY_GROUP_TYPE_RSP;
+                rsp[1] = entry_len as u8;
+                Ok(len)
+            }
+
+            ATT_WRITE_REQ | ATT_WRITE_CMD if req.len() >= 3 => {
+                let handle = u16::from_le_bytes([req[1], req[2]]);
+                self.write_attribute(handle, &req[3..])
+                    .map_err(|error| (handle, error))?;
+                rsp[0] = ATT_WRITE_RSP;
+                Ok(1)
+            }
+
+            ATT_EXCHANGE_MTU_REQ
+            | ATT_FIND_INFORMATION_REQ
+            | ATT_FIND_BY_TYPE_VALUE_REQ
+            | ATT_READ_BY_TYPE_REQ
+            | ATT_READ_BY_GROUP_TYPE_REQ
+            | ATT_WRITE_REQ => Err((0, ATT_ERROR_INVALID_PDU)),
+
+            _ => Err((0, ATT_ERROR_REQUEST_NOT_SUPPORTED)),
+        }
+    }
+}
+
+impl ChannelClient for GattServer<'a> {
+    fn connected(&self) {
+        self.connected.set(true);
+        self.notifications_enabled.set(false);
+        self.client.map(|client| client.connected());
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.connected.set(false);
+        self.notifications_enabled.set(false);
+        self.client.map(|client| client.disconnected(reason));
+    }
+
+    fn receive(&self, req: &[u8]) {
+        if req.is_empty() {
+            return;
+        }
+        let opcode = req[0];
+        let mut rsp = [0; ATT_MTU];
+        let result = self.handle_request(req, &mut rsp);
+        if opcode & ATT_COMMAND_FLAG != 0 {
+            return;
+        }
+        let len = match result {
+            Ok(len) => len,
+            Err((handle, error)) => {
+                // BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part F], section 3.4.1.1
+                // request opcode (1), attribute handle (2), error code (1)
+                rsp[0] = ATT_ERROR_RSP;
+                rsp[1] = opcode;
+                rsp[2..4].copy_from_slice(&handle.to_le_bytes());
+                rsp[4] = error;
+                5
+            }
+        };
+        self.l2cap.send(l2cap::CID_ATT, &rsp[..len]);
+    }
+}
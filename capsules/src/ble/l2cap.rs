@@ -0,0 +1,167 @@
+//! Logical Link Control and Adaptation Protocol for LE connections.
+//!
+//! Only the fixed channels of an LE connection are supported:
+//!
+//! - The attribute protocol channel is handed to a `ChannelClient`, usually
+//!   the GATT server.
+//! - Requests on the LE signaling channel are answered with a command reject.
+//! - Pairing requests on the security manager channel fail with "pairing not
+//!   supported".
+//!
+//! PDUs are reassembled from link layer fragments up to `MAX_PDU_LEN` bytes,
+//! longer PDUs are dropped.
+
+use crate::ble::link_layer::{self, LinkClient, Peripheral};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::ReturnCode;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part A], section 2.1
+pub const CID_ATT: u16 = 0x0004;
+pub const CID_LE_SIGNALING: u16 = 0x0005;
+pub const CID_SMP: u16 = 0x0006;
+
+/// Length of the basic L2CAP header: length (2) and channel ID (2)
+pub const HEADER_LEN: usize = 4;
+/// Maximum length of a reassembled PDU, including its header.
+pub const MAX_PDU_LEN: usize = 64;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part A], section 4
+const COMMAND_REJECT: u8 = 0x01;
+const CONNECTION_PARAMETER_UPDATE_RESPONSE: u8 = 0x13;
+const REASON_COMMAND_NOT_UNDERSTOOD: u16 = 0x0000;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part H], section 3.5
+const SMP_PAIRING_REQUEST: u8 = 0x01;
+const SMP_PAIRING_FAILED: u8 = 0x05;
+const SMP_PAIRING_NOT_SUPPORTED: u8 = 0x05;
+
+/// Client of a fixed L2CAP channel.
+pub trait ChannelClient {
+    fn connected(&self);
+    fn disconnected(&self, reason: u8);
+    /// A complete SDU was received on the channel.
+    fn receive(&self, sdu: &[u8]);
+}
+
+struct Reassembly {
+    buf: [u8; MAX_PDU_LEN],
+    len: usize,
+    /// Total length of the PDU being reassembled, including its header
+    expected: usize,
+}
+
+pub struct L2cap<'a> {
+    link: &'a Peripheral<'a>,
+    att_client: OptionalCell<&'a ChannelClient>,
+    rx: MapCell<Reassembly>,
+    /// The PDU being reassembled is too long and its fragments are dropped
+    dropping: Cell<bool>,
+}
+
+impl L2cap<'a> {
+    pub fn new(link: &'a Peripheral<'a>) -> L2cap<'a> {
+        L2cap {
+            link: link,
+            att_client: OptionalCell::empty(),
+            rx: MapCell::new(Reassembly {
+                buf: [0; MAX_PDU_LEN],
+                len: 0,
+                expected: 0,
+            }),
+            dropping: Cell::new(false),
+        }
+    }
+
+    pub fn set_att_client(&self, client: &'a ChannelClient) {
+        self.att_client.set(client);
+    }
+
+    /// Send an SDU on the given channel.
+    pub fn send(&self, cid: u16, sdu: &[u8]) -> ReturnCode {
+        if sdu.len() + HEADER_LEN > MAX_PDU_LEN {
+            return ReturnCode::ESIZE;
+        }
+        let mut pdu = [0; MAX_PDU_LEN];
+        pdu[0..2].copy_from_slice(&(sdu.len() as u16).to_le_bytes());
+        pdu[2..4].copy_from_slice(&cid.to_le_bytes());
+        pdu[HEADER_LEN..HEADER_LEN + sdu.len()].copy_from_slice(sdu);
+        self.link.send(&pdu[..HEADER_LEN + sdu.len()])
+    }
+
+    fn receive_pdu(&self, cid: u16, sdu: &[u8]) {
+        match cid {
+            CID_ATT => {
+                self.att_client.map(|client| client.receive(sdu));
+            }
+            CID_LE_SIGNALING => {
+                // BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part A], section 4
+                // code (1), identifier (1), length (2), data
+                if sdu.len() >= 4 && sdu[0] != CONNECTION_PARAMETER_UPDATE_RESPONSE {
+                    // Responses (even codes) are not answered
+                    if sdu[0] & 1 == 0 {
+                        return;
+                    }
+                    let reason = REASON_COMMAND_NOT_UNDERSTOOD.to_le_bytes();
+                    self.send(
+                        CID_LE_SIGNALING,
+                        &[COMMAND_REJECT, sdu[1], 2, 0, reason[0], reason[1]],
+                    );
+                }
+            }
+            CID_SMP => {
+                if sdu.first() == Some(&SMP_PAIRING_REQUEST) {
+                    self.send(CID_SMP, &[SMP_PAIRING_FAILED, SMP_PAIRING_NOT_SUPPORTED]);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl LinkClient for L2cap<'a> {
+    fn connected(&self, _peer: &[u8; link_layer::ADDRESS_LEN]) {
+        self.rx.map(|rx| rx.len = 0);
+        self.dropping.set(false);
+        self.att_client.map(|client| client.connected());
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.att_client.map(|client| client.disconnected(reason));
+    }
+
+    fn receive(&self, fragment: &[u8], start: bool) {
+        let complete = self.rx.map_or(None, |rx| {
+            if start {
+                rx.len = 0;
+                self.dropping.set(false);
+                if fragment.len() < 2 {
+                    self.dropping.set(true);
+                    return None;
+                }
+                let sdu_len = u16::from_le_bytes([fragment[0], fragment[1]]) as usize;
+                rx.expected = sdu_len + HEADER_LEN;
+                if rx.expected > MAX_PDU_LEN {
+                    self.dropping.set(true);
+                }
+            }
+            if self.dropping.get() || rx.len + fragment.len() > rx.expected {
+                self.dropping.set(true);
+                return None;
+            }
+            rx.buf[rx.len..rx.len + fragment.len()].copy_from_slice(fragment);
+            rx.len += fragment.len();
+            if rx.len == rx.expected && rx.len >= HEADER_LEN {
+                rx.len = 0;
+                Some((rx.buf, rx.expected))
+            } else {
+                None
+            }
+        });
+
+        if let Some((buf, len)) = complete {
+            let cid = u16::from_le_bytes([buf[2], buf[3]]);
+            self.receive_pdu(cid, &buf[HEADER_LEN..len]);
+        }
+    }
+}
@@ -0,0 +1,947 @@
+//! Bluetooth Low Energy link layer in the peripheral (slave) role.
+//!
+//! The link layer sends connectable undirected advertisements (`ADV_IND`) on
+//! the three advertising channels and listens for a `CONNECT_IND` after each
+//! of them. Once a central connects, it follows the connection events of the
+//! central:
+//!
+//! - Connection events are scheduled on the alarm from the anchor point of the
+//!   last received packet, widened by the sleep clock accuracy of both sides.
+//! - The data channel of every event is chosen with channel selection
+//!   algorithm #1 (hop increment over the used channels of the channel map).
+//! - Every event consists of exactly one exchange: the central's packet and
+//!   our response. The MD (more data) bit is never set.
+//! - Data PDUs are acknowledged with the SN/NESN bits. Unacknowledged PDUs
+//!   are retransmitted in the next event.
+//! - The LL control procedures a central starts on its own are supported:
+//!   connection update, channel map update, termination, feature exchange,
+//!   version exchange and ping. Encryption is rejected.
+//!
+//! The connection is dropped if no packet with a valid CRC is received within
+//! the supervision timeout.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ble_link = static_init!(
+//!     capsules::ble::link_layer::LinkLayer<'static, nrf52::radio::Radio, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::ble::link_layer::LinkLayer::new(
+//!         &nrf52::radio::RADIO,
+//!         ble_link_virtual_alarm,
+//!         &mut capsules::ble::link_layer::ADV_BUF,
+//!         nrf52::ficr::FICR_INSTANCE.ble_address()
+//!     )
+//! );
+//! kernel::hil::ble_advertising::BleConnectionDriver::set_connection_client(
+//!     &nrf52::radio::RADIO,
+//!     ble_link,
+//! );
+//! ble_link_virtual_alarm.set_client(ble_link);
+//! ```
+
+use crate::ble::RadioUser;
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::hil::ble_advertising::{self, RadioChannel};
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::ReturnCode;
+
+/// Buffer for the advertising PDU.
+pub static mut ADV_BUF: [u8; ADV_PDU_MAX_LEN] = [0; ADV_PDU_MAX_LEN];
+
+pub const ADDRESS_LEN: usize = 6;
+/// Maximum length of the advertising data of an `ADV_IND`.
+pub const MAX_ADV_DATA_LEN: usize = 31;
+/// Maximum payload of a data channel PDU (no data length extension).
+pub const MAX_DATA_PAYLOAD: usize = 27;
+
+const ADV_PDU_MAX_LEN: usize = 2 + ADDRESS_LEN + MAX_ADV_DATA_LEN;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3
+const ADV_IND: u8 = 0b0000;
+const CONNECT_IND: u8 = 0b0101;
+const ADV_HEADER_TYPE_MASK: u8 = 0x0f;
+const ADV_HEADER_TXADD: u8 = 1 << 6;
+const CONNECT_IND_PAYLOAD_LEN: usize = 34;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.4
+const LLID_MASK: u8 = 0b11;
+const NESN: u8 = 1 << 2;
+const SN: u8 = 1 << 3;
+pub const LLID_CONTINUATION: u8 = 0b01;
+pub const LLID_START: u8 = 0b10;
+const LLID_CONTROL: u8 = 0b11;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.4.2
+const LL_CONNECTION_UPDATE_IND: u8 = 0x00;
+const LL_CHANNEL_MAP_IND: u8 = 0x01;
+const LL_TERMINATE_IND: u8 = 0x02;
+const LL_ENC_REQ: u8 = 0x03;
+const LL_UNKNOWN_RSP: u8 = 0x07;
+const LL_FEATURE_REQ: u8 = 0x08;
+const LL_FEATURE_RSP: u8 = 0x09;
+const LL_VERSION_IND: u8 = 0x0c;
+const LL_REJECT_IND: u8 = 0x0d;
+const LL_PING_REQ: u8 = 0x12;
+const LL_PING_RSP: u8 = 0x13;
+
+// Bluetooth Assigned Numbers, Link Layer Version: 4.2
+const LL_VERSION: u8 = 0x08;
+// Company identifier reserved for test and unassigned use
+const LL_COMPANY_ID: u16 = 0xffff;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part D], Error Codes
+pub const ERROR_CONNECTION_TIMEOUT: u8 = 0x08;
+pub const ERROR_REMOTE_USER_TERMINATED: u8 = 0x13;
+pub const ERROR_LOCAL_HOST_TERMINATED: u8 = 0x16;
+const ERROR_UNSUPPORTED_REMOTE_FEATURE: u8 = 0x1a;
+const ERROR_INSTANT_PASSED: u8 = 0x28;
+
+const NUM_DATA_CHANNELS: u8 = 37;
+const TX_QUEUE_LEN: usize = 4;
+
+// Timing, in microseconds
+const CONN_INTERVAL_UNIT_US: u32 = 1250;
+const SUPERVISION_TIMEOUT_UNIT_US: u32 = 10_000;
+const TRANSMIT_WINDOW_DELAY_US: u32 = 1250;
+// Time the receiver needs to ramp up before the radio listens
+const RX_RAMP_UP_US: u32 = 140;
+// Fixed part of the window widening, plus some slack for the alarm
+// resolution and interrupt latency
+const WINDOW_WIDENING_US: u32 = 16 + 100;
+// How long to keep listening after the latest expected start of a packet,
+// long enough to receive a PDU of maximum length
+const RX_WINDOW_US: u32 = 1000;
+// How long to listen for requests after a connectable advertisement
+const ADV_LISTEN_US: u32 = 1500;
+// Accuracy of our own sleep clock
+const SLEEP_CLOCK_ACCURACY_PPM: u32 = 50;
+
+/// Maximum accuracy (in ppm) of the master's sleep clock for each SCA value.
+const MASTER_SCA_PPM: [u32; 8] = [500, 250, 150, 100, 75, 50, 30, 20];
+
+/// Client of the link layer, usually L2CAP.
+pub trait LinkClient {
+    /// A central connected to us.
+    fn connected(&self, peer: &[u8; ADDRESS_LEN]);
+
+    /// The connection was closed with the given error code.
+    fn disconnected(&self, reason: u8);
+
+    /// A fragment of an L2CAP PDU was received. `start` is true for the first
+    /// fragment of a PDU.
+    fn receive(&self, fragment: &[u8], start: bool);
+}
+
+/// Interface the link layer offers to its client.
+pub trait Peripheral<'a> {
+    fn set_client(&self, client: &'a LinkClient);
+
+    /// Start connectable advertising with the given advertising data, which
+    /// must not be longer than `MAX_ADV_DATA_LEN`.
+    fn start_advertising(&self, adv_data: &[u8], interval_ms: u32) -> ReturnCode;
+
+    /// Stop advertising. Returns `EALREADY` if not advertising.
+    fn stop_advertising(&self) -> ReturnCode;
+
+    /// Queue an L2CAP PDU for transmission. It is split into fragments of
+    /// `MAX_DATA_PAYLOAD` bytes. Returns `EBUSY` if there is not enough room
+    /// in the transmit queue and `EOFF` if not connected.
+    fn send(&self, pdu: &[u8]) -> ReturnCode;
+
+    /// Terminate the connection. `LinkClient::disconnected` is called once
+    /// the central has acknowledged the termination.
+    fn disconnect(&self) -> ReturnCode;
+
+    fn is_advertising(&self) -> bool;
+    fn is_connected(&self) -> bool;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Standby,
+    /// Waiting for the next advertising event
+    AdvertisingIdle,
+    /// Advertised on the channel and now listening for a `CONNECT_IND`
+    Advertising(RadioChannel),
+    /// Waiting for the next connection event
+    ConnectionIdle,
+    /// Listening for the central's packet of this connection event
+    ConnectionListening,
+    /// Received a packet, waiting for the response to be sent
+    ConnectionExchange,
+}
+
+/// What was sent in the last connection event and not acknowledged yet.
+#[derive(Copy, Clone, PartialEq)]
+enum InFlight {
+    Nothing,
+    Empty,
+    Queued,
+}
+
+#[derive(Copy, Clone)]
+struct ConnectionUpdate {
+    win_size: u8,
+    win_offset: u16,
+    interval: u16,
+    latency: u16,
+    timeout: u16,
+    instant: u16,
+}
+
+#[derive(Copy, Clone)]
+struct ChannelMapUpdate {
+    channel_map: [u8; 5],
+    instant: u16,
+}
+
+#[derive(Copy, Clone)]
+struct Connection {
+    peer: [u8; ADDRESS_LEN],
+    /// Connection interval in units of 1.25 ms
+    interval: u16,
+    /// Slave latency, we nevertheless listen in every connection event
+    latency: u16,
+    /// Supervision timeout in units of 10 ms
+    timeout: u16,
+    channel_map: [u8; 5],
+    hop: u8,
+    master_sca_ppm: u32,
+
+    event_counter: u16,
+    last_unmapped_channel: u8,
+    channel: RadioChannel,
+    /// Expected start of the current connection event
+    anchor: u32,
+    /// Extra time after `anchor` during which the central may start
+    /// transmitting, i.e. a transmit window
+    window_us: u32,
+    /// Last anchor point that was observed on air
+    sync: u32,
+    /// Time of the last packet with a valid CRC
+    last_rx: u32,
+    /// A packet has been received with a valid CRC
+    established: bool,
+
+    sn: bool,
+    nesn: bool,
+    in_flight: InFlight,
+    version_sent: bool,
+    /// Error code to report once the exchange in progress is over
+    terminate: Option<u8>,
+
+    connection_update: Option<ConnectionUpdate>,
+    channel_map_update: Option<ChannelMapUpdate>,
+}
+
+impl Connection {
+    fn new() -> Connection {
+        Connection {
+            peer: [0; ADDRESS_LEN],
+            interval: 0,
+            latency: 0,
+            timeout: 0,
+            channel_map: [0; 5],
+            hop: 0,
+            master_sca_ppm: MASTER_SCA_PPM[0],
+            event_counter: 0,
+            last_unmapped_channel: 0,
+            channel: RadioChannel::DataChannel0,
+            anchor: 0,
+            window_us: 0,
+            sync: 0,
+            last_rx: 0,
+            established: false,
+            sn: false,
+            nesn: false,
+            in_flight: InFlight::Nothing,
+            version_sent: false,
+            terminate: None,
+            connection_update: None,
+            channel_map_update: None,
+        }
+    }
+
+    fn channel_used(&self, channel: u8) -> bool {
+        self.channel_map[(channel / 8) as usize] & (1 << (channel % 8)) != 0
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.8.2
+    // Channel Selection
+    fn next_channel(&mut self) -> RadioChannel {
+        let unmapped = (self.last_unmapped_channel + self.hop) % NUM_DATA_CHANNELS;
+        self.last_unmapped_channel = unmapped;
+
+        let channel = if self.channel_used(unmapped) {
+            unmapped
+        } else {
+            let num_used = (0..NUM_DATA_CHANNELS)
+                .filter(|c| self.channel_used(*c))
+                .count();
+            let remapping_index = unmapped as usize % num_used;
+            (0..NUM_DATA_CHANNELS)
+                .filter(|c| self.channel_used(*c))
+                .nth(remapping_index)
+                .unwrap_or(0)
+        };
+        RadioChannel::from_channel_index(channel).unwrap_or(RadioChannel::DataChannel0)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct DataPdu {
+    llid: u8,
+    len: u8,
+    payload: [u8; MAX_DATA_PAYLOAD],
+}
+
+/// Data PDUs waiting to be sent. The head stays in the queue until it is
+/// acknowledged.
+struct TxQueue {
+    pdus: [DataPdu; TX_QUEUE_LEN],
+    head: usize,
+    count: usize,
+}
+
+impl TxQueue {
+    fn new() -> TxQueue {
+        TxQueue {
+            pdus: [DataPdu {
+                llid: 0,
+                len: 0,
+                payload: [0; MAX_DATA_PAYLOAD],
+            }; TX_QUEUE_LEN],
+            head: 0,
+            count: 0,
+        }
+    }
+
+    fn available(&self) -> usize {
+        TX_QUEUE_LEN - self.count
+    }
+
+    fn push(&mut self, llid: u8, payload: &[u8]) {
+        let index = (self.head + self.count) % TX_QUEUE_LEN;
+        let pdu = &mut self.pdus[index];
+        pdu.llid = llid;
+        pdu.len = payload.len() as u8;
+        pdu.payload[..payload.len()].copy_from_slice(payload);
+        self.count += 1;
+    }
+
+    fn head(&self) -> Option<&DataPdu> {
+        if self.count > 0 {
+            Some(&self.pdus[self.head])
+        } else {
+            None
+        }
+    }
+
+    fn pop(&mut self) -> Option<DataPdu> {
+        if self.count > 0 {
+            let pdu = self.pdus[self.head];
+            self.head = (self.head + 1) % TX_QUEUE_LEN;
+            self.count -= 1;
+            Some(pdu)
+        } else {
+            None
+        }
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.count = 0;
+    }
+}
+
+pub struct LinkLayer<'a, R: ble_advertising::BleConnectionDriver, A: Alarm> {
+    radio: &'a R,
+    alarm: &'a A,
+    address: [u8; ADDRESS_LEN],
+    client: OptionalCell<&'a LinkClient>,
+    state: Cell<State>,
+    /// Other user of the radio, which must not be using it when advertising
+    /// starts
+    radio_sharer: OptionalCell<&'a RadioUser>,
+
+    adv_buf: TakeCell<'static, [u8]>,
+    adv_len: Cell<usize>,
+    adv_interval_ms: Cell<u32>,
+    random_nonce: Cell<u32>,
+
+    connection: Cell<Connection>,
+    tx_queue: MapCell<TxQueue>,
+}
+
+impl<R: ble_advertising::BleConnectionDriver, A: Alarm> LinkLayer<'a, R, A> {
+    pub fn new(
+        radio: &'a R,
+        alarm: &'a A,
+        adv_buf: &'static mut [u8],
+        address: [u8; ADDRESS_LEN],
+    ) -> LinkLayer<'a, R, A> {
+        LinkLayer {
+            radio: radio,
+            alarm: alarm,
+            address: address,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Standby),
+            radio_sharer: OptionalCell::empty(),
+            adv_buf: TakeCell::new(adv_buf),
+            adv_len: Cell::new(0),
+            adv_interval_ms: Cell::new(100),
+            random_nonce: Cell::new(0xdeadbeef),
+            connection: Cell::new(Connection::new()),
+            tx_queue: MapCell::new(TxQueue::new()),
+        }
+    }
+
+    pub fn address(&self) -> [u8; ADDRESS_LEN] {
+        self.address
+    }
+
+    /// Share the radio with `other`, so that advertising only starts while
+    /// `other` does not use the radio.
+    pub fn share_radio_with(&self, other: &'a RadioUser) {
+        self.radio_sharer.set(other);
+    }
+
+    fn us_to_ticks(&self, us: u32) -> u32 {
+        (us as u64 * <A::Frequency>::frequency() as u64 / 1_000_000) as u32
+    }
+
+    fn ticks_to_us(&self, ticks: u32) -> u32 {
+        (ticks as u64 * 1_000_000 / <A::Frequency>::frequency() as u64) as u32
+    }
+
+    /// Set the alarm to `when`, or as soon as possible if that has already
+    /// passed.
+    fn set_alarm_at(&self, when: u32) {
+        let now = self.alarm.now();
+        let min_delay = self.us_to_ticks(100).max(1);
+        let delay = when.wrapping_sub(now);
+        if delay < min_delay || delay > u32::max_value() / 2 {
+            self.alarm.set_alarm(now.wrapping_add(min_delay));
+        } else {
+            self.alarm.set_alarm(when);
+        }
+    }
+
+    // Xorshift, as in the advertising driver, to pick the pseudo-random
+    // advDelay
+    fn random(&self) -> u32 {
+        let mut nonce = self.random_nonce.get();
+        nonce ^= nonce << 13;
+        nonce ^= nonce >> 17;
+        nonce ^= nonce << 5;
+        self.random_nonce.set(nonce);
+        nonce
+    }
+
+    fn advertise(&self, channel: RadioChannel) {
+        self.state.set(State::Advertising(channel));
+        let len = self.adv_len.get();
+        self.adv_buf.take().map(|buf| {
+            let buf = self
+                .radio
+                .transmit_connectable_advertisement(buf, len, channel);
+            self.adv_buf.replace(buf);
+        });
+        self.set_alarm_at(
+            self.alarm
+                .now()
+                .wrapping_add(self.us_to_ticks(ADV_LISTEN_US)),
+        );
+    }
+
+    fn schedule_advertising_event(&self) {
+        self.state.set(State::AdvertisingIdle);
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.4.2.2
+        // advDelay is a pseudo-random value between 0 and 10 ms
+        let delay_ms = self.adv_interval_ms.get() + self.random() % 11;
+        self.set_alarm_at(
+            self.alarm
+                .now()
+                .wrapping_add(self.us_to_ticks(delay_ms * 1000)),
+        );
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.3.1
+    // CONNECT_IND: InitA (6), AdvA (6), LLData (22)
+    fn connect(&self, pdu: &[u8]) {
+        let payload = &pdu[2..];
+        let mut conn = Connection::new();
+        conn.peer.copy_from_slice(&payload[0..6]);
+        let ll_data = &payload[12..34];
+        let access_address = u32::from_le_bytes([ll_data[0], ll_data[1], ll_data[2], ll_data[3]]);
+        let crc_init = u32::from_le_bytes([ll_data[4], ll_data[5], ll_data[6], 0]);
+        let win_size = ll_data[7];
+        let win_offset = u16::from_le_bytes([ll_data[8], ll_data[9]]);
+        conn.interval = u16::from_le_bytes([ll_data[10], ll_data[11]]);
+        conn.latency = u16::from_le_bytes([ll_data[12], ll_data[13]]);
+        conn.timeout = u16::from_le_bytes([ll_data[14], ll_data[15]]);
+        conn.channel_map.copy_from_slice(&ll_data[16..21]);
+        conn.channel_map[4] &= 0x1f;
+        conn.hop = ll_data[21] & 0x1f;
+        conn.master_sca_ppm = MASTER_SCA_PPM[(ll_data[21] >> 5) as usize];
+
+        // Invalid parameters, ignore the request
+        if conn.hop < 5 || conn.hop > 16 || conn.interval == 0 || conn.channel_map == [0; 5] {
+            return;
+        }
+
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.3
+        // The transmit window starts transmitWindowDelay + transmitWindowOffset
+        // after the end of the CONNECT_IND
+        let now = self.alarm.now();
+        conn.anchor = now.wrapping_add(
+            self.us_to_ticks(TRANSMIT_WINDOW_DELAY_US + win_offset as u32 * CONN_INTERVAL_UNIT_US),
+        );
+        conn.window_us = win_size as u32 * CONN_INTERVAL_UNIT_US;
+        conn.sync = now;
+        conn.last_rx = now;
+        conn.channel = conn.next_channel();
+
+        self.radio.set_access_address(access_address, crc_init);
+        self.tx_queue.map(|queue| queue.clear());
+        self.connection.set(conn);
+        self.schedule_connection_event(&conn);
+        self.client.map(|client| client.connected(&conn.peer));
+    }
+
+    fn window_widening_us(&self, conn: &Connection) -> u32 {
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.7
+        let elapsed_us = self.ticks_to_us(conn.anchor.wrapping_sub(conn.sync)) as u64;
+        let ppm = (conn.master_sca_ppm + SLEEP_CLOCK_ACCURACY_PPM) as u64;
+        (elapsed_us * ppm / 1_000_000) as u32 + WINDOW_WIDENING_US
+    }
+
+    fn schedule_connection_event(&self, conn: &Connection) {
+        self.state.set(State::ConnectionIdle);
+        let early_us = self.window_widening_us(conn) + RX_RAMP_UP_US;
+        self.set_alarm_at(conn.anchor.wrapping_sub(self.us_to_ticks(early_us)));
+    }
+
+    fn start_connection_event(&self) {
+        let conn = self.connection.get();
+        self.state.set(State::ConnectionListening);
+        self.radio.receive_data(conn.channel);
+        let listen_us = self.window_widening_us(&conn) + conn.window_us + RX_WINDOW_US;
+        self.set_alarm_at(conn.anchor.wrapping_add(self.us_to_ticks(listen_us)));
+    }
+
+    /// Close the current connection event and schedule the next one, applying
+    /// any updates whose instant has been reached.
+    fn close_connection_event(&self) {
+        let mut conn = self.connection.get();
+
+        if let Some(reason) = conn.terminate {
+            self.end_connection(reason);
+            return;
+        }
+
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.2
+        let timeout_us = if conn.established {
+            conn.timeout as u32 * SUPERVISION_TIMEOUT_UNIT_US
+        } else {
+            6 * conn.interval as u32 * CONN_INTERVAL_UNIT_US
+        };
+        let since_rx = self.alarm.now().wrapping_sub(conn.last_rx);
+        if since_rx > self.us_to_ticks(timeout_us) {
+            self.end_connection(ERROR_CONNECTION_TIMEOUT);
+            return;
+        }
+
+        let interval_ticks = self.us_to_ticks(conn.interval as u32 * CONN_INTERVAL_UNIT_US);
+        conn.event_counter = conn.event_counter.wrapping_add(1);
+        conn.anchor = conn.anchor.wrapping_add(interval_ticks);
+        conn.window_us = 0;
+
+        if let Some(update) = conn.channel_map_update {
+            if update.instant == conn.event_counter {
+                conn.channel_map = update.channel_map;
+                conn.channel_map_update = None;
+            }
+        }
+        if let Some(update) = conn.connection_update {
+            // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 5.1.1
+            // The transmit window of the instant starts winOffset after the
+            // anchor point the instant would have had with the old parameters
+            if update.instant == conn.event_counter {
+                conn.anchor = conn.anchor.wrapping_add(
+                    self.us_to_ticks(update.win_offset as u32 * CONN_INTERVAL_UNIT_US),
+                );
+                conn.window_us = update.win_size as u32 * CONN_INTERVAL_UNIT_US;
+                conn.interval = update.interval;
+                conn.latency = update.latency;
+                conn.timeout = update.timeout;
+                conn.connection_update = None;
+            }
+        }
+
+        conn.channel = conn.next_channel();
+        self.connection.set(conn);
+        self.schedule_connection_event(&conn);
+    }
+
+    fn end_connection(&self, reason: u8) {
+        self.radio.stop();
+        self.state.set(State::Standby);
+        self.tx_queue.map(|queue| queue.clear());
+        self.connection.set(Connection::new());
+        self.client.map(|client| client.disconnected(reason));
+    }
+
+    fn queue_control(&self, payload: &[u8]) {
+        self.tx_queue.map(|queue| {
+            if queue.available() > 0 {
+                queue.push(LLID_CONTROL, payload);
+            }
+        });
+    }
+
+    /// Handle an LL control PDU from the central.
+    fn handle_control(&self, conn: &mut Connection, payload: &[u8]) {
+        if payload.is_empty() {
+            return;
+        }
+        let opcode = payload[0];
+        let data = &payload[1..];
+        match opcode {
+            LL_CONNECTION_UPDATE_IND if data.len() >= 11 => {
+                let update = ConnectionUpdate {
+                    win_size: data[0],
+                    win_offset: u16::from_le_bytes([data[1], data[2]]),
+                    interval: u16::from_le_bytes([data[3], data[4]]),
+                    latency: u16::from_le_bytes([data[5], data[6]]),
+                    timeout: u16::from_le_bytes([data[7], data[8]]),
+                    instant: u16::from_le_bytes([data[9], data[10]]),
+                };
+                if Self::instant_passed(conn, update.instant) {
+                    conn.terminate = Some(ERROR_INSTANT_PASSED);
+                } else {
+                    conn.connection_update = Some(update);
+                }
+            }
+            LL_CHANNEL_MAP_IND if data.len() >= 7 => {
+                let mut update = ChannelMapUpdate {
+                    channel_map: [0; 5],
+                    instant: u16::from_le_bytes([data[5], data[6]]),
+                };
+                update.channel_map.copy_from_slice(&data[..5]);
+                update.channel_map[4] &= 0x1f;
+                if Self::instant_passed(conn, update.instant) {
+                    conn.terminate = Some(ERROR_INSTANT_PASSED);
+                } else if update.channel_map != [0; 5] {
+                    conn.channel_map_update = Some(update);
+                }
+            }
+            LL_TERMINATE_IND => {
+                let reason = data.first().map_or(ERROR_REMOTE_USER_TERMINATED, |r| *r);
+                conn.terminate = Some(reason);
+            }
+            LL_FEATURE_REQ => {
+                // No optional features are supported
+                let mut rsp = [0; 9];
+                rsp[0] = LL_FEATURE_RSP;
+                self.queue_control(&rsp);
+            }
+            LL_VERSION_IND => {
+                // The version is only exchanged once per connection
+                if !conn.version_sent {
+                    conn.version_sent = true;
+                    let company = LL_COMPANY_ID.to_le_bytes();
+                    self.queue_control(&[LL_VERSION_IND, LL_VERSION, company[0], company[1], 0, 0]);
+                }
+            }
+            LL_PING_REQ => self.queue_control(&[LL_PING_RSP]),
+            LL_ENC_REQ => self.queue_control(&[LL_REJECT_IND, ERROR_UNSUPPORTED_REMOTE_FEATURE]),
+            LL_CONNECTION_UPDATE_IND | LL_CHANNEL_MAP_IND => {}
+            // Responses to procedures we never start
+            LL_UNKNOWN_RSP | LL_FEATURE_RSP | LL_REJECT_IND | LL_PING_RSP => {}
+            _ => self.queue_control(&[LL_UNKNOWN_RSP, opcode]),
+        }
+    }
+
+    // The instant is in the past if it is more than 32767 events away
+    fn instant_passed(conn: &Connection, instant: u16) -> bool {
+        instant.wrapping_sub(conn.event_counter) >= 32767
+    }
+}
+
+impl<R: ble_advertising::BleConnectionDriver, A: Alarm> Peripheral<'a> for LinkLayer<'a, R, A> {
+    fn set_client(&self, client: &'a LinkClient) {
+        self.client.set(client);
+    }
+
+    fn start_advertising(&self, adv_data: &[u8], interval_ms: u32) -> ReturnCode {
+        if adv_data.len() > MAX_ADV_DATA_LEN {
+            return ReturnCode::ESIZE;
+        }
+        match self.state.get() {
+            State::Standby => {
+                if self.radio_sharer.map_or(false, |other| other.uses_radio()) {
+                    return ReturnCode::EBUSY;
+                }
+            }
+            State::AdvertisingIdle => {}
+            _ => return ReturnCode::EBUSY,
+        }
+        self.adv_buf
+            .map(|buf| {
+                // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.1.1
+                // ADV_IND: AdvA (6), AdvData (0-31), from a random address
+                buf[0] = ADV_IND | ADV_HEADER_TXADD;
+                buf[1] = (ADDRESS_LEN + adv_data.len()) as u8;
+                buf[2..2 + ADDRESS_LEN].copy_from_slice(&self.address);
+                buf[2 + ADDRESS_LEN..2 + ADDRESS_LEN + adv_data.len()].copy_from_slice(adv_data);
+                self.adv_len.set(2 + ADDRESS_LEN + adv_data.len());
+                // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.4.2.2
+                // The advertising interval is at least 20 ms
+                self.adv_interval_ms.set(interval_ms.max(20));
+                self.random_nonce.set(self.alarm.now() | 1);
+                self.schedule_advertising_event();
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or(ReturnCode::ENOMEM)
+    }
+
+    fn stop_advertising(&self) -> ReturnCode {
+        match self.state.get() {
+            State::AdvertisingIdle => {
+                self.alarm.disable();
+                self.state.set(State::Standby);
+                ReturnCode::SUCCESS
+            }
+            State::Advertising(_) => {
+                self.alarm.disable();
+                self.radio.stop();
+                self.state.set(State::Standby);
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::EALREADY,
+        }
+    }
+
+    fn send(&self, pdu: &[u8]) -> ReturnCode {
+        if !self.is_connected() {
+            return ReturnCode::EOFF;
+        }
+        let fragments = (pdu.len() + MAX_DATA_PAYLOAD - 1) / MAX_DATA_PAYLOAD;
+        if fragments == 0 {
+            return ReturnCode::EINVAL;
+        }
+        self.tx_queue
+            .map(|queue| {
+                if queue.available() < fragments {
+                    return ReturnCode::EBUSY;
+                }
+                for (i, fragment) in pdu.chunks(MAX_DATA_PAYLOAD).enumerate() {
+                    let llid = if i == 0 {
+                        LLID_START
+                    } else {
+                        LLID_CONTINUATION
+                    };
+                    queue.push(llid, fragment);
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or(ReturnCode::FAIL)
+    }
+
+    fn disconnect(&self) -> ReturnCode {
+        if !self.is_connected() {
+            return ReturnCode::EOFF;
+        }
+        self.tx_queue
+            .map(|queue| {
+                if queue.available() == 0 {
+                    return ReturnCode::EBUSY;
+                }
+                queue.push(
+                    LLID_CONTROL,
+                    &[LL_TERMINATE_IND, ERROR_REMOTE_USER_TERMINATED],
+                );
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or(ReturnCode::FAIL)
+    }
+
+    fn is_advertising(&self) -> bool {
+        match self.state.get() {
+            State::AdvertisingIdle | State::Advertising(_) => true,
+            _ => false,
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        match self.state.get() {
+            State::ConnectionIdle | State::ConnectionListening | State::ConnectionExchange => true,
+            _ => false,
+        }
+    }
+}
+
+impl<R: ble_advertising::BleConnectionDriver, A: Alarm> RadioUser for LinkLayer<'a, R, A> {
+    fn uses_radio(&self) -> bool {
+        self.state.get() != State::Standby
+    }
+}
+
+impl<R: ble_advertising::BleConnectionDriver, A: Alarm> time::Client for LinkLayer<'a, R, A> {
+    fn fired(&self) {
+        match self.state.get() {
+            State::AdvertisingIdle => self.advertise(RadioChannel::AdvertisingChannel37),
+            State::Advertising(channel) => {
+                // Nobody connected during the listen window
+                self.radio.stop();
+                match channel {
+                    RadioChannel::AdvertisingChannel37 => {
+                        self.advertise(RadioChannel::AdvertisingChannel38)
+                    }
+                    RadioChannel::AdvertisingChannel38 => {
+                        self.advertise(RadioChannel::AdvertisingChannel39)
+                    }
+                    _ => self.schedule_advertising_event(),
+                }
+            }
+            State::ConnectionIdle => self.start_connection_event(),
+            State::ConnectionListening => {
+                // Missed the central's packet in this connection event
+                self.radio.stop();
+                self.close_connection_event();
+            }
+            State::Standby | State::ConnectionExchange => {}
+        }
+    }
+}
+
+impl<R: ble_advertising::BleConnectionDriver, A: Alarm> ble_advertising::ConnectionClient
+    for LinkLayer<'a, R, A>
+{
+    fn advertising_request(&self, pdu: &[u8], result: ReturnCode) {
+        if let State::Advertising(_) = self.state.get() {
+            if result == ReturnCode::SUCCESS
+                && pdu.len() >= 2 + CONNECT_IND_PAYLOAD_LEN
+                && pdu[0] & ADV_HEADER_TYPE_MASK == CONNECT_IND
+                && pdu[8..14] == self.address
+            {
+                self.alarm.disable();
+                self.connect(pdu);
+            }
+            // Anything else is ignored, the listen window is closed by the
+            // alarm
+        }
+    }
+
+    fn data_received(&self, pdu: &[u8], result: ReturnCode, response: &mut [u8]) -> usize {
+        let mut conn = self.connection.get();
+
+        if self.state.get() == State::ConnectionListening {
+            self.state.set(State::ConnectionExchange);
+            // The access address matched, so this is the actual anchor point
+            // of this event. The packet took 1 + 4 + len + 3 bytes on air.
+            let now = self.alarm.now();
+            let air_ticks = self.us_to_ticks((8 + pdu.len() as u32) * 8);
+            conn.anchor = now.wrapping_sub(air_ticks);
+            conn.sync = conn.anchor;
+        }
+
+        let mut acked = None;
+        let mut new_payload = false;
+        if result == ReturnCode::SUCCESS && pdu.len() >= 2 {
+            conn.last_rx = self.alarm.now();
+            conn.established = true;
+            let header = pdu[0];
+
+            // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.9
+            // Acknowledgement and Flow Control
+            if (header & NESN != 0) != conn.sn {
+                conn.sn = !conn.sn;
+                if conn.in_flight == InFlight::Queued {
+                    acked = self.tx_queue.and_then(|queue| queue.pop());
+                }
+                conn.in_flight = InFlight::Nothing;
+            }
+            if (header & SN != 0) == conn.nesn {
+                conn.nesn = !conn.nesn;
+                new_payload = true;
+            }
+        }
+
+        // Our termination was acknowledged
+        if let Some(pdu) = acked {
+            if pdu.llid == LLID_CONTROL && pdu.payload[0] == LL_TERMINATE_IND {
+                conn.terminate = Some(ERROR_LOCAL_HOST_TERMINATED);
+            }
+        }
+
+        if new_payload {
+            let len = (pdu[1] as usize).min(pdu.len() - 2);
+            let payload = &pdu[2..2 + len];
+            match pdu[0] & LLID_MASK {
+                LLID_CONTROL => self.handle_control(&mut conn, payload),
+                LLID_START => {
+                    self.connection.set(conn);
+                    self.client.map(|client| client.receive(payload, true));
+                    conn = self.connection.get();
+                }
+                LLID_CONTINUATION if len > 0 => {
+                    self.connection.set(conn);
+                    self.client.map(|client| client.receive(payload, false));
+                    conn = self.connection.get();
+                }
+                _ => {}
+            }
+        }
+
+        // Resend whatever is not acknowledged yet, otherwise send the next
+        // queued PDU or an empty one
+        if conn.in_flight == InFlight::Nothing {
+            conn.in_flight = if self.tx_queue.map_or(false, |queue| queue.head().is_some()) {
+                InFlight::Queued
+            } else {
+                InFlight::Empty
+            };
+        }
+        let mut header = 0;
+        if conn.nesn {
+            header |= NESN;
+        }
+        if conn.sn {
+            header |= SN;
+        }
+        let len = match conn.in_flight {
+            InFlight::Queued => self
+                .tx_queue
+                .and_then(|queue| {
+                    queue.head().map(|pdu| {
+                        let len = pdu.len as usize;
+                        response[0] = header | pdu.llid;
+                        response[1] = pdu.len;
+                        response[2..2 + len].copy_from_slice(&pdu.payload[..len]);
+                        2 + len
+                    })
+                })
+                .unwrap_or(0),
+            _ => 0,
+        };
+        self.connection.set(conn);
+        if len > 0 {
+            len
+        } else {
+            response[0] = header | LLID_CONTINUATION;
+            response[1] = 0;
+            2
+        }
+    }
+
+    fn response_sent(&self, _result: ReturnCode) {
+        if self.state.get() == State::ConnectionExchange {
+            self.alarm.disable();
+            self.close_connection_event();
+        }
+    }
+}
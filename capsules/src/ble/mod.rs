@@ -0,0 +1,39 @@
+//! Bluetooth Low Energy connections in the peripheral role, helpers for
+//! advertising data and the scheduling of advertising events.
+//!
+//! ```text
+//! +----------------------+
+//! | BlePeripheralDriver  |  system call driver
+//! +----------------------+
+//! | GattServer           |  attribute protocol, fixed attribute database
+//! +----------------------+
+//! | L2cap                |  fixed channels, reassembly
+//! +----------------------+
+//! | LinkLayer            |  advertising, connection events, acknowledgements
+//! +----------------------+
+//! | BleConnectionDriver  |  radio HIL
+//! +----------------------+
+//! ```
+//!
+//! The link layer and the advertising driver use the same radio, which cannot
+//! advertise or scan for one while it is connected or advertising for the
+//! other. Boards that have both tell each about the other with
+//! `share_radio_with`, and each then refuses to start with `EBUSY` while the
+//! other is using the radio.
+
+pub mod advertising_data;
+pub mod gatt;
+pub mod l2cap;
+pub mod link_layer;
+pub mod scheduler;
+
+mod driver;
+
+pub use self::driver::BlePeripheralDriver;
+pub use self::driver::DRIVER_NUM;
+
+/// A user of a radio that it shares with another one.
+pub trait RadioUser {
+    /// Whether the radio is advertising, scanning or connected for this user.
+    fn uses_radio(&self) -> bool;
+}
@@ -0,0 +1,551 @@
+//! Earliest-deadline scheduling of advertising and scanning events.
+//!
+//! Every process that advertises or scans has a `Schedule` with the deadline
+//! of its next event. Only one event can use the radio at a time, so when
+//! several are due the one with the earliest deadline goes first, and ties go
+//! to the process that least recently had an event.
+//!
+//! After an event starts, the next deadline of its process is
+//! `advInterval + advDelay` after the start, with `advDelay` a pseudo-random
+//! delay of 0 to 10 ms as required by the Bluetooth specification. A process
+//! that had to wait keeps its deadline, so the processes served while it waits
+//! all get later deadlines than it. This bounds how long a due event waits:
+//! at most one event of each other process runs first.
+//!
+//! The `Scheduler` also keeps the timeout of the current step of an event,
+//! e.g. the window after an advertisement in which scan requests are
+//! accepted, and sets the alarm for whichever comes first.
+//!
+//! ```rust
+//! let mut next = scheduler::Earliest::new(scheduler.now());
+//! for (id, schedule) in processes {
+//!     next.offer(id, schedule);
+//! }
+//! match next.due() {
+//!     Some(id) => { /* start the event of `id` */ }
+//!     None => scheduler.arm(next.deadline()),
+//! }
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{Alarm, Frequency};
+
+/// Upper bound of the random `advDelay`.
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.4.2.2
+pub const MAX_ADV_DELAY_MS: u32 = 10;
+
+/// Alarms closer than this to the current time may be missed, so they are
+/// set this far in the future instead.
+const MIN_ALARM_TICKS: u32 = 2;
+
+/// Scheduling state of a single process.
+#[derive(Copy, Clone, Debug)]
+pub struct Schedule {
+    active: bool,
+    interval: u32,
+    deadline: u32,
+    last_start: u32,
+    /// State of the xorshift generator for `advDelay`
+    nonce: u32,
+}
+
+impl Default for Schedule {
+    fn default() -> Schedule {
+        Schedule {
+            active: false,
+            interval: 0,
+            deadline: 0,
+            last_start: 0,
+            // Just use any non-zero starting value by default
+            nonce: 0xdeadbeef,
+        }
+    }
+}
+
+impl Schedule {
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// The time at which the next event should start.
+    pub fn deadline(&self) -> Option<u32> {
+        if self.active {
+            Some(self.deadline)
+        } else {
+            None
+        }
+    }
+
+    /// How many ticks the next event is overdue at `now`, if it is due.
+    fn lateness(&self, now: u32) -> Option<u32> {
+        let late = now.wrapping_sub(self.deadline);
+        if self.active && (late as i32) >= 0 {
+            Some(late)
+        } else {
+            None
+        }
+    }
+
+    // Xorshift, see https://en.wikipedia.org/wiki/Xorshift
+    fn random(&mut self) -> u32 {
+        let mut next_nonce = ::core::num::Wrapping(self.nonce);
+        next_nonce ^= next_nonce << 13;
+        next_nonce ^= next_nonce >> 17;
+        next_nonce ^= next_nonce << 5;
+        self.nonce = next_nonce.0;
+        self.nonce
+    }
+}
+
+/// Finds the event to run next among the schedules offered to it.
+pub struct Earliest<K: Copy> {
+    now: u32,
+    due: Option<(K, u32, u32)>,
+    deadline: Option<u32>,
+}
+
+impl<K: Copy> Earliest<K> {
+    pub fn new(now: u32) -> Earliest<K> {
+        Earliest {
+            now: now,
+            due: None,
+            deadline: None,
+        }
+    }
+
+    pub fn offer(&mut self, key: K, schedule: &Schedule) {
+        match schedule.lateness(self.now) {
+            Some(late) => {
+                let since_start = self.now.wrapping_sub(schedule.last_start);
+                let first = self.due.map_or(true, |(_, best_late, best_since_start)| {
+                    late > best_late || (late == best_late && since_start > best_since_start)
+                });
+                if first {
+                    self.due = Some((key, late, since_start));
+                }
+            }
+            None => {
+                if let Some(deadline) = schedule.deadline() {
+                    let now = self.now;
+                    let sooner = self.deadline.map_or(true, |next| {
+                        deadline.wrapping_sub(now) < next.wrapping_sub(now)
+                    });
+                    if sooner {
+                        self.deadline = Some(deadline);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The due event with the earliest deadline.
+    pub fn due(&self) -> Option<K> {
+        self.due.map(|(key, _, _)| key)
+    }
+
+    /// The earliest deadline of the events that are not due yet.
+    pub fn deadline(&self) -> Option<u32> {
+        self.deadline
+    }
+}
+
+pub struct Scheduler<'a, A: Alarm> {
+    alarm: &'a A,
+    /// End of the current step of the running event
+    timeout: OptionalCell<u32>,
+    /// The alarm is set for a deadline or timeout
+    armed: Cell<bool>,
+}
+
+impl<A: Alarm> Scheduler<'a, A> {
+    pub fn new(alarm: &'a A) -> Scheduler<'a, A> {
+        Scheduler {
+            alarm: alarm,
+            timeout: OptionalCell::empty(),
+            armed: Cell::new(false),
+        }
+    }
+
+    pub fn now(&self) -> u32 {
+        self.alarm.now()
+    }
+
+    pub fn ms_to_ticks(&self, ms: u32) -> u32 {
+        (ms as u64 * A::Frequency::frequency() as u64 / 1000) as u32
+    }
+
+    pub fn us_to_ticks(&self, us: u32) -> u32 {
+        (us as u64 * A::Frequency::frequency() as u64 / 1_000_000) as u32
+    }
+
+    /// Start scheduling events every `interval_ms`, the first one after
+    /// `interval_ms` plus `advDelay`.
+    pub fn start(&self, schedule: &mut Schedule, interval_ms: u32) {
+        let now = self.now();
+        // Seed the generator, different processes start at different times
+        if now != 0 {
+            schedule.nonce = now;
+        }
+        schedule.active = true;
+        schedule.interval = self.ms_to_ticks(interval_ms);
+        schedule.last_start = now;
+        schedule.deadline = now.wrapping_add(schedule.interval + self.adv_delay(schedule));
+    }
+
+    pub fn stop(&self, schedule: &mut Schedule) {
+        schedule.active = false;
+    }
+
+    /// The event of `schedule` starts now.
+    pub fn event_started(&self, schedule: &mut Schedule) {
+        let now = self.now();
+        schedule.last_start = now;
+        schedule.deadline = now.wrapping_add(schedule.interval + self.adv_delay(schedule));
+    }
+
+    fn adv_delay(&self, schedule: &mut Schedule) -> u32 {
+        schedule.random() % (self.ms_to_ticks(MAX_ADV_DELAY_MS) + 1)
+    }
+
+    /// End the current step of the running event `us` from now.
+    pub fn set_timeout(&self, us: u32) {
+        let timeout = self.now().wrapping_add(self.us_to_ticks(us));
+        self.timeout.set(timeout);
+    }
+
+    pub fn clear_timeout(&self) {
+        self.timeout.clear();
+    }
+
+    /// Whether the timeout passed, in which case it is cleared.
+    pub fn timeout_expired(&self) -> bool {
+        let now = self.now();
+        let expired = self
+            .timeout
+            .map_or(false, |timeout| (now.wrapping_sub(*timeout) as i32) >= 0);
+        if expired {
+            self.timeout.clear();
+        }
+        expired
+    }
+
+    /// Set the alarm for the timeout if there is one, or otherwise for the
+    /// given deadline.
+    pub fn arm(&self, deadline: Option<u32>) {
+        match self.timeout.map(|timeout| *timeout).or(deadline) {
+            Some(at) => {
+                let now = self.now();
+                let at = if (at.wrapping_sub(now) as i32) < MIN_ALARM_TICKS as i32 {
+                    now.wrapping_add(MIN_ALARM_TICKS)
+                } else {
+                    at
+                };
+                self.armed.set(true);
+                self.alarm.set_alarm(at);
+            }
+            None => {
+                if self.armed.get() {
+                    self.armed.set(false);
+                    self.alarm.disable();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cmp;
+    use kernel::hil::time::{Freq32KHz, Time};
+
+    struct FakeAlarm {
+        now: Cell<u32>,
+        alarm: Cell<Option<u32>>,
+    }
+
+    impl FakeAlarm {
+        fn new(now: u32) -> FakeAlarm {
+            FakeAlarm {
+                now: Cell::new(now),
+                alarm: Cell::new(None),
+            }
+        }
+
+        fn advance(&self, ticks: u32) {
+            self.now.set(self.now.get().wrapping_add(ticks));
+        }
+
+        /// Move to the alarm, returning whether it was set.
+        fn fire(&self) -> bool {
+            match self.alarm.take() {
+                Some(at) => {
+                    assert!((at.wrapping_sub(self.now.get()) as i32) > 0);
+                    self.now.set(at);
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    impl Time for FakeAlarm {
+        type Frequency = Freq32KHz;
+
+        fn disable(&self) {
+            self.alarm.set(None);
+        }
+
+        fn is_armed(&self) -> bool {
+            self.alarm.get().is_some()
+        }
+    }
+
+    impl Alarm for FakeAlarm {
+        fn now(&self) -> u32 {
+            self.now.get()
+        }
+
+        fn set_alarm(&self, tics: u32) {
+            self.alarm.set(Some(tics));
+        }
+
+        fn get_alarm(&self) -> u32 {
+            self.alarm.get().unwrap_or(0)
+        }
+    }
+
+    const MAX_APPS: usize = 8;
+
+    /// Starts of each app's events, relative to the start of the replay
+    struct Replay {
+        events: [usize; MAX_APPS],
+        max_gap: [u32; MAX_APPS],
+        min_gap: [u32; MAX_APPS],
+        /// Longest wait of a due event, in number of other events run first
+        max_wait: [usize; MAX_APPS],
+    }
+
+    /// Run apps advertising at `intervals_ms` for `duration_ms`, each event
+    /// taking `event_ms`, the way the advertising driver does.
+    fn replay(start: u32, intervals_ms: &[u32], event_ms: u32, duration_ms: u32) -> Replay {
+        let alarm = FakeAlarm::new(start);
+        let scheduler = Scheduler::new(&alarm);
+        let mut schedules = [Schedule::default(); MAX_APPS];
+        let mut last_start = [0u32; MAX_APPS];
+        let mut due_since = [None; MAX_APPS];
+        let mut replay = Replay {
+            events: [0; MAX_APPS],
+            max_gap: [0; MAX_APPS],
+            min_gap: [u32::max_value(); MAX_APPS],
+            max_wait: [0; MAX_APPS],
+        };
+        let apps = intervals_ms.len();
+        for (i, interval) in intervals_ms.iter().enumerate() {
+            // Processes start at slightly different times
+            alarm.advance(i as u32 + 1);
+            scheduler.start(&mut schedules[i], *interval);
+            last_start[i] = alarm.now();
+        }
+
+        let end = start.wrapping_add(scheduler.ms_to_ticks(duration_ms));
+        while (end.wrapping_sub(alarm.now()) as i32) > 0 {
+            let mut next = Earliest::new(alarm.now());
+            for i in 0..apps {
+                next.offer(i, &schedules[i]);
+                if schedules[i].lateness(alarm.now()).is_some() && due_since[i].is_none() {
+                    due_since[i] = Some(0);
+                }
+            }
+            match next.due() {
+                Some(i) => {
+                    let gap = alarm.now().wrapping_sub(last_start[i]);
+                    if replay.events[i] > 0 {
+                        replay.max_gap[i] = cmp::max(replay.max_gap[i], gap);
+                        replay.min_gap[i] = cmp::min(replay.min_gap[i], gap);
+                    }
+                    replay.events[i] += 1;
+                    last_start[i] = alarm.now();
+                    replay.max_wait[i] =
+                        cmp::max(replay.max_wait[i], due_since[i].take().unwrap_or(0));
+                    for j in 0..apps {
+                        if let Some(waited) = due_since[j].as_mut() {
+                            *waited += 1;
+                        }
+                    }
+                    scheduler.event_started(&mut schedules[i]);
+                    // The radio is busy for the whole event
+                    alarm.advance(scheduler.ms_to_ticks(event_ms));
+                }
+                None => {
+                    scheduler.arm(next.deadline());
+                    assert!(alarm.fire(), "no alarm set while apps are advertising");
+                }
+            }
+        }
+        replay
+    }
+
+    #[test]
+    fn single_app_interval_and_jitter() {
+        let alarm = FakeAlarm::new(0);
+        let scheduler = Scheduler::new(&alarm);
+        let interval = scheduler.ms_to_ticks(100);
+        let max_delay = scheduler.ms_to_ticks(MAX_ADV_DELAY_MS);
+
+        let r = replay(0, &[100], 1, 10_000);
+        assert!(r.min_gap[0] >= interval);
+        assert!(r.max_gap[0] <= interval + max_delay);
+        // 10 s at 100 to 110 ms per event
+        assert!(r.events[0] >= 90 && r.events[0] <= 100);
+    }
+
+    #[test]
+    fn adv_delay_is_random() {
+        let alarm = FakeAlarm::new(1234);
+        let scheduler = Scheduler::new(&alarm);
+        let mut schedule = Schedule::default();
+        scheduler.start(&mut schedule, 20);
+        let interval = scheduler.ms_to_ticks(20);
+
+        let mut min = u32::max_value();
+        let mut max = 0;
+        for _ in 0..1000 {
+            let deadline = schedule.deadline().unwrap();
+            let delay = deadline.wrapping_sub(alarm.now()) - interval;
+            min = cmp::min(min, delay);
+            max = cmp::max(max, delay);
+            alarm.now.set(deadline);
+            scheduler.event_started(&mut schedule);
+        }
+        assert!(max <= scheduler.ms_to_ticks(MAX_ADV_DELAY_MS));
+        // The delays spread over most of the allowed range
+        assert!(min < scheduler.ms_to_ticks(1));
+        assert!(max > scheduler.ms_to_ticks(9));
+    }
+
+    #[test]
+    fn earliest_deadline_goes_first() {
+        let alarm = FakeAlarm::new(0);
+        let scheduler = Scheduler::new(&alarm);
+        let mut a = Schedule::default();
+        let mut b = Schedule::default();
+        scheduler.start(&mut a, 100);
+        scheduler.start(&mut b, 100);
+        a.deadline = 500;
+        b.deadline = 400;
+
+        alarm.now.set(300);
+        let mut next = Earliest::new(alarm.now());
+        next.offer('a', &a);
+        next.offer('b', &b);
+        assert_eq!(next.due(), None);
+        assert_eq!(next.deadline(), Some(400));
+
+        alarm.now.set(600);
+        let mut next = Earliest::new(alarm.now());
+        next.offer('a', &a);
+        next.offer('b', &b);
+        assert_eq!(next.due(), Some('b'));
+    }
+
+    #[test]
+    fn ties_go_to_least_recently_served() {
+        let alarm = FakeAlarm::new(0);
+        let scheduler = Scheduler::new(&alarm);
+        let mut a = Schedule::default();
+        let mut b = Schedule::default();
+        scheduler.start(&mut a, 100);
+        scheduler.start(&mut b, 100);
+        a.deadline = 400;
+        a.last_start = 200;
+        b.deadline = 400;
+        b.last_start = 100;
+
+        let mut next = Earliest::new(500);
+        next.offer('a', &a);
+        next.offer('b', &b);
+        assert_eq!(next.due(), Some('b'));
+    }
+
+    #[test]
+    fn identical_intervals_share_the_radio() {
+        // Events take longer than the interval divided by the number of apps,
+        // so the radio is always busy
+        let r = replay(0, &[20, 20, 20, 20, 20, 20], 8, 20_000);
+        let min = r.events[..6].iter().min().unwrap();
+        let max = r.events[..6].iter().max().unwrap();
+        // advDelay reorders the apps from round to round, but none falls
+        // behind by more than a few events
+        assert!(*min > 0);
+        assert!(max - min <= 3, "events {:?}", &r.events[..6]);
+        for i in 0..6 {
+            assert!(r.max_wait[i] <= 5, "app {} waited {}", i, r.max_wait[i]);
+        }
+    }
+
+    #[test]
+    fn fast_app_does_not_starve_slow_apps() {
+        let alarm = FakeAlarm::new(0);
+        let scheduler = Scheduler::new(&alarm);
+        let intervals = [20, 1000, 1000, 250, 37, 1000, 500, 20];
+        let event_ms = 5;
+        let r = replay(0, &intervals, event_ms, 60_000);
+        for (i, interval) in intervals.iter().enumerate() {
+            assert!(r.events[i] > 0, "app {} never advertised", i);
+            // A due event waits for at most one event of every other app
+            assert!(
+                r.max_wait[i] < intervals.len(),
+                "app {} waited {}",
+                i,
+                r.max_wait[i]
+            );
+            let bound = scheduler
+                .ms_to_ticks(interval + MAX_ADV_DELAY_MS + event_ms * (intervals.len() as u32 - 1));
+            assert!(
+                r.max_gap[i] <= bound + scheduler.ms_to_ticks(event_ms),
+                "app {} gap {} above {}",
+                i,
+                r.max_gap[i],
+                bound
+            );
+        }
+    }
+
+    #[test]
+    fn clock_wraps_around() {
+        let alarm = FakeAlarm::new(0);
+        let scheduler = Scheduler::new(&alarm);
+        let interval = scheduler.ms_to_ticks(100);
+        let max_delay = scheduler.ms_to_ticks(MAX_ADV_DELAY_MS);
+
+        let r = replay(u32::max_value() - 5000, &[100, 150], 1, 5_000);
+        assert!(r.events[0] >= 45);
+        assert!(r.events[1] >= 30);
+        assert!(r.max_gap[0] <= interval + max_delay + scheduler.ms_to_ticks(1));
+    }
+
+    #[test]
+    fn timeout_comes_before_deadlines() {
+        let alarm = FakeAlarm::new(1000);
+        let scheduler = Scheduler::new(&alarm);
+        scheduler.set_timeout(2000);
+        scheduler.arm(Some(5000));
+        assert_eq!(alarm.alarm.get(), Some(1000 + scheduler.us_to_ticks(2000)));
+        assert!(!scheduler.timeout_expired());
+
+        assert!(alarm.fire());
+        assert!(scheduler.timeout_expired());
+        scheduler.arm(Some(5000));
+        assert_eq!(alarm.alarm.get(), Some(5000));
+
+        // Deadlines in the past set the alarm just ahead of now
+        alarm.now.set(6000);
+        scheduler.arm(Some(5000));
+        assert_eq!(alarm.alarm.get(), Some(6000 + MIN_ALARM_TICKS));
+
+        scheduler.arm(None);
+        assert_eq!(alarm.alarm.get(), None);
+    }
+}
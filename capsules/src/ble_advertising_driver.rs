@@ -8,15 +8,23 @@
 //! Processes can also control the TX power used for their advertisements.
 //!
 //! Data payloads are limited to 31 bytes since the maximum advertising channel
-//! protocol data unit (PDU) is 37 bytes and includes a 6-byte header.
+//! protocol data unit (PDU) is 37 bytes and includes a 6-byte header. Scannable
+//! advertisements can carry another 31 bytes of scan response data, which is
+//! sent to scanners that ask for it with a `SCAN_REQ`.
+//!
+//! Scanning is either passive, only listening for advertisements, or active,
+//! also sending a `SCAN_REQ` to scannable advertisers and reporting their
+//! `SCAN_RSP`. A process can set a filter on the advertising data so that only
+//! advertisements it is interested in are copied to its scanning buffer.
 //!
 //! ### Allow system call
 //!
 //! The allow systems calls are used for buffers from allocated by userland
 //!
-//! There are two different buffers:
+//! There are three different buffers:
 //! * 0: Advertising data
-//! * 1: Passive scanning buffer
+//! * 1: Scanning buffer
+//! * 2: Scan response data
 //!
 //! The possible return codes from the 'allow' system call indicate the following:
 //!
@@ -48,19 +56,30 @@
 //!
 //! * 0: start advertisement
 //! * 1: stop advertisement or scanning
-//! * 5: start scanning
+//! * 2: configure transmitting power
+//! * 5: start passive scanning
+//! * 6: start active scanning
+//! * 7: set the scan filter, with `subcommand number` selecting the filter and
+//!      the third argument its value:
+//!   - 0: none
+//!   - 1: advertises the 16-bit service UUID
+//!   - 2: has manufacturer specific data of the company identifier
+//!   - 3: has all of the flags set
+//!   - 4: has a local name
 //!
 //! The possible return codes from the `command` system call indicate the following:
 //!
 //! * SUCCESS:      The command was successful
-//! * EBUSY:        The driver is currently busy with other tasks
+//! * EBUSY:        The driver is currently busy with other tasks, or the radio is used by the
+//!                 BLE link layer it is shared with
 //! * ENOSUPPORT:   The operation is not supported
 //!
 //! Usage
 //! -----
 //!
-//! You need a device that provides the `kernel::BleAdvertisementDriver` trait along with a virtual
-//! timer to perform events and not block the entire kernel
+//! You need a device that provides the `kernel::BleAdvertisementDriver` and
+//! `kernel::BleAdvertisementResponder` traits along with a virtual timer to perform events and not
+//! block the entire kernel
 //!
 //! ```rust
 //!     let ble_radio = static_init!(
@@ -75,6 +94,8 @@
 //!                                                                      ble_radio);
 //!    nrf5x::ble_advertising_hil::BleAdvertisementDriver::set_tx_client(&nrf52::radio::RADIO,
 //!                                                                      ble_radio);
+//!    nrf5x::ble_advertising_hil::BleAdvertisementResponder::set_response_client(
+//!        &nrf52::radio::RADIO, ble_radio);
 //!    ble_radio_virtual_alarm.set_client(ble_radio);
 //! ```
 //!
@@ -85,23 +106,31 @@
 
 // # Implementation
 //
-// Advertising virtualization works by implementing a virtual periodic timer for each process. The
-// timer is configured to fire at each advertising interval, as specified by the process. When a
-// timer fires, we serialize the advertising packet for that process (using the provided AdvData
-// payload, generated address and PDU type) and perform one advertising event (on each of three
-// channels).
+// Advertising virtualization works by giving each process a deadline for its next advertising or
+// scanning event, as specified by its interval. The `ble::scheduler` runs the due event with the
+// earliest deadline whenever the radio is free. For an advertising event we serialize the
+// advertising packet for that process (using the provided AdvData payload, generated address and
+// PDU type) and send it on each of the three advertising channels. A scanning event listens on each
+// of the three channels until a packet is received or the scan window is over.
 //
-// This means that advertising events can collide. In this case, we just defer one of the
-// advertisements. Because we add a pseudo random pad to the timer interval each time (as required
-// by the Bluetooth specification) multiple collisions of the same processes are highly unlikely.
-
+// Scannable advertisements with scan response data keep the radio listening for a `SCAN_REQ` for
+// a short window after each of the three advertisements, and the scan response is sent by the radio
+// from within that window. Active scanning is the mirror image: the radio sends a `SCAN_REQ` to a
+// scannable advertiser as soon as its advertisement is received and reports the `SCAN_RSP`.
+//
+// When events of several processes are due at the same time, all but one are deferred. A deferred
+// event keeps its deadline, so it goes before the events of any process that was served in the
+// meantime and no process starves. Because we add a pseudo random delay to the interval each time
+// (as required by the Bluetooth specification) the same processes rarely collide repeatedly.
+
+use crate::ble::advertising_data::{self, AdFilter};
+use crate::ble::scheduler::{Earliest, Schedule, Scheduler};
+use crate::ble::RadioUser;
 use core::cell::Cell;
 use core::cmp;
 use kernel::common::cells::OptionalCell;
-use kernel::debug;
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
-use kernel::hil::time::Frequency;
 use kernel::ReturnCode;
 
 /// Syscall driver number.
@@ -114,6 +143,16 @@ pub static mut BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
 const PACKET_ADDR_LEN: usize = 6;
 const PACKET_LENGTH: usize = 39;
 const ADV_HEADER_TXADD_OFFSET: usize = 6;
+const ADV_HEADER_RXADD_OFFSET: usize = 7;
+const ADV_HEADER_TYPE_MASK: u8 = 0x0f;
+const MAX_ADV_DATA_LEN: usize = 31;
+
+// How long to listen for a SCAN_REQ after an advertisement: T_IFS, the
+// SCAN_REQ, T_IFS again and the longest SCAN_RSP take less than 1 ms
+const SCAN_REQ_LISTEN_US: u32 = 2000;
+
+// How long to listen on each channel during a scanning event, unless a packet is received earlier
+const SCAN_WINDOW_US: u32 = 10_000;
 
 #[derive(PartialEq, Debug)]
 enum BLEState {
@@ -125,27 +164,6 @@ enum BLEState {
     Advertising(RadioChannel),
 }
 
-#[derive(Copy, Clone)]
-enum Expiration {
-    Disabled,
-    Abs(u32),
-}
-
-#[derive(Copy, Clone)]
-struct AlarmData {
-    t0: u32,
-    expiration: Expiration,
-}
-
-impl AlarmData {
-    fn new() -> AlarmData {
-        AlarmData {
-            t0: 0,
-            expiration: Expiration::Disabled,
-        }
-    }
-}
-
 type AdvPduType = u8;
 
 // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.3
@@ -153,9 +171,7 @@ const ADV_IND: AdvPduType = 0b0000;
 #[allow(dead_code)]
 const ADV_DIRECTED_IND: AdvPduType = 0b0001;
 const ADV_NONCONN_IND: AdvPduType = 0b0010;
-#[allow(dead_code)]
 const SCAN_REQ: AdvPduType = 0b0011;
-#[allow(dead_code)]
 const SCAN_RESP: AdvPduType = 0b0100;
 #[allow(dead_code)]
 const CONNECT_IND: AdvPduType = 0b0101;
@@ -164,7 +180,7 @@ const ADV_SCAN_IND: AdvPduType = 0b0110;
 /// Process specific memory
 pub struct App {
     process_status: Option<BLEState>,
-    alarm_data: AlarmData,
+    schedule: Schedule,
 
     // Advertising meta-data
     adv_data: Option<kernel::AppSlice<kernel::Shared, u8>>,
@@ -172,32 +188,35 @@ pub struct App {
     pdu_type: AdvPduType,
     advertisement_interval_ms: u32,
     tx_power: u8,
-    /// The state of an app-specific pseudo random number.
-    ///
-    /// For example, it can be used for the pseudo-random `advDelay` parameter.
-    /// It should be read using the `random_number` method, which updates it as
-    /// well.
-    random_nonce: u32,
+
+    // Scan response meta-data
+    scan_rsp_data: Option<kernel::AppSlice<kernel::Shared, u8>>,
 
     // Scanning meta-data
     scan_buffer: Option<kernel::AppSlice<kernel::Shared, u8>>,
     scan_callback: Option<kernel::Callback>,
+    active_scan: bool,
+    scan_filter: AdFilter,
+    /// Advertiser a `SCAN_REQ` was sent to, whose `SCAN_RSP` is expected
+    scan_target: Option<[u8; PACKET_ADDR_LEN]>,
 }
 
 impl Default for App {
     fn default() -> App {
         App {
-            alarm_data: AlarmData::new(),
+            schedule: Schedule::default(),
             adv_data: None,
+            scan_rsp_data: None,
             scan_buffer: None,
+            active_scan: false,
+            scan_filter: AdFilter::None,
+            scan_target: None,
             address: [0; PACKET_ADDR_LEN],
             pdu_type: ADV_NONCONN_IND,
             scan_callback: None,
             process_status: Some(BLEState::NotInitialized),
             tx_power: 0,
             advertisement_interval_ms: 200,
-            // Just use any non-zero starting value by default
-            random_nonce: 0xdeadbeef,
         }
     }
 }
@@ -231,9 +250,17 @@ impl App {
         ReturnCode::SUCCESS
     }
 
+    // Scan requests are only answered for scannable advertisements with scan
+    // response data
+    fn has_scan_response(&self) -> bool {
+        (self.pdu_type == ADV_IND || self.pdu_type == ADV_SCAN_IND) && self.scan_rsp_data.is_some()
+    }
+
     fn send_advertisement<'a, B, A>(&self, ble: &BLE<'a, B, A>, channel: RadioChannel) -> ReturnCode
     where
-        B: ble_advertising::BleAdvertisementDriver + ble_advertising::BleConfig,
+        B: ble_advertising::BleAdvertisementDriver
+            + ble_advertising::BleAdvertisementResponder
+            + ble_advertising::BleConfig,
         A: kernel::hil::time::Alarm,
     {
         self.adv_data
@@ -265,9 +292,13 @@ impl App {
                             data[..adv_data_len].copy_from_slice(adv_data_corrected);
                         }
                         let total_len = cmp::min(PACKET_LENGTH, payload_len + 2);
-                        let result = ble
-                            .radio
-                            .transmit_advertisement(kernel_tx, total_len, channel);
+                        let result = if self.has_scan_response() {
+                            ble.radio
+                                .transmit_advertisement_and_listen(kernel_tx, total_len, channel)
+                        } else {
+                            ble.radio
+                                .transmit_advertisement(kernel_tx, total_len, channel)
+                        };
                         ble.kernel_tx.replace(result);
                         ReturnCode::SUCCESS
                     })
@@ -276,47 +307,117 @@ impl App {
             .unwrap_or(ReturnCode::FAIL)
     }
 
-    // Returns a new pseudo-random number and updates the randomness state.
-    //
-    // Uses the [Xorshift](https://en.wikipedia.org/wiki/Xorshift) algorithm to
-    // produce pseudo-random numbers. Uses the `random_nonce` field to keep
-    // state.
-    fn random_nonce(&mut self) -> u32 {
-        let mut next_nonce = ::core::num::Wrapping(self.random_nonce);
-        next_nonce ^= next_nonce << 13;
-        next_nonce ^= next_nonce >> 17;
-        next_nonce ^= next_nonce << 5;
-        self.random_nonce = next_nonce.0;
-        self.random_nonce
+    // Build the SCAN_RSP to a SCAN_REQ addressed to this app, if it is in the middle of an
+    // advertising event that accepts scan requests
+    fn scan_response(&self, pdu: &[u8], response: &mut [u8]) -> usize {
+        match self.process_status {
+            Some(BLEState::Advertising(_)) if self.has_scan_response() => {}
+            _ => return 0,
+        }
+        // SCAN_REQ payload: ScanA (6), AdvA (6)
+        if pdu.len() < 2 + 2 * PACKET_ADDR_LEN
+            || pdu[1] & 0x3f != 2 * PACKET_ADDR_LEN as u8
+            || pdu[2 + PACKET_ADDR_LEN..2 + 2 * PACKET_ADDR_LEN] != self.address
+        {
+            return 0;
+        }
+        self.scan_rsp_data.as_ref().map_or(0, |data| {
+            let data_len = cmp::min(
+                cmp::min(data.len(), MAX_ADV_DATA_LEN),
+                response.len().saturating_sub(2 + PACKET_ADDR_LEN),
+            );
+            response[0] = SCAN_RESP | 1 << ADV_HEADER_TXADD_OFFSET;
+            response[1] = (PACKET_ADDR_LEN + data_len) as u8;
+            response[2..2 + PACKET_ADDR_LEN].copy_from_slice(&self.address);
+            response[2 + PACKET_ADDR_LEN..2 + PACKET_ADDR_LEN + data_len]
+                .copy_from_slice(&data.as_ref()[..data_len]);
+            2 + PACKET_ADDR_LEN + data_len
+        })
     }
 
-    // Set the next alarm for this app using the period and provided start time.
-    fn set_next_alarm<F: Frequency>(&mut self, now: u32) {
-        self.alarm_data.t0 = now;
-        let nonce = self.random_nonce() % 10;
+    // Build a SCAN_REQ to the scannable advertiser of `pdu` if this app is actively scanning and
+    // interested in it. The advertisement itself is reported to the app right away, since the
+    // radio only reports the packet that follows the request.
+    fn scan_request(&mut self, pdu: &[u8], response: &mut [u8]) -> usize {
+        match self.process_status {
+            Some(BLEState::Scanning(_)) if self.active_scan => {}
+            _ => return 0,
+        }
+        if response.len() < 2 + 2 * PACKET_ADDR_LEN || !self.report_scan(pdu) {
+            return 0;
+        }
+        // The RxAdd bit of the request is the TxAdd bit of the advertiser
+        let adv_random = (pdu[0] >> ADV_HEADER_TXADD_OFFSET) & 1;
+        response[0] =
+            SCAN_REQ | 1 << ADV_HEADER_TXADD_OFFSET | adv_random << ADV_HEADER_RXADD_OFFSET;
+        response[1] = 2 * PACKET_ADDR_LEN as u8;
+        response[2..2 + PACKET_ADDR_LEN].copy_from_slice(&self.address);
+        response[2 + PACKET_ADDR_LEN..2 + 2 * PACKET_ADDR_LEN]
+            .copy_from_slice(&pdu[2..2 + PACKET_ADDR_LEN]);
+        let mut target = [0; PACKET_ADDR_LEN];
+        target.copy_from_slice(&pdu[2..2 + PACKET_ADDR_LEN]);
+        self.scan_target = Some(target);
+        2 + 2 * PACKET_ADDR_LEN
+    }
+
+    // Copy a received PDU to the scanning buffer and notify the app if it passes the scan filter.
+    // Returns whether it was reported.
+    fn report_scan(&mut self, pdu: &[u8]) -> bool {
+        // Only the SCAN_RSP to our own SCAN_REQ is of interest
+        let scan_target = self.scan_target.take();
+        let interesting = match (pdu.first().map(|h| h & ADV_HEADER_TYPE_MASK), scan_target) {
+            (Some(SCAN_RESP), Some(target)) => {
+                pdu.len() >= 2 + PACKET_ADDR_LEN && pdu[2..2 + PACKET_ADDR_LEN] == target
+            }
+            _ => match advertising_data::advertising_data(pdu) {
+                Some(data) => self.scan_filter.matches(data),
+                None => self.scan_filter == AdFilter::None,
+            },
+        };
+        if !interesting {
+            return false;
+        }
 
-        let period_ms = (self.advertisement_interval_ms + nonce) * F::frequency() / 1000;
-        self.alarm_data.expiration = Expiration::Abs(now.wrapping_add(period_ms));
+        let reported = self
+            .scan_buffer
+            .as_mut()
+            .map(|userland| {
+                for (dst, src) in userland.iter_mut().zip(pdu.iter()) {
+                    *dst = *src;
+                }
+            })
+            .is_some();
+        if reported {
+            self.scan_callback.map(|mut cb| {
+                cb.schedule(usize::from(ReturnCode::SUCCESS), pdu.len(), 0);
+            });
+        }
+        reported
     }
 }
 
 pub struct BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleAdvertisementResponder
+        + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm,
 {
     radio: &'a B,
     busy: Cell<bool>,
     app: kernel::Grant<App>,
     kernel_tx: kernel::common::cells::TakeCell<'static, [u8]>,
-    alarm: &'a A,
+    scheduler: Scheduler<'a, A>,
     sending_app: OptionalCell<kernel::AppId>,
     receiving_app: OptionalCell<kernel::AppId>,
+    radio_sharer: OptionalCell<&'a RadioUser>,
 }
 
 impl<B, A> BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleAdvertisementResponder
+        + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm,
 {
     pub fn new(
@@ -330,37 +431,172 @@ where
             busy: Cell::new(false),
             app: container,
             kernel_tx: kernel::common::cells::TakeCell::new(tx_buf),
-            alarm: alarm,
+            scheduler: Scheduler::new(alarm),
             sending_app: OptionalCell::empty(),
             receiving_app: OptionalCell::empty(),
+            radio_sharer: OptionalCell::empty(),
+        }
+    }
+
+    /// Share the radio with `other`, so that advertising and scanning only
+    /// start while `other` does not use the radio.
+    pub fn share_radio_with(&self, other: &'a RadioUser) {
+        self.radio_sharer.set(other);
+    }
+
+    // Whether the radio is used by the other user it is shared with.
+    fn radio_shared_busy(&self) -> bool {
+        self.radio_sharer.map_or(false, |other| other.uses_radio())
+    }
+
+    // Advertise on the next channel of the advertising event of `app`, or end the event after
+    // channel 39.
+    fn next_advertisement(&self, app: &mut App) {
+        match app.process_status {
+            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37)) => {
+                app.process_status =
+                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38));
+                self.radio.set_tx_power(app.tx_power);
+                app.send_advertisement(&self, RadioChannel::AdvertisingChannel38);
+            }
+
+            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38)) => {
+                app.process_status =
+                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39));
+                app.send_advertisement(&self, RadioChannel::AdvertisingChannel39);
+            }
+
+            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39)) => {
+                if app.has_scan_response() {
+                    self.radio.stop_listening();
+                }
+                self.scheduler.clear_timeout();
+                self.busy.set(false);
+                app.process_status = Some(BLEState::AdvertisingIdle);
+            }
+            // Invalid state => don't care
+            _ => (),
+        }
+    }
+
+    // Scan on the next channel of the scanning event of `app`, or end the event after channel 39.
+    fn next_scan(&self, app: &mut App) {
+        match app.process_status {
+            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37)) => {
+                app.process_status = Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38));
+                self.radio.set_tx_power(app.tx_power);
+                self.scan(app, RadioChannel::AdvertisingChannel38);
+            }
+            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38)) => {
+                app.process_status = Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39));
+                self.scan(app, RadioChannel::AdvertisingChannel39);
+            }
+            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39)) => {
+                self.radio.stop_listening();
+                self.scheduler.clear_timeout();
+                self.busy.set(false);
+                app.process_status = Some(BLEState::ScanningIdle);
+            }
+            // Invalid state => don't care
+            _ => (),
+        }
+    }
+
+    fn scan(&self, app: &App, channel: RadioChannel) {
+        self.scheduler.set_timeout(SCAN_WINDOW_US);
+        if app.active_scan {
+            self.radio.receive_advertisement_and_respond(channel);
+        } else {
+            self.radio.receive_advertisement(channel);
         }
     }
 
-    // Determines which app timer will expire next and sets the underlying alarm
-    // to it.
+    // Start the advertising or scanning event of `app` on the first channel.
+    fn start_event(&self, appid: kernel::AppId, app: &mut App) {
+        match app.process_status {
+            Some(BLEState::AdvertisingIdle) => {
+                self.scheduler.event_started(&mut app.schedule);
+                self.busy.set(true);
+                app.process_status =
+                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37));
+                self.sending_app.set(appid);
+                self.radio.set_tx_power(app.tx_power);
+                app.send_advertisement(&self, RadioChannel::AdvertisingChannel37);
+            }
+            Some(BLEState::ScanningIdle) => {
+                self.scheduler.event_started(&mut app.schedule);
+                self.busy.set(true);
+                app.process_status = Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37));
+                self.receiving_app.set(appid);
+                self.radio.set_tx_power(app.tx_power);
+                self.scan(app, RadioChannel::AdvertisingChannel37);
+            }
+            // Invalid state => don't care
+            _ => (),
+        }
+    }
+
+    // Move the running event on once the current step timed out.
+    fn event_timeout(&self) {
+        let advertising = self.sending_app.map_or(false, |appid| {
+            self.app
+                .enter(*appid, |app, _| match app.process_status {
+                    // The window for scan requests after an advertisement is over
+                    Some(BLEState::Advertising(_)) => {
+                        self.next_advertisement(app);
+                        true
+                    }
+                    _ => false,
+                })
+                .unwrap_or(false)
+        });
+        let scanning = !advertising
+            && self.receiving_app.map_or(false, |appid| {
+                self.app
+                    .enter(*appid, |app, _| match app.process_status {
+                        Some(BLEState::Scanning(_)) => {
+                            self.next_scan(app);
+                            true
+                        }
+                        _ => false,
+                    })
+                    .unwrap_or(false)
+            });
+        if !advertising && !scanning {
+            // The process of the event is gone
+            self.radio.stop_listening();
+            self.busy.set(false);
+        }
+    }
+
+    // Start the due event with the earliest deadline if the radio is free, and set the alarm for
+    // whatever has to happen next.
     //
     // This method iterates through all grants so it should be used somewhat
     // sparingly. Moreover, it should _not_ be called from within a grant,
-    // since any open grant will not be iterated over and the wrong timer will
+    // since any open grant will not be iterated over and the wrong event will
     // likely be chosen.
-    fn reset_active_alarm(&self) {
-        let now = self.alarm.now();
-        let mut next_alarm = u32::max_value();
-        let mut next_dist = u32::max_value();
+    fn run_next(&self) {
+        if self.busy.get() {
+            self.scheduler.arm(None);
+            return;
+        }
+
+        let mut next = Earliest::new(self.scheduler.now());
         for app in self.app.iter() {
-            app.enter(|app, _| match app.alarm_data.expiration {
-                Expiration::Abs(exp) => {
-                    let t_dist = exp.wrapping_sub(now);
-                    if next_dist > t_dist {
-                        next_alarm = exp;
-                        next_dist = t_dist;
-                    }
+            app.enter(|app, _| match app.process_status {
+                Some(BLEState::AdvertisingIdle) | Some(BLEState::ScanningIdle) => {
+                    next.offer(app.appid(), &app.schedule)
                 }
-                Expiration::Disabled => {}
+                _ => {}
             });
         }
-        if next_alarm != u32::max_value() {
-            self.alarm.set_alarm(next_alarm);
+        match next.due() {
+            Some(appid) => {
+                let _ = self.app.enter(appid, |app, _| self.start_event(appid, app));
+                self.scheduler.arm(None);
+            }
+            None => self.scheduler.arm(next.deadline()),
         }
     }
 }
@@ -368,81 +604,33 @@ where
 // Timer alarm
 impl<B, A> kernel::hil::time::Client for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleAdvertisementResponder
+        + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm,
 {
-    // When an alarm is fired, we find which apps have expired timers. Expired
-    // timers indicate a desire to perform some operation (e.g. start an
-    // advertising or scanning event). We know which operation based on the
-    // current app's state.
-    //
-    // In case of collision---if there is already an event happening---we'll
-    // just delay the operation for next time and hope for the best. Since some
-    // randomness is added for each period in an app's timer, collisions should
-    // be rare in practice.
-    //
-    // TODO: perhaps break ties more fairly by prioritizing apps that have least
-    // recently performed an operation.
+    // When an alarm fires, either the current step of the running event timed out, e.g. the window
+    // for scan requests after an advertisement is over, or the deadline of an event passed and it
+    // can be started if the radio is free.
     fn fired(&self) {
-        let now = self.alarm.now();
-
-        self.app.each(|app| {
-            if let Expiration::Abs(exp) = app.alarm_data.expiration {
-                let expired =
-                    now.wrapping_sub(app.alarm_data.t0) >= exp.wrapping_sub(app.alarm_data.t0);
-                if expired {
-                    if self.busy.get() {
-                        // The radio is currently busy, so we won't be able to start the
-                        // operation at the appropriate time. Instead, reschedule the
-                        // operation for later. This is _kind_ of simulating actual
-                        // on-air interference
-                        debug!("BLE: operation delayed for app {:?}", app.appid());
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now());
-                        return;
-                    }
-
-                    app.alarm_data.expiration = Expiration::Disabled;
-
-                    match app.process_status {
-                        Some(BLEState::AdvertisingIdle) => {
-                            self.busy.set(true);
-                            app.process_status =
-                                Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37));
-                            self.sending_app.set(app.appid());
-                            self.radio.set_tx_power(app.tx_power);
-                            app.send_advertisement(&self, RadioChannel::AdvertisingChannel37);
-                        }
-                        Some(BLEState::ScanningIdle) => {
-                            self.busy.set(true);
-                            app.process_status =
-                                Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37));
-                            self.receiving_app.set(app.appid());
-                            self.radio.set_tx_power(app.tx_power);
-                            self.radio
-                                .receive_advertisement(RadioChannel::AdvertisingChannel37);
-                        }
-                        _ => debug!(
-                            "app: {:?} \t invalid state {:?}",
-                            app.appid(),
-                            app.process_status
-                        ),
-                    }
-                }
-            }
-        });
-        self.reset_active_alarm();
+        if self.busy.get() && self.scheduler.timeout_expired() {
+            self.event_timeout();
+        }
+        self.run_next();
     }
 }
 
 // Callback from the radio once a RX event occur
 impl<B, A> ble_advertising::RxClient for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleAdvertisementResponder
+        + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm,
 {
     fn receive_event(&self, buf: &'static mut [u8], len: u8, result: ReturnCode) {
         self.receiving_app.map(|appid| {
-            let _ = self.app.enter(*appid, |app, _| {
+            let entered = self.app.enter(*appid, |app, _| {
                 // Validate the received data, because ordinary BLE packets can be bigger than 39
                 // bytes. Thus, we need to check for that!
                 // Moreover, we use the packet header to find size but the radio reads maximum
@@ -453,50 +641,17 @@ where
                 // only be sent on the other 37 RadioChannel channels.
 
                 if len <= PACKET_LENGTH as u8 && result == ReturnCode::SUCCESS {
-                    // write to buffer in userland
-                    let success = app
-                        .scan_buffer
-                        .as_mut()
-                        .map(|userland| {
-                            for (dst, src) in userland.iter_mut().zip(buf[0..len as usize].iter()) {
-                                *dst = *src;
-                            }
-                        })
-                        .is_some();
-
-                    if success {
-                        app.scan_callback.map(|mut cb| {
-                            cb.schedule(usize::from(result), len as usize, 0);
-                        });
-                    }
+                    app.report_scan(&buf[0..len as usize]);
                 }
+                app.scan_target = None;
 
-                match app.process_status {
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37)) => {
-                        app.process_status =
-                            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38));
-                        self.receiving_app.set(app.appid());
-                        self.radio.set_tx_power(app.tx_power);
-                        self.radio
-                            .receive_advertisement(RadioChannel::AdvertisingChannel38);
-                    }
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38)) => {
-                        app.process_status =
-                            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39));
-                        self.receiving_app.set(app.appid());
-                        self.radio
-                            .receive_advertisement(RadioChannel::AdvertisingChannel39);
-                    }
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
-                        app.process_status = Some(BLEState::ScanningIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now());
-                    }
-                    // Invalid state => don't care
-                    _ => (),
-                }
+                self.next_scan(app);
             });
-            self.reset_active_alarm();
+            if entered.is_err() {
+                // The process of the event is gone
+                self.busy.set(false);
+            }
+            self.run_next();
         });
     }
 }
@@ -504,48 +659,81 @@ where
 // Callback from the radio once a TX event occur
 impl<B, A> ble_advertising::TxClient for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleAdvertisementResponder
+        + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm,
 {
     // The ReturnCode indicates valid CRC or not, not used yet but could be used for
     // re-transmissions for invalid CRCs
     fn transmit_event(&self, _crc_ok: ReturnCode) {
         self.sending_app.map(|appid| {
-            let _ = self.app.enter(*appid, |app, _| {
-                match app.process_status {
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37)) => {
-                        app.process_status =
-                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38));
-                        self.sending_app.set(app.appid());
-                        self.radio.set_tx_power(app.tx_power);
-                        app.send_advertisement(&self, RadioChannel::AdvertisingChannel38);
-                    }
-
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38)) => {
-                        app.process_status =
-                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39));
-                        self.sending_app.set(app.appid());
-                        app.send_advertisement(&self, RadioChannel::AdvertisingChannel39);
-                    }
-
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
-                        app.process_status = Some(BLEState::AdvertisingIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now());
-                    }
-                    // Invalid state => don't care
-                    _ => (),
+            let entered = self.app.enter(*appid, |app, _| {
+                if app.has_scan_response() {
+                    // Keep listening for scan requests for a while, the alarm moves on to the
+                    // next channel
+                    self.scheduler.set_timeout(SCAN_REQ_LISTEN_US);
+                } else {
+                    self.next_advertisement(app);
                 }
             });
-            self.reset_active_alarm();
+            if entered.is_err() {
+                // The process of the event is gone
+                self.radio.stop_listening();
+                self.busy.set(false);
+            }
+            self.run_next();
         });
     }
 }
 
+// Called by the radio within T_IFS of a received packet
+impl<B, A> ble_advertising::ResponseClient for BLE<'a, B, A>
+where
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleAdvertisementResponder
+        + ble_advertising::BleConfig,
+    A: kernel::hil::time::Alarm,
+{
+    fn respond(&self, pdu: &[u8], response: &mut [u8]) -> usize {
+        match pdu.first().map(|header| header & ADV_HEADER_TYPE_MASK) {
+            Some(SCAN_REQ) => self.sending_app.map_or(0, |appid| {
+                self.app
+                    .enter(*appid, |app, _| app.scan_response(pdu, response))
+                    .unwrap_or(0)
+            }),
+            Some(ADV_IND) | Some(ADV_SCAN_IND) if pdu.len() >= 2 + PACKET_ADDR_LEN => {
+                self.receiving_app.map_or(0, |appid| {
+                    self.app
+                        .enter(*appid, |app, _| app.scan_request(pdu, response))
+                        .unwrap_or(0)
+                })
+            }
+            _ => 0,
+        }
+    }
+}
+
+impl<B, A> RadioUser for BLE<'a, B, A>
+where
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleAdvertisementResponder
+        + ble_advertising::BleConfig,
+    A: kernel::hil::time::Alarm,
+{
+    fn uses_radio(&self) -> bool {
+        self.app
+            .iter()
+            .any(|app| app.enter(|app, _| app.schedule.is_active()))
+    }
+}
+
 // System Call implementation
 impl<B, A> kernel::Driver for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleAdvertisementResponder
+        + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm,
 {
     fn command(
@@ -557,40 +745,55 @@ where
     ) -> ReturnCode {
         match command_num {
             // Start periodic advertisements
-            0 => self
-                .app
-                .enter(appid, |app, _| {
-                    if let Some(BLEState::Initialized) = app.process_status {
-                        let pdu_type = data as AdvPduType;
-                        match pdu_type {
-                            ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
-                                app.pdu_type = pdu_type;
-                                app.process_status = Some(BLEState::AdvertisingIdle);
-                                app.random_nonce = self.alarm.now();
-                                app.advertisement_interval_ms = cmp::max(20, interval as u32);
-                                app.set_next_alarm::<A::Frequency>(self.alarm.now());
-                                self.reset_active_alarm();
-                                ReturnCode::SUCCESS
+            0 => {
+                if self.radio_shared_busy() {
+                    return ReturnCode::EBUSY;
+                }
+                let result = self
+                    .app
+                    .enter(appid, |app, _| {
+                        if let Some(BLEState::Initialized) = app.process_status {
+                            let pdu_type = data as AdvPduType;
+                            match pdu_type {
+                                ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
+                                    app.pdu_type = pdu_type;
+                                    app.process_status = Some(BLEState::AdvertisingIdle);
+                                    app.advertisement_interval_ms = cmp::max(20, interval as u32);
+                                    let interval_ms = app.advertisement_interval_ms;
+                                    self.scheduler.start(&mut app.schedule, interval_ms);
+                                    ReturnCode::SUCCESS
+                                }
+                                _ => ReturnCode::EINVAL,
                             }
-                            _ => ReturnCode::EINVAL,
+                        } else {
+                            ReturnCode::EBUSY
                         }
-                    } else {
-                        ReturnCode::EBUSY
-                    }
-                })
-                .unwrap_or_else(|err| err.into()),
+                    })
+                    .unwrap_or_else(|err| err.into());
+                if result == ReturnCode::SUCCESS {
+                    self.run_next();
+                }
+                result
+            }
 
-            // Stop periodic advertisements or passive scanning
-            1 => self
-                .app
-                .enter(appid, |app, _| match app.process_status {
-                    Some(BLEState::AdvertisingIdle) | Some(BLEState::ScanningIdle) => {
-                        app.process_status = Some(BLEState::Initialized);
-                        ReturnCode::SUCCESS
-                    }
-                    _ => ReturnCode::EBUSY,
-                })
-                .unwrap_or_else(|err| err.into()),
+            // Stop periodic advertisements or scanning
+            1 => {
+                let result = self
+                    .app
+                    .enter(appid, |app, _| match app.process_status {
+                        Some(BLEState::AdvertisingIdle) | Some(BLEState::ScanningIdle) => {
+                            app.process_status = Some(BLEState::Initialized);
+                            self.scheduler.stop(&mut app.schedule);
+                            ReturnCode::SUCCESS
+                        }
+                        _ => ReturnCode::EBUSY,
+                    })
+                    .unwrap_or_else(|err| err.into());
+                if result == ReturnCode::SUCCESS {
+                    self.run_next();
+                }
+                result
+            }
 
             // Configure transmitted power
             // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part A], section 3
@@ -623,20 +826,48 @@ where
                     .unwrap_or_else(|err| err.into())
             }
 
-            // Passive scanning mode
-            5 => self
-                .app
-                .enter(appid, |app, _| {
-                    if let Some(BLEState::Initialized) = app.process_status {
-                        app.process_status = Some(BLEState::ScanningIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now());
-                        self.reset_active_alarm();
+            // Passive and active scanning mode
+            5 | 6 => {
+                if self.radio_shared_busy() {
+                    return ReturnCode::EBUSY;
+                }
+                let result = self
+                    .app
+                    .enter(appid, |app, _| {
+                        if let Some(BLEState::Initialized) = app.process_status {
+                            app.active_scan = command_num == 6;
+                            app.process_status = Some(BLEState::ScanningIdle);
+                            let interval_ms = app.advertisement_interval_ms;
+                            self.scheduler.start(&mut app.schedule, interval_ms);
+                            ReturnCode::SUCCESS
+                        } else {
+                            ReturnCode::EBUSY
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into());
+                if result == ReturnCode::SUCCESS {
+                    self.run_next();
+                }
+                result
+            }
+
+            // Scan filter
+            7 => {
+                let filter = match data {
+                    0 => AdFilter::None,
+                    1 => AdFilter::ServiceUuid16(interval as u16),
+                    2 => AdFilter::CompanyId(interval as u16),
+                    3 => AdFilter::Flags(interval as u8),
+                    4 => AdFilter::HasName,
+                    _ => return ReturnCode::EINVAL,
+                };
+                self.app
+                    .enter(appid, |app, _| {
+                        app.scan_filter = filter;
                         ReturnCode::SUCCESS
-                    } else {
-                        ReturnCode::EBUSY
-                    }
-                })
-                .unwrap_or_else(|err| err.into()),
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
 
             _ => ReturnCode::ENOSUPPORT,
         }
@@ -663,7 +894,7 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
-            // Passive scanning buffer
+            // Scanning buffer
             1 => self
                 .app
                 .enter(appid, |app, _| match app.process_status {
@@ -676,6 +907,19 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Scan response buffer
+            2 => self
+                .app
+                .enter(appid, |app, _| match app.process_status {
+                    // The radio may be listening for scan requests
+                    Some(BLEState::Advertising(_)) => ReturnCode::EBUSY,
+                    _ => {
+                        app.scan_rsp_data = slice;
+                        ReturnCode::SUCCESS
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
             // Operation not supported
             _ => ReturnCode::ENOSUPPORT,
         }
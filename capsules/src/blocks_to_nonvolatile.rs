@@ -0,0 +1,264 @@
+//! Provide block storage on top of nonvolatile storage.
+//!
+//! This lets users of `hil::block_storage`, such as the FAT filesystem, use
+//! any `NonvolatileStorage`, for example FRAM, or flash through
+//! `nonvolatile_to_pages` or `nonvolatile_to_blocks`. The storage is read and
+//! written in blocks of a size chosen by the board, and is never erased.
+//! While it is handling a read or write it returns `EBUSY` to all additional
+//! requests.
+//!
+//! `NonvolatileStorage` does not return the buffer of an operation that fails
+//! to start, so neither does this module, except when it is busy or the
+//! operation is invalid.
+//!
+//! ```plain
+//!       hil::block_storage::BlockStorage
+//!                ┌─────────────┐
+//!                │             │
+//!                │ This module │
+//!                │             │
+//!                └─────────────┘
+//! hil::nonvolatile_storage::NonvolatileStorage
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let blocks = static_init!(
+//!     capsules::blocks_to_nonvolatile::BlocksToNonvolatile<'static, NonvolatileToPages<'static, F>>,
+//!     capsules::blocks_to_nonvolatile::BlocksToNonvolatile::new(nv_to_page, 512, 0x10000)
+//! );
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, blocks);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::hil;
+use kernel::hil::nonvolatile_storage::NonvolatileStorage;
+use kernel::ReturnCode;
+
+pub struct BlocksToNonvolatile<'a, N: NonvolatileStorage> {
+    storage: &'a N,
+    client: OptionalCell<&'static hil::block_storage::BlockStorageClient>,
+    block_size: usize,
+    capacity: usize,
+    busy: Cell<bool>,
+    /// Length of the current operation, to check that all of it completed.
+    length: Cell<usize>,
+}
+
+impl<N: NonvolatileStorage> BlocksToNonvolatile<'a, N> {
+    /// Provide the first `capacity` bytes of `storage` in blocks of
+    /// `block_size` bytes.
+    pub fn new(storage: &'a N, block_size: usize, capacity: usize) -> BlocksToNonvolatile<'a, N> {
+        BlocksToNonvolatile {
+            storage: storage,
+            client: OptionalCell::empty(),
+            block_size: block_size,
+            capacity: capacity - capacity % block_size,
+            busy: Cell::new(false),
+            length: Cell::new(0),
+        }
+    }
+
+    fn check(&self, buffer: &[u8], address: u64, length: usize) -> ReturnCode {
+        if self.busy.get() {
+            return ReturnCode::EBUSY;
+        }
+        if address % self.block_size as u64 != 0
+            || length % self.block_size != 0
+            || length > buffer.len()
+            || address + length as u64 > self.capacity as u64
+        {
+            return ReturnCode::EINVAL;
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn started(
+        &self,
+        result: ReturnCode,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if result == ReturnCode::SUCCESS {
+            self.busy.set(true);
+            self.length.set(length);
+        }
+        (result, None)
+    }
+
+    fn result(&self, length: usize) -> ReturnCode {
+        self.busy.set(false);
+        if length == self.length.get() {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::FAIL
+        }
+    }
+}
+
+impl<N: NonvolatileStorage> hil::block_storage::BlockStorage for BlocksToNonvolatile<'a, N> {
+    fn set_client(&self, client: &'static hil::block_storage::BlockStorageClient) {
+        self.client.set(client);
+    }
+
+    fn geometry(&self) -> hil::block_storage::Geometry {
+        hil::block_storage::Geometry {
+            read_size: self.block_size,
+            write_size: self.block_size,
+            erase_size: 0,
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity as u64
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        match self.check(buffer, address, length) {
+            ReturnCode::SUCCESS => {
+                let result = self.storage.read(buffer, address as usize, length);
+                self.started(result, length)
+            }
+            error => (error, Some(buffer)),
+        }
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        match self.check(buffer, address, length) {
+            ReturnCode::SUCCESS => {
+                let result = self.storage.write(buffer, address as usize, length);
+                self.started(result, length)
+            }
+            error => (error, Some(buffer)),
+        }
+    }
+
+    fn erase(&self, _address: u64, _length: usize) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+}
+
+impl<N: NonvolatileStorage> hil::nonvolatile_storage::NonvolatileStorageClient
+    for BlocksToNonvolatile<'a, N>
+{
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        let result = self.result(length);
+        self.client
+            .map(move |client| client.read_done(buffer, result));
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        let result = self.result(length);
+        self.client
+            .map(move |client| client.write_done(buffer, result));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::storage_sim::{leak, pump, zeroed, Sim, SimNonvolatileStorage};
+    use kernel::common::cells::TakeCell;
+    use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
+    use kernel::hil::block_storage::{BlockStorage, BlockStorageClient};
+    use std::cell::RefCell;
+    use std::vec;
+    use std::vec::Vec;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Read(Vec<u8>, ReturnCode),
+        Written(ReturnCode),
+    }
+
+    struct TestClient {
+        events: RefCell<Vec<Event>>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl BlockStorageClient for TestClient {
+        fn read_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+            self.events
+                .borrow_mut()
+                .push(Event::Read(buffer.to_vec(), result));
+            self.buffer.replace(buffer);
+        }
+
+        fn write_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+            self.events.borrow_mut().push(Event::Written(result));
+            self.buffer.replace(buffer);
+        }
+
+        fn erase_done(&self, _result: ReturnCode) {}
+    }
+
+    fn setup() -> (
+        &'static BlocksToNonvolatile<'static, SimNonvolatileStorage<'static>>,
+        &'static DynamicDeferredCall,
+        &'static TestClient,
+    ) {
+        let sim = Sim::nonvolatile(vec![0; 2048]);
+        let blocks = leak(BlocksToNonvolatile::new(sim.storage, 256, 2000));
+        sim.storage.set_client(blocks);
+        let client = leak(TestClient {
+            events: RefCell::new(Vec::new()),
+            buffer: TakeCell::new(zeroed(512)),
+        });
+        blocks.set_client(client);
+        (blocks, sim.deferred_caller, client)
+    }
+
+    #[test]
+    fn reads_and_writes_blocks() {
+        let (blocks, deferred_caller, client) = setup();
+        let buffer = client.buffer.take().unwrap();
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        assert_eq!(blocks.write(buffer, 256, 512), (ReturnCode::SUCCESS, None));
+        assert_eq!(blocks.read(zeroed(16), 0, 256).0, ReturnCode::EBUSY);
+        pump(deferred_caller);
+        assert_eq!(
+            client.events.borrow_mut().pop(),
+            Some(Event::Written(ReturnCode::SUCCESS))
+        );
+
+        let buffer = client.buffer.take().unwrap();
+        assert_eq!(blocks.read(buffer, 512, 256), (ReturnCode::SUCCESS, None));
+        pump(deferred_caller);
+        let expected: Vec<u8> = (0..512)
+            .map(|i| if i < 256 { i + 256 } else { i } as u8)
+            .collect();
+        assert_eq!(
+            client.events.borrow_mut().pop(),
+            Some(Event::Read(expected, ReturnCode::SUCCESS))
+        );
+    }
+
+    #[test]
+    fn rejects_unaligned_blocks_and_returns_the_buffer() {
+        let (blocks, _, client) = setup();
+        assert_eq!(blocks.capacity(), 1792);
+        assert_eq!(blocks.erase(0, 256), ReturnCode::ENOSUPPORT);
+        for &(address, length) in [(100, 256), (0, 100), (1536, 512)].iter() {
+            let buffer = client.buffer.take().unwrap();
+            let (result, buffer) = blocks.read(buffer, address, length);
+            assert_eq!(result, ReturnCode::EINVAL);
+            client.buffer.replace(buffer.unwrap());
+        }
+    }
+}
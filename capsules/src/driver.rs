@@ -10,17 +10,23 @@ pub enum NUM {
     AmbientLight = 0x60002,
     AnalogComparator = 0x00007,
     AppFlash =  0x50000,
+    AppUpdate = 0x50006,
     BleAdvertising = 0x030000,
+    BleConnection = 0x030003,
     Button = 0x00000003,
     Console = 0x00000001,
     Crc = 0x40002,
     Dac = 0x00000006,
+    Fat = 0x50005,
     Gpio = 0x00000004,
     GpioAsync = 0x80003,
     Humidity= 0x60001,
     I2cMaster = 0x40006,
     I2cMasterSlave = 0x20006,
+    KernelUpdate = 0x50007,
+    KVStore = 0x50004,
     Led = 0x2,
+    LogStorage = 0x50003,
     Lps25hb = 0x70004,
     Ltc294x = 0x80000,
     Max17205 = 0x80001,
@@ -0,0 +1,2621 @@
+//! FAT filesystem, for kernel capsules and a userspace Driver.
+//!
+//! `FatFs` reads and writes files on a FAT12, FAT16 or FAT32 volume in
+//! `hil::block_storage` storage, such as an SD card, so that data recorded by
+//! a board can be read on a PC and the other way around. The storage must be
+//! readable and writable in 512 byte sectors without erasing; flash can be
+//! used through `nonvolatile_to_blocks` and `blocks_to_nonvolatile`. The volume is either the whole
+//! device or the first FAT partition in its master boot record, and is
+//! mounted on the first operation. `FatDriver` exposes the filesystem to
+//! processes, each of which has its own table of open files.
+//!
+//! Files are opened by path, for example `/LOGS/DATA.CSV`. Only short (8.3)
+//! names are supported: long names are ignored when listing and searching
+//! directories, and new files get short names only. Names are matched in
+//! upper case. Files can be created, but not deleted or renamed, and
+//! directories cannot be created.
+//!
+//! Operations
+//! ----------
+//!
+//! One operation runs at a time. Every operation is a sequence of steps
+//! which each need at most two sectors, held in a cache of two sectors.
+//! When a step needs a sector that is not cached, the least recently used
+//! sector is written back if it was modified, the new one is read, and the
+//! step is run again from its start. Modified sectors stay in the cache until
+//! they are evicted or the file is closed, so files must be closed for their
+//! data and size to reach the device. Sectors of the first FAT are written to
+//! every copy of the FAT.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let fat = static_init!(
+//!     capsules::fat::FatFs<'static, SDCardBlockStorage<'static, VirtualMuxAlarm<'static, Ast>>>,
+//!     capsules::fat::FatFs::new(
+//!         sdcard_block_storage,
+//!         &mut capsules::fat::CACHE,
+//!         dynamic_deferred_call
+//!     )
+//! );
+//! sdcard_block_storage.set_client(fat);
+//! fat.set_deferred_call_handle(
+//!     dynamic_deferred_call
+//!         .register(fat)
+//!         .expect("no deferred call slot available for the filesystem"),
+//! );
+//!
+//! let fat_driver = static_init!(
+//!     capsules::fat::FatDriver<'static, SDCardBlockStorage<...>>,
+//!     capsules::fat::FatDriver::new(
+//!         fat,
+//!         &mut capsules::fat::BUFFER,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! fat.set_client(fat_driver);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::block_storage::{BlockStorage, BlockStorageClient};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Fat as usize;
+
+pub const SECTOR_SIZE: usize = 512;
+const DIR_ENTRY_SIZE: usize = 32;
+
+/// Longest path that can be opened.
+pub const MAX_PATH_LENGTH: usize = 64;
+/// Number of files a process can have open at once.
+pub const MAX_FILES: usize = 4;
+
+/// Sector cache of the filesystem.
+pub static mut CACHE: [u8; 2 * SECTOR_SIZE] = [0; 2 * SECTOR_SIZE];
+/// Buffer for reads and writes of the userspace driver.
+pub static mut BUFFER: [u8; SECTOR_SIZE] = [0; SECTOR_SIZE];
+
+// Directory entry attributes.
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+// First name byte of free directory entries.
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FREE: u8 = 0xE5;
+
+/// Callbacks of `FatFs`. Not finding a file is reported as ENOSUPPORT.
+pub trait FatClient {
+    fn open_done(&self, file: File, result: ReturnCode);
+    fn read_done(&self, file: File, buffer: &'static mut [u8], length: usize, result: ReturnCode);
+    fn write_done(&self, file: File, buffer: &'static mut [u8], length: usize, result: ReturnCode);
+    /// The next entry of a directory, or `None` at its end.
+    fn read_dir_done(&self, dir: File, entry: Option<DirEntry>, result: ReturnCode);
+    fn close_done(&self, result: ReturnCode);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// An open file or directory, and the position in it. Operations return the
+/// updated file, which must be passed to the next operation on it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct File {
+    first_cluster: u32,
+    size: u32,
+    position: u32,
+    // Cluster holding the position, and its index in the cluster chain.
+    cluster: u32,
+    cluster_index: u32,
+    // Location of the directory entry.
+    entry_sector: u32,
+    entry_offset: usize,
+    is_dir: bool,
+    modified: bool,
+}
+
+impl File {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Move to `position`, which cannot be past the end of a file.
+    pub fn seek(&mut self, position: u32) -> ReturnCode {
+        if !self.is_dir && position > self.size {
+            return ReturnCode::EINVAL;
+        }
+        self.position = position;
+        ReturnCode::SUCCESS
+    }
+}
+
+/// An entry of a directory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DirEntry {
+    name: [u8; 12],
+    name_length: usize,
+    size: u32,
+    is_dir: bool,
+}
+
+impl DirEntry {
+    /// The name, for example `DATA.CSV`.
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_length]
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+}
+
+/// Layout of a mounted volume. Sectors are absolute.
+#[derive(Clone, Copy, Debug)]
+struct Volume {
+    fat_type: FatType,
+    sectors_per_cluster: u32,
+    fat_start: u32,
+    fat_size: u32,
+    num_fats: u32,
+    root_start: u32,
+    root_sectors: u32,
+    data_start: u32,
+    cluster_count: u32,
+    root_cluster: u32,
+}
+
+impl Volume {
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn cluster_size(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFFFFFF,
+        }
+    }
+
+    fn is_end_of_chain(&self, value: u32) -> bool {
+        value >= self.end_of_chain() - 7
+    }
+
+    fn in_fat(&self, sector: u32) -> bool {
+        sector >= self.fat_start && sector < self.fat_start + self.fat_size
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u32 {
+    data[offset] as u32 | (data[offset + 1] as u32) << 8
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    read_u16(data, offset) | read_u16(data, offset + 2) << 16
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u32) {
+    data[offset] = value as u8;
+    data[offset + 1] = (value >> 8) as u8;
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    write_u16(data, offset, value);
+    write_u16(data, offset + 2, value >> 16);
+}
+
+fn has_signature(sector: &[u8]) -> bool {
+    sector[510] == 0x55 && sector[511] == 0xAA
+}
+
+/// Parse the boot sector of a volume starting at sector `start`.
+fn parse_boot_sector(sector: &[u8], start: u32) -> Result<Volume, ReturnCode> {
+    let bytes_per_sector = read_u16(sector, 11);
+    let sectors_per_cluster = sector[13] as u32;
+    let reserved_sectors = read_u16(sector, 14);
+    let num_fats = sector[16] as u32;
+    let root_entries = read_u16(sector, 17);
+    let fat_size = match read_u16(sector, 22) {
+        0 => read_u32(sector, 36),
+        size => size,
+    };
+    let total_sectors = match read_u16(sector, 19) {
+        0 => read_u32(sector, 32),
+        total => total,
+    };
+    if !has_signature(sector)
+        || (sector[0] != 0xEB && sector[0] != 0xE9)
+        || bytes_per_sector != SECTOR_SIZE as u32
+        || !sectors_per_cluster.is_power_of_two()
+        || reserved_sectors == 0
+        || num_fats == 0
+        || fat_size == 0
+    {
+        return Err(ReturnCode::FAIL);
+    }
+
+    let root_sectors =
+        (root_entries * DIR_ENTRY_SIZE as u32 + SECTOR_SIZE as u32 - 1) / SECTOR_SIZE as u32;
+    let metadata_sectors = reserved_sectors + num_fats * fat_size + root_sectors;
+    if total_sectors <= metadata_sectors {
+        return Err(ReturnCode::FAIL);
+    }
+    let cluster_count = (total_sectors - metadata_sectors) / sectors_per_cluster;
+    let (fat_type, fat_bits) = if cluster_count < 4085 {
+        (FatType::Fat12, 12)
+    } else if cluster_count < 65525 {
+        (FatType::Fat16, 16)
+    } else {
+        (FatType::Fat32, 32)
+    };
+    let root_cluster = if fat_type == FatType::Fat32 {
+        read_u32(sector, 44)
+    } else {
+        0
+    };
+    if (cluster_count as u64 + 2) * fat_bits > fat_size as u64 * SECTOR_SIZE as u64 * 8
+        || (fat_type == FatType::Fat32 && (root_entries != 0 || root_cluster < 2))
+    {
+        return Err(ReturnCode::FAIL);
+    }
+
+    Ok(Volume {
+        fat_type: fat_type,
+        sectors_per_cluster: sectors_per_cluster,
+        fat_start: start + reserved_sectors,
+        fat_size: fat_size,
+        num_fats: num_fats,
+        root_start: start + reserved_sectors + num_fats * fat_size,
+        root_sectors: root_sectors,
+        data_start: start + metadata_sectors,
+        cluster_count: cluster_count,
+        root_cluster: root_cluster,
+    })
+}
+
+/// Convert a path component to the padded 8.3 form used in directories.
+fn short_name(component: &[u8]) -> Result<[u8; 11], ReturnCode> {
+    let mut name = [b' '; 11];
+    let (base, extension) = match component.iter().position(|c| *c == b'.') {
+        Some(dot) => (&component[..dot], &component[dot + 1..]),
+        None => (component, &component[component.len()..]),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return Err(ReturnCode::EINVAL);
+    }
+    for (i, c) in base.iter().chain(extension.iter()).enumerate() {
+        let c = c.to_ascii_uppercase();
+        if c <= b' ' || c >= 0x7F || b"\"*+,./:;<=>?[\\]|".contains(&c) {
+            return Err(ReturnCode::EINVAL);
+        }
+        if i < base.len() {
+            name[i] = c;
+        } else {
+            name[8 + i - base.len()] = c;
+        }
+    }
+    Ok(name)
+}
+
+/// Components of a path, separated by slashes.
+fn components(path: &[u8]) -> impl Iterator<Item = &[u8]> {
+    path.split(|c| *c == b'/').filter(|c| !c.is_empty())
+}
+
+/// Whether a directory entry is a file or directory, rather than free space,
+/// a long name or the volume label.
+fn is_visible(entry: &[u8]) -> bool {
+    entry[0] != ENTRY_FREE
+        && entry[11] & ATTR_LONG_NAME != ATTR_LONG_NAME
+        && entry[11] & ATTR_VOLUME_ID == 0
+}
+
+fn entry_file(volume: &Volume, entry: &[u8], sector: u32, offset: usize) -> File {
+    let high = if volume.fat_type == FatType::Fat32 {
+        read_u16(entry, 20)
+    } else {
+        0
+    };
+    File {
+        first_cluster: high << 16 | read_u16(entry, 26),
+        size: read_u32(entry, 28),
+        position: 0,
+        cluster: 0,
+        cluster_index: 0,
+        entry_sector: sector,
+        entry_offset: offset,
+        is_dir: entry[11] & ATTR_DIRECTORY != 0,
+        modified: false,
+    }
+}
+
+fn dir_entry(entry: &[u8]) -> DirEntry {
+    let mut name = [0; 12];
+    let mut length = 0;
+    for (i, &c) in entry[..8].iter().enumerate() {
+        if c != b' ' {
+            // 0x05 stands for 0xE5, which marks free entries.
+            name[length] = if i == 0 && c == 0x05 { ENTRY_FREE } else { c };
+            length += 1;
+        }
+    }
+    if entry[8] != b' ' {
+        name[length] = b'.';
+        length += 1;
+        for &c in entry[8..11].iter().filter(|c| **c != b' ') {
+            name[length] = c;
+            length += 1;
+        }
+    }
+    DirEntry {
+        name: name,
+        name_length: length,
+        size: read_u32(entry, 28),
+        is_dir: entry[11] & ATTR_DIRECTORY != 0,
+    }
+}
+
+/// Result of an access that may have to wait for the block device: `None`
+/// while waiting, after which the step is run again.
+type Access<T> = Option<Result<T, ReturnCode>>;
+
+/// The value of an `Access`, returning from the enclosing step if it has to
+/// wait or failed.
+macro_rules! ready {
+    ($access:expr) => {
+        match $access {
+            Some(Ok(value)) => value,
+            Some(Err(error)) => return Some(Err(error)),
+            None => return None,
+        }
+    };
+}
+
+struct CacheSlot {
+    buffer: TakeCell<'static, [u8]>,
+    sector: Cell<Option<u32>>,
+    dirty: Cell<bool>,
+    // Copies of a FAT sector written back so far.
+    copies_written: Cell<u32>,
+}
+
+impl CacheSlot {
+    fn new(buffer: &'static mut [u8]) -> CacheSlot {
+        CacheSlot {
+            buffer: TakeCell::new(buffer),
+            sector: Cell::new(None),
+            dirty: Cell::new(false),
+            copies_written: Cell::new(0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Io {
+    Idle,
+    Reading { slot: usize, sector: u32 },
+    Writing { slot: usize },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    Running,
+    Completing,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    None,
+    Open { create: bool, step: OpenStep },
+    Read,
+    Write,
+    ReadDir,
+    Close,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OpenStep {
+    /// Starting at the root directory.
+    Root,
+    /// Searching the directory for the current path component.
+    Lookup,
+    /// Zeroing a cluster added to a full directory, from this sector on.
+    Extend(u32),
+    /// Writing the entry of a new file.
+    Create,
+}
+
+pub struct FatFs<'a, B: BlockStorage> {
+    device: &'a B,
+    client: OptionalCell<&'a FatClient>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+    volume: Cell<Option<Volume>>,
+
+    cache: [CacheSlot; 2],
+    recent_slot: Cell<usize>,
+    io: Cell<Io>,
+
+    // Cluster allocation. A newly allocated cluster is kept until it is
+    // linked, so that steps that are run again do not allocate another one.
+    allocated: Cell<Option<u32>>,
+    chain_end: Cell<Option<u32>>,
+    next_free: Cell<u32>,
+    clusters_scanned: Cell<u32>,
+
+    // The current operation.
+    state: Cell<State>,
+    operation: Cell<Operation>,
+    result: Cell<ReturnCode>,
+    file: Cell<File>,
+    dir: Cell<File>,
+    path: MapCell<[u8; MAX_PATH_LENGTH]>,
+    path_length: Cell<usize>,
+    component: Cell<usize>,
+    free_entry: Cell<Option<(u32, usize)>>,
+    buffer: TakeCell<'static, [u8]>,
+    length: Cell<usize>,
+    done: Cell<usize>,
+    entry: Cell<Option<DirEntry>>,
+}
+
+impl<B: BlockStorage> FatFs<'a, B> {
+    /// Create a filesystem on `device`, caching sectors in `cache`, which
+    /// must hold two sectors.
+    pub fn new(
+        device: &'a B,
+        cache: &'static mut [u8],
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> FatFs<'a, B> {
+        let (first, second) = cache.split_at_mut(SECTOR_SIZE);
+        FatFs {
+            device: device,
+            client: OptionalCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            volume: Cell::new(None),
+            cache: [
+                CacheSlot::new(first),
+                CacheSlot::new(&mut second[..SECTOR_SIZE]),
+            ],
+            recent_slot: Cell::new(0),
+            io: Cell::new(Io::Idle),
+            allocated: Cell::new(None),
+            chain_end: Cell::new(None),
+            next_free: Cell::new(2),
+            clusters_scanned: Cell::new(0),
+            state: Cell::new(State::Idle),
+            operation: Cell::new(Operation::None),
+            result: Cell::new(ReturnCode::SUCCESS),
+            file: Cell::new(File::default()),
+            dir: Cell::new(File::default()),
+            path: MapCell::new([0; MAX_PATH_LENGTH]),
+            path_length: Cell::new(0),
+            component: Cell::new(0),
+            free_entry: Cell::new(None),
+            buffer: TakeCell::empty(),
+            length: Cell::new(0),
+            done: Cell::new(0),
+            entry: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a FatClient) {
+        self.client.set(client);
+    }
+
+    pub fn set_deferred_call_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Type of the volume, once it is mounted.
+    pub fn fat_type(&self) -> Option<FatType> {
+        self.volume.get().map(|volume| volume.fat_type)
+    }
+
+    /// Open the file or directory at `path`, creating a file if it does not
+    /// exist and `create` is set. An empty path opens the root directory.
+    pub fn open(&self, path: &[u8], create: bool) -> ReturnCode {
+        if path.len() > MAX_PATH_LENGTH {
+            return ReturnCode::ESIZE;
+        }
+        for component in components(path) {
+            if let Err(error) = short_name(component) {
+                return error;
+            }
+        }
+        let result = self.start(Operation::Open {
+            create: create,
+            step: OpenStep::Root,
+        });
+        if result == ReturnCode::SUCCESS {
+            self.path
+                .map(|buffer| buffer[..path.len()].copy_from_slice(path));
+            self.path_length.set(path.len());
+            self.component.set(0);
+            self.free_entry.set(None);
+        }
+        result
+    }
+
+    /// Read up to `length` bytes from the position of `file`.
+    pub fn read(
+        &self,
+        file: File,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if file.is_dir || length > buffer.len() {
+            return (ReturnCode::EINVAL, Some(buffer));
+        }
+        self.start_transfer(Operation::Read, file, buffer, length)
+    }
+
+    /// Write `length` bytes at the position of `file`, extending it if
+    /// needed.
+    pub fn write(
+        &self,
+        file: File,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if file.is_dir || length > buffer.len() {
+            return (ReturnCode::EINVAL, Some(buffer));
+        }
+        self.start_transfer(Operation::Write, file, buffer, length)
+    }
+
+    /// Read the entry of directory `dir` at its position.
+    pub fn read_dir(&self, dir: File) -> ReturnCode {
+        if !dir.is_dir {
+            return ReturnCode::EINVAL;
+        }
+        let result = self.start(Operation::ReadDir);
+        if result == ReturnCode::SUCCESS {
+            self.file.set(dir);
+        }
+        result
+    }
+
+    /// Update the directory entry of `file` and write all cached sectors to
+    /// the device.
+    pub fn close(&self, file: File) -> ReturnCode {
+        let result = self.start(Operation::Close);
+        if result == ReturnCode::SUCCESS {
+            self.file.set(file);
+        }
+        result
+    }
+
+    fn start_transfer(
+        &self,
+        operation: Operation,
+        file: File,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let result = self.start(operation);
+        if result != ReturnCode::SUCCESS {
+            return (result, Some(buffer));
+        }
+        self.file.set(file);
+        self.buffer.replace(buffer);
+        self.length.set(length);
+        self.done.set(0);
+        (ReturnCode::SUCCESS, None)
+    }
+
+    // Start an operation, which runs from a deferred call.
+    fn start(&self, operation: Operation) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.handle.map_or(ReturnCode::FAIL, |handle| {
+            self.operation.set(operation);
+            self.chain_end.set(None);
+            self.state.set(State::Running);
+            self.deferred_caller.set(*handle);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    // Run the current operation until it has to wait for the block device.
+    fn run(&self) {
+        if self.state.get() != State::Running || self.io.get() != Io::Idle {
+            return;
+        }
+        let progress = match self.mount() {
+            Some(Ok(volume)) => self.step(&volume),
+            Some(Err(error)) => Some(Err(error)),
+            None => None,
+        };
+        match progress {
+            None => {}
+            Some(result) => {
+                self.result.set(result.err().unwrap_or(ReturnCode::SUCCESS));
+                self.state.set(State::Completing);
+                self.handle.map(|handle| self.deferred_caller.set(*handle));
+            }
+        }
+    }
+
+    fn step(&self, volume: &Volume) -> Access<()> {
+        match self.operation.get() {
+            Operation::None => Some(Ok(())),
+            Operation::Open { create, step } => self.open_step(volume, create, step),
+            Operation::Read => self.read_step(volume),
+            Operation::Write => self.write_step(volume),
+            Operation::ReadDir => self.read_dir_step(volume),
+            Operation::Close => self.close_step(volume),
+        }
+    }
+
+    fn mount(&self) -> Access<Volume> {
+        if let Some(volume) = self.volume.get() {
+            return Some(Ok(volume));
+        }
+        // Sectors are read and written whole, without erasing.
+        let geometry = self.device.geometry();
+        if SECTOR_SIZE % geometry.read_size != 0
+            || SECTOR_SIZE % geometry.write_size != 0
+            || geometry.erase_size != 0
+        {
+            return Some(Err(ReturnCode::ENOSUPPORT));
+        }
+        // Sector 0 is either the boot sector of the volume, or a master boot
+        // record with the volume in a partition.
+        let partition = ready!(self.read_sector(0, |sector| {
+            if parse_boot_sector(sector, 0).is_ok() {
+                return Ok(0);
+            }
+            if !has_signature(sector) {
+                return Err(ReturnCode::FAIL);
+            }
+            for entry in sector[446..510].chunks(16) {
+                match entry[4] {
+                    0x01 | 0x04 | 0x06 | 0x0B | 0x0C | 0x0E => return Ok(read_u32(entry, 8)),
+                    _ => {}
+                }
+            }
+            Err(ReturnCode::FAIL)
+        }));
+        let partition = match partition {
+            Ok(partition) => partition,
+            Err(error) => return Some(Err(error)),
+        };
+        let volume =
+            ready!(self.read_sector(partition, |sector| parse_boot_sector(sector, partition)));
+        if let Ok(volume) = volume {
+            self.volume.set(Some(volume));
+        }
+        Some(volume)
+    }
+
+    fn open_step(&self, volume: &Volume, create: bool, step: OpenStep) -> Access<()> {
+        let mut step = step;
+        loop {
+            match step {
+                OpenStep::Root => {
+                    self.dir.set(self.root(volume));
+                    step = OpenStep::Lookup;
+                }
+                OpenStep::Lookup => {
+                    let (start, end) = match self.next_component() {
+                        Some(component) => component,
+                        None => {
+                            // The whole path was found.
+                            self.file.set(self.dir.get());
+                            return Some(Ok(()));
+                        }
+                    };
+                    let name = self
+                        .path
+                        .map_or(Err(ReturnCode::FAIL), |path| short_name(&path[start..end]));
+                    let name = match name {
+                        Ok(name) => name,
+                        Err(error) => return Some(Err(error)),
+                    };
+                    match ready!(self.find_entry(volume, &name)) {
+                        Some(file) => {
+                            let last = self.is_last_component(end);
+                            if !last && !file.is_dir {
+                                return Some(Err(ReturnCode::ENOSUPPORT));
+                            }
+                            self.dir.set(file);
+                            self.component.set(end);
+                            self.free_entry.set(None);
+                        }
+                        None => {
+                            if !create || !self.is_last_component(end) {
+                                return Some(Err(ReturnCode::ENOSUPPORT));
+                            }
+                            step = if self.free_entry.get().is_some() {
+                                OpenStep::Create
+                            } else if self.is_fixed_root(&self.dir.get()) {
+                                return Some(Err(ReturnCode::ENOMEM));
+                            } else {
+                                OpenStep::Extend(0)
+                            };
+                        }
+                    }
+                }
+                OpenStep::Extend(first) => {
+                    // The directory is positioned at its end, so this adds a
+                    // cluster.
+                    let mut dir = self.dir.get();
+                    let sector = self.locate(volume, &mut dir, true);
+                    self.dir.set(dir);
+                    let sector = match ready!(sector) {
+                        Some(sector) => sector,
+                        None => return Some(Err(ReturnCode::FAIL)),
+                    };
+                    for i in first..volume.sectors_per_cluster {
+                        self.set_open_step(create, OpenStep::Extend(i));
+                        ready!(self.modify_sector(sector + i, false, |data| {
+                            for byte in data.iter_mut() {
+                                *byte = 0;
+                            }
+                        }));
+                    }
+                    self.free_entry.set(Some((sector, 0)));
+                    step = OpenStep::Create;
+                }
+                OpenStep::Create => {
+                    let (sector, offset) = match self.free_entry.get() {
+                        Some(entry) => entry,
+                        None => return Some(Err(ReturnCode::FAIL)),
+                    };
+                    let (start, end) = match self.next_component() {
+                        Some(component) => component,
+                        None => return Some(Err(ReturnCode::FAIL)),
+                    };
+                    let name = self
+                        .path
+                        .map_or(Err(ReturnCode::FAIL), |path| short_name(&path[start..end]));
+                    let name = match name {
+                        Ok(name) => name,
+                        Err(error) => return Some(Err(error)),
+                    };
+                    ready!(self.modify_sector(sector, true, |data| {
+                        let entry = &mut data[offset..offset + DIR_ENTRY_SIZE];
+                        for byte in entry.iter_mut() {
+                            *byte = 0;
+                        }
+                        entry[..11].copy_from_slice(&name);
+                        entry[11] = ATTR_ARCHIVE;
+                        // Created, accessed and modified on 1980-01-01.
+                        write_u16(entry, 16, 0x0021);
+                        write_u16(entry, 18, 0x0021);
+                        write_u16(entry, 24, 0x0021);
+                    }));
+                    self.file.set(File {
+                        entry_sector: sector,
+                        entry_offset: offset,
+                        ..File::default()
+                    });
+                    return Some(Ok(()));
+                }
+            }
+            self.set_open_step(create, step);
+        }
+    }
+
+    fn set_open_step(&self, create: bool, step: OpenStep) {
+        self.operation.set(Operation::Open {
+            create: create,
+            step: step,
+        });
+    }
+
+    // The next path component to look up, as a range of the path.
+    fn next_component(&self) -> Option<(usize, usize)> {
+        let length = self.path_length.get();
+        self.path.map_or(None, |path| {
+            let mut start = self.component.get();
+            while start < length && path[start] == b'/' {
+                start += 1;
+            }
+            if start == length {
+                return None;
+            }
+            let end = path[start..length]
+                .iter()
+                .position(|c| *c == b'/')
+                .map_or(length, |i| start + i);
+            Some((start, end))
+        })
+    }
+
+    fn is_last_component(&self, end: usize) -> bool {
+        let length = self.path_length.get();
+        self.path
+            .map_or(true, |path| path[end..length].iter().all(|c| *c == b'/'))
+    }
+
+    fn root(&self, volume: &Volume) -> File {
+        File {
+            first_cluster: volume.root_cluster,
+            is_dir: true,
+            ..File::default()
+        }
+    }
+
+    fn is_fixed_root(&self, dir: &File) -> bool {
+        dir.is_dir && dir.first_cluster == 0
+    }
+
+    // Search the directory being opened for `name`, from its position.
+    // Remembers the first free entry on the way.
+    fn find_entry(&self, volume: &Volume, name: &[u8; 11]) -> Access<Option<File>> {
+        loop {
+            let mut dir = self.dir.get();
+            let sector = self.locate(volume, &mut dir, false);
+            self.dir.set(dir);
+            let sector = match ready!(sector) {
+                Some(sector) => sector,
+                None => return Some(Ok(None)),
+            };
+            let start = dir.position as usize % SECTOR_SIZE;
+            let found = ready!(self.read_sector(sector, |data| {
+                for offset in (start..SECTOR_SIZE).step_by(DIR_ENTRY_SIZE) {
+                    let entry = &data[offset..offset + DIR_ENTRY_SIZE];
+                    if entry[0] == ENTRY_END || entry[0] == ENTRY_FREE {
+                        if self.free_entry.get().is_none() {
+                            self.free_entry.set(Some((sector, offset)));
+                        }
+                        if entry[0] == ENTRY_END {
+                            return Err(());
+                        }
+                    } else if is_visible(entry) && entry[..11] == name[..] {
+                        return Ok(Some(entry_file(volume, entry, sector, offset)));
+                    }
+                }
+                Ok(None)
+            }));
+            match found {
+                Ok(Some(file)) => return Some(Ok(Some(file))),
+                Ok(None) => {
+                    dir.position += (SECTOR_SIZE - start) as u32;
+                    self.dir.set(dir);
+                }
+                Err(()) => return Some(Ok(None)),
+            }
+        }
+    }
+
+    fn read_step(&self, volume: &Volume) -> Access<()> {
+        loop {
+            let mut file = self.file.get();
+            let remaining = cmp::min(
+                self.length.get() - self.done.get(),
+                file.size.saturating_sub(file.position) as usize,
+            );
+            if remaining == 0 {
+                return Some(Ok(()));
+            }
+            let sector = self.locate(volume, &mut file, false);
+            self.file.set(file);
+            let sector = match ready!(sector) {
+                Some(sector) => sector,
+                None => return Some(Err(ReturnCode::FAIL)),
+            };
+            let offset = file.position as usize % SECTOR_SIZE;
+            let length = cmp::min(SECTOR_SIZE - offset, remaining);
+            let done = self.done.get();
+            ready!(self.read_sector(sector, |data| {
+                self.buffer.map(|buffer| {
+                    buffer[done..done + length].copy_from_slice(&data[offset..offset + length])
+                });
+            }));
+            file.position += length as u32;
+            self.file.set(file);
+            self.done.set(done + length);
+        }
+    }
+
+    fn write_step(&self, volume: &Volume) -> Access<()> {
+        loop {
+            let mut file = self.file.get();
+            let done = self.done.get();
+            let remaining = self.length.get() - done;
+            if remaining == 0 {
+                return Some(Ok(()));
+            }
+            let sector = self.locate(volume, &mut file, true);
+            self.file.set(file);
+            let sector = match ready!(sector) {
+                Some(sector) => sector,
+                None => return Some(Err(ReturnCode::FAIL)),
+            };
+            let offset = file.position as usize % SECTOR_SIZE;
+            let length = cmp::min(SECTOR_SIZE - offset, remaining);
+            // Whole sectors are not read first.
+            let partial = length < SECTOR_SIZE;
+            ready!(self.modify_sector(sector, partial, |data| {
+                self.buffer.map(|buffer| {
+                    data[offset..offset + length].copy_from_slice(&buffer[done..done + length])
+                });
+            }));
+            file.position += length as u32;
+            file.size = cmp::max(file.size, file.position);
+            file.modified = true;
+            self.file.set(file);
+            self.done.set(done + length);
+        }
+    }
+
+    fn read_dir_step(&self, volume: &Volume) -> Access<()> {
+        loop {
+            let mut dir = self.file.get();
+            let sector = self.locate(volume, &mut dir, false);
+            self.file.set(dir);
+            let sector = match ready!(sector) {
+                Some(sector) => sector,
+                None => {
+                    self.entry.set(None);
+                    return Some(Ok(()));
+                }
+            };
+            let start = dir.position as usize % SECTOR_SIZE;
+            let found = ready!(self.read_sector(sector, |data| {
+                for offset in (start..SECTOR_SIZE).step_by(DIR_ENTRY_SIZE) {
+                    let entry = &data[offset..offset + DIR_ENTRY_SIZE];
+                    if entry[0] == ENTRY_END {
+                        return Err(offset);
+                    }
+                    if is_visible(entry) && entry[0] != b'.' {
+                        return Ok(Some((offset, dir_entry(entry))));
+                    }
+                }
+                Ok(None)
+            }));
+            match found {
+                Ok(Some((offset, entry))) => {
+                    dir.position += (offset + DIR_ENTRY_SIZE - start) as u32;
+                    self.file.set(dir);
+                    self.entry.set(Some(entry));
+                    return Some(Ok(()));
+                }
+                Ok(None) => {
+                    dir.position += (SECTOR_SIZE - start) as u32;
+                    self.file.set(dir);
+                }
+                Err(offset) => {
+                    // Stay at the end, where new entries are added.
+                    dir.position += (offset - start) as u32;
+                    self.file.set(dir);
+                    self.entry.set(None);
+                    return Some(Ok(()));
+                }
+            }
+        }
+    }
+
+    fn close_step(&self, volume: &Volume) -> Access<()> {
+        let mut file = self.file.get();
+        if file.modified {
+            let offset = file.entry_offset;
+            ready!(self.modify_sector(file.entry_sector, true, |data| {
+                let entry = &mut data[offset..offset + DIR_ENTRY_SIZE];
+                if volume.fat_type == FatType::Fat32 {
+                    write_u16(entry, 20, file.first_cluster >> 16);
+                }
+                write_u16(entry, 26, file.first_cluster);
+                write_u32(entry, 28, file.size);
+                entry[11] |= ATTR_ARCHIVE;
+            }));
+            file.modified = false;
+            self.file.set(file);
+        }
+        self.flush()
+    }
+
+    // The sector holding the position of `file`, or `None` past the end of
+    // its clusters. With `allocate`, clusters are added instead.
+    fn locate(&self, volume: &Volume, file: &mut File, allocate: bool) -> Access<Option<u32>> {
+        if self.is_fixed_root(file) {
+            let sector = file.position / SECTOR_SIZE as u32;
+            return Some(Ok(if sector < volume.root_sectors {
+                Some(volume.root_start + sector)
+            } else {
+                None
+            }));
+        }
+
+        let target = file.position / volume.cluster_size();
+        if file.first_cluster == 0 {
+            if !allocate {
+                return Some(Ok(None));
+            }
+            let cluster = ready!(self.allocate_cluster(volume));
+            self.allocated.set(None);
+            file.first_cluster = cluster;
+            file.cluster = 0;
+            file.modified = true;
+        }
+        if file.cluster == 0 || file.cluster_index > target {
+            file.cluster = file.first_cluster;
+            file.cluster_index = 0;
+        }
+        while file.cluster_index < target {
+            // Once the end of the chain has been reached, it is not read
+            // again while a cluster is allocated.
+            let next = if self.chain_end.get() == Some(file.cluster) {
+                volume.end_of_chain()
+            } else {
+                ready!(self.fat_entry(volume, file.cluster))
+            };
+            let next = if !volume.is_end_of_chain(next) {
+                if !volume.is_valid_cluster(next) {
+                    return Some(Err(ReturnCode::FAIL));
+                }
+                next
+            } else if allocate {
+                self.chain_end.set(Some(file.cluster));
+                let cluster = ready!(self.allocate_cluster(volume));
+                ready!(self.set_fat_entry(volume, file.cluster, cluster));
+                self.allocated.set(None);
+                self.chain_end.set(None);
+                cluster
+            } else {
+                return Some(Ok(None));
+            };
+            file.cluster = next;
+            file.cluster_index += 1;
+        }
+        let sector_in_cluster = file.position % volume.cluster_size() / SECTOR_SIZE as u32;
+        Some(Ok(Some(
+            volume.cluster_sector(file.cluster) + sector_in_cluster,
+        )))
+    }
+
+    // Find a free cluster and mark it as the end of a chain.
+    fn allocate_cluster(&self, volume: &Volume) -> Access<u32> {
+        if let Some(cluster) = self.allocated.get() {
+            return Some(Ok(cluster));
+        }
+        while self.clusters_scanned.get() < volume.cluster_count {
+            let mut cluster = self.next_free.get();
+            if !volume.is_valid_cluster(cluster) {
+                cluster = 2;
+                self.next_free.set(cluster);
+            }
+            if ready!(self.fat_entry(volume, cluster)) == 0 {
+                ready!(self.set_fat_entry(volume, cluster, volume.end_of_chain()));
+                self.allocated.set(Some(cluster));
+                self.next_free.set(cluster + 1);
+                self.clusters_scanned.set(0);
+                return Some(Ok(cluster));
+            }
+            self.next_free.set(cluster + 1);
+            self.clusters_scanned.set(self.clusters_scanned.get() + 1);
+        }
+        self.clusters_scanned.set(0);
+        Some(Err(ReturnCode::ENOMEM))
+    }
+
+    fn fat_entry(&self, volume: &Volume, cluster: u32) -> Access<u32> {
+        match volume.fat_type {
+            FatType::Fat12 => {
+                let offset = cluster + cluster / 2;
+                let low = ready!(self.fat_byte(volume, offset, None));
+                let high = ready!(self.fat_byte(volume, offset + 1, None));
+                let value = low as u32 | (high as u32) << 8;
+                Some(Ok(if cluster & 1 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xFFF
+                }))
+            }
+            FatType::Fat16 => {
+                let offset = cluster * 2;
+                self.read_sector(volume.fat_start + offset / SECTOR_SIZE as u32, |data| {
+                    read_u16(data, offset as usize % SECTOR_SIZE)
+                })
+            }
+            FatType::Fat32 => {
+                let offset = cluster * 4;
+                self.read_sector(volume.fat_start + offset / SECTOR_SIZE as u32, |data| {
+                    read_u32(data, offset as usize % SECTOR_SIZE) & 0x0FFFFFFF
+                })
+            }
+        }
+    }
+
+    fn set_fat_entry(&self, volume: &Volume, cluster: u32, value: u32) -> Access<()> {
+        match volume.fat_type {
+            FatType::Fat12 => {
+                let offset = cluster + cluster / 2;
+                if cluster & 1 == 1 {
+                    ready!(self.fat_byte(
+                        volume,
+                        offset,
+                        Some(&|byte| byte & 0x0F | (value << 4) as u8)
+                    ));
+                    ready!(self.fat_byte(volume, offset + 1, Some(&|_| (value >> 4) as u8)));
+                } else {
+                    ready!(self.fat_byte(volume, offset, Some(&|_| value as u8)));
+                    ready!(self.fat_byte(
+                        volume,
+                        offset + 1,
+                        Some(&|byte| byte & 0xF0 | (value >> 8) as u8 & 0x0F)
+                    ));
+                }
+                Some(Ok(()))
+            }
+            FatType::Fat16 => {
+                let offset = cluster * 2;
+                self.modify_sector(
+                    volume.fat_start + offset / SECTOR_SIZE as u32,
+                    true,
+                    |data| write_u16(data, offset as usize % SECTOR_SIZE, value),
+                )
+            }
+            FatType::Fat32 => {
+                let offset = cluster * 4;
+                self.modify_sector(
+                    volume.fat_start + offset / SECTOR_SIZE as u32,
+                    true,
+                    |data| {
+                        // The top four bits are reserved.
+                        let offset = offset as usize % SECTOR_SIZE;
+                        let reserved = read_u32(data, offset) & 0xF0000000;
+                        write_u32(data, offset, reserved | value & 0x0FFFFFFF)
+                    },
+                )
+            }
+        }
+    }
+
+    // Read a byte of the FAT, or change it with `modify`. A FAT12 entry can
+    // span two sectors, so it is accessed a byte at a time.
+    fn fat_byte(&self, volume: &Volume, offset: u32, modify: Option<&Fn(u8) -> u8>) -> Access<u8> {
+        let sector = volume.fat_start + offset / SECTOR_SIZE as u32;
+        let offset = offset as usize % SECTOR_SIZE;
+        match modify {
+            None => self.read_sector(sector, |data| data[offset]),
+            Some(modify) => self.modify_sector(sector, true, |data| {
+                data[offset] = modify(data[offset]);
+                data[offset]
+            }),
+        }
+    }
+
+    fn read_sector<R, F: FnOnce(&[u8]) -> R>(&self, sector: u32, f: F) -> Access<R> {
+        self.access(sector, true, false, |data| f(data))
+    }
+
+    // Change a sector, which is only read from the device first if `read`
+    // is set, as it would otherwise be completely overwritten.
+    fn modify_sector<R, F: FnOnce(&mut [u8]) -> R>(
+        &self,
+        sector: u32,
+        read: bool,
+        f: F,
+    ) -> Access<R> {
+        self.access(sector, read, true, f)
+    }
+
+    fn access<R, F: FnOnce(&mut [u8]) -> R>(
+        &self,
+        sector: u32,
+        read: bool,
+        modify: bool,
+        f: F,
+    ) -> Access<R> {
+        let slot = match self
+            .cache
+            .iter()
+            .position(|slot| slot.sector.get() == Some(sector))
+        {
+            Some(slot) => slot,
+            None => {
+                // Replace the least recently used sector.
+                let slot = 1 - self.recent_slot.get();
+                if self.cache[slot].dirty.get() {
+                    return self.write_back(slot);
+                }
+                if read {
+                    return self.start_read(slot, sector);
+                }
+                self.cache[slot].sector.set(Some(sector));
+                slot
+            }
+        };
+        self.recent_slot.set(slot);
+        if modify {
+            self.cache[slot].dirty.set(true);
+        }
+        self.cache[slot]
+            .buffer
+            .map(|data| Ok(f(data)))
+            .or(Some(Err(ReturnCode::FAIL)))
+    }
+
+    fn start_read<R>(&self, slot: usize, sector: u32) -> Access<R> {
+        self.cache[slot].sector.set(None);
+        self.cache[slot]
+            .buffer
+            .take()
+            .map_or(Some(Err(ReturnCode::FAIL)), |buffer| {
+                let (result, buffer) =
+                    self.device
+                        .read(buffer, sector as u64 * SECTOR_SIZE as u64, SECTOR_SIZE);
+                match buffer {
+                    Some(buffer) => {
+                        self.cache[slot].buffer.replace(buffer);
+                        Some(Err(result))
+                    }
+                    None => {
+                        self.io.set(Io::Reading {
+                            slot: slot,
+                            sector: sector,
+                        });
+                        None
+                    }
+                }
+            })
+    }
+
+    // Write the next copy of a modified sector to the device.
+    fn write_back<R>(&self, slot: usize) -> Access<R> {
+        let volume = match self.volume.get() {
+            Some(volume) => volume,
+            None => return Some(Err(ReturnCode::FAIL)),
+        };
+        let sector = match self.cache[slot].sector.get() {
+            Some(sector) => sector,
+            None => return Some(Err(ReturnCode::FAIL)),
+        };
+        let copy = self.cache[slot].copies_written.get();
+        let target = sector + copy * volume.fat_size;
+        self.cache[slot]
+            .buffer
+            .take()
+            .map_or(Some(Err(ReturnCode::FAIL)), |buffer| {
+                let (result, buffer) =
+                    self.device
+                        .write(buffer, target as u64 * SECTOR_SIZE as u64, SECTOR_SIZE);
+                match buffer {
+                    Some(buffer) => {
+                        self.cache[slot].buffer.replace(buffer);
+                        Some(Err(result))
+                    }
+                    None => {
+                        self.io.set(Io::Writing { slot: slot });
+                        None
+                    }
+                }
+            })
+    }
+
+    // Write all modified sectors to the device.
+    fn flush(&self) -> Access<()> {
+        for slot in 0..self.cache.len() {
+            if self.cache[slot].dirty.get() {
+                return self.write_back(slot);
+            }
+        }
+        Some(Ok(()))
+    }
+
+    fn io_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        let io = self.io.replace(Io::Idle);
+        match io {
+            Io::Idle => return,
+            Io::Reading { slot, sector } => {
+                self.cache[slot].buffer.replace(buffer);
+                if result == ReturnCode::SUCCESS {
+                    self.cache[slot].sector.set(Some(sector));
+                    self.recent_slot.set(slot);
+                }
+            }
+            Io::Writing { slot } => {
+                self.cache[slot].buffer.replace(buffer);
+                if result == ReturnCode::SUCCESS {
+                    let copies = match (self.volume.get(), self.cache[slot].sector.get()) {
+                        (Some(volume), Some(sector)) if volume.in_fat(sector) => volume.num_fats,
+                        _ => 1,
+                    };
+                    let written = self.cache[slot].copies_written.get() + 1;
+                    if written >= copies {
+                        self.cache[slot].copies_written.set(0);
+                        self.cache[slot].dirty.set(false);
+                    } else {
+                        self.cache[slot].copies_written.set(written);
+                    }
+                }
+            }
+        }
+        if result != ReturnCode::SUCCESS {
+            self.result.set(ReturnCode::FAIL);
+            self.state.set(State::Completing);
+            self.handle.map(|handle| self.deferred_caller.set(*handle));
+        } else {
+            self.run();
+        }
+    }
+}
+
+impl<B: BlockStorage> BlockStorageClient for FatFs<'a, B> {
+    fn read_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.io_done(buffer, result);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.io_done(buffer, result);
+    }
+
+    fn erase_done(&self, _result: ReturnCode) {}
+}
+
+impl<B: BlockStorage> DynamicDeferredCallClient for FatFs<'a, B> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        match self.state.get() {
+            State::Running => self.run(),
+            State::Completing => {
+                self.state.set(State::Idle);
+                let operation = self.operation.replace(Operation::None);
+                let result = self.result.get();
+                let file = self.file.get();
+                let length = self.done.get();
+                match operation {
+                    Operation::None => {}
+                    Operation::Open { .. } => {
+                        self.client.map(|client| client.open_done(file, result));
+                    }
+                    Operation::Read => {
+                        self.buffer.take().map(|buffer| {
+                            self.client
+                                .map(move |client| client.read_done(file, buffer, length, result));
+                        });
+                    }
+                    Operation::Write => {
+                        self.buffer.take().map(|buffer| {
+                            self.client
+                                .map(move |client| client.write_done(file, buffer, length, result));
+                        });
+                    }
+                    Operation::ReadDir => {
+                        let entry = self.entry.take();
+                        self.client
+                            .map(|client| client.read_dir_done(file, entry, result));
+                    }
+                    Operation::Close => {
+                        self.client.map(|client| client.close_done(result));
+                    }
+                }
+            }
+            State::Idle => {}
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Open {
+        length: usize,
+        create: bool,
+    },
+    Read {
+        file: usize,
+        length: usize,
+    },
+    Write {
+        file: usize,
+        length: usize,
+        append: bool,
+    },
+    ReadDir {
+        file: usize,
+    },
+    Close {
+        file: usize,
+    },
+}
+
+pub struct App {
+    open_callback: Option<Callback>,
+    read_callback: Option<Callback>,
+    write_callback: Option<Callback>,
+    read_dir_callback: Option<Callback>,
+    close_callback: Option<Callback>,
+    path: Option<AppSlice<Shared, u8>>,
+    read_buffer: Option<AppSlice<Shared, u8>>,
+    write_buffer: Option<AppSlice<Shared, u8>>,
+    files: [Option<File>; MAX_FILES],
+    pending_command: Option<Command>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            open_callback: None,
+            read_callback: None,
+            write_callback: None,
+            read_dir_callback: None,
+            close_callback: None,
+            path: None,
+            read_buffer: None,
+            write_buffer: None,
+            files: [None; MAX_FILES],
+            pending_command: None,
+        }
+    }
+}
+
+/// Userspace interface to a filesystem. Every app refers to its open files
+/// by their index in its own table.
+pub struct FatDriver<'a, B: BlockStorage> {
+    fs: &'a FatFs<'a, B>,
+    buffer: TakeCell<'static, [u8]>,
+    apps: Grant<App>,
+    current_app: OptionalCell<AppId>,
+}
+
+impl<B: BlockStorage> FatDriver<'a, B> {
+    pub fn new(
+        fs: &'a FatFs<'a, B>,
+        buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> FatDriver<'a, B> {
+        FatDriver {
+            fs: fs,
+            buffer: TakeCell::new(buffer),
+            apps: grant,
+            current_app: OptionalCell::empty(),
+        }
+    }
+
+    // Queue a command for the app, and run it if the filesystem is not in
+    // use.
+    fn enqueue_command(&self, command: Command, appid: AppId) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                if app.pending_command.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                let valid = match command {
+                    Command::Open { length, .. } => {
+                        if !app.files.iter().any(|file| file.is_none()) {
+                            return ReturnCode::ENOMEM;
+                        }
+                        length <= app.path.as_ref().map_or(0, |path| path.len())
+                    }
+                    Command::Read { file, length } => {
+                        Self::file(app, file).is_some()
+                            && length <= app.read_buffer.as_ref().map_or(0, |slice| slice.len())
+                    }
+                    Command::Write { file, length, .. } => {
+                        Self::file(app, file).is_some()
+                            && length <= app.write_buffer.as_ref().map_or(0, |slice| slice.len())
+                    }
+                    Command::ReadDir { file } | Command::Close { file } => {
+                        Self::file(app, file).is_some()
+                    }
+                };
+                if !valid {
+                    return ReturnCode::EINVAL;
+                }
+                app.pending_command = Some(command);
+                if self.current_app.is_none() {
+                    let result = self.run_command(app, appid);
+                    if result != ReturnCode::SUCCESS {
+                        app.pending_command = None;
+                    }
+                    result
+                } else {
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn file(app: &App, file: usize) -> Option<File> {
+        app.files.get(file).and_then(|file| *file)
+    }
+
+    fn run_command(&self, app: &mut App, appid: AppId) -> ReturnCode {
+        let command = match app.pending_command {
+            Some(command) => command,
+            None => return ReturnCode::FAIL,
+        };
+        let result = match command {
+            Command::Open { length, create } => {
+                app.path.as_ref().map_or(ReturnCode::EINVAL, |path| {
+                    self.fs.open(&path.as_ref()[..length], create)
+                })
+            }
+            Command::Read { file, length } => {
+                let file = Self::file(app, file).unwrap_or_default();
+                self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+                    let length = cmp::min(length, buffer.len());
+                    let (result, buffer) = self.fs.read(file, buffer, length);
+                    buffer.map(|buffer| self.buffer.replace(buffer));
+                    result
+                })
+            }
+            Command::Write {
+                file,
+                length,
+                append,
+            } => {
+                let mut file = Self::file(app, file).unwrap_or_default();
+                if append {
+                    let size = file.size();
+                    file.seek(size);
+                }
+                self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+                    let length = cmp::min(length, buffer.len());
+                    app.write_buffer.as_ref().map(|slice| {
+                        buffer[..length].copy_from_slice(&slice.as_ref()[..length]);
+                    });
+                    let (result, buffer) = self.fs.write(file, buffer, length);
+                    buffer.map(|buffer| self.buffer.replace(buffer));
+                    result
+                })
+            }
+            Command::ReadDir { file } => {
+                self.fs.read_dir(Self::file(app, file).unwrap_or_default())
+            }
+            Command::Close { file } => self.fs.close(Self::file(app, file).unwrap_or_default()),
+        };
+        if result == ReturnCode::SUCCESS {
+            self.current_app.set(appid);
+        }
+        result
+    }
+
+    // Run the next queued command, reporting commands that cannot run.
+    fn check_queue(&self) {
+        for cntr in self.apps.iter() {
+            let started = cntr.enter(|app, _| {
+                let command = match app.pending_command {
+                    Some(command) => command,
+                    None => return false,
+                };
+                let appid = app.appid();
+                let result = self.run_command(app, appid);
+                if result == ReturnCode::SUCCESS {
+                    return true;
+                }
+                app.pending_command = None;
+                let callback = match command {
+                    Command::Open { .. } => &mut app.open_callback,
+                    Command::Read { .. } => &mut app.read_callback,
+                    Command::Write { .. } => &mut app.write_callback,
+                    Command::ReadDir { .. } => &mut app.read_dir_callback,
+                    Command::Close { .. } => &mut app.close_callback,
+                };
+                callback.map(|mut cb| cb.schedule(usize::from(result), 0, 0));
+                false
+            });
+            if started {
+                break;
+            }
+        }
+    }
+
+    // Notify the app whose command completed and run the next one.
+    fn done<C: FnOnce(&mut App, Command)>(&self, notify: C) {
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.pending_command
+                    .take()
+                    .map(|command| notify(app, command));
+            });
+        });
+        self.check_queue();
+    }
+}
+
+impl<B: BlockStorage> FatClient for FatDriver<'a, B> {
+    fn open_done(&self, file: File, result: ReturnCode) {
+        self.done(|app, _| {
+            let mut index = 0;
+            let mut result = result;
+            if result == ReturnCode::SUCCESS {
+                match app.files.iter().position(|file| file.is_none()) {
+                    Some(free) => {
+                        app.files[free] = Some(file);
+                        index = free;
+                    }
+                    None => result = ReturnCode::ENOMEM,
+                }
+            }
+            app.open_callback
+                .map(|mut cb| cb.schedule(usize::from(result), index, file.size() as usize));
+        });
+    }
+
+    fn read_done(&self, file: File, buffer: &'static mut [u8], length: usize, result: ReturnCode) {
+        self.buffer.replace(buffer);
+        self.done(|app, command| {
+            if let Command::Read { file: index, .. } = command {
+                app.files[index] = Some(file);
+            }
+            self.buffer.map(|buffer| {
+                app.read_buffer.as_mut().map(|slice| {
+                    let length = cmp::min(length, slice.len());
+                    slice.as_mut()[..length].copy_from_slice(&buffer[..length]);
+                });
+            });
+            app.read_callback
+                .map(|mut cb| cb.schedule(usize::from(result), length, 0));
+        });
+    }
+
+    fn write_done(&self, file: File, buffer: &'static mut [u8], length: usize, result: ReturnCode) {
+        self.buffer.replace(buffer);
+        self.done(|app, command| {
+            if let Command::Write { file: index, .. } = command {
+                app.files[index] = Some(file);
+            }
+            app.write_callback
+                .map(|mut cb| cb.schedule(usize::from(result), length, 0));
+        });
+    }
+
+    fn read_dir_done(&self, dir: File, entry: Option<DirEntry>, result: ReturnCode) {
+        self.done(|app, command| {
+            if let Command::ReadDir { file: index } = command {
+                app.files[index] = Some(dir);
+            }
+            // Directories are listed with a trailing slash.
+            let mut length = 0;
+            entry.map(|entry| {
+                app.read_buffer.as_mut().map(|slice| {
+                    let name = entry.name();
+                    length = cmp::min(name.len(), slice.len());
+                    slice.as_mut()[..length].copy_from_slice(&name[..length]);
+                    if entry.is_dir() && length < slice.len() {
+                        slice.as_mut()[length] = b'/';
+                        length += 1;
+                    }
+                });
+            });
+            let size = entry.map_or(0, |entry| entry.size() as usize);
+            app.read_dir_callback
+                .map(|mut cb| cb.schedule(usize::from(result), length, size));
+        });
+    }
+
+    fn close_done(&self, result: ReturnCode) {
+        self.done(|app, command| {
+            if let Command::Close { file: index } = command {
+                app.files[index] = None;
+            }
+            app.close_callback
+                .map(|mut cb| cb.schedule(usize::from(result), 0, 0));
+        });
+    }
+}
+
+impl<B: BlockStorage> Driver for FatDriver<'a, B> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Path of the file to open.
+    /// - `1`: Buffer to read data and directory entries into.
+    /// - `2`: Buffer holding data to write.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.path = slice,
+                    1 => app.read_buffer = slice,
+                    2 => app.write_buffer = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// The first argument of every callback is the result of the operation.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Open done. The second argument is the index of the file, the
+    ///   third its size. ENOSUPPORT means the file does not exist.
+    /// - `1`: Read done. The second argument is the number of bytes read,
+    ///   which is zero at the end of the file.
+    /// - `2`: Write or append done. The second argument is the number of
+    ///   bytes written.
+    /// - `3`: Directory entry read. The second argument is the length of the
+    ///   name in the read buffer, which ends with a slash for directories and
+    ///   is zero at the end of the directory. The third is the size of the
+    ///   file.
+    /// - `4`: Close done.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(app_id, |app, _| {
+                match subscribe_num {
+                    0 => app.open_callback = callback,
+                    1 => app.read_callback = callback,
+                    2 => app.write_callback = callback,
+                    3 => app.read_dir_callback = callback,
+                    4 => app.close_callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Open the file whose path is the first `arg1` bytes of the path
+    ///   buffer. If `arg2` is 1, the file is created if it does not exist.
+    /// - `2`: Read at most `arg2` bytes from file `arg1`.
+    /// - `3`: Write the first `arg2` bytes of the write buffer to file `arg1`.
+    /// - `4`: Append the first `arg2` bytes of the write buffer to file
+    ///   `arg1`.
+    /// - `5`: Read the next entry of directory `arg1`.
+    /// - `6`: Close file `arg1`, writing its data and size to the device.
+    /// - `7`: Move the position in file `arg1` to `arg2`.
+    /// - `8`: Return the size of file `arg1`.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.enqueue_command(
+                Command::Open {
+                    length: arg1,
+                    create: arg2 == 1,
+                },
+                appid,
+            ),
+            2 => self.enqueue_command(
+                Command::Read {
+                    file: arg1,
+                    length: arg2,
+                },
+                appid,
+            ),
+            3 | 4 => self.enqueue_command(
+                Command::Write {
+                    file: arg1,
+                    length: arg2,
+                    append: command_num == 4,
+                },
+                appid,
+            ),
+            5 => self.enqueue_command(Command::ReadDir { file: arg1 }, appid),
+            6 => self.enqueue_command(Command::Close { file: arg1 }, appid),
+            7 => self
+                .apps
+                .enter(appid, |app, _| {
+                    if app.pending_command.is_some() {
+                        return ReturnCode::EBUSY;
+                    }
+                    match app.files.get_mut(arg1) {
+                        Some(Some(file)) => file.seek(arg2 as u32),
+                        _ => ReturnCode::EINVAL,
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+            8 => self
+                .apps
+                .enter(appid, |app, _| match Self::file(app, arg1) {
+                    Some(file) => ReturnCode::SuccessWithValue {
+                        value: file.size() as usize,
+                    },
+                    None => ReturnCode::EINVAL,
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::storage_sim::{leak, zeroed, Sim, SimBlockStorage};
+    use kernel::hil::block_storage::Geometry;
+    use std::cell::RefCell;
+    use std::string::String;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Direct access to the simulated storage.
+    #[derive(Clone, Copy)]
+    struct Disk(&'static SimBlockStorage<'static>);
+
+    impl Disk {
+        fn peek(&self, address: usize, length: usize) -> Vec<u8> {
+            self.0
+                .map_storage(|storage| storage[address..address + length].to_vec())
+                .unwrap()
+        }
+
+        fn poke(&self, address: usize, data: &[u8]) {
+            self.0.map_storage(|storage| {
+                storage[address..address + data.len()].copy_from_slice(data)
+            });
+        }
+
+        fn set_block(&self, block: u32, data: &[u8]) {
+            self.poke(block as usize * SECTOR_SIZE, data);
+        }
+    }
+
+    /// Layout of a test volume, from which the test formats and checks
+    /// images without using the filesystem under test.
+    #[derive(Clone, Copy, Debug)]
+    struct Layout {
+        fat_type: FatType,
+        // Start of the partition, or 0 for a volume without a partition
+        // table.
+        start: u32,
+        total_sectors: u32,
+        sectors_per_cluster: u32,
+        reserved_sectors: u32,
+        num_fats: u32,
+        root_entries: u32,
+        fat_size: u32,
+    }
+
+    const FAT12: Layout = Layout {
+        fat_type: FatType::Fat12,
+        start: 0,
+        total_sectors: 2048,
+        sectors_per_cluster: 1,
+        reserved_sectors: 1,
+        num_fats: 2,
+        root_entries: 224,
+        fat_size: 6,
+    };
+
+    const FAT16: Layout = Layout {
+        fat_type: FatType::Fat16,
+        start: 2048,
+        total_sectors: 16384,
+        sectors_per_cluster: 2,
+        reserved_sectors: 4,
+        num_fats: 2,
+        root_entries: 512,
+        fat_size: 32,
+    };
+
+    const FAT32: Layout = Layout {
+        fat_type: FatType::Fat32,
+        start: 0,
+        total_sectors: 67092,
+        sectors_per_cluster: 1,
+        reserved_sectors: 32,
+        num_fats: 2,
+        root_entries: 0,
+        fat_size: 530,
+    };
+
+    /// A directory of a test image.
+    #[derive(Clone, Copy)]
+    enum Dir {
+        Root,
+        Cluster(u32),
+    }
+
+    struct Image {
+        disk: Disk,
+        layout: Layout,
+    }
+
+    impl Image {
+        fn format(disk: Disk, layout: Layout) -> Image {
+            let g = layout;
+            if g.start != 0 {
+                let mut mbr = vec![0; SECTOR_SIZE];
+                mbr[446 + 4] = 0x06;
+                write_u32(&mut mbr, 446 + 8, g.start);
+                write_u32(&mut mbr, 446 + 12, g.total_sectors);
+                mbr[510] = 0x55;
+                mbr[511] = 0xAA;
+                disk.set_block(0, &mbr);
+            }
+
+            let mut boot = vec![0; SECTOR_SIZE];
+            boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+            boot[3..11].copy_from_slice(b"MSWIN4.1");
+            write_u16(&mut boot, 11, SECTOR_SIZE as u32);
+            boot[13] = g.sectors_per_cluster as u8;
+            write_u16(&mut boot, 14, g.reserved_sectors);
+            boot[16] = g.num_fats as u8;
+            write_u16(&mut boot, 17, g.root_entries);
+            boot[21] = 0xF8;
+            write_u32(&mut boot, 28, g.start);
+            if g.fat_type == FatType::Fat32 {
+                write_u32(&mut boot, 32, g.total_sectors);
+                write_u32(&mut boot, 36, g.fat_size);
+                write_u32(&mut boot, 44, 2);
+                write_u16(&mut boot, 48, 1);
+                write_u16(&mut boot, 50, 6);
+            } else {
+                write_u16(&mut boot, 19, g.total_sectors);
+                write_u16(&mut boot, 22, g.fat_size);
+            }
+            boot[510] = 0x55;
+            boot[511] = 0xAA;
+            disk.set_block(g.start, &boot);
+
+            let image = Image {
+                disk: disk,
+                layout: layout,
+            };
+            image.set_fat(0, 0x0FFFFFF8);
+            image.set_fat(1, 0x0FFFFFFF);
+            if g.fat_type == FatType::Fat32 {
+                image.set_fat(2, 0x0FFFFFFF);
+            }
+            image
+        }
+
+        fn fat_start(&self) -> u32 {
+            self.layout.start + self.layout.reserved_sectors
+        }
+
+        fn root_start(&self) -> u32 {
+            self.fat_start() + self.layout.num_fats * self.layout.fat_size
+        }
+
+        fn data_start(&self) -> u32 {
+            self.root_start() + self.layout.root_entries * 32 / SECTOR_SIZE as u32
+        }
+
+        fn cluster_count(&self) -> u32 {
+            (self.layout.total_sectors - (self.data_start() - self.layout.start))
+                / self.layout.sectors_per_cluster
+        }
+
+        fn cluster_size(&self) -> usize {
+            self.layout.sectors_per_cluster as usize * SECTOR_SIZE
+        }
+
+        fn cluster_address(&self, cluster: u32) -> usize {
+            (self.data_start() as usize
+                + (cluster as usize - 2) * self.cluster_size() / SECTOR_SIZE)
+                * SECTOR_SIZE
+        }
+
+        fn fat_address(&self, copy: u32, cluster: u32) -> usize {
+            let offset = match self.layout.fat_type {
+                FatType::Fat12 => cluster + cluster / 2,
+                FatType::Fat16 => cluster * 2,
+                FatType::Fat32 => cluster * 4,
+            };
+            (self.fat_start() + copy * self.layout.fat_size) as usize * SECTOR_SIZE
+                + offset as usize
+        }
+
+        fn fat_copy(&self, copy: u32, cluster: u32) -> u32 {
+            let address = self.fat_address(copy, cluster);
+            match self.layout.fat_type {
+                FatType::Fat12 => {
+                    let value = read_u16(&self.disk.peek(address, 2), 0);
+                    if cluster & 1 == 1 {
+                        value >> 4
+                    } else {
+                        value & 0xFFF
+                    }
+                }
+                FatType::Fat16 => read_u16(&self.disk.peek(address, 2), 0),
+                FatType::Fat32 => read_u32(&self.disk.peek(address, 4), 0) & 0x0FFFFFFF,
+            }
+        }
+
+        fn fat(&self, cluster: u32) -> u32 {
+            self.fat_copy(0, cluster)
+        }
+
+        fn set_fat(&self, cluster: u32, value: u32) {
+            for copy in 0..self.layout.num_fats {
+                let address = self.fat_address(copy, cluster);
+                match self.layout.fat_type {
+                    FatType::Fat12 => {
+                        let mut bytes = self.disk.peek(address, 2);
+                        let old = read_u16(&bytes, 0);
+                        let new = if cluster & 1 == 1 {
+                            old & 0x000F | (value & 0xFFF) << 4
+                        } else {
+                            old & 0xF000 | value & 0xFFF
+                        };
+                        write_u16(&mut bytes, 0, new);
+                        self.disk.poke(address, &bytes);
+                    }
+                    FatType::Fat16 => self.disk.poke(address, &[value as u8, (value >> 8) as u8]),
+                    FatType::Fat32 => {
+                        let mut bytes = vec![0; 4];
+                        write_u32(&mut bytes, 0, value & 0x0FFFFFFF);
+                        self.disk.poke(address, &bytes);
+                    }
+                }
+            }
+        }
+
+        fn is_end(&self, value: u32) -> bool {
+            match self.layout.fat_type {
+                FatType::Fat12 => value >= 0xFF8,
+                FatType::Fat16 => value >= 0xFFF8,
+                FatType::Fat32 => value >= 0x0FFFFFF8,
+            }
+        }
+
+        fn chain(&self, first: u32) -> Vec<u32> {
+            let mut chain = Vec::new();
+            let mut cluster = first;
+            while cluster != 0 && !self.is_end(cluster) {
+                assert!(chain.len() <= self.cluster_count() as usize, "cycle");
+                chain.push(cluster);
+                cluster = self.fat(cluster);
+            }
+            chain
+        }
+
+        fn free_clusters(&self) -> usize {
+            (2..self.cluster_count() + 2)
+                .filter(|c| self.fat(*c) == 0)
+                .count()
+        }
+
+        /// Whether every copy of the FAT has the same entries.
+        fn fats_match(&self) -> bool {
+            (0..self.cluster_count() + 2).all(|c| {
+                (1..self.layout.num_fats).all(|copy| self.fat_copy(copy, c) == self.fat(c))
+            })
+        }
+
+        fn allocate(&self, clusters: usize) -> u32 {
+            let mut free = (2..self.cluster_count() + 2).filter(|c| self.fat(*c) == 0);
+            let chain: Vec<u32> = (0..clusters).map(|_| free.next().unwrap()).collect();
+            for pair in chain.windows(2) {
+                self.set_fat(pair[0], pair[1]);
+            }
+            chain.last().map(|last| self.set_fat(*last, 0x0FFFFFFF));
+            chain.first().cloned().unwrap_or(0)
+        }
+
+        /// Addresses of the entries of a directory.
+        fn entries(&self, dir: Dir) -> Vec<usize> {
+            let (first, fixed) = match dir {
+                Dir::Root if self.layout.fat_type != FatType::Fat32 => (0, true),
+                Dir::Root => (2, false),
+                Dir::Cluster(cluster) => (cluster, false),
+            };
+            if fixed {
+                let start = self.root_start() as usize * SECTOR_SIZE;
+                (0..self.layout.root_entries as usize)
+                    .map(|i| start + i * 32)
+                    .collect()
+            } else {
+                self.chain(first)
+                    .iter()
+                    .flat_map(|c| {
+                        let start = self.cluster_address(*c);
+                        (0..self.cluster_size() / 32).map(move |i| start + i * 32)
+                    })
+                    .collect()
+            }
+        }
+
+        fn add_entry(&self, dir: Dir, entry: &[u8]) {
+            let address = *self
+                .entries(dir)
+                .iter()
+                .find(|a| {
+                    let first = self.disk.peek(**a, 1)[0];
+                    first == ENTRY_END || first == ENTRY_FREE
+                })
+                .unwrap();
+            self.disk.poke(address, entry);
+        }
+
+        fn entry(name: &[u8; 11], attributes: u8, cluster: u32, size: u32) -> Vec<u8> {
+            let mut entry = vec![0; 32];
+            entry[..11].copy_from_slice(name);
+            entry[11] = attributes;
+            write_u16(&mut entry, 20, cluster >> 16);
+            write_u16(&mut entry, 26, cluster);
+            write_u32(&mut entry, 28, size);
+            entry
+        }
+
+        fn add_file(&self, dir: Dir, name: &[u8; 11], data: &[u8]) {
+            let clusters = (data.len() + self.cluster_size() - 1) / self.cluster_size();
+            let first = self.allocate(clusters);
+            for (cluster, chunk) in self
+                .chain(first)
+                .iter()
+                .zip(data.chunks(self.cluster_size()))
+            {
+                self.disk.poke(self.cluster_address(*cluster), chunk);
+            }
+            self.add_entry(
+                dir,
+                &Image::entry(name, ATTR_ARCHIVE, first, data.len() as u32),
+            );
+        }
+
+        fn add_dir(&self, dir: Dir, name: &[u8; 11]) -> Dir {
+            let cluster = self.allocate(1);
+            let address = self.cluster_address(cluster);
+            self.disk.poke(address, &vec![0; self.cluster_size()]);
+            let parent = match dir {
+                Dir::Root => 0,
+                Dir::Cluster(parent) => parent,
+            };
+            self.disk.poke(
+                address,
+                &Image::entry(b".          ", ATTR_DIRECTORY, cluster, 0),
+            );
+            self.disk.poke(
+                address + 32,
+                &Image::entry(b"..         ", ATTR_DIRECTORY, parent, 0),
+            );
+            self.add_entry(dir, &Image::entry(name, ATTR_DIRECTORY, cluster, 0));
+            Dir::Cluster(cluster)
+        }
+
+        /// Contents of a file, found and read without the filesystem under
+        /// test.
+        fn file(&self, dir: Dir, name: &[u8; 11]) -> Option<Vec<u8>> {
+            let address = *self
+                .entries(dir)
+                .iter()
+                .find(|a| self.disk.peek(**a, 11) == &name[..])?;
+            let entry = self.disk.peek(address, 32);
+            let first = read_u16(&entry, 20) << 16 | read_u16(&entry, 26);
+            let size = read_u32(&entry, 28) as usize;
+            let chain = self.chain(first);
+            assert_eq!(
+                chain.len(),
+                (size + self.cluster_size() - 1) / self.cluster_size()
+            );
+            let mut data: Vec<u8> = chain
+                .iter()
+                .flat_map(|c| {
+                    self.disk
+                        .peek(self.cluster_address(*c), self.cluster_size())
+                })
+                .collect();
+            data.truncate(size);
+            Some(data)
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Opened(File, ReturnCode),
+        Read(File, Vec<u8>, ReturnCode),
+        Written(File, usize, ReturnCode),
+        Entry(File, Option<DirEntry>, ReturnCode),
+        Closed(ReturnCode),
+    }
+
+    struct TestClient {
+        buffer: TakeCell<'static, [u8]>,
+        events: RefCell<Vec<Event>>,
+    }
+
+    impl FatClient for TestClient {
+        fn open_done(&self, file: File, result: ReturnCode) {
+            self.events.borrow_mut().push(Event::Opened(file, result));
+        }
+
+        fn read_done(
+            &self,
+            file: File,
+            buffer: &'static mut [u8],
+            length: usize,
+            result: ReturnCode,
+        ) {
+            self.events
+                .borrow_mut()
+                .push(Event::Read(file, buffer[..length].to_vec(), result));
+            self.buffer.replace(buffer);
+        }
+
+        fn write_done(
+            &self,
+            file: File,
+            buffer: &'static mut [u8],
+            length: usize,
+            result: ReturnCode,
+        ) {
+            self.events
+                .borrow_mut()
+                .push(Event::Written(file, length, result));
+            self.buffer.replace(buffer);
+        }
+
+        fn read_dir_done(&self, dir: File, entry: Option<DirEntry>, result: ReturnCode) {
+            self.events
+                .borrow_mut()
+                .push(Event::Entry(dir, entry, result));
+        }
+
+        fn close_done(&self, result: ReturnCode) {
+            self.events.borrow_mut().push(Event::Closed(result));
+        }
+    }
+
+    struct Harness {
+        image: Image,
+        fs: &'static FatFs<'static, SimBlockStorage<'static>>,
+        sim: &'static Sim<SimBlockStorage<'static>>,
+        client: &'static TestClient,
+    }
+
+    impl Harness {
+        fn new(layout: Layout) -> Harness {
+            Harness::with_geometry(
+                layout,
+                Geometry {
+                    read_size: SECTOR_SIZE,
+                    write_size: SECTOR_SIZE,
+                    erase_size: 0,
+                },
+            )
+        }
+
+        fn with_geometry(layout: Layout, geometry: Geometry) -> Harness {
+            let size = (layout.start + layout.total_sectors) as usize * SECTOR_SIZE;
+            let sim = Sim::blocks(vec![0; size], geometry);
+            let fs = leak(FatFs::new(
+                sim.storage,
+                zeroed(2 * SECTOR_SIZE),
+                sim.deferred_caller,
+            ));
+            fs.set_deferred_call_handle(sim.deferred_caller.register(fs).unwrap());
+            sim.storage.set_client(fs);
+            let client = leak(TestClient {
+                buffer: TakeCell::new(zeroed(4096)),
+                events: RefCell::new(Vec::new()),
+            });
+            fs.set_client(client);
+            Harness {
+                image: Image::format(Disk(sim.storage), layout),
+                fs: fs,
+                sim: sim,
+                client: client,
+            }
+        }
+
+        fn event(&self) -> Event {
+            self.sim.pump();
+            let mut events = self.client.events.borrow_mut();
+            assert_eq!(events.len(), 1, "{:?}", *events);
+            events.pop().unwrap()
+        }
+
+        fn open(&self, path: &str, create: bool) -> Result<File, ReturnCode> {
+            assert_eq!(self.fs.open(path.as_bytes(), create), ReturnCode::SUCCESS);
+            match self.event() {
+                Event::Opened(file, ReturnCode::SUCCESS) => Ok(file),
+                Event::Opened(_, error) => Err(error),
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        fn read(&self, file: &mut File, length: usize) -> Vec<u8> {
+            let buffer = self.client.buffer.take().unwrap();
+            let (result, _) = self.fs.read(*file, buffer, length);
+            assert_eq!(result, ReturnCode::SUCCESS);
+            match self.event() {
+                Event::Read(updated, data, ReturnCode::SUCCESS) => {
+                    *file = updated;
+                    data
+                }
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        fn read_all(&self, file: &mut File, chunk: usize) -> Vec<u8> {
+            let mut data = Vec::new();
+            loop {
+                let part = self.read(file, chunk);
+                if part.is_empty() {
+                    return data;
+                }
+                data.extend(part);
+            }
+        }
+
+        fn write(&self, file: &mut File, data: &[u8]) -> (usize, ReturnCode) {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            let (result, _) = self.fs.write(*file, buffer, data.len());
+            assert_eq!(result, ReturnCode::SUCCESS);
+            match self.event() {
+                Event::Written(updated, length, result) => {
+                    *file = updated;
+                    (length, result)
+                }
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        fn write_all(&self, file: &mut File, data: &[u8], chunk: usize) {
+            for part in data.chunks(chunk) {
+                assert_eq!(self.write(file, part), (part.len(), ReturnCode::SUCCESS));
+            }
+        }
+
+        fn list(&self, path: &str) -> Vec<(String, u32, bool)> {
+            let mut dir = self.open(path, false).unwrap();
+            let mut entries = Vec::new();
+            loop {
+                assert_eq!(self.fs.read_dir(dir), ReturnCode::SUCCESS);
+                match self.event() {
+                    Event::Entry(updated, Some(entry), ReturnCode::SUCCESS) => {
+                        dir = updated;
+                        entries.push((
+                            String::from_utf8(entry.name().to_vec()).unwrap(),
+                            entry.size(),
+                            entry.is_dir(),
+                        ));
+                    }
+                    Event::Entry(_, None, ReturnCode::SUCCESS) => return entries,
+                    event => panic!("unexpected {:?}", event),
+                }
+            }
+        }
+
+        fn close(&self, file: File) {
+            assert_eq!(self.fs.close(file), ReturnCode::SUCCESS);
+            assert_eq!(self.event(), Event::Closed(ReturnCode::SUCCESS));
+        }
+    }
+
+    fn data(length: usize, seed: usize) -> Vec<u8> {
+        (0..length)
+            .map(|i| (i * 13 + i / 256 + seed) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn reads_files_on_each_fat_type() {
+        for layout in [FAT12, FAT16, FAT32].iter() {
+            let h = Harness::new(*layout);
+            let hello = data(3 * h.image.cluster_size() + 77, 1);
+            let inner = data(1000, 2);
+            h.image.add_file(Dir::Root, b"HELLO   TXT", &hello);
+            let dir = h.image.add_dir(Dir::Root, b"DIR        ");
+            h.image.add_file(dir, b"INNER   BIN", &inner);
+
+            let mut file = h.open("/hello.txt", false).unwrap();
+            assert_eq!(h.fs.fat_type(), Some(layout.fat_type));
+            assert_eq!(file.size(), hello.len() as u32);
+            assert_eq!(h.read_all(&mut file, 300), hello);
+
+            // Reading again from the middle walks the chain from its start.
+            assert_eq!(file.seek(600), ReturnCode::SUCCESS);
+            assert_eq!(h.read(&mut file, 100), &hello[600..700]);
+
+            let mut file = h.open("DIR/INNER.BIN", false).unwrap();
+            assert_eq!(h.read_all(&mut file, 4096), inner);
+
+            assert_eq!(h.open("/MISSING.TXT", false), Err(ReturnCode::ENOSUPPORT));
+            assert_eq!(h.open("/HELLO.TXT/X", false), Err(ReturnCode::ENOSUPPORT));
+            assert_eq!(h.open("/NOPE/X", true), Err(ReturnCode::ENOSUPPORT));
+            assert_eq!(h.fs.open(b"/LONGFILENAME.TXT", false), ReturnCode::EINVAL);
+        }
+    }
+
+    #[test]
+    fn creates_and_writes_files_across_clusters() {
+        for layout in [FAT12, FAT16, FAT32].iter() {
+            let h = Harness::new(*layout);
+            let free = h.image.free_clusters();
+            let contents = data(3000, 3);
+
+            let mut file = h.open("/NEW.DAT", true).unwrap();
+            assert_eq!(file.size(), 0);
+            h.write_all(&mut file, &contents, 700);
+            assert_eq!(file.size(), 3000);
+            h.close(file);
+
+            assert_eq!(
+                h.image.file(Dir::Root, b"NEW     DAT"),
+                Some(contents.clone())
+            );
+            let clusters = (3000 + h.image.cluster_size() - 1) / h.image.cluster_size();
+            assert_eq!(h.image.free_clusters(), free - clusters);
+            assert!(h.image.fats_match());
+
+            let mut file = h.open("/new.dat", true).unwrap();
+            assert_eq!(h.read_all(&mut file, 512), contents);
+        }
+    }
+
+    #[test]
+    fn overwrites_and_appends() {
+        for layout in [FAT12, FAT32].iter() {
+            let h = Harness::new(*layout);
+            let mut contents = data(1500, 4);
+            h.image.add_file(Dir::Root, b"LOG     TXT", &contents);
+
+            let mut file = h.open("/LOG.TXT", false).unwrap();
+            assert_eq!(file.seek(1501), ReturnCode::EINVAL);
+            assert_eq!(file.seek(100), ReturnCode::SUCCESS);
+            h.write_all(&mut file, &[0xAA; 50], 50);
+            contents[100..150].copy_from_slice(&[0xAA; 50]);
+            assert_eq!(file.size(), 1500);
+
+            let size = file.size();
+            assert_eq!(file.seek(size), ReturnCode::SUCCESS);
+            let more = data(2000, 5);
+            h.write_all(&mut file, &more, 999);
+            contents.extend(&more);
+            h.close(file);
+
+            assert_eq!(h.image.file(Dir::Root, b"LOG     TXT"), Some(contents));
+            assert!(h.image.fats_match());
+        }
+    }
+
+    #[test]
+    fn lists_directories() {
+        let h = Harness::new(FAT16);
+        h.image.add_entry(
+            Dir::Root,
+            &Image::entry(b"VOLUME     ", ATTR_VOLUME_ID, 0, 0),
+        );
+        h.image.add_file(Dir::Root, b"A       TXT", &data(10, 0));
+        let mut long_name = Image::entry(b"Bxxxxxxxxxx", ATTR_LONG_NAME, 0, 0);
+        long_name[0] = 0x41;
+        h.image.add_entry(Dir::Root, &long_name);
+        h.image.add_file(Dir::Root, b"DELETED    ", &data(10, 0));
+        let dir = h.image.add_dir(Dir::Root, b"SUB        ");
+        h.image.add_file(dir, b"README     ", &data(700, 0));
+        h.image.add_file(Dir::Root, b"LAST    C  ", &data(3, 0));
+        let deleted = h.image.entries(Dir::Root)[3];
+        h.image.disk.poke(deleted, &[ENTRY_FREE]);
+
+        assert_eq!(
+            h.list("/"),
+            vec![
+                (String::from("A.TXT"), 10, false),
+                (String::from("SUB"), 0, true),
+                (String::from("LAST.C"), 3, false),
+            ]
+        );
+        assert_eq!(h.list("/SUB"), vec![(String::from("README"), 700, false)]);
+    }
+
+    #[test]
+    fn creates_files_in_growing_subdirectories() {
+        for layout in [FAT16, FAT32].iter() {
+            let h = Harness::new(*layout);
+            let dir = h.image.add_dir(Dir::Root, b"LOGS       ");
+            let entries_per_cluster = h.image.cluster_size() / 32;
+
+            // With the dot entries, this needs more than two clusters.
+            let count = 2 * entries_per_cluster;
+            for i in 0..count {
+                let path = std::format!("/LOGS/F{}.LOG", i);
+                let mut file = h.open(&path, true).unwrap();
+                h.write_all(&mut file, &data(i, i), 512);
+                h.close(file);
+            }
+
+            match dir {
+                Dir::Cluster(cluster) => assert_eq!(h.image.chain(cluster).len(), 3),
+                Dir::Root => unreachable!(),
+            }
+            for i in 0..count {
+                let name = std::format!("F{:<7}LOG", i);
+                let mut short_name = [0; 11];
+                short_name.copy_from_slice(name.as_bytes());
+                assert_eq!(h.image.file(dir, &short_name), Some(data(i, i)));
+            }
+            assert_eq!(h.list("/LOGS").len(), count);
+            assert!(h.image.fats_match());
+        }
+    }
+
+    #[test]
+    fn fixed_root_directory_fills_up() {
+        let h = Harness::new(FAT12);
+        for _ in 0..FAT12.root_entries {
+            h.image
+                .add_entry(Dir::Root, &Image::entry(b"FILLER     ", ATTR_ARCHIVE, 0, 0));
+        }
+        assert_eq!(h.open("/NEW", true), Err(ReturnCode::ENOMEM));
+    }
+
+    #[test]
+    fn reports_a_full_disk() {
+        let h = Harness::new(FAT12);
+        h.image
+            .add_file(Dir::Root, b"BIG        ", &data(500 * 512, 6));
+        let free = h.image.free_clusters();
+
+        let mut file = h.open("/FILL", true).unwrap();
+        let mut written = 0;
+        loop {
+            let (length, result) = h.write(&mut file, &data(1000, written));
+            written += length;
+            if result != ReturnCode::SUCCESS {
+                assert_eq!(result, ReturnCode::ENOMEM);
+                break;
+            }
+        }
+        assert_eq!(written, free * 512);
+        assert_eq!(file.size() as usize, written);
+        h.close(file);
+
+        assert_eq!(h.image.free_clusters(), 0);
+        assert_eq!(
+            h.image.file(Dir::Root, b"FILL       ").unwrap().len(),
+            written
+        );
+        assert!(h.image.fats_match());
+        assert_eq!(
+            h.image.file(Dir::Root, b"BIG        "),
+            Some(data(500 * 512, 6))
+        );
+    }
+
+    #[test]
+    fn refuses_storage_that_must_be_erased() {
+        let geometry = Geometry {
+            read_size: SECTOR_SIZE,
+            write_size: SECTOR_SIZE,
+            erase_size: 4096,
+        };
+        let h = Harness::with_geometry(FAT12, geometry);
+        assert_eq!(h.open("/", false), Err(ReturnCode::ENOSUPPORT));
+    }
+}
@@ -0,0 +1,571 @@
+//! Software CSMA-CA and retransmission MAC layer for IEEE 802.15.4 radios
+//! that do not implement channel access, acknowledgements and retries in
+//! hardware.
+//!
+//! `CsmaMac` implements the unslotted CSMA-CA algorithm of IEEE
+//! 802.15.4-2015 (6.2.5.1) on top of any `kernel::hil::radio::Radio`. Before
+//! each transmission attempt, it waits for a random number of backoff periods
+//! between 0 and `2^BE - 1` and then performs a clear channel assessment
+//! (CCA). If the channel is busy, the backoff exponent `BE` is increased up to
+//! `max_be` and it backs off again, giving up with EBUSY after
+//! `max_csma_backoffs` busy assessments.
+//!
+//! Clear channel assessment uses the energy detection of the radio, if it
+//! implements `kernel::hil::radio::RadioEnergyDetect`: the channel is clear
+//! when the measured energy is below the CCA threshold. Without energy
+//! detection, the channel is always assumed to be clear and only the random
+//! backoff is performed.
+//!
+//! When a transmitted frame requests an acknowledgement, the layer waits
+//! `macAckWaitDuration` for an ACK frame with the matching sequence number,
+//! retransmitting the frame (with a new CSMA-CA backoff) up to
+//! `max_frame_retries` times. The client is told whether the frame was
+//! acknowledged through `send_done(acked)`; if no acknowledgement was received
+//! after the last retry the result is ENOACK. Received ACK frames are consumed
+//! by this layer and not passed up.
+//!
+//! Unless the radio acknowledges frames itself, this layer also sends an
+//! immediate ACK for each received frame that is addressed to it and requests
+//! one, using the buffer passed to `initialize`. The sequence number of the
+//! last such frame from each sender is remembered, so that a retransmission of
+//! a frame whose ACK was lost is acknowledged again but not passed up twice.
+//!
+//! Usage
+//! -----
+//! This capsule implements the `capsules::ieee802154::mac::Mac` interface
+//! while wrapping a `kernel::hil::radio::Radio`, and can be used as the
+//! backend for a `capsules::ieee802154::device::MacDevice` such as a `Framer`.
+//! Backoffs are drawn from a `kernel::hil::rng::Random`, such as the
+//! `SynchronousRandom` of the `rng` capsule.
+//!
+//! ```rust
+//! type CsmaDevice = capsules::ieee802154::csma::CsmaMac<
+//!     'static,
+//!     Radio,
+//!     VirtualMuxAlarm<'static, Rtc>,
+//! >;
+//!
+//! let csma_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let random = static_init!(
+//!     capsules::rng::SynchronousRandom<'static>,
+//!     capsules::rng::SynchronousRandom::new(rng)
+//! );
+//! random.initialize();
+//! let csma_mac = static_init!(CsmaDevice, CsmaMac::new(radio, csma_alarm, random));
+//! csma_mac.initialize(&mut CSMA_ACK_BUF);
+//! csma_alarm.set_client(csma_mac);
+//! radio.set_transmit_client(csma_mac);
+//! radio.set_receive_client(csma_mac, &mut RADIO_RX_BUF);
+//! // Optional: use the radio's energy detection for CCA
+//! csma_mac.set_energy_detect(radio);
+//! radio.set_energy_client(csma_mac);
+//!
+//! let mac_device = static_init!(
+//!     capsules::ieee802154::framer::Framer<'static, CsmaDevice, AESCCM>,
+//!     capsules::ieee802154::framer::Framer::new(csma_mac, aes_ccm)
+//! );
+//! csma_mac.set_transmit_client(mac_device);
+//! csma_mac.set_receive_client(mac_device);
+//! csma_mac.set_config_client(mac_device);
+//! ```
+
+use crate::ieee802154::mac::{self, Mac};
+use crate::net::ieee802154::{FrameType, Header, MacAddress, BROADCAST};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::radio;
+use kernel::hil::rng::Random;
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::ReturnCode;
+
+// The symbol period of the 2.4 GHz O-QPSK PHY, in microseconds
+const SYMBOL_PERIOD_US: u32 = 16;
+// aUnitBackoffPeriod, in symbols
+const UNIT_BACKOFF_PERIOD: u32 = 20;
+// macAckWaitDuration for the 2.4 GHz O-QPSK PHY: aUnitBackoffPeriod +
+// aTurnaroundTime + phySHRDuration + 6 * phySymbolsPerOctet, in symbols
+const ACK_WAIT_DURATION: u32 = UNIT_BACKOFF_PERIOD + 12 + 10 + 12;
+// An immediate ACK is the frame control field and the sequence number
+const ACK_LEN: usize = 3;
+// The number of senders whose last sequence number is remembered
+const RX_SEQ_ENTRIES: usize = 8;
+
+/// Default value of macMinBe
+pub const DEFAULT_MIN_BE: u8 = 3;
+/// Default value of macMaxBe
+pub const DEFAULT_MAX_BE: u8 = 5;
+/// Default value of macMaxCsmaBackoffs
+pub const DEFAULT_MAX_CSMA_BACKOFFS: u8 = 4;
+/// Default value of macMaxFrameRetries
+pub const DEFAULT_MAX_FRAME_RETRIES: u8 = 3;
+/// Default energy level at or above which the channel is considered busy,
+/// on the 0-255 scale of `RadioEnergyDetect`.
+pub const DEFAULT_CCA_THRESHOLD: u8 = 64;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum CsmaState {
+    Idle,
+    // Waiting for the random backoff before a CCA
+    Backoff,
+    // Waiting for the energy measurement of a CCA
+    Cca,
+    // Waiting for the radio to transmit the frame
+    Transmitting,
+    // Waiting for an acknowledgement of the transmitted frame
+    WaitingAck,
+}
+
+pub struct CsmaMac<'a, R: radio::Radio, A: Alarm> {
+    radio: &'a R,
+    alarm: &'a A,
+    random: &'a Random<'a>,
+    energy_detect: OptionalCell<&'a radio::RadioEnergyDetect>,
+
+    tx_client: OptionalCell<&'static radio::TxClient>,
+    rx_client: OptionalCell<&'static radio::RxClient>,
+
+    state: Cell<CsmaState>,
+    // The frame being transmitted, and whether and with which sequence number
+    // it must be acknowledged
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_ack_seq: Cell<Option<u8>>,
+    // NB, BE and the number of retransmissions of the current frame
+    backoffs: Cell<u8>,
+    backoff_exponent: Cell<u8>,
+    retries: Cell<u8>,
+
+    min_be: Cell<u8>,
+    max_be: Cell<u8>,
+    max_csma_backoffs: Cell<u8>,
+    max_frame_retries: Cell<u8>,
+    cca_threshold: Cell<u8>,
+    promiscuous: Cell<bool>,
+
+    // The buffer for the ACKs sent for received frames, and whether one is
+    // being transmitted
+    ack_buf: TakeCell<'static, [u8]>,
+    sending_ack: Cell<bool>,
+    // The sequence number of the last frame received from recent senders,
+    // replaced in round-robin order
+    rx_seqs: Cell<[Option<(MacAddress, u8)>; RX_SEQ_ENTRIES]>,
+    next_rx_seq: Cell<usize>,
+}
+
+impl<R: radio::Radio, A: Alarm> CsmaMac<'a, R, A> {
+    pub fn new(radio: &'a R, alarm: &'a A, random: &'a Random<'a>) -> CsmaMac<'a, R, A> {
+        CsmaMac {
+            radio: radio,
+            alarm: alarm,
+            random: random,
+            energy_detect: OptionalCell::empty(),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            state: Cell::new(CsmaState::Idle),
+            tx_buf: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_ack_seq: Cell::new(None),
+            backoffs: Cell::new(0),
+            backoff_exponent: Cell::new(0),
+            retries: Cell::new(0),
+            min_be: Cell::new(DEFAULT_MIN_BE),
+            max_be: Cell::new(DEFAULT_MAX_BE),
+            max_csma_backoffs: Cell::new(DEFAULT_MAX_CSMA_BACKOFFS),
+            max_frame_retries: Cell::new(DEFAULT_MAX_FRAME_RETRIES),
+            cca_threshold: Cell::new(DEFAULT_CCA_THRESHOLD),
+            promiscuous: Cell::new(false),
+            ack_buf: TakeCell::empty(),
+            sending_ack: Cell::new(false),
+            rx_seqs: Cell::new([None; RX_SEQ_ENTRIES]),
+            next_rx_seq: Cell::new(0),
+        }
+    }
+
+    /// Sets the energy detector used for clear channel assessment, normally
+    /// the radio itself.
+    pub fn set_energy_detect(&self, energy_detect: &'a radio::RadioEnergyDetect) {
+        self.energy_detect.set(energy_detect);
+    }
+
+    /// Sets the minimum and maximum backoff exponents (macMinBe and macMaxBe).
+    /// Returns EINVAL unless `min_be <= max_be <= 8`.
+    pub fn set_backoff_exponents(&self, min_be: u8, max_be: u8) -> ReturnCode {
+        if min_be > max_be || max_be > 8 {
+            return ReturnCode::EINVAL;
+        }
+        self.min_be.set(min_be);
+        self.max_be.set(max_be);
+        ReturnCode::SUCCESS
+    }
+
+    /// Sets the number of times the channel can be found busy before a
+    /// transmission attempt fails (macMaxCsmaBackoffs).
+    pub fn set_max_csma_backoffs(&self, max_csma_backoffs: u8) {
+        self.max_csma_backoffs.set(max_csma_backoffs);
+    }
+
+    /// Sets the number of retransmissions of an unacknowledged frame
+    /// (macMaxFrameRetries).
+    pub fn set_max_frame_retries(&self, max_frame_retries: u8) {
+        self.max_frame_retries.set(max_frame_retries);
+    }
+
+    /// Sets the energy level at or above which the channel is busy.
+    pub fn set_cca_threshold(&self, threshold: u8) {
+        self.cca_threshold.set(threshold);
+    }
+
+    fn set_timer_symbols(&self, symbols: u32) {
+        let us = (symbols * SYMBOL_PERIOD_US) as u64;
+        let ticks = (us * <A::Frequency>::frequency() as u64 / 1_000_000) as u32;
+        // A zero-tick alarm would be in the past by the time it is set
+        self.alarm
+            .set_alarm(self.alarm.now().wrapping_add(ticks.max(1)));
+    }
+
+    /// Starts the CSMA-CA algorithm for a new transmission attempt.
+    fn start_csma(&self) {
+        self.backoffs.set(0);
+        self.backoff_exponent.set(self.min_be.get());
+        self.backoff();
+    }
+
+    /// Waits a random number of unit backoff periods in [0, 2^BE - 1].
+    fn backoff(&self) {
+        self.state.set(CsmaState::Backoff);
+        let periods = self.random.random() & ((1 << self.backoff_exponent.get()) - 1);
+        self.set_timer_symbols(periods * UNIT_BACKOFF_PERIOD);
+    }
+
+    fn clear_channel_assessment(&self) {
+        let started = self.energy_detect.map(|energy_detect| {
+            self.state.set(CsmaState::Cca);
+            energy_detect.energy_detect()
+        });
+        match started {
+            None => self.send(),
+            Some(ReturnCode::SUCCESS) => {}
+            // The radio cannot measure energy while it is receiving a frame,
+            // so the channel is busy.
+            Some(_) => self.channel_busy(),
+        }
+    }
+
+    fn channel_busy(&self) {
+        let backoffs = self.backoffs.get() + 1;
+        self.backoffs.set(backoffs);
+        if backoffs > self.max_csma_backoffs.get() {
+            // Channel access failure
+            self.done(false, ReturnCode::EBUSY);
+        } else {
+            let be = self.backoff_exponent.get() + 1;
+            self.backoff_exponent.set(be.min(self.max_be.get()));
+            self.backoff();
+        }
+    }
+
+    fn send(&self) {
+        if self.sending_ack.get() {
+            // The channel is taken by our own ACK
+            self.channel_busy();
+            return;
+        }
+        self.state.set(CsmaState::Transmitting);
+        let result = self.tx_buf.take().map(|buf| {
+            let (result, buf) = self.radio.transmit(buf, self.tx_len.get());
+            buf.map(|buf| self.tx_buf.replace(buf));
+            result
+        });
+        match result {
+            Some(ReturnCode::SUCCESS) => {}
+            Some(result) => self.done(false, result),
+            None => self.done(false, ReturnCode::FAIL),
+        }
+    }
+
+    /// The frame was not acknowledged in time: retransmit it if there are
+    /// retries left.
+    fn ack_timeout(&self) {
+        let retries = self.retries.get();
+        if retries < self.max_frame_retries.get() {
+            self.retries.set(retries + 1);
+            self.start_csma();
+        } else {
+            self.done(false, ReturnCode::ENOACK);
+        }
+    }
+
+    /// Sends an immediate ACK for the received frame with sequence number
+    /// `seq`. If the radio is busy, no ACK is sent and the sender retries.
+    fn send_ack(&self, seq: u8) {
+        if self.state.get() == CsmaState::Transmitting || self.sending_ack.get() {
+            return;
+        }
+        self.ack_buf.take().map(|buf| {
+            let fcf = FrameType::Acknowledgement as u16;
+            buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + 2].copy_from_slice(&fcf.to_le_bytes());
+            buf[radio::PSDU_OFFSET + 2] = seq;
+            let (result, buf) = self.radio.transmit(buf, ACK_LEN);
+            buf.map(|buf| self.ack_buf.replace(buf));
+            self.sending_ack.set(result == ReturnCode::SUCCESS);
+        });
+    }
+
+    /// Records `seq` as the last sequence number received from `src`, and
+    /// returns whether it already was, in which case the frame is a
+    /// retransmission.
+    fn is_duplicate(&self, src: MacAddress, seq: u8) -> bool {
+        let mut rx_seqs = self.rx_seqs.get();
+        match rx_seqs
+            .iter_mut()
+            .find(|entry| entry.map_or(false, |(addr, _)| addr == src))
+        {
+            Some(entry) => {
+                let duplicate = *entry == Some((src, seq));
+                *entry = Some((src, seq));
+                self.rx_seqs.set(rx_seqs);
+                duplicate
+            }
+            None => {
+                let next = self.next_rx_seq.get();
+                rx_seqs[next] = Some((src, seq));
+                self.rx_seqs.set(rx_seqs);
+                self.next_rx_seq.set((next + 1) % RX_SEQ_ENTRIES);
+                false
+            }
+        }
+    }
+
+    /// Ends the transmission and returns the frame to the client.
+    fn done(&self, acked: bool, result: ReturnCode) {
+        self.state.set(CsmaState::Idle);
+        self.tx_buf.take().map(|buf| {
+            self.tx_client.map(move |client| {
+                client.send_done(buf, acked, result);
+            });
+        });
+    }
+}
+
+impl<R: radio::Radio, A: Alarm> Mac for CsmaMac<'a, R, A> {
+    fn initialize(&self, mac_buf: &'static mut [u8]) -> ReturnCode {
+        // The frame buffer passed to transmit is kept for retransmissions,
+        // so the extra buffer is only used to send ACKs.
+        if mac_buf.len() < radio::PSDU_OFFSET + ACK_LEN {
+            return ReturnCode::ESIZE;
+        }
+        self.ack_buf.replace(mac_buf);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_on(&self) -> bool {
+        self.radio.is_on()
+    }
+
+    fn set_config_client(&self, client: &'static radio::ConfigClient) {
+        self.radio.set_config_client(client)
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.radio.set_address(addr)
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.radio.set_address_long(addr)
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.radio.set_pan(id)
+    }
+
+    fn get_address(&self) -> u16 {
+        self.radio.get_address()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.radio.get_address_long()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.radio.get_pan()
+    }
+
+    fn get_channel(&self) -> u8 {
+        self.radio.get_channel()
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        self.radio.set_channel(chan)
+    }
+
+    fn get_promiscuous(&self) -> bool {
+        self.promiscuous.get()
+    }
+
+    fn set_promiscuous(&self, enable: bool) -> ReturnCode {
+        // The radio always stays in promiscuous mode: acknowledgements
+        // are not addressed to us, so this layer filters frames in software.
+        if enable && !self.radio.get_promiscuous() {
+            return ReturnCode::ENOSUPPORT;
+        }
+        self.promiscuous.set(enable);
+        ReturnCode::SUCCESS
+    }
+
+    fn get_auto_ack(&self) -> bool {
+        self.radio.get_auto_ack()
+    }
+
+    fn set_auto_ack(&self, enable: bool) -> ReturnCode {
+        self.radio.set_auto_ack(enable)
+    }
+
+    fn config_commit(&self) {
+        self.radio.config_commit()
+    }
+
+    fn set_transmit_client(&self, client: &'static radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'static radio::RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.radio.set_receive_buffer(buffer);
+    }
+
+    fn transmit(
+        &self,
+        full_mac_frame: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.state.get() != CsmaState::Idle {
+            return (ReturnCode::EBUSY, Some(full_mac_frame));
+        }
+        if !self.radio.is_on() {
+            return (ReturnCode::EOFF, Some(full_mac_frame));
+        }
+        let header = match Header::decode(&full_mac_frame[radio::PSDU_OFFSET..], false).done() {
+            Some((_, (header, _))) => header,
+            None => return (ReturnCode::EINVAL, Some(full_mac_frame)),
+        };
+        let ack_seq = if header.ack_requested {
+            header.seq
+        } else {
+            None
+        };
+
+        self.tx_ack_seq.set(ack_seq);
+        self.tx_len.set(frame_len);
+        self.tx_buf.replace(full_mac_frame);
+        self.retries.set(0);
+        self.start_csma();
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+impl<R: radio::Radio, A: Alarm> time::Client for CsmaMac<'a, R, A> {
+    fn fired(&self) {
+        match self.state.get() {
+            CsmaState::Backoff => self.clear_channel_assessment(),
+            CsmaState::WaitingAck => self.ack_timeout(),
+            _ => {}
+        }
+    }
+}
+
+impl<R: radio::Radio, A: Alarm> radio::EnergyClient for CsmaMac<'a, R, A> {
+    fn energy_detect_done(&self, level: u8, result: ReturnCode) {
+        if self.state.get() != CsmaState::Cca {
+            return;
+        }
+        if result == ReturnCode::SUCCESS && level < self.cca_threshold.get() {
+            self.send();
+        } else {
+            self.channel_busy();
+        }
+    }
+}
+
+impl<R: radio::Radio, A: Alarm> radio::TxClient for CsmaMac<'a, R, A> {
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        if self.sending_ack.get() {
+            self.sending_ack.set(false);
+            self.ack_buf.replace(buf);
+            return;
+        }
+        self.tx_buf.replace(buf);
+        if result != ReturnCode::SUCCESS {
+            self.done(false, result);
+        } else if self.tx_ack_seq.get().is_none() || acked {
+            // No acknowledgement needed, or the radio already received it
+            self.done(acked, result);
+        } else {
+            self.state.set(CsmaState::WaitingAck);
+            self.set_timer_symbols(ACK_WAIT_DURATION);
+        }
+    }
+}
+
+impl<R: radio::Radio, A: Alarm> radio::RxClient for CsmaMac<'a, R, A> {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        quality: radio::LinkQuality,
+        crc_valid: bool,
+        result: ReturnCode,
+    ) {
+        let frame = &buf[radio::PSDU_OFFSET..];
+        let header = Header::decode(frame, false).done().map(|(_, (header, _))| {
+            (
+                header.frame_type,
+                header.seq,
+                header.ack_requested && header.dst_addr != Some(MacAddress::Short(BROADCAST)),
+                header.src_addr,
+            )
+        });
+        let addressed = mac::addressed_to_radio(self.radio, frame);
+        match header {
+            Some((FrameType::Acknowledgement, seq, _, _)) => {
+                // ACKs are handled here and never passed up
+                if crc_valid
+                    && self.state.get() == CsmaState::WaitingAck
+                    && seq.is_some()
+                    && seq == self.tx_ack_seq.get()
+                {
+                    self.alarm.disable();
+                    self.done(true, ReturnCode::SUCCESS);
+                }
+                self.radio.set_receive_buffer(buf);
+            }
+            Some((_, Some(seq), true, src)) if addressed && crc_valid => {
+                if !self.radio.get_auto_ack() {
+                    self.send_ack(seq);
+                }
+                let duplicate = src.map_or(false, |src| self.is_duplicate(src, seq));
+                if duplicate {
+                    self.radio.set_receive_buffer(buf);
+                } else {
+                    self.rx_client.map(move |client| {
+                        client.receive(buf, frame_len, quality, crc_valid, result);
+                    });
+                }
+            }
+            _ => {
+                if self.promiscuous.get() || addressed {
+                    self.rx_client.map(move |client| {
+                        client.receive(buf, frame_len, quality, crc_valid, result);
+                    });
+                } else {
+                    self.radio.set_receive_buffer(buf);
+                }
+            }
+        }
+    }
+}
@@ -12,7 +12,8 @@
 //! procedure in hardware, as opposed to requiring a software implementation.
 
 use crate::ieee802154::framer::Frame;
-use crate::net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel};
+use crate::net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel, SuperframeSpec};
+use kernel::hil::radio::LinkQuality;
 use kernel::ReturnCode;
 
 pub trait MacDevice<'a> {
@@ -35,6 +36,29 @@ pub trait MacDevice<'a> {
     /// Set the 16-bit PAN ID of the MAC device
     fn set_pan(&self, id: u16);
 
+    /// The 802.15.4 channel of the MAC device
+    fn get_channel(&self) -> u8;
+    /// Set the 802.15.4 channel of the MAC device. Returns EINVAL if the
+    /// channel is not supported.
+    fn set_channel(&self, chan: u8) -> ReturnCode;
+
+    /// Whether frames not addressed to this MAC device are received
+    fn get_promiscuous(&self) -> bool;
+    /// Receive all frames rather than only those addressed to this MAC device.
+    /// Returns ENOSUPPORT if the radio cannot receive all frames.
+    fn set_promiscuous(&self, enable: bool) -> ReturnCode;
+    /// Whether received frames requesting an acknowledgement are acknowledged
+    fn get_auto_ack(&self) -> bool;
+    /// Set whether received frames requesting an acknowledgement are
+    /// acknowledged. Returns ENOSUPPORT if this cannot be changed.
+    fn set_auto_ack(&self, enable: bool) -> ReturnCode;
+
+    /// The frame counter of the next secured outgoing frame
+    fn get_outgoing_frame_counter(&self) -> u32;
+    /// Set the frame counter of the next secured outgoing frame, e.g. to
+    /// restore the value last reported to the `FrameCounterClient`
+    fn set_outgoing_frame_counter(&self, frame_counter: u32);
+
     /// This method must be called after one or more calls to `set_*`. If
     /// `set_*` is called without calling `config_commit`, there is no guarantee
     /// that the underlying hardware configuration (addresses, pan ID) is in
@@ -69,6 +93,42 @@ pub trait MacDevice<'a> {
         security_needed: Option<(SecurityLevel, KeyId)>,
     ) -> Result<Frame, &'static mut [u8]>;
 
+    /// Prepares a mutable buffer slice as an 802.15.4 MAC command frame. This
+    /// is identical to `prepare_data_frame`, except that the command
+    /// identifier `command_id` is written as the first byte of the payload.
+    /// Any command content can then be appended to the returned Frame.
+    fn prepare_command_frame(
+        &self,
+        buf: &'static mut [u8],
+        dst_pan: PanID,
+        dst_addr: MacAddress,
+        src_pan: PanID,
+        src_addr: MacAddress,
+        command_id: u8,
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<Frame, &'static mut [u8]>;
+
+    /// Prepares a mutable buffer slice as an 802.15.4 beacon request command
+    /// frame, which is broadcast to all PANs on the current channel to ask
+    /// their coordinators to send a beacon. The frame has no further payload.
+    fn prepare_beacon_request_frame(
+        &self,
+        buf: &'static mut [u8],
+    ) -> Result<Frame, &'static mut [u8]>;
+
+    /// Prepares a mutable buffer slice as an 802.15.4 beacon frame sent by the
+    /// coordinator of PAN `src_pan`. The superframe specification, GTS and
+    /// pending address fields are written at the start of the payload, and
+    /// the beacon payload can then be appended to the returned Frame.
+    fn prepare_beacon_frame(
+        &self,
+        buf: &'static mut [u8],
+        src_pan: PanID,
+        src_addr: MacAddress,
+        superframe_spec: SuperframeSpec,
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<Frame, &'static mut [u8]>;
+
     /// Transmits a frame that has been prepared by the above process. If the
     /// transmission process fails, the buffer inside the frame is returned so
     /// that it can be re-used.
@@ -110,5 +170,13 @@ pub trait RxClient {
     /// `buf`, so that the payload of the frame is contained in
     /// `buf[data_offset..data_offset + data_len]`.
     /// - `data_len`: Length of the data payload
-    fn receive<'a>(&self, buf: &'a [u8], header: Header<'a>, data_offset: usize, data_len: usize);
+    /// - `quality`: The link quality the frame was received with
+    fn receive<'a>(
+        &self,
+        buf: &'a [u8],
+        header: Header<'a>,
+        data_offset: usize,
+        data_len: usize,
+        quality: LinkQuality,
+    );
 }
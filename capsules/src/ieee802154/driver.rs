@@ -2,14 +2,26 @@
 //!
 //! Implements a userspace interface for sending and receiving IEEE 802.15.4
 //! frames. Also provides a minimal list-based interface for managing keys and
-//! known link neighbors, which is needed for 802.15.4 security.
+//! known link neighbors, which is needed for 802.15.4 security. If a scanner is
+//! set, channel scans can also be used to find PANs to join, and to respond to
+//! beacon requests from devices looking for this device's PAN. If a
+//! key-value store is set, the addresses and PAN ID are saved in it whenever
+//! the configuration is committed, and can be loaded from it at boot. The
+//! driver is also the `FrameCounterClient` of the `Framer`: the outgoing
+//! frame counter is saved whenever more counter values are reserved, and is
+//! restored first when the configuration is loaded, so that CCM* nonces are
+//! not reused after a reboot.
 
-use crate::ieee802154::{device, framer};
-use crate::net::ieee802154::{AddressMode, Header, KeyId, MacAddress, PanID, SecurityLevel};
+use crate::ieee802154::{device, framer, scan};
+use crate::net::ieee802154::{
+    AddressMode, Header, KeyId, MacAddress, PanID, SecurityLevel, SuperframeSpec,
+};
 use crate::net::stream::{decode_bytes, decode_u8, encode_bytes, encode_u8, SResult};
 use core::cell::Cell;
 use core::cmp::min;
 use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::hil::kv_store::{KVStore, KVStoreClient};
+use kernel::hil::radio::LinkQuality;
 use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 
 const MAX_NEIGHBORS: usize = 4;
@@ -18,10 +30,72 @@ const MAX_KEYS: usize = 4;
 /// Syscall number
 pub const DRIVER_NUM: usize = 0x30001;
 
+/// Configuration kept in the key-value store, in the order it is loaded and
+/// saved. The outgoing frame counter is saved on its own, whenever the
+/// `Framer` reserves more counter values.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum ConfigKey {
+    FrameCounter,
+    ShortAddress,
+    LongAddress,
+    Pan,
+}
+
+impl ConfigKey {
+    fn key(&self) -> &'static [u8] {
+        match *self {
+            ConfigKey::FrameCounter => b"ieee802154.frame_counter",
+            ConfigKey::ShortAddress => b"ieee802154.short_addr",
+            ConfigKey::LongAddress => b"ieee802154.long_addr",
+            ConfigKey::Pan => b"ieee802154.pan",
+        }
+    }
+
+    fn length(&self) -> usize {
+        match *self {
+            ConfigKey::ShortAddress | ConfigKey::Pan => 2,
+            ConfigKey::FrameCounter => 4,
+            ConfigKey::LongAddress => 8,
+        }
+    }
+
+    fn next(&self) -> Option<ConfigKey> {
+        match *self {
+            ConfigKey::FrameCounter => Some(ConfigKey::ShortAddress),
+            ConfigKey::ShortAddress => Some(ConfigKey::LongAddress),
+            ConfigKey::LongAddress => Some(ConfigKey::Pan),
+            ConfigKey::Pan => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum ConfigOp {
+    Load(ConfigKey),
+    Save(ConfigKey),
+}
+
+impl ConfigOp {
+    // The operation on the next value, if any. Saving the frame counter does
+    // not save the rest of the configuration.
+    fn next(&self) -> Option<ConfigOp> {
+        match *self {
+            ConfigOp::Load(key) => key.next().map(ConfigOp::Load),
+            ConfigOp::Save(ConfigKey::FrameCounter) => None,
+            ConfigOp::Save(key) => key.next().map(ConfigOp::Save),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 struct DeviceDescriptor {
     short_addr: u16,
     long_addr: [u8; 8],
+    // The lowest frame counter accepted in the next secured frame from this
+    // neighbor
+    frame_counter: u32,
+    // The link quality of the last frame received from this neighbor
+    link_quality: Option<LinkQuality>,
 }
 
 impl Default for DeviceDescriptor {
@@ -29,6 +103,8 @@ impl Default for DeviceDescriptor {
         DeviceDescriptor {
             short_addr: 0,
             long_addr: [0; 8],
+            frame_counter: 0,
+            link_quality: None,
         }
     }
 }
@@ -147,6 +223,7 @@ impl KeyDescriptor {
 pub struct App {
     rx_callback: Option<Callback>,
     tx_callback: Option<Callback>,
+    scan_callback: Option<Callback>,
     app_read: Option<AppSlice<Shared, u8>>,
     app_write: Option<AppSlice<Shared, u8>>,
     app_cfg: Option<AppSlice<Shared, u8>>,
@@ -158,6 +235,7 @@ impl Default for App {
         App {
             rx_callback: None,
             tx_callback: None,
+            scan_callback: None,
             app_read: None,
             app_write: None,
             app_cfg: None,
@@ -189,6 +267,30 @@ pub struct RadioDriver<'a> {
 
     /// Buffer that stores the IEEE 802.15.4 frame to be transmitted.
     kernel_tx: TakeCell<'static, [u8]>,
+
+    /// Channel scanner, if scanning is supported.
+    scanner: OptionalCell<&'a scan::Scan<'a>>,
+    /// ID of app whose scan is in progress.
+    scan_app: OptionalCell<AppId>,
+
+    /// Link quality of the last received frame.
+    last_quality: OptionalCell<LinkQuality>,
+
+    /// Store for the addresses, PAN ID and outgoing frame counter, if they
+    /// are persistent.
+    config_store: OptionalCell<&'a KVStore>,
+    /// Buffer for the values exchanged with the store.
+    config_buf: TakeCell<'static, [u8]>,
+    /// Value being loaded or saved.
+    config_op: OptionalCell<ConfigOp>,
+    /// Whether the configuration was committed again while it was being
+    /// saved.
+    config_dirty: Cell<bool>,
+    /// Highest outgoing frame counter reserved by the `Framer` or loaded
+    /// from the store, which is the value saved in the store.
+    frame_counter_reserved: Cell<u32>,
+    /// Whether the frame counter was reserved again while the store was busy.
+    frame_counter_dirty: Cell<bool>,
 }
 
 impl RadioDriver<'a> {
@@ -206,6 +308,125 @@ impl RadioDriver<'a> {
             apps: grant,
             current_app: OptionalCell::empty(),
             kernel_tx: TakeCell::new(kernel_tx),
+            scanner: OptionalCell::empty(),
+            scan_app: OptionalCell::empty(),
+            last_quality: OptionalCell::empty(),
+            config_store: OptionalCell::empty(),
+            config_buf: TakeCell::empty(),
+            config_op: OptionalCell::empty(),
+            config_dirty: Cell::new(false),
+            frame_counter_reserved: Cell::new(0),
+            frame_counter_dirty: Cell::new(false),
+        }
+    }
+
+    pub fn set_scanner(&self, scanner: &'a scan::Scan<'a>) {
+        self.scanner.set(scanner);
+    }
+
+    /// Keep the addresses, PAN ID and outgoing frame counter in `store`,
+    /// which must use this driver as its client. `buffer` must hold at least
+    /// 8 bytes.
+    pub fn set_config_store(&self, store: &'a KVStore, buffer: &'static mut [u8]) {
+        self.config_store.set(store);
+        self.config_buf.replace(buffer);
+    }
+
+    /// Load the addresses and PAN ID saved in the store, replacing those that
+    /// are set, and commit them. Values that were never saved are left
+    /// unchanged. The outgoing frame counter is loaded first, and only ever
+    /// moves forward.
+    pub fn load_config(&self) -> ReturnCode {
+        if self.config_op.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.start_config_op(ConfigOp::Load(ConfigKey::FrameCounter))
+    }
+
+    // Save the configuration, or save it again once the current save is done.
+    fn save_config(&self) {
+        if self.config_store.is_none() {
+            return;
+        }
+        if self.config_op.is_some() {
+            self.config_dirty.set(true);
+            return;
+        }
+        self.config_dirty.set(false);
+        self.start_config_op(ConfigOp::Save(ConfigKey::ShortAddress));
+    }
+
+    // Save the reserved frame counter, or save it once the current operation
+    // is done.
+    fn save_frame_counter(&self) {
+        if self.config_store.is_none() {
+            return;
+        }
+        if self.config_op.is_some() {
+            self.frame_counter_dirty.set(true);
+            return;
+        }
+        self.frame_counter_dirty.set(false);
+        self.start_config_op(ConfigOp::Save(ConfigKey::FrameCounter));
+    }
+
+    fn start_config_op(&self, op: ConfigOp) -> ReturnCode {
+        let store = match self.config_store.map(|store| *store) {
+            Some(store) => store,
+            None => return ReturnCode::ENODEVICE,
+        };
+        let buf = match self.config_buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::ERESERVE,
+        };
+        let (result, buf) = match op {
+            ConfigOp::Load(key) => store.get(key.key(), buf),
+            ConfigOp::Save(key) => {
+                match key {
+                    ConfigKey::FrameCounter => {
+                        buf[..4].copy_from_slice(&self.frame_counter_reserved.get().to_le_bytes())
+                    }
+                    ConfigKey::ShortAddress => {
+                        buf[..2].copy_from_slice(&self.mac.get_address().to_le_bytes())
+                    }
+                    ConfigKey::LongAddress => {
+                        buf[..8].copy_from_slice(&self.mac.get_address_long())
+                    }
+                    ConfigKey::Pan => buf[..2].copy_from_slice(&self.mac.get_pan().to_le_bytes()),
+                }
+                store.set(key.key(), buf, key.length())
+            }
+        };
+        buf.map(|buf| self.config_buf.replace(buf));
+        if result == ReturnCode::SUCCESS {
+            self.config_op.set(op);
+        }
+        result
+    }
+
+    // Move on to the next value once one has been loaded or saved.
+    fn config_op_done(&self, buf: Option<&'static mut [u8]>) {
+        buf.map(|buf| self.config_buf.replace(buf));
+        let next = self.config_op.take().and_then(|op| match (op, op.next()) {
+            (ConfigOp::Load(_), None) => {
+                self.mac.config_commit();
+                None
+            }
+            (_, next) => next,
+        });
+        match next {
+            Some(op) => {
+                if self.start_config_op(op) != ReturnCode::SUCCESS {
+                    self.config_dirty.set(false);
+                }
+            }
+            None => {
+                if self.frame_counter_dirty.get() {
+                    self.save_frame_counter();
+                } else if self.config_dirty.get() {
+                    self.save_config();
+                }
+            }
         }
     }
 
@@ -218,9 +439,10 @@ impl RadioDriver<'a> {
     fn add_neighbor(&self, new_neighbor: DeviceDescriptor) -> Option<usize> {
         self.neighbors.and_then(|neighbors| {
             let num_neighbors = self.num_neighbors.get();
-            let position = neighbors[..num_neighbors]
-                .iter()
-                .position(|neighbor| *neighbor == new_neighbor);
+            let position = neighbors[..num_neighbors].iter().position(|neighbor| {
+                neighbor.short_addr == new_neighbor.short_addr
+                    && neighbor.long_addr == new_neighbor.long_addr
+            });
             match position {
                 Some(index) => Some(index),
                 None => {
@@ -265,6 +487,21 @@ impl RadioDriver<'a> {
         }
     }
 
+    /// Records the link quality of a frame received from `addr` for the
+    /// matching neighbor, if there is one.
+    fn update_neighbor_quality(&self, addr: MacAddress, quality: LinkQuality) {
+        let num_neighbors = self.num_neighbors.get();
+        self.neighbors.map(|neighbors| {
+            neighbors[..num_neighbors]
+                .iter_mut()
+                .filter(|neighbor| match addr {
+                    MacAddress::Short(addr) => addr == neighbor.short_addr,
+                    MacAddress::Long(addr) => addr == neighbor.long_addr,
+                })
+                .for_each(|neighbor| neighbor.link_quality = Some(quality));
+        });
+    }
+
     // Key management functions
 
     /// Add a new key to the end of the list if there is still space
@@ -507,6 +744,40 @@ impl framer::DeviceProcedure for RadioDriver<'a> {
                 .map(|neighbor| neighbor.long_addr)
         })
     }
+
+    /// Gets the lowest frame counter accepted from the neighbor with the given
+    /// long address. If no such neighbor exists, returns `None`.
+    fn lookup_frame_counter(&self, addr_long: [u8; 8]) -> Option<u32> {
+        self.neighbors.and_then(|neighbors| {
+            neighbors[..self.num_neighbors.get()]
+                .iter()
+                .find(|neighbor| neighbor.long_addr == addr_long)
+                .map(|neighbor| neighbor.frame_counter)
+        })
+    }
+
+    /// Raises the lowest frame counter accepted from the neighbor with the
+    /// given long address, if such a neighbor exists.
+    fn update_frame_counter(&self, addr_long: [u8; 8], frame_counter: u32) {
+        let num_neighbors = self.num_neighbors.get();
+        self.neighbors.map(|neighbors| {
+            neighbors[..num_neighbors]
+                .iter_mut()
+                .filter(|neighbor| neighbor.long_addr == addr_long)
+                .for_each(|neighbor| neighbor.frame_counter = frame_counter);
+        });
+    }
+}
+
+impl framer::FrameCounterClient for RadioDriver<'a> {
+    /// Saves the reserved frame counter, so that it can be restored by
+    /// `load_config` after a reboot.
+    fn frame_counter_reserved(&self, frame_counter: u32) {
+        if frame_counter > self.frame_counter_reserved.get() {
+            self.frame_counter_reserved.set(frame_counter);
+        }
+        self.save_frame_counter();
+    }
 }
 
 impl framer::KeyProcedure for RadioDriver<'a> {
@@ -528,7 +799,8 @@ impl Driver for RadioDriver<'a> {
     ///
     /// ### `allow_num`
     ///
-    /// - `0`: Read buffer. Will contain the received frame.
+    /// - `0`: Read buffer. Will contain the received frame, followed by its LQI
+    ///        and RSSI (in dBm, signed) if the buffer is large enough.
     /// - `1`: Write buffer. Contains the frame payload to be transmitted.
     /// - `2`: Config buffer. Used to contain miscellaneous data associated with
     ///        some commands because the system call parameters / return codes are
@@ -559,6 +831,7 @@ impl Driver for RadioDriver<'a> {
     ///
     /// - `0`: Setup callback for when frame is received.
     /// - `1`: Setup callback for when frame is transmitted.
+    /// - `2`: Setup callback for when a channel scan is done.
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -574,6 +847,10 @@ impl Driver for RadioDriver<'a> {
                 app.tx_callback = callback;
                 ReturnCode::SUCCESS
             }),
+            2 => self.do_with_app(app_id, |app| {
+                app.scan_callback = callback;
+                ReturnCode::SUCCESS
+            }),
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -627,7 +904,44 @@ impl Driver for RadioDriver<'a> {
     ///                      9 bytes: the key ID (might not use all bytes) +
     ///                      16 bytes: the key.
     /// - `25`: Remove the key at an index.
-    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+    /// - `26`: Transmit a frame to the given short address.
+    ///        app_cfg (in): 1 byte: the security level +
+    ///                      1 byte: the key ID mode +
+    ///                      9 bytes: the key ID (might not use all bytes).
+    /// - `27`: Start a channel scan. The low byte of the first argument is the
+    ///         scan type (0: energy detection, 1: active, 2: passive) and the
+    ///         next byte is the scan duration. The second argument is the mask
+    ///         of channels to scan, where bit `n` selects channel `n`.
+    /// - `28`: Get the PAN descriptor at an index found by the last active or
+    ///         passive scan.
+    ///        app_cfg (out): 1 byte: the channel +
+    ///                       2 bytes: the coordinator PAN ID +
+    ///                       1 byte: the coordinator address mode +
+    ///                       8 bytes: the coordinator address (a short
+    ///                                address uses the first 2 bytes) +
+    ///                       2 bytes: the superframe specification.
+    /// - `29`: Get the energy level of a channel measured by the last energy
+    ///         detection scan.
+    /// - `30`: Respond to beacon requests as the coordinator of our PAN.
+    ///         0: disabled, 1: enabled, 2: enabled and permitting association.
+    ///
+    /// Link qualities are returned as the LQI in the low byte and the RSSI (in
+    /// dBm, signed) in the next byte.
+    ///
+    /// - `31`: Get the link quality of the last received frame.
+    /// - `32`: Get the link quality of the last frame received from the
+    ///         neighbor at an index.
+    /// - `33`: Get the link quality of the last beacon from the coordinator of
+    ///         the PAN descriptor at an index.
+    /// - `34`: Set promiscuous mode, receiving frames regardless of their
+    ///         destination. 0: disabled, 1: enabled.
+    /// - `35`: Get whether promiscuous mode is enabled.
+    /// - `36`: Set automatic acknowledgement of received frames. 0: disabled,
+    ///         1: enabled.
+    /// - `37`: Get whether automatic acknowledgement is enabled.
+    ///
+    /// Changes made with commands 34 and 36 take effect after command 7.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
         match command_num {
             0 => ReturnCode::SUCCESS,
             1 => {
@@ -651,12 +965,12 @@ impl Driver for RadioDriver<'a> {
                 self.mac.set_pan(arg1 as u16);
                 ReturnCode::SUCCESS
             }
-            // XXX: Setting channel DEPRECATED by MAC layer channel control
-            5 => ReturnCode::ENOSUPPORT,
+            5 => self.mac.set_channel(arg1 as u8),
             // XXX: Setting tx power DEPRECATED by MAC layer tx power control
             6 => ReturnCode::ENOSUPPORT,
             7 => {
                 self.mac.config_commit();
+                self.save_config();
                 ReturnCode::SUCCESS
             }
             8 => {
@@ -677,8 +991,13 @@ impl Driver for RadioDriver<'a> {
                     value: (pan as usize) + 1,
                 }
             }
-            // XXX: Getting channel DEPRECATED by MAC layer channel control
-            11 => ReturnCode::ENOSUPPORT,
+            11 => {
+                // Guarantee that the channel is positive by adding 1
+                let channel = self.mac.get_channel();
+                ReturnCode::SuccessWithValue {
+                    value: (channel as usize) + 1,
+                }
+            }
             // XXX: Getting tx power DEPRECATED by MAC layer tx power control
             12 => ReturnCode::ENOSUPPORT,
             13 => {
@@ -791,6 +1110,74 @@ impl Driver for RadioDriver<'a> {
                     self.do_next_tx_sync(appid)
                 })
             }
+            27 => self.scanner.map_or(ReturnCode::ENOSUPPORT, |scanner| {
+                if self.scan_app.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                let scan_type = match scan::ScanType::from_usize(arg1 & 0xff) {
+                    Some(scan_type) => scan_type,
+                    None => return ReturnCode::EINVAL,
+                };
+                let duration = ((arg1 >> 8) & 0xff) as u8;
+                let result = scanner.scan(scan_type, arg2 as u32, duration);
+                if result == ReturnCode::SUCCESS {
+                    self.scan_app.set(appid);
+                }
+                result
+            }),
+            28 => self.do_with_cfg_mut(appid, 14, |cfg| {
+                self.scanner
+                    .and_then(|scanner| scanner.get_pan_descriptor(arg1))
+                    .map_or(ReturnCode::EINVAL, |pan| {
+                        encode_pan_descriptor(&pan, cfg);
+                        ReturnCode::SUCCESS
+                    })
+            }),
+            29 => self
+                .scanner
+                .and_then(|scanner| scanner.get_energy_level(arg1 as u8))
+                .map_or(ReturnCode::EINVAL, |level| {
+                    // Guarantee that it is positive by adding 1
+                    ReturnCode::SuccessWithValue {
+                        value: (level as usize) + 1,
+                    }
+                }),
+            30 => self.scanner.map_or(ReturnCode::ENOSUPPORT, |scanner| {
+                match arg1 {
+                    0 => scanner.set_beacon_response(None),
+                    1 | 2 => scanner
+                        .set_beacon_response(Some(SuperframeSpec::nonbeacon(true, arg1 == 2))),
+                    _ => return ReturnCode::EINVAL,
+                }
+                ReturnCode::SUCCESS
+            }),
+            31 => self
+                .last_quality
+                .map_or(ReturnCode::EINVAL, |quality| encode_link_quality(quality)),
+            32 => self
+                .get_neighbor(arg1)
+                .and_then(|neighbor| neighbor.link_quality)
+                .map_or(ReturnCode::EINVAL, |quality| encode_link_quality(&quality)),
+            33 => self
+                .scanner
+                .and_then(|scanner| scanner.get_pan_descriptor(arg1))
+                .map_or(ReturnCode::EINVAL, |pan| {
+                    encode_link_quality(&pan.link_quality)
+                }),
+            34 => match arg1 {
+                0 | 1 => self.mac.set_promiscuous(arg1 == 1),
+                _ => ReturnCode::EINVAL,
+            },
+            35 => ReturnCode::SuccessWithValue {
+                value: self.mac.get_promiscuous() as usize,
+            },
+            36 => match arg1 {
+                0 | 1 => self.mac.set_auto_ack(arg1 == 1),
+                _ => ReturnCode::EINVAL,
+            },
+            37 => ReturnCode::SuccessWithValue {
+                value: self.mac.get_auto_ack() as usize,
+            },
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -810,6 +1197,81 @@ impl device::TxClient for RadioDriver<'a> {
     }
 }
 
+impl scan::ScanClient for RadioDriver<'a> {
+    fn scan_done(&self, scan_type: scan::ScanType, result: ReturnCode, num_results: usize) {
+        self.scan_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.scan_callback
+                    .take()
+                    .map(|mut cb| cb.schedule(result.into(), scan_type as usize, num_results));
+            });
+        });
+    }
+}
+
+impl KVStoreClient for RadioDriver<'a> {
+    fn get_complete(&self, value: &'static mut [u8], length: usize, result: ReturnCode) {
+        if let Some(ConfigOp::Load(key)) = self.config_op.map(|op| *op) {
+            if result == ReturnCode::SUCCESS && length == key.length() {
+                match key {
+                    ConfigKey::FrameCounter => {
+                        let frame_counter =
+                            u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+                        if frame_counter > self.mac.get_outgoing_frame_counter() {
+                            self.mac.set_outgoing_frame_counter(frame_counter);
+                        }
+                        if frame_counter > self.frame_counter_reserved.get() {
+                            self.frame_counter_reserved.set(frame_counter);
+                        }
+                    }
+                    ConfigKey::ShortAddress => self
+                        .mac
+                        .set_address(u16::from_le_bytes([value[0], value[1]])),
+                    ConfigKey::LongAddress => {
+                        let mut addr_long = [0u8; 8];
+                        addr_long.copy_from_slice(&value[..8]);
+                        self.mac.set_address_long(addr_long);
+                    }
+                    ConfigKey::Pan => self.mac.set_pan(u16::from_le_bytes([value[0], value[1]])),
+                }
+            }
+        }
+        self.config_op_done(Some(value));
+    }
+
+    fn set_complete(&self, value: &'static mut [u8], _result: ReturnCode) {
+        self.config_op_done(Some(value));
+    }
+
+    fn delete_complete(&self, _result: ReturnCode) {
+        self.config_op_done(None);
+    }
+}
+
+/// Encodes a PAN descriptor into the 14 bytes expected by the userland driver.
+fn encode_pan_descriptor(pan: &scan::PanDescriptor, cfg: &mut [u8]) {
+    cfg[0] = pan.channel;
+    cfg[1..3].copy_from_slice(&pan.coord_pan.to_le_bytes());
+    cfg[3] = AddressMode::from(&Some(pan.coord_addr)) as u8;
+    match pan.coord_addr {
+        MacAddress::Short(addr) => {
+            cfg[4..6].copy_from_slice(&addr.to_le_bytes());
+            cfg[6..12].copy_from_slice(&[0; 6]);
+        }
+        MacAddress::Long(addr) => cfg[4..12].copy_from_slice(&addr),
+    }
+    cfg[12..14].copy_from_slice(&pan.superframe_spec.to_u16().to_le_bytes());
+}
+
+/// Encodes a link quality into a single usize, guaranteed to be positive by
+/// adding 1.
+#[inline]
+fn encode_link_quality(quality: &LinkQuality) -> ReturnCode {
+    ReturnCode::SuccessWithValue {
+        value: (((quality.rssi as u8 as usize) << 8) | quality.lqi as usize) + 1,
+    }
+}
+
 /// Encode two PAN IDs into a single usize.
 #[inline]
 fn encode_pans(dst_pan: &Option<PanID>, src_pan: &Option<PanID>) -> usize {
@@ -827,16 +1289,34 @@ fn encode_address(addr: &Option<MacAddress>) -> usize {
 }
 
 impl device::RxClient for RadioDriver<'a> {
-    fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        data_offset: usize,
+        data_len: usize,
+        quality: LinkQuality,
+    ) {
+        self.last_quality.set(quality);
+        header
+            .src_addr
+            .map(|addr| self.update_neighbor_quality(addr, quality));
+
         self.apps.each(|app| {
             app.app_read.take().as_mut().map(|rbuf| {
                 let rbuf = rbuf.as_mut();
-                let len = min(rbuf.len(), data_offset + data_len);
+                let end = data_offset + data_len;
+                let len = min(rbuf.len(), end);
                 // Copy the entire frame over to userland, preceded by two
                 // bytes: the data offset and the data length.
                 rbuf[..len].copy_from_slice(&buf[..len]);
                 rbuf[0] = data_offset as u8;
                 rbuf[1] = data_len as u8;
+                // Followed by the link quality, if there is room
+                if rbuf.len() >= end + 2 {
+                    rbuf[end] = quality.lqi;
+                    rbuf[end + 1] = quality.rssi as u8;
+                }
 
                 // Encode useful parts of the header in 3 usizes
                 let pans = encode_pans(&header.dst_pan, &header.src_pan);
@@ -849,3 +1329,174 @@ impl device::RxClient for RadioDriver<'a> {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::ieee802154::device::MacDevice;
+    use crate::ieee802154::framer::{Framer, FRAME_COUNTER_RESERVATION};
+    use crate::ieee802154::mac::AwakeMac;
+    use crate::ieee802154::sim::{Medium, SimRadio};
+    use crate::kv_store;
+    use crate::storage_sim::{grant, leak, zeroed, Sim, SimFlash, SimPage, WriteMode, PAGE_SIZE};
+    use kernel::hil::flash::HasClient;
+    use kernel::hil::radio;
+    use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM};
+    use std::vec;
+
+    /// Frames are only prepared, so they are never encrypted.
+    struct NoCcm;
+
+    impl AES128CCM<'a> for NoCcm {
+        fn set_client(&'a self, _client: &'a CCMClient) {}
+
+        fn set_key(&self, _key: &[u8]) -> ReturnCode {
+            ReturnCode::ENOSUPPORT
+        }
+
+        fn set_nonce(&self, _nonce: &[u8]) -> ReturnCode {
+            ReturnCode::ENOSUPPORT
+        }
+
+        fn crypt(
+            &self,
+            buf: &'static mut [u8],
+            _a_off: usize,
+            _m_off: usize,
+            _m_len: usize,
+            _mic_len: usize,
+            _confidential: bool,
+            _encrypting: bool,
+        ) -> (ReturnCode, Option<&'static mut [u8]>) {
+            (ReturnCode::ENOSUPPORT, Some(buf))
+        }
+    }
+
+    const PAN: u16 = 0xabcd;
+    const KEY_ID: KeyId = KeyId::Index(1);
+
+    type SimFramer = Framer<'static, AwakeMac<'static, SimRadio<'static>>, NoCcm>;
+
+    /// Erased flash, kept across reboots of the stores booted on it.
+    fn sim() -> &'static Sim<SimFlash<'static>> {
+        Sim::flash(vec![0xff; PAGE_SIZE * 2], WriteMode::Nor)
+    }
+
+    /// A radio driver with a key, whose configuration is kept in a store on
+    /// the flash of `sim`, wired up as on imix. Its configuration has
+    /// started loading, but the load has not completed yet.
+    fn boot(
+        sim: &'static Sim<SimFlash<'static>>,
+    ) -> (&'static SimFramer, &'static RadioDriver<'static>) {
+        let medium = leak(Medium::new(1));
+        let awake_mac = leak(AwakeMac::new(leak(SimRadio::new(medium, 0))));
+        let framer: &'static SimFramer = leak(Framer::new(&*awake_mac, &NoCcm));
+
+        let store = leak(kv_store::KVStore::new(
+            sim.storage,
+            0,
+            leak(SimPage::new()),
+            leak(SimPage::new()),
+            zeroed(64),
+            grant(),
+            sim.deferred_caller,
+        ));
+        sim.storage.set_client(store);
+        store.set_deferred_call_handle(sim.deferred_caller.register(store).unwrap());
+
+        let driver = leak(RadioDriver::new(
+            framer,
+            grant(),
+            zeroed(radio::MAX_BUF_SIZE),
+        ));
+        framer.set_key_procedure(driver);
+        framer.set_frame_counter_client(driver);
+        driver.add_key(KeyDescriptor {
+            level: SecurityLevel::EncMic32,
+            key_id: KEY_ID,
+            key: [7; 16],
+        });
+        driver.set_config_store(store, zeroed(8));
+        KVStore::set_client(store, driver);
+        assert_eq!(driver.load_config(), ReturnCode::SUCCESS);
+        (framer, driver)
+    }
+
+    /// Prepares secured frames as if they were sent, using up their frame
+    /// counters.
+    fn send_secured(framer: &SimFramer, count: u32) {
+        for _ in 0..count {
+            let buf = zeroed(radio::MAX_BUF_SIZE);
+            let frame = framer.prepare_data_frame(
+                buf,
+                PAN,
+                MacAddress::Short(2),
+                PAN,
+                MacAddress::Short(1),
+                Some((SecurityLevel::EncMic32, KEY_ID)),
+            );
+            assert!(frame.is_ok());
+        }
+    }
+
+    #[test]
+    fn frame_counters_are_restored_after_a_restart() {
+        let sim = sim();
+        let (framer, _) = boot(sim);
+        sim.pump();
+        assert_eq!(framer.get_outgoing_frame_counter(), 0);
+        send_secured(framer, 3);
+        sim.pump();
+
+        // Counters up to the reservation may have been used before the
+        // restart, so the next boot starts after them
+        let (framer, _) = boot(sim);
+        sim.pump();
+        assert_eq!(
+            framer.get_outgoing_frame_counter(),
+            FRAME_COUNTER_RESERVATION
+        );
+        send_secured(framer, FRAME_COUNTER_RESERVATION + 1);
+        sim.pump();
+
+        let (framer, _) = boot(sim);
+        sim.pump();
+        assert_eq!(
+            framer.get_outgoing_frame_counter(),
+            3 * FRAME_COUNTER_RESERVATION
+        );
+    }
+
+    #[test]
+    fn frame_counters_reserved_while_loading_never_lower_the_saved_one() {
+        let sim = sim();
+        let (framer, _) = boot(sim);
+        sim.pump();
+        send_secured(framer, FRAME_COUNTER_RESERVATION + 1);
+        sim.pump();
+
+        // A frame sent before the load completes reserves counters from 0,
+        // which must not replace the saved reservation
+        let (framer, driver) = boot(sim);
+        send_secured(framer, 1);
+        sim.pump();
+        assert_eq!(
+            framer.get_outgoing_frame_counter(),
+            2 * FRAME_COUNTER_RESERVATION
+        );
+
+        // The addresses are loaded after the counter, as before
+        driver.mac.set_address(0x1234);
+        driver.save_config();
+        sim.pump();
+        let (framer, driver) = boot(sim);
+        sim.pump();
+        assert_eq!(
+            framer.get_outgoing_frame_counter(),
+            2 * FRAME_COUNTER_RESERVATION
+        );
+        assert_eq!(driver.mac.get_address(), 0x1234);
+    }
+}
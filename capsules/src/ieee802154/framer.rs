@@ -62,17 +62,26 @@
 //! mac_device.set_transmit_client(radio_capsule);
 //! mac_device.set_receive_client(radio_capsule);
 //! ```
-
-//
-// TODO: Encryption/decryption
-// TODO: Sending beacon frames
-// TODO: Channel scanning
-//
+//!
+//! Secured frames are protected with the AES-CCM* facility passed to the
+//! `Framer`. Keys are found through the `KeyProcedure`, and the extended
+//! address and frame counter of each neighbor through the `DeviceProcedure`,
+//! which rejects replayed frames. To avoid reusing outgoing frame counters
+//! across reboots, the value passed to `FrameCounterClient` must be stored
+//! and restored with `set_outgoing_frame_counter` at boot. The `RadioDriver`
+//! does so when it has a key-value store:
+//!
+//! ```rust
+//! mac_device.set_frame_counter_client(radio_capsule);
+//! radio_capsule.set_config_store(kv_store, &mut RADIO_CONFIG_BUF);
+//! radio_capsule.load_config();
+//! ```
 
 use crate::ieee802154::device::{MacDevice, RxClient, TxClient};
 use crate::ieee802154::mac::Mac;
 use crate::net::ieee802154::{
-    FrameType, FrameVersion, Header, KeyId, MacAddress, PanID, Security, SecurityLevel,
+    BeaconFields, FrameType, FrameVersion, Header, KeyId, MacAddress, MacCommand, PanID, Security,
+    SecurityLevel, SuperframeSpec, BROADCAST,
 };
 use crate::net::stream::SResult;
 use crate::net::stream::{encode_bytes, encode_u32, encode_u8};
@@ -114,6 +123,9 @@ struct FrameInfo {
 
     // Security level, key, and nonce
     security_params: Option<(SecurityLevel, [u8; 16], [u8; 13])>,
+    // The extended address of the source device and the frame counter of a
+    // received secured frame, recorded once the frame is authenticated
+    rx_frame_counter: Option<([u8; 8], u32)>,
 }
 
 impl Frame {
@@ -122,6 +134,12 @@ impl Frame {
         self.buf
     }
 
+    /// The length of the frame as it is transmitted, after any security
+    /// processing, not including the MAC footer
+    pub fn secured_length(&self) -> usize {
+        self.info.secured_length()
+    }
+
     /// Calculates how much more data this frame can hold
     pub fn remaining_data_capacity(&self) -> usize {
         self.buf.len() - radio::PSDU_OFFSET - radio::MFR_SIZE - self.info.secured_length()
@@ -156,20 +174,29 @@ impl FrameInfo {
     /// the CCM* authentication and encryption procedures which depends on the
     /// frame type and security levels. Returns the (offset, len) of the m data
     /// fields, not including the MIC. The a data is always the remaining prefix
-    /// of the header, so it can be determined implicitly.
-    #[allow(dead_code)]
-    fn ccm_encrypt_ranges(&self) -> (usize, usize) {
+    /// of the header, so it can be determined implicitly. `buf` should start
+    /// at the PSDU. Returns `None` if the frame is malformed.
+    fn ccm_encrypt_ranges(&self, buf: &[u8]) -> Option<(usize, usize)> {
+        let frame_len = self.unsecured_length();
+        if frame_len > buf.len() {
+            return None;
+        }
+
         // IEEE 802.15.4-2015: Table 9-1. Exceptions to Private Payload field
         // The boundary between open and private payload fields depends
         // on the type of frame.
         let private_payload_offset = match self.frame_type {
             FrameType::Beacon => {
-                // Beginning of beacon payload field
-                unimplemented!()
+                // Beginning of beacon payload field, after the superframe
+                // specification, GTS and pending address fields
+                let mac_payload = &buf[self.mac_payload_offset..frame_len];
+                let (off, _) = BeaconFields::decode(mac_payload).done()?;
+                self.mac_payload_offset + off
             }
             FrameType::MACCommand => {
-                // Beginning of MAC command content field
-                unimplemented!()
+                // Beginning of MAC command content field, after the
+                // command identifier
+                self.mac_payload_offset + 1
             }
             _ => {
                 // MAC payload field, which includes payload IEs
@@ -183,14 +210,13 @@ impl FrameInfo {
             .map_or(false, |(level, _, _)| level.encryption_needed());
         if !encryption_needed {
             // If only integrity is need, a data is the whole frame
-            (self.unsecured_length(), 0)
+            Some((frame_len, 0))
+        } else if private_payload_offset > frame_len {
+            None
         } else {
             // Otherwise, a data is the header and the open payload, and
             // m data is the private payload field
-            (
-                private_payload_offset,
-                self.unsecured_length() | private_payload_offset,
-            )
+            Some((private_payload_offset, frame_len - private_payload_offset))
         }
     }
 }
@@ -220,6 +246,11 @@ fn get_ccm_nonce(device_addr: &[u8; 8], frame_counter: u32, level: SecurityLevel
 /// - pads the m data to 16-byte blocks
 pub const CRYPT_BUF_SIZE: usize = radio::MAX_MTU + 3 * 16;
 
+/// Number of outgoing frame counter values reserved at a time. The
+/// `FrameCounterClient` is notified only once per reservation, which bounds
+/// how often the counter needs to be persisted.
+pub const FRAME_COUNTER_RESERVATION: u32 = 256;
+
 /// IEEE 802.15.4-2015, 9.2.2, KeyDescriptor lookup procedure.
 /// Trait to be implemented by an upper layer that manages the list of 802.15.4
 /// key descriptors. This trait interface enables the lookup procedure to be
@@ -242,6 +273,29 @@ pub trait DeviceProcedure {
     /// address is already long, a long address should be returned only if the
     /// given address matches a known DeviceDescriptor.
     fn lookup_addr_long(&self, addr: MacAddress) -> Option<([u8; 8])>;
+
+    /// Look up the FrameCounter of the DeviceDescriptor for the device with
+    /// the given extended address. This is the lowest frame counter that will
+    /// be accepted in the next secured frame from that device; frames with a
+    /// lower counter are rejected as replays.
+    fn lookup_frame_counter(&self, addr_long: [u8; 8]) -> Option<u32>;
+
+    /// Set the FrameCounter of the DeviceDescriptor for the device with the
+    /// given extended address. Called after a secured frame from that device
+    /// passes the incoming frame security procedure, with the frame counter
+    /// that follows the one in the frame.
+    fn update_frame_counter(&self, addr_long: [u8; 8], frame_counter: u32);
+}
+
+/// Trait to be implemented by an upper layer that persists the outgoing frame
+/// counter, so that frame counters are never reused with the same key after a
+/// reboot.
+pub trait FrameCounterClient {
+    /// Called when the `Framer` reserves more outgoing frame counter values.
+    /// No outgoing frame will use a counter of `frame_counter` or greater
+    /// until this is called again, so `frame_counter` is safe to restore with
+    /// `Framer::set_outgoing_frame_counter` after a reboot.
+    fn frame_counter_reserved(&self, frame_counter: u32);
 }
 
 /// This state enum describes the state of the transmission pipeline.
@@ -296,6 +350,13 @@ pub struct Framer<'a, M: Mac, A: AES128CCM<'a>> {
     aes_ccm: &'a A,
     data_sequence: Cell<u8>,
 
+    /// Frame counter for the next secured outgoing frame
+    frame_counter: Cell<u32>,
+    /// Outgoing frame counters below this value have been reserved and
+    /// reported to the frame counter client
+    frame_counter_reserved: Cell<u32>,
+    frame_counter_client: OptionalCell<&'a FrameCounterClient>,
+
     /// KeyDescriptor lookup procedure
     key_procedure: OptionalCell<&'a KeyProcedure>,
     /// DeviceDescriptor lookup procedure
@@ -312,6 +373,8 @@ pub struct Framer<'a, M: Mac, A: AES128CCM<'a>> {
     /// `None`, except when transitioning between states.
     rx_state: MapCell<RxState>,
     rx_client: OptionalCell<&'a RxClient>,
+    /// Link quality of the frame in the reception pipeline
+    rx_quality: Cell<radio::LinkQuality>,
 }
 
 impl<M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
@@ -320,12 +383,16 @@ impl<M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
             mac: mac,
             aes_ccm: aes_ccm,
             data_sequence: Cell::new(0),
+            frame_counter: Cell::new(0),
+            frame_counter_reserved: Cell::new(0),
+            frame_counter_client: OptionalCell::empty(),
             key_procedure: OptionalCell::empty(),
             device_procedure: OptionalCell::empty(),
             tx_state: MapCell::new(TxState::Idle),
             tx_client: OptionalCell::empty(),
             rx_state: MapCell::new(RxState::Idle),
             rx_client: OptionalCell::empty(),
+            rx_quality: Cell::new(radio::LinkQuality::UNKNOWN),
         }
     }
 
@@ -339,6 +406,118 @@ impl<M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
         self.device_procedure.set(device_procedure);
     }
 
+    /// Sets the client that persists the outgoing frame counter.
+    pub fn set_frame_counter_client(&self, client: &'a FrameCounterClient) {
+        self.frame_counter_client.set(client);
+    }
+
+    /// IEEE 802.15.4-2015, 9.2.1, steps b and d. Returns the frame counter
+    /// for a new secured outgoing frame and advances it, reserving more
+    /// counter values when needed. Returns `None` once the counter is
+    /// exhausted.
+    fn next_frame_counter(&self) -> Option<u32> {
+        let frame_counter = self.frame_counter.get();
+        if frame_counter == 0xffffffff {
+            return None;
+        }
+        if frame_counter >= self.frame_counter_reserved.get() {
+            let reserved = frame_counter.saturating_add(FRAME_COUNTER_RESERVATION);
+            self.frame_counter_reserved.set(reserved);
+            self.frame_counter_client
+                .map(|client| client.frame_counter_reserved(reserved));
+        }
+        self.frame_counter.set(frame_counter + 1);
+        Some(frame_counter)
+    }
+
+    /// Writes the MAC header of a frame of the given type into `buf`,
+    /// leaving it ready to have its payload appended.
+    fn prepare_frame(
+        &self,
+        frame_type: FrameType,
+        buf: &'static mut [u8],
+        dst: Option<(PanID, MacAddress)>,
+        src: Option<(PanID, MacAddress)>,
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<Frame, &'static mut [u8]> {
+        // IEEE 802.15.4-2015: 9.2.1, outgoing frame security
+        // Steps a-e of the security procedure are implemented here.
+
+        // TODO: For Thread, in the case of `KeyIdMode::Source4Index`, the source
+        // address should instead be some constant defined in their
+        // specification.
+        let src_addr_long = self.get_address_long();
+        let security_desc = match security_needed {
+            None | Some((SecurityLevel::None, _)) => None,
+            Some((level, key_id)) => {
+                // If security was requested, fail when desired key was not
+                // found or the frame counter is exhausted.
+                let key = match self.lookup_key(level, key_id) {
+                    Some(key) => key,
+                    None => {
+                        return Err(buf);
+                    }
+                };
+                let frame_counter = match self.next_frame_counter() {
+                    Some(frame_counter) => frame_counter,
+                    None => {
+                        return Err(buf);
+                    }
+                };
+                let nonce = get_ccm_nonce(&src_addr_long, frame_counter, level);
+                Some((
+                    Security {
+                        level: level,
+                        asn_in_nonce: false,
+                        frame_counter: Some(frame_counter),
+                        key_id: key_id,
+                    },
+                    key,
+                    nonce,
+                ))
+            }
+        };
+
+        // Construct MAC header
+        let security = security_desc.map(|(sec, _, _)| sec);
+        let mic_len = security.map_or(0, |sec| sec.level.mic_len());
+        let header = Header {
+            frame_type: frame_type,
+            /* TODO: determine this by looking at queue, and also set it in
+             * hardware so that ACKs set this flag to the right value. */
+            frame_pending: false,
+            // Unicast frames request acknowledgement
+            ack_requested: dst.map_or(false, |(_, addr)| addr != MacAddress::Short(BROADCAST)),
+            version: FrameVersion::V2006,
+            seq: Some(self.data_sequence.get()),
+            dst_pan: dst.map(|(pan, _)| pan),
+            dst_addr: dst.map(|(_, addr)| addr),
+            src_pan: src.map(|(pan, _)| pan),
+            src_addr: src.map(|(_, addr)| addr),
+            security: security,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        };
+
+        match header.encode(&mut buf[radio::PSDU_OFFSET..], true).done() {
+            Some((data_offset, mac_payload_offset)) => Ok(Frame {
+                buf: buf,
+                info: FrameInfo {
+                    frame_type: frame_type,
+                    mac_payload_offset: mac_payload_offset,
+                    data_offset: data_offset,
+                    data_len: 0,
+                    mic_len: mic_len,
+                    security_params: security_desc.map(|(sec, key, nonce)| (sec.level, key, nonce)),
+                    rx_frame_counter: None,
+                },
+            }),
+            None => Err(buf),
+        }
+    }
+
     /// Look up the key using the IEEE 802.15.4 KeyDescriptor lookup prodecure
     /// implemented elsewhere.
     fn lookup_key(&self, level: SecurityLevel, key_id: KeyId) -> Option<([u8; 16])> {
@@ -355,6 +534,13 @@ impl<M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
         })
     }
 
+    /// Look up the lowest frame counter accepted from a device using the IEEE
+    /// 802.15.4 DeviceDescriptor lookup prodecure implemented elsewhere.
+    fn lookup_frame_counter(&self, addr_long: [u8; 8]) -> Option<u32> {
+        self.device_procedure
+            .and_then(|device_procedure| device_procedure.lookup_frame_counter(addr_long))
+    }
+
     /// IEEE 802.15.4-2015, 9.2.1, outgoing frame security procedure
     /// Performs the first checks in the security procedure. The rest of the
     /// steps are performed as part of the transmission pipeline.
@@ -398,11 +584,16 @@ impl<M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
                 // exposing it to the user. At that time, the data payload field
                 // will not include the payload IEs.
                 let mic_len = header.security.map_or(0, |sec| sec.level.mic_len());
+                if frame_len < data_offset + mic_len {
+                    return None;
+                }
                 let data_len = frame_len - data_offset - mic_len;
                 if let Some(security) = header.security {
                     // IEEE 802.15.4-2015: 9.2.3, incoming frame security procedure
                     // for security-enabled headers
-                    if header.version == FrameVersion::V2003 {
+                    if header.version == FrameVersion::V2003
+                        || security.level == SecurityLevel::None
+                    {
                         None
                     } else {
                         // Step e: Lookup the key.
@@ -432,7 +623,15 @@ impl<M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
                                     // Counter error
                                     return None;
                                 }
-                                // TODO: Check frame counter against source device
+                                // Reject frames that do not advance the
+                                // counter of the source device, which are
+                                // either replayed or out of order
+                                match self.lookup_frame_counter(device_addr) {
+                                    Some(min_counter) if frame_counter >= min_counter => {}
+                                    _ => {
+                                        return None;
+                                    }
+                                }
                                 frame_counter
                             }
                             // TSCH mode, where ASN is used instead, not supported
@@ -451,12 +650,19 @@ impl<M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
                             data_len: data_len,
                             mic_len: mic_len,
                             security_params: Some((security.level, key, nonce)),
+                            rx_frame_counter: Some((device_addr, frame_counter)),
                         })
                     }
                 } else {
                     // No security needed, can yield the frame immediately
                     self.rx_client.map(|client| {
-                        client.receive(&buf, header, radio::PSDU_OFFSET + data_offset, data_len);
+                        client.receive(
+                            &buf,
+                            header,
+                            radio::PSDU_OFFSET + data_offset,
+                            data_len,
+                            self.rx_quality.get(),
+                        );
                     });
                     None
                 }
@@ -485,11 +691,13 @@ impl<M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
                                 (TxState::Idle, (ReturnCode::FAIL, Some(buf)))
                             }
                             Some((level, key, nonce)) => {
-                                let (m_off, m_len) = info.ccm_encrypt_ranges();
+                                let ranges = info.ccm_encrypt_ranges(&buf[radio::PSDU_OFFSET..]);
+                                let (m_off, m_len) = ranges.unwrap_or((0, 0));
                                 let (a_off, m_off) =
                                     (radio::PSDU_OFFSET, radio::PSDU_OFFSET + m_off);
 
-                                if self.aes_ccm.set_key(&key) != ReturnCode::SUCCESS
+                                if ranges.is_none()
+                                    || self.aes_ccm.set_key(&key) != ReturnCode::SUCCESS
                                     || self.aes_ccm.set_nonce(&nonce) != ReturnCode::SUCCESS
                                 {
                                     (TxState::Idle, (ReturnCode::FAIL, Some(buf)))
@@ -568,10 +776,12 @@ impl<M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
                             (RxState::Idle, Some(buf))
                         }
                         Some((level, key, nonce)) => {
-                            let (m_off, m_len) = info.ccm_encrypt_ranges();
+                            let ranges = info.ccm_encrypt_ranges(&buf[radio::PSDU_OFFSET..]);
+                            let (m_off, m_len) = ranges.unwrap_or((0, 0));
                             let (a_off, m_off) = (radio::PSDU_OFFSET, radio::PSDU_OFFSET + m_off);
 
-                            if self.aes_ccm.set_key(&key) != ReturnCode::SUCCESS
+                            if ranges.is_none()
+                                || self.aes_ccm.set_key(&key) != ReturnCode::SUCCESS
                                 || self.aes_ccm.set_nonce(&nonce) != ReturnCode::SUCCESS
                             {
                                 (RxState::Idle, Some(buf))
@@ -583,7 +793,7 @@ impl<M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
                                     m_len,
                                     info.mic_len,
                                     level.encryption_needed(),
-                                    true,
+                                    false,
                                 );
                                 match res {
                                     ReturnCode::SUCCESS => (RxState::Decrypting(info), None),
@@ -628,6 +838,7 @@ impl<M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
                                 header,
                                 radio::PSDU_OFFSET + data_offset,
                                 frame_len - data_offset,
+                                self.rx_quality.get(),
                             );
                         });
                     }
@@ -678,6 +889,39 @@ impl<M: Mac, A: AES128CCM<'a>> MacDevice<'a> for Framer<'a, M, A> {
         self.mac.set_pan(id)
     }
 
+    fn get_channel(&self) -> u8 {
+        self.mac.get_channel()
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        self.mac.set_channel(chan)
+    }
+
+    fn get_promiscuous(&self) -> bool {
+        self.mac.get_promiscuous()
+    }
+
+    fn set_promiscuous(&self, enable: bool) -> ReturnCode {
+        self.mac.set_promiscuous(enable)
+    }
+
+    fn get_auto_ack(&self) -> bool {
+        self.mac.get_auto_ack()
+    }
+
+    fn set_auto_ack(&self, enable: bool) -> ReturnCode {
+        self.mac.set_auto_ack(enable)
+    }
+
+    fn get_outgoing_frame_counter(&self) -> u32 {
+        self.frame_counter.get()
+    }
+
+    fn set_outgoing_frame_counter(&self, frame_counter: u32) {
+        self.frame_counter.set(frame_counter);
+        self.frame_counter_reserved.set(frame_counter);
+    }
+
     fn config_commit(&self) {
         self.mac.config_commit()
     }
@@ -695,72 +939,85 @@ impl<M: Mac, A: AES128CCM<'a>> MacDevice<'a> for Framer<'a, M, A> {
         src_addr: MacAddress,
         security_needed: Option<(SecurityLevel, KeyId)>,
     ) -> Result<Frame, &'static mut [u8]> {
-        // IEEE 802.15.4-2015: 9.2.1, outgoing frame security
-        // Steps a-e of the security procedure are implemented here.
+        self.prepare_frame(
+            FrameType::Data,
+            buf,
+            Some((dst_pan, dst_addr)),
+            Some((src_pan, src_addr)),
+            security_needed,
+        )
+    }
 
-        // TODO: For Thread, in the case of `KeyIdMode::Source4Index`, the source
-        // address should instead be some constant defined in their
-        // specification.
-        let src_addr_long = self.get_address_long();
-        let security_desc = security_needed.and_then(|(level, key_id)| {
-            self.lookup_key(level, key_id).map(|key| {
-                // TODO: lookup frame counter for device
-                let frame_counter = 0;
-                let nonce = get_ccm_nonce(&src_addr_long, frame_counter, level);
-                (
-                    Security {
-                        level: level,
-                        asn_in_nonce: false,
-                        frame_counter: Some(frame_counter),
-                        key_id: key_id,
-                    },
-                    key,
-                    nonce,
-                )
-            })
-        });
-        if security_needed.is_some() && security_desc.is_none() {
-            // If security was requested, fail when desired key was not found.
-            return Err(buf);
+    fn prepare_command_frame(
+        &self,
+        buf: &'static mut [u8],
+        dst_pan: PanID,
+        dst_addr: MacAddress,
+        src_pan: PanID,
+        src_addr: MacAddress,
+        command_id: u8,
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<Frame, &'static mut [u8]> {
+        let mut frame = self.prepare_frame(
+            FrameType::MACCommand,
+            buf,
+            Some((dst_pan, dst_addr)),
+            Some((src_pan, src_addr)),
+            security_needed,
+        )?;
+        // The command identifier is the first byte of the MAC payload
+        if frame.append_payload(&[command_id]) != ReturnCode::SUCCESS {
+            return Err(frame.into_buf());
         }
+        Ok(frame)
+    }
 
-        // Construct MAC header
-        let security = security_desc.map(|(sec, _, _)| sec);
-        let mic_len = security.map_or(0, |sec| sec.level.mic_len());
-        let header = Header {
-            frame_type: FrameType::Data,
-            /* TODO: determine this by looking at queue, and also set it in
-             * hardware so that ACKs set this flag to the right value. */
-            frame_pending: false,
-            // Unicast data frames request acknowledgement
-            ack_requested: true,
-            version: FrameVersion::V2006,
-            seq: Some(self.data_sequence.get()),
-            dst_pan: Some(dst_pan),
-            dst_addr: Some(dst_addr),
-            src_pan: Some(src_pan),
-            src_addr: Some(src_addr),
-            security: security,
-            header_ies: Default::default(),
-            header_ies_len: 0,
-            payload_ies: Default::default(),
-            payload_ies_len: 0,
-        };
+    fn prepare_beacon_request_frame(
+        &self,
+        buf: &'static mut [u8],
+    ) -> Result<Frame, &'static mut [u8]> {
+        // IEEE 802.15.4-2015: 7.5.8, the beacon request command is broadcast
+        // to all PANs and omits the source address
+        let mut frame = self.prepare_frame(
+            FrameType::MACCommand,
+            buf,
+            Some((BROADCAST, MacAddress::Short(BROADCAST))),
+            None,
+            None,
+        )?;
+        if frame.append_payload(&[MacCommand::BeaconRequest as u8]) != ReturnCode::SUCCESS {
+            return Err(frame.into_buf());
+        }
+        Ok(frame)
+    }
 
-        match header.encode(&mut buf[radio::PSDU_OFFSET..], true).done() {
-            Some((data_offset, mac_payload_offset)) => Ok(Frame {
-                buf: buf,
-                info: FrameInfo {
-                    frame_type: FrameType::Data,
-                    mac_payload_offset: mac_payload_offset,
-                    data_offset: data_offset,
-                    data_len: 0,
-                    mic_len: mic_len,
-                    security_params: security_desc.map(|(sec, key, nonce)| (sec.level, key, nonce)),
-                },
-            }),
-            None => Err(buf),
+    fn prepare_beacon_frame(
+        &self,
+        buf: &'static mut [u8],
+        src_pan: PanID,
+        src_addr: MacAddress,
+        superframe_spec: SuperframeSpec,
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<Frame, &'static mut [u8]> {
+        let mut frame = self.prepare_frame(
+            FrameType::Beacon,
+            buf,
+            None,
+            Some((src_pan, src_addr)),
+            security_needed,
+        )?;
+        let mut fields = [0u8; 4];
+        let result = match BeaconFields::new(superframe_spec)
+            .encode(&mut fields)
+            .done()
+        {
+            Some((len, _)) => frame.append_payload(&fields[..len]),
+            None => ReturnCode::FAIL,
+        };
+        if result != ReturnCode::SUCCESS {
+            return Err(frame.into_buf());
         }
+        Ok(frame)
     }
 
     fn transmit(&self, frame: Frame) -> (ReturnCode, Option<&'static mut [u8]>) {
@@ -795,7 +1052,14 @@ impl<M: Mac, A: AES128CCM<'a>> radio::TxClient for Framer<'a, M, A> {
 }
 
 impl<M: Mac, A: AES128CCM<'a>> radio::RxClient for Framer<'a, M, A> {
-    fn receive(&self, buf: &'static mut [u8], frame_len: usize, crc_valid: bool, _: ReturnCode) {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        quality: radio::LinkQuality,
+        crc_valid: bool,
+        _: ReturnCode,
+    ) {
         // Drop all frames with invalid CRC
         if !crc_valid {
             self.mac.set_receive_buffer(buf);
@@ -807,6 +1071,7 @@ impl<M: Mac, A: AES128CCM<'a>> radio::RxClient for Framer<'a, M, A> {
                 RxState::Idle => {
                     // We can start processing a new received frame only if
                     // the reception pipeline is free
+                    self.rx_quality.set(quality);
                     self.incoming_frame_security(buf, frame_len)
                 }
                 other_state => {
@@ -882,7 +1147,16 @@ impl<M: Mac, A: AES128CCM<'a>> CCMClient for Framer<'a, M, A> {
             self.rx_state.take().map(move |state| {
                 match state {
                     RxState::Decrypting(info) => {
-                        let next_state = if tag_is_valid {
+                        let next_state = if res == ReturnCode::SUCCESS && tag_is_valid {
+                            // IEEE 802.15.4-2015: 9.2.3, step m: the frame is
+                            // authentic, so frames from the source device must
+                            // now use a higher frame counter
+                            if let Some((addr_long, frame_counter)) = info.rx_frame_counter {
+                                self.device_procedure.map(|device_procedure| {
+                                    device_procedure
+                                        .update_frame_counter(addr_long, frame_counter + 1)
+                                });
+                            }
                             RxState::ReadyToYield(info, buf)
                         } else {
                             RxState::ReadyToReturn(buf)
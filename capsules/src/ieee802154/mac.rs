@@ -7,9 +7,12 @@
 //!
 //! AwakeMac provides a default implementation of such a layer, maintaining
 //! the underlying kernel::hil::radio::Radio powered at all times and passing
-//! through each frame for transmission.
+//! through each frame for transmission. CsmaMac (in `csma.rs`) additionally
+//! performs CSMA-CA, acknowledgements and retransmissions in software for
+//! radios that do not do so in hardware.
 
-use crate::net::ieee802154::{Header, MacAddress};
+use crate::net::ieee802154::{FrameType, Header, MacAddress, BROADCAST};
+use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
 use kernel::debug;
 use kernel::hil::radio;
@@ -43,6 +46,22 @@ pub trait Mac {
     /// Sets the 16-bit PAN id of the radio
     fn set_pan(&self, id: u16);
 
+    /// The 802.15.4 channel of the radio
+    fn get_channel(&self) -> u8;
+    /// Sets the 802.15.4 channel of the radio
+    fn set_channel(&self, chan: u8) -> ReturnCode;
+
+    /// Whether frames not addressed to this device are passed up
+    fn get_promiscuous(&self) -> bool;
+    /// Pass up all received frames rather than only those addressed to this
+    /// device. Returns ENOSUPPORT if the radio cannot receive all frames.
+    fn set_promiscuous(&self, enable: bool) -> ReturnCode;
+    /// Whether received frames requesting an acknowledgement are acknowledged
+    fn get_auto_ack(&self) -> bool;
+    /// Sets whether received frames requesting an acknowledgement are
+    /// acknowledged. Returns ENOSUPPORT if this cannot be changed.
+    fn set_auto_ack(&self, enable: bool) -> ReturnCode;
+
     /// Must be called after one or more calls to `set_*`. If
     /// `set_*` is called without calling `config_commit`, there is no guarantee
     /// that the underlying hardware configuration (addresses, pan ID) is in
@@ -62,6 +81,21 @@ pub trait Mac {
     ) -> (ReturnCode, Option<&'static mut [u8]>);
 }
 
+/// Whether a received frame, starting at the PSDU, should be passed up by a
+/// Mac layer over a radio in promiscuous mode: frames addressed to the radio's
+/// short or long address, broadcast frames and beacons, which have no
+/// destination.
+pub fn addressed_to_radio<R: radio::RadioConfig>(radio: &R, frame: &[u8]) -> bool {
+    match Header::decode(frame, false).done() {
+        Some((_, (header, _))) => match header.dst_addr {
+            Some(MacAddress::Short(addr)) => addr == radio.get_address() || addr == BROADCAST,
+            Some(MacAddress::Long(long_addr)) => long_addr == radio.get_address_long(),
+            None => header.frame_type == FrameType::Beacon,
+        },
+        None => false,
+    }
+}
+
 ///
 /// Default implementation of a Mac layer. Acts as a pass-through between a MacDevice
 /// implementation and the underlying radio::Radio device. Does not change the power
@@ -69,6 +103,7 @@ pub trait Mac {
 ///
 pub struct AwakeMac<'a, R: radio::Radio> {
     radio: &'a R,
+    promiscuous: Cell<bool>,
 
     tx_client: OptionalCell<&'static radio::TxClient>,
     rx_client: OptionalCell<&'static radio::RxClient>,
@@ -78,6 +113,7 @@ impl<R: radio::Radio> AwakeMac<'a, R> {
     pub fn new(radio: &'a R) -> AwakeMac<'a, R> {
         AwakeMac {
             radio: radio,
+            promiscuous: Cell::new(false),
             tx_client: OptionalCell::empty(),
             rx_client: OptionalCell::empty(),
         }
@@ -122,6 +158,37 @@ impl<R: radio::Radio> Mac for AwakeMac<'a, R> {
         self.radio.get_pan()
     }
 
+    fn get_channel(&self) -> u8 {
+        self.radio.get_channel()
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        self.radio.set_channel(chan)
+    }
+
+    fn get_promiscuous(&self) -> bool {
+        self.promiscuous.get()
+    }
+
+    fn set_promiscuous(&self, enable: bool) -> ReturnCode {
+        // Frames are also filtered in software, so the radio only has to
+        // support enabling promiscuous mode
+        let result = self.radio.set_promiscuous(enable);
+        if enable && result != ReturnCode::SUCCESS {
+            return result;
+        }
+        self.promiscuous.set(enable);
+        ReturnCode::SUCCESS
+    }
+
+    fn get_auto_ack(&self) -> bool {
+        self.radio.get_auto_ack()
+    }
+
+    fn set_auto_ack(&self, enable: bool) -> ReturnCode {
+        self.radio.set_auto_ack(enable)
+    }
+
     fn config_commit(&self) {
         self.radio.config_commit()
     }
@@ -160,24 +227,15 @@ impl<R: radio::Radio> radio::RxClient for AwakeMac<'a, R> {
         &self,
         buf: &'static mut [u8],
         frame_len: usize,
+        quality: radio::LinkQuality,
         crc_valid: bool,
         result: ReturnCode,
     ) {
-        // Filter packets by destination because radio is in promiscuous mode
-        let mut addr_match = false;
-        if let Some((_, (header, _))) = Header::decode(&buf[radio::PSDU_OFFSET..], false).done() {
-            if let Some(dst_addr) = header.dst_addr {
-                addr_match = match dst_addr {
-                    MacAddress::Short(addr) => addr == self.radio.get_address(),
-                    MacAddress::Long(long_addr) => long_addr == self.radio.get_address_long(),
-                };
-            }
-        }
-
-        if addr_match {
+        // Filter packets by destination because radio is in promiscuous mode.
+        if self.promiscuous.get() || addressed_to_radio(self.radio, &buf[radio::PSDU_OFFSET..]) {
             //debug!("[AwakeMAC] Rcvd a 15.4 frame addressed to this device");
             self.rx_client.map(move |c| {
-                c.receive(buf, frame_len, crc_valid, result);
+                c.receive(buf, frame_len, quality, crc_valid, result);
             });
         } else {
             debug!("[AwakeMAC] Received a packet, but not addressed to us");
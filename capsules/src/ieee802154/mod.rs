@@ -1,6 +1,10 @@
+pub mod csma;
 pub mod device;
 pub mod framer;
 pub mod mac;
+pub mod scan;
+pub mod sim;
+pub mod sniffer;
 pub mod virtual_mac;
 pub mod xmac;
 
@@ -0,0 +1,520 @@
+//! IEEE 802.15.4 channel scanning and beacon responses.
+//!
+//! `Scanner` implements the energy detection, active and passive channel
+//! scans of IEEE 802.15.4-2015 (6.3), which let a device measure the activity
+//! on each channel and discover the PANs around it before joining one. The
+//! scanner visits each requested channel in turn, staying on it for a time
+//! determined by the scan duration and timed with an alarm:
+//!
+//! - An energy detection scan records the peak energy measured on each
+//!   channel. This requires a radio that implements `RadioEnergyDetect`.
+//! - A passive scan listens for beacons.
+//! - An active scan first broadcasts a beacon request, to which the
+//!   coordinators of nonbeacon-enabled PANs respond with a beacon.
+//!
+//! Each beacon received during a passive or active scan is recorded as a
+//! `PanDescriptor`. When the scan is done, the original channel and PAN ID of
+//! the MAC device are restored and the client is told how many results there
+//! are; the results can then be read until the next scan starts.
+//!
+//! The `Scanner` can also act as the coordinator of a PAN: once a superframe
+//! specification has been set with `set_beacon_response`, it responds to
+//! beacon requests from other devices with a beacon for its own PAN, so that
+//! they can find and join it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let scan_mac = static_init!(
+//!     capsules::ieee802154::virtual_mac::MacUser<'static>,
+//!     capsules::ieee802154::virtual_mac::MacUser::new(mux_mac)
+//! );
+//! mux_mac.add_user(scan_mac);
+//! let scan_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let scanner = static_init!(
+//!     Scanner<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     Scanner::new(scan_mac, scan_alarm, &mut SCAN_BUF)
+//! );
+//! scan_mac.set_transmit_client(scanner);
+//! scan_mac.set_receive_client(scanner);
+//! scan_alarm.set_client(scanner);
+//! scanner.set_client(radio_driver);
+//! radio_driver.set_scanner(scanner);
+//! ```
+
+use crate::ieee802154::device::{MacDevice, RxClient, TxClient};
+use crate::net::ieee802154::{
+    BeaconFields, FrameType, Header, MacAddress, MacCommand, PanID, SuperframeSpec, BROADCAST,
+};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::hil::radio;
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::ReturnCode;
+
+/// The lowest channel of the 2.4 GHz O-QPSK PHY
+pub const MIN_CHANNEL: u8 = 11;
+/// The highest channel of the 2.4 GHz O-QPSK PHY
+pub const MAX_CHANNEL: u8 = 26;
+pub const NUM_CHANNELS: usize = (MAX_CHANNEL - MIN_CHANNEL + 1) as usize;
+/// Channel mask selecting every channel of the 2.4 GHz O-QPSK PHY, where bit
+/// `n` selects channel `n`.
+pub const ALL_CHANNELS: u32 = 0x07ff_f800;
+
+/// The largest scan duration accepted by `Scan::scan`
+pub const MAX_SCAN_DURATION: u8 = 14;
+/// The maximum number of PAN descriptors recorded by a single scan
+pub const MAX_PAN_DESCRIPTORS: usize = 8;
+/// The maximum length of the payload sent in beacons
+pub const MAX_BEACON_PAYLOAD_LEN: usize = 32;
+
+// aBaseSuperframeDuration, in symbols
+const BASE_SUPERFRAME_DURATION: u32 = 960;
+// The symbol period of the 2.4 GHz O-QPSK PHY, in microseconds
+const SYMBOL_PERIOD_US: u32 = 16;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ScanType {
+    EnergyDetect = 0,
+    Active = 1,
+    Passive = 2,
+}
+
+impl ScanType {
+    pub fn from_usize(scan_type: usize) -> Option<ScanType> {
+        match scan_type {
+            0 => Some(ScanType::EnergyDetect),
+            1 => Some(ScanType::Active),
+            2 => Some(ScanType::Passive),
+            _ => None,
+        }
+    }
+}
+
+/// IEEE 802.15.4-2015: 8.2.5.2, a PAN discovered by receiving a beacon from
+/// its coordinator
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct PanDescriptor {
+    pub channel: u8,
+    pub coord_pan: PanID,
+    pub coord_addr: MacAddress,
+    pub superframe_spec: SuperframeSpec,
+    /// Whether the beacon was secured
+    pub security_enabled: bool,
+    /// The link quality of the last beacon received from the coordinator
+    pub link_quality: radio::LinkQuality,
+}
+
+impl Default for PanDescriptor {
+    fn default() -> Self {
+        PanDescriptor {
+            channel: 0,
+            coord_pan: 0,
+            coord_addr: MacAddress::Short(0),
+            superframe_spec: Default::default(),
+            security_enabled: false,
+            link_quality: radio::LinkQuality::UNKNOWN,
+        }
+    }
+}
+
+pub trait ScanClient {
+    /// Called when a scan started with `Scan::scan` is done, with the number
+    /// of channels that have an energy level for an energy detection scan, or
+    /// the number of PAN descriptors recorded for an active or passive scan.
+    /// `result` is ENOMEM if the scan stopped early because no more PAN
+    /// descriptors could be recorded.
+    fn scan_done(&self, scan_type: ScanType, result: ReturnCode, num_results: usize);
+}
+
+/// The interface to a channel scanner exposed to other capsules.
+pub trait Scan<'a> {
+    fn set_client(&self, client: &'a ScanClient);
+
+    /// Starts a scan of the channels selected by the `channels` mask. Each
+    /// channel is scanned for `aBaseSuperframeDuration * (2^duration + 1)`
+    /// symbols, so `duration` must be at most `MAX_SCAN_DURATION`.
+    ///
+    /// Returns EBUSY if a scan is already in progress, EINVAL if the mask
+    /// selects no channels or channels outside of `ALL_CHANNELS`, and
+    /// ENOSUPPORT for an energy detection scan if the radio cannot measure
+    /// energy.
+    fn scan(&self, scan_type: ScanType, channels: u32, duration: u8) -> ReturnCode;
+
+    /// The PAN descriptor at `index` recorded by the last active or passive
+    /// scan.
+    fn get_pan_descriptor(&self, index: usize) -> Option<PanDescriptor>;
+
+    /// The peak energy measured on `channel` by the last energy detection
+    /// scan, if it was scanned.
+    fn get_energy_level(&self, channel: u8) -> Option<u8>;
+
+    /// Enables responding to beacon requests with a beacon carrying
+    /// `superframe_spec`, or disables beacon responses if `None`.
+    fn set_beacon_response(&self, superframe_spec: Option<SuperframeSpec>);
+}
+
+pub struct Scanner<'a, A: Alarm> {
+    mac: &'a MacDevice<'a>,
+    alarm: &'a A,
+    energy_detect: OptionalCell<&'a radio::RadioEnergyDetect>,
+    client: OptionalCell<&'a ScanClient>,
+    tx_buf: TakeCell<'static, [u8]>,
+
+    // The scan in progress, the channels left to scan, and the channel being
+    // scanned
+    scan_type: OptionalCell<ScanType>,
+    duration: Cell<u8>,
+    channels: Cell<u32>,
+    channel: Cell<u8>,
+    // The channel of the energy measurement in progress, if any
+    energy_channel: OptionalCell<u8>,
+    // The configuration to restore after the scan
+    saved_channel: Cell<u8>,
+    saved_pan: Cell<PanID>,
+
+    result: Cell<ReturnCode>,
+    pans: MapCell<[PanDescriptor; MAX_PAN_DESCRIPTORS]>,
+    num_pans: Cell<usize>,
+    energy: Cell<[Option<u8>; NUM_CHANNELS]>,
+
+    beacon_spec: OptionalCell<SuperframeSpec>,
+    beacon_payload: MapCell<[u8; MAX_BEACON_PAYLOAD_LEN]>,
+    beacon_payload_len: Cell<usize>,
+}
+
+impl<A: Alarm> Scanner<'a, A> {
+    pub fn new(mac: &'a MacDevice<'a>, alarm: &'a A, tx_buf: &'static mut [u8]) -> Scanner<'a, A> {
+        Scanner {
+            mac: mac,
+            alarm: alarm,
+            energy_detect: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+            tx_buf: TakeCell::new(tx_buf),
+            scan_type: OptionalCell::empty(),
+            duration: Cell::new(0),
+            channels: Cell::new(0),
+            channel: Cell::new(0),
+            energy_channel: OptionalCell::empty(),
+            saved_channel: Cell::new(0),
+            saved_pan: Cell::new(0),
+            result: Cell::new(ReturnCode::SUCCESS),
+            pans: MapCell::new(Default::default()),
+            num_pans: Cell::new(0),
+            energy: Cell::new([None; NUM_CHANNELS]),
+            beacon_spec: OptionalCell::empty(),
+            beacon_payload: MapCell::new([0; MAX_BEACON_PAYLOAD_LEN]),
+            beacon_payload_len: Cell::new(0),
+        }
+    }
+
+    /// Sets the radio used for energy detection scans. Without one, energy
+    /// detection scans are not supported.
+    pub fn set_energy_detect(&self, energy_detect: &'a radio::RadioEnergyDetect) {
+        self.energy_detect.set(energy_detect);
+    }
+
+    /// Sets the payload carried by the beacons sent in response to beacon
+    /// requests. Returns ESIZE if it is longer than `MAX_BEACON_PAYLOAD_LEN`.
+    pub fn set_beacon_payload(&self, payload: &[u8]) -> ReturnCode {
+        if payload.len() > MAX_BEACON_PAYLOAD_LEN {
+            return ReturnCode::ESIZE;
+        }
+        self.beacon_payload.map(|buf| {
+            buf[..payload.len()].copy_from_slice(payload);
+        });
+        self.beacon_payload_len.set(payload.len());
+        ReturnCode::SUCCESS
+    }
+
+    /// The number of alarm ticks spent on each channel for a scan duration
+    fn dwell_ticks(duration: u8) -> u32 {
+        let symbols = BASE_SUPERFRAME_DURATION * ((1 << duration) + 1);
+        let us = (symbols * SYMBOL_PERIOD_US) as u64;
+        (us * <A::Frequency>::frequency() as u64 / 1_000_000) as u32
+    }
+
+    /// Moves on to the next channel in the mask, or finishes the scan if
+    /// there is none left.
+    fn next_channel(&self) {
+        let channels = self.channels.get();
+        if channels == 0 {
+            self.finish();
+            return;
+        }
+        let channel = channels.trailing_zeros() as u8;
+        self.channels.set(channels & !(1 << channel));
+        self.channel.set(channel);
+
+        if self.mac.set_channel(channel) != ReturnCode::SUCCESS {
+            // Skip channels the radio does not support
+            self.next_channel();
+            return;
+        }
+        self.mac.config_commit();
+
+        match self.scan_type.unwrap_or(ScanType::Passive) {
+            ScanType::EnergyDetect => {
+                // If a measurement is still running on the previous channel,
+                // the next one is started when it completes.
+                if self.energy_channel.is_none() {
+                    self.start_energy_detect();
+                }
+            }
+            ScanType::Active => self.send_beacon_request(),
+            ScanType::Passive => {}
+        }
+
+        let ticks = Self::dwell_ticks(self.duration.get());
+        self.alarm.set_alarm(self.alarm.now().wrapping_add(ticks));
+    }
+
+    fn start_energy_detect(&self) {
+        self.energy_detect.map(|energy_detect| {
+            if energy_detect.energy_detect() == ReturnCode::SUCCESS {
+                self.energy_channel.set(self.channel.get());
+            }
+        });
+    }
+
+    /// Restores the configuration of the MAC device and reports the results
+    /// of the scan to the client.
+    fn finish(&self) {
+        self.scan_type.take().map(|scan_type| {
+            self.mac.set_channel(self.saved_channel.get());
+            self.mac.set_pan(self.saved_pan.get());
+            self.mac.config_commit();
+
+            let num_results = match scan_type {
+                ScanType::EnergyDetect => self
+                    .energy
+                    .get()
+                    .iter()
+                    .filter(|level| level.is_some())
+                    .count(),
+                _ => self.num_pans.get(),
+            };
+            self.client
+                .map(|client| client.scan_done(scan_type, self.result.get(), num_results));
+        });
+    }
+
+    fn send_beacon_request(&self) {
+        self.tx_buf.take().map(|buf| {
+            match self.mac.prepare_beacon_request_frame(buf) {
+                Ok(frame) => {
+                    let (_, buf) = self.mac.transmit(frame);
+                    buf.map(|buf| self.tx_buf.replace(buf));
+                }
+                Err(buf) => {
+                    self.tx_buf.replace(buf);
+                }
+            };
+        });
+    }
+
+    /// Sends a beacon for our own PAN in response to a beacon request.
+    fn send_beacon(&self) {
+        if self.scan_type.is_some() {
+            // We are not listening on our own channel
+            return;
+        }
+        let superframe_spec = match self.beacon_spec.map(|spec| *spec) {
+            Some(spec) => spec,
+            None => return,
+        };
+        let src_addr = match self.mac.get_address() {
+            // These short addresses mean that the long address is used
+            0xfffe | BROADCAST => MacAddress::Long(self.mac.get_address_long()),
+            addr => MacAddress::Short(addr),
+        };
+        self.tx_buf.take().map(|buf| {
+            match self.mac.prepare_beacon_frame(
+                buf,
+                self.mac.get_pan(),
+                src_addr,
+                superframe_spec,
+                None,
+            ) {
+                Ok(mut frame) => {
+                    let len = self.beacon_payload_len.get();
+                    self.beacon_payload
+                        .map(|payload| frame.append_payload(&payload[..len]));
+                    let (_, buf) = self.mac.transmit(frame);
+                    buf.map(|buf| self.tx_buf.replace(buf));
+                }
+                Err(buf) => {
+                    self.tx_buf.replace(buf);
+                }
+            };
+        });
+    }
+
+    /// Records the PAN advertised by a beacon received during a scan.
+    fn beacon_received(&self, header: &Header, payload: &[u8], quality: radio::LinkQuality) {
+        match self.scan_type.map(|scan_type| *scan_type) {
+            Some(ScanType::Active) | Some(ScanType::Passive) => {}
+            _ => return,
+        }
+        let fields = match BeaconFields::decode(payload).done() {
+            Some((_, fields)) => fields,
+            None => return,
+        };
+        let (coord_pan, coord_addr) = match (header.src_pan, header.src_addr) {
+            (Some(pan), Some(addr)) => (pan, addr),
+            _ => return,
+        };
+        let descriptor = PanDescriptor {
+            channel: self.channel.get(),
+            coord_pan: coord_pan,
+            coord_addr: coord_addr,
+            superframe_spec: fields.superframe_spec,
+            security_enabled: header.security.is_some(),
+            link_quality: quality,
+        };
+
+        let full = self.pans.map_or(false, |pans| {
+            let num_pans = self.num_pans.get();
+            let known = pans[..num_pans].iter_mut().find(|pan| {
+                pan.channel == descriptor.channel
+                    && pan.coord_pan == descriptor.coord_pan
+                    && pan.coord_addr == descriptor.coord_addr
+            });
+            if let Some(pan) = known {
+                pan.link_quality = descriptor.link_quality;
+                false
+            } else if num_pans == MAX_PAN_DESCRIPTORS {
+                true
+            } else {
+                pans[num_pans] = descriptor;
+                self.num_pans.set(num_pans + 1);
+                false
+            }
+        });
+        if full {
+            // End the scan early as there is no room for more results
+            self.result.set(ReturnCode::ENOMEM);
+            self.channels.set(0);
+            self.alarm.disable();
+            self.finish();
+        }
+    }
+}
+
+impl<A: Alarm> Scan<'a> for Scanner<'a, A> {
+    fn set_client(&self, client: &'a ScanClient) {
+        self.client.set(client);
+    }
+
+    fn scan(&self, scan_type: ScanType, channels: u32, duration: u8) -> ReturnCode {
+        if self.scan_type.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if channels == 0 || channels & !ALL_CHANNELS != 0 || duration > MAX_SCAN_DURATION {
+            return ReturnCode::EINVAL;
+        }
+        if scan_type == ScanType::EnergyDetect && self.energy_detect.is_none() {
+            return ReturnCode::ENOSUPPORT;
+        }
+
+        self.result.set(ReturnCode::SUCCESS);
+        self.num_pans.set(0);
+        self.energy.set([None; NUM_CHANNELS]);
+
+        // Listen to beacons from every PAN while scanning
+        self.saved_channel.set(self.mac.get_channel());
+        self.saved_pan.set(self.mac.get_pan());
+        self.mac.set_pan(BROADCAST);
+
+        self.scan_type.set(scan_type);
+        self.duration.set(duration);
+        self.channels.set(channels);
+        self.next_channel();
+        ReturnCode::SUCCESS
+    }
+
+    fn get_pan_descriptor(&self, index: usize) -> Option<PanDescriptor> {
+        if index < self.num_pans.get() {
+            self.pans.map(|pans| pans[index])
+        } else {
+            None
+        }
+    }
+
+    fn get_energy_level(&self, channel: u8) -> Option<u8> {
+        if channel < MIN_CHANNEL || channel > MAX_CHANNEL {
+            return None;
+        }
+        self.energy.get()[(channel - MIN_CHANNEL) as usize]
+    }
+
+    fn set_beacon_response(&self, superframe_spec: Option<SuperframeSpec>) {
+        self.beacon_spec.insert(superframe_spec);
+    }
+}
+
+impl<A: Alarm> time::Client for Scanner<'a, A> {
+    fn fired(&self) {
+        if self.scan_type.is_some() {
+            self.next_channel();
+        }
+    }
+}
+
+impl<A: Alarm> radio::EnergyClient for Scanner<'a, A> {
+    fn energy_detect_done(&self, level: u8, result: ReturnCode) {
+        let channel = match self.energy_channel.take() {
+            Some(channel) => channel,
+            None => return,
+        };
+        if self.scan_type.map(|scan_type| *scan_type) != Some(ScanType::EnergyDetect) {
+            return;
+        }
+        // A measurement that was still running when the scan moved on to the
+        // next channel may have measured either channel, so it is discarded
+        if result == ReturnCode::SUCCESS
+            && channel == self.channel.get()
+            && channel >= MIN_CHANNEL
+            && channel <= MAX_CHANNEL
+        {
+            let mut energy = self.energy.get();
+            let index = (channel - MIN_CHANNEL) as usize;
+            energy[index] = Some(energy[index].map_or(level, |peak| peak.max(level)));
+            self.energy.set(energy);
+        }
+        // Keep measuring until the alarm moves on to the next channel
+        self.start_energy_detect();
+    }
+}
+
+impl<A: Alarm> TxClient for Scanner<'a, A> {
+    fn send_done(&self, spi_buf: &'static mut [u8], _acked: bool, _result: ReturnCode) {
+        self.tx_buf.replace(spi_buf);
+    }
+}
+
+impl<A: Alarm> RxClient for Scanner<'a, A> {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        data_offset: usize,
+        data_len: usize,
+        quality: radio::LinkQuality,
+    ) {
+        let payload = &buf[data_offset..data_offset + data_len];
+        match header.frame_type {
+            FrameType::Beacon => self.beacon_received(&header, payload, quality),
+            FrameType::MACCommand => {
+                if payload.first() == Some(&(MacCommand::BeaconRequest as u8)) {
+                    self.send_beacon();
+                }
+            }
+            _ => {}
+        }
+    }
+}
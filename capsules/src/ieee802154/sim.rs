@@ -0,0 +1,2780 @@
+//! Simulated IEEE 802.15.4 radios sharing an in-process medium.
+//!
+//! `SimRadio` implements `hil::radio::Radio` without any hardware, so that
+//! several nodes running the full MAC, 6LoWPAN and UDP stack can exchange
+//! frames on the host, for example in `cargo test`. All radios attached to a
+//! `Medium` share its clock, and `SimAlarm` provides alarms on that clock for
+//! the layers that need one.
+//!
+//! The medium models:
+//!
+//! - Topology: a frame from one node reaches another only if there is a
+//!   `Link` from the sender to the receiver. Links are directional, so
+//!   asymmetric links can be modeled with `Medium::set_link`.
+//! - Packet loss: each link drops a percentage of the frames sent over it.
+//! - Delay: a frame occupies the channel for its airtime at 250 kbps, and is
+//!   delivered when its transmission ends plus the link delay.
+//! - Collisions: a frame is lost at a receiver if the receiver hears another
+//!   frame on the same channel while it is on the air, or if the receiver
+//!   is transmitting itself.
+//!
+//! Radios filter frames by destination address unless they are promiscuous,
+//! and acknowledge frames that request it when automatic acknowledgements are
+//! enabled. Acknowledgements are never lost and do not take any airtime.
+//! Configuration changes take effect immediately; `config_commit` only issues
+//! the callback. Energy detection reports the strongest frame heard during
+//! the measurement.
+//!
+//! Nothing happens until the medium is run: `Medium::run_for` processes the
+//! events in order of time, issuing the callbacks of the radios and alarms,
+//! and then advances the clock to the end of the interval.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let medium = static_init!(Medium<'static>, Medium::new(SEED));
+//! let radio_a = static_init!(SimRadio<'static>, SimRadio::new(medium, 0));
+//! let radio_b = static_init!(SimRadio<'static>, SimRadio::new(medium, 1));
+//! medium.add_radio(radio_a);
+//! medium.add_radio(radio_b);
+//! medium.connect(0, 1, Link::PERFECT);
+//!
+//! // Set up the MAC layers on top of each radio, as for a hardware radio
+//!
+//! medium.run_for(32768);
+//! ```
+
+use crate::ieee802154::mac;
+use crate::net::ieee802154::{Header, MacAddress, BROADCAST};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::radio::{self, LinkQuality};
+use kernel::hil::time::{self, Alarm, Freq32KHz, Frequency, Time};
+use kernel::ReturnCode;
+
+/// Maximum number of radios attached to a medium.
+pub const MAX_NODES: usize = 16;
+
+/// Synchronization header and PHY header bytes sent before each frame.
+const PHY_OVERHEAD: usize = 6;
+
+/// Time to send one byte at 250 kbps, in microseconds.
+const BYTE_US: u32 = 32;
+
+/// Duration of an energy detection measurement, about 128 us.
+const ED_TICKS: u32 = 4;
+
+/// Received signal strength range mapped to link quality and energy levels,
+/// in dBm.
+const RSSI_FLOOR: i16 = -94;
+const RSSI_CEILING: i16 = -10;
+
+/// Time a frame of `frame_len` bytes, excluding the frame check sequence,
+/// occupies the channel, in ticks of the medium clock.
+pub fn airtime(frame_len: usize) -> u32 {
+    let us = (PHY_OVERHEAD + frame_len + radio::MFR_SIZE) as u32 * BYTE_US;
+    let freq = Freq32KHz::frequency();
+    ((us as u64 * freq as u64 + 999_999) / 1_000_000) as u32
+}
+
+/// Scales a received signal strength to a 0 to 255 link quality or energy
+/// level.
+fn rssi_to_level(rssi: i8) -> u8 {
+    let span = RSSI_CEILING - RSSI_FLOOR;
+    let above = (rssi as i16 - RSSI_FLOOR).max(0).min(span);
+    (above * 255 / span) as u8
+}
+
+/// Whether the interval `[a_start, a_end)` overlaps `[b_start, b_end)` on
+/// the wrapping clock.
+fn overlaps((a_start, a_end): (u32, u32), (b_start, b_end): (u32, u32)) -> bool {
+    (b_end.wrapping_sub(a_start) as i32) > 0 && (a_end.wrapping_sub(b_start) as i32) > 0
+}
+
+/// Ticks from `now` until `when`, or 0 if `when` has passed.
+fn until(now: u32, when: u32) -> u32 {
+    let delta = when.wrapping_sub(now);
+    if (delta as i32) < 0 {
+        0
+    } else {
+        delta
+    }
+}
+
+/// A directional link between two simulated radios.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Link {
+    /// Percentage of frames lost on this link, from 0 to 100
+    pub loss: u8,
+    /// Propagation and processing delay added to the airtime, in ticks
+    pub delay: u32,
+    /// Received signal strength, in dBm
+    pub rssi: i8,
+}
+
+impl Link {
+    /// A link that never loses frames and has no extra delay.
+    pub const PERFECT: Link = Link {
+        loss: 0,
+        delay: 0,
+        rssi: -40,
+    };
+}
+
+impl Default for Link {
+    fn default() -> Link {
+        Link::PERFECT
+    }
+}
+
+/// Counters of the frames sent over a medium.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MediumStats {
+    /// Frames transmitted
+    pub sent: usize,
+    /// Frames handed to a receiving radio
+    pub delivered: usize,
+    /// Frames lost to link loss
+    pub lost: usize,
+    /// Frames lost because the receiver heard or sent another frame at the
+    /// same time
+    pub collided: usize,
+    /// Frames lost because the receiver had no receive buffer
+    pub dropped: usize,
+}
+
+#[derive(Copy, Clone)]
+enum Event<'a> {
+    Power(&'a SimRadio<'a>),
+    Config(&'a SimRadio<'a>),
+    TransmitDone(&'a SimRadio<'a>),
+    Receive(&'a SimRadio<'a>),
+    EnergyDetectDone(&'a SimRadio<'a>),
+    Alarm(&'a SimAlarm<'a>),
+}
+
+/// The shared channel and clock of a set of simulated radios.
+pub struct Medium<'a> {
+    now: Cell<u32>,
+    seed: Cell<u32>,
+    links: MapCell<[[Option<Link>; MAX_NODES]; MAX_NODES]>,
+    radios: List<'a, SimRadio<'a>>,
+    alarms: List<'a, SimAlarm<'a>>,
+    stats: Cell<MediumStats>,
+}
+
+impl Medium<'a> {
+    /// Creates a medium without any links. `seed` seeds the random number
+    /// generator used for packet loss, so that runs are reproducible.
+    pub fn new(seed: u32) -> Medium<'a> {
+        Medium {
+            now: Cell::new(0),
+            // xorshift never leaves 0
+            seed: Cell::new(if seed == 0 { 0x2545_f491 } else { seed }),
+            links: MapCell::new([[None; MAX_NODES]; MAX_NODES]),
+            radios: List::new(),
+            alarms: List::new(),
+            stats: Cell::new(MediumStats::default()),
+        }
+    }
+
+    pub fn add_radio(&self, radio: &'a SimRadio<'a>) {
+        assert!(radio.id < MAX_NODES, "radio id out of range");
+        self.radios.push_tail(radio);
+    }
+
+    /// Sets or, with `None`, removes the link from `from` to `to`.
+    pub fn set_link(&self, from: usize, to: usize, link: Option<Link>) {
+        if from != to {
+            self.links.map(|links| links[from][to] = link);
+        }
+    }
+
+    /// Links `a` and `b` in both directions.
+    pub fn connect(&self, a: usize, b: usize, link: Link) {
+        self.set_link(a, b, Some(link));
+        self.set_link(b, a, Some(link));
+    }
+
+    /// Removes the links between `a` and `b` in both directions.
+    pub fn disconnect(&self, a: usize, b: usize) {
+        self.set_link(a, b, None);
+        self.set_link(b, a, None);
+    }
+
+    /// Links every pair of the first `nodes` radios.
+    pub fn connect_all(&self, nodes: usize, link: Link) {
+        for a in 0..nodes {
+            for b in 0..nodes {
+                self.set_link(a, b, Some(link));
+            }
+        }
+    }
+
+    pub fn link(&self, from: usize, to: usize) -> Option<Link> {
+        self.links.map_or(None, |links| links[from][to])
+    }
+
+    /// Current time, in ticks of the medium clock.
+    pub fn now(&self) -> u32 {
+        self.now.get()
+    }
+
+    pub fn stats(&self) -> MediumStats {
+        self.stats.get()
+    }
+
+    /// Processes all events in the next `ticks` ticks, then advances the
+    /// clock to the end of the interval.
+    pub fn run_for(&self, ticks: u32) {
+        let end = self.now.get().wrapping_add(ticks);
+        loop {
+            let remaining = end.wrapping_sub(self.now.get());
+            match self.next_event() {
+                Some((delay, event)) if delay <= remaining => {
+                    self.now.set(self.now.get().wrapping_add(delay));
+                    self.fire(event);
+                }
+                _ => break,
+            }
+        }
+        self.now.set(end);
+    }
+
+    /// Processes the next event, advancing the clock to it. Returns false if
+    /// there is nothing left to do.
+    pub fn step(&self) -> bool {
+        match self.next_event() {
+            Some((delay, event)) => {
+                self.now.set(self.now.get().wrapping_add(delay));
+                self.fire(event);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn next_event(&self) -> Option<(u32, Event<'a>)> {
+        let now = self.now.get();
+        let mut next: Option<(u32, Event<'a>)> = None;
+        let mut consider = |delay: u32, event: Event<'a>| {
+            if next.map_or(true, |(earliest, _)| delay < earliest) {
+                next = Some((delay, event));
+            }
+        };
+        for radio in self.radios.iter() {
+            if radio.power_pending.get() {
+                consider(0, Event::Power(radio));
+            }
+            if radio.config_pending.get() {
+                consider(0, Event::Config(radio));
+            }
+            if let Some((_, end)) = radio.tx.get() {
+                consider(until(now, end), Event::TransmitDone(radio));
+            }
+            if let Some((at, _, _)) = radio.rx_pending.get() {
+                consider(until(now, at), Event::Receive(radio));
+            }
+            if let Some(at) = radio.ed_pending.get() {
+                consider(until(now, at), Event::EnergyDetectDone(radio));
+            }
+        }
+        for alarm in self.alarms.iter() {
+            if alarm.armed.get() {
+                consider(until(now, alarm.when.get()), Event::Alarm(alarm));
+            }
+        }
+        next
+    }
+
+    fn fire(&self, event: Event<'a>) {
+        match event {
+            Event::Power(radio) => {
+                radio.power_pending.set(false);
+                let on = radio.on.get();
+                radio.power_client.map(|client| client.changed(on));
+            }
+            Event::Config(radio) => {
+                radio.config_pending.set(false);
+                radio
+                    .config_client
+                    .map(|client| client.config_done(ReturnCode::SUCCESS));
+            }
+            Event::TransmitDone(radio) => self.transmission_done(radio),
+            Event::Receive(radio) => radio.deliver(),
+            Event::EnergyDetectDone(radio) => {
+                radio.ed_pending.set(None);
+                let level = self.energy_level(radio);
+                radio
+                    .energy_client
+                    .map(|client| client.energy_detect_done(level, ReturnCode::SUCCESS));
+            }
+            Event::Alarm(alarm) => {
+                alarm.armed.set(false);
+                alarm.client.map(|client| client.fired());
+            }
+        }
+    }
+
+    fn random(&self) -> u32 {
+        let mut x = self.seed.get();
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.seed.set(x);
+        x
+    }
+
+    fn count<F: FnOnce(&mut MediumStats)>(&self, f: F) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
+    /// Whether `radio` hears a frame other than the one sent by `sender`
+    /// during `interval`.
+    fn hears_other(&self, radio: &SimRadio, sender: &SimRadio, interval: (u32, u32)) -> bool {
+        self.radios.iter().any(|other| {
+            other.id != sender.id
+                && other.id != radio.id
+                && other.channel.get() == radio.channel.get()
+                && self.link(other.id, radio.id).is_some()
+                && other.sent_during(interval)
+        })
+    }
+
+    /// Hands the frame `sender` has finished sending to every radio that
+    /// receives it, then completes the transmission.
+    fn transmission_done(&self, sender: &'a SimRadio<'a>) {
+        let interval = match sender.tx.get() {
+            Some(interval) => interval,
+            None => return,
+        };
+        self.count(|stats| stats.sent += 1);
+
+        let frame_len = sender.tx_len.get();
+        let acked = sender.tx_buf.map_or(false, |buf| {
+            let frame = &buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len];
+            let header = Header::decode(frame, false).done().map(|(_, (h, _))| h);
+            let mut acked = false;
+            for receiver in self.radios.iter() {
+                if receiver.id == sender.id
+                    || !receiver.on.get()
+                    || receiver.channel.get() != sender.channel.get()
+                {
+                    continue;
+                }
+                let link = match self.link(sender.id, receiver.id) {
+                    Some(link) => link,
+                    None => continue,
+                };
+                if receiver.sent_during(interval) || self.hears_other(receiver, sender, interval) {
+                    self.count(|stats| stats.collided += 1);
+                    continue;
+                }
+                if link.loss > 0 && self.random() % 100 < link.loss as u32 {
+                    self.count(|stats| stats.lost += 1);
+                    continue;
+                }
+                if !receiver.promiscuous.get() && !mac::addressed_to_radio(receiver, frame) {
+                    continue;
+                }
+                let quality = LinkQuality {
+                    rssi: link.rssi,
+                    lqi: rssi_to_level(link.rssi),
+                };
+                let at = interval.1.wrapping_add(link.delay);
+                if !receiver.receive_frame(buf, frame_len, quality, at) {
+                    self.count(|stats| stats.dropped += 1);
+                    continue;
+                }
+                self.count(|stats| stats.delivered += 1);
+                let for_receiver = header.map_or(false, |header| {
+                    header.ack_requested
+                        && match header.dst_addr {
+                            Some(MacAddress::Short(addr)) => {
+                                addr != BROADCAST && addr == receiver.addr.get()
+                            }
+                            Some(MacAddress::Long(addr)) => addr == receiver.addr_long.get(),
+                            None => false,
+                        }
+                });
+                acked |= for_receiver && receiver.auto_ack.get();
+            }
+            acked
+        });
+
+        sender.tx.set(None);
+        sender.last_tx.set(Some(interval));
+        sender.tx_buf.take().map(|buf| {
+            sender
+                .tx_client
+                .map(move |client| client.send_done(buf, acked, ReturnCode::SUCCESS));
+        });
+    }
+
+    /// Peak energy level `radio` heard during the last energy detection
+    /// measurement.
+    fn energy_level(&self, radio: &SimRadio) -> u8 {
+        let now = self.now.get();
+        let window = (now.wrapping_sub(ED_TICKS), now);
+        self.radios
+            .iter()
+            .filter(|other| {
+                other.id != radio.id
+                    && other.channel.get() == radio.channel.get()
+                    && other.sent_during(window)
+            })
+            .filter_map(|other| self.link(other.id, radio.id))
+            .map(|link| rssi_to_level(link.rssi))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// A simulated 802.15.4 radio attached to a `Medium`.
+pub struct SimRadio<'a> {
+    medium: &'a Medium<'a>,
+    id: usize,
+    next: ListLink<'a, SimRadio<'a>>,
+
+    on: Cell<bool>,
+    power_pending: Cell<bool>,
+    config_pending: Cell<bool>,
+
+    addr: Cell<u16>,
+    addr_long: Cell<[u8; 8]>,
+    pan: Cell<u16>,
+    tx_power: Cell<i8>,
+    channel: Cell<u8>,
+    promiscuous: Cell<bool>,
+    auto_ack: Cell<bool>,
+
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    /// Start and end of the frame on the air
+    tx: Cell<Option<(u32, u32)>>,
+    /// Start and end of the most recent completed frame
+    last_tx: Cell<Option<(u32, u32)>>,
+
+    rx_buf: TakeCell<'static, [u8]>,
+    /// Delivery time, length and quality of the frame in the receive buffer
+    rx_pending: Cell<Option<(u32, usize, LinkQuality)>>,
+    ed_pending: Cell<Option<u32>>,
+
+    tx_client: OptionalCell<&'static radio::TxClient>,
+    rx_client: OptionalCell<&'static radio::RxClient>,
+    config_client: OptionalCell<&'static radio::ConfigClient>,
+    power_client: OptionalCell<&'static radio::PowerClient>,
+    energy_client: OptionalCell<&'static radio::EnergyClient>,
+}
+
+impl ListNode<'a, SimRadio<'a>> for SimRadio<'a> {
+    fn next(&self) -> &'a ListLink<SimRadio<'a>> {
+        &self.next
+    }
+}
+
+impl SimRadio<'a> {
+    /// Creates a radio that is node `id` of the medium's topology. The
+    /// radio must be attached with `Medium::add_radio`.
+    pub fn new(medium: &'a Medium<'a>, id: usize) -> SimRadio<'a> {
+        SimRadio {
+            medium: medium,
+            id: id,
+            next: ListLink::empty(),
+            on: Cell::new(false),
+            power_pending: Cell::new(false),
+            config_pending: Cell::new(false),
+            addr: Cell::new(0),
+            addr_long: Cell::new([0; 8]),
+            pan: Cell::new(0),
+            tx_power: Cell::new(0),
+            channel: Cell::new(26),
+            promiscuous: Cell::new(false),
+            auto_ack: Cell::new(true),
+            tx_buf: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx: Cell::new(None),
+            last_tx: Cell::new(None),
+            rx_buf: TakeCell::empty(),
+            rx_pending: Cell::new(None),
+            ed_pending: Cell::new(None),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            config_client: OptionalCell::empty(),
+            power_client: OptionalCell::empty(),
+            energy_client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    fn sent_during(&self, interval: (u32, u32)) -> bool {
+        self.tx.get().map_or(false, |tx| overlaps(tx, interval))
+            || self
+                .last_tx
+                .get()
+                .map_or(false, |tx| overlaps(tx, interval))
+    }
+
+    /// Copies a received frame into the receive buffer, to be delivered at
+    /// `at`. Returns false if there is no free receive buffer.
+    fn receive_frame(&self, buf: &[u8], frame_len: usize, quality: LinkQuality, at: u32) -> bool {
+        if self.rx_pending.get().is_some() {
+            return false;
+        }
+        let end = radio::PSDU_OFFSET + frame_len;
+        self.rx_buf.map_or(false, |rbuf| {
+            if rbuf.len() < end {
+                return false;
+            }
+            rbuf[..end].copy_from_slice(&buf[..end]);
+            rbuf[1] = (frame_len + radio::MFR_SIZE) as u8;
+            self.rx_pending.set(Some((at, frame_len, quality)));
+            true
+        })
+    }
+
+    fn deliver(&self) {
+        let (_, frame_len, quality) = match self.rx_pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        // Frames arriving while the radio is off are lost
+        if !self.on.get() || self.rx_client.is_none() {
+            return;
+        }
+        self.rx_buf.take().map(|buf| {
+            self.rx_client.map(move |client| {
+                client.receive(buf, frame_len, quality, true, ReturnCode::SUCCESS)
+            });
+        });
+    }
+}
+
+impl radio::Radio for SimRadio<'a> {}
+
+impl radio::RadioConfig for SimRadio<'a> {
+    fn initialize(
+        &self,
+        _spi_buf: &'static mut [u8],
+        _reg_write: &'static mut [u8],
+        _reg_read: &'static mut [u8],
+    ) -> ReturnCode {
+        // There are no registers to access
+        ReturnCode::SUCCESS
+    }
+
+    fn reset(&self) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+
+    fn start(&self) -> ReturnCode {
+        if self.on.get() {
+            return ReturnCode::EALREADY;
+        }
+        self.on.set(true);
+        self.power_pending.set(true);
+        ReturnCode::SUCCESS
+    }
+
+    /// A frame that is on the air when the radio is stopped is still sent.
+    fn stop(&self) -> ReturnCode {
+        if !self.on.get() {
+            return ReturnCode::EALREADY;
+        }
+        self.on.set(false);
+        self.ed_pending.set(None);
+        self.power_pending.set(true);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_on(&self) -> bool {
+        self.on.get()
+    }
+
+    fn busy(&self) -> bool {
+        self.tx.get().is_some() || self.rx_pending.get().is_some()
+    }
+
+    fn set_power_client(&self, client: &'static radio::PowerClient) {
+        self.power_client.set(client);
+    }
+
+    fn config_commit(&self) {
+        self.config_pending.set(true);
+    }
+
+    fn set_config_client(&self, client: &'static radio::ConfigClient) {
+        self.config_client.set(client);
+    }
+
+    fn get_address(&self) -> u16 {
+        self.addr.get()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.addr_long.get()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.pan.get()
+    }
+
+    fn get_tx_power(&self) -> i8 {
+        self.tx_power.get()
+    }
+
+    fn get_channel(&self) -> u8 {
+        self.channel.get()
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.addr.set(addr);
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.addr_long.set(addr);
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.pan.set(id);
+    }
+
+    /// The transmit power is recorded but does not change link strengths.
+    fn set_tx_power(&self, power: i8) -> ReturnCode {
+        self.tx_power.set(power);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        if chan < 11 || chan > 26 {
+            return ReturnCode::EINVAL;
+        }
+        self.channel.set(chan);
+        ReturnCode::SUCCESS
+    }
+
+    fn get_promiscuous(&self) -> bool {
+        self.promiscuous.get()
+    }
+
+    fn get_auto_ack(&self) -> bool {
+        self.auto_ack.get()
+    }
+
+    fn set_promiscuous(&self, enable: bool) -> ReturnCode {
+        self.promiscuous.set(enable);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_auto_ack(&self, enable: bool) -> ReturnCode {
+        self.auto_ack.set(enable);
+        ReturnCode::SUCCESS
+    }
+}
+
+impl radio::RadioData for SimRadio<'a> {
+    fn set_transmit_client(&self, client: &'static radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(
+        &self,
+        client: &'static radio::RxClient,
+        receive_buffer: &'static mut [u8],
+    ) {
+        self.rx_client.set(client);
+        self.rx_buf.replace(receive_buffer);
+    }
+
+    fn set_receive_buffer(&self, receive_buffer: &'static mut [u8]) {
+        self.rx_buf.replace(receive_buffer);
+    }
+
+    fn transmit(
+        &self,
+        spi_buf: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if !self.on.get() {
+            return (ReturnCode::EOFF, Some(spi_buf));
+        } else if self.tx.get().is_some() {
+            return (ReturnCode::EBUSY, Some(spi_buf));
+        } else if frame_len + radio::MFR_SIZE > radio::MAX_FRAME_SIZE
+            || radio::PSDU_OFFSET + frame_len > spi_buf.len()
+        {
+            return (ReturnCode::ESIZE, Some(spi_buf));
+        }
+
+        spi_buf[1] = (frame_len + radio::MFR_SIZE) as u8;
+        let start = self.medium.now();
+        self.tx
+            .set(Some((start, start.wrapping_add(airtime(frame_len)))));
+        self.tx_len.set(frame_len);
+        self.tx_buf.replace(spi_buf);
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+impl radio::RadioEnergyDetect for SimRadio<'a> {
+    fn set_energy_client(&self, client: &'static radio::EnergyClient) {
+        self.energy_client.set(client);
+    }
+
+    fn energy_detect(&self) -> ReturnCode {
+        if !self.on.get() {
+            return ReturnCode::EOFF;
+        } else if self.ed_pending.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.ed_pending
+            .set(Some(self.medium.now().wrapping_add(ED_TICKS)));
+        ReturnCode::SUCCESS
+    }
+}
+
+/// An alarm on the clock of a `Medium`, running at 32 kHz.
+pub struct SimAlarm<'a> {
+    medium: &'a Medium<'a>,
+    when: Cell<u32>,
+    armed: Cell<bool>,
+    next: ListLink<'a, SimAlarm<'a>>,
+    client: OptionalCell<&'a time::Client>,
+}
+
+impl ListNode<'a, SimAlarm<'a>> for SimAlarm<'a> {
+    fn next(&self) -> &'a ListLink<SimAlarm<'a>> {
+        &self.next
+    }
+}
+
+impl SimAlarm<'a> {
+    pub fn new(medium: &'a Medium<'a>) -> SimAlarm<'a> {
+        SimAlarm {
+            medium: medium,
+            when: Cell::new(0),
+            armed: Cell::new(false),
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Attaches the alarm to the medium. An alarm without a client only
+    /// tells the time.
+    pub fn set_client(&'a self, client: &'a time::Client) {
+        self.medium.alarms.push_head(self);
+        self.armed.set(false);
+        self.client.set(client);
+    }
+}
+
+impl Time for SimAlarm<'a> {
+    type Frequency = Freq32KHz;
+
+    fn disable(&self) {
+        self.armed.set(false);
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.get()
+    }
+}
+
+impl Alarm for SimAlarm<'a> {
+    fn now(&self) -> u32 {
+        self.medium.now()
+    }
+
+    fn set_alarm(&self, tics: u32) {
+        self.when.set(tics);
+        self.armed.set(true);
+    }
+
+    fn get_alarm(&self) -> u32 {
+        self.when.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::ieee802154::csma::CsmaMac;
+    use crate::ieee802154::device::MacDevice;
+    use crate::ieee802154::framer::Framer;
+    use crate::ieee802154::mac::{AwakeMac, Mac};
+    use crate::ieee802154::scan::{Scan, ScanClient, ScanType, Scanner};
+    use crate::ieee802154::virtual_mac::{MacUser, MuxMac};
+    use crate::ieee802154::xmac::{XMac, XMacConfig, XMacStats};
+    use crate::net::ieee802154::SuperframeSpec;
+    use crate::net::ipv6::ip_utils::IPAddr;
+    use crate::net::ipv6::ipv6::{IP6Packet, IPPayload, TransportHeader};
+    use crate::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvStruct};
+    use crate::net::ipv6::ipv6_send::{IP6SendStruct, IP6Sender, NextHopLookup};
+    use crate::net::rpl::rpl::ROOT_RANK;
+    use crate::net::rpl::rpl_node::RplNode;
+    use crate::net::sixlowpan::sixlowpan_compression;
+    use crate::net::sixlowpan::sixlowpan_mesh::{
+        lowpan_mesh, MeshForwarder, StaticForwardingTable,
+    };
+    use crate::net::sixlowpan::sixlowpan_state::{RxState, Sixlowpan, SixlowpanState, TxState};
+    use crate::net::thread::mle::{mle_command, Mle, MleClient, DEFAULT_POLL_PERIOD_MS, MLE_PORT};
+    use crate::net::thread::tlv::Tlv;
+    use crate::net::udp::udp::UDPHeader;
+    use crate::net::udp::udp_port_table::{UDPPortTable, UDPSocket};
+    use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+    use crate::net::udp::udp_send::{UDPSendClient, UDPSendStruct, UDPSender};
+    use crate::storage_sim::{leak, zeroed};
+    use kernel::hil::radio::{RadioConfig, RadioData, RadioEnergyDetect};
+    use kernel::hil::rng::{self, Random};
+    use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM, CCM_NONCE_LENGTH};
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    const PAN: u16 = 0xabcd;
+
+    /// Writes a data frame with short addresses and returns its length.
+    fn write_frame(buf: &mut [u8], dst: u16, src: u16, ack: bool, payload_len: usize) -> usize {
+        let mut fcf: u16 = 0x0001 | 0x0040 | 0x0800 | 0x8000;
+        if ack {
+            fcf |= 0x0020;
+        }
+        let frame = &mut buf[radio::PSDU_OFFSET..];
+        frame[0..2].copy_from_slice(&fcf.to_le_bytes());
+        frame[2] = 0;
+        frame[3..5].copy_from_slice(&PAN.to_le_bytes());
+        frame[5..7].copy_from_slice(&dst.to_le_bytes());
+        frame[7..9].copy_from_slice(&src.to_le_bytes());
+        9 + payload_len
+    }
+
+    /// A radio that sends raw frames and counts what it sends and receives.
+    struct Node {
+        radio: &'static SimRadio<'static>,
+        tx_buf: TakeCell<'static, [u8]>,
+        sent: Cell<usize>,
+        acked: Cell<usize>,
+        sent_at: Cell<u32>,
+        received: Cell<usize>,
+        received_at: Cell<u32>,
+        received_from: Cell<u16>,
+        quality: Cell<LinkQuality>,
+        energy: Cell<Option<u8>>,
+    }
+
+    impl Node {
+        fn new(medium: &'static Medium<'static>, id: usize) -> &'static Node {
+            let radio = leak(SimRadio::new(medium, id));
+            medium.add_radio(radio);
+            let node = leak(Node {
+                radio: radio,
+                tx_buf: TakeCell::new(zeroed(radio::MAX_BUF_SIZE)),
+                sent: Cell::new(0),
+                acked: Cell::new(0),
+                sent_at: Cell::new(0),
+                received: Cell::new(0),
+                received_at: Cell::new(0),
+                received_from: Cell::new(0),
+                quality: Cell::new(LinkQuality::UNKNOWN),
+                energy: Cell::new(None),
+            });
+            radio.set_transmit_client(node);
+            radio.set_receive_client(node, zeroed(radio::MAX_BUF_SIZE));
+            radio.set_energy_client(node);
+            radio.set_pan(PAN);
+            radio.set_address(Node::address(id));
+            assert_eq!(radio.start(), ReturnCode::SUCCESS);
+            node
+        }
+
+        fn address(id: usize) -> u16 {
+            0x1000 + id as u16
+        }
+
+        fn send(&self, dst: u16, ack: bool, payload_len: usize) -> ReturnCode {
+            let buf = self.tx_buf.take().expect("frame already in flight");
+            let src = self.radio.get_address();
+            let frame_len = write_frame(buf, dst, src, ack, payload_len);
+            let (result, buf) = self.radio.transmit(buf, frame_len);
+            if let Some(buf) = buf {
+                self.tx_buf.replace(buf);
+            }
+            result
+        }
+    }
+
+    impl radio::TxClient for Node {
+        fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+            assert_eq!(result, ReturnCode::SUCCESS);
+            self.tx_buf.replace(buf);
+            self.sent.set(self.sent.get() + 1);
+            self.sent_at.set(self.radio.medium.now());
+            if acked {
+                self.acked.set(self.acked.get() + 1);
+            }
+        }
+    }
+
+    impl radio::RxClient for Node {
+        fn receive(
+            &self,
+            buf: &'static mut [u8],
+            frame_len: usize,
+            quality: LinkQuality,
+            crc_valid: bool,
+            result: ReturnCode,
+        ) {
+            assert!(crc_valid);
+            assert_eq!(result, ReturnCode::SUCCESS);
+            assert_eq!(buf[1] as usize, frame_len + radio::MFR_SIZE);
+            let src = radio::PSDU_OFFSET + 7;
+            self.received.set(self.received.get() + 1);
+            self.received_at.set(self.radio.medium.now());
+            self.received_from
+                .set(u16::from_le_bytes([buf[src], buf[src + 1]]));
+            self.quality.set(quality);
+            self.radio.set_receive_buffer(buf);
+        }
+    }
+
+    impl radio::EnergyClient for Node {
+        fn energy_detect_done(&self, level: u8, result: ReturnCode) {
+            assert_eq!(result, ReturnCode::SUCCESS);
+            self.energy.set(Some(level));
+        }
+    }
+
+    fn medium() -> &'static Medium<'static> {
+        leak(Medium::new(0x1234_5678))
+    }
+
+    fn nodes(medium: &'static Medium<'static>, count: usize) -> Vec<&'static Node> {
+        let nodes: Vec<&'static Node> = (0..count).map(|id| Node::new(medium, id)).collect();
+        // Deliver the power callbacks
+        medium.run_for(1);
+        nodes
+    }
+
+    #[test]
+    fn frames_follow_the_topology() {
+        let medium = medium();
+        let nodes = nodes(medium, 3);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.connect(1, 2, Link::PERFECT);
+
+        assert_eq!(nodes[0].send(BROADCAST, false, 10), ReturnCode::SUCCESS);
+        medium.run_for(100);
+        assert_eq!(nodes[0].sent.get(), 1);
+        assert_eq!(nodes[1].received.get(), 1);
+        assert_eq!(nodes[1].received_from.get(), Node::address(0));
+        assert_eq!(nodes[2].received.get(), 0);
+
+        assert_eq!(nodes[1].send(BROADCAST, false, 10), ReturnCode::SUCCESS);
+        medium.run_for(100);
+        assert_eq!(nodes[0].received.get(), 1);
+        assert_eq!(nodes[2].received.get(), 1);
+
+        // One-way link
+        medium.set_link(2, 1, None);
+        assert_eq!(nodes[2].send(BROADCAST, false, 10), ReturnCode::SUCCESS);
+        medium.run_for(100);
+        assert_eq!(nodes[1].received.get(), 1);
+
+        let stats = medium.stats();
+        assert_eq!(stats.sent, 3);
+        assert_eq!(stats.delivered, 3);
+        assert_eq!(stats.lost + stats.collided + stats.dropped, 0);
+    }
+
+    #[test]
+    fn frames_take_airtime_and_link_delay() {
+        let medium = medium();
+        let nodes = nodes(medium, 2);
+        let link = Link {
+            delay: 100,
+            rssi: -60,
+            ..Link::PERFECT
+        };
+        medium.connect(0, 1, link);
+
+        let start = medium.now();
+        assert_eq!(nodes[0].send(BROADCAST, false, 50), ReturnCode::SUCCESS);
+        assert!(nodes[0].radio.busy());
+        // 6 + 59 + 2 bytes at 32 us per byte
+        assert_eq!(airtime(59), 71);
+        medium.run_for(71 + 99);
+        assert_eq!(nodes[0].sent_at.get(), start + 71);
+        assert_eq!(nodes[1].received.get(), 0);
+        medium.run_for(1);
+        assert_eq!(nodes[1].received.get(), 1);
+        assert_eq!(nodes[1].received_at.get(), start + 71 + 100);
+        assert_eq!(nodes[1].quality.get().rssi, -60);
+        assert!(nodes[1].quality.get().lqi > 0 && nodes[1].quality.get().lqi < 255);
+    }
+
+    #[test]
+    fn hidden_terminals_collide() {
+        let medium = medium();
+        let nodes = nodes(medium, 3);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.connect(2, 1, Link::PERFECT);
+
+        assert_eq!(nodes[0].send(BROADCAST, false, 40), ReturnCode::SUCCESS);
+        medium.run_for(10);
+        assert_eq!(nodes[2].send(BROADCAST, false, 40), ReturnCode::SUCCESS);
+        medium.run_for(200);
+        assert_eq!(nodes[0].sent.get(), 1);
+        assert_eq!(nodes[2].sent.get(), 1);
+        assert_eq!(nodes[1].received.get(), 0);
+        assert_eq!(medium.stats().collided, 2);
+
+        // A node cannot receive while it transmits
+        assert_eq!(nodes[0].send(BROADCAST, false, 40), ReturnCode::SUCCESS);
+        assert_eq!(nodes[1].send(BROADCAST, false, 40), ReturnCode::SUCCESS);
+        medium.run_for(200);
+        assert_eq!(nodes[1].received.get(), 0);
+        assert_eq!(nodes[0].received.get(), 0);
+
+        // Frames one after the other are both received
+        assert_eq!(nodes[0].send(BROADCAST, false, 40), ReturnCode::SUCCESS);
+        medium.run_for(200);
+        assert_eq!(nodes[2].send(BROADCAST, false, 40), ReturnCode::SUCCESS);
+        medium.run_for(200);
+        assert_eq!(nodes[1].received.get(), 2);
+    }
+
+    #[test]
+    fn lossy_links_drop_frames() {
+        let medium = medium();
+        let nodes = nodes(medium, 3);
+        medium.connect(
+            0,
+            1,
+            Link {
+                loss: 30,
+                ..Link::PERFECT
+            },
+        );
+        medium.connect(
+            0,
+            2,
+            Link {
+                loss: 100,
+                ..Link::PERFECT
+            },
+        );
+
+        for _ in 0..100 {
+            assert_eq!(nodes[0].send(BROADCAST, false, 20), ReturnCode::SUCCESS);
+            medium.run_for(100);
+        }
+        assert_eq!(nodes[0].sent.get(), 100);
+        let received = nodes[1].received.get();
+        assert!(received > 50 && received < 90, "received {}", received);
+        assert_eq!(nodes[2].received.get(), 0);
+        let stats = medium.stats();
+        assert_eq!(stats.delivered, received);
+        assert_eq!(stats.lost, 200 - received);
+    }
+
+    #[test]
+    fn radios_filter_and_acknowledge_frames() {
+        let medium = medium();
+        let nodes = nodes(medium, 2);
+        medium.connect(0, 1, Link::PERFECT);
+
+        assert_eq!(
+            nodes[0].send(Node::address(1), true, 10),
+            ReturnCode::SUCCESS
+        );
+        medium.run_for(100);
+        assert_eq!(nodes[1].received.get(), 1);
+        assert_eq!(nodes[0].acked.get(), 1);
+
+        // Broadcast frames are never acknowledged
+        assert_eq!(nodes[0].send(BROADCAST, true, 10), ReturnCode::SUCCESS);
+        medium.run_for(100);
+        assert_eq!(nodes[1].received.get(), 2);
+        assert_eq!(nodes[0].acked.get(), 1);
+
+        // Frames to other nodes are filtered out unless promiscuous
+        assert_eq!(nodes[0].send(0x99, true, 10), ReturnCode::SUCCESS);
+        medium.run_for(100);
+        assert_eq!(nodes[1].received.get(), 2);
+        assert_eq!(nodes[0].acked.get(), 1);
+        nodes[1].radio.set_promiscuous(true);
+        assert_eq!(nodes[0].send(0x99, true, 10), ReturnCode::SUCCESS);
+        medium.run_for(100);
+        assert_eq!(nodes[1].received.get(), 3);
+        assert_eq!(nodes[0].acked.get(), 1);
+
+        nodes[1].radio.set_auto_ack(false);
+        assert_eq!(
+            nodes[0].send(Node::address(1), true, 10),
+            ReturnCode::SUCCESS
+        );
+        medium.run_for(100);
+        assert_eq!(nodes[1].received.get(), 4);
+        assert_eq!(nodes[0].acked.get(), 1);
+    }
+
+    #[test]
+    fn powered_off_radios_neither_send_nor_receive() {
+        let medium = medium();
+        let nodes = nodes(medium, 2);
+        medium.connect(0, 1, Link::PERFECT);
+
+        assert_eq!(nodes[1].radio.stop(), ReturnCode::SUCCESS);
+        assert_eq!(nodes[1].radio.stop(), ReturnCode::EALREADY);
+        assert_eq!(nodes[1].send(BROADCAST, false, 10), ReturnCode::EOFF);
+        assert_eq!(nodes[0].send(BROADCAST, false, 10), ReturnCode::SUCCESS);
+        medium.run_for(100);
+        assert_eq!(nodes[1].received.get(), 0);
+
+        assert_eq!(nodes[1].radio.start(), ReturnCode::SUCCESS);
+        assert_eq!(nodes[0].send(BROADCAST, false, 10), ReturnCode::SUCCESS);
+        medium.run_for(100);
+        assert_eq!(nodes[1].received.get(), 1);
+    }
+
+    #[test]
+    fn energy_detection_hears_frames_on_the_channel() {
+        let medium = medium();
+        let nodes = nodes(medium, 3);
+        medium.connect_all(3, Link::PERFECT);
+
+        assert_eq!(nodes[1].radio.energy_detect(), ReturnCode::SUCCESS);
+        assert_eq!(nodes[1].radio.energy_detect(), ReturnCode::EBUSY);
+        medium.run_for(10);
+        assert_eq!(nodes[1].energy.take(), Some(0));
+
+        assert_eq!(nodes[0].send(BROADCAST, false, 100), ReturnCode::SUCCESS);
+        medium.run_for(10);
+        assert_eq!(nodes[1].radio.energy_detect(), ReturnCode::SUCCESS);
+        assert_eq!(nodes[2].radio.set_channel(11), ReturnCode::SUCCESS);
+        assert_eq!(nodes[2].radio.energy_detect(), ReturnCode::SUCCESS);
+        medium.run_for(10);
+        assert_eq!(
+            nodes[1].energy.take(),
+            Some(rssi_to_level(Link::PERFECT.rssi))
+        );
+        assert_eq!(nodes[2].energy.take(), Some(0));
+    }
+
+    struct Counter {
+        fired: Cell<usize>,
+    }
+
+    impl time::Client for Counter {
+        fn fired(&self) {
+            self.fired.set(self.fired.get() + 1);
+        }
+    }
+
+    #[test]
+    fn alarms_fire_on_the_medium_clock() {
+        let medium = medium();
+        let counter = leak(Counter {
+            fired: Cell::new(0),
+        });
+        let alarm = leak(SimAlarm::new(medium));
+        alarm.set_client(counter);
+
+        medium.run_for(1000);
+        alarm.set_alarm(alarm.now().wrapping_add(50));
+        assert!(alarm.is_armed());
+        medium.run_for(49);
+        assert_eq!(counter.fired.get(), 0);
+        medium.run_for(1);
+        assert_eq!(counter.fired.get(), 1);
+        assert_eq!(medium.now(), 1050);
+        assert!(!alarm.is_armed());
+
+        alarm.set_alarm(alarm.now().wrapping_add(10));
+        alarm.disable();
+        medium.run_for(100);
+        assert_eq!(counter.fired.get(), 1);
+    }
+
+    /// A xorshift generator, so that backoffs are the same on every run.
+    struct TestRandom {
+        state: Cell<u32>,
+    }
+
+    impl Random<'a> for TestRandom {
+        fn initialize(&'a self) {}
+
+        fn reseed(&self, seed: u32) {
+            self.state.set(seed);
+        }
+
+        fn random(&self) -> u32 {
+            let mut x = self.state.get();
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            self.state.set(x);
+            x
+        }
+    }
+
+    type SimCsma = CsmaMac<'static, SimRadio<'static>, SimAlarm<'static>>;
+
+    /// A radio without automatic acknowledgements, sending raw frames through
+    /// `CsmaMac`.
+    struct CsmaNode {
+        radio: &'static SimRadio<'static>,
+        csma: &'static SimCsma,
+        tx_buf: TakeCell<'static, [u8]>,
+        done: Cell<Option<(bool, ReturnCode)>>,
+        received: Cell<usize>,
+    }
+
+    impl CsmaNode {
+        fn new(medium: &'static Medium<'static>, id: usize) -> &'static CsmaNode {
+            let radio = leak(SimRadio::new(medium, id));
+            medium.add_radio(radio);
+            let alarm = leak(SimAlarm::new(medium));
+            let random = leak(TestRandom {
+                state: Cell::new(0x9e37_79b9 + id as u32),
+            });
+            let csma = leak(CsmaMac::new(&*radio, &*alarm, &*random));
+            assert_eq!(
+                csma.initialize(zeroed(radio::MAX_BUF_SIZE)),
+                ReturnCode::SUCCESS
+            );
+            alarm.set_client(csma);
+            radio.set_transmit_client(csma);
+            radio.set_receive_client(csma, zeroed(radio::MAX_BUF_SIZE));
+            csma.set_energy_detect(radio);
+            radio.set_energy_client(csma);
+            radio.set_auto_ack(false);
+            radio.set_promiscuous(true);
+            radio.set_pan(PAN);
+            radio.set_address(Node::address(id));
+
+            let node = leak(CsmaNode {
+                radio: radio,
+                csma: csma,
+                tx_buf: TakeCell::new(zeroed(radio::MAX_BUF_SIZE)),
+                done: Cell::new(None),
+                received: Cell::new(0),
+            });
+            csma.set_transmit_client(node);
+            csma.set_receive_client(node);
+            assert_eq!(radio.start(), ReturnCode::SUCCESS);
+            node
+        }
+
+        fn send(&self, dst: u16, seq: u8) -> ReturnCode {
+            let buf = self.tx_buf.take().expect("frame already in flight");
+            let frame_len = write_frame(buf, dst, self.radio.get_address(), true, 20);
+            buf[radio::PSDU_OFFSET + 2] = seq;
+            self.done.set(None);
+            let (result, buf) = self.csma.transmit(buf, frame_len);
+            if let Some(buf) = buf {
+                self.tx_buf.replace(buf);
+            }
+            result
+        }
+    }
+
+    impl radio::TxClient for CsmaNode {
+        fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+            self.tx_buf.replace(buf);
+            self.done.set(Some((acked, result)));
+        }
+    }
+
+    impl radio::RxClient for CsmaNode {
+        fn receive(
+            &self,
+            buf: &'static mut [u8],
+            _frame_len: usize,
+            _quality: LinkQuality,
+            _crc_valid: bool,
+            _result: ReturnCode,
+        ) {
+            self.received.set(self.received.get() + 1);
+            self.csma.set_receive_buffer(buf);
+        }
+    }
+
+    /// Runs the medium until `node` is told its frame is done, for at most
+    /// `ticks`.
+    fn run_until_done(medium: &Medium, node: &CsmaNode, ticks: u32) -> Option<(bool, ReturnCode)> {
+        for _ in 0..ticks {
+            if node.done.get().is_some() {
+                break;
+            }
+            medium.run_for(1);
+        }
+        node.done.get()
+    }
+
+    #[test]
+    fn csma_nodes_acknowledge_each_other() {
+        let medium = medium();
+        let a = CsmaNode::new(medium, 0);
+        let b = CsmaNode::new(medium, 1);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.run_for(1);
+
+        assert_eq!(a.send(Node::address(1), 7), ReturnCode::SUCCESS);
+        assert_eq!(
+            run_until_done(medium, a, 2000),
+            Some((true, ReturnCode::SUCCESS))
+        );
+        assert_eq!(b.received.get(), 1);
+        assert_eq!(b.send(Node::address(0), 7), ReturnCode::SUCCESS);
+        assert_eq!(
+            run_until_done(medium, b, 2000),
+            Some((true, ReturnCode::SUCCESS))
+        );
+        assert_eq!(a.received.get(), 1);
+
+        // Each data frame was answered by an ACK frame, which is not passed up
+        assert_eq!(medium.stats().sent, 4);
+        medium.run_for(100);
+        assert_eq!((a.received.get(), b.received.get()), (1, 1));
+    }
+
+    #[test]
+    fn csma_retransmissions_are_acknowledged_but_not_passed_up() {
+        let medium = medium();
+        let a = CsmaNode::new(medium, 0);
+        let b = CsmaNode::new(medium, 1);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.run_for(1);
+
+        // The same sequence number again, as if the first ACK had been lost
+        for _ in 0..2 {
+            assert_eq!(a.send(Node::address(1), 3), ReturnCode::SUCCESS);
+            assert_eq!(
+                run_until_done(medium, a, 2000),
+                Some((true, ReturnCode::SUCCESS))
+            );
+        }
+        assert_eq!(b.received.get(), 1);
+
+        assert_eq!(a.send(Node::address(1), 4), ReturnCode::SUCCESS);
+        assert_eq!(
+            run_until_done(medium, a, 2000),
+            Some((true, ReturnCode::SUCCESS))
+        );
+        assert_eq!(b.received.get(), 2);
+    }
+
+    #[test]
+    fn csma_retries_until_acknowledged() {
+        let medium = medium();
+        let a = CsmaNode::new(medium, 0);
+        let b = CsmaNode::new(medium, 1);
+        medium.run_for(1);
+
+        // The first attempt is lost and times out waiting for the ACK
+        assert_eq!(a.send(Node::address(1), 1), ReturnCode::SUCCESS);
+        while medium.stats().sent == 0 {
+            medium.run_for(1);
+        }
+        medium.connect(0, 1, Link::PERFECT);
+        assert_eq!(
+            run_until_done(medium, a, 2000),
+            Some((true, ReturnCode::SUCCESS))
+        );
+        assert_eq!(b.received.get(), 1);
+        // Two data frames and one ACK
+        assert_eq!(medium.stats().sent, 3);
+    }
+
+    #[test]
+    fn csma_gives_up_after_the_last_retry() {
+        let medium = medium();
+        let a = CsmaNode::new(medium, 0);
+        CsmaNode::new(medium, 1);
+        medium.run_for(1);
+
+        a.csma.set_max_frame_retries(2);
+        assert_eq!(a.send(Node::address(1), 1), ReturnCode::SUCCESS);
+        assert_eq!(
+            run_until_done(medium, a, 5000),
+            Some((false, ReturnCode::ENOACK))
+        );
+        assert_eq!(medium.stats().sent, 3);
+    }
+
+    #[test]
+    fn csma_backs_off_while_the_channel_is_busy() {
+        let medium = medium();
+        let a = CsmaNode::new(medium, 0);
+        let b = CsmaNode::new(medium, 1);
+        let jammer = Node::new(medium, 2);
+        medium.connect_all(3, Link::PERFECT);
+        medium.run_for(1);
+
+        // Every clear channel assessment fails while the jammer sends, to a
+        // node that does not exist
+        let jam = |ticks: u32| {
+            for _ in 0..ticks {
+                if jammer.tx_buf.is_some() {
+                    assert_eq!(
+                        jammer.send(Node::address(9), false, 100),
+                        ReturnCode::SUCCESS
+                    );
+                }
+                medium.run_for(1);
+                if a.done.get().is_some() {
+                    break;
+                }
+            }
+        };
+        assert_eq!(a.send(Node::address(1), 1), ReturnCode::SUCCESS);
+        jam(5000);
+        assert_eq!(a.done.get(), Some((false, ReturnCode::EBUSY)));
+        assert_eq!(b.received.get(), 0);
+
+        // Once it stops, the frame goes out after a backoff
+        assert_eq!(a.send(Node::address(1), 2), ReturnCode::SUCCESS);
+        jam(100);
+        assert_eq!(a.done.get(), None);
+        assert_eq!(
+            run_until_done(medium, a, 2000),
+            Some((true, ReturnCode::SUCCESS))
+        );
+        assert_eq!(b.received.get(), 1);
+    }
+
+    /// XMac only needs randomness to back off behind another sender, which
+    /// these tests avoid, so it never arrives.
+    struct NoRng;
+
+    impl rng::Rng<'a> for NoRng {
+        fn get(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+
+        fn cancel(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+
+        fn set_client(&'a self, _client: &'a rng::Client) {}
+    }
+
+    type SimXMac = XMac<'static, SimRadio<'static>, SimAlarm<'static>>;
+
+    /// A duty-cycled radio, sending raw frames through `XMac`.
+    struct XMacNode {
+        xmac: &'static SimXMac,
+        tx_buf: TakeCell<'static, [u8]>,
+        done: Cell<Option<(bool, ReturnCode)>>,
+        received: Cell<usize>,
+    }
+
+    impl XMacNode {
+        fn new(
+            medium: &'static Medium<'static>,
+            id: usize,
+            config: XMacConfig,
+        ) -> &'static XMacNode {
+            let radio = leak(SimRadio::new(medium, id));
+            medium.add_radio(radio);
+            let alarm = leak(SimAlarm::new(medium));
+            let xmac = leak(XMac::new(&*radio, &*alarm, &NoRng));
+            assert_eq!(xmac.set_config(config), ReturnCode::SUCCESS);
+            assert_eq!(
+                xmac.initialize(zeroed(radio::MAX_BUF_SIZE)),
+                ReturnCode::SUCCESS
+            );
+            alarm.set_client(xmac);
+            radio.set_transmit_client(xmac);
+            radio.set_receive_client(xmac, zeroed(radio::MAX_BUF_SIZE));
+            radio.set_power_client(xmac);
+            radio.set_promiscuous(true);
+            radio.set_pan(PAN);
+            radio.set_address(Node::address(id));
+
+            let node = leak(XMacNode {
+                xmac: xmac,
+                tx_buf: TakeCell::new(zeroed(radio::MAX_BUF_SIZE)),
+                done: Cell::new(None),
+                received: Cell::new(0),
+            });
+            xmac.set_transmit_client(node);
+            xmac.set_receive_client(node);
+            assert_eq!(radio.start(), ReturnCode::SUCCESS);
+            node
+        }
+
+        fn send(&self, dst: u16) -> ReturnCode {
+            let buf = self.tx_buf.take().expect("frame already in flight");
+            let frame_len = write_frame(buf, dst, self.xmac.get_address(), true, 20);
+            self.done.set(None);
+            let (result, buf) = self.xmac.transmit(buf, frame_len);
+            if let Some(buf) = buf {
+                self.tx_buf.replace(buf);
+            }
+            result
+        }
+
+        /// Sends a frame to `dst` and returns the stats accumulated while
+        /// it was sent.
+        fn send_and_wait(&self, medium: &Medium, dst: u16) -> XMacStats {
+            self.xmac.reset_stats();
+            assert_eq!(self.send(dst), ReturnCode::SUCCESS);
+            for _ in 0..(SECOND / 10) {
+                if self.done.get().is_some() {
+                    break;
+                }
+                medium.run_for(10);
+            }
+            assert_eq!(self.done.get(), Some((true, ReturnCode::SUCCESS)));
+            self.xmac.get_stats()
+        }
+    }
+
+    impl radio::TxClient for XMacNode {
+        fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+            self.tx_buf.replace(buf);
+            self.done.set(Some((acked, result)));
+        }
+    }
+
+    impl radio::RxClient for XMacNode {
+        fn receive(
+            &self,
+            buf: &'static mut [u8],
+            _frame_len: usize,
+            _quality: LinkQuality,
+            _crc_valid: bool,
+            _result: ReturnCode,
+        ) {
+            self.received.set(self.received.get() + 1);
+            self.xmac.set_receive_buffer(buf);
+        }
+    }
+
+    #[test]
+    fn xmac_sleeps_less_under_load_and_more_when_idle() {
+        let medium = medium();
+        let config = XMacConfig::default();
+        let a = XMacNode::new(medium, 0, config);
+        let b = XMacNode::new(medium, 1, config);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.run_for(SECOND);
+        assert_eq!(b.xmac.get_stats().sleep_interval_ms, config.max_sleep_ms);
+
+        // Each data packet halves the sleep interval of the receiver
+        let mut interval = config.max_sleep_ms;
+        for count in 1..=4 {
+            a.send_and_wait(medium, Node::address(1));
+            assert_eq!(b.received.get(), count);
+            interval = (interval / 2).max(config.min_sleep_ms);
+            assert_eq!(b.xmac.get_stats().sleep_interval_ms, interval);
+        }
+        assert_eq!(interval, config.min_sleep_ms);
+
+        // And each idle wakeup lengthens it again, up to the maximum
+        b.xmac.reset_stats();
+        medium.run_for(SECOND / 5);
+        let stats = b.xmac.get_stats();
+        assert!(stats.idle_wakeups >= 2);
+        assert!(stats.sleep_interval_ms > config.min_sleep_ms);
+        assert!(stats.sleep_interval_ms < config.max_sleep_ms);
+        medium.run_for(5 * SECOND);
+        assert_eq!(b.xmac.get_stats().sleep_interval_ms, config.max_sleep_ms);
+        // The radio was off most of the time
+        b.xmac.reset_stats();
+        medium.run_for(5 * SECOND);
+        let stats = b.xmac.get_stats();
+        assert_eq!(stats.idle_wakeups, stats.wakeups);
+        assert!(stats.awake_ms < 500);
+    }
+
+    /// The preambles needed for a packet sent 100 ms after a previous one
+    /// to the same neighbor.
+    fn xmac_second_preamble_train(learn_schedules: bool) -> XMacStats {
+        let medium = medium();
+        let config = XMacConfig {
+            learn_schedules: learn_schedules,
+            ..XMacConfig::default()
+        };
+        let a = XMacNode::new(medium, 0, config);
+        let b = XMacNode::new(medium, 1, config);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.run_for(SECOND);
+
+        a.send_and_wait(medium, Node::address(1));
+        medium.run_for(SECOND / 10);
+        let stats = a.send_and_wait(medium, Node::address(1));
+        assert_eq!(b.received.get(), 2);
+        stats
+    }
+
+    #[test]
+    fn xmac_shortens_preamble_trains_to_neighbors_with_known_schedules() {
+        let unscheduled = xmac_second_preamble_train(false);
+        assert_eq!(unscheduled.data_sent_scheduled, 0);
+        let scheduled = xmac_second_preamble_train(true);
+        assert_eq!(scheduled.data_sent_scheduled, 1);
+        assert_eq!(scheduled.data_sent, 1);
+
+        // Preambles start just before the predicted wakeup of the neighbor,
+        // instead of as soon as the packet is sent
+        assert!(scheduled.preambles_sent <= 10);
+        assert!(unscheduled.preambles_sent > 2 * scheduled.preambles_sent);
+        assert!(scheduled.awake_ms < unscheduled.awake_ms);
+    }
+
+    /// Software encryption is not needed for unsecured frames.
+    struct NoCcm;
+
+    impl AES128CCM<'a> for NoCcm {
+        fn set_client(&'a self, _client: &'a CCMClient) {}
+
+        fn set_key(&self, _key: &[u8]) -> ReturnCode {
+            ReturnCode::ENOSUPPORT
+        }
+
+        fn set_nonce(&self, _nonce: &[u8]) -> ReturnCode {
+            ReturnCode::ENOSUPPORT
+        }
+
+        fn crypt(
+            &self,
+            buf: &'static mut [u8],
+            _a_off: usize,
+            _m_off: usize,
+            _m_len: usize,
+            _mic_len: usize,
+            _confidential: bool,
+            _encrypting: bool,
+        ) -> (ReturnCode, Option<&'static mut [u8]>) {
+            (ReturnCode::ENOSUPPORT, Some(buf))
+        }
+    }
+
+    const UDP_PORT: u16 = 5000;
+    const MAX_DGRAM: usize = 600;
+
+    type IPSender = IP6SendStruct<'static, SimAlarm<'static>>;
+
+    /// Sends and records UDP datagrams.
+    struct UdpNode {
+        udp_send: &'static UDPSendStruct<'static, IPSender>,
+        addr: IPAddr,
+        sent: Cell<Option<ReturnCode>>,
+        received: Cell<usize>,
+        payload: TakeCell<'static, [u8]>,
+        payload_len: Cell<usize>,
+        from: Cell<Option<(IPAddr, u16)>>,
+    }
+
+    impl UDPSendClient for UdpNode {
+        fn send_done(&self, result: ReturnCode) {
+            self.sent.set(Some(result));
+        }
+    }
+
+    impl UDPRecvClient for UdpNode {
+        fn receive(
+            &self,
+            src_addr: IPAddr,
+            dst_addr: IPAddr,
+            src_port: u16,
+            dst_port: u16,
+            payload: &[u8],
+        ) {
+            assert_eq!(dst_addr, self.addr);
+            assert_eq!(dst_port, UDP_PORT);
+            self.received.set(self.received.get() + 1);
+            self.from.set(Some((src_addr, src_port)));
+            self.payload_len.set(payload.len());
+            self.payload
+                .map(|buf| buf[..payload.len()].copy_from_slice(payload));
+        }
+    }
+
+    fn ip_addr(mac: u16) -> IPAddr {
+        let mut addr = [0; 16];
+        addr[0] = 0xfe;
+        addr[1] = 0x80;
+        addr[11] = 0xff;
+        addr[12] = 0xfe;
+        addr[14..16].copy_from_slice(&mac.to_be_bytes());
+        IPAddr(addr)
+    }
+
+    type SimSixlowpan = Sixlowpan<'static, SimAlarm<'static>, sixlowpan_compression::Context>;
+
+    /// The radio, MAC and 6LoWPAN layers of a node, shared by the IPv6
+    /// senders built on top of them.
+    struct Stack {
+        mac_addr: u16,
+        radio: &'static SimRadio<'static>,
+        mux_mac: &'static MuxMac<'static>,
+        sixlowpan: &'static SimSixlowpan,
+    }
+
+    fn stack(medium: &'static Medium<'static>, id: usize) -> Stack {
+        let mac_addr = Node::address(id);
+        let radio = leak(SimRadio::new(medium, id));
+        medium.add_radio(radio);
+
+        let awake_mac = leak(AwakeMac::new(&*radio));
+        radio.set_transmit_client(awake_mac);
+        radio.set_receive_client(awake_mac, zeroed(radio::MAX_BUF_SIZE));
+        let framer = leak(Framer::new(&*awake_mac, &NoCcm));
+        awake_mac.set_transmit_client(framer);
+        awake_mac.set_receive_client(framer);
+        awake_mac.set_config_client(framer);
+        let mux_mac = leak(MuxMac::new(&*framer));
+        framer.set_transmit_client(mux_mac);
+        framer.set_receive_client(mux_mac);
+
+        let clock = leak(SimAlarm::new(medium));
+        let sixlowpan = leak(Sixlowpan::new(
+            sixlowpan_compression::Context {
+                prefix: [0; 16],
+                prefix_len: 0,
+                id: 0,
+                compress: false,
+            },
+            &*clock,
+        ));
+        let sixlowpan_state = &*sixlowpan as &SixlowpanState;
+        sixlowpan_state.add_rx_state(leak(RxState::new(zeroed(1280))));
+
+        // Frames are received by all users, so only one passes them on
+        let rx_mac = leak(MacUser::new(mux_mac));
+        mux_mac.add_user(rx_mac);
+        rx_mac.set_receive_client(sixlowpan);
+        rx_mac.set_pan(PAN);
+        rx_mac.set_address(mac_addr);
+        rx_mac.config_commit();
+
+        Stack {
+            mac_addr: mac_addr,
+            radio: radio,
+            mux_mac: mux_mac,
+            sixlowpan: sixlowpan,
+        }
+    }
+
+    /// Builds an IPv6 sender on `stack` whose default link-layer next hop is
+    /// `gateway`, as on imix.
+    fn ip_sender(
+        medium: &'static Medium<'static>,
+        stack: &Stack,
+        gateway: usize,
+    ) -> &'static IPSender {
+        let mac_user = leak(MacUser::new(stack.mux_mac));
+        stack.mux_mac.add_user(mac_user);
+        let sixlowpan_tx = TxState::new(stack.sixlowpan);
+        let ip6_dg = leak(IP6Packet::new(IPPayload::new(
+            TransportHeader::UDP(UDPHeader::new()),
+            zeroed(MAX_DGRAM),
+        )));
+        let ip_alarm = leak(SimAlarm::new(medium));
+        let ip_send = leak(IP6SendStruct::new(
+            ip6_dg,
+            &*ip_alarm,
+            zeroed(radio::MAX_BUF_SIZE),
+            sixlowpan_tx,
+            &*mac_user,
+            MacAddress::Short(Node::address(gateway)),
+            MacAddress::Short(stack.mac_addr),
+        ));
+        ip_alarm.set_client(ip_send);
+        ip_send.set_addr(ip_addr(stack.mac_addr));
+        mac_user.set_transmit_client(ip_send);
+        ip_send
+    }
+
+    /// Builds the UDP layer on `ip_send`, with a socket bound to `UDP_PORT`.
+    /// The returned receiver must be given the packets for `addr`.
+    fn udp(
+        ip_send: &'static IPSender,
+        addr: IPAddr,
+    ) -> (&'static UdpNode, &'static UDPReceiver<'static>) {
+        let udp_send = leak(UDPSendStruct::new(ip_send));
+        ip_send.set_client(udp_send);
+        let sockets: &'static mut [Option<UDPSocket<'static>>] =
+            Box::leak(vec![None; 4].into_boxed_slice());
+        let port_table = leak(UDPPortTable::new(sockets));
+        let udp_recv = leak(UDPReceiver::new(port_table));
+
+        let node = leak(UdpNode {
+            udp_send: udp_send,
+            addr: addr,
+            sent: Cell::new(None),
+            received: Cell::new(0),
+            payload: TakeCell::new(zeroed(MAX_DGRAM)),
+            payload_len: Cell::new(0),
+            from: Cell::new(None),
+        });
+        udp_send.set_client(node);
+        assert_eq!(port_table.bind_kernel(node, None, UDP_PORT), Ok(UDP_PORT));
+        (node, udp_recv)
+    }
+
+    /// Builds the radio, MAC, 6LoWPAN, IPv6 and UDP layers of a node whose
+    /// link-layer next hop is `gateway`, as on imix.
+    fn udp_node(medium: &'static Medium<'static>, id: usize, gateway: usize) -> &'static UdpNode {
+        let stack = stack(medium, id);
+        let ip_send = ip_sender(medium, &stack, gateway);
+        let ip_receive = leak(IP6RecvStruct::new());
+        (&*stack.sixlowpan as &SixlowpanState).set_rx_client(ip_receive);
+        let (node, udp_recv) = udp(ip_send, ip_addr(stack.mac_addr));
+        ip_receive.set_client(udp_recv);
+        assert_eq!(stack.radio.start(), ReturnCode::SUCCESS);
+        node
+    }
+
+    fn send_datagram(from: &UdpNode, to: &UdpNode, len: usize) {
+        let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        from.sent.set(None);
+        assert_eq!(
+            from.udp_send.send_to(to.addr, UDP_PORT, UDP_PORT, &payload),
+            ReturnCode::SUCCESS
+        );
+    }
+
+    fn assert_received(node: &UdpNode, count: usize, len: usize) {
+        assert_eq!(node.received.get(), count);
+        assert_eq!(node.payload_len.get(), len);
+        node.payload.map(|buf| {
+            for (i, byte) in buf[..len].iter().enumerate() {
+                assert_eq!(*byte, i as u8);
+            }
+        });
+    }
+
+    #[test]
+    fn udp_datagrams_cross_the_full_stack() {
+        let medium = medium();
+        let a = udp_node(medium, 0, 1);
+        let b = udp_node(medium, 1, 0);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.run_for(10);
+
+        send_datagram(a, b, 40);
+        medium.run_for(32768);
+        assert_eq!(a.sent.get(), Some(ReturnCode::SUCCESS));
+        assert_received(b, 1, 40);
+        assert_eq!(b.from.get(), Some((a.addr, UDP_PORT)));
+
+        send_datagram(b, a, 20);
+        medium.run_for(32768);
+        assert_eq!(b.sent.get(), Some(ReturnCode::SUCCESS));
+        assert_received(a, 1, 20);
+    }
+
+    #[test]
+    fn fragmented_udp_datagrams_are_reassembled() {
+        let medium = medium();
+        let a = udp_node(medium, 0, 1);
+        let b = udp_node(medium, 1, 0);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.run_for(10);
+
+        send_datagram(a, b, 500);
+        medium.run_for(2 * 32768);
+        assert_eq!(a.sent.get(), Some(ReturnCode::SUCCESS));
+        assert_received(b, 1, 500);
+        assert!(medium.stats().sent > 4);
+    }
+
+    #[test]
+    fn udp_datagrams_need_a_link_to_the_next_hop() {
+        let medium = medium();
+        let a = udp_node(medium, 0, 2);
+        let b = udp_node(medium, 1, 0);
+        let c = udp_node(medium, 2, 1);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.connect(1, 2, Link::PERFECT);
+        medium.run_for(10);
+
+        // Node 2 is out of range of node 0, and node 1 does not forward
+        send_datagram(a, c, 40);
+        medium.run_for(32768);
+        assert_eq!(a.sent.get(), Some(ReturnCode::SUCCESS));
+        assert_eq!(b.received.get(), 0);
+        assert_eq!(c.received.get(), 0);
+
+        send_datagram(c, b, 40);
+        medium.run_for(32768);
+        assert_received(b, 1, 40);
+        send_datagram(b, a, 40);
+        medium.run_for(32768);
+        assert_received(a, 1, 40);
+    }
+
+    /// Builds a node like `udp_node` that also forwards mesh-under frames.
+    /// Each route in `routes` maps a final destination to the neighbor that
+    /// frames for it are sent to, and is used both for this node's own
+    /// datagrams and for the ones it forwards.
+    fn mesh_node(
+        medium: &'static Medium<'static>,
+        id: usize,
+        gateway: usize,
+        routes: &[(usize, usize)],
+    ) -> &'static UdpNode {
+        let stack = stack(medium, id);
+        let table = leak(StaticForwardingTable::new(leak([None; 4])));
+        for &(final_dst, next_hop) in routes {
+            assert_eq!(
+                table.add_route(
+                    MacAddress::Short(Node::address(final_dst)),
+                    MacAddress::Short(Node::address(next_hop))
+                ),
+                ReturnCode::SUCCESS
+            );
+        }
+
+        let mesh_mac = leak(MacUser::new(stack.mux_mac));
+        stack.mux_mac.add_user(mesh_mac);
+        let mesh_dgram = leak(IP6Packet::new(IPPayload::new(
+            TransportHeader::UDP(UDPHeader::new()),
+            zeroed(MAX_DGRAM),
+        )));
+        let forwarder = leak(MeshForwarder::new(
+            &*mesh_mac,
+            &*table,
+            TxState::new(stack.sixlowpan),
+            mesh_dgram,
+            zeroed(radio::MAX_BUF_SIZE),
+        ));
+        mesh_mac.set_transmit_client(forwarder);
+        stack.sixlowpan.set_mesh_forwarder(forwarder);
+
+        let ip_send = ip_sender(medium, &stack, gateway);
+        ip_send.set_mesh_table(table);
+        let ip_receive = leak(IP6RecvStruct::new());
+        (&*stack.sixlowpan as &SixlowpanState).set_rx_client(ip_receive);
+        let (node, udp_recv) = udp(ip_send, ip_addr(stack.mac_addr));
+        ip_receive.set_client(udp_recv);
+        assert_eq!(stack.radio.start(), ReturnCode::SUCCESS);
+        node
+    }
+
+    #[test]
+    fn mesh_datagrams_are_forwarded_over_several_hops() {
+        let medium = medium();
+        // 0 - 1 - 2 - 3, where only neighbors are in range
+        let a = mesh_node(medium, 0, 3, &[(3, 1)]);
+        let b = mesh_node(medium, 1, 0, &[(3, 2), (0, 0)]);
+        let c = mesh_node(medium, 2, 0, &[(3, 3), (0, 1)]);
+        let d = mesh_node(medium, 3, 0, &[(0, 2)]);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.connect(1, 2, Link::PERFECT);
+        medium.connect(2, 3, Link::PERFECT);
+        medium.run_for(10);
+
+        send_datagram(a, d, 40);
+        medium.run_for(SECOND);
+        assert_eq!(a.sent.get(), Some(ReturnCode::SUCCESS));
+        assert_received(d, 1, 40);
+        assert_eq!(d.from.get(), Some((a.addr, UDP_PORT)));
+        assert_eq!(b.received.get(), 0);
+        assert_eq!(c.received.get(), 0);
+        assert_eq!(medium.stats().sent, 3);
+
+        // Large datagrams are reassembled and fragmented again at every hop
+        send_datagram(d, a, 500);
+        medium.run_for(4 * SECOND);
+        assert_eq!(d.sent.get(), Some(ReturnCode::SUCCESS));
+        assert_received(a, 1, 500);
+        assert_eq!(a.from.get(), Some((d.addr, UDP_PORT)));
+        assert_eq!(b.received.get(), 0);
+        assert_eq!(c.received.get(), 0);
+        assert!(medium.stats().sent > 3 + 3 * 4);
+    }
+
+    #[test]
+    fn mesh_datagrams_are_dropped_when_their_hops_run_out() {
+        let medium = medium();
+        // Nodes 1 and 2 send datagrams for the missing node 9 to each other
+        let a = mesh_node(medium, 0, 9, &[(9, 1)]);
+        mesh_node(medium, 1, 0, &[(9, 2)]);
+        mesh_node(medium, 2, 0, &[(9, 1)]);
+        medium.connect_all(3, Link::PERFECT);
+        medium.run_for(10);
+
+        let payload = [0; 40];
+        assert_eq!(
+            a.udp_send
+                .send_to(ip_addr(Node::address(9)), UDP_PORT, UDP_PORT, &payload),
+            ReturnCode::SUCCESS
+        );
+        medium.run_for(4 * SECOND);
+        // The original frame, and one for each hop until a node receives
+        // the datagram with a single hop left
+        assert_eq!(
+            medium.stats().sent,
+            1 + lowpan_mesh::DEFAULT_HOPS_LEFT as usize - 1
+        );
+    }
+
+    /// Ticks of the medium clock in a second.
+    const SECOND: u32 = 32768;
+
+    /// The /64 prefix advertised by RPL roots.
+    const PREFIX: IPAddr = IPAddr([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    /// Rank added by each hop with the default OF0 parameters.
+    const HOP_RANK: u16 = 3 * ROOT_RANK;
+
+    /// The address a node configures from `PREFIX`.
+    fn global_addr(id: usize) -> IPAddr {
+        let mut addr = ip_addr(Node::address(id));
+        addr.set_prefix(&PREFIX.0, 64);
+        addr
+    }
+
+    /// A node routing with RPL, with UDP on top.
+    struct RplTestNode {
+        rpl: &'static RplNode<'static, SimAlarm<'static>>,
+        udp_ip_send: &'static IPSender,
+        udp: &'static UdpNode,
+    }
+
+    /// Builds a node whose RPL messages, forwarded packets and UDP datagrams
+    /// are sent to the next hop chosen by RPL, as described in `rpl_node`.
+    fn rpl_node(medium: &'static Medium<'static>, id: usize) -> RplTestNode {
+        let stack = stack(medium, id);
+        let rpl_ip_send = ip_sender(medium, &stack, id);
+        let udp_ip_send = ip_sender(medium, &stack, id);
+        let rpl_alarm = leak(SimAlarm::new(medium));
+        let rpl = leak(RplNode::new(
+            &*rpl_alarm,
+            rpl_ip_send,
+            MacAddress::Short(stack.mac_addr),
+            zeroed(128),
+            Box::leak(vec![None; 4].into_boxed_slice()),
+            Box::leak(vec![None; 8].into_boxed_slice()),
+        ));
+        rpl_alarm.set_client(rpl);
+        rpl_ip_send.set_client(rpl);
+        rpl_ip_send.set_next_hop_lookup(rpl);
+        udp_ip_send.set_next_hop_lookup(rpl);
+
+        let ip_receive = leak(IP6RecvStruct::new());
+        (&*stack.sixlowpan as &SixlowpanState).set_rx_client(ip_receive);
+        ip_receive.set_client(rpl);
+        let (udp, udp_recv) = udp(udp_ip_send, global_addr(id));
+        rpl.set_client(udp_recv);
+        assert_eq!(stack.radio.start(), ReturnCode::SUCCESS);
+        RplTestNode {
+            rpl: rpl,
+            udp_ip_send: udp_ip_send,
+            udp: udp,
+        }
+    }
+
+    /// Builds `count` RPL nodes, with node 0 as the root. The other nodes are
+    /// started but not yet joined.
+    fn rpl_network(medium: &'static Medium<'static>, count: usize) -> Vec<RplTestNode> {
+        let nodes: Vec<RplTestNode> = (0..count).map(|id| rpl_node(medium, id)).collect();
+        medium.run_for(10);
+        assert_eq!(
+            nodes[0].rpl.start_root(global_addr(0), PREFIX, 64),
+            ReturnCode::SUCCESS
+        );
+        for node in nodes[1..].iter() {
+            assert_eq!(node.rpl.start(), ReturnCode::SUCCESS);
+        }
+        nodes
+    }
+
+    fn assert_parent(node: &RplTestNode, parent: usize, rank: u16) {
+        assert!(node.rpl.is_joined());
+        assert_eq!(
+            node.rpl.get_preferred_parent(),
+            Some(ip_addr(Node::address(parent)))
+        );
+        assert_eq!(node.rpl.get_rank(), rank);
+    }
+
+    #[test]
+    fn rpl_nodes_join_through_dis_dio_and_dao() {
+        let medium = medium();
+        let root = rpl_node(medium, 0);
+        let leaf = rpl_node(medium, 1);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.run_for(10);
+        assert_eq!(
+            root.rpl.start_root(global_addr(0), PREFIX, 64),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(root.rpl.get_global_addr(), Some(global_addr(0)));
+
+        // Once the trickle timer of the root has backed off, a new node only
+        // hears a DIO soon because its DIS resets the timer
+        medium.run_for(600 * SECOND);
+        assert_eq!(leaf.rpl.start(), ReturnCode::SUCCESS);
+        assert!(!leaf.rpl.is_joined());
+        medium.run_for(15 * SECOND);
+        assert_parent(&leaf, 0, ROOT_RANK + HOP_RANK);
+        assert_eq!(leaf.rpl.get_global_addr(), Some(global_addr(1)));
+
+        // The DAO the leaf sends after joining installs a downward route at
+        // the root
+        assert_eq!(
+            root.rpl.next_hop(global_addr(1)),
+            Some(MacAddress::Short(Node::address(1)))
+        );
+        assert_eq!(
+            leaf.rpl.next_hop(global_addr(0)),
+            Some(MacAddress::Short(Node::address(0)))
+        );
+    }
+
+    #[test]
+    fn rpl_nodes_prefer_the_parent_closest_to_the_root() {
+        let medium = medium();
+        let nodes = rpl_network(medium, 4);
+        // 0 - 1 - 2 - 3, where 3 can also reach 1 directly
+        medium.connect(0, 1, Link::PERFECT);
+        medium.connect(1, 2, Link::PERFECT);
+        medium.connect(2, 3, Link::PERFECT);
+        medium.connect(1, 3, Link::PERFECT);
+        medium.run_for(60 * SECOND);
+
+        assert_parent(&nodes[1], 0, ROOT_RANK + HOP_RANK);
+        assert_parent(&nodes[2], 1, ROOT_RANK + 2 * HOP_RANK);
+        assert_parent(&nodes[3], 1, ROOT_RANK + 2 * HOP_RANK);
+    }
+
+    #[test]
+    fn udp_datagrams_follow_rpl_routes_over_several_hops() {
+        let medium = medium();
+        let nodes = rpl_network(medium, 3);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.connect(1, 2, Link::PERFECT);
+        medium.run_for(60 * SECOND);
+        assert_parent(&nodes[2], 1, ROOT_RANK + 2 * HOP_RANK);
+        for (id, node) in nodes.iter().enumerate() {
+            node.udp_ip_send.set_addr(global_addr(id));
+        }
+        let (root, leaf) = (nodes[0].udp, nodes[2].udp);
+
+        // Up through the default route of each node to the border router
+        send_datagram(leaf, root, 40);
+        medium.run_for(2 * SECOND);
+        assert_eq!(leaf.sent.get(), Some(ReturnCode::SUCCESS));
+        assert_received(root, 1, 40);
+        assert_eq!(root.from.get(), Some((global_addr(2), UDP_PORT)));
+        assert_eq!(nodes[1].udp.received.get(), 0);
+
+        // And back down the routes learned from DAOs
+        send_datagram(root, leaf, 30);
+        medium.run_for(2 * SECOND);
+        assert_eq!(root.sent.get(), Some(ReturnCode::SUCCESS));
+        assert_received(leaf, 1, 30);
+        assert_eq!(leaf.from.get(), Some((global_addr(0), UDP_PORT)));
+    }
+
+    /// Stands in for AES-CCM. Messages are left in the clear and
+    /// authenticated with a keyed checksum, which `mle_mic` computes for the
+    /// messages that tests build by hand. Operations complete on the next
+    /// tick of the medium clock, as with a hardware engine.
+    struct SimCcm {
+        alarm: &'static SimAlarm<'static>,
+        client: OptionalCell<&'static CCMClient>,
+        key: Cell<[u8; 16]>,
+        nonce: Cell<[u8; CCM_NONCE_LENGTH]>,
+        buf: TakeCell<'static, [u8]>,
+        tag_is_valid: Cell<bool>,
+    }
+
+    fn mle_mic(key: &[u8], nonce: &[u8], data: &[u8]) -> [u8; 4] {
+        let mut mic = [0u8; 4];
+        for (i, byte) in key.iter().chain(nonce).chain(data).enumerate() {
+            mic[i % 4] = mic[i % 4].rotate_left(3) ^ byte;
+        }
+        mic
+    }
+
+    impl AES128CCM<'static> for SimCcm {
+        fn set_client(&'static self, client: &'static CCMClient) {
+            self.client.set(client);
+        }
+
+        fn set_key(&self, key: &[u8]) -> ReturnCode {
+            let mut k = [0; 16];
+            k.copy_from_slice(key);
+            self.key.set(k);
+            ReturnCode::SUCCESS
+        }
+
+        fn set_nonce(&self, nonce: &[u8]) -> ReturnCode {
+            let mut n = [0; CCM_NONCE_LENGTH];
+            n.copy_from_slice(nonce);
+            self.nonce.set(n);
+            ReturnCode::SUCCESS
+        }
+
+        fn crypt(
+            &self,
+            buf: &'static mut [u8],
+            a_off: usize,
+            m_off: usize,
+            m_len: usize,
+            mic_len: usize,
+            _confidential: bool,
+            encrypting: bool,
+        ) -> (ReturnCode, Option<&'static mut [u8]>) {
+            let end = m_off + m_len;
+            let mic = mle_mic(&self.key.get(), &self.nonce.get(), &buf[a_off..end]);
+            if encrypting {
+                buf[end..end + mic_len].copy_from_slice(&mic[..mic_len]);
+                self.tag_is_valid.set(true);
+            } else {
+                self.tag_is_valid
+                    .set(buf[end..end + mic_len] == mic[..mic_len]);
+            }
+            self.buf.replace(buf);
+            self.alarm.set_alarm(self.alarm.now().wrapping_add(1));
+            (ReturnCode::SUCCESS, None)
+        }
+    }
+
+    impl time::Client for SimCcm {
+        fn fired(&self) {
+            self.buf.take().map(|buf| {
+                self.client.map(move |client| {
+                    client.crypt_done(buf, ReturnCode::SUCCESS, self.tag_is_valid.get())
+                });
+            });
+        }
+    }
+
+    const MLE_KEY: [u8; 16] = [7; 16];
+    const MLE_KEY_SEQUENCE: u32 = 1;
+
+    /// The nonce of a message from `src`, as built by `Mle`.
+    fn mle_nonce(src: &IPAddr, frame_counter: u32) -> [u8; CCM_NONCE_LENGTH] {
+        let mut nonce = [0; CCM_NONCE_LENGTH];
+        nonce[..8].copy_from_slice(&src.0[8..16]);
+        nonce[0] ^= 0b00000010;
+        nonce[8..12].copy_from_slice(&frame_counter.to_be_bytes());
+        nonce[12] = 5;
+        nonce
+    }
+
+    /// A Thread router that answers the Parent Requests and Child ID
+    /// Requests of a sleepy end device with hand-built MLE messages.
+    struct MleParent {
+        udp_send: OptionalCell<&'static UDPSendStruct<'static, IPSender>>,
+        addr: IPAddr,
+        rloc16: u16,
+        child_rloc16: u16,
+        challenge: [u8; 8],
+        frame_counter: Cell<u32>,
+        // Whether to corrupt the MIC of the messages sent
+        tamper: Cell<bool>,
+        parent_requests: Cell<usize>,
+        child_id_requests: Cell<usize>,
+    }
+
+    impl MleParent {
+        fn send(&self, dst: IPAddr, command: u8, tlvs: &[Tlv]) {
+            let frame_counter = self.frame_counter.get();
+            self.frame_counter.set(frame_counter + 1);
+            let mut aux_hdr = vec![0x15];
+            aux_hdr.extend_from_slice(&frame_counter.to_le_bytes());
+            aux_hdr.extend_from_slice(&MLE_KEY_SEQUENCE.to_be_bytes());
+            aux_hdr.push((MLE_KEY_SEQUENCE & 0x7f) as u8 + 1);
+            let mut msg = vec![command];
+            for tlv in tlvs {
+                let mut buf = [0; 64];
+                let (len, _) = tlv.encode(&mut buf).done().unwrap();
+                msg.extend_from_slice(&buf[..len]);
+            }
+
+            let mut authenticated = self.addr.0.to_vec();
+            authenticated.extend_from_slice(&dst.0);
+            authenticated.extend_from_slice(&aux_hdr);
+            authenticated.extend_from_slice(&msg);
+            let mut mic = mle_mic(
+                &MLE_KEY,
+                &mle_nonce(&self.addr, frame_counter),
+                &authenticated,
+            );
+            if self.tamper.get() {
+                mic[0] ^= 1;
+            }
+
+            let mut payload = vec![0];
+            payload.extend_from_slice(&aux_hdr);
+            payload.extend_from_slice(&msg);
+            payload.extend_from_slice(&mic);
+            self.udp_send.map(|udp_send| {
+                assert_eq!(
+                    udp_send.send_to(dst, MLE_PORT, MLE_PORT, &payload),
+                    ReturnCode::SUCCESS
+                );
+            });
+        }
+    }
+
+    impl UDPSendClient for MleParent {
+        fn send_done(&self, result: ReturnCode) {
+            assert_eq!(result, ReturnCode::SUCCESS);
+        }
+    }
+
+    impl UDPRecvClient for MleParent {
+        fn receive(
+            &self,
+            src_addr: IPAddr,
+            dst_addr: IPAddr,
+            _src_port: u16,
+            _dst_port: u16,
+            payload: &[u8],
+        ) {
+            // Check the message as the child secured it
+            let (aux_hdr, rest) = payload[1..].split_at(10);
+            let (msg, mic) = rest.split_at(rest.len() - 4);
+            let mut frame_counter = [0; 4];
+            frame_counter.copy_from_slice(&aux_hdr[1..5]);
+            let mut authenticated = src_addr.0.to_vec();
+            authenticated.extend_from_slice(&dst_addr.0);
+            authenticated.extend_from_slice(aux_hdr);
+            authenticated.extend_from_slice(msg);
+            let nonce = mle_nonce(&src_addr, u32::from_le_bytes(frame_counter));
+            assert_eq!(mic, mle_mic(&MLE_KEY, &nonce, &authenticated));
+
+            let mut tlvs = Vec::new();
+            let mut off = 1;
+            while off < msg.len() {
+                let end = off + 2 + msg[off + 1] as usize;
+                tlvs.push(Tlv::decode(&msg[off..end]).done().unwrap().1);
+                off = end;
+            }
+            let challenge = tlvs.iter().find_map(|tlv| match tlv {
+                Tlv::Challenge(challenge) => Some(*challenge),
+                _ => None,
+            });
+            let response = tlvs.iter().find_map(|tlv| match tlv {
+                Tlv::Response(response) => Some(*response),
+                _ => None,
+            });
+
+            match msg[0] {
+                mle_command::PARENT_REQUEST => {
+                    assert_eq!(dst_addr.0[..2], [0xff, 0x02]);
+                    self.parent_requests.set(self.parent_requests.get() + 1);
+                    self.send(
+                        src_addr,
+                        mle_command::PARENT_RESPONSE,
+                        &[
+                            Tlv::SourceAddress(self.rloc16),
+                            Tlv::Challenge(self.challenge),
+                            Tlv::Response(challenge.unwrap()),
+                            Tlv::LinkMargin(30),
+                            Tlv::Connectivity {
+                                parent_priority: 0,
+                                link_quality_3: 1,
+                                link_quality_2: 0,
+                                link_quality_1: 0,
+                                leader_cost: 0,
+                                id_sequence: 0,
+                                active_routers: 1,
+                                sed_buffer_size: None,
+                                sed_datagram_count: None,
+                            },
+                        ],
+                    );
+                }
+                mle_command::CHILD_ID_REQUEST => {
+                    assert_eq!(response, Some(self.challenge));
+                    self.child_id_requests.set(self.child_id_requests.get() + 1);
+                    self.send(
+                        src_addr,
+                        mle_command::CHILD_ID_RESPONSE,
+                        &[
+                            Tlv::SourceAddress(self.rloc16),
+                            Tlv::Address16(self.child_rloc16),
+                        ],
+                    );
+                }
+                command => panic!("unexpected MLE command {}", command),
+            }
+        }
+    }
+
+    /// Records the attach events of an MLE child.
+    #[derive(Default)]
+    struct MleEvents {
+        attach_done: Cell<Option<ReturnCode>>,
+        detached: Cell<usize>,
+    }
+
+    impl MleClient for MleEvents {
+        fn attach_done(&self, result: ReturnCode) {
+            self.attach_done.set(Some(result));
+        }
+
+        fn detached(&self) {
+            self.detached.set(self.detached.get() + 1);
+        }
+    }
+
+    type SimMle = Mle<'static, SimAlarm<'static>>;
+
+    /// The network layers that MLE messages are sent and received through.
+    struct MleStack {
+        ip_send: &'static IPSender,
+        udp_send: &'static UDPSendStruct<'static, IPSender>,
+        port_table: &'static UDPPortTable<'static>,
+        mac_user: &'static MacUser<'static>,
+    }
+
+    fn mle_stack(medium: &'static Medium<'static>, id: usize, gateway: usize) -> MleStack {
+        let stack = stack(medium, id);
+        let ip_send = ip_sender(medium, &stack, gateway);
+        let udp_send = leak(UDPSendStruct::new(ip_send));
+        ip_send.set_client(udp_send);
+        let sockets: &'static mut [Option<UDPSocket<'static>>] =
+            Box::leak(vec![None; 2].into_boxed_slice());
+        let port_table = leak(UDPPortTable::new(sockets));
+        let udp_recv = leak(UDPReceiver::new(port_table));
+        let ip_receive = leak(IP6RecvStruct::new());
+        (&*stack.sixlowpan as &SixlowpanState).set_rx_client(ip_receive);
+        ip_receive.set_client(udp_recv);
+
+        let mac_user = leak(MacUser::new(stack.mux_mac));
+        stack.mux_mac.add_user(mac_user);
+        mac_user.set_address_long([0x02, 0, 0, 0, 0, 0, 0x10, id as u8]);
+        mac_user.config_commit();
+        assert_eq!(stack.radio.start(), ReturnCode::SUCCESS);
+        MleStack {
+            ip_send: ip_send,
+            udp_send: udp_send,
+            port_table: port_table,
+            mac_user: mac_user,
+        }
+    }
+
+    fn mle_parent(medium: &'static Medium<'static>, id: usize, child: usize) -> &'static MleParent {
+        let parent = leak(MleParent {
+            udp_send: OptionalCell::empty(),
+            addr: ip_addr(Node::address(id)),
+            rloc16: Node::address(id),
+            child_rloc16: Node::address(child),
+            challenge: [0xc0, 0xff, 0xee, 0, 0, 0, 0, id as u8],
+            frame_counter: Cell::new(100),
+            tamper: Cell::new(false),
+            parent_requests: Cell::new(0),
+            child_id_requests: Cell::new(0),
+        });
+        let stack = mle_stack(medium, id, child);
+        stack.udp_send.set_client(parent);
+        parent.udp_send.set(stack.udp_send);
+        assert_eq!(
+            stack.port_table.bind_kernel(parent, None, MLE_PORT),
+            Ok(MLE_PORT)
+        );
+        parent
+    }
+
+    fn mle_child(
+        medium: &'static Medium<'static>,
+        id: usize,
+        parent: usize,
+    ) -> (&'static SimMle, &'static MleEvents) {
+        let ccm_alarm = leak(SimAlarm::new(medium));
+        let ccm = leak(SimCcm {
+            alarm: ccm_alarm,
+            client: OptionalCell::empty(),
+            key: Cell::new([0; 16]),
+            nonce: Cell::new([0; CCM_NONCE_LENGTH]),
+            buf: TakeCell::empty(),
+            tag_is_valid: Cell::new(false),
+        });
+        ccm_alarm.set_client(ccm);
+        let stack = mle_stack(medium, id, parent);
+
+        let alarm = leak(SimAlarm::new(medium));
+        let mle = leak(Mle::new(
+            &*alarm,
+            stack.udp_send,
+            stack.mac_user,
+            &*ccm,
+            zeroed(128),
+            zeroed(radio::MAX_BUF_SIZE),
+        ));
+        alarm.set_client(mle);
+        stack.udp_send.set_client(mle);
+        stack.mac_user.set_transmit_client(mle);
+        ccm.set_client(mle);
+        assert_eq!(
+            stack.port_table.bind_kernel(mle, None, MLE_PORT),
+            Ok(MLE_PORT)
+        );
+        // MLE messages are authenticated with the address derived from the
+        // extended address
+        stack.ip_send.set_addr(mle.get_link_local_addr());
+
+        let events = leak(MleEvents::default());
+        mle.set_client(events);
+        mle.set_key(MLE_KEY_SEQUENCE, MLE_KEY);
+        (mle, events)
+    }
+
+    #[test]
+    fn mle_children_attach_through_parent_and_child_id_exchanges() {
+        let medium = medium();
+        let parent = mle_parent(medium, 0, 1);
+        let (mle, events) = mle_child(medium, 1, 0);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.run_for(10);
+
+        assert_eq!(mle.start(), ReturnCode::SUCCESS);
+        medium.run_for(SECOND / 2);
+        assert_eq!(parent.parent_requests.get(), 1);
+        assert_eq!(parent.child_id_requests.get(), 0);
+        assert!(!mle.is_attached());
+
+        // The child waits for other responses before picking a parent
+        medium.run_for(SECOND);
+        assert_eq!(parent.child_id_requests.get(), 1);
+        assert_eq!(events.attach_done.get(), Some(ReturnCode::SUCCESS));
+        assert!(mle.is_attached());
+        assert_eq!(mle.get_rloc16(), Some(Node::address(1)));
+
+        // Data polls to the parent are acknowledged, so the child stays
+        let polls_before = medium.stats().sent;
+        medium.run_for(5 * DEFAULT_POLL_PERIOD_MS / 1000 * SECOND);
+        assert!(medium.stats().sent >= polls_before + 5);
+        assert!(mle.is_attached());
+        assert_eq!(events.detached.get(), 0);
+        assert_eq!(parent.parent_requests.get(), 1);
+    }
+
+    #[test]
+    fn mle_children_ignore_parents_that_fail_authentication() {
+        let medium = medium();
+        let parent = mle_parent(medium, 0, 1);
+        let (mle, events) = mle_child(medium, 1, 0);
+        medium.connect(0, 1, Link::PERFECT);
+        medium.run_for(10);
+        parent.tamper.set(true);
+
+        // Each attempt sends a Parent Request to routers, then to routers
+        // and REEDs, and the attempts are spread out by a backoff
+        assert_eq!(mle.start(), ReturnCode::SUCCESS);
+        medium.run_for(30 * SECOND);
+        assert_eq!(parent.parent_requests.get(), 6);
+        assert_eq!(parent.child_id_requests.get(), 0);
+        assert_eq!(events.attach_done.get(), Some(ReturnCode::FAIL));
+        assert!(!mle.is_attached());
+
+        // Once responses check out, a new attach succeeds
+        parent.tamper.set(false);
+        assert_eq!(mle.start(), ReturnCode::SUCCESS);
+        medium.run_for(2 * SECOND);
+        assert_eq!(events.attach_done.get(), Some(ReturnCode::SUCCESS));
+    }
+
+    type SimScanner = Scanner<'static, SimAlarm<'static>>;
+
+    /// A node scanning channels, or responding to beacon requests.
+    struct ScanNode {
+        stack: Stack,
+        scanner: &'static SimScanner,
+        mac_user: &'static MacUser<'static>,
+        done: Cell<Option<(ScanType, ReturnCode, usize)>>,
+    }
+
+    impl ScanClient for ScanNode {
+        fn scan_done(&self, scan_type: ScanType, result: ReturnCode, num_results: usize) {
+            self.done.set(Some((scan_type, result, num_results)));
+        }
+    }
+
+    fn scan_node(medium: &'static Medium<'static>, id: usize) -> &'static ScanNode {
+        let stack = stack(medium, id);
+        let mac_user = leak(MacUser::new(stack.mux_mac));
+        stack.mux_mac.add_user(mac_user);
+        let alarm = leak(SimAlarm::new(medium));
+        let scanner = leak(Scanner::new(
+            &*mac_user,
+            &*alarm,
+            zeroed(radio::MAX_BUF_SIZE),
+        ));
+        mac_user.set_transmit_client(scanner);
+        mac_user.set_receive_client(scanner);
+        alarm.set_client(scanner);
+        scanner.set_energy_detect(stack.radio);
+        stack.radio.set_energy_client(scanner);
+        assert_eq!(stack.radio.start(), ReturnCode::SUCCESS);
+
+        let node = leak(ScanNode {
+            stack: stack,
+            scanner: scanner,
+            mac_user: mac_user,
+            done: Cell::new(None),
+        });
+        scanner.set_client(node);
+        node
+    }
+
+    /// Moves a node to the PAN and channel it coordinates, and has it respond
+    /// to beacon requests.
+    fn coordinate(node: &ScanNode, pan: u16, channel: u8) -> SuperframeSpec {
+        let spec = SuperframeSpec::nonbeacon(true, true);
+        node.mac_user.set_pan(pan);
+        assert_eq!(node.mac_user.set_channel(channel), ReturnCode::SUCCESS);
+        node.mac_user.config_commit();
+        node.scanner.set_beacon_response(Some(spec));
+        spec
+    }
+
+    fn channels(channels: &[u8]) -> u32 {
+        channels
+            .iter()
+            .fold(0, |mask, channel| mask | 1u32 << channel)
+    }
+
+    /// Ticks spent on each channel by a scan of duration 0.
+    const DWELL: u32 = 1006;
+
+    #[test]
+    fn energy_detection_scans_find_the_busy_channels() {
+        let medium = medium();
+        let node = scan_node(medium, 0);
+        let busy = Node::new(medium, 1);
+        medium.connect_all(2, Link::PERFECT);
+        assert_eq!(busy.radio.set_channel(15), ReturnCode::SUCCESS);
+        medium.run_for(10);
+
+        let mask = channels(&[14, 15, 16]);
+        assert_eq!(
+            node.scanner.scan(ScanType::EnergyDetect, mask, 0),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(
+            node.scanner.scan(ScanType::EnergyDetect, mask, 0),
+            ReturnCode::EBUSY
+        );
+        for _ in 0..(3 * DWELL / 10 + 10) {
+            if busy.tx_buf.is_some() {
+                assert_eq!(busy.send(BROADCAST, false, 100), ReturnCode::SUCCESS);
+            }
+            medium.run_for(10);
+        }
+
+        assert_eq!(
+            node.done.get(),
+            Some((ScanType::EnergyDetect, ReturnCode::SUCCESS, 3))
+        );
+        assert_eq!(node.scanner.get_energy_level(14), Some(0));
+        assert_eq!(
+            node.scanner.get_energy_level(15),
+            Some(rssi_to_level(Link::PERFECT.rssi))
+        );
+        assert_eq!(node.scanner.get_energy_level(16), Some(0));
+        assert_eq!(node.scanner.get_energy_level(17), None);
+        // The radio is back on its own channel and PAN
+        assert_eq!(node.stack.radio.get_channel(), 26);
+        assert_eq!(node.stack.radio.get_pan(), PAN);
+    }
+
+    #[test]
+    fn active_scans_find_coordinators_that_respond_to_beacon_requests() {
+        let medium = medium();
+        let nodes: Vec<&'static ScanNode> = (0..4).map(|id| scan_node(medium, id)).collect();
+        medium.connect_all(4, Link::PERFECT);
+        let spec = coordinate(nodes[0], PAN, 26);
+        coordinate(nodes[2], 0x1234, 25);
+        // A coordinator that does not respond to beacon requests
+        coordinate(nodes[3], 0x5678, 24);
+        nodes[3].scanner.set_beacon_response(None);
+        medium.run_for(10);
+
+        assert_eq!(
+            nodes[1]
+                .scanner
+                .scan(ScanType::Active, channels(&[24, 25, 26]), 0),
+            ReturnCode::SUCCESS
+        );
+        medium.run_for(3 * DWELL + 10);
+
+        assert_eq!(
+            nodes[1].done.get(),
+            Some((ScanType::Active, ReturnCode::SUCCESS, 2))
+        );
+        let found = nodes[1].scanner.get_pan_descriptor(0).unwrap();
+        assert_eq!(found.channel, 25);
+        assert_eq!(found.coord_pan, 0x1234);
+        assert_eq!(found.coord_addr, MacAddress::Short(Node::address(2)));
+        assert_eq!(found.superframe_spec, spec);
+        let found = nodes[1].scanner.get_pan_descriptor(1).unwrap();
+        assert_eq!(found.channel, 26);
+        assert_eq!(found.coord_pan, PAN);
+        assert_eq!(found.coord_addr, MacAddress::Short(Node::address(0)));
+        assert!(!found.security_enabled);
+        assert_eq!(nodes[1].scanner.get_pan_descriptor(2), None);
+        assert_eq!(nodes[1].stack.radio.get_channel(), 26);
+        assert_eq!(nodes[1].stack.radio.get_pan(), PAN);
+    }
+
+    #[test]
+    fn passive_scans_only_record_the_beacons_they_hear() {
+        let medium = medium();
+        let nodes: Vec<&'static ScanNode> = (0..3).map(|id| scan_node(medium, id)).collect();
+        medium.connect_all(3, Link::PERFECT);
+        coordinate(nodes[0], PAN, 26);
+        medium.run_for(10);
+
+        // Coordinators of nonbeacon-enabled PANs stay silent by themselves
+        let mask = channels(&[26]);
+        assert_eq!(
+            nodes[1].scanner.scan(ScanType::Passive, mask, 0),
+            ReturnCode::SUCCESS
+        );
+        medium.run_for(DWELL + 10);
+        assert_eq!(
+            nodes[1].done.get(),
+            Some((ScanType::Passive, ReturnCode::SUCCESS, 0))
+        );
+
+        // But a passive scan hears the beacon sent in response to the beacon
+        // request of another node
+        assert_eq!(
+            nodes[1].scanner.scan(ScanType::Passive, mask, 2),
+            ReturnCode::SUCCESS
+        );
+        medium.run_for(100);
+        assert_eq!(
+            nodes[2].scanner.scan(ScanType::Active, mask, 0),
+            ReturnCode::SUCCESS
+        );
+        medium.run_for(5 * DWELL);
+        assert_eq!(
+            nodes[1].done.get(),
+            Some((ScanType::Passive, ReturnCode::SUCCESS, 1))
+        );
+        assert_eq!(
+            nodes[1]
+                .scanner
+                .get_pan_descriptor(0)
+                .map(|pan| pan.coord_addr),
+            Some(MacAddress::Short(Node::address(0)))
+        );
+        assert_eq!(
+            nodes[2].done.get(),
+            Some((ScanType::Active, ReturnCode::SUCCESS, 1))
+        );
+    }
+}
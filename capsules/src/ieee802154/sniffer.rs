@@ -0,0 +1,399 @@
+//! IEEE 802.15.4 packet capture.
+//!
+//! `Sniffer` taps a `MuxMac` and streams every frame it sends and receives
+//! in the libpcap file format, with link type `LINKTYPE_IEEE802_15_4` (195),
+//! over a UART such as a `virtual_uart::UartDevice` or `segger_rtt`. The
+//! capture can be loaded straight into Wireshark, either from a file or live
+//! from the serial port:
+//!
+//! ```text
+//! $ stty -F /dev/ttyUSB1 115200 raw
+//! $ wireshark -k -i /dev/ttyUSB1
+//! ```
+//!
+//! The UART should not carry anything else, such as the console or debug
+//! output, or the capture will be corrupted.
+//!
+//! Transmitted frames are captured as they were sent over the air, and
+//! received frames as they were delivered by the MAC device, that is, after
+//! they have been unsecured. Since the radio filters frames by destination,
+//! only the frames addressed to this device, broadcast frames and beacons are
+//! captured. The frame check sequence is computed by the sniffer.
+//!
+//! Frames are queued in a buffer while the UART is busy; frames that do not
+//! fit are dropped and counted.
+//!
+//! The sniffer is controlled with the `sniff` command of the process console:
+//!
+//! - `sniff start`: write the pcap file header and start capturing
+//! - `sniff stop`: stop capturing
+//! - `sniff channel <n>`: switch the radio to channel `n`
+//! - `sniff pan <id|any>`: only capture frames to or from PAN `id`
+//! - `sniff addr <short address|any>`: only capture frames to or from a short
+//!   address
+//! - `sniff type <data|beacon|ack|command|any>`: only capture one frame type
+//! - `sniff status`: print the configuration and capture statistics
+//!
+//! Numbers can be given in decimal or in hexadecimal with a `0x` prefix.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let sniffer_uart = static_init!(UartDevice, UartDevice::new(uart_mux, false));
+//! sniffer_uart.setup();
+//! let sniffer_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let sniffer = static_init!(
+//!     Sniffer<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     Sniffer::new(sniffer_uart, sniffer_alarm, radio_mac, &mut QUEUE_BUF, &mut TX_BUF)
+//! );
+//! hil::uart::Transmit::set_transmit_client(sniffer_uart, sniffer);
+//! mux_mac.set_tap(sniffer);
+//! pconsole.set_command(sniffer);
+//! ```
+
+use crate::ieee802154::device::MacDevice;
+use crate::ieee802154::virtual_mac::FrameTap;
+use crate::net::ieee802154::{FrameType, Header, MacAddress, PanID};
+use crate::process_console::ConsoleCommand;
+use core::cell::Cell;
+use core::cmp::min;
+use kernel::common::cells::TakeCell;
+use kernel::debug;
+use kernel::hil::time::{Alarm, Frequency};
+use kernel::hil::uart;
+use kernel::ReturnCode;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 127;
+const LINKTYPE_IEEE802_15_4: u32 = 195;
+const PCAP_HEADER_LEN: usize = 24;
+const PCAP_RECORD_HEADER_LEN: usize = 16;
+const FCS_LEN: usize = 2;
+
+/// Which frames are captured
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SnifferFilter {
+    pub pan: Option<PanID>,
+    pub short_addr: Option<u16>,
+    pub frame_type: Option<FrameType>,
+}
+
+impl Default for SnifferFilter {
+    fn default() -> Self {
+        SnifferFilter {
+            pan: None,
+            short_addr: None,
+            frame_type: None,
+        }
+    }
+}
+
+impl SnifferFilter {
+    fn matches(&self, header: &Header) -> bool {
+        let pan_match = self.pan.map_or(true, |pan| {
+            header.dst_pan == Some(pan) || header.src_pan == Some(pan)
+        });
+        let addr_match = self.short_addr.map_or(true, |addr| {
+            header.dst_addr == Some(MacAddress::Short(addr))
+                || header.src_addr == Some(MacAddress::Short(addr))
+        });
+        let type_match = self
+            .frame_type
+            .map_or(true, |frame_type| header.frame_type == frame_type);
+        pan_match && addr_match && type_match
+    }
+}
+
+/// The CRC-16 used as the 802.15.4 frame check sequence (ITU-T, reflected,
+/// initial value 0)
+fn fcs(frame: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in frame {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0x8408;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal number.
+fn parse_u16(s: &str) -> Option<u16> {
+    if s.starts_with("0x") {
+        u16::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse::<u16>().ok()
+    }
+}
+
+pub struct Sniffer<'a, A: Alarm> {
+    uart: &'a uart::Transmit<'a>,
+    alarm: &'a A,
+    mac: &'a MacDevice<'a>,
+    running: Cell<bool>,
+    filter: Cell<SnifferFilter>,
+
+    // Queue of pcap bytes waiting to be written, as a ring buffer
+    queue: TakeCell<'static, [u8]>,
+    queue_head: Cell<usize>,
+    queue_len: Cell<usize>,
+    tx_buf: TakeCell<'static, [u8]>,
+
+    // The high bits of the 64-bit timestamp, and the last time read, to
+    // detect when the alarm counter wraps
+    time_high: Cell<u32>,
+    last_now: Cell<u32>,
+
+    captured: Cell<u32>,
+    dropped: Cell<u32>,
+}
+
+impl<A: Alarm> Sniffer<'a, A> {
+    pub fn new(
+        uart: &'a uart::Transmit<'a>,
+        alarm: &'a A,
+        mac: &'a MacDevice<'a>,
+        queue: &'static mut [u8],
+        tx_buf: &'static mut [u8],
+    ) -> Sniffer<'a, A> {
+        Sniffer {
+            uart: uart,
+            alarm: alarm,
+            mac: mac,
+            running: Cell::new(false),
+            filter: Cell::new(Default::default()),
+            queue: TakeCell::new(queue),
+            queue_head: Cell::new(0),
+            queue_len: Cell::new(0),
+            tx_buf: TakeCell::new(tx_buf),
+            time_high: Cell::new(0),
+            last_now: Cell::new(0),
+            captured: Cell::new(0),
+            dropped: Cell::new(0),
+        }
+    }
+
+    /// Starts a capture by writing the pcap file header, after any bytes
+    /// still queued from a previous capture.
+    pub fn start(&self) -> ReturnCode {
+        let mut header = [0u8; PCAP_HEADER_LEN];
+        header[0..4].copy_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&PCAP_VERSION_MAJOR.to_le_bytes());
+        header[6..8].copy_from_slice(&PCAP_VERSION_MINOR.to_le_bytes());
+        // Bytes 8..16 are the time zone offset and timestamp accuracy, both 0
+        header[16..20].copy_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header[20..24].copy_from_slice(&LINKTYPE_IEEE802_15_4.to_le_bytes());
+
+        self.captured.set(0);
+        self.dropped.set(0);
+        if !self.enqueue(&[&header]) {
+            return ReturnCode::ENOMEM;
+        }
+        self.running.set(true);
+        self.send_queued();
+        ReturnCode::SUCCESS
+    }
+
+    pub fn stop(&self) {
+        self.running.set(false);
+    }
+
+    pub fn get_filter(&self) -> SnifferFilter {
+        self.filter.get()
+    }
+
+    pub fn set_filter(&self, filter: SnifferFilter) {
+        self.filter.set(filter);
+    }
+
+    /// The current time as a 64-bit tick count
+    fn now(&self) -> u64 {
+        let now = self.alarm.now();
+        if now < self.last_now.get() {
+            self.time_high.set(self.time_high.get() + 1);
+        }
+        self.last_now.set(now);
+        ((self.time_high.get() as u64) << 32) | now as u64
+    }
+
+    fn capture(&self, frame: &[u8]) {
+        if !self.running.get() {
+            return;
+        }
+        let filter = self.filter.get();
+        match Header::decode(frame, false).done() {
+            Some((_, (header, _))) if filter.matches(&header) => {}
+            _ => return,
+        }
+
+        let ticks = self.now();
+        let frequency = <A::Frequency>::frequency() as u64;
+        let seconds = (ticks / frequency) as u32;
+        let micros = ((ticks % frequency) * 1_000_000 / frequency) as u32;
+        let len = (frame.len() + FCS_LEN) as u32;
+
+        let mut record = [0u8; PCAP_RECORD_HEADER_LEN];
+        record[0..4].copy_from_slice(&seconds.to_le_bytes());
+        record[4..8].copy_from_slice(&micros.to_le_bytes());
+        record[8..12].copy_from_slice(&len.to_le_bytes());
+        record[12..16].copy_from_slice(&len.to_le_bytes());
+        let fcs = fcs(frame).to_le_bytes();
+
+        if self.enqueue(&[&record, frame, &fcs]) {
+            self.captured.set(self.captured.get() + 1);
+            self.send_queued();
+        } else {
+            self.dropped.set(self.dropped.get() + 1);
+        }
+    }
+
+    /// Appends the concatenation of `parts` to the queue if it all fits.
+    fn enqueue(&self, parts: &[&[u8]]) -> bool {
+        self.queue.map_or(false, |queue| {
+            let total: usize = parts.iter().map(|part| part.len()).sum();
+            if self.queue_len.get() + total > queue.len() {
+                return false;
+            }
+            for part in parts {
+                for byte in part.iter() {
+                    let tail = (self.queue_head.get() + self.queue_len.get()) % queue.len();
+                    queue[tail] = *byte;
+                    self.queue_len.set(self.queue_len.get() + 1);
+                }
+            }
+            true
+        })
+    }
+
+    /// Writes as much of the queue as fits in the transmit buffer, if the
+    /// UART is idle.
+    fn send_queued(&self) {
+        if self.queue_len.get() == 0 {
+            return;
+        }
+        self.tx_buf.take().map(|tx_buf| {
+            let len = self.queue.map_or(0, |queue| {
+                let len = min(self.queue_len.get(), tx_buf.len());
+                for i in 0..len {
+                    tx_buf[i] = queue[(self.queue_head.get() + i) % queue.len()];
+                }
+                len
+            });
+            let (_, tx_buf) = self.uart.transmit_buffer(tx_buf, len);
+            tx_buf.map(|tx_buf| self.tx_buf.replace(tx_buf));
+        });
+    }
+
+    fn print_status(&self) {
+        let filter = self.filter.get();
+        debug!(
+            "sniff: {}, channel {}, {} captured, {} dropped",
+            if self.running.get() {
+                "running"
+            } else {
+                "stopped"
+            },
+            self.mac.get_channel(),
+            self.captured.get(),
+            self.dropped.get()
+        );
+        debug!(
+            "sniff: filter pan {:?}, addr {:?}, type {:?}",
+            filter.pan, filter.short_addr, filter.frame_type
+        );
+    }
+}
+
+impl<A: Alarm> FrameTap for Sniffer<'a, A> {
+    fn frame_transmitted(&self, frame: &[u8]) {
+        self.capture(frame);
+    }
+
+    fn frame_received(&self, frame: &[u8]) {
+        self.capture(frame);
+    }
+}
+
+impl<A: Alarm> uart::TransmitClient for Sniffer<'a, A> {
+    fn transmitted_buffer(&self, tx_buf: &'static mut [u8], tx_len: usize, _rval: ReturnCode) {
+        self.tx_buf.replace(tx_buf);
+        self.queue.map(|queue| {
+            let sent = min(tx_len, self.queue_len.get());
+            self.queue_head
+                .set((self.queue_head.get() + sent) % queue.len());
+            self.queue_len.set(self.queue_len.get() - sent);
+        });
+        self.send_queued();
+    }
+}
+
+impl<A: Alarm> ConsoleCommand for Sniffer<'a, A> {
+    fn name(&self) -> &'static str {
+        "sniff"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[start|stop|status|channel <n>|pan <id|any>|addr <addr|any>|type <type|any>]"
+    }
+
+    fn execute(&self, args: &str) {
+        let mut words = args.split_whitespace();
+        let command = words.next();
+        let argument = words.next();
+        let mut filter = self.filter.get();
+        match (command, argument) {
+            (Some("start"), None) => {
+                if self.start() != ReturnCode::SUCCESS {
+                    debug!("sniff: could not start capture");
+                }
+            }
+            (Some("stop"), None) => self.stop(),
+            (Some("status"), None) | (None, _) => self.print_status(),
+            (Some("channel"), Some(channel)) => {
+                let result = parse_u16(channel).map_or(ReturnCode::EINVAL, |channel| {
+                    self.mac.set_channel(channel as u8)
+                });
+                if result == ReturnCode::SUCCESS {
+                    self.mac.config_commit();
+                } else {
+                    debug!("sniff: invalid channel {}", channel);
+                }
+            }
+            (Some("pan"), Some("any")) => filter.pan = None,
+            (Some("pan"), Some(pan)) => match parse_u16(pan) {
+                Some(pan) => filter.pan = Some(pan),
+                None => debug!("sniff: invalid PAN ID {}", pan),
+            },
+            (Some("addr"), Some("any")) => filter.short_addr = None,
+            (Some("addr"), Some(addr)) => match parse_u16(addr) {
+                Some(addr) => filter.short_addr = Some(addr),
+                None => debug!("sniff: invalid address {}", addr),
+            },
+            (Some("type"), Some(frame_type)) => {
+                filter.frame_type = match frame_type {
+                    "data" => Some(FrameType::Data),
+                    "beacon" => Some(FrameType::Beacon),
+                    "ack" => Some(FrameType::Acknowledgement),
+                    "command" => Some(FrameType::MACCommand),
+                    "any" => None,
+                    _ => {
+                        debug!("sniff: invalid frame type {}", frame_type);
+                        filter.frame_type
+                    }
+                }
+            }
+            _ => debug!("sniff {}", self.usage()),
+        }
+        self.filter.set(filter);
+    }
+}
@@ -6,7 +6,9 @@
 //! subsequently 6LoWPAN-encoded and fragmented IP packets. This capsule allows
 //! that to happen by providing a mechanism for sequencing transmission attempts,
 //! Every radio frame received is provided to all listening clients so that each
-//! client can perform its own frame filtering logic.
+//! client can perform its own frame filtering logic. A `FrameTap`, such as a
+//! packet sniffer, can additionally observe every frame that goes through the
+//! mux in either direction.
 //!
 //! Usage
 //! -----
@@ -27,12 +29,24 @@
 //! ```
 
 use crate::ieee802154::{device, framer};
-use crate::net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel};
+use crate::net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel, SuperframeSpec};
 use core::cell::Cell;
 use kernel::common::cells::{MapCell, OptionalCell};
 use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::radio;
 use kernel::ReturnCode;
 
+/// Observes the frames sent and received through a `MuxMac`. Frames start at
+/// the PSDU and do not include the MAC footer.
+pub trait FrameTap {
+    /// A frame was successfully transmitted. Secured frames are observed as
+    /// they were sent over the air.
+    fn frame_transmitted(&self, frame: &[u8]);
+    /// A frame was received. Secured frames are observed after they have
+    /// been unsecured, with the MIC removed.
+    fn frame_received(&self, frame: &[u8]);
+}
+
 /// IEE 802.15.4 MAC device muxer that keeps a list of MAC users and sequences
 /// any pending transmission requests. Any received frames from the underlying
 /// MAC device are sent to all users.
@@ -40,10 +54,20 @@ pub struct MuxMac<'a> {
     mac: &'a device::MacDevice<'a>,
     users: List<'a, MacUser<'a>>,
     inflight: OptionalCell<&'a MacUser<'a>>,
+    // The length of the frame in flight, for the tap
+    inflight_len: Cell<usize>,
+    tap: OptionalCell<&'a FrameTap>,
 }
 
 impl device::TxClient for MuxMac<'a> {
     fn send_done(&self, spi_buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        if result == ReturnCode::SUCCESS {
+            let end = radio::PSDU_OFFSET + self.inflight_len.get();
+            if end <= spi_buf.len() {
+                self.tap
+                    .map(|tap| tap.frame_transmitted(&spi_buf[radio::PSDU_OFFSET..end]));
+            }
+        }
         self.inflight.take().map(move |user| {
             user.send_done(spi_buf, acked, result);
         });
@@ -52,9 +76,18 @@ impl device::TxClient for MuxMac<'a> {
 }
 
 impl device::RxClient for MuxMac<'a> {
-    fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        data_offset: usize,
+        data_len: usize,
+        quality: radio::LinkQuality,
+    ) {
+        self.tap
+            .map(|tap| tap.frame_received(&buf[radio::PSDU_OFFSET..data_offset + data_len]));
         for user in self.users.iter() {
-            user.receive(buf, header, data_offset, data_len);
+            user.receive(buf, header, data_offset, data_len, quality);
         }
     }
 }
@@ -65,9 +98,16 @@ impl MuxMac<'a> {
             mac: mac,
             users: List::new(),
             inflight: OptionalCell::empty(),
+            inflight_len: Cell::new(0),
+            tap: OptionalCell::empty(),
         }
     }
 
+    /// Sets the tap that observes every frame sent and received.
+    pub fn set_tap(&self, tap: &'a FrameTap) {
+        self.tap.set(tap);
+    }
+
     /// Registers a MAC user with this MAC mux device. Each MAC user should only
     /// be registered once.
     pub fn add_user(&self, user: &'a MacUser<'a>) {
@@ -101,6 +141,7 @@ impl MuxMac<'a> {
     /// buffer to the `MacUser` via its transmit client.
     fn perform_op_async(&self, node: &'a MacUser<'a>, op: Op) {
         if let Op::Transmit(frame) = op {
+            self.inflight_len.set(frame.secured_length());
             let (result, mbuf) = self.mac.transmit(frame);
             // If a buffer is returned, the transmission failed,
             // otherwise it succeeded.
@@ -121,6 +162,7 @@ impl MuxMac<'a> {
         op: Op,
     ) -> Option<(ReturnCode, Option<&'static mut [u8]>)> {
         if let Op::Transmit(frame) = op {
+            self.inflight_len.set(frame.secured_length());
             let (result, mbuf) = self.mac.transmit(frame);
             if result == ReturnCode::SUCCESS {
                 self.inflight.set(node);
@@ -210,10 +252,17 @@ impl MacUser<'a> {
             .map(move |client| client.send_done(spi_buf, acked, result));
     }
 
-    fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        data_offset: usize,
+        data_len: usize,
+        quality: radio::LinkQuality,
+    ) {
         self.rx_client
             .get()
-            .map(move |client| client.receive(buf, header, data_offset, data_len));
+            .map(move |client| client.receive(buf, header, data_offset, data_len, quality));
     }
 }
 
@@ -256,6 +305,38 @@ impl device::MacDevice<'a> for MacUser<'a> {
         self.mux.mac.set_pan(id)
     }
 
+    fn get_channel(&self) -> u8 {
+        self.mux.mac.get_channel()
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        self.mux.mac.set_channel(chan)
+    }
+
+    fn get_promiscuous(&self) -> bool {
+        self.mux.mac.get_promiscuous()
+    }
+
+    fn set_promiscuous(&self, enable: bool) -> ReturnCode {
+        self.mux.mac.set_promiscuous(enable)
+    }
+
+    fn get_auto_ack(&self) -> bool {
+        self.mux.mac.get_auto_ack()
+    }
+
+    fn set_auto_ack(&self, enable: bool) -> ReturnCode {
+        self.mux.mac.set_auto_ack(enable)
+    }
+
+    fn get_outgoing_frame_counter(&self) -> u32 {
+        self.mux.mac.get_outgoing_frame_counter()
+    }
+
+    fn set_outgoing_frame_counter(&self, frame_counter: u32) {
+        self.mux.mac.set_outgoing_frame_counter(frame_counter)
+    }
+
     fn config_commit(&self) {
         self.mux.mac.config_commit()
     }
@@ -278,6 +359,47 @@ impl device::MacDevice<'a> for MacUser<'a> {
             .prepare_data_frame(buf, dst_pan, dst_addr, src_pan, src_addr, security_needed)
     }
 
+    fn prepare_command_frame(
+        &self,
+        buf: &'static mut [u8],
+        dst_pan: PanID,
+        dst_addr: MacAddress,
+        src_pan: PanID,
+        src_addr: MacAddress,
+        command_id: u8,
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<framer::Frame, &'static mut [u8]> {
+        self.mux.mac.prepare_command_frame(
+            buf,
+            dst_pan,
+            dst_addr,
+            src_pan,
+            src_addr,
+            command_id,
+            security_needed,
+        )
+    }
+
+    fn prepare_beacon_request_frame(
+        &self,
+        buf: &'static mut [u8],
+    ) -> Result<framer::Frame, &'static mut [u8]> {
+        self.mux.mac.prepare_beacon_request_frame(buf)
+    }
+
+    fn prepare_beacon_frame(
+        &self,
+        buf: &'static mut [u8],
+        src_pan: PanID,
+        src_addr: MacAddress,
+        superframe_spec: SuperframeSpec,
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<framer::Frame, &'static mut [u8]> {
+        self.mux
+            .mac
+            .prepare_beacon_frame(buf, src_pan, src_addr, superframe_spec, security_needed)
+    }
+
     fn transmit(&self, frame: framer::Frame) -> (ReturnCode, Option<&'static mut [u8]>) {
         // If the muxer is idle, immediately transmit the frame, otherwise
         // attempt to queue the transmission request. However, each MAC user can
@@ -11,6 +11,22 @@
 //! packet before returning to sleep. See comments below for implementation
 //! details.
 //!
+//! The duty cycle adapts to the traffic load. Each time a node receives a
+//! data packet, it halves its sleep interval (down to `min_sleep_ms`), and
+//! each time it wakes and hears nothing, it sleeps `sleep_step_ms` longer (up
+//! to `max_sleep_ms`). Nodes also learn the wake schedules of their
+//! neighbors: every preamble carries the sender's current sleep interval, and
+//! after a data packet has been delivered to a neighbor, the time of its next
+//! wakeups can be predicted by applying the same adaptation. A transmission
+//! to that neighbor then waits until just before its predicted wakeup
+//! instead of sending a full train of preambles, falling back to the full
+//! train if the prediction was wrong. This assumes that all nodes share the
+//! same `XMacConfig`.
+//!
+//! The parameters can be changed with `set_config`, and `get_stats` returns
+//! counters (wakeups, preambles sent, time awake, ...) to measure their
+//! effect.
+//!
 //! Additional notes:
 //!
 //!   * Since much of a node's time is spent sleeping, transmission latency is
@@ -68,7 +84,6 @@
 //
 // TODO: Test no-preamble transmission with randomized backoff, requires 3
 //       devices.
-// TODO: Modifying sleep time with traffic load to optimize energy usage.
 // TODO: Remove expectation that radios cancel pending sleeps when receiving a
 //       new packet (see line 652).
 //
@@ -78,8 +93,9 @@
 
 use crate::ieee802154::mac::Mac;
 use crate::net::ieee802154::{FrameType, FrameVersion, Header, MacAddress, PanID};
+use crate::net::stream::{decode_u16, encode_u16};
 use core::cell::Cell;
-use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::hil::radio;
 use kernel::hil::rng::{self, Rng};
 use kernel::hil::time::{self, Alarm, Frequency, Time};
@@ -90,15 +106,18 @@ use kernel::ReturnCode;
 // having been awake for more than 4-6 ms; 10 ms is a safe amount of time where
 // we are very likely to pick up any incoming preambles, and is half as much
 // as the 20 ms lower bound in Buettner et al.
-const WAKE_TIME_MS: u32 = 10;
-// Time the radio will sleep between wakes. Configurable to any desired value
-// less than or equal to the max time the transmitter sends preambles before
-// abandoning the transmission.
-const SLEEP_TIME_MS: u32 = 250;
+const DEFAULT_WAKE_TIME_MS: u32 = 10;
+// Bounds of the time the radio will sleep between wakes. Configurable to any
+// desired value less than or equal to the max time the transmitter sends
+// preambles before abandoning the transmission.
+const DEFAULT_MIN_SLEEP_MS: u32 = 50;
+const DEFAULT_MAX_SLEEP_MS: u32 = 250;
+// Increase of the sleep time after each wake with no traffic
+const DEFAULT_SLEEP_STEP_MS: u32 = 25;
 // Time the radio will continue to send preamble packets before aborting the
 // transmission and returning ENOACK. Should be at least as large as the maximum
 // sleep time for any node in the network.
-const PREAMBLE_TX_MS: u32 = 251;
+const DEFAULT_PREAMBLE_TX_MS: u32 = 251;
 
 // Maximum backoff for a transmitter attempting to send a data packet, when the
 // node has detected a data packet sent to the same destination from another
@@ -109,19 +128,125 @@ const MAX_TX_BACKOFF_MS: u32 = 10;
 // any additional incoming packets before going to sleep.
 const MAX_RX_SLEEP_DELAY_MS: u32 = MAX_TX_BACKOFF_MS;
 
+// Number of neighbors whose wake schedules are remembered
+const MAX_SCHEDULE_NEIGHBORS: usize = 8;
+// Number of wakeups of a neighbor predicted after delivering a packet to it;
+// beyond that, the prediction is too uncertain to be useful.
+const MAX_PREDICTED_WAKEUPS: usize = 16;
+// Time before the predicted wakeup of a neighbor at which preambles start, to
+// absorb clock drift and radio startup time.
+const SCHEDULE_GUARD_MS: u32 = 3;
+
+/// Parameters of the XMac duty cycle. All nodes in a network should use the
+/// same parameters.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct XMacConfig {
+    /// Time the radio stays awake listening for preambles after waking
+    pub wake_time_ms: u32,
+    /// Shortest sleep interval, reached under heavy traffic
+    pub min_sleep_ms: u32,
+    /// Longest sleep interval, reached when the channel is idle
+    pub max_sleep_ms: u32,
+    /// Increase of the sleep interval after each wake with no traffic
+    pub sleep_step_ms: u32,
+    /// Time preambles are sent before a transmission fails with ENOACK
+    pub preamble_tx_ms: u32,
+    /// Whether the sleep interval adapts to traffic; if not, nodes always
+    /// sleep for `max_sleep_ms`
+    pub adaptive: bool,
+    /// Whether to learn the wake schedules of neighbors to shorten preamble
+    /// trains
+    pub learn_schedules: bool,
+}
+
+impl Default for XMacConfig {
+    fn default() -> Self {
+        XMacConfig {
+            wake_time_ms: DEFAULT_WAKE_TIME_MS,
+            min_sleep_ms: DEFAULT_MIN_SLEEP_MS,
+            max_sleep_ms: DEFAULT_MAX_SLEEP_MS,
+            sleep_step_ms: DEFAULT_SLEEP_STEP_MS,
+            preamble_tx_ms: DEFAULT_PREAMBLE_TX_MS,
+            adaptive: true,
+            learn_schedules: true,
+        }
+    }
+}
+
+impl XMacConfig {
+    // The sleep interval after receiving a data packet
+    fn sleep_after_rx(&self, sleep_ms: u32) -> u32 {
+        if self.adaptive {
+            (sleep_ms / 2).max(self.min_sleep_ms)
+        } else {
+            self.max_sleep_ms
+        }
+    }
+
+    // The sleep interval after a wake with no traffic
+    fn sleep_after_idle(&self, sleep_ms: u32) -> u32 {
+        if self.adaptive {
+            (sleep_ms + self.sleep_step_ms).min(self.max_sleep_ms)
+        } else {
+            self.max_sleep_ms
+        }
+    }
+}
+
+/// Counters describing the behavior of the XMac layer since it started or
+/// the last call to `reset_stats`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct XMacStats {
+    /// Number of times the radio woke to listen for preambles
+    pub wakeups: u32,
+    /// Number of wakeups during which nothing was received
+    pub idle_wakeups: u32,
+    /// Number of preambles transmitted
+    pub preambles_sent: u32,
+    /// Number of data packets transmitted
+    pub data_sent: u32,
+    /// Number of data packets transmitted without preambles, after
+    /// overhearing another transmission to the same destination
+    pub data_sent_direct: u32,
+    /// Number of transmissions whose preamble train was delayed until the
+    /// learned wakeup time of the destination
+    pub data_sent_scheduled: u32,
+    /// Number of transmissions that failed because no preamble was
+    /// acknowledged
+    pub data_not_acked: u32,
+    /// Number of data packets received
+    pub data_received: u32,
+    /// Total time the radio was on
+    pub awake_ms: u32,
+    /// The current sleep interval
+    pub sleep_interval_ms: u32,
+}
+
+// What we know about the wake schedule of a neighbor
+#[derive(Copy, Clone, Eq, PartialEq)]
+struct XMacNeighbor {
+    addr: MacAddress,
+    // The sleep interval it advertised in its last preamble, or that it is
+    // predicted to use after our last delivery
+    sleep_ms: u32,
+    // The time our last data packet was delivered to it
+    delivered_at: Option<u32>,
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Copy, Clone, PartialEq)]
 enum XMacState {
     // The primary purpose of these states is to manage the timer that runs the
     // protocol and determines the state of the radio (e.g. if in SLEEP, a fired
     // timer indicates we should transition to AWAKE).
-    AWAKE,       // Awake and listening for incoming preambles
-    DELAY_SLEEP, // Receiving done; waiting for any other incoming data packets
-    SLEEP,       // Asleep and not receiving or transmitting
-    STARTUP,     // Radio waking up, PowerClient::on() transitions to next state
-    TX_PREAMBLE, // Transmitting preambles and waiting for an ACK
-    TX,          // Transmitting data packet to the destination node
-    TX_DELAY,    // Backing off to send data directly without preamble
+    AWAKE,        // Awake and listening for incoming preambles
+    DELAY_SLEEP,  // Receiving done; waiting for any other incoming data packets
+    SLEEP,        // Asleep and not receiving or transmitting
+    STARTUP,      // Radio waking up, PowerClient::on() transitions to next state
+    TX_PREAMBLE,  // Transmitting preambles and waiting for an ACK
+    TX,           // Transmitting data packet to the destination node
+    TX_DELAY,     // Backing off to send data directly without preamble
+    TX_SCHEDULED, // Waiting for the predicted wakeup of the destination node
 }
 
 // Information extracted for each packet from the data buffer provided to
@@ -160,6 +285,20 @@ pub struct XMac<'a, R: radio::Radio, A: Alarm> {
     tx_preamble_buf: TakeCell<'static, [u8]>,
 
     rx_pending: Cell<bool>,
+
+    config: Cell<XMacConfig>,
+    sleep_interval: Cell<u32>,
+    // Whether any frame was received since the radio last woke
+    wake_traffic: Cell<bool>,
+    // Whether the data packet being sent skipped the preambles
+    tx_direct: Cell<bool>,
+    neighbors: MapCell<[Option<XMacNeighbor>; MAX_SCHEDULE_NEIGHBORS]>,
+    next_neighbor: Cell<usize>,
+
+    stats: Cell<XMacStats>,
+    promiscuous: Cell<bool>,
+    awake_since: Cell<u32>,
+    awake_ticks: Cell<u64>,
 }
 
 impl<R: radio::Radio, A: Alarm> XMac<'a, R, A> {
@@ -179,13 +318,154 @@ impl<R: radio::Radio, A: Alarm> XMac<'a, R, A> {
             tx_preamble_seq_num: Cell::new(0),
             tx_preamble_buf: TakeCell::empty(),
             rx_pending: Cell::new(false),
+            promiscuous: Cell::new(false),
+            config: Cell::new(Default::default()),
+            sleep_interval: Cell::new(DEFAULT_MAX_SLEEP_MS),
+            wake_traffic: Cell::new(false),
+            tx_direct: Cell::new(false),
+            neighbors: MapCell::new(Default::default()),
+            next_neighbor: Cell::new(0),
+            stats: Cell::new(Default::default()),
+            awake_since: Cell::new(0),
+            awake_ticks: Cell::new(0),
         }
     }
 
+    pub fn get_config(&self) -> XMacConfig {
+        self.config.get()
+    }
+
+    /// Changes the duty cycle parameters. Returns EINVAL if the sleep
+    /// interval bounds are inconsistent or if preambles would not be sent
+    /// for longer than the maximum sleep interval.
+    pub fn set_config(&self, config: XMacConfig) -> ReturnCode {
+        if config.wake_time_ms == 0
+            || config.min_sleep_ms > config.max_sleep_ms
+            || config.preamble_tx_ms <= config.max_sleep_ms
+        {
+            return ReturnCode::EINVAL;
+        }
+        self.config.set(config);
+        self.sleep_interval.set(config.max_sleep_ms);
+        self.neighbors
+            .map(|neighbors| *neighbors = Default::default());
+        ReturnCode::SUCCESS
+    }
+
+    pub fn get_stats(&self) -> XMacStats {
+        let mut stats = self.stats.get();
+        let mut awake_ticks = self.awake_ticks.get();
+        if self.radio.is_on() {
+            awake_ticks += self.alarm.now().wrapping_sub(self.awake_since.get()) as u64;
+        }
+        stats.awake_ms = (awake_ticks * 1000 / <A::Frequency>::frequency() as u64) as u32;
+        stats.sleep_interval_ms = self.sleep_interval.get();
+        stats
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.set(Default::default());
+        self.awake_ticks.set(0);
+        self.awake_since.set(self.alarm.now());
+    }
+
+    fn update_stats<F: FnOnce(&mut XMacStats)>(&self, f: F) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
     fn sleep_time(&self) -> u32 {
-        // TODO (ongoing) modify based on traffic load to efficiently schedule
-        // sleep. Currently sleeps for a constant amount of time.
-        SLEEP_TIME_MS
+        self.sleep_interval.get()
+    }
+
+    fn ms_to_ticks(&self, ms: u32) -> u32 {
+        ((ms as f32 / 1000.0) * <A::Frequency>::frequency() as f32) as u32
+    }
+
+    // The radio has woken to listen for preambles
+    fn woke(&self) {
+        self.wake_traffic.set(false);
+        self.update_stats(|stats| stats.wakeups += 1);
+    }
+
+    fn radio_stop(&self) {
+        if self.radio.is_on() {
+            let awake = self.alarm.now().wrapping_sub(self.awake_since.get());
+            self.awake_ticks.set(self.awake_ticks.get() + awake as u64);
+        }
+        self.radio.stop();
+    }
+
+    fn radio_start(&self) {
+        self.awake_since.set(self.alarm.now());
+        self.radio.start();
+    }
+
+    // Runs `f` on the schedule of the neighbor with address `addr`, adding it
+    // to the table (replacing the oldest entry) if `add` is set.
+    fn with_neighbor<F: FnOnce(&mut XMacNeighbor)>(&self, addr: MacAddress, add: bool, f: F) {
+        self.neighbors.map(|neighbors| {
+            let index = neighbors
+                .iter()
+                .position(|n| n.map_or(false, |n| n.addr == addr));
+            let index = match index {
+                Some(index) => index,
+                None if add => {
+                    let index = self.next_neighbor.get();
+                    self.next_neighbor.set((index + 1) % MAX_SCHEDULE_NEIGHBORS);
+                    neighbors[index] = Some(XMacNeighbor {
+                        addr: addr,
+                        sleep_ms: self.config.get().max_sleep_ms,
+                        delivered_at: None,
+                    });
+                    index
+                }
+                None => return,
+            };
+            neighbors[index].as_mut().map(f);
+        });
+    }
+
+    // The number of ticks until the predicted next wakeup of the neighbor
+    // with address `addr`, if its schedule is known.
+    fn predict_wakeup(&self, addr: MacAddress) -> Option<u32> {
+        let config = self.config.get();
+        let mut neighbor = None;
+        self.with_neighbor(addr, false, |n| neighbor = Some(*n));
+        let neighbor = neighbor?;
+        let elapsed = self.alarm.now().wrapping_sub(neighbor.delivered_at?);
+
+        // After receiving our packet, the neighbor stays awake for a while,
+        // then follows its sleep schedule, which lengthens with each idle
+        // wakeup.
+        let mut sleep_ms = neighbor.sleep_ms;
+        let mut wakeup = self.ms_to_ticks(MAX_RX_SLEEP_DELAY_MS + sleep_ms);
+        for _ in 0..MAX_PREDICTED_WAKEUPS {
+            if wakeup >= elapsed {
+                return Some(wakeup - elapsed);
+            }
+            sleep_ms = config.sleep_after_idle(sleep_ms);
+            wakeup = wakeup.saturating_add(self.ms_to_ticks(config.wake_time_ms + sleep_ms));
+        }
+        None
+    }
+
+    // Starts sending preambles to the destination of the pending packet.
+    fn start_preambles(&self) {
+        // If the radio is on, start the preamble timer and start transmitting
+        if self.radio.is_on() {
+            self.state.set(XMacState::TX_PREAMBLE);
+            self.set_timer_ms::<A>(self.config.get().preamble_tx_ms);
+            self.transmit_preamble();
+
+        // If the radio is currently sleeping, wake it and indicate that when
+        // ready, it should begin transmitting preambles
+        } else {
+            self.state.set(XMacState::STARTUP);
+            self.tx_preamble_pending.set(true);
+            self.radio_start();
+        }
     }
 
     fn sleep(&self) {
@@ -198,7 +478,7 @@ impl<R: radio::Radio, A: Alarm> XMac<'a, R, A> {
 
             // Otherwise, don't sleep if expecting a data packet or transmitting
             } else if !self.rx_pending.get() {
-                self.radio.stop();
+                self.radio_stop();
                 self.state.set(XMacState::SLEEP);
                 self.set_timer_ms::<A>(self.sleep_time());
             }
@@ -246,10 +526,25 @@ impl<R: radio::Radio, A: Alarm> XMac<'a, R, A> {
             self.tx_preamble_seq_num
                 .set(self.tx_preamble_seq_num.get() + 1);
 
-            match header.encode(&mut buf[radio::PSDU_OFFSET..], true).done() {
+            // The payload advertises our sleep interval so that the
+            // destination can learn our wake schedule.
+            let sleep_ms = self.sleep_time().min(0xffff) as u16;
+            let encoded = header
+                .encode(&mut buf[radio::PSDU_OFFSET..], true)
+                .done()
+                .and_then(|(data_offset, _)| {
+                    encode_u16(
+                        &mut buf[radio::PSDU_OFFSET + data_offset..],
+                        sleep_ms.to_be(),
+                    )
+                    .done()
+                    .map(|(len, _)| data_offset + len)
+                });
+            match encoded {
                 // If we can successfully encode the preamble, transmit.
-                Some((data_offset, _)) => {
-                    result = self.radio.transmit(buf, data_offset + radio::PSDU_OFFSET);
+                Some(frame_len) => {
+                    self.update_stats(|stats| stats.preambles_sent += 1);
+                    result = self.radio.transmit(buf, frame_len);
                 }
                 None => {
                     self.tx_preamble_buf.replace(buf);
@@ -283,6 +578,31 @@ impl<R: radio::Radio, A: Alarm> XMac<'a, R, A> {
     // Reports back to client that transmission is complete, radio can turn off
     // if not kept awake by other portions of the protocol.
     fn call_tx_client(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        if self.state.get() == XMacState::TX && result == ReturnCode::SUCCESS {
+            let direct = self.tx_direct.get();
+            self.update_stats(|stats| {
+                stats.data_sent += 1;
+                if direct {
+                    stats.data_sent_direct += 1;
+                }
+            });
+            // The destination is now awake and restarts its schedule with a
+            // shorter sleep interval.
+            if self.config.get().learn_schedules {
+                let dst_addr = self.tx_header.get().and_then(|hdr| hdr.dst_addr);
+                dst_addr.map(|addr| {
+                    let config = self.config.get();
+                    let now = self.alarm.now();
+                    self.with_neighbor(addr, true, |neighbor| {
+                        neighbor.sleep_ms = config.sleep_after_rx(neighbor.sleep_ms);
+                        neighbor.delivered_at = Some(now);
+                    });
+                });
+            }
+        } else if result == ReturnCode::ENOACK {
+            self.update_stats(|stats| stats.data_not_acked += 1);
+        }
+        self.tx_direct.set(false);
         self.state.set(XMacState::AWAKE);
         self.sleep();
         self.tx_client.map(move |c| {
@@ -296,14 +616,20 @@ impl<R: radio::Radio, A: Alarm> XMac<'a, R, A> {
         &self,
         buf: &'static mut [u8],
         len: usize,
+        quality: radio::LinkQuality,
         crc_valid: bool,
         result: ReturnCode,
     ) {
+        self.update_stats(|stats| stats.data_received += 1);
+        let config = self.config.get();
+        self.sleep_interval
+            .set(config.sleep_after_rx(self.sleep_interval.get()));
+
         self.delay_sleep.set(true);
         self.sleep();
 
         self.rx_client.map(move |c| {
-            c.receive(buf, len, crc_valid, result);
+            c.receive(buf, len, quality, crc_valid, result);
         });
     }
 }
@@ -383,6 +709,37 @@ impl<R: radio::Radio, A: Alarm> Mac for XMac<'a, R, A> {
         self.radio.get_pan()
     }
 
+    fn get_channel(&self) -> u8 {
+        self.radio.get_channel()
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        self.radio.set_channel(chan)
+    }
+
+    fn get_promiscuous(&self) -> bool {
+        self.promiscuous.get()
+    }
+
+    fn set_promiscuous(&self, enable: bool) -> ReturnCode {
+        // The radio always stays in promiscuous mode: overheard frames
+        // are used to detect when the destination of a pending transmission
+        // is awake.
+        if enable && !self.radio.get_promiscuous() {
+            return ReturnCode::ENOSUPPORT;
+        }
+        self.promiscuous.set(enable);
+        ReturnCode::SUCCESS
+    }
+
+    fn get_auto_ack(&self) -> bool {
+        self.radio.get_auto_ack()
+    }
+
+    fn set_auto_ack(&self, enable: bool) -> ReturnCode {
+        self.radio.set_auto_ack(enable)
+    }
+
     fn config_commit(&self) {
         self.radio.config_commit()
     }
@@ -440,18 +797,28 @@ impl<R: radio::Radio, A: Alarm> Mac for XMac<'a, R, A> {
 
         self.tx_preamble_seq_num.set(0);
 
-        // If the radio is on, start the preamble timer and start transmitting
-        if self.radio.is_on() {
-            self.state.set(XMacState::TX_PREAMBLE);
-            self.set_timer_ms::<A>(PREAMBLE_TX_MS);
-            self.transmit_preamble();
-
-        // If the radio is currently sleeping, wake it and indicate that when
-        // ready, it should begin transmitting preambles
+        // If we know when the destination wakes next, wait until just before
+        // then to send preambles.
+        let guard = self.ms_to_ticks(SCHEDULE_GUARD_MS);
+        let wakeup = if self.config.get().learn_schedules {
+            self.tx_header
+                .get()
+                .and_then(|hdr| hdr.dst_addr)
+                .and_then(|addr| self.predict_wakeup(addr))
         } else {
-            self.state.set(XMacState::STARTUP);
-            self.tx_preamble_pending.set(true);
-            self.radio.start();
+            None
+        };
+        match wakeup {
+            Some(ticks) if ticks > guard => {
+                self.update_stats(|stats| stats.data_sent_scheduled += 1);
+                if self.radio.is_on() && !self.rx_pending.get() {
+                    self.radio_stop();
+                }
+                self.state.set(XMacState::TX_SCHEDULED);
+                self.alarm
+                    .set_alarm(self.alarm.now().wrapping_add(ticks - guard));
+            }
+            _ => self.start_preambles(),
         }
 
         (ReturnCode::SUCCESS, None)
@@ -468,15 +835,23 @@ impl<R: radio::Radio, A: Alarm> time::Client for XMac<'a, R, A> {
                 // indicate that the radio is ready
                 if !self.radio.is_on() {
                     self.state.set(XMacState::STARTUP);
-                    self.radio.start();
+                    self.radio_start();
                 } else {
-                    self.set_timer_ms::<A>(WAKE_TIME_MS);
+                    self.set_timer_ms::<A>(self.config.get().wake_time_ms);
                     self.state.set(XMacState::AWAKE);
+                    self.woke();
                 }
             }
             // If we've been delaying sleep or haven't heard any incoming
-            // preambles, turn the radio off.
+            // preambles, turn the radio off. An idle wake lengthens the sleep
+            // interval.
             XMacState::AWAKE => {
+                if !self.wake_traffic.get() {
+                    self.update_stats(|stats| stats.idle_wakeups += 1);
+                    let config = self.config.get();
+                    self.sleep_interval
+                        .set(config.sleep_after_idle(self.sleep_interval.get()));
+                }
                 self.sleep();
             }
             XMacState::DELAY_SLEEP => {
@@ -493,8 +868,13 @@ impl<R: radio::Radio, A: Alarm> time::Client for XMac<'a, R, A> {
             // After a randomized backoff period, transmit the data directly.
             XMacState::TX_DELAY => {
                 self.state.set(XMacState::TX);
+                self.tx_direct.set(true);
                 self.transmit_packet();
             }
+            // The destination should wake up soon.
+            XMacState::TX_SCHEDULED => {
+                self.start_preambles();
+            }
             _ => {}
         }
     }
@@ -510,11 +890,12 @@ impl<R: radio::Radio, A: Alarm> radio::PowerClient for XMac<'a, R, A> {
                 if self.tx_preamble_pending.get() {
                     self.tx_preamble_pending.set(false);
                     self.state.set(XMacState::TX_PREAMBLE);
-                    self.set_timer_ms::<A>(PREAMBLE_TX_MS);
+                    self.set_timer_ms::<A>(self.config.get().preamble_tx_ms);
                     self.transmit_preamble();
                 } else {
                     self.state.set(XMacState::AWAKE);
-                    self.set_timer_ms::<A>(WAKE_TIME_MS);
+                    self.set_timer_ms::<A>(self.config.get().wake_time_ms);
+                    self.woke();
                 }
             }
         }
@@ -540,7 +921,7 @@ impl<R: radio::Radio, A: Alarm> radio::TxClient for XMac<'a, R, A> {
                     self.transmit_preamble();
                 }
             }
-            XMacState::TX_DELAY | XMacState::SLEEP => {
+            XMacState::TX_DELAY | XMacState::SLEEP | XMacState::TX_SCHEDULED => {
                 // If, while sending preambles, we switch to TX_DELAY mode, the
                 // last preamble sent will complete afterwards. If no ACK, the
                 // radio may have fallen sleep before the callback is processed.
@@ -560,6 +941,7 @@ impl<R: radio::Radio, A: Alarm> radio::RxClient for XMac<'a, R, A> {
         &self,
         buf: &'static mut [u8],
         frame_len: usize,
+        quality: radio::LinkQuality,
         crc_valid: bool,
         result: ReturnCode,
     ) {
@@ -569,7 +951,10 @@ impl<R: radio::Radio, A: Alarm> radio::RxClient for XMac<'a, R, A> {
         // First, check to make sure we can decode the MAC header (especially
         // the destination address) to see if we can backoff/send pending
         // transmission.
-        if let Some((_, (header, _))) = Header::decode(&buf[radio::PSDU_OFFSET..], false).done() {
+        if let Some((_, (header, data_offset))) =
+            Header::decode(&buf[radio::PSDU_OFFSET..], false).done()
+        {
+            self.wake_traffic.set(true);
             if let Some(dst_addr) = header.dst_addr {
                 let addr_match = match dst_addr {
                     MacAddress::Short(addr) => addr == self.radio.get_address(),
@@ -599,6 +984,16 @@ impl<R: radio::Radio, A: Alarm> radio::RxClient for XMac<'a, R, A> {
                         FrameType::Multipurpose => {
                             continue_sleep = false;
                             self.rx_pending.set(true);
+                            // Learn the sender's sleep interval
+                            let payload = &buf[radio::PSDU_OFFSET + data_offset..];
+                            let sleep_ms = decode_u16(payload).done().map(|(_, s)| u16::from_be(s));
+                            if let (Some(addr), Some(sleep_ms)) = (header.src_addr, sleep_ms) {
+                                if self.config.get().learn_schedules {
+                                    self.with_neighbor(addr, true, |neighbor| {
+                                        neighbor.sleep_ms = sleep_ms as u32;
+                                    });
+                                }
+                            }
                         }
                         FrameType::Data => {
                             continue_sleep = false;
@@ -608,6 +1003,10 @@ impl<R: radio::Radio, A: Alarm> radio::RxClient for XMac<'a, R, A> {
                     }
                 }
             }
+            // In promiscuous mode, pass up every frame except preambles
+            if self.promiscuous.get() && header.frame_type != FrameType::Multipurpose {
+                data_received = true;
+            }
         }
 
         // TODO: this currently assumes that upon receiving a packet, the radio
@@ -621,7 +1020,7 @@ impl<R: radio::Radio, A: Alarm> radio::RxClient for XMac<'a, R, A> {
 
         if data_received {
             self.rx_pending.set(false);
-            self.call_rx_client(buf, frame_len, crc_valid, result);
+            self.call_rx_client(buf, frame_len, quality, crc_valid, result);
         } else {
             self.radio.set_receive_buffer(buf);
         }
@@ -0,0 +1,1010 @@
+//! Updates of the kernel itself, in two slots booted by the A/B bootloader.
+//!
+//! The bootloader in `boards/ab_bootloader` boots the newest good kernel of
+//! two slots, in the format of `tock_boot`. `KernelUpdate` writes a new
+//! kernel to the slot that is not running, checks it and writes its header,
+//! which makes it the newest. After the next reset the bootloader boots it.
+//! The board then calls `confirm` once the new kernel works, for example
+//! after its apps started. A kernel that is not confirmed after a few boots
+//! is given up on, and the bootloader boots the previous one again, which
+//! marks the failed kernel rejected when it is confirmed in turn.
+//!
+//! `KernelUpdate` implements `app_update::Update`, so the front ends of
+//! `app_update` can update the kernel from a process, over UDP or over USB.
+//! The image written is the binary of a kernel linked to run from its slot,
+//! right after the header page, and it is rejected if its vector table
+//! points elsewhere. `rollback` makes the kernel in the other slot the
+//! newest again.
+//!
+//! A header is rewritten by erasing its page and writing it again. Losing
+//! power in between loses the kernel of that slot, and the bootloader then
+//! boots the other one.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! #[link_section = ".boot_handoff"]
+//! static mut BOOT_HANDOFF: tock_boot::Handoff = tock_boot::Handoff::new();
+//!
+//! let kernel_update = static_init!(
+//!     capsules::kernel_update::KernelUpdate<'static, FlashUser<...>>,
+//!     capsules::kernel_update::KernelUpdate::new(
+//!         kernel_flash,
+//!         [128, 384], // First page of each slot
+//!         256,        // Pages of each slot, including the header page
+//!         0,          // Address of page 0
+//!         &BOOT_HANDOFF,
+//!         &mut KERNEL_UPDATE_PAGE,
+//!         dynamic_deferred_call
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(kernel_flash, kernel_update);
+//! kernel_update.set_deferred_call_handle(
+//!     dynamic_deferred_call
+//!         .register(kernel_update)
+//!         .expect("no deferred call slot available for kernel updates"),
+//! );
+//!
+//! let kernel_update_driver = static_init!(
+//!     capsules::app_update::AppUpdateDriver<'static, KernelUpdate<...>>,
+//!     capsules::app_update::AppUpdateDriver::new(
+//!         kernel_update,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! kernel_update.add_client(kernel_update_driver);
+//!
+//! // Once the board is up.
+//! kernel_update.confirm();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil;
+use kernel::ReturnCode;
+use tock_boot::{crc32, Handoff, Header, Verifier, MAX_SIGNATURE_LENGTH};
+
+use crate::app_update::{Update, UpdateClient};
+use crate::driver;
+
+/// Syscall driver number, for an `app_update::AppUpdateDriver` of the
+/// kernel.
+pub const DRIVER_NUM: usize = driver::NUM::KernelUpdate as usize;
+
+/// Most clients of a `KernelUpdate`, one for each front end.
+const MAX_CLIENTS: usize = 3;
+
+/// Receives the result of `confirm`.
+pub trait ConfirmClient {
+    fn confirm_done(&self, result: ReturnCode);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Reading the header of a slot.
+    Loading(usize),
+    /// Reading the header of the slot of the operation, to change it.
+    HeaderReading,
+    /// Erasing the header page of the slot of the operation.
+    HeaderErasing,
+    /// Writing the header page of the slot of the operation.
+    HeaderWriting,
+    /// Erasing the page of the image that the page buffer is written to.
+    Erasing,
+    /// Writing the page buffer to the image.
+    Writing,
+    /// Reading back a page of the image to check it.
+    Verifying(usize),
+    /// The image and its header are done, and a deferred call reports the
+    /// result to the update clients, or a confirmation to the confirm client.
+    Completing,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Begin,
+    Write,
+    Install,
+    Rollback,
+    Confirm,
+}
+
+pub struct KernelUpdate<'a, F: hil::flash::Flash + 'static> {
+    flash: &'a F,
+    slots: [usize; 2],
+    slot_pages: usize,
+    page_size: usize,
+    flash_address: usize,
+    // The slot the kernel was booted from, and whether the bootloader gave up
+    // on the image in the other one.
+    running: usize,
+    failed: bool,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+    clients: [OptionalCell<&'a UpdateClient>; MAX_CLIENTS],
+    confirm_client: OptionalCell<&'a ConfirmClient>,
+    verifier: OptionalCell<&'a Verifier>,
+
+    page: TakeCell<'static, F::Page>,
+    // The headers of the slots, once both have been read.
+    headers: Cell<[Option<Header>; 2]>,
+    loaded: Cell<bool>,
+
+    state: Cell<State>,
+    operation: Cell<Operation>,
+    result: Cell<ReturnCode>,
+    // The slot that the operation changes, and the state that `confirm`
+    // gives its header.
+    slot: Cell<usize>,
+    new_state: Cell<tock_boot::State>,
+
+    // The image of the session, or the one checked by `rollback`.
+    session: Cell<bool>,
+    length: Cell<usize>,
+    crc: Cell<u32>,
+    signature: Cell<[u8; MAX_SIGNATURE_LENGTH]>,
+    signature_length: Cell<usize>,
+    received: Cell<usize>,
+    // Bytes taken by the current write.
+    written: Cell<usize>,
+    // CRC of the bytes of the image read back so far.
+    verify_crc: Cell<u32>,
+}
+
+impl<F: hil::flash::Flash> KernelUpdate<'a, F> {
+    /// Creates the update of the two slots that start at the pages `slots`
+    /// and have `slot_pages` pages each. `flash_address` is the address at
+    /// which page 0 of `flash` is mapped. `handoff` is the handoff from the
+    /// bootloader, which is ignored if the kernel was not booted by it.
+    pub fn new(
+        flash: &'a F,
+        slots: [usize; 2],
+        slot_pages: usize,
+        flash_address: usize,
+        handoff: &Handoff,
+        page: &'static mut F::Page,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> KernelUpdate<'a, F> {
+        let page_size = page.as_mut().len();
+        let running = if handoff.is_valid() && handoff.slot == 1 {
+            1
+        } else {
+            0
+        };
+        KernelUpdate {
+            flash: flash,
+            slots: slots,
+            slot_pages: slot_pages,
+            page_size: page_size,
+            flash_address: flash_address,
+            running: running,
+            failed: handoff.is_valid() && handoff.failed & (1 << (1 - running)) != 0,
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            clients: [
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+            ],
+            confirm_client: OptionalCell::empty(),
+            verifier: OptionalCell::empty(),
+            page: TakeCell::new(page),
+            headers: Cell::new([None; 2]),
+            loaded: Cell::new(false),
+            state: Cell::new(State::Idle),
+            operation: Cell::new(Operation::Begin),
+            result: Cell::new(ReturnCode::SUCCESS),
+            slot: Cell::new(0),
+            new_state: Cell::new(tock_boot::State::Confirmed),
+            session: Cell::new(false),
+            length: Cell::new(0),
+            crc: Cell::new(0),
+            signature: Cell::new([0; MAX_SIGNATURE_LENGTH]),
+            signature_length: Cell::new(0),
+            received: Cell::new(0),
+            written: Cell::new(0),
+            verify_crc: Cell::new(0),
+        }
+    }
+
+    pub fn set_deferred_call_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Adds a client, such as a front end. Returns ENOMEM if there are
+    /// already `MAX_CLIENTS` clients.
+    pub fn add_client(&self, client: &'a UpdateClient) -> ReturnCode {
+        match self.clients.iter().find(|cell| cell.is_none()) {
+            Some(cell) => {
+                cell.set(client);
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    pub fn set_confirm_client(&self, client: &'a ConfirmClient) {
+        self.confirm_client.set(client);
+    }
+
+    /// Sets the verifier that checks the signatures of images when they are
+    /// installed. The bootloader checks them at boot only if it has its own.
+    pub fn set_verifier(&self, verifier: &'a Verifier) {
+        self.verifier.set(verifier);
+    }
+
+    /// The slot the kernel was booted from.
+    pub fn running_slot(&self) -> usize {
+        self.running
+    }
+
+    /// Confirms that the running kernel works, so that the bootloader keeps
+    /// booting it. If the bootloader gave up on the kernel in the other slot,
+    /// that kernel is rejected. `confirm_done` follows.
+    pub fn confirm(&self) -> ReturnCode {
+        if self.session.get() || self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.start(Operation::Confirm)
+    }
+
+    fn inactive(&self) -> usize {
+        1 - self.running
+    }
+
+    fn header_page(&self, slot: usize) -> usize {
+        self.slots[slot]
+    }
+
+    fn image_page(&self, slot: usize, index: usize) -> usize {
+        self.slots[slot] + 1 + index
+    }
+
+    // The address that an image in the slot must be linked to run from.
+    fn image_address(&self, slot: usize) -> usize {
+        self.flash_address + self.image_page(slot, 0) * self.page_size
+    }
+
+    // Run an operation, reading the headers first if needed.
+    fn start(&self, operation: Operation) -> ReturnCode {
+        self.operation.set(operation);
+        let result = if self.loaded.get() {
+            self.run()
+        } else {
+            self.read_page(State::Loading(0), self.header_page(0))
+        };
+        if result != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+            if operation == Operation::Begin || operation == Operation::Install {
+                self.session.set(false);
+            }
+        }
+        result
+    }
+
+    fn run(&self) -> ReturnCode {
+        match self.operation.get() {
+            Operation::Begin => {
+                // Erase the header first, so that the bootloader never boots
+                // a partly written image.
+                self.slot.set(self.inactive());
+                self.state.set(State::HeaderErasing);
+                self.flash.erase_page(self.header_page(self.inactive()))
+            }
+            Operation::Write => self.complete(ReturnCode::FAIL),
+            Operation::Install => self.verify(),
+            Operation::Rollback => {
+                let slot = self.inactive();
+                match self.headers.get()[slot] {
+                    Some(header) if header.state != tock_boot::State::Rejected => {
+                        self.slot.set(slot);
+                        self.length.set(header.length as usize);
+                        self.crc.set(header.crc);
+                        // Read the signature, to keep it in the new header.
+                        self.read_page(State::HeaderReading, self.header_page(slot))
+                    }
+                    _ => self.complete(ReturnCode::EINVAL),
+                }
+            }
+            Operation::Confirm => self.confirm_next(),
+        }
+    }
+
+    fn read_page(&self, state: State, page_number: usize) -> ReturnCode {
+        self.page.take().map_or(ReturnCode::ERESERVE, |page| {
+            self.state.set(state);
+            self.flash.read_page(page_number, page)
+        })
+    }
+
+    // Change the state of the next header that needs it, or complete the
+    // confirmation.
+    fn confirm_next(&self) -> ReturnCode {
+        let headers = self.headers.get();
+        let pending = |slot: usize| {
+            headers[slot].map_or(false, |header| header.state == tock_boot::State::Pending)
+        };
+        let (slot, state) = if self.failed && pending(self.inactive()) {
+            (self.inactive(), tock_boot::State::Rejected)
+        } else if pending(self.running) {
+            (self.running, tock_boot::State::Confirmed)
+        } else {
+            return self.complete(ReturnCode::SUCCESS);
+        };
+        self.slot.set(slot);
+        self.new_state.set(state);
+        self.read_page(State::HeaderReading, self.header_page(slot))
+    }
+
+    // Place as much of `data` in the page buffer as fits. Returns the number
+    // of bytes of `data` taken.
+    fn place(&self, page: &mut [u8], data: &[u8]) -> usize {
+        let offset = self.received.get() % self.page_size;
+        let length = cmp::min(
+            cmp::min(data.len(), self.page_size - offset),
+            self.length.get() - self.received.get(),
+        );
+        page[offset..offset + length].copy_from_slice(&data[..length]);
+        self.received.set(self.received.get() + length);
+        length
+    }
+
+    // Write the page buffer to flash if it is full or holds the end of the
+    // image, or complete the write.
+    fn flush(&self) -> ReturnCode {
+        let received = self.received.get();
+        if received % self.page_size == 0 || received == self.length.get() {
+            self.state.set(State::Erasing);
+            self.flash.erase_page(self.page_of_received())
+        } else {
+            self.complete(ReturnCode::SUCCESS)
+        }
+    }
+
+    // The page of the image that holds the last byte received.
+    fn page_of_received(&self) -> usize {
+        self.image_page(self.inactive(), (self.received.get() - 1) / self.page_size)
+    }
+
+    fn clear_page(&self) {
+        self.page.map(|page| {
+            for byte in page.as_mut().iter_mut() {
+                *byte = 0xff;
+            }
+        });
+    }
+
+    // Read back the image in the slot of the operation to check it.
+    fn verify(&self) -> ReturnCode {
+        self.verify_crc.set(0);
+        if self.operation.get() == Operation::Install {
+            self.verifier.map(|verifier| verifier.reset());
+        }
+        self.read_page(State::Verifying(0), self.image_page(self.slot.get(), 0))
+    }
+
+    // Page `index` of the image was read back.
+    fn page_verified(&self, page: &'static mut F::Page, index: usize) -> ReturnCode {
+        let length = self.length.get();
+        let start = index * self.page_size;
+        let end = cmp::min(start + self.page_size, length);
+        let install = self.operation.get() == Operation::Install;
+        {
+            let data = &page.as_mut()[..end - start];
+            if index == 0 && !tock_boot::valid_vectors(data, self.image_address(self.slot.get())) {
+                self.page.replace(page);
+                return self.complete(ReturnCode::EINVAL);
+            }
+            self.verify_crc.set(crc32(self.verify_crc.get(), data));
+            if install {
+                self.verifier.map(|verifier| verifier.update(data));
+            }
+        }
+        if end < length {
+            self.state.set(State::Verifying(index + 1));
+            return self
+                .flash
+                .read_page(self.image_page(self.slot.get(), index + 1), page);
+        }
+        self.page.replace(page);
+
+        let signature = self.signature.get();
+        let signature = &signature[..self.signature_length.get()];
+        let signed = !install
+            || self
+                .verifier
+                .map_or(true, |verifier| verifier.verify(signature));
+        if self.verify_crc.get() != self.crc.get() || !signed {
+            return self.complete(ReturnCode::FAIL);
+        }
+        self.write_header(signature)
+    }
+
+    // Write the header that makes the image of the operation the newest.
+    fn write_header(&self, signature: &[u8]) -> ReturnCode {
+        let headers = self.headers.get();
+        let newest = headers.iter().filter_map(|header| *header).fold(
+            None,
+            |newest: Option<Header>, header| match newest {
+                Some(newest) if !header.is_newer_than(&newest) => Some(newest),
+                _ => Some(header),
+            },
+        );
+        let state = match headers[self.slot.get()] {
+            Some(header) if self.operation.get() == Operation::Rollback => header.state,
+            _ => tock_boot::State::Pending,
+        };
+        let header = Header {
+            version: newest.map_or(1, |newest| newest.version.wrapping_add(1)),
+            length: self.length.get() as u32,
+            crc: self.crc.get(),
+            state: state,
+            signature_length: signature.len(),
+        };
+        self.clear_page();
+        self.page.map(|page| header.write(page.as_mut(), signature));
+        self.state.set(State::HeaderErasing);
+        self.flash.erase_page(self.header_page(self.slot.get()))
+    }
+
+    fn set_header(&self, slot: usize, header: Option<Header>) {
+        let mut headers = self.headers.get();
+        headers[slot] = header;
+        self.headers.set(headers);
+    }
+
+    // Report `result` from a deferred call. A confirmation with no pending
+    // header, or a `Write` outside a session, ends without touching flash,
+    // so the clients would otherwise be called back from within the request.
+    fn complete(&self, result: ReturnCode) -> ReturnCode {
+        self.result.set(result);
+        self.state.set(State::Completing);
+        self.handle.map_or(ReturnCode::FAIL, |handle| {
+            self.deferred_caller.set(*handle);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn continue_with(&self, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.complete(result);
+        }
+    }
+
+    // The flash may no longer match the headers, so read them again before
+    // the next operation.
+    fn failed(&self) {
+        self.loaded.set(false);
+        self.complete(ReturnCode::FAIL);
+    }
+}
+
+impl<F: hil::flash::Flash> Update for KernelUpdate<'a, F> {
+    fn slot_size(&self) -> usize {
+        (self.slot_pages - 1) * self.page_size
+    }
+
+    fn received(&self) -> usize {
+        self.received.get()
+    }
+
+    fn in_session(&self) -> bool {
+        self.session.get()
+    }
+
+    fn begin(&self, length: usize, crc: u32) -> ReturnCode {
+        if self.session.get() || self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if length < 8 || length % 4 != 0 || length > self.slot_size() {
+            return ReturnCode::EINVAL;
+        }
+        self.session.set(true);
+        self.length.set(length);
+        self.crc.set(crc);
+        self.received.set(0);
+        self.start(Operation::Begin)
+    }
+
+    fn write(&self, data: &[u8]) -> ReturnCode {
+        if !self.session.get() || data.len() == 0 {
+            return ReturnCode::EINVAL;
+        }
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if self.received.get() == self.length.get() {
+            return ReturnCode::ESIZE;
+        }
+        self.operation.set(Operation::Write);
+        let result = self.page.map_or(ReturnCode::ERESERVE, |page| {
+            self.written.set(self.place(page.as_mut(), data));
+            ReturnCode::SUCCESS
+        });
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        let result = self.flush();
+        if result != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+            self.session.set(false);
+        }
+        result
+    }
+
+    /// The signature is checked by the verifier, if the board set one.
+    fn install(&self, signature: &[u8]) -> ReturnCode {
+        if !self.session.get() || self.received.get() != self.length.get() {
+            return ReturnCode::EINVAL;
+        }
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if signature.len() > MAX_SIGNATURE_LENGTH {
+            return ReturnCode::ESIZE;
+        }
+        let mut buffer = [0; MAX_SIGNATURE_LENGTH];
+        buffer[..signature.len()].copy_from_slice(signature);
+        self.signature.set(buffer);
+        self.signature_length.set(signature.len());
+        self.slot.set(self.inactive());
+        self.start(Operation::Install)
+    }
+
+    /// Makes the kernel in the slot that is not running the newest, after
+    /// checking its CRC again. It is booted after the next reset.
+    fn rollback(&self) -> ReturnCode {
+        if self.session.get() || self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.start(Operation::Rollback)
+    }
+
+    fn abort(&self) -> ReturnCode {
+        if !self.session.get() {
+            return ReturnCode::EINVAL;
+        }
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.session.set(false);
+        ReturnCode::SUCCESS
+    }
+}
+
+impl<F: hil::flash::Flash> hil::flash::Client<F> for KernelUpdate<'a, F> {
+    fn read_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        if error != hil::flash::Error::CommandComplete {
+            self.page.replace(page);
+            return self.failed();
+        }
+        let result = match self.state.get() {
+            State::Loading(slot) => {
+                self.set_header(slot, Header::parse(page.as_mut()));
+                if slot == 0 {
+                    self.state.set(State::Loading(1));
+                    self.flash.read_page(self.header_page(1), page)
+                } else {
+                    self.page.replace(page);
+                    self.loaded.set(true);
+                    self.run()
+                }
+            }
+            State::HeaderReading => match self.operation.get() {
+                Operation::Rollback => {
+                    let mut signature = [0; MAX_SIGNATURE_LENGTH];
+                    let length = Header::parse(page.as_mut()).map_or(0, |header| {
+                        let source = header.signature(page.as_mut());
+                        signature[..source.len()].copy_from_slice(source);
+                        source.len()
+                    });
+                    self.signature.set(signature);
+                    self.signature_length.set(length);
+                    self.page.replace(page);
+                    self.verify()
+                }
+                _ => {
+                    tock_boot::set_state(page.as_mut(), self.new_state.get());
+                    self.page.replace(page);
+                    self.state.set(State::HeaderErasing);
+                    self.flash.erase_page(self.header_page(self.slot.get()))
+                }
+            },
+            State::Verifying(index) => self.page_verified(page, index),
+            _ => {
+                self.page.replace(page);
+                ReturnCode::SUCCESS
+            }
+        };
+        self.continue_with(result);
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        if error != hil::flash::Error::CommandComplete {
+            self.page.replace(page);
+            return self.failed();
+        }
+        let result = match self.state.get() {
+            State::Writing => {
+                self.page.replace(page);
+                self.clear_page();
+                self.complete(ReturnCode::SUCCESS)
+            }
+            State::HeaderWriting => {
+                self.set_header(self.slot.get(), Header::parse(page.as_mut()));
+                self.page.replace(page);
+                match self.operation.get() {
+                    Operation::Confirm => self.confirm_next(),
+                    _ => self.complete(ReturnCode::SUCCESS),
+                }
+            }
+            _ => {
+                self.page.replace(page);
+                ReturnCode::SUCCESS
+            }
+        };
+        self.continue_with(result);
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        if error != hil::flash::Error::CommandComplete {
+            return self.failed();
+        }
+        let result = match self.state.get() {
+            State::HeaderErasing if self.operation.get() == Operation::Begin => {
+                self.set_header(self.inactive(), None);
+                self.clear_page();
+                self.complete(ReturnCode::SUCCESS)
+            }
+            State::HeaderErasing => self.page.take().map_or(ReturnCode::ERESERVE, |page| {
+                self.state.set(State::HeaderWriting);
+                self.flash
+                    .write_page(self.header_page(self.slot.get()), page)
+            }),
+            State::Erasing => self.page.take().map_or(ReturnCode::ERESERVE, |page| {
+                self.state.set(State::Writing);
+                self.flash.write_page(self.page_of_received(), page)
+            }),
+            _ => ReturnCode::SUCCESS,
+        };
+        self.continue_with(result);
+    }
+}
+
+impl<F: hil::flash::Flash> DynamicDeferredCallClient for KernelUpdate<'a, F> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if self.state.get() != State::Completing {
+            return;
+        }
+        self.state.set(State::Idle);
+        let result = self.result.get();
+        let operation = self.operation.get();
+        // A failed operation or an installed image ends the session.
+        if result != ReturnCode::SUCCESS || operation == Operation::Install {
+            self.session.set(false);
+        }
+        if operation == Operation::Confirm {
+            self.confirm_client
+                .map(|client| client.confirm_done(result));
+            return;
+        }
+        for cell in self.clients.iter() {
+            cell.map(|client| match operation {
+                Operation::Begin => client.begin_done(result),
+                Operation::Write => client.write_done(self.written.get(), result),
+                _ => client.install_done(result),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::storage_sim::{leak, pump, Sim, SimFlash, SimPage, WriteMode, PAGE_SIZE};
+    use kernel::hil::flash::HasClient;
+    use std::cell::RefCell;
+    use std::vec;
+    use std::vec::Vec;
+
+    // Two slots of a header page and four pages of image.
+    const SLOTS: [usize; 2] = [0, 5];
+    const SLOT_PAGES: usize = 5;
+    const FLASH_ADDRESS: usize = 0x10000;
+
+    fn image_address(slot: usize) -> usize {
+        FLASH_ADDRESS + (SLOTS[slot] + 1) * PAGE_SIZE
+    }
+
+    /// A kernel image of `length` bytes linked to run from `slot`.
+    fn image(slot: usize, length: usize, fill: u8) -> Vec<u8> {
+        let mut image = vec![fill; length];
+        let reset_handler = (image_address(slot) + 0x41) as u32;
+        image[4..8].copy_from_slice(&reset_handler.to_le_bytes());
+        image
+    }
+
+    /// The flash of a board, and the handoff from its bootloader to its
+    /// kernel, both of which survive resets.
+    struct Device {
+        flash: &'static SimFlash<'static>,
+        deferred_caller: &'static DynamicDeferredCall,
+        handoff: RefCell<Handoff>,
+    }
+
+    fn sim() -> &'static Device {
+        // The first kernel is flashed to slot 0 with a confirmed header, as
+        // by `boards/ab_bootloader/add_header.py`.
+        let mut storage = vec![0xff; PAGE_SIZE * 2 * SLOT_PAGES];
+        let first = image(0, 1000, 0);
+        let start = image_address(0) - FLASH_ADDRESS;
+        storage[start..start + first.len()].copy_from_slice(&first);
+        let header = Header {
+            version: 1,
+            length: first.len() as u32,
+            crc: crc32(0, &first),
+            state: tock_boot::State::Confirmed,
+            signature_length: 0,
+        };
+        header.write(&mut storage[SLOTS[0] * PAGE_SIZE..], &[]);
+        let sim = Sim::flash(storage, WriteMode::Nor);
+        leak(Device {
+            flash: sim.storage,
+            deferred_caller: sim.deferred_caller,
+            handoff: RefCell::new(Handoff::new()),
+        })
+    }
+
+    /// Runs the bootloader, and returns the slot it boots.
+    fn bootloader(sim: &Device) -> usize {
+        let headers = sim
+            .flash
+            .map_storage(|storage| {
+                let check = |slot: usize| {
+                    let start = SLOTS[slot] * PAGE_SIZE;
+                    tock_boot::check_slot(
+                        &storage[start..start + SLOT_PAGES * PAGE_SIZE],
+                        FLASH_ADDRESS + start,
+                        PAGE_SIZE,
+                        None,
+                    )
+                };
+                [check(0), check(1)]
+            })
+            .unwrap();
+        tock_boot::select(&headers, &mut sim.handoff.borrow_mut()).expect("no kernel to boot")
+    }
+
+    fn header(sim: &Device, slot: usize) -> Option<Header> {
+        sim.flash
+            .map_storage(|storage| Header::parse(&storage[SLOTS[slot] * PAGE_SIZE..]))
+            .unwrap()
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Begun(ReturnCode),
+        Written(usize, ReturnCode),
+        Installed(ReturnCode),
+        Confirmed(ReturnCode),
+    }
+
+    struct TestClient {
+        events: RefCell<Vec<Event>>,
+    }
+
+    impl TestClient {
+        fn last(&self) -> Option<Event> {
+            self.events.borrow_mut().pop()
+        }
+    }
+
+    impl UpdateClient for TestClient {
+        fn begin_done(&self, result: ReturnCode) {
+            self.events.borrow_mut().push(Event::Begun(result));
+        }
+
+        fn write_done(&self, length: usize, result: ReturnCode) {
+            self.events
+                .borrow_mut()
+                .push(Event::Written(length, result));
+        }
+
+        fn install_done(&self, result: ReturnCode) {
+            self.events.borrow_mut().push(Event::Installed(result));
+        }
+    }
+
+    impl ConfirmClient for TestClient {
+        fn confirm_done(&self, result: ReturnCode) {
+            self.events.borrow_mut().push(Event::Confirmed(result));
+        }
+    }
+
+    type SimUpdate = KernelUpdate<'static, SimFlash<'static>>;
+
+    /// Boots the kernel from the slot the bootloader chose.
+    fn boot(sim: &'static Device) -> (&'static SimUpdate, &'static TestClient) {
+        let update = leak(KernelUpdate::new(
+            sim.flash,
+            SLOTS,
+            SLOT_PAGES,
+            FLASH_ADDRESS,
+            &sim.handoff.borrow(),
+            leak(SimPage::new()),
+            sim.deferred_caller,
+        ));
+        sim.flash.set_client(update);
+        update.set_deferred_call_handle(sim.deferred_caller.register(update).unwrap());
+        let client = leak(TestClient {
+            events: RefCell::new(Vec::new()),
+        });
+        update.add_client(client);
+        update.set_confirm_client(client);
+        (update, client)
+    }
+
+    fn install(
+        sim: &Device,
+        update: &SimUpdate,
+        client: &TestClient,
+        image: &[u8],
+        crc: u32,
+    ) -> Option<Event> {
+        assert_eq!(update.begin(image.len(), crc), ReturnCode::SUCCESS);
+        pump(sim.deferred_caller);
+        assert_eq!(client.last(), Some(Event::Begun(ReturnCode::SUCCESS)));
+        let mut position = 0;
+        while position < image.len() {
+            assert_eq!(update.write(&image[position..]), ReturnCode::SUCCESS);
+            pump(sim.deferred_caller);
+            match client.last() {
+                Some(Event::Written(length, ReturnCode::SUCCESS)) => position += length,
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+        assert_eq!(update.install(&[]), ReturnCode::SUCCESS);
+        pump(sim.deferred_caller);
+        client.last()
+    }
+
+    fn confirm(sim: &Device, update: &SimUpdate, client: &TestClient) -> Option<Event> {
+        assert_eq!(update.confirm(), ReturnCode::SUCCESS);
+        pump(sim.deferred_caller);
+        client.last()
+    }
+
+    const INSTALLED: Option<Event> = Some(Event::Installed(ReturnCode::SUCCESS));
+    const CONFIRMED: Option<Event> = Some(Event::Confirmed(ReturnCode::SUCCESS));
+
+    #[test]
+    fn installs_and_confirms_kernels() {
+        let sim = sim();
+        assert_eq!(bootloader(sim), 0);
+        let (update, client) = boot(sim);
+        assert_eq!(confirm(sim, update, client), CONFIRMED);
+
+        let first = image(1, 1500, 1);
+        assert_eq!(
+            install(sim, update, client, &first, crc32(0, &first)),
+            INSTALLED
+        );
+        assert_eq!(
+            header(sim, 1).map(|header| header.state),
+            Some(tock_boot::State::Pending)
+        );
+        sim.flash.map_storage(|storage| {
+            let start = image_address(1) - FLASH_ADDRESS;
+            assert_eq!(&storage[start..start + first.len()], &first[..]);
+        });
+
+        assert_eq!(bootloader(sim), 1);
+        let (update, client) = boot(sim);
+        assert_eq!(update.running_slot(), 1);
+        assert_eq!(confirm(sim, update, client), CONFIRMED);
+        assert_eq!(
+            header(sim, 1).map(|header| header.state),
+            Some(tock_boot::State::Confirmed)
+        );
+        for _ in 0..2 * tock_boot::MAX_ATTEMPTS {
+            assert_eq!(bootloader(sim), 1);
+        }
+
+        // The next kernel goes to the other slot, and fills it.
+        let second = image(0, 4 * PAGE_SIZE, 2);
+        assert_eq!(
+            install(sim, update, client, &second, crc32(0, &second)),
+            INSTALLED
+        );
+        assert_eq!(bootloader(sim), 0);
+        let (update, client) = boot(sim);
+        assert_eq!(confirm(sim, update, client), CONFIRMED);
+        assert_eq!(header(sim, 0).map(|header| header.version), Some(3));
+
+        // Roll back to the first kernel.
+        assert_eq!(update.rollback(), ReturnCode::SUCCESS);
+        pump(sim.deferred_caller);
+        assert_eq!(client.last(), INSTALLED);
+        assert_eq!(bootloader(sim), 1);
+        assert_eq!(header(sim, 1).map(|header| header.version), Some(4));
+    }
+
+    #[test]
+    fn rolls_back_a_kernel_that_fails_to_boot() {
+        let sim = sim();
+        assert_eq!(bootloader(sim), 0);
+        let (update, client) = boot(sim);
+        let bad = image(1, 1000, 3);
+        assert_eq!(
+            install(sim, update, client, &bad, crc32(0, &bad)),
+            INSTALLED
+        );
+
+        // The new kernel never confirms itself.
+        for _ in 0..tock_boot::MAX_ATTEMPTS {
+            assert_eq!(bootloader(sim), 1);
+        }
+        assert_eq!(bootloader(sim), 0);
+        let (update, client) = boot(sim);
+        assert_eq!(confirm(sim, update, client), CONFIRMED);
+        assert_eq!(
+            header(sim, 1).map(|header| header.state),
+            Some(tock_boot::State::Rejected)
+        );
+        assert_eq!(bootloader(sim), 0);
+        assert_eq!(sim.handoff.borrow().failed, 0);
+
+        // A rejected kernel cannot be rolled back to.
+        assert_eq!(update.rollback(), ReturnCode::SUCCESS);
+        pump(sim.deferred_caller);
+        assert_eq!(client.last(), Some(Event::Installed(ReturnCode::EINVAL)));
+    }
+
+    #[test]
+    fn checks_kernels_before_installing() {
+        let sim = sim();
+        bootloader(sim);
+        let (update, client) = boot(sim);
+        for &length in [4, 1002, 4 * PAGE_SIZE + 4].iter() {
+            assert_eq!(update.begin(length, 0), ReturnCode::EINVAL);
+        }
+
+        let good = image(1, 1200, 4);
+        assert_eq!(
+            install(sim, update, client, &good, 0x1234),
+            Some(Event::Installed(ReturnCode::FAIL))
+        );
+        assert!(!update.in_session());
+        // Linked for the other slot.
+        let elsewhere = image(0, 1200, 4);
+        assert_eq!(
+            install(sim, update, client, &elsewhere, crc32(0, &elsewhere)),
+            Some(Event::Installed(ReturnCode::EINVAL))
+        );
+        assert_eq!(header(sim, 1), None);
+        assert_eq!(bootloader(sim), 0);
+
+        // An aborted update leaves no header behind.
+        assert_eq!(
+            install(sim, update, client, &good, crc32(0, &good)),
+            INSTALLED
+        );
+        assert_eq!(
+            update.begin(good.len(), crc32(0, &good)),
+            ReturnCode::SUCCESS
+        );
+        pump(sim.deferred_caller);
+        assert_eq!(update.abort(), ReturnCode::SUCCESS);
+        assert_eq!(header(sim, 1), None);
+        assert_eq!(bootloader(sim), 0);
+    }
+}
@@ -0,0 +1,1350 @@
+//! Persistent key-value store in flash, for kernel capsules and processes.
+//!
+//! `KVStore` keeps small values, such as configuration, in two pages of a
+//! `hil::flash::Flash`. The kernel uses the store through `hil::kv_store`,
+//! and processes through the syscall driver. Every process gets its own
+//! namespace, named by its package name, and the kernel has a namespace of
+//! its own, so keys never clash between them.
+//!
+//! Layout
+//! ------
+//!
+//! One of the two pages is active at a time. It starts with a header holding
+//! a magic number, a generation number, a CRC of the header and a commit
+//! word. Records follow, each holding the key and value of one update or
+//! deletion, protected by a CRC:
+//!
+//! ```text
+//! +-------+------------+-----+--------+---------+------+--------+-----------+-----+-------+
+//! | magic | generation | CRC | commit | key len | kind | length | namespace | CRC | key...|
+//! +-------+------------+-----+--------+---------+------+--------+-----------+-----+-------+
+//! \___________ page header ___________/\______________ record header ______________/
+//! ```
+//!
+//! Updates append a record to the active page; the newest record for a key
+//! holds its value. A record is written in one page write, and is ignored if
+//! its CRC does not match, so an update interrupted by a loss of power has no
+//! effect.
+//!
+//! When the active page is full, the newest record of every key is copied to
+//! the other page with a higher generation (garbage collection). The commit
+//! word is cleared with a second write once the page is complete, and only
+//! committed pages are used, so the old page stays active until the new one
+//! is fully written.
+//!
+//! The store keeps a copy of the active page in RAM, read from flash when it
+//! is first used. Flash that is erased as part of every page write, rather
+//! than only programmed, can lose updates made since the last garbage
+//! collection if power is lost during a write.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let kv_flash = static_init!(
+//!     capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::virtual_flash::FlashUser::new(mux_flash)
+//! );
+//! let kv_store = static_init!(
+//!     capsules::kv_store::KVStore<
+//!         'static,
+//!         capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+//!     >,
+//!     capsules::kv_store::KVStore::new(
+//!         kv_flash,
+//!         510, // First of the two flash pages of the store
+//!         &mut KV_PAGE,
+//!         &mut KV_SPARE_PAGE,
+//!         &mut capsules::kv_store::BUFFER,
+//!         board_kernel.create_grant(&grant_cap),
+//!         dynamic_deferred_call
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(kv_flash, kv_store);
+//! kv_store.set_deferred_call_handle(
+//!     dynamic_deferred_call
+//!         .register(kv_store)
+//!         .expect("no deferred call slot available for the key-value store"),
+//! );
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil;
+use kernel::hil::kv_store::KVStoreClient;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use tock_boot::crc32;
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::KVStore as usize;
+
+/// Buffer for the values of processes.
+pub static mut BUFFER: [u8; 128] = [0; 128];
+
+/// Longest key, in bytes.
+pub const MAX_KEY_LENGTH: usize = 32;
+
+/// Namespace of the keys of the kernel.
+const KERNEL_NAMESPACE: u32 = 0;
+
+const PAGE_MAGIC: [u8; 4] = *b"TKVS";
+const PAGE_HEADER_SIZE: usize = 16;
+const PAGE_COMMITTED: u32 = 0;
+const RECORD_HEADER_SIZE: usize = 12;
+const RECORD_ERASED: u8 = 0xff;
+const RECORD_VALUE: u8 = 1;
+const RECORD_DELETED: u8 = 2;
+
+fn read_u32(buf: &[u8]) -> u32 {
+    buf[0] as u32 | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24
+}
+
+fn write_u32(buf: &mut [u8], value: u32) {
+    for i in 0..4 {
+        buf[i] = (value >> (8 * i)) as u8;
+    }
+}
+
+/// Namespace of the keys of a process with the given package name. It is
+/// never the namespace of the kernel.
+fn app_namespace(name: &str) -> u32 {
+    let hash = name.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    });
+    cmp::max(hash, 1)
+}
+
+/// Returns the generation of a page, if it has a committed header.
+fn parse_header(page: &[u8]) -> Option<u32> {
+    if page[0..4] != PAGE_MAGIC
+        || read_u32(&page[8..12]) != crc32(0, &page[0..8])
+        || read_u32(&page[12..16]) != PAGE_COMMITTED
+    {
+        return None;
+    }
+    Some(read_u32(&page[4..8]))
+}
+
+fn write_header(page: &mut [u8], generation: u32, commit: u32) {
+    page[0..4].copy_from_slice(&PAGE_MAGIC);
+    write_u32(&mut page[4..8], generation);
+    let crc = crc32(0, &page[0..8]);
+    write_u32(&mut page[8..12], crc);
+    write_u32(&mut page[12..16], commit);
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    offset: usize,
+    kind: u8,
+    namespace: u32,
+    key_length: usize,
+    value_length: usize,
+}
+
+impl Record {
+    fn key(&self) -> usize {
+        self.offset + RECORD_HEADER_SIZE
+    }
+
+    fn value(&self) -> usize {
+        self.key() + self.key_length
+    }
+
+    fn end(&self) -> usize {
+        self.value() + self.value_length
+    }
+
+    fn matches(&self, page: &[u8], namespace: u32, key: &[u8]) -> bool {
+        self.namespace == namespace && &page[self.key()..self.value()] == key
+    }
+}
+
+/// The outcome of reading the record at an offset of a page.
+enum Parsed {
+    Record(Record),
+    /// The rest of the page is erased.
+    End,
+    /// The record is damaged, so the rest of the page cannot be used.
+    Invalid,
+}
+
+fn parse_record(page: &[u8], offset: usize) -> Parsed {
+    if offset + RECORD_HEADER_SIZE > page.len() || page[offset] == RECORD_ERASED {
+        return Parsed::End;
+    }
+    let record = Record {
+        offset: offset,
+        kind: page[offset + 1],
+        namespace: read_u32(&page[offset + 4..offset + 8]),
+        key_length: page[offset] as usize,
+        value_length: page[offset + 2] as usize | (page[offset + 3] as usize) << 8,
+    };
+    if record.end() > page.len() || (record.kind != RECORD_VALUE && record.kind != RECORD_DELETED) {
+        return Parsed::Invalid;
+    }
+    let crc = crc32(
+        crc32(0, &page[offset..offset + 8]),
+        &page[record.key()..record.end()],
+    );
+    if crc != read_u32(&page[offset + 8..offset + 12]) {
+        return Parsed::Invalid;
+    }
+    Parsed::Record(record)
+}
+
+/// Calls `f` with every valid record of the page, and returns the offset
+/// where the next record can be written.
+fn for_each_record<F: FnMut(Record)>(page: &[u8], mut f: F) -> usize {
+    let mut offset = PAGE_HEADER_SIZE;
+    loop {
+        match parse_record(page, offset) {
+            Parsed::Record(record) => {
+                f(record);
+                offset = record.end();
+            }
+            Parsed::End => return offset,
+            Parsed::Invalid => return page.len(),
+        }
+    }
+}
+
+/// The newest record of a key.
+fn find(page: &[u8], namespace: u32, key: &[u8]) -> Option<Record> {
+    let mut found = None;
+    for_each_record(page, |record| {
+        if record.matches(page, namespace, key) {
+            found = Some(record);
+        }
+    });
+    found
+}
+
+fn write_record(
+    page: &mut [u8],
+    offset: usize,
+    kind: u8,
+    namespace: u32,
+    key: &[u8],
+    value: &[u8],
+) {
+    let key_start = offset + RECORD_HEADER_SIZE;
+    let value_start = key_start + key.len();
+    page[offset] = key.len() as u8;
+    page[offset + 1] = kind;
+    page[offset + 2] = value.len() as u8;
+    page[offset + 3] = (value.len() >> 8) as u8;
+    write_u32(&mut page[offset + 4..offset + 8], namespace);
+    page[key_start..value_start].copy_from_slice(key);
+    page[value_start..value_start + value.len()].copy_from_slice(value);
+    let crc = crc32(
+        crc32(0, &page[offset..offset + 8]),
+        &page[key_start..value_start + value.len()],
+    );
+    write_u32(&mut page[offset + 8..offset + 12], crc);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Reading one of the two pages to find the active one.
+    Loading(usize),
+    /// Writing a record to the active page.
+    Writing,
+    /// Erasing the page that garbage collection copies records to.
+    Erasing,
+    /// Writing the collected records.
+    Collecting,
+    /// Writing the commit word of the collected page.
+    Committing,
+    /// The active page is up to date with the operation, whose result goes to
+    /// the kernel client or process, whichever started it, from a deferred
+    /// call.
+    Completing,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Get,
+    Set(usize),
+    Delete,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum User {
+    Kernel,
+    App(AppId),
+}
+
+pub struct App {
+    callback_get: Option<Callback>,
+    callback_set: Option<Callback>,
+    callback_delete: Option<Callback>,
+    buffer_key: Option<AppSlice<Shared, u8>>,
+    buffer_value: Option<AppSlice<Shared, u8>>,
+    pending_command: Option<Operation>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback_get: None,
+            callback_set: None,
+            callback_delete: None,
+            buffer_key: None,
+            buffer_value: None,
+            pending_command: None,
+        }
+    }
+}
+
+pub struct KVStore<'a, F: hil::flash::Flash + 'static> {
+    flash: &'a F,
+    start_page: usize,
+    page_size: usize,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+
+    // Copy of the active page, and a buffer to build the next one in.
+    page: TakeCell<'static, F::Page>,
+    spare: TakeCell<'static, F::Page>,
+    // Which of the two pages is active, if the store has been loaded.
+    active: Cell<Option<usize>>,
+    generation: Cell<u32>,
+    // Where the next record goes in the active page.
+    tail: Cell<usize>,
+    // Where the next record goes in the spare page, during garbage collection.
+    spare_tail: Cell<usize>,
+
+    state: Cell<State>,
+    // The operation in progress, and who requested it.
+    user: OptionalCell<User>,
+    operation: Cell<Operation>,
+    namespace: Cell<u32>,
+    key: Cell<[u8; MAX_KEY_LENGTH]>,
+    key_length: Cell<usize>,
+    value: TakeCell<'static, [u8]>,
+    result: Cell<ReturnCode>,
+    result_length: Cell<usize>,
+
+    // Operation requested by the kernel, waiting for the store.
+    kernel_client: OptionalCell<&'static KVStoreClient>,
+    kernel_pending: Cell<Option<Operation>>,
+    kernel_key: Cell<[u8; MAX_KEY_LENGTH]>,
+    kernel_key_length: Cell<usize>,
+    kernel_buffer: TakeCell<'static, [u8]>,
+
+    apps: Grant<App>,
+    app_buffer: TakeCell<'static, [u8]>,
+    app_buffer_length: usize,
+}
+
+impl<F: hil::flash::Flash> KVStore<'a, F> {
+    /// Creates a store in flash pages `start_page` and `start_page + 1`.
+    /// `page` and `spare` are buffers for one page each, and `buffer` holds
+    /// the values of processes.
+    pub fn new(
+        flash: &'a F,
+        start_page: usize,
+        page: &'static mut F::Page,
+        spare: &'static mut F::Page,
+        buffer: &'static mut [u8],
+        grant: Grant<App>,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> KVStore<'a, F> {
+        let page_size = page.as_mut().len();
+        KVStore {
+            flash: flash,
+            start_page: start_page,
+            page_size: page_size,
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            page: TakeCell::new(page),
+            spare: TakeCell::new(spare),
+            active: Cell::new(None),
+            generation: Cell::new(0),
+            tail: Cell::new(PAGE_HEADER_SIZE),
+            spare_tail: Cell::new(PAGE_HEADER_SIZE),
+            state: Cell::new(State::Idle),
+            user: OptionalCell::empty(),
+            operation: Cell::new(Operation::Get),
+            namespace: Cell::new(KERNEL_NAMESPACE),
+            key: Cell::new([0; MAX_KEY_LENGTH]),
+            key_length: Cell::new(0),
+            value: TakeCell::empty(),
+            result: Cell::new(ReturnCode::SUCCESS),
+            result_length: Cell::new(0),
+            kernel_client: OptionalCell::empty(),
+            kernel_pending: Cell::new(None),
+            kernel_key: Cell::new([0; MAX_KEY_LENGTH]),
+            kernel_key_length: Cell::new(0),
+            kernel_buffer: TakeCell::empty(),
+            apps: grant,
+            app_buffer_length: buffer.len(),
+            app_buffer: TakeCell::new(buffer),
+        }
+    }
+
+    pub fn set_deferred_call_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Largest value that can be stored under a key of `key_length` bytes.
+    pub fn max_value_length(&self, key_length: usize) -> usize {
+        self.page_size - PAGE_HEADER_SIZE - RECORD_HEADER_SIZE - key_length
+    }
+
+    // Queue an operation of the kernel.
+    fn enqueue_kernel(
+        &self,
+        operation: Operation,
+        key: &[u8],
+        value: Option<&'static mut [u8]>,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.kernel_pending.get().is_some()
+            || self.user.map_or(false, |user| *user == User::Kernel)
+        {
+            return (ReturnCode::EBUSY, value);
+        }
+        if key.len() == 0 || key.len() > MAX_KEY_LENGTH {
+            return (ReturnCode::ESIZE, value);
+        }
+        if let Operation::Set(length) = operation {
+            if value.as_ref().map_or(0, |value| value.len()) < length
+                || length > self.max_value_length(key.len())
+            {
+                return (ReturnCode::ESIZE, value);
+            }
+        }
+        let mut kernel_key = [0; MAX_KEY_LENGTH];
+        kernel_key[..key.len()].copy_from_slice(key);
+        self.kernel_key.set(kernel_key);
+        self.kernel_key_length.set(key.len());
+        value.map(|value| self.kernel_buffer.replace(value));
+        self.kernel_pending.set(Some(operation));
+        self.check_queue();
+        (ReturnCode::SUCCESS, None)
+    }
+
+    // Queue an operation of a process.
+    fn enqueue_app(&self, operation: Operation, appid: AppId) -> ReturnCode {
+        if appid.get_package_name().is_empty() {
+            return ReturnCode::ENOSUPPORT;
+        }
+        let result = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.pending_command.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                let key_length = app.buffer_key.as_ref().map_or(0, |key| key.len());
+                if key_length == 0 {
+                    return ReturnCode::ERESERVE;
+                }
+                if key_length > MAX_KEY_LENGTH {
+                    return ReturnCode::ESIZE;
+                }
+                if let Operation::Set(length) = operation {
+                    let available = app.buffer_value.as_ref().map_or(0, |value| value.len());
+                    let max = cmp::min(self.max_value_length(key_length), self.app_buffer_length);
+                    if length > available || length > max {
+                        return ReturnCode::ESIZE;
+                    }
+                }
+                app.pending_command = Some(operation);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into());
+        if result == ReturnCode::SUCCESS {
+            self.check_queue();
+        }
+        result
+    }
+
+    // Start the next queued operation, if the store is idle. The kernel goes
+    // first.
+    fn check_queue(&self) {
+        if self.user.is_some() {
+            return;
+        }
+        if let Some(operation) = self.kernel_pending.take() {
+            self.user.set(User::Kernel);
+            self.operation.set(operation);
+            self.namespace.set(KERNEL_NAMESPACE);
+            self.key.set(self.kernel_key.get());
+            self.key_length.set(self.kernel_key_length.get());
+            self.kernel_buffer
+                .take()
+                .map(|buffer| self.value.replace(buffer));
+            self.start();
+            return;
+        }
+        for cntr in self.apps.iter() {
+            let started = cntr.enter(|app, _| {
+                let operation = match app.pending_command {
+                    Some(operation) => operation,
+                    None => return false,
+                };
+                let appid = app.appid();
+                let mut key = [0; MAX_KEY_LENGTH];
+                let mut key_length = 0;
+                app.buffer_key.as_ref().map(|slice| {
+                    key_length = cmp::min(slice.len(), MAX_KEY_LENGTH);
+                    key[..key_length].copy_from_slice(&slice.as_ref()[..key_length]);
+                });
+                let buffer = match self.app_buffer.take() {
+                    Some(buffer) => buffer,
+                    None => return false,
+                };
+                if let Operation::Set(length) = operation {
+                    app.buffer_value.as_ref().map(|slice| {
+                        let length = cmp::min(length, slice.len());
+                        buffer[..length].copy_from_slice(&slice.as_ref()[..length]);
+                    });
+                }
+                self.user.set(User::App(appid));
+                self.operation.set(operation);
+                self.namespace.set(app_namespace(appid.get_package_name()));
+                self.key.set(key);
+                self.key_length.set(key_length);
+                self.value.replace(buffer);
+                true
+            });
+            if started {
+                self.start();
+                return;
+            }
+        }
+    }
+
+    // Run the current operation, loading the store first if needed.
+    fn start(&self) {
+        let result = if self.active.get().is_none() {
+            self.page.take().map_or(ReturnCode::ERESERVE, |page| {
+                self.state.set(State::Loading(0));
+                self.flash.read_page(self.start_page, page)
+            })
+        } else {
+            self.run()
+        };
+        self.continue_with(result);
+    }
+
+    fn run(&self) -> ReturnCode {
+        let namespace = self.namespace.get();
+        let key_buffer = self.key.get();
+        let key = &key_buffer[..self.key_length.get()];
+        let found = self
+            .page
+            .map_or(None, |page| find(page.as_mut(), namespace, key))
+            .filter(|record| record.kind == RECORD_VALUE);
+
+        match self.operation.get() {
+            Operation::Get => match found {
+                Some(record) => {
+                    self.result_length.set(record.value_length);
+                    let fits = self
+                        .value
+                        .map_or(false, |value| value.len() >= record.value_length);
+                    if !fits {
+                        return self.complete(ReturnCode::ESIZE);
+                    }
+                    self.page.map(|page| {
+                        let page = page.as_mut();
+                        self.value.map(|value| {
+                            value[..record.value_length]
+                                .copy_from_slice(&page[record.value()..record.end()])
+                        });
+                    });
+                    self.complete(ReturnCode::SUCCESS)
+                }
+                None => self.complete(ReturnCode::ENOSUPPORT),
+            },
+            Operation::Set(length) => {
+                self.result_length.set(length);
+                self.update(RECORD_VALUE, length)
+            }
+            Operation::Delete => match found {
+                Some(_) => self.update(RECORD_DELETED, 0),
+                None => self.complete(ReturnCode::ENOSUPPORT),
+            },
+        }
+    }
+
+    // Append a record for the current key to the active page, collecting
+    // garbage first if it is full.
+    fn update(&self, kind: u8, length: usize) -> ReturnCode {
+        let size = RECORD_HEADER_SIZE + self.key_length.get() + length;
+        if self.tail.get() + size <= self.page_size {
+            let tail = self.tail.get();
+            self.page
+                .map(|page| self.append(page.as_mut(), tail, kind, length));
+            self.tail.set(tail + size);
+            return self.page.take().map_or(ReturnCode::ERESERVE, |page| {
+                self.state.set(State::Writing);
+                self.flash
+                    .write_page(self.physical_page(self.active_page()), page)
+            });
+        }
+
+        // Copy the newest record of every other key, then add this one. A
+        // deleted key needs no record in the new page.
+        let tail = self.collect();
+        if kind == RECORD_DELETED {
+            self.spare_tail.set(tail);
+        } else if tail + size <= self.page_size {
+            self.spare
+                .map(|spare| self.append(spare.as_mut(), tail, kind, length));
+            self.spare_tail.set(tail + size);
+        } else {
+            return self.complete(ReturnCode::ENOMEM);
+        }
+        self.state.set(State::Erasing);
+        self.flash
+            .erase_page(self.physical_page(1 - self.active_page()))
+    }
+
+    fn append(&self, page: &mut [u8], offset: usize, kind: u8, length: usize) {
+        let key_buffer = self.key.get();
+        let key = &key_buffer[..self.key_length.get()];
+        match kind {
+            RECORD_VALUE => {
+                self.value.map(|value| {
+                    write_record(
+                        page,
+                        offset,
+                        kind,
+                        self.namespace.get(),
+                        key,
+                        &value[..length],
+                    )
+                });
+            }
+            _ => write_record(page, offset, kind, self.namespace.get(), key, &[]),
+        }
+    }
+
+    // Build the next page in the spare buffer from the newest record of every
+    // key other than the current one. Returns where the next record goes.
+    fn collect(&self) -> usize {
+        let namespace = self.namespace.get();
+        let key_buffer = self.key.get();
+        let key = &key_buffer[..self.key_length.get()];
+        let mut tail = PAGE_HEADER_SIZE;
+        self.page.map(|page| {
+            let page = page.as_mut();
+            self.spare.map(|spare| {
+                let spare = spare.as_mut();
+                for byte in spare.iter_mut() {
+                    *byte = 0xff;
+                }
+                write_header(
+                    spare,
+                    self.generation.get().wrapping_add(1),
+                    !PAGE_COMMITTED,
+                );
+                for_each_record(page, |record| {
+                    let key_range = &page[record.key()..record.value()];
+                    let newest = find(page, record.namespace, key_range)
+                        .map_or(false, |newest| newest.offset == record.offset);
+                    if record.kind == RECORD_VALUE
+                        && newest
+                        && !record.matches(page, namespace, key)
+                    {
+                        let size = record.end() - record.offset;
+                        spare[tail..tail + size]
+                            .copy_from_slice(&page[record.offset..record.end()]);
+                        tail += size;
+                    }
+                });
+            });
+        });
+        tail
+    }
+
+    fn swap_pages(&self) {
+        let page = self.page.take();
+        let spare = self.spare.take();
+        page.map(|page| self.spare.replace(page));
+        spare.map(|spare| self.page.replace(spare));
+    }
+
+    // Make the page built by garbage collection the active one.
+    fn collected(&self) {
+        self.swap_pages();
+        self.active.set(Some(1 - self.active_page()));
+        self.generation.set(self.generation.get().wrapping_add(1));
+        self.tail.set(self.spare_tail.get());
+    }
+
+    // Pick the active page once both pages have been read.
+    fn loaded(&self) -> ReturnCode {
+        let first = self.page.map_or(None, |page| parse_header(page.as_mut()));
+        let second = self.spare.map_or(None, |page| parse_header(page.as_mut()));
+        let active = match (first, second) {
+            (Some(a), Some(b)) => Some(if b > a { 1 } else { 0 }),
+            (Some(_), None) => Some(0),
+            (None, Some(_)) => Some(1),
+            (None, None) => None,
+        };
+        match active {
+            Some(active) => {
+                if active == 1 {
+                    self.swap_pages();
+                }
+                self.active.set(Some(active));
+                self.generation
+                    .set(cmp::max(first.unwrap_or(0), second.unwrap_or(0)));
+                let tail = self.page.map_or(self.page_size, |page| {
+                    for_each_record(page.as_mut(), |_| {})
+                });
+                self.tail.set(tail);
+            }
+            None => {
+                // A new store. Start with an empty page, which is written out
+                // by the first update.
+                self.active.set(Some(1));
+                self.generation.set(0);
+                self.page.map(|page| {
+                    for byte in page.as_mut().iter_mut() {
+                        *byte = 0xff;
+                    }
+                });
+                self.tail.set(self.page_size);
+            }
+        }
+        self.state.set(State::Idle);
+        self.run()
+    }
+
+    fn active_page(&self) -> usize {
+        self.active.get().unwrap_or(0)
+    }
+
+    fn physical_page(&self, index: usize) -> usize {
+        self.start_page + index
+    }
+
+    // Report `result` from a deferred call. Gets, and deletes of missing
+    // keys, are answered from the copy of the active page, so the kernel
+    // client would otherwise be called back from within `get` or `delete`.
+    fn complete(&self, result: ReturnCode) -> ReturnCode {
+        self.result.set(result);
+        self.state.set(State::Completing);
+        self.handle.map_or(ReturnCode::FAIL, |handle| {
+            self.deferred_caller.set(*handle);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn continue_with(&self, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.complete(result);
+        }
+    }
+
+    // The copy of the active page no longer matches the flash, so read it
+    // again before the next operation.
+    fn failed(&self) {
+        self.active.set(None);
+        self.complete(ReturnCode::FAIL);
+    }
+}
+
+impl<F: hil::flash::Flash> hil::kv_store::KVStore for KVStore<'a, F> {
+    fn set_client(&self, client: &'static KVStoreClient) {
+        self.kernel_client.set(client);
+    }
+
+    fn get(&self, key: &[u8], value: &'static mut [u8]) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.enqueue_kernel(Operation::Get, key, Some(value))
+    }
+
+    fn set(
+        &self,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.enqueue_kernel(Operation::Set(length), key, Some(value))
+    }
+
+    fn delete(&self, key: &[u8]) -> ReturnCode {
+        self.enqueue_kernel(Operation::Delete, key, None).0
+    }
+}
+
+impl<F: hil::flash::Flash> hil::flash::Client<F> for KVStore<'a, F> {
+    fn read_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        if let State::Loading(index) = self.state.get() {
+            if index == 0 {
+                self.page.replace(page);
+            } else {
+                self.spare.replace(page);
+            }
+            if error != hil::flash::Error::CommandComplete {
+                self.failed();
+            } else if index == 0 {
+                let result = self.spare.take().map_or(ReturnCode::ERESERVE, |spare| {
+                    self.state.set(State::Loading(1));
+                    self.flash.read_page(self.physical_page(1), spare)
+                });
+                self.continue_with(result);
+            } else {
+                let result = self.loaded();
+                self.continue_with(result);
+            }
+        }
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        match self.state.get() {
+            State::Writing => {
+                self.page.replace(page);
+                if error == hil::flash::Error::CommandComplete {
+                    self.complete(ReturnCode::SUCCESS);
+                } else {
+                    self.failed();
+                }
+            }
+            State::Collecting => {
+                if error != hil::flash::Error::CommandComplete {
+                    self.spare.replace(page);
+                    self.failed();
+                    return;
+                }
+                // The page is complete, so it can now be used.
+                write_u32(&mut page.as_mut()[12..16], PAGE_COMMITTED);
+                self.state.set(State::Committing);
+                let result = self
+                    .flash
+                    .write_page(self.physical_page(1 - self.active_page()), page);
+                if result != ReturnCode::SUCCESS {
+                    self.failed();
+                }
+            }
+            State::Committing => {
+                self.spare.replace(page);
+                if error == hil::flash::Error::CommandComplete {
+                    self.collected();
+                    self.complete(ReturnCode::SUCCESS);
+                } else {
+                    self.failed();
+                }
+            }
+            _ => {
+                self.page.replace(page);
+            }
+        }
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        if self.state.get() != State::Erasing {
+            return;
+        }
+        if error != hil::flash::Error::CommandComplete {
+            self.failed();
+            return;
+        }
+        let result = self.spare.take().map_or(ReturnCode::ERESERVE, |spare| {
+            self.state.set(State::Collecting);
+            self.flash
+                .write_page(self.physical_page(1 - self.active_page()), spare)
+        });
+        if result != ReturnCode::SUCCESS {
+            self.failed();
+        }
+    }
+}
+
+impl<F: hil::flash::Flash> DynamicDeferredCallClient for KVStore<'a, F> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if self.state.get() != State::Completing {
+            return;
+        }
+        self.state.set(State::Idle);
+        let result = self.result.get();
+        let length = self.result_length.get();
+        let operation = self.operation.get();
+        match self.user.take() {
+            Some(User::Kernel) => {
+                self.kernel_client.map(|client| match operation {
+                    Operation::Get => {
+                        self.value
+                            .take()
+                            .map(|value| client.get_complete(value, length, result));
+                    }
+                    Operation::Set(_) => {
+                        self.value
+                            .take()
+                            .map(|value| client.set_complete(value, result));
+                    }
+                    Operation::Delete => client.delete_complete(result),
+                });
+            }
+            Some(User::App(appid)) => {
+                let _ = self.apps.enter(appid, |app, _| {
+                    app.pending_command = None;
+                    let callback = match operation {
+                        Operation::Get => {
+                            if result == ReturnCode::SUCCESS {
+                                self.value.map(|value| {
+                                    app.buffer_value.as_mut().map(|slice| {
+                                        let length = cmp::min(length, slice.len());
+                                        slice.as_mut()[..length].copy_from_slice(&value[..length]);
+                                    });
+                                });
+                            }
+                            app.callback_get
+                        }
+                        Operation::Set(_) => app.callback_set,
+                        Operation::Delete => app.callback_delete,
+                    };
+                    callback.map(|mut cb| cb.schedule(usize::from(result), length, 0));
+                });
+                self.value
+                    .take()
+                    .map(|buffer| self.app_buffer.replace(buffer));
+            }
+            None => {}
+        }
+        // The kernel client may have started its next operation already.
+        self.check_queue();
+    }
+}
+
+impl<F: hil::flash::Flash> Driver for KVStore<'a, F> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer holding the key, at most `MAX_KEY_LENGTH` bytes.
+    /// - `1`: Buffer for the value, read by set and written by get.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.buffer_key = slice,
+                    1 => app.buffer_value = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// The callbacks are passed the result of the operation and the length
+    /// of the value. ENOSUPPORT means that the key is not in the store.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Get done. The length is also given with ESIZE if the value did
+    ///   not fit in the buffer.
+    /// - `1`: Set done. ENOMEM means that the store is full.
+    /// - `2`: Delete done.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(app_id, |app, _| {
+                match subscribe_num {
+                    0 => app.callback_get = callback,
+                    1 => app.callback_set = callback,
+                    2 => app.callback_delete = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Command interface.
+    ///
+    /// Processes without a package name cannot use the store (ENOSUPPORT),
+    /// as their keys could not be found again after a restart.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Get the value of the key.
+    /// - `2`: Set the key to the first `arg1` bytes of the value buffer.
+    /// - `3`: Delete the key.
+    /// - `4`: Return the largest value that can be stored.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.enqueue_app(Operation::Get, appid),
+            2 => self.enqueue_app(Operation::Set(arg1), appid),
+            3 => self.enqueue_app(Operation::Delete, appid),
+            4 => ReturnCode::SuccessWithValue {
+                value: cmp::min(
+                    self.max_value_length(MAX_KEY_LENGTH),
+                    self.app_buffer_length,
+                ),
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::storage_sim::{
+        grant, leak, pump, zeroed, Sim, SimFlash, SimPage, WriteMode, PAGE_SIZE,
+    };
+    use kernel::hil::flash::HasClient;
+    use kernel::hil::kv_store::KVStore as _;
+    use std::cell::RefCell;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Erased flash, kept across reboots of the stores booted on it.
+    fn sim() -> &'static Sim<SimFlash<'static>> {
+        Sim::flash(vec![0xff; PAGE_SIZE * 2], WriteMode::Nor)
+    }
+
+    /// Writes a committed page of records of processes to page 0, as if
+    /// they had been stored before the kernel booted.
+    fn store_app_records(sim: &Sim<SimFlash<'static>>, records: &[(&str, &[u8], &[u8])]) {
+        sim.storage.map_storage(|storage| {
+            let page = &mut storage[..PAGE_SIZE];
+            write_header(page, 1, PAGE_COMMITTED);
+            let mut offset = PAGE_HEADER_SIZE;
+            for &(name, key, value) in records {
+                write_record(page, offset, RECORD_VALUE, app_namespace(name), key, value);
+                offset += RECORD_HEADER_SIZE + key.len() + value.len();
+            }
+        });
+    }
+
+    /// The value of `key` of the process `name` in the committed page with
+    /// the highest generation.
+    fn app_value(sim: &Sim<SimFlash<'static>>, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        sim.storage
+            .map_storage(|storage| {
+                let active = storage
+                    .chunks(PAGE_SIZE)
+                    .filter(|page| parse_header(page).is_some())
+                    .max_by_key(|page| parse_header(page))?;
+                find(active, app_namespace(name), key)
+                    .filter(|record| record.kind == RECORD_VALUE)
+                    .map(|record| active[record.value()..record.end()].to_vec())
+            })
+            .unwrap()
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Got(Vec<u8>, ReturnCode),
+        Set(ReturnCode),
+        Deleted(ReturnCode),
+    }
+
+    struct TestClient {
+        buffer: TakeCell<'static, [u8]>,
+        event: RefCell<Option<Event>>,
+    }
+
+    impl KVStoreClient for TestClient {
+        fn get_complete(&self, value: &'static mut [u8], length: usize, result: ReturnCode) {
+            let data = if result == ReturnCode::SUCCESS {
+                value[..length].to_vec()
+            } else {
+                Vec::new()
+            };
+            *self.event.borrow_mut() = Some(Event::Got(data, result));
+            self.buffer.replace(value);
+        }
+
+        fn set_complete(&self, value: &'static mut [u8], result: ReturnCode) {
+            *self.event.borrow_mut() = Some(Event::Set(result));
+            self.buffer.replace(value);
+        }
+
+        fn delete_complete(&self, result: ReturnCode) {
+            *self.event.borrow_mut() = Some(Event::Deleted(result));
+        }
+    }
+
+    /// A store over the flash of `sim`, as set up at boot.
+    struct Harness {
+        kv: &'static KVStore<'static, SimFlash<'static>>,
+        deferred_caller: &'static DynamicDeferredCall,
+        client: &'static TestClient,
+    }
+
+    impl Harness {
+        fn boot(sim: &'static Sim<SimFlash<'static>>) -> Harness {
+            let kv = leak(KVStore::new(
+                sim.storage,
+                0,
+                leak(SimPage::new()),
+                leak(SimPage::new()),
+                zeroed(64),
+                grant(),
+                sim.deferred_caller,
+            ));
+            sim.storage.set_client(kv);
+            kv.set_deferred_call_handle(sim.deferred_caller.register(kv).unwrap());
+            let client = leak(TestClient {
+                buffer: TakeCell::new(zeroed(256)),
+                event: RefCell::new(None),
+            });
+            kv.set_client(client);
+            Harness {
+                kv: kv,
+                deferred_caller: sim.deferred_caller,
+                client: client,
+            }
+        }
+
+        /// Runs the operation started by `start`, and returns its event, or
+        /// None if it never completes.
+        fn run<F: FnOnce(&'static mut [u8]) -> ReturnCode>(&self, start: F) -> Option<Event> {
+            let buffer = self.client.buffer.take().unwrap();
+            assert_eq!(start(buffer), ReturnCode::SUCCESS);
+            pump(self.deferred_caller);
+            self.client.event.borrow_mut().take()
+        }
+
+        fn set(&self, key: &[u8], value: &[u8]) -> Option<Event> {
+            self.run(|buffer| {
+                buffer[..value.len()].copy_from_slice(value);
+                self.kv.set(key, buffer, value.len()).0
+            })
+        }
+
+        fn get(&self, key: &[u8]) -> Option<Event> {
+            self.run(|buffer| self.kv.get(key, buffer).0)
+        }
+
+        fn delete(&self, key: &[u8]) -> Option<Event> {
+            self.run(|buffer| {
+                self.client.buffer.replace(buffer);
+                self.kv.delete(key)
+            })
+        }
+
+        /// Sets `key` until the page is full, so that the next update of
+        /// `key` collects garbage.
+        fn fill(&self, key: &[u8]) {
+            let size = RECORD_HEADER_SIZE + key.len() + 8;
+            let mut count = 0u32;
+            while self.kv.active.get().is_none() || self.kv.tail.get() + size <= self.kv.page_size {
+                let value = [count as u8; 8];
+                assert_eq!(self.set(key, &value), Some(Event::Set(ReturnCode::SUCCESS)));
+                count += 1;
+            }
+        }
+    }
+
+    fn got(value: &[u8]) -> Option<Event> {
+        Some(Event::Got(value.to_vec(), ReturnCode::SUCCESS))
+    }
+
+    fn not_found() -> Option<Event> {
+        Some(Event::Got(Vec::new(), ReturnCode::ENOSUPPORT))
+    }
+
+    #[test]
+    fn sets_gets_and_deletes_keys() {
+        let sim = sim();
+        let harness = Harness::boot(sim);
+        assert_eq!(harness.get(b"volume"), not_found());
+        assert_eq!(
+            harness.set(b"volume", b"3"),
+            Some(Event::Set(ReturnCode::SUCCESS))
+        );
+        assert_eq!(
+            harness.set(b"volume", b"11"),
+            Some(Event::Set(ReturnCode::SUCCESS))
+        );
+        assert_eq!(
+            harness.set(b"channel", b"26"),
+            Some(Event::Set(ReturnCode::SUCCESS))
+        );
+        assert_eq!(harness.get(b"volume"), got(b"11"));
+        assert_eq!(
+            harness.delete(b"volume"),
+            Some(Event::Deleted(ReturnCode::SUCCESS))
+        );
+        assert_eq!(harness.get(b"volume"), not_found());
+        assert_eq!(
+            harness.delete(b"volume"),
+            Some(Event::Deleted(ReturnCode::ENOSUPPORT))
+        );
+
+        let harness = Harness::boot(sim);
+        assert_eq!(harness.get(b"volume"), not_found());
+        assert_eq!(harness.get(b"channel"), got(b"26"));
+
+        // Deleted keys are dropped by garbage collection.
+        harness.fill(b"channel");
+        assert_eq!(
+            harness.set(b"channel", b"11"),
+            Some(Event::Set(ReturnCode::SUCCESS))
+        );
+        let harness = Harness::boot(sim);
+        assert_eq!(harness.get(b"volume"), not_found());
+        assert_eq!(harness.get(b"channel"), got(b"11"));
+    }
+
+    #[test]
+    fn keeps_the_keys_of_processes_apart() {
+        let sim = sim();
+        assert_ne!(app_namespace("org.tock.a"), app_namespace("org.tock.b"));
+        assert_ne!(app_namespace(""), KERNEL_NAMESPACE);
+        store_app_records(
+            sim,
+            &[
+                ("org.tock.a", b"config", b"from a"),
+                ("org.tock.b", b"config", b"from b"),
+            ],
+        );
+
+        // The kernel does not see the keys of processes, and changing its
+        // own key of the same name leaves theirs alone.
+        let harness = Harness::boot(sim);
+        assert_eq!(harness.get(b"config"), not_found());
+        assert_eq!(
+            harness.delete(b"config"),
+            Some(Event::Deleted(ReturnCode::ENOSUPPORT))
+        );
+        assert_eq!(
+            harness.set(b"config", b"kernel"),
+            Some(Event::Set(ReturnCode::SUCCESS))
+        );
+        assert_eq!(harness.get(b"config"), got(b"kernel"));
+        harness.fill(b"config");
+        assert_eq!(
+            harness.delete(b"config"),
+            Some(Event::Deleted(ReturnCode::SUCCESS))
+        );
+        assert_eq!(
+            app_value(sim, "org.tock.a", b"config"),
+            Some(b"from a".to_vec())
+        );
+        assert_eq!(
+            app_value(sim, "org.tock.b", b"config"),
+            Some(b"from b".to_vec())
+        );
+        assert_eq!(app_value(sim, "org.tock.c", b"config"), None);
+        assert_eq!(sim.storage.erase_count(1), 1);
+    }
+
+    #[test]
+    fn updates_keep_the_old_or_the_new_value_after_a_power_cut() {
+        // Cut power while writing a record that lies before, across and after
+        // the middle of the page, up to which a cut write still changes the
+        // page. A record cut in two fails its CRC and is ignored.
+        for &(filler, expected) in &[
+            (0, &b"new value"[..]),
+            (170, &b"old value"[..]),
+            (200, &b"old value"[..]),
+        ] {
+            let sim = sim();
+            let harness = Harness::boot(sim);
+            assert_eq!(
+                harness.set(b"other", &vec![7; filler / 2][..]),
+                Some(Event::Set(ReturnCode::SUCCESS))
+            );
+            if filler > 0 {
+                assert_eq!(
+                    harness.set(b"filler", &vec![0; filler / 2][..]),
+                    Some(Event::Set(ReturnCode::SUCCESS))
+                );
+            }
+            assert_eq!(
+                harness.set(b"key", b"old value"),
+                Some(Event::Set(ReturnCode::SUCCESS))
+            );
+
+            sim.storage.cut_power_after(0);
+            assert_eq!(harness.set(b"key", b"new value"), None);
+            sim.storage.power_on();
+
+            let harness = Harness::boot(sim);
+            assert_eq!(harness.get(b"key"), got(expected));
+            assert_eq!(harness.get(b"other"), got(&vec![7; filler / 2][..]));
+            assert_eq!(
+                harness.set(b"key", b"newer value"),
+                Some(Event::Set(ReturnCode::SUCCESS))
+            );
+            let harness = Harness::boot(sim);
+            assert_eq!(harness.get(b"key"), got(b"newer value"));
+            assert_eq!(harness.get(b"other"), got(&vec![7; filler / 2][..]));
+        }
+    }
+
+    #[test]
+    fn garbage_collection_survives_a_power_cut() {
+        // Cut power while erasing the new page and while writing it, which
+        // leaves the old page active, and while committing it, which still
+        // clears the commit word in the first half of the page.
+        for &(operations, committed) in &[(0, false), (1, false), (2, true)] {
+            let sim = sim();
+            let harness = Harness::boot(sim);
+            assert_eq!(
+                harness.set(b"name", b"imix"),
+                Some(Event::Set(ReturnCode::SUCCESS))
+            );
+            assert_eq!(
+                harness.set(b"deleted", b"x"),
+                Some(Event::Set(ReturnCode::SUCCESS))
+            );
+            assert_eq!(
+                harness.delete(b"deleted"),
+                Some(Event::Deleted(ReturnCode::SUCCESS))
+            );
+            harness.fill(b"counter");
+            let old = match harness.get(b"counter") {
+                Some(Event::Got(value, ReturnCode::SUCCESS)) => value,
+                event => panic!("{:?}", event),
+            };
+            let erases = sim.storage.erase_count(0) + sim.storage.erase_count(1);
+
+            sim.storage.cut_power_after(operations);
+            assert_eq!(harness.set(b"counter", b"new"), None);
+            assert_eq!(
+                sim.storage.erase_count(0) + sim.storage.erase_count(1),
+                erases + 1
+            );
+            sim.storage.power_on();
+
+            let harness = Harness::boot(sim);
+            let expected = if committed { &b"new"[..] } else { &old[..] };
+            assert_eq!(harness.get(b"counter"), got(expected));
+            assert_eq!(harness.get(b"name"), got(b"imix"));
+            assert_eq!(harness.get(b"deleted"), not_found());
+
+            harness.fill(b"counter");
+            assert_eq!(
+                harness.set(b"counter", b"final"),
+                Some(Event::Set(ReturnCode::SUCCESS))
+            );
+            let harness = Harness::boot(sim);
+            assert_eq!(harness.get(b"counter"), got(b"final"));
+            assert_eq!(harness.get(b"name"), got(b"imix"));
+            assert_eq!(harness.get(b"deleted"), not_found());
+        }
+    }
+}
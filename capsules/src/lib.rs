@@ -14,7 +14,10 @@ pub mod ambient_light;
 pub mod analog_comparator;
 pub mod analog_sensor;
 pub mod app_flash_driver;
+pub mod app_update;
+pub mod ble;
 pub mod ble_advertising_driver;
+pub mod blocks_to_nonvolatile;
 pub mod button;
 pub mod buzzer_driver;
 pub mod console;
@@ -22,6 +25,7 @@ pub mod crc;
 pub mod dac;
 pub mod debug_process_restart;
 pub mod driver;
+pub mod fat;
 pub mod fm25cl;
 pub mod fxos8700cq;
 pub mod gpio;
@@ -31,7 +35,10 @@ pub mod i2c_master;
 pub mod i2c_master_slave_driver;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod kernel_update;
+pub mod kv_store;
 pub mod led;
+pub mod log_storage;
 pub mod lps25hb;
 pub mod ltc294x;
 pub mod max17205;
@@ -39,6 +46,7 @@ pub mod mcp230xx;
 pub mod mx25r6435f;
 pub mod ninedof;
 pub mod nonvolatile_storage_driver;
+pub mod nonvolatile_to_blocks;
 pub mod nonvolatile_to_pages;
 pub mod nrf51822_serialization;
 pub mod pca9544a;
@@ -50,6 +58,8 @@ pub mod sdcard;
 pub mod segger_rtt;
 pub mod si7021;
 pub mod spi;
+#[cfg(test)]
+pub mod storage_sim;
 pub mod temperature;
 pub mod tmp006;
 pub mod tsl2561;
@@ -0,0 +1,1382 @@
+//! Append-only log in flash, for kernel capsules and a userspace Driver.
+//!
+//! `LogStorage` implements `hil::log` on top of a range of pages of a
+//! `hil::flash::Flash`, for example a `virtual_flash::FlashUser`. Entries are
+//! checked with a CRC when they are read back, so that a loss of power while
+//! the log is written never returns corrupted data. `LogStorageDriver`
+//! exposes a log to processes, for example to record sensor data.
+//!
+//! Layout
+//! ------
+//!
+//! Every page starts with a header holding a magic number, the sequence
+//! number of the page in the log, the number of times the page has been
+//! erased and a CRC of the header. Entries follow, each made of its length,
+//! a CRC of the length and data, and the data. The rest of the page is left
+//! erased.
+//!
+//! ```text
+//! +-------+-----+-------------+-----+-----------+-----+-------+-----+-------+
+//! | magic | seq | erase count | CRC | length    | CRC | data  | ... | erased|
+//! +-------+-----+-------------+-----+-----------+-----+-------+-----+-------+
+//! \______________ page header ______/\______ entry ___________/
+//! ```
+//!
+//! Page `seq` of the log is stored in flash page `seq % num_pages` of the
+//! log's range, so pages are reused in turn and wear evenly. When the log is
+//! full, the page holding the oldest entries is erased and reused, and those
+//! entries are lost. Entry IDs are `seq * page_size + offset`.
+//!
+//! Appended entries are gathered in a page buffer, which is written to flash
+//! when it is full or when the log is synced. Each page is written exactly
+//! once after it is erased; after a sync, new entries start on the next page
+//! even if the synced page was not full.
+//!
+//! Recovery
+//! --------
+//!
+//! The first operation on the log reads the header of every page to find the
+//! oldest and newest pages. Pages with an invalid header, for example because
+//! power was lost while they were erased or written, are skipped. Within a
+//! page, reading stops at the first entry with an invalid CRC. New entries
+//! are appended on the page after the newest one. If power is lost while the
+//! log is erased, some of the old entries may remain.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let log_flash = static_init!(
+//!     capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::virtual_flash::FlashUser::new(mux_flash)
+//! );
+//! let log = static_init!(
+//!     capsules::log_storage::LogStorage<
+//!         'static,
+//!         capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+//!     >,
+//!     capsules::log_storage::LogStorage::new(
+//!         log_flash,
+//!         448, // First flash page of the log
+//!         64,  // Number of flash pages
+//!         &mut LOG_WRITE_PAGE,
+//!         &mut LOG_READ_PAGE,
+//!         dynamic_deferred_call
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(log_flash, log);
+//! log.set_deferred_call_handle(
+//!     dynamic_deferred_call
+//!         .register(log)
+//!         .expect("no deferred call slot available for the log"),
+//! );
+//!
+//! let log_driver = static_init!(
+//!     capsules::log_storage::LogStorageDriver<'static, LogStorage<...>>,
+//!     capsules::log_storage::LogStorageDriver::new(
+//!         log,
+//!         &mut capsules::log_storage::BUFFER,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! log.set_read_client(log_driver);
+//! log.set_append_client(log_driver);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil;
+use kernel::hil::log::{EntryId, LogRead, LogReadClient, LogWrite, LogWriteClient};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use tock_boot::crc32;
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::LogStorage as usize;
+
+/// Buffer for the entries of `LogStorageDriver`.
+pub static mut BUFFER: [u8; 256] = [0; 256];
+
+const PAGE_MAGIC: [u8; 4] = *b"TLOG";
+const PAGE_HEADER_SIZE: usize = 16;
+const ENTRY_HEADER_SIZE: usize = 6;
+const ERASED_LENGTH: u16 = 0xffff;
+
+fn read_u32(buf: &[u8]) -> u32 {
+    buf[0] as u32 | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24
+}
+
+fn write_u32(buf: &mut [u8], value: u32) {
+    for i in 0..4 {
+        buf[i] = (value >> (8 * i)) as u8;
+    }
+}
+
+/// Returns the sequence number and erase count in a page header, if it is
+/// valid.
+fn parse_header(page: &[u8]) -> Option<(usize, u32)> {
+    if page[0..4] != PAGE_MAGIC || read_u32(&page[12..16]) != crc32(0, &page[0..12]) {
+        return None;
+    }
+    Some((read_u32(&page[4..8]) as usize, read_u32(&page[8..12])))
+}
+
+/// Returns the length of the valid entry at `offset` in `page`, or `None` if
+/// there are no more valid entries in the page.
+fn parse_entry(page: &[u8], offset: usize) -> Option<usize> {
+    if offset + ENTRY_HEADER_SIZE > page.len() {
+        return None;
+    }
+    let length = page[offset] as u16 | (page[offset + 1] as u16) << 8;
+    let data = offset + ENTRY_HEADER_SIZE;
+    if length == ERASED_LENGTH || length == 0 || data + length as usize > page.len() {
+        return None;
+    }
+    let crc = crc32(
+        crc32(0, &page[offset..offset + 2]),
+        &page[data..data + length as usize],
+    );
+    if crc != read_u32(&page[offset + 2..data]) {
+        return None;
+    }
+    Some(length as usize)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Reading page `index` of the log to find the oldest and newest pages.
+    Recovering(usize),
+    /// Reading the page that is about to be reused, to learn its erase count.
+    FlushReading,
+    /// Erasing the page that is about to be reused. Holds its new erase count.
+    FlushErasing(u32),
+    /// Writing the page buffer to flash.
+    FlushWriting,
+    /// Reading a page to find the entry at the read cursor.
+    Reading,
+    /// Erasing page `index` of the log.
+    Erasing(usize),
+    /// The entry is appended to the page buffer or read out of it, and the
+    /// buffer goes back to the append or read client from a deferred call.
+    Completing,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    None,
+    Append,
+    Read,
+    Sync,
+    Erase,
+}
+
+pub struct LogStorage<'a, F: hil::flash::Flash + 'static> {
+    flash: &'a F,
+    start_page: usize,
+    num_pages: usize,
+    page_size: usize,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+
+    initialized: Cell<bool>,
+    state: Cell<State>,
+    operation: Cell<Operation>,
+    // The client buffer of the current append or read.
+    buffer: TakeCell<'static, [u8]>,
+    length: Cell<usize>,
+    result: Cell<ReturnCode>,
+    records_lost: Cell<bool>,
+
+    // Entries that have not been written to flash yet.
+    write_page: TakeCell<'static, F::Page>,
+    // Page read from flash, and the sequence number it holds.
+    read_page: TakeCell<'static, F::Page>,
+    read_page_seq: Cell<Option<usize>>,
+
+    // Sequence number of the oldest page still in the log.
+    oldest_seq: Cell<usize>,
+    // Sequence number of the page being filled in `write_page`.
+    write_seq: Cell<usize>,
+    // Bytes used in `write_page`, or 0 if no entry was appended to it.
+    write_offset: Cell<usize>,
+    read_cursor: Cell<EntryId>,
+    // Highest erase count seen, used for pages whose header is lost.
+    max_erase_count: Cell<u32>,
+    // Newest page seen while recovering.
+    newest_seq: Cell<Option<usize>>,
+
+    read_client: OptionalCell<&'static LogReadClient>,
+    append_client: OptionalCell<&'static LogWriteClient>,
+}
+
+impl<F: hil::flash::Flash> LogStorage<'a, F> {
+    /// Creates a log stored in the `num_pages` flash pages starting at
+    /// `start_page`. `write_page` and `read_page` are buffers for one page
+    /// each.
+    pub fn new(
+        flash: &'a F,
+        start_page: usize,
+        num_pages: usize,
+        write_page: &'static mut F::Page,
+        read_page: &'static mut F::Page,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> LogStorage<'a, F> {
+        let page_size = write_page.as_mut().len();
+        LogStorage {
+            flash: flash,
+            start_page: start_page,
+            num_pages: num_pages,
+            page_size: page_size,
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            initialized: Cell::new(false),
+            state: Cell::new(State::Idle),
+            operation: Cell::new(Operation::None),
+            buffer: TakeCell::empty(),
+            length: Cell::new(0),
+            result: Cell::new(ReturnCode::SUCCESS),
+            records_lost: Cell::new(false),
+            write_page: TakeCell::new(write_page),
+            read_page: TakeCell::new(read_page),
+            read_page_seq: Cell::new(None),
+            oldest_seq: Cell::new(0),
+            write_seq: Cell::new(0),
+            write_offset: Cell::new(0),
+            read_cursor: Cell::new(PAGE_HEADER_SIZE),
+            max_erase_count: Cell::new(0),
+            newest_seq: Cell::new(None),
+            read_client: OptionalCell::empty(),
+            append_client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_deferred_call_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Largest entry that can be appended.
+    pub fn max_entry_length(&self) -> usize {
+        cmp::min(
+            self.page_size - PAGE_HEADER_SIZE - ENTRY_HEADER_SIZE,
+            ERASED_LENGTH as usize - 1,
+        )
+    }
+
+    fn physical_page(&self, seq: usize) -> usize {
+        self.start_page + seq % self.num_pages
+    }
+
+    // Start an operation, scanning the pages first if this is the first one.
+    fn start(
+        &self,
+        operation: Operation,
+        buffer: Option<&'static mut [u8]>,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.operation.get() != Operation::None {
+            return (ReturnCode::EBUSY, buffer);
+        }
+        self.operation.set(operation);
+        self.records_lost.set(false);
+        self.length.set(length);
+        buffer.map(|buffer| self.buffer.replace(buffer));
+
+        let result = if self.initialized.get() {
+            self.run()
+        } else {
+            self.oldest_seq.set(usize::max_value());
+            self.newest_seq.set(None);
+            self.recover_page(0)
+        };
+        if result != ReturnCode::SUCCESS {
+            self.operation.set(Operation::None);
+            self.state.set(State::Idle);
+            return (result, self.buffer.take());
+        }
+        (ReturnCode::SUCCESS, None)
+    }
+
+    // Run the current operation once the pages have been scanned.
+    fn run(&self) -> ReturnCode {
+        match self.operation.get() {
+            Operation::None => ReturnCode::SUCCESS,
+            Operation::Append => {
+                if self.entry_fits(self.length.get()) {
+                    self.append_entry();
+                    self.complete(ReturnCode::SUCCESS)
+                } else {
+                    self.flush()
+                }
+            }
+            Operation::Read => self.read_entry(),
+            Operation::Sync => {
+                if self.write_offset.get() != 0 {
+                    self.flush()
+                } else {
+                    self.complete(ReturnCode::SUCCESS)
+                }
+            }
+            Operation::Erase => {
+                self.read_page_seq.set(None);
+                self.state.set(State::Erasing(0));
+                self.flash.erase_page(self.start_page)
+            }
+        }
+    }
+
+    // Hand `result` to the client from a deferred call. Appends that fit in
+    // the write page and reads of entries already in a page buffer finish
+    // without any flash callback, and must not call back from within
+    // `append` or `read`.
+    fn complete(&self, result: ReturnCode) -> ReturnCode {
+        self.result.set(result);
+        self.state.set(State::Completing);
+        self.handle.map_or(ReturnCode::FAIL, |handle| {
+            self.deferred_caller.set(*handle);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    // Continue an operation from a flash callback, completing it if the next
+    // step could not start.
+    fn continue_with(&self, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.complete(result);
+        }
+    }
+
+    fn recover_page(&self, index: usize) -> ReturnCode {
+        self.read_page.take().map_or(ReturnCode::ERESERVE, |page| {
+            self.read_page_seq.set(None);
+            self.state.set(State::Recovering(index));
+            self.flash.read_page(self.start_page + index, page)
+        })
+    }
+
+    fn recovered_page(&self, index: usize, page: &[u8]) {
+        if let Some((seq, erase_count)) = parse_header(page) {
+            if seq % self.num_pages == index {
+                if erase_count > self.max_erase_count.get() {
+                    self.max_erase_count.set(erase_count);
+                }
+                if self.newest_seq.get().map_or(true, |newest| seq > newest) {
+                    self.newest_seq.set(Some(seq));
+                }
+                if seq < self.oldest_seq.get() {
+                    self.oldest_seq.set(seq);
+                }
+            }
+        }
+    }
+
+    fn finish_recovery(&self) {
+        match self.newest_seq.get() {
+            Some(newest) => {
+                // Only the last `num_pages` pages can still be in the log.
+                let first = (newest + 1).saturating_sub(self.num_pages);
+                self.oldest_seq.set(cmp::max(self.oldest_seq.get(), first));
+                self.write_seq.set(newest + 1);
+            }
+            None => {
+                self.oldest_seq.set(0);
+                self.write_seq.set(0);
+            }
+        }
+        self.write_offset.set(0);
+        self.read_cursor.set(self.log_start());
+        self.initialized.set(true);
+        self.state.set(State::Idle);
+    }
+
+    fn entry_fits(&self, length: usize) -> bool {
+        cmp::max(self.write_offset.get(), PAGE_HEADER_SIZE) + ENTRY_HEADER_SIZE + length
+            <= self.page_size
+    }
+
+    // Copy the client's entry into the page buffer.
+    fn append_entry(&self) {
+        let length = self.length.get();
+        let offset = cmp::max(self.write_offset.get(), PAGE_HEADER_SIZE);
+        self.write_page.map(|page| {
+            let page = page.as_mut();
+            if self.write_offset.get() == 0 {
+                for byte in page.iter_mut() {
+                    *byte = 0xff;
+                }
+            }
+            let data = offset + ENTRY_HEADER_SIZE;
+            page[offset] = length as u8;
+            page[offset + 1] = (length >> 8) as u8;
+            self.buffer.map(|buffer| {
+                page[data..data + length].copy_from_slice(&buffer[..length]);
+            });
+            let crc = crc32(
+                crc32(0, &page[offset..offset + 2]),
+                &page[data..data + length],
+            );
+            write_u32(&mut page[offset + 2..data], crc);
+        });
+        self.write_offset.set(offset + ENTRY_HEADER_SIZE + length);
+    }
+
+    // Write the page buffer to flash. The flash page it goes to is read
+    // first, to learn its erase count, and then erased.
+    fn flush(&self) -> ReturnCode {
+        self.read_page.take().map_or(ReturnCode::ERESERVE, |page| {
+            self.read_page_seq.set(None);
+            self.state.set(State::FlushReading);
+            self.flash
+                .read_page(self.physical_page(self.write_seq.get()), page)
+        })
+    }
+
+    fn flush_read_done(&self, page: &[u8]) -> ReturnCode {
+        let seq = self.write_seq.get();
+        let erase_count = match parse_header(page) {
+            Some((_, erase_count)) => erase_count,
+            None => self.max_erase_count.get(),
+        }
+        .saturating_add(1);
+        if erase_count > self.max_erase_count.get() {
+            self.max_erase_count.set(erase_count);
+        }
+
+        // The flash page still holds the oldest page of the log, whose
+        // entries are lost once it is erased.
+        if seq >= self.oldest_seq.get() + self.num_pages {
+            self.oldest_seq.set(seq + 1 - self.num_pages);
+            self.records_lost.set(true);
+            if self.read_cursor.get() < self.log_start() {
+                self.read_cursor.set(self.log_start());
+            }
+        }
+
+        self.state.set(State::FlushErasing(erase_count));
+        self.flash.erase_page(self.physical_page(seq))
+    }
+
+    fn flush_erase_done(&self, erase_count: u32) -> ReturnCode {
+        let seq = self.write_seq.get();
+        self.write_page.take().map_or(ReturnCode::ERESERVE, |page| {
+            {
+                let header = &mut page.as_mut()[..PAGE_HEADER_SIZE];
+                header[0..4].copy_from_slice(&PAGE_MAGIC);
+                write_u32(&mut header[4..8], seq as u32);
+                write_u32(&mut header[8..12], erase_count);
+                let crc = crc32(0, &header[0..12]);
+                write_u32(&mut header[12..16], crc);
+            }
+            self.state.set(State::FlushWriting);
+            self.flash.write_page(self.physical_page(seq), page)
+        })
+    }
+
+    fn flush_write_done(&self) -> ReturnCode {
+        self.write_seq.set(self.write_seq.get() + 1);
+        self.write_offset.set(0);
+        if self.operation.get() == Operation::Append {
+            self.append_entry();
+        }
+        self.complete(ReturnCode::SUCCESS)
+    }
+
+    // Find the entry at the read cursor, reading pages from flash as needed.
+    fn read_entry(&self) -> ReturnCode {
+        loop {
+            if self.read_cursor.get() < self.log_start() {
+                self.read_cursor.set(self.log_start());
+            }
+            let cursor = self.read_cursor.get();
+            if cursor >= self.log_end() {
+                return self.complete(ReturnCode::FAIL);
+            }
+            let seq = cursor / self.page_size;
+            let offset = cmp::max(cursor % self.page_size, PAGE_HEADER_SIZE);
+
+            let entry = if seq == self.write_seq.get() {
+                self.write_page
+                    .map_or(None, |page| self.copy_entry(page.as_mut(), seq, offset))
+            } else if self.read_page_seq.get() == Some(seq) {
+                self.read_page.map_or(None, |page| {
+                    let page = page.as_mut();
+                    match parse_header(page) {
+                        Some((page_seq, _)) if page_seq == seq => {
+                            self.copy_entry(page, seq, offset)
+                        }
+                        _ => None,
+                    }
+                })
+            } else {
+                return self.read_page.take().map_or(ReturnCode::ERESERVE, |page| {
+                    self.read_page_seq.set(Some(seq));
+                    self.state.set(State::Reading);
+                    self.flash.read_page(self.physical_page(seq), page)
+                });
+            };
+
+            match entry {
+                Some(result) => return self.complete(result),
+                // No more entries in this page.
+                None => self
+                    .read_cursor
+                    .set((seq + 1) * self.page_size + PAGE_HEADER_SIZE),
+            }
+        }
+    }
+
+    // Copy the entry at `offset` of page `seq` to the client's buffer and
+    // move the cursor past it. Returns `None` if there are no more entries
+    // in the page.
+    fn copy_entry(&self, page: &[u8], seq: usize, offset: usize) -> Option<ReturnCode> {
+        let length = parse_entry(page, offset)?;
+        let data = offset + ENTRY_HEADER_SIZE;
+        let limit = self
+            .buffer
+            .map_or(0, |buffer| cmp::min(buffer.len(), self.length.get()));
+        self.length.set(length);
+        if length > limit {
+            return Some(ReturnCode::ESIZE);
+        }
+        self.buffer
+            .map(|buffer| buffer[..length].copy_from_slice(&page[data..data + length]));
+        self.read_cursor.set(seq * self.page_size + data + length);
+        Some(ReturnCode::SUCCESS)
+    }
+
+    fn erase_done(&self, index: usize) -> ReturnCode {
+        if index + 1 < self.num_pages {
+            self.state.set(State::Erasing(index + 1));
+            return self.flash.erase_page(self.start_page + index + 1);
+        }
+        // Entry IDs keep increasing, so IDs from before the erase are never
+        // mistaken for new entries.
+        self.oldest_seq.set(self.write_seq.get());
+        self.write_offset.set(0);
+        self.read_cursor.set(self.log_start());
+        self.complete(ReturnCode::SUCCESS)
+    }
+}
+
+impl<F: hil::flash::Flash> LogRead for LogStorage<'a, F> {
+    fn set_read_client(&self, client: &'static LogReadClient) {
+        self.read_client.set(client);
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.start(Operation::Read, Some(buffer), length)
+    }
+
+    fn seek(&self, entry: EntryId) -> ReturnCode {
+        if entry < self.log_start()
+            || entry > self.log_end()
+            || entry % self.page_size < PAGE_HEADER_SIZE
+        {
+            return ReturnCode::EINVAL;
+        }
+        self.read_cursor.set(entry);
+        ReturnCode::SUCCESS
+    }
+
+    fn next_read_entry_id(&self) -> EntryId {
+        self.read_cursor.get()
+    }
+
+    fn log_start(&self) -> EntryId {
+        self.oldest_seq.get() * self.page_size + PAGE_HEADER_SIZE
+    }
+
+    fn log_end(&self) -> EntryId {
+        self.write_seq.get() * self.page_size + cmp::max(self.write_offset.get(), PAGE_HEADER_SIZE)
+    }
+
+    fn get_size(&self) -> usize {
+        self.num_pages * self.page_size
+    }
+}
+
+impl<F: hil::flash::Flash> LogWrite for LogStorage<'a, F> {
+    fn set_append_client(&self, client: &'static LogWriteClient) {
+        self.append_client.set(client);
+    }
+
+    fn append(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if length == 0 || length > buffer.len() || length > self.max_entry_length() {
+            return (ReturnCode::ESIZE, Some(buffer));
+        }
+        self.start(Operation::Append, Some(buffer), length)
+    }
+
+    fn sync(&self) -> ReturnCode {
+        self.start(Operation::Sync, None, 0).0
+    }
+
+    fn erase(&self) -> ReturnCode {
+        self.start(Operation::Erase, None, 0).0
+    }
+}
+
+impl<F: hil::flash::Flash> hil::flash::Client<F> for LogStorage<'a, F> {
+    fn read_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        let ok = error == hil::flash::Error::CommandComplete;
+        match self.state.get() {
+            State::Recovering(index) => {
+                if ok {
+                    self.recovered_page(index, page.as_mut());
+                }
+                self.read_page.replace(page);
+                if !ok {
+                    self.complete(ReturnCode::FAIL);
+                } else if index + 1 < self.num_pages {
+                    let result = self.recover_page(index + 1);
+                    self.continue_with(result);
+                } else {
+                    self.finish_recovery();
+                    let result = self.run();
+                    self.continue_with(result);
+                }
+            }
+            State::FlushReading => {
+                let result = if ok {
+                    self.flush_read_done(page.as_mut())
+                } else {
+                    ReturnCode::FAIL
+                };
+                self.read_page.replace(page);
+                self.continue_with(result);
+            }
+            State::Reading => {
+                self.read_page.replace(page);
+                if ok {
+                    let result = self.read_entry();
+                    self.continue_with(result);
+                } else {
+                    self.read_page_seq.set(None);
+                    self.complete(ReturnCode::FAIL);
+                }
+            }
+            _ => {
+                self.read_page.replace(page);
+            }
+        }
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        self.write_page.replace(page);
+        if self.state.get() == State::FlushWriting {
+            if error == hil::flash::Error::CommandComplete {
+                let result = self.flush_write_done();
+                self.continue_with(result);
+            } else {
+                self.complete(ReturnCode::FAIL);
+            }
+        }
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        let result = if error != hil::flash::Error::CommandComplete {
+            ReturnCode::FAIL
+        } else {
+            match self.state.get() {
+                State::FlushErasing(erase_count) => self.flush_erase_done(erase_count),
+                State::Erasing(index) => self.erase_done(index),
+                _ => return,
+            }
+        };
+        self.continue_with(result);
+    }
+}
+
+impl<F: hil::flash::Flash> DynamicDeferredCallClient for LogStorage<'a, F> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if self.state.get() != State::Completing {
+            return;
+        }
+        self.state.set(State::Idle);
+        let operation = self.operation.replace(Operation::None);
+        let result = self.result.get();
+        let length = self.length.get();
+        match operation {
+            Operation::None => {}
+            Operation::Read => {
+                self.buffer.take().map(|buffer| {
+                    self.read_client
+                        .map(move |client| client.read_done(buffer, length, result));
+                });
+            }
+            Operation::Append => {
+                let records_lost = self.records_lost.get();
+                self.buffer.take().map(|buffer| {
+                    self.append_client.map(move |client| {
+                        client.append_done(buffer, length, records_lost, result)
+                    });
+                });
+            }
+            Operation::Sync => {
+                self.append_client.map(|client| client.sync_done(result));
+            }
+            Operation::Erase => {
+                self.append_client.map(|client| client.erase_done(result));
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Read(usize),
+    Append(usize),
+    Sync,
+    Erase,
+}
+
+pub struct App {
+    read_callback: Option<Callback>,
+    append_callback: Option<Callback>,
+    sync_callback: Option<Callback>,
+    erase_callback: Option<Callback>,
+    read_buffer: Option<AppSlice<Shared, u8>>,
+    append_buffer: Option<AppSlice<Shared, u8>>,
+    cursor: Option<EntryId>,
+    pending_command: Option<Command>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            read_callback: None,
+            append_callback: None,
+            sync_callback: None,
+            erase_callback: None,
+            read_buffer: None,
+            append_buffer: None,
+            cursor: None,
+            pending_command: None,
+        }
+    }
+}
+
+/// Userspace interface to a log. Every app has its own read cursor, which
+/// starts at the oldest entry.
+pub struct LogStorageDriver<'a, L: LogRead + LogWrite> {
+    log: &'a L,
+    buffer: TakeCell<'static, [u8]>,
+    apps: Grant<App>,
+    current_app: OptionalCell<AppId>,
+}
+
+impl<L: LogRead + LogWrite> LogStorageDriver<'a, L> {
+    pub fn new(
+        log: &'a L,
+        buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> LogStorageDriver<'a, L> {
+        LogStorageDriver {
+            log: log,
+            buffer: TakeCell::new(buffer),
+            apps: grant,
+            current_app: OptionalCell::empty(),
+        }
+    }
+
+    // Queue a command for the app, and run it if the log is not in use.
+    fn enqueue_command(&self, command: Command, appid: AppId) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                if app.pending_command.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                let (slice, length) = match command {
+                    Command::Read(length) => (&app.read_buffer, length),
+                    Command::Append(length) => (&app.append_buffer, length),
+                    _ => (&None, 0),
+                };
+                if length > slice.as_ref().map_or(0, |slice| slice.len()) {
+                    return ReturnCode::EINVAL;
+                }
+                app.pending_command = Some(command);
+                if self.current_app.is_none() {
+                    let result = self.run_command(app, appid);
+                    if result != ReturnCode::SUCCESS {
+                        app.pending_command = None;
+                    }
+                    result
+                } else {
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn run_command(&self, app: &mut App, appid: AppId) -> ReturnCode {
+        let command = match app.pending_command {
+            Some(command) => command,
+            None => return ReturnCode::FAIL,
+        };
+        let result = match command {
+            Command::Read(length) => self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+                let length = cmp::min(length, buffer.len());
+                let cursor = app.cursor.unwrap_or(self.log.log_start());
+                if self.log.seek(cursor) != ReturnCode::SUCCESS {
+                    // Entries were lost since this app last read.
+                    self.log.seek(self.log.log_start());
+                }
+                let (result, buffer) = self.log.read(buffer, length);
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                result
+            }),
+            Command::Append(length) => self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+                let length = cmp::min(length, buffer.len());
+                app.append_buffer.as_ref().map(|slice| {
+                    buffer[..length].copy_from_slice(&slice.as_ref()[..length]);
+                });
+                let (result, buffer) = self.log.append(buffer, length);
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                result
+            }),
+            Command::Sync => self.log.sync(),
+            Command::Erase => self.log.erase(),
+        };
+        if result == ReturnCode::SUCCESS {
+            self.current_app.set(appid);
+        }
+        result
+    }
+
+    // Run the next queued command, reporting commands that cannot run.
+    fn check_queue(&self) {
+        for cntr in self.apps.iter() {
+            let started = cntr.enter(|app, _| {
+                let command = match app.pending_command {
+                    Some(command) => command,
+                    None => return false,
+                };
+                let appid = app.appid();
+                let result = self.run_command(app, appid);
+                if result == ReturnCode::SUCCESS {
+                    return true;
+                }
+                app.pending_command = None;
+                let callback = match command {
+                    Command::Read(_) => &mut app.read_callback,
+                    Command::Append(_) => &mut app.append_callback,
+                    Command::Sync => &mut app.sync_callback,
+                    Command::Erase => &mut app.erase_callback,
+                };
+                callback.map(|mut cb| cb.schedule(usize::from(result), 0, 0));
+                false
+            });
+            if started {
+                break;
+            }
+        }
+    }
+}
+
+impl<L: LogRead + LogWrite> LogReadClient for LogStorageDriver<'a, L> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, error: ReturnCode) {
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.pending_command = None;
+                app.cursor = Some(self.log.next_read_entry_id());
+                if error == ReturnCode::SUCCESS {
+                    app.read_buffer.as_mut().map(|slice| {
+                        let length = cmp::min(length, slice.len());
+                        slice.as_mut()[..length].copy_from_slice(&buffer[..length]);
+                    });
+                }
+                app.read_callback
+                    .map(|mut cb| cb.schedule(usize::from(error), length, 0));
+            });
+        });
+        self.buffer.replace(buffer);
+        self.check_queue();
+    }
+}
+
+impl<L: LogRead + LogWrite> LogWriteClient for LogStorageDriver<'a, L> {
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        records_lost: bool,
+        error: ReturnCode,
+    ) {
+        self.buffer.replace(buffer);
+        self.done(|app| {
+            app.append_callback
+                .map(|mut cb| cb.schedule(usize::from(error), length, records_lost as usize));
+        });
+    }
+
+    fn sync_done(&self, error: ReturnCode) {
+        self.done(|app| {
+            app.sync_callback
+                .map(|mut cb| cb.schedule(usize::from(error), 0, 0));
+        });
+    }
+
+    fn erase_done(&self, error: ReturnCode) {
+        self.done(|app| {
+            app.erase_callback
+                .map(|mut cb| cb.schedule(usize::from(error), 0, 0));
+        });
+    }
+}
+
+impl<L: LogRead + LogWrite> LogStorageDriver<'a, L> {
+    // Notify the app whose command completed and run the next one.
+    fn done<C: FnOnce(&mut App)>(&self, notify: C) {
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.pending_command = None;
+                notify(app);
+            });
+        });
+        self.check_queue();
+    }
+}
+
+impl<L: LogRead + LogWrite> Driver for LogStorageDriver<'a, L> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer to read entries into.
+    /// - `1`: Buffer holding the entry to append.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.read_buffer = slice,
+                    1 => app.append_buffer = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// The first argument of every callback is the result of the operation.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Read done. The second argument is the length of the entry,
+    ///   which is also given when it did not fit in the buffer (ESIZE). FAIL
+    ///   means there are no more entries.
+    /// - `1`: Append done. The second argument is the length of the entry,
+    ///   the third is 1 if the oldest entries were lost to make room.
+    /// - `2`: Sync done.
+    /// - `3`: Erase done.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(app_id, |app, _| {
+                match subscribe_num {
+                    0 => app.read_callback = callback,
+                    1 => app.append_callback = callback,
+                    2 => app.sync_callback = callback,
+                    3 => app.erase_callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Read the entry at the app's cursor, at most `arg1` bytes.
+    /// - `2`: Append the first `arg1` bytes of the append buffer as an entry.
+    /// - `3`: Write appended entries to flash.
+    /// - `4`: Erase the log.
+    /// - `5`: Move the app's cursor to entry `arg1`.
+    /// - `6`: Return the app's cursor.
+    /// - `7`: Return the ID of the oldest entry.
+    /// - `8`: Return the ID of the end of the log.
+    /// - `9`: Return the size of the log in bytes.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.enqueue_command(Command::Read(arg1), appid),
+            2 => self.enqueue_command(Command::Append(arg1), appid),
+            3 => self.enqueue_command(Command::Sync, appid),
+            4 => self.enqueue_command(Command::Erase, appid),
+            5 => {
+                if arg1 < self.log.log_start() || arg1 > self.log.log_end() {
+                    return ReturnCode::EINVAL;
+                }
+                self.apps
+                    .enter(appid, |app, _| {
+                        app.cursor = Some(arg1);
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            6 => {
+                let start = self.log.log_start();
+                self.apps
+                    .enter(appid, |app, _| ReturnCode::SuccessWithValue {
+                        value: cmp::max(app.cursor.unwrap_or(start), start),
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            7 => ReturnCode::SuccessWithValue {
+                value: self.log.log_start(),
+            },
+            8 => ReturnCode::SuccessWithValue {
+                value: self.log.log_end(),
+            },
+            9 => ReturnCode::SuccessWithValue {
+                value: self.log.get_size(),
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::storage_sim::{leak, pump, zeroed, Sim, SimFlash, SimPage, WriteMode, PAGE_SIZE};
+    use kernel::hil::flash::HasClient;
+    use std::cell::RefCell;
+    use std::vec;
+    use std::vec::Vec;
+
+    const NUM_PAGES: usize = 4;
+
+    /// Erased flash, kept across reboots of the logs booted on it.
+    fn sim() -> &'static Sim<SimFlash<'static>> {
+        Sim::flash(vec![0xff; PAGE_SIZE * NUM_PAGES], WriteMode::Nor)
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Read(Vec<u8>, ReturnCode),
+        Appended(bool, ReturnCode),
+        Synced(ReturnCode),
+        Erased(ReturnCode),
+    }
+
+    struct TestClient {
+        buffer: TakeCell<'static, [u8]>,
+        events: RefCell<Vec<Event>>,
+    }
+
+    impl LogReadClient for TestClient {
+        fn read_done(&self, buffer: &'static mut [u8], length: usize, error: ReturnCode) {
+            let data = if error == ReturnCode::SUCCESS {
+                buffer[..length].to_vec()
+            } else {
+                Vec::new()
+            };
+            self.events.borrow_mut().push(Event::Read(data, error));
+            self.buffer.replace(buffer);
+        }
+    }
+
+    impl LogWriteClient for TestClient {
+        fn append_done(
+            &self,
+            buffer: &'static mut [u8],
+            _length: usize,
+            records_lost: bool,
+            error: ReturnCode,
+        ) {
+            self.events
+                .borrow_mut()
+                .push(Event::Appended(records_lost, error));
+            self.buffer.replace(buffer);
+        }
+
+        fn sync_done(&self, error: ReturnCode) {
+            self.events.borrow_mut().push(Event::Synced(error));
+        }
+
+        fn erase_done(&self, error: ReturnCode) {
+            self.events.borrow_mut().push(Event::Erased(error));
+        }
+    }
+
+    /// A log over the flash of `sim`, as set up at boot.
+    struct Harness {
+        log: &'static LogStorage<'static, SimFlash<'static>>,
+        deferred_caller: &'static DynamicDeferredCall,
+        client: &'static TestClient,
+    }
+
+    impl Harness {
+        fn boot(sim: &'static Sim<SimFlash<'static>>) -> Harness {
+            let log = leak(LogStorage::new(
+                sim.storage,
+                0,
+                NUM_PAGES,
+                leak(SimPage::new()),
+                leak(SimPage::new()),
+                sim.deferred_caller,
+            ));
+            log.set_deferred_call_handle(sim.deferred_caller.register(log).unwrap());
+            sim.storage.set_client(log);
+            let client = leak(TestClient {
+                buffer: TakeCell::new(zeroed(PAGE_SIZE)),
+                events: RefCell::new(Vec::new()),
+            });
+            log.set_read_client(client);
+            log.set_append_client(client);
+            Harness {
+                log: log,
+                deferred_caller: sim.deferred_caller,
+                client: client,
+            }
+        }
+
+        fn run(&self) {
+            pump(self.deferred_caller);
+        }
+
+        fn event(&self) -> Event {
+            self.run();
+            let mut events = self.client.events.borrow_mut();
+            assert_eq!(events.len(), 1, "{:?}", *events);
+            events.pop().unwrap()
+        }
+
+        fn append(&self, data: &[u8]) -> Event {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            let (result, buffer) = self.log.append(buffer, data.len());
+            assert_eq!(result, ReturnCode::SUCCESS);
+            assert!(buffer.is_none());
+            self.event()
+        }
+
+        fn read(&self, length: usize) -> Event {
+            let buffer = self.client.buffer.take().unwrap();
+            let (result, buffer) = self.log.read(buffer, length);
+            assert_eq!(result, ReturnCode::SUCCESS);
+            assert!(buffer.is_none());
+            self.event()
+        }
+
+        fn sync(&self) {
+            assert_eq!(self.log.sync(), ReturnCode::SUCCESS);
+            assert_eq!(self.event(), Event::Synced(ReturnCode::SUCCESS));
+        }
+
+        /// Reads the remaining entries.
+        fn read_all(&self) -> Vec<Vec<u8>> {
+            let mut entries = Vec::new();
+            loop {
+                match self.read(PAGE_SIZE) {
+                    Event::Read(data, ReturnCode::SUCCESS) => entries.push(data),
+                    Event::Read(_, ReturnCode::FAIL) => return entries,
+                    event => panic!("unexpected {:?}", event),
+                }
+            }
+        }
+    }
+
+    fn entry(n: u8) -> Vec<u8> {
+        vec![n; 20 + n as usize % 7]
+    }
+
+    fn appended() -> Event {
+        Event::Appended(false, ReturnCode::SUCCESS)
+    }
+
+    #[test]
+    fn entries_are_read_back_in_order_across_pages() {
+        let h = Harness::boot(sim());
+        for n in 0..40 {
+            assert_eq!(h.append(&entry(n)), appended());
+        }
+        let expected: Vec<_> = (0..40).map(entry).collect();
+        assert_eq!(h.read_all(), expected);
+
+        assert_eq!(h.append(&entry(40)), appended());
+        assert_eq!(h.read_all(), vec![entry(40)]);
+    }
+
+    #[test]
+    fn entries_are_checked_against_the_buffer_length() {
+        let h = Harness::boot(sim());
+        let buffer = h.client.buffer.take().unwrap();
+        let (result, buffer) = h.log.append(buffer, h.log.max_entry_length() + 1);
+        assert_eq!(result, ReturnCode::ESIZE);
+        h.client.buffer.replace(buffer.unwrap());
+
+        assert_eq!(h.append(&entry(1)), appended());
+        assert_eq!(h.read(4), Event::Read(Vec::new(), ReturnCode::ESIZE));
+        assert_eq!(h.read_all(), vec![entry(1)]);
+    }
+
+    #[test]
+    fn oldest_entries_are_lost_when_the_log_wraps() {
+        let sim = sim();
+        let h = Harness::boot(sim);
+        let mut lost = 0;
+        for n in 0..150 {
+            if h.append(&entry(n)) == Event::Appended(true, ReturnCode::SUCCESS) {
+                lost += 1;
+            }
+        }
+        assert!(lost > 0);
+
+        let entries = h.read_all();
+        assert_eq!(entries.last(), Some(&entry(149)));
+        assert!(entries.len() < 150);
+        for pair in entries.windows(2) {
+            assert_eq!(pair[0][0] + 1, pair[1][0]);
+        }
+
+        // Every page is erased in turn.
+        let counts: Vec<_> = (0..NUM_PAGES).map(|p| sim.storage.erase_count(p)).collect();
+        let min = counts.iter().min().unwrap();
+        let max = counts.iter().max().unwrap();
+        assert!(*min > 0 && max - min <= 1, "{:?}", counts);
+    }
+
+    #[test]
+    fn synced_entries_survive_a_reboot() {
+        let sim = sim();
+        let h = Harness::boot(sim);
+        for n in 0..30 {
+            h.append(&entry(n));
+        }
+        h.sync();
+        let start = h.log.log_start();
+        let end = h.log.log_end();
+        let before = h.read_all();
+
+        let h = Harness::boot(sim);
+        assert_eq!(h.read_all(), before);
+        assert_eq!(h.log.log_start(), start);
+        assert_eq!(h.log.log_end(), end);
+
+        // New entries go after the recovered ones.
+        assert_eq!(h.append(&entry(30)), appended());
+        assert_eq!(h.read_all(), vec![entry(30)]);
+    }
+
+    #[test]
+    fn power_cut_while_writing_a_page_keeps_earlier_pages() {
+        let sim = sim();
+        let h = Harness::boot(sim);
+        h.append(&entry(0));
+        h.sync();
+        for n in 1..16 {
+            h.append(&entry(n));
+        }
+        // The erase completes, the write is torn.
+        sim.storage.cut_power_after(1);
+        assert_eq!(h.log.sync(), ReturnCode::SUCCESS);
+        h.run();
+        assert!(h.client.events.borrow().is_empty());
+
+        sim.storage.power_on();
+        let h = Harness::boot(sim);
+        let entries = h.read_all();
+        assert_eq!(entries[0], entry(0));
+        // Entries in the torn half of the page are kept, the rest are not.
+        assert!(entries.len() > 1 && entries.len() < 16);
+        for (n, data) in entries.iter().enumerate() {
+            assert_eq!(*data, entry(n as u8));
+        }
+
+        assert_eq!(h.append(&entry(9)), appended());
+        h.sync();
+        assert_eq!(Harness::boot(sim).read_all().last(), Some(&entry(9)));
+    }
+
+    #[test]
+    fn power_cut_while_erasing_a_page_loses_only_that_page() {
+        let sim = sim();
+        let h = Harness::boot(sim);
+        for n in 0..NUM_PAGES as u8 {
+            h.append(&entry(n));
+            h.sync();
+        }
+        // The next page reuses the oldest one, whose erase is torn.
+        h.append(&entry(10));
+        sim.storage.cut_power_after(0);
+        assert_eq!(h.log.sync(), ReturnCode::SUCCESS);
+        h.run();
+
+        sim.storage.power_on();
+        let h = Harness::boot(sim);
+        assert_eq!(h.read_all(), vec![entry(1), entry(2), entry(3)]);
+        assert_eq!(h.append(&entry(11)), appended());
+        assert_eq!(h.read_all(), vec![entry(11)]);
+    }
+
+    #[test]
+    fn seek_moves_the_read_cursor() {
+        let h = Harness::boot(sim());
+        h.append(&entry(0));
+        let second = h.log.log_end();
+        h.append(&entry(1));
+        h.append(&entry(2));
+
+        assert_eq!(h.log.seek(second), ReturnCode::SUCCESS);
+        assert_eq!(h.log.next_read_entry_id(), second);
+        assert_eq!(h.read_all(), vec![entry(1), entry(2)]);
+
+        assert_eq!(h.log.seek(h.log.log_end() + 1), ReturnCode::EINVAL);
+        assert_eq!(h.log.seek(h.log.log_start()), ReturnCode::SUCCESS);
+        assert_eq!(h.read_all().len(), 3);
+    }
+
+    #[test]
+    fn erase_removes_all_entries() {
+        let sim = sim();
+        let h = Harness::boot(sim);
+        for n in 0..10 {
+            h.append(&entry(n));
+        }
+        h.sync();
+        let end = h.log.log_end();
+        assert_eq!(h.log.erase(), ReturnCode::SUCCESS);
+        assert_eq!(h.event(), Event::Erased(ReturnCode::SUCCESS));
+        assert!(h.log.log_start() >= end);
+        assert_eq!(h.read_all(), Vec::<Vec<u8>>::new());
+        assert_eq!(
+            sim.storage
+                .map_storage(|storage| storage.iter().all(|b| *b == 0xff)),
+            Some(true)
+        );
+
+        h.append(&entry(3));
+        assert_eq!(h.read_all(), vec![entry(3)]);
+        assert_eq!(Harness::boot(sim).read_all(), Vec::<Vec<u8>>::new());
+    }
+}
@@ -44,8 +44,13 @@
 //! mx25r6435f_spi.set_client(mx25r6435f);
 //! mx25r6435f_virtual_alarm.set_client(mx25r6435f);
 //! ```
+//!
+//! Besides `hil::flash`, which reads and writes whole 4 kB sectors and erases
+//! them before writing, the chip implements `hil::block_storage`, where it is
+//! read in bytes, written in 256 byte pages and erased in 4 kB sectors.
 
 use core::cell::Cell;
+use core::cmp;
 use core::ops::{Index, IndexMut};
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
@@ -60,6 +65,7 @@ pub static mut RXBUFFER: [u8; PAGE_SIZE as usize + 4] = [0; PAGE_SIZE as usize +
 const SPI_SPEED: u32 = 8000000;
 const SECTOR_SIZE: u32 = 4096;
 const PAGE_SIZE: u32 = 256;
+const CAPACITY: u32 = 8 * 1024 * 1024;
 
 /// This is a wrapper around a u8 array that is sized to a single page for the
 /// MX25R6435F. The page size is 4k because that is the smallest size that can
@@ -112,16 +118,24 @@ enum Opcodes {
 #[derive(Clone, Copy, PartialEq)]
 enum Operation {
     Erase,
-    Write { sector_index: u32 },
+    Write {
+        sector_index: u32,
+    },
+    /// Erase of sectors up to `end_sector` through `hil::block_storage`.
+    EraseBlocks {
+        sector_index: u32,
+        end_sector: u32,
+    },
 }
 
 #[derive(Clone, Copy, PartialEq)]
 enum State {
     Idle,
 
-    ReadSector {
-        sector_index: u32,
-        page_index: u32,
+    Read {
+        address: u32,
+        index: u32,
+        length: u32,
     },
 
     EraseSectorWriteEnable {
@@ -134,23 +148,29 @@ enum State {
     EraseSectorCheckDone {
         operation: Operation,
     },
-    EraseSectorDone,
+    EraseSectorDone {
+        operation: Operation,
+    },
 
-    WriteSectorWriteEnable {
-        sector_index: u32,
-        page_index: u32,
+    ProgramWriteEnable {
+        address: u32,
+        index: u32,
+        length: u32,
     },
-    WriteSectorWrite {
-        sector_index: u32,
-        page_index: u32,
+    ProgramWrite {
+        address: u32,
+        index: u32,
+        length: u32,
     },
-    WriteSectorCheckDone {
-        sector_index: u32,
-        page_index: u32,
+    ProgramCheckDone {
+        address: u32,
+        index: u32,
+        length: u32,
     },
-    WriteSectorWaitDone {
-        sector_index: u32,
-        page_index: u32,
+    ProgramWaitDone {
+        address: u32,
+        index: u32,
+        length: u32,
     },
 
     ReadId,
@@ -171,6 +191,8 @@ pub struct MX25R6435F<
     rxbuffer: TakeCell<'static, [u8]>,
     client: OptionalCell<&'a hil::flash::Client<MX25R6435F<'a, S, P, A>>>,
     client_sector: TakeCell<'static, Mx25r6435fSector>,
+    block_client: OptionalCell<&'static hil::block_storage::BlockStorageClient>,
+    client_buffer: TakeCell<'static, [u8]>,
 }
 
 impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time::Alarm + 'a>
@@ -194,6 +216,8 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
             rxbuffer: TakeCell::new(rxbuffer),
             client: OptionalCell::empty(),
             client_sector: TakeCell::empty(),
+            block_client: OptionalCell::empty(),
+            client_buffer: TakeCell::empty(),
         }
     }
 
@@ -248,6 +272,14 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
     }
 
     fn read_sector(&self, sector_index: u32, sector: &'static mut Mx25r6435fSector) -> ReturnCode {
+        // Save the user buffer for later
+        self.client_sector.replace(sector);
+        self.read_range(sector_index * SECTOR_SIZE, SECTOR_SIZE)
+    }
+
+    /// Read `length` bytes from `address` into the client's sector or buffer,
+    /// a page at a time.
+    fn read_range(&self, address: u32, length: u32) -> ReturnCode {
         self.configure_spi();
         self.txbuffer
             .take()
@@ -255,25 +287,21 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                 self.rxbuffer
                     .take()
                     .map_or(ReturnCode::ERESERVE, move |rxbuffer| {
-                        // Save the user buffer for later
-                        self.client_sector.replace(sector);
-
                         // Setup the read instruction
                         txbuffer[0] = Opcodes::READ as u8;
-                        txbuffer[1] = ((sector_index * SECTOR_SIZE) >> 16) as u8;
-                        txbuffer[2] = ((sector_index * SECTOR_SIZE) >> 8) as u8;
-                        txbuffer[3] = ((sector_index * SECTOR_SIZE) >> 0) as u8;
+                        txbuffer[1] = (address >> 16) as u8;
+                        txbuffer[2] = (address >> 8) as u8;
+                        txbuffer[3] = (address >> 0) as u8;
 
                         // Call the SPI driver to kick things off.
-                        self.state.set(State::ReadSector {
-                            sector_index,
-                            page_index: 0,
+                        self.state.set(State::Read {
+                            address,
+                            index: 0,
+                            length,
                         });
-                        self.spi.read_write_bytes(
-                            txbuffer,
-                            Some(rxbuffer),
-                            (PAGE_SIZE + 4) as usize,
-                        )
+                        let chunk = cmp::min(PAGE_SIZE, length);
+                        self.spi
+                            .read_write_bytes(txbuffer, Some(rxbuffer), (chunk + 4) as usize)
                     })
             })
     }
@@ -287,6 +315,85 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
         });
         self.enable_write()
     }
+
+    /// Call `f` with the data of the client's sector or buffer.
+    fn map_data<F: FnOnce(&mut [u8])>(&self, f: F) {
+        match self.client_sector.take() {
+            Some(sector) => {
+                f(&mut sector.0);
+                self.client_sector.replace(sector);
+            }
+            None => {
+                self.client_buffer.map(f);
+            }
+        }
+    }
+
+    fn read_complete(&self) {
+        match self.client_sector.take() {
+            Some(sector) => self.client.map(move |client| {
+                client.read_complete(sector, hil::flash::Error::CommandComplete);
+            }),
+            None => self.client_buffer.take().map(|buffer| {
+                self.block_client
+                    .map(move |client| client.read_done(buffer, ReturnCode::SUCCESS));
+            }),
+        };
+    }
+
+    fn write_complete(&self) {
+        match self.client_sector.take() {
+            Some(sector) => self.client.map(move |client| {
+                client.write_complete(sector, hil::flash::Error::CommandComplete);
+            }),
+            None => self.client_buffer.take().map(|buffer| {
+                self.block_client
+                    .map(move |client| client.write_done(buffer, ReturnCode::SUCCESS));
+            }),
+        };
+    }
+
+    /// Check a `hil::block_storage` operation, which must be non-empty,
+    /// aligned to `block_size` and within the chip.
+    fn check_block_operation(&self, address: u64, length: usize, block_size: u32) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if length == 0
+            || address % block_size as u64 != 0
+            || length % block_size as usize != 0
+            || address + length as u64 > CAPACITY as u64
+        {
+            return ReturnCode::EINVAL;
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Start a `hil::block_storage` read or write of the client's buffer,
+    /// returning the buffer if it fails.
+    fn start_block_transfer<F: FnOnce() -> ReturnCode>(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+        block_size: u32,
+        start: F,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let mut result = self.check_block_operation(address, length, block_size);
+        if length > buffer.len() {
+            result = ReturnCode::EINVAL;
+        }
+        if result != ReturnCode::SUCCESS {
+            return (result, Some(buffer));
+        }
+        self.client_buffer.replace(buffer);
+        let result = start();
+        if result != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+            return (result, self.client_buffer.take());
+        }
+        (result, None)
+    }
 }
 
 impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time::Alarm + 'a>
@@ -309,47 +416,46 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                     self.rxbuffer.replace(read_buffer);
                 });
             }
-            State::ReadSector {
-                sector_index,
-                page_index,
+            State::Read {
+                address,
+                index,
+                length,
             } => {
-                self.client_sector.take().map(|sector| {
-                    read_buffer.map(move |read_buffer| {
-                        // Copy read in bytes to user page
-                        for i in 0..(PAGE_SIZE as usize) {
-                            // Skip the command and address bytes (hence the +4).
-                            sector[i + (page_index * PAGE_SIZE) as usize] = read_buffer[i + 4];
-                        }
-
-                        if (page_index + 1) * PAGE_SIZE == SECTOR_SIZE {
-                            // Done reading
-                            self.state.set(State::Idle);
-                            self.txbuffer.replace(write_buffer);
-                            self.rxbuffer.replace(read_buffer);
-
-                            self.client.map(move |client| {
-                                client.read_complete(sector, hil::flash::Error::CommandComplete);
-                            });
-                        } else {
-                            let address =
-                                (sector_index * SECTOR_SIZE) + ((page_index + 1) * PAGE_SIZE);
-                            write_buffer[0] = Opcodes::READ as u8;
-                            write_buffer[1] = (address >> 16) as u8;
-                            write_buffer[2] = (address >> 8) as u8;
-                            write_buffer[3] = (address >> 0) as u8;
-
-                            self.state.set(State::ReadSector {
-                                sector_index,
-                                page_index: page_index + 1,
-                            });
-                            self.client_sector.replace(sector);
-                            self.spi.read_write_bytes(
-                                write_buffer,
-                                Some(read_buffer),
-                                (PAGE_SIZE + 4) as usize,
-                            );
-                        }
+                read_buffer.map(move |read_buffer| {
+                    // Copy read in bytes to the user's data, skipping the
+                    // command and address bytes (hence the +4).
+                    let chunk = cmp::min(PAGE_SIZE, length - index) as usize;
+                    let start = index as usize;
+                    self.map_data(|data| {
+                        data[start..start + chunk].copy_from_slice(&read_buffer[4..4 + chunk]);
                     });
+                    let index = index + chunk as u32;
+
+                    if index == length {
+                        // Done reading
+                        self.state.set(State::Idle);
+                        self.txbuffer.replace(write_buffer);
+                        self.rxbuffer.replace(read_buffer);
+                        self.read_complete();
+                    } else {
+                        let next = address + index;
+                        write_buffer[0] = Opcodes::READ as u8;
+                        write_buffer[1] = (next >> 16) as u8;
+                        write_buffer[2] = (next >> 8) as u8;
+                        write_buffer[3] = (next >> 0) as u8;
+
+                        self.state.set(State::Read {
+                            address,
+                            index,
+                            length,
+                        });
+                        let chunk = cmp::min(PAGE_SIZE, length - index);
+                        self.spi.read_write_bytes(
+                            write_buffer,
+                            Some(read_buffer),
+                            (chunk + 4) as usize,
+                        );
+                    }
                 });
             }
             State::EraseSectorWriteEnable {
@@ -382,14 +488,38 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                         // Erase is still in progress.
                         self.spi
                             .read_write_bytes(write_buffer, Some(read_buffer), 2);
+                    } else if let Operation::EraseBlocks {
+                        sector_index,
+                        end_sector,
+                    } = operation
+                    {
+                        self.rxbuffer.replace(read_buffer);
+                        if sector_index + 1 < end_sector {
+                            // Erase the next sector, which needs another
+                            // write enable.
+                            let sector_index = sector_index + 1;
+                            self.state.set(State::EraseSectorWriteEnable {
+                                sector_index,
+                                operation: Operation::EraseBlocks {
+                                    sector_index,
+                                    end_sector,
+                                },
+                            });
+                            self.txbuffer.replace(write_buffer);
+                            self.enable_write();
+                        } else {
+                            self.state.set(State::EraseSectorDone { operation });
+                            self.read_write_done(write_buffer, None, len);
+                        }
                     } else {
                         // Erase has finished, so jump to the next state.
                         let next_state = match operation {
-                            Operation::Erase => State::EraseSectorDone,
-                            Operation::Write { sector_index } => State::WriteSectorWriteEnable {
-                                sector_index,
-                                page_index: 0,
+                            Operation::Write { sector_index } => State::ProgramWriteEnable {
+                                address: sector_index * SECTOR_SIZE,
+                                index: 0,
+                                length: SECTOR_SIZE,
                             },
+                            _ => State::EraseSectorDone { operation },
                         };
                         self.state.set(next_state);
                         self.rxbuffer.replace(read_buffer);
@@ -397,70 +527,77 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                     }
                 });
             }
-            State::EraseSectorDone => {
+            State::EraseSectorDone { operation } => {
                 // No need to disable write, chip does it automatically.
                 self.state.set(State::Idle);
                 self.txbuffer.replace(write_buffer);
-                self.client.map(|client| {
-                    client.erase_complete(hil::flash::Error::CommandComplete);
-                });
+                if let Operation::EraseBlocks { .. } = operation {
+                    self.block_client
+                        .map(|client| client.erase_done(ReturnCode::SUCCESS));
+                } else {
+                    self.client.map(|client| {
+                        client.erase_complete(hil::flash::Error::CommandComplete);
+                    });
+                }
             }
-            State::WriteSectorWriteEnable {
-                sector_index,
-                page_index,
+            State::ProgramWriteEnable {
+                address,
+                index,
+                length,
             } => {
-                // Check if we are done. This happens when we have written a
-                // sector's worth of data, one page at a time.
-                if page_index * PAGE_SIZE == SECTOR_SIZE {
+                // Check if we are done. This happens when we have written all
+                // of the data, one page at a time.
+                if index == length {
                     // No need to disable writes since it happens automatically.
                     self.state.set(State::Idle);
                     self.txbuffer.replace(write_buffer);
-                    self.client.map(|client| {
-                        self.client_sector.take().map(|sector| {
-                            client.write_complete(sector, hil::flash::Error::CommandComplete);
-                        });
-                    });
+                    self.write_complete();
                 } else {
-                    self.state.set(State::WriteSectorWrite {
-                        sector_index,
-                        page_index,
+                    self.state.set(State::ProgramWrite {
+                        address,
+                        index,
+                        length,
                     });
                     // Need to write enable before each PP
                     write_buffer[0] = Opcodes::WREN as u8;
                     self.spi.read_write_bytes(write_buffer, None, 1);
                 }
             }
-            State::WriteSectorWrite {
-                sector_index,
-                page_index,
+            State::ProgramWrite {
+                address,
+                index,
+                length,
             } => {
                 // Continue writing page by page.
-                self.state.set(State::WriteSectorCheckDone {
-                    sector_index,
-                    page_index: page_index + 1,
+                self.state.set(State::ProgramCheckDone {
+                    address,
+                    index: index + PAGE_SIZE,
+                    length,
                 });
-                let address = (sector_index * SECTOR_SIZE) + (page_index * PAGE_SIZE);
+                let page_address = address + index;
                 write_buffer[0] = Opcodes::PP as u8;
-                write_buffer[1] = (address >> 16) as u8;
-                write_buffer[2] = (address >> 8) as u8;
-                write_buffer[3] = (address >> 0) as u8;
-
-                self.client_sector.map(|sector| {
-                    for i in 0..(PAGE_SIZE as usize) {
-                        write_buffer[i + 4] = sector[i + (page_index * PAGE_SIZE) as usize];
-                    }
+                write_buffer[1] = (page_address >> 16) as u8;
+                write_buffer[2] = (page_address >> 8) as u8;
+                write_buffer[3] = (page_address >> 0) as u8;
+
+                let start = index as usize;
+                self.map_data(|data| {
+                    write_buffer[4..4 + PAGE_SIZE as usize]
+                        .copy_from_slice(&data[start..start + PAGE_SIZE as usize]);
                 });
 
                 self.spi
                     .read_write_bytes(write_buffer, None, (PAGE_SIZE + 4) as usize);
             }
-            State::WriteSectorCheckDone {
-                sector_index,
-                page_index,
+            State::ProgramCheckDone {
+                address,
+                index,
+                length,
             } => {
-                self.state.set(State::WriteSectorWaitDone {
-                    sector_index,
-                    page_index,
+                self.state.set(State::ProgramWaitDone {
+                    address,
+                    index,
+                    length,
                 });
                 self.txbuffer.replace(write_buffer);
                 // Datasheet says write page takes 3.2 ms on average. So we wait
@@ -469,9 +606,10 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                 let tics = self.alarm.now().wrapping_add(interval);
                 self.alarm.set_alarm(tics);
             }
-            State::WriteSectorWaitDone {
-                sector_index,
-                page_index,
+            State::ProgramWaitDone {
+                address,
+                index,
+                length,
             } => {
                 read_buffer.map(move |read_buffer| {
                     let status = read_buffer[1];
@@ -483,9 +621,10 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                             .read_write_bytes(write_buffer, Some(read_buffer), 2);
                     } else {
                         // Write has finished, so go back to writing.
-                        self.state.set(State::WriteSectorWriteEnable {
-                            sector_index,
-                            page_index,
+                        self.state.set(State::ProgramWriteEnable {
+                            address,
+                            index,
+                            length,
                         });
                         self.rxbuffer.replace(read_buffer);
                         self.read_write_done(write_buffer, None, len);
@@ -496,7 +635,6 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
         }
     }
 }
-
 impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time::Alarm + 'a>
     hil::time::Client for MX25R6435F<'a, S, P, A>
 {
@@ -543,3 +681,73 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
         self.erase_sector(page_number as u32)
     }
 }
+
+impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time::Alarm + 'a>
+    hil::block_storage::BlockStorage for MX25R6435F<'a, S, P, A>
+{
+    fn set_client(&self, client: &'static hil::block_storage::BlockStorageClient) {
+        self.block_client.set(client);
+    }
+
+    fn geometry(&self) -> hil::block_storage::Geometry {
+        hil::block_storage::Geometry {
+            read_size: 1,
+            write_size: PAGE_SIZE as usize,
+            erase_size: SECTOR_SIZE as usize,
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        CAPACITY as u64
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.start_block_transfer(buffer, address, length, 1, || {
+            self.read_range(address as u32, length as u32)
+        })
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.start_block_transfer(buffer, address, length, PAGE_SIZE, || {
+            // The page program state sends a write enable first.
+            self.configure_spi();
+            self.state.set(State::ProgramWrite {
+                address: address as u32,
+                index: 0,
+                length: length as u32,
+            });
+            self.enable_write()
+        })
+    }
+
+    fn erase(&self, address: u64, length: usize) -> ReturnCode {
+        let result = self.check_block_operation(address, length, SECTOR_SIZE);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        let sector_index = address as u32 / SECTOR_SIZE;
+        self.configure_spi();
+        self.state.set(State::EraseSectorWriteEnable {
+            sector_index,
+            operation: Operation::EraseBlocks {
+                sector_index,
+                end_sector: sector_index + length as u32 / SECTOR_SIZE,
+            },
+        });
+        let result = self.enable_write();
+        if result != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+        }
+        result
+    }
+}
@@ -81,6 +81,20 @@ impl FrameType {
     }
 }
 
+/// Command identifiers of MAC command frames (IEEE 802.15.4-2015: Table 7-49)
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MacCommand {
+    AssociationRequest = 0x01,
+    AssociationResponse = 0x02,
+    DisassociationNotification = 0x03,
+    DataRequest = 0x04,
+    PanIdConflictNotification = 0x05,
+    OrphanNotification = 0x06,
+    BeaconRequest = 0x07,
+    CoordinatorRealignment = 0x08,
+}
+
 #[repr(u16)]
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum FrameVersion {
@@ -857,3 +871,141 @@ impl Header<'a> {
         stream_done!(off, (dst_pan, dst_addr, src_pan, src_addr));
     }
 }
+
+/// The broadcast PAN ID and short address
+pub const BROADCAST: u16 = 0xffff;
+
+mod superframe_spec {
+    pub const BEACON_ORDER_MASK: u16 = 0xf;
+    pub const SUPERFRAME_ORDER_POS: usize = 4;
+    pub const SUPERFRAME_ORDER_MASK: u16 = 0xf << 4;
+    pub const FINAL_CAP_SLOT_POS: usize = 8;
+    pub const FINAL_CAP_SLOT_MASK: u16 = 0xf << 8;
+    pub const BATTERY_LIFE_EXTENSION: u16 = 1 << 12;
+    pub const PAN_COORDINATOR: u16 = 1 << 14;
+    pub const ASSOCIATION_PERMIT: u16 = 1 << 15;
+}
+
+/// IEEE 802.15.4-2015: 7.3.1.3, Superframe Specification field of a beacon
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SuperframeSpec {
+    pub beacon_order: u8,
+    pub superframe_order: u8,
+    pub final_cap_slot: u8,
+    pub battery_life_extension: bool,
+    pub pan_coordinator: bool,
+    pub association_permit: bool,
+}
+
+impl Default for SuperframeSpec {
+    fn default() -> Self {
+        SuperframeSpec::nonbeacon(false, false)
+    }
+}
+
+impl SuperframeSpec {
+    /// The superframe specification sent by a coordinator of a PAN that does
+    /// not use periodic beacons, where beacons are only sent in response to
+    /// beacon requests.
+    pub fn nonbeacon(pan_coordinator: bool, association_permit: bool) -> SuperframeSpec {
+        SuperframeSpec {
+            beacon_order: 15,
+            superframe_order: 15,
+            final_cap_slot: 15,
+            battery_life_extension: false,
+            pan_coordinator: pan_coordinator,
+            association_permit: association_permit,
+        }
+    }
+
+    pub fn to_u16(&self) -> u16 {
+        let mut spec = (self.beacon_order as u16) & superframe_spec::BEACON_ORDER_MASK;
+        spec |= ((self.superframe_order as u16) << superframe_spec::SUPERFRAME_ORDER_POS)
+            & superframe_spec::SUPERFRAME_ORDER_MASK;
+        spec |= ((self.final_cap_slot as u16) << superframe_spec::FINAL_CAP_SLOT_POS)
+            & superframe_spec::FINAL_CAP_SLOT_MASK;
+        if self.battery_life_extension {
+            spec |= superframe_spec::BATTERY_LIFE_EXTENSION;
+        }
+        if self.pan_coordinator {
+            spec |= superframe_spec::PAN_COORDINATOR;
+        }
+        if self.association_permit {
+            spec |= superframe_spec::ASSOCIATION_PERMIT;
+        }
+        spec
+    }
+
+    pub fn from_u16(spec: u16) -> SuperframeSpec {
+        SuperframeSpec {
+            beacon_order: (spec & superframe_spec::BEACON_ORDER_MASK) as u8,
+            superframe_order: ((spec & superframe_spec::SUPERFRAME_ORDER_MASK)
+                >> superframe_spec::SUPERFRAME_ORDER_POS) as u8,
+            final_cap_slot: ((spec & superframe_spec::FINAL_CAP_SLOT_MASK)
+                >> superframe_spec::FINAL_CAP_SLOT_POS) as u8,
+            battery_life_extension: (spec & superframe_spec::BATTERY_LIFE_EXTENSION) != 0,
+            pan_coordinator: (spec & superframe_spec::PAN_COORDINATOR) != 0,
+            association_permit: (spec & superframe_spec::ASSOCIATION_PERMIT) != 0,
+        }
+    }
+}
+
+/// IEEE 802.15.4-2015: 7.3.1, the fields that begin the MAC payload of a beacon
+/// frame, before the beacon payload. Guaranteed time slots and pending
+/// addresses are not supported when encoding, but are skipped when decoding.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct BeaconFields {
+    pub superframe_spec: SuperframeSpec,
+    /// Whether the coordinator has data pending for any device
+    pub pending_addrs: bool,
+}
+
+impl BeaconFields {
+    pub fn new(superframe_spec: SuperframeSpec) -> BeaconFields {
+        BeaconFields {
+            superframe_spec: superframe_spec,
+            pending_addrs: false,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        let off = enc_consume!(buf; encode_u16, self.superframe_spec.to_u16().to_be());
+        // GTS Specification: no descriptors
+        let off = enc_consume!(buf, off; encode_u8, 0);
+        // Pending Address Specification: no addresses
+        let off = enc_consume!(buf, off; encode_u8, 0);
+        stream_done!(off);
+    }
+
+    /// Decodes the beacon fields, returning the offset of the beacon payload.
+    pub fn decode(buf: &[u8]) -> SResult<BeaconFields> {
+        let (off, spec_be) = dec_try!(buf; decode_u16);
+        let superframe_spec = SuperframeSpec::from_u16(u16::from_be(spec_be));
+
+        // GTS Specification, followed by the GTS Directions and GTS List
+        // fields only if there are GTS descriptors
+        let (off, gts_spec) = dec_try!(buf, off; decode_u8);
+        let gts_count = (gts_spec & 0x07) as usize;
+        let off = if gts_count > 0 {
+            off + 1 + 3 * gts_count
+        } else {
+            off
+        };
+
+        // Pending Address Specification, followed by the short addresses and
+        // then the extended addresses with data pending
+        let (off, pending_spec) = dec_try!(buf, off; decode_u8);
+        let short_count = (pending_spec & 0x07) as usize;
+        let long_count = ((pending_spec >> 4) & 0x07) as usize;
+        let off = off + 2 * short_count + 8 * long_count;
+        stream_len_cond!(buf, off);
+
+        stream_done!(
+            off,
+            BeaconFields {
+                superframe_spec: superframe_spec,
+                pending_addrs: short_count + long_count > 0,
+            }
+        );
+    }
+}
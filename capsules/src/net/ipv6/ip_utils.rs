@@ -188,7 +188,7 @@ pub fn compute_icmp_checksum(
     let mut sum: u32 = 0;
 
     // add ipv6 pseudo-header
-    sum += compute_ipv6_ph_sum(ipv6_header);
+    sum += compute_ipv6_ph_sum(ipv6_header, icmp_header.get_len(), ip6_nh::ICMP);
 
     // add type and code
     let msb = (icmp_header.get_type_as_int() as u32) << 8;
@@ -205,6 +205,7 @@ pub fn compute_icmp_checksum(
             sum += id as u32;
             sum += seqno as u32;
         }
+        ICMP6HeaderOptions::Type155 => {}
     }
 
     // add icmp payload
@@ -215,7 +216,7 @@ pub fn compute_icmp_checksum(
     while sum > 0xffff {
         let sum_upper = sum >> 16;
         let sum_lower = sum & 0xffff;
-        sum += sum_upper + sum_lower;
+        sum = sum_upper + sum_lower;
     }
 
     sum = !sum;
@@ -224,7 +225,11 @@ pub fn compute_icmp_checksum(
     sum as u16
 }
 
-pub fn compute_ipv6_ph_sum(ip6_header: &IP6Header) -> u32 {
+/// Computes the sum over the IPv6 pseudo-header (RFC 8200, section 8.1).
+/// The upper-layer length and protocol are passed separately, as they
+/// differ from the payload length and next header of the IPv6 header when
+/// extension headers are present.
+pub fn compute_ipv6_ph_sum(ip6_header: &IP6Header, upper_layer_len: u16, next_header: u8) -> u32 {
     let mut sum: u32 = 0;
 
     // sum over src/dest addresses
@@ -241,8 +246,8 @@ pub fn compute_ipv6_ph_sum(ip6_header: &IP6Header) -> u32 {
         i += 2;
     }
 
-    sum += ip6_header.payload_len as u32;
-    sum += ip6_header.next_header as u32;
+    sum += upper_layer_len as u32;
+    sum += next_header as u32;
 
     sum
 }
@@ -253,7 +258,12 @@ pub fn compute_sum(buf: &[u8], len: u16) -> u32 {
     let mut i: usize = 0;
     while i < (len as usize) {
         let msb = (buf[i] as u32) << 8;
-        let lsb = buf[i + 1] as u32;
+        // An odd-length buffer is padded with a zero byte
+        let lsb = if i + 1 < (len as usize) {
+            buf[i + 1] as u32
+        } else {
+            0
+        };
         sum += msb + lsb;
         i += 2;
     }
@@ -8,16 +8,18 @@
 //! An implementation for the structure of an IPv6 packet is provided by this
 //! file, and a rough outline is given below:
 //!
-//!            ----------------------------------------------
-//!            |                 IP6Packet                  |
-//!            |--------------------------------------------|
-//!            |                 |         IPPayload        |
-//!            |    IP6Header    |--------------------------|
-//!            |                 |TransportHeader | Payload |
-//!            ----------------------------------------------
+//!            -----------------------------------------------------------
+//!            |                        IP6Packet                        |
+//!            |---------------------------------------------------------|
+//!            |             |              |         IPPayload        |
+//!            |  IP6Header  | IP6ExtHeaders|--------------------------|
+//!            |             |              |TransportHeader | Payload |
+//!            -----------------------------------------------------------
 //!
 //! The [IP6Packet](struct.IP6Packet.html) struct contains an
-//! [IP6Header](struct.IP6Header.html) struct and an
+//! [IP6Header](struct.IP6Header.html) struct, an optional chain of
+//! extension headers held in an
+//! [IP6ExtHeaders](../ipv6_ext/struct.IP6ExtHeaders.html) struct, and an
 //! [IPPayload](struct.IPPayload.html) struct, with the `IPPayload` struct
 //! also containing a [TransportHeader](enum.TransportHeader.html) enum and
 //! a `Payload` buffer. Note that transport-level headers are contained inside
@@ -55,7 +57,9 @@
 //
 // One of the primary problems with the current encapsulation design is that
 // it is impossible to encode recursive headers - any subsequent headers (IPv6
-// or transport) must be serialized and carried in the raw payload. This may
+// or transport) must be serialized and carried in the raw payload. Extension
+// headers are the exception, as they are kept serialized in a fixed-size
+// buffer of `MAX_EXT_HDRS_LEN` bytes in the `IP6Packet`. This may
 // be avoided with references and allocation, but since we do not have
 // a memory allocator we could not allocate all possible headers at compile
 // time. Additionally, we couldn't just allocate headers "as-needed" on the
@@ -66,6 +70,7 @@
 
 use crate::net::icmpv6::icmpv6::ICMP6Header;
 use crate::net::ipv6::ip_utils::{compute_icmp_checksum, compute_udp_checksum, ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6_ext::{decode_chain, is_fragmented, IP6ExtHeaders};
 use crate::net::stream::SResult;
 use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
 use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
@@ -257,10 +262,23 @@ impl IP6Header {
 
     /// Utility function for verifying whether a transport layer checksum of a received
     /// packet is correct. Is called on the assocaite IPv6 Header, and passed the buffer
-    /// containing the remainder of the packet.
+    /// containing the remainder of the packet. Any extension headers are skipped, and
+    /// ENOSUPPORT is returned for fragments, whose checksums cannot be verified
+    /// without reassembly.
     pub fn check_transport_checksum(&self, buf: &[u8]) -> ReturnCode {
-        match self.next_header {
+        let (offset, next_header) = match decode_chain(self.next_header, buf).done() {
+            Some(result) => result,
+            None => return ReturnCode::FAIL,
+        };
+        if is_fragmented(self.next_header, buf) {
+            return ReturnCode::ENOSUPPORT;
+        }
+        let buf = &buf[offset..];
+        match next_header {
             ip6_nh::UDP => {
+                if buf.len() < UDP_HDR_LEN {
+                    return ReturnCode::FAIL;
+                }
                 let mut udp_header: [u8; UDP_HDR_LEN] = [0; UDP_HDR_LEN];
                 udp_header.copy_from_slice(&buf[..UDP_HDR_LEN]);
                 let checksum = match UDPHeader::decode(&udp_header).done() {
@@ -279,16 +297,18 @@ impl IP6Header {
             }
             ip6_nh::ICMP => {
                 // Untested (10/5/18)
-                let mut icmp_header: [u8; ICMP_HDR_LEN] = [0; ICMP_HDR_LEN];
-                icmp_header.copy_from_slice(&buf[..ICMP_HDR_LEN]);
-                let checksum = match ICMP6Header::decode(&icmp_header).done() {
-                    Some((_offset, mut hdr)) => {
+                // RPL control messages have a shorter header than
+                // ICMP_HDR_LEN, so leave the length check to `decode`
+                let valid = match ICMP6Header::decode(buf).done() {
+                    Some((offset, mut hdr)) => {
                         hdr.set_len(buf.len() as u16);
-                        u16::from_be(compute_icmp_checksum(&self, &hdr, &buf[ICMP_HDR_LEN..]))
+                        // The checksum is computed over every field except
+                        // the checksum itself, so compare against it
+                        compute_icmp_checksum(&self, &hdr, &buf[offset..]) == hdr.get_cksum()
                     }
-                    None => 0xffff, //Will be dropped, as ones comp -0 checksum is invalid
+                    None => false,
                 };
-                if checksum != 0 {
+                if !valid {
                     return ReturnCode::FAIL; //Incorrect cksum
                 }
                 ReturnCode::SUCCESS
@@ -305,8 +325,41 @@ pub enum TransportHeader {
     UDP(UDPHeader),
     TCP(TCPHeader),
     ICMP(ICMP6Header),
-    // TODO: Need a length in RawIPPacket for the buffer in TransportHeader
-    /* Raw(RawIPPacket<'a>), */
+    Raw(RawHeader),
+}
+
+/// Describes a payload that is carried without being interpreted, such as
+/// a packet being forwarded that is a fragment of a larger IPv6 packet. The
+/// upper-layer header, if any, is part of the payload.
+#[derive(Copy, Clone)]
+pub struct RawHeader {
+    next_header: u8,
+    len: u16,
+}
+
+impl RawHeader {
+    pub fn new(next_header: u8) -> RawHeader {
+        RawHeader {
+            next_header: next_header,
+            len: 0,
+        }
+    }
+
+    pub fn get_next_header(&self) -> u8 {
+        self.next_header
+    }
+
+    pub fn get_len(&self) -> u16 {
+        self.len
+    }
+
+    pub fn set_len(&mut self, len: u16) {
+        self.len = len;
+    }
+
+    pub fn get_hdr_size(&self) -> usize {
+        0
+    }
 }
 
 /// The `IPPayload` struct contains a `TransportHeader` and a mutable buffer
@@ -352,15 +405,21 @@ impl IPPayload<'a> {
             TransportHeader::UDP(mut udp_header) => {
                 let length = (payload.len() + udp_header.get_hdr_size()) as u16;
                 udp_header.set_len(length);
-                self.header = transport_header;
+                self.header = TransportHeader::UDP(udp_header);
                 (ip6_nh::UDP, length)
             }
             TransportHeader::ICMP(mut icmp_header) => {
                 let length = (payload.len() + icmp_header.get_hdr_size()) as u16;
                 icmp_header.set_len(length);
-                self.header = transport_header;
+                self.header = TransportHeader::ICMP(icmp_header);
                 (ip6_nh::ICMP, length)
             }
+            TransportHeader::Raw(mut raw_header) => {
+                let length = payload.len() as u16;
+                raw_header.set_len(length);
+                self.header = TransportHeader::Raw(raw_header);
+                (raw_header.get_next_header(), length)
+            }
             _ => (ip6_nh::NO_NEXT, payload.len() as u16),
         }
     }
@@ -380,6 +439,7 @@ impl IPPayload<'a> {
         let (offset, _) = match self.header {
             TransportHeader::UDP(udp_header) => udp_header.encode(buf, offset).done().unwrap(),
             TransportHeader::ICMP(icmp_header) => icmp_header.encode(buf, offset).done().unwrap(),
+            TransportHeader::Raw(_) => (offset, offset),
             _ => {
                 unimplemented!();
             }
@@ -397,6 +457,7 @@ impl IPPayload<'a> {
             TransportHeader::ICMP(icmp_header) => {
                 icmp_header.get_len() as usize - icmp_header.get_hdr_size()
             }
+            TransportHeader::Raw(raw_header) => raw_header.get_len() as usize,
             _ => {
                 unimplemented!();
             }
@@ -404,10 +465,11 @@ impl IPPayload<'a> {
     }
 }
 
-/// This struct defines the `IP6Packet` format, and contains an `IP6Header`
-/// and an `IPPayload`.
+/// This struct defines the `IP6Packet` format, and contains an `IP6Header`,
+/// the extension headers that follow it, and an `IPPayload`.
 pub struct IP6Packet<'a> {
     pub header: IP6Header,
+    pub ext_headers: IP6ExtHeaders,
     pub payload: IPPayload<'a>,
 }
 
@@ -426,12 +488,14 @@ impl IP6Packet<'a> {
     pub fn new(payload: IPPayload<'a>) -> IP6Packet<'a> {
         IP6Packet {
             header: IP6Header::default(),
+            ext_headers: IP6ExtHeaders::new(),
             payload: payload,
         }
     }
 
     pub fn reset(&mut self) {
         self.header = IP6Header::default();
+        self.ext_headers.clear();
     }
 
     pub fn get_total_len(&self) -> u16 {
@@ -446,9 +510,10 @@ impl IP6Packet<'a> {
         let transport_hdr_size = match self.payload.header {
             TransportHeader::UDP(udp_hdr) => udp_hdr.get_hdr_size(),
             TransportHeader::ICMP(icmp_header) => icmp_header.get_hdr_size(),
+            TransportHeader::Raw(raw_header) => raw_header.get_hdr_size(),
             _ => unimplemented!(),
         };
-        40 + transport_hdr_size
+        40 + self.ext_headers.get_len() + transport_hdr_size
     }
 
     pub fn set_transport_checksum(&mut self) {
@@ -472,6 +537,8 @@ impl IP6Packet<'a> {
                 let cksum = compute_icmp_checksum(&self.header, &icmp_header, self.payload.payload);
                 icmp_header.set_cksum(cksum);
             }
+            // The checksum of a raw payload is left unchanged
+            TransportHeader::Raw(_) => {}
             _ => {
                 unimplemented!();
             }
@@ -483,9 +550,11 @@ impl IP6Packet<'a> {
     /// method to set the transport header and transport payload, which then
     /// returns the `ip6_nh` value for the `TransportHeader` and the length of
     /// the serialized `IPPayload` region. This function then sets the
-    /// `IP6Header` next header field correctly. **Without using this function,
-    /// the `IP6Header.next_header` field may not agree with the actual
-    /// next header (`IP6Header.payload.header`)**
+    /// `IP6Header` next header field correctly, linking any extension headers
+    /// in between. **Without using this function, the `IP6Header.next_header`
+    /// field may not agree with the actual next header
+    /// (`IP6Header.payload.header`)**. Extension headers must therefore be
+    /// added before the payload is set.
     ///
     /// # Arguments
     ///
@@ -494,17 +563,83 @@ impl IP6Packet<'a> {
     /// transport payload
     pub fn set_payload(&mut self, transport_header: TransportHeader, payload: &[u8]) {
         let (next_header, payload_len) = self.payload.set_payload(transport_header, payload);
-        self.header.set_next_header(next_header);
-        self.header.set_payload_len(payload_len);
+        if self.ext_headers.is_empty() {
+            self.header.set_next_header(next_header);
+        } else {
+            self.ext_headers.set_upper_layer(next_header);
+            self.header
+                .set_next_header(self.ext_headers.get_first_type());
+        }
+        self.header
+            .set_payload_len(payload_len + self.ext_headers.get_len() as u16);
     }
 
-    // TODO: Do we need a decode equivalent? I don't think so, but we might
+    /// Fills this packet with a packet that was received, for example to
+    /// forward it. `payload` holds the serialized extension headers, transport
+    /// header and payload that follow `ip6_header`. UDP and ICMPv6 headers are
+    /// decoded so that their checksum can be recomputed. Fragments and other
+    /// protocols are kept as a raw payload, which leaves their checksum
+    /// unchanged.
+    ///
+    /// # Return Value
+    ///
+    /// `EINVAL` if the extension headers are malformed, and `ESIZE` if the
+    /// payload does not fit in this packet.
+    pub fn set_received(&mut self, ip6_header: IP6Header, payload: &[u8]) -> ReturnCode {
+        let first_header = ip6_header.get_next_header();
+        let (ext_len, next_header) = match decode_chain(first_header, payload).done() {
+            Some(result) => result,
+            None => return ReturnCode::EINVAL,
+        };
+        let transport_payload = &payload[ext_len..];
+
+        // The transport checksum is recomputed below, so it is cleared first
+        // (the UDP checksum computation includes the existing value).
+        let decoded = if is_fragmented(first_header, payload) {
+            None
+        } else {
+            match next_header {
+                ip6_nh::UDP => UDPHeader::decode(transport_payload)
+                    .done()
+                    .map(|(off, mut hdr)| {
+                        hdr.set_cksum(0);
+                        (off, TransportHeader::UDP(hdr))
+                    }),
+                ip6_nh::ICMP => {
+                    ICMP6Header::decode(transport_payload)
+                        .done()
+                        .map(|(off, mut hdr)| {
+                            hdr.set_cksum(0);
+                            (off, TransportHeader::ICMP(hdr))
+                        })
+                }
+                _ => None,
+            }
+        };
+        let (offset, transport_header) =
+            decoded.unwrap_or((0, TransportHeader::Raw(RawHeader::new(next_header))));
+        if self.payload.payload.len() < transport_payload.len() - offset {
+            return ReturnCode::ESIZE;
+        }
+
+        let mut ext_headers = IP6ExtHeaders::new();
+        let result = ext_headers.set_chain(first_header, &payload[..ext_len]);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        self.header = ip6_header;
+        self.ext_headers = ext_headers;
+        self.set_payload(transport_header, &transport_payload[offset..]);
+        self.set_transport_checksum();
+        ReturnCode::SUCCESS
+    }
 
     pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
         let ip6_header = self.header;
 
         // TODO: Handle unwrap safely
         let (off, _) = ip6_header.encode(buf).done().unwrap();
+        let off = enc_consume!(buf, off; encode_bytes, self.ext_headers.as_bytes());
         self.payload.encode(buf, off)
     }
 }
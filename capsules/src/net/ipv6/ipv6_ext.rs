@@ -0,0 +1,508 @@
+//! This file contains structs and methods for parsing and generating chains
+//! of IPv6 extension headers (RFC 8200, section 4).
+//!
+//! Extension headers sit between the IPv6 header and the upper-layer
+//! (transport) header. Each one begins with the type of the header that
+//! follows it, so the chain is walked starting from the next header field
+//! of the IPv6 header:
+//!
+//! ```text
+//! [ IP6Header | Hop-by-Hop | Fragment | UDP header | payload ]
+//!   nh = 0      nh = 44      nh = 17
+//! ```
+//!
+//! Received chains are parsed in place with an
+//! [ExtHeaderIter](struct.ExtHeaderIter.html), or skipped over with
+//! `decode_chain`. Chains are generated by pushing headers onto the
+//! [IP6ExtHeaders](struct.IP6ExtHeaders.html) held by an `IP6Packet`, which
+//! keeps the next header fields of the chain consistent.
+//!
+//! Only the Hop-by-Hop Options, Routing, Fragment, Destination Options and
+//! Mobility headers are supported. Hop-by-Hop and Destination Options headers
+//! are padded automatically, and the RPL option (RFC 6553) they may carry
+//! can be encoded and decoded with [RplOption](struct.RplOption.html).
+
+use crate::net::ipv6::ip_utils::ip6_nh;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u16, decode_u32, decode_u8};
+use crate::net::stream::{encode_u16, encode_u32, encode_u8};
+use kernel::ReturnCode;
+
+/// The maximum total length of the extension headers that can be attached
+/// to an outgoing packet. This fits a Hop-by-Hop Options header carrying an
+/// RPL option, a Fragment header and a short Routing header.
+pub const MAX_EXT_HDRS_LEN: usize = 32;
+
+/// Option types used in Hop-by-Hop and Destination Options headers
+pub mod ip6_opt {
+    pub const PAD1: u8 = 0;
+    pub const PADN: u8 = 1;
+    pub const RPL: u8 = 0x63;
+}
+
+/// Returns true if `next_header` identifies an extension header supported
+/// by this module.
+pub fn is_ext_header(next_header: u8) -> bool {
+    match next_header {
+        ip6_nh::HOP_OPTS
+        | ip6_nh::ROUTING
+        | ip6_nh::FRAGMENT
+        | ip6_nh::DST_OPTS
+        | ip6_nh::MOBILITY => true,
+        _ => false,
+    }
+}
+
+/// Returns true for the extension headers that contain a sequence of options
+fn has_options(hdr_type: u8) -> bool {
+    hdr_type == ip6_nh::HOP_OPTS || hdr_type == ip6_nh::DST_OPTS
+}
+
+/// A single extension header within a serialized chain.
+#[derive(Copy, Clone)]
+pub struct ExtHeader<'a> {
+    hdr_type: u8,
+    bytes: &'a [u8],
+}
+
+impl ExtHeader<'a> {
+    pub fn get_type(&self) -> u8 {
+        self.hdr_type
+    }
+
+    pub fn get_next_header(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    /// Returns the total length of the header in bytes
+    pub fn get_len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns the serialized header, including the next header and length
+    /// fields
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Returns the contents of the header following the next header and
+    /// length fields
+    pub fn get_data(&self) -> &'a [u8] {
+        &self.bytes[2..]
+    }
+
+    /// Returns an iterator over the options in a Hop-by-Hop or Destination
+    /// Options header. For other header types, the iterator is empty.
+    pub fn options(&self) -> OptionIter<'a> {
+        if has_options(self.hdr_type) {
+            OptionIter::new(self.get_data())
+        } else {
+            OptionIter::new(&[])
+        }
+    }
+
+    /// Returns true if this is a Fragment header for a packet that has been
+    /// split into multiple fragments. Atomic fragments (RFC 6946), which
+    /// carry the entire packet, are not considered fragmented.
+    pub fn is_fragmented(&self) -> bool {
+        self.hdr_type == ip6_nh::FRAGMENT
+            && FragmentHeader::decode(self.get_data())
+                .done()
+                .map_or(true, |(_, frag)| !frag.is_atomic())
+    }
+}
+
+/// Iterates over the extension headers in a serialized chain. Once the
+/// iterator is exhausted, `get_next_header` and `get_offset` return the
+/// upper-layer protocol and the offset of its header.
+pub struct ExtHeaderIter<'a> {
+    buf: &'a [u8],
+    next_header: u8,
+    offset: usize,
+    malformed: bool,
+}
+
+impl ExtHeaderIter<'a> {
+    /// Creates an iterator over the chain in `buf`, which begins with a
+    /// header of type `next_header`.
+    pub fn new(next_header: u8, buf: &'a [u8]) -> ExtHeaderIter<'a> {
+        ExtHeaderIter {
+            buf: buf,
+            next_header: next_header,
+            offset: 0,
+            malformed: false,
+        }
+    }
+
+    pub fn get_next_header(&self) -> u8 {
+        self.next_header
+    }
+
+    pub fn get_offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns true if iteration stopped because a header was truncated
+    pub fn is_malformed(&self) -> bool {
+        self.malformed
+    }
+}
+
+impl Iterator for ExtHeaderIter<'a> {
+    type Item = ExtHeader<'a>;
+
+    fn next(&mut self) -> Option<ExtHeader<'a>> {
+        if self.malformed || !is_ext_header(self.next_header) {
+            return None;
+        }
+        let remaining = &self.buf[self.offset..];
+        if remaining.len() < 8 {
+            self.malformed = true;
+            return None;
+        }
+        // The Fragment header has a reserved field in place of the length,
+        // and is always 8 bytes long
+        let len = if self.next_header == ip6_nh::FRAGMENT {
+            8
+        } else {
+            (remaining[1] as usize + 1) * 8
+        };
+        if remaining.len() < len {
+            self.malformed = true;
+            return None;
+        }
+        let header = ExtHeader {
+            hdr_type: self.next_header,
+            bytes: &remaining[..len],
+        };
+        self.next_header = remaining[0];
+        self.offset += len;
+        Some(header)
+    }
+}
+
+/// Skips over the chain of extension headers in `buf`, which begins with a
+/// header of type `next_header`. Returns the offset of the upper-layer header
+/// and its protocol, or an error if the chain is truncated.
+pub fn decode_chain(next_header: u8, buf: &[u8]) -> SResult<u8> {
+    let mut iter = ExtHeaderIter::new(next_header, buf);
+    while iter.next().is_some() {}
+    stream_cond!(!iter.is_malformed());
+    stream_done!(iter.get_offset(), iter.get_next_header());
+}
+
+/// Returns true if the chain in `buf` contains a Fragment header for a packet
+/// that has been split into multiple fragments. The upper-layer header and
+/// payload of such a packet cannot be processed without reassembly.
+pub fn is_fragmented(next_header: u8, buf: &[u8]) -> bool {
+    ExtHeaderIter::new(next_header, buf).any(|hdr| hdr.is_fragmented())
+}
+
+/// Iterates over the options in a Hop-by-Hop or Destination Options header,
+/// yielding the type and data of each option. Padding options are skipped.
+pub struct OptionIter<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl OptionIter<'a> {
+    pub fn new(buf: &'a [u8]) -> OptionIter<'a> {
+        OptionIter {
+            buf: buf,
+            offset: 0,
+        }
+    }
+}
+
+impl Iterator for OptionIter<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        while self.offset < self.buf.len() {
+            let opt_type = self.buf[self.offset];
+            if opt_type == ip6_opt::PAD1 {
+                self.offset += 1;
+                continue;
+            }
+            if self.offset + 2 > self.buf.len() {
+                return None;
+            }
+            let start = self.offset + 2;
+            let end = start + self.buf[self.offset + 1] as usize;
+            if end > self.buf.len() {
+                return None;
+            }
+            self.offset = end;
+            if opt_type != ip6_opt::PADN {
+                return Some((opt_type, &self.buf[start..end]));
+            }
+        }
+        None
+    }
+}
+
+/// Writes padding into `buf`, using a Pad1 option for a single byte and a
+/// PadN option otherwise.
+fn encode_padding(buf: &mut [u8]) {
+    match buf.len() {
+        0 => {}
+        1 => buf[0] = ip6_opt::PAD1,
+        n => {
+            buf[0] = ip6_opt::PADN;
+            buf[1] = (n - 2) as u8;
+            for b in buf[2..].iter_mut() {
+                *b = 0;
+            }
+        }
+    }
+}
+
+/// The RPL option (RFC 6553), carried in a Hop-by-Hop Options header to
+/// allow routers to detect loops in a RPL network.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RplOption {
+    /// The packet is travelling down the DODAG
+    pub down: bool,
+    pub rank_error: bool,
+    pub forwarding_error: bool,
+    pub instance_id: u8,
+    pub sender_rank: u16,
+}
+
+mod rpl_opt_flags {
+    pub const DOWN: u8 = 0x80;
+    pub const RANK_ERROR: u8 = 0x40;
+    pub const FORWARDING_ERROR: u8 = 0x20;
+}
+
+impl RplOption {
+    /// The length of the encoded option, including its type and length
+    pub const SIZE: usize = 6;
+
+    /// Encodes the option, including its type and length fields, so that it
+    /// can be placed in the options of a Hop-by-Hop Options header
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        stream_len_cond!(buf, Self::SIZE);
+        let mut flags = 0;
+        if self.down {
+            flags |= rpl_opt_flags::DOWN;
+        }
+        if self.rank_error {
+            flags |= rpl_opt_flags::RANK_ERROR;
+        }
+        if self.forwarding_error {
+            flags |= rpl_opt_flags::FORWARDING_ERROR;
+        }
+        let off = enc_consume!(buf, 0; encode_u8, ip6_opt::RPL);
+        let off = enc_consume!(buf, off; encode_u8, (Self::SIZE - 2) as u8);
+        let off = enc_consume!(buf, off; encode_u8, flags);
+        let off = enc_consume!(buf, off; encode_u8, self.instance_id);
+        let off = enc_consume!(buf, off; encode_u16, self.sender_rank);
+        stream_done!(off, off);
+    }
+
+    /// Decodes the option from the option data yielded by an `OptionIter`
+    pub fn decode(buf: &[u8]) -> SResult<RplOption> {
+        stream_len_cond!(buf, Self::SIZE - 2);
+        let (off, flags) = dec_try!(buf, 0; decode_u8);
+        let (off, instance_id) = dec_try!(buf, off; decode_u8);
+        let (off, sender_rank) = dec_try!(buf, off; decode_u16);
+        stream_done!(
+            off,
+            RplOption {
+                down: flags & rpl_opt_flags::DOWN != 0,
+                rank_error: flags & rpl_opt_flags::RANK_ERROR != 0,
+                forwarding_error: flags & rpl_opt_flags::FORWARDING_ERROR != 0,
+                instance_id: instance_id,
+                sender_rank: sender_rank,
+            }
+        );
+    }
+
+    /// Finds and decodes the RPL option in the Hop-by-Hop Options header of
+    /// the chain in `buf`, if there is one
+    pub fn find(next_header: u8, buf: &[u8]) -> Option<RplOption> {
+        ExtHeaderIter::new(next_header, buf)
+            .filter(|hdr| hdr.get_type() == ip6_nh::HOP_OPTS)
+            .flat_map(|hdr| hdr.options())
+            .filter(|&(opt_type, _)| opt_type == ip6_opt::RPL)
+            .filter_map(|(_, data)| RplOption::decode(data).done())
+            .map(|(_, opt)| opt)
+            .next()
+    }
+}
+
+/// The contents of an IPv6 Fragment header following its next header and
+/// reserved fields.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FragmentHeader {
+    /// Offset of the fragment in 8-byte units
+    pub offset: u16,
+    /// More fragments follow this one
+    pub more: bool,
+    pub id: u32,
+}
+
+impl FragmentHeader {
+    /// The length of the encoded fragment data
+    pub const SIZE: usize = 6;
+
+    pub fn is_atomic(&self) -> bool {
+        self.offset == 0 && !self.more
+    }
+
+    /// Encodes the fragment data, which is passed to `IP6ExtHeaders::push`
+    /// to add a Fragment header to a packet
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        stream_len_cond!(buf, Self::SIZE);
+        let offset_flags = (self.offset << 3) | (self.more as u16);
+        let off = enc_consume!(buf, 0; encode_u16, offset_flags);
+        let off = enc_consume!(buf, off; encode_u32, self.id);
+        stream_done!(off, off);
+    }
+
+    /// Decodes the fragment data returned by `ExtHeader::get_data`
+    pub fn decode(buf: &[u8]) -> SResult<FragmentHeader> {
+        stream_len_cond!(buf, Self::SIZE);
+        let (off, offset_flags) = dec_try!(buf, 0; decode_u16);
+        let (off, id) = dec_try!(buf, off; decode_u32);
+        stream_done!(
+            off,
+            FragmentHeader {
+                offset: offset_flags >> 3,
+                more: offset_flags & 1 != 0,
+                id: id,
+            }
+        );
+    }
+}
+
+/// A chain of extension headers to be sent with an outgoing packet. The
+/// chain is stored serialized, and the next header field of the last
+/// header is updated to the upper-layer protocol when the packet's payload
+/// is set.
+#[derive(Copy, Clone)]
+pub struct IP6ExtHeaders {
+    buf: [u8; MAX_EXT_HDRS_LEN],
+    len: usize,
+    first: u8,
+    last: usize,
+}
+
+impl Default for IP6ExtHeaders {
+    fn default() -> IP6ExtHeaders {
+        IP6ExtHeaders {
+            buf: [0; MAX_EXT_HDRS_LEN],
+            len: 0,
+            first: ip6_nh::NO_NEXT,
+            last: 0,
+        }
+    }
+}
+
+impl IP6ExtHeaders {
+    pub fn new() -> IP6ExtHeaders {
+        IP6ExtHeaders::default()
+    }
+
+    pub fn clear(&mut self) {
+        *self = IP6ExtHeaders::default();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the total length of the chain in bytes
+    pub fn get_len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the type of the first header in the chain, or `NO_NEXT` if
+    /// the chain is empty
+    pub fn get_first_type(&self) -> u8 {
+        self.first
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn iter(&self) -> ExtHeaderIter {
+        ExtHeaderIter::new(self.first, self.as_bytes())
+    }
+
+    /// Appends a header of type `hdr_type` to the end of the chain. `data` is
+    /// the contents of the header following the next header and length
+    /// fields. Hop-by-Hop and Destination Options headers are padded to a
+    /// multiple of 8 bytes; other headers must already have the right length.
+    ///
+    /// Returns EINVAL if `hdr_type` is not a supported extension header or
+    /// `data` has the wrong length, and ESIZE if the chain would not fit.
+    pub fn push(&mut self, hdr_type: u8, data: &[u8]) -> ReturnCode {
+        if !is_ext_header(hdr_type) {
+            return ReturnCode::EINVAL;
+        }
+        let unpadded = data.len() + 2;
+        let len = (unpadded + 7) & !7;
+        if len != unpadded && !has_options(hdr_type) {
+            return ReturnCode::EINVAL;
+        }
+        if hdr_type == ip6_nh::FRAGMENT && len != 8 {
+            return ReturnCode::EINVAL;
+        }
+        if self.len + len > MAX_EXT_HDRS_LEN {
+            return ReturnCode::ESIZE;
+        }
+
+        let start = self.len;
+        self.buf[start] = ip6_nh::NO_NEXT;
+        self.buf[start + 1] = (len / 8 - 1) as u8;
+        self.buf[start + 2..start + unpadded].copy_from_slice(data);
+        encode_padding(&mut self.buf[start + unpadded..start + len]);
+
+        if self.is_empty() {
+            self.first = hdr_type;
+        } else {
+            self.buf[self.last] = hdr_type;
+        }
+        self.last = start;
+        self.len += len;
+        ReturnCode::SUCCESS
+    }
+
+    /// Replaces the chain with a copy of the serialized chain in `buf`, which
+    /// begins with a header of type `next_header` and must contain only
+    /// extension headers. Returns EINVAL if the chain is malformed and ESIZE
+    /// if it does not fit.
+    pub fn set_chain(&mut self, next_header: u8, buf: &[u8]) -> ReturnCode {
+        if buf.len() > MAX_EXT_HDRS_LEN {
+            return ReturnCode::ESIZE;
+        }
+        let mut iter = ExtHeaderIter::new(next_header, buf);
+        let mut last = 0;
+        while let Some(hdr) = iter.next() {
+            last = iter.get_offset() - hdr.get_len();
+        }
+        if iter.is_malformed() || iter.get_offset() != buf.len() {
+            return ReturnCode::EINVAL;
+        }
+
+        self.clear();
+        if !buf.is_empty() {
+            self.buf[..buf.len()].copy_from_slice(buf);
+            self.len = buf.len();
+            self.first = next_header;
+            self.last = last;
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Sets the next header field of the last header in the chain to the
+    /// upper-layer protocol `next_header`.
+    pub fn set_upper_layer(&mut self, next_header: u8) {
+        if !self.is_empty() {
+            self.buf[self.last] = next_header;
+        }
+    }
+}
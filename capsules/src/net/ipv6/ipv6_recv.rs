@@ -23,6 +23,9 @@ use kernel::ReturnCode;
   implements the `ip_receive ` trait.
 - the `ip_receive` implementing struct (`IP6RecvStruct`) has a single client, which is
   udp_recv, a `UDPReceive` struct.
+- When the node takes part in a RPL network, a `RplNode` is interposed between
+  `IP6RecvStruct` and udp_recv. It consumes RPL control messages and forwards
+  packets addressed to other nodes.
 - The UDPReceive struct is a field of the UDPDriver, which ultimately passes the
   packets up to userland.
 */
@@ -20,6 +20,8 @@ use crate::ieee802154::device::{MacDevice, TxClient};
 use crate::net::ieee802154::MacAddress;
 use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::ipv6::ipv6::{IP6Header, IP6Packet, TransportHeader};
+use crate::net::ipv6::ipv6_ext::IP6ExtHeaders;
+use crate::net::sixlowpan::sixlowpan_mesh::{lowpan_mesh, ForwardingTable, MeshHeader};
 use crate::net::sixlowpan::sixlowpan_state::TxState;
 use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
@@ -35,6 +37,17 @@ pub trait IP6SendClient {
     fn send_done(&self, result: ReturnCode);
 }
 
+/// Routing components implement this trait to choose the link-layer neighbor
+/// that a packet is handed to. It is consulted by `IP6SendStruct` for every
+/// packet, so that packets for destinations several hops away are sent to
+/// the appropriate next hop.
+pub trait NextHopLookup {
+    /// Returns the MAC address of the neighbor that packets for `dst` should
+    /// be sent to, or `None` if the sender should fall back to its default
+    /// destination MAC address.
+    fn next_hop(&self, dst: IPAddr) -> Option<MacAddress>;
+}
+
 /// This trait provides a basic IPv6 sending interface. It exposes basic
 /// configuration information for the IPv6 layer (setting the source address,
 /// setting the gateway MAC address), as well as a way to send an IPv6
@@ -79,6 +92,34 @@ pub trait IP6Sender<'a> {
     /// `payload` - The transport payload for the packet being sent
     fn send_to(&self, dst: IPAddr, transport_header: TransportHeader, payload: &[u8])
         -> ReturnCode;
+
+    /// This method is the same as `send_to`, except that the given chain of
+    /// extension headers is inserted between the IPv6 header and the
+    /// transport header.
+    ///
+    /// # Arguments
+    /// `dst` - IPv6 address to send the packet to
+    /// `ext_headers` - The extension headers for the packet being sent
+    /// `transport_header` - The `TransportHeader` for the packet being sent
+    /// `payload` - The transport payload for the packet being sent
+    fn send_to_with_ext_headers(
+        &self,
+        dst: IPAddr,
+        ext_headers: &IP6ExtHeaders,
+        transport_header: TransportHeader,
+        payload: &[u8],
+    ) -> ReturnCode;
+
+    /// This method sends a packet that originated at another node on towards
+    /// its destination. Unlike `send_to`, the provided header (including the
+    /// source address) is sent unchanged, so the caller is responsible for
+    /// decrementing the hop limit.
+    ///
+    /// # Arguments
+    /// `ip6_header` - The header of the packet being forwarded
+    /// `payload` - The serialized extension headers, transport header and
+    /// payload of the packet
+    fn forward(&self, ip6_header: IP6Header, payload: &[u8]) -> ReturnCode;
 }
 
 /// This struct is a specific implementation of the `IP6Sender` trait. This
@@ -96,6 +137,8 @@ pub struct IP6SendStruct<'a, A: time::Alarm> {
     radio: &'a MacDevice<'a>,
     dst_mac_addr: MacAddress,
     src_mac_addr: MacAddress,
+    mesh_table: OptionalCell<&'a ForwardingTable>,
+    next_hop_lookup: OptionalCell<&'a NextHopLookup>,
     client: OptionalCell<&'a IP6SendClient>,
 }
 
@@ -123,16 +166,33 @@ impl<A: time::Alarm> IP6Sender<'a> for IP6SendStruct<'a, A> {
         transport_header: TransportHeader,
         payload: &[u8],
     ) -> ReturnCode {
-        self.sixlowpan.init(
-            self.src_mac_addr,
-            self.dst_mac_addr,
-            self.radio.get_pan(),
-            None,
-        );
-        self.init_packet(dst, transport_header, payload);
+        self.send_to_with_ext_headers(dst, &IP6ExtHeaders::new(), transport_header, payload)
+    }
+
+    fn send_to_with_ext_headers(
+        &self,
+        dst: IPAddr,
+        ext_headers: &IP6ExtHeaders,
+        transport_header: TransportHeader,
+        payload: &[u8],
+    ) -> ReturnCode {
+        self.init_sixlowpan(dst);
+        self.init_packet(dst, ext_headers, transport_header, payload);
         let ret = self.send_next_fragment();
         ret
     }
+
+    fn forward(&self, ip6_header: IP6Header, payload: &[u8]) -> ReturnCode {
+        let dst = ip6_header.get_dst_addr();
+        let result = self.ip6_packet.map_or(ReturnCode::ENOMEM, |ip6_packet| {
+            ip6_packet.set_received(ip6_header, payload)
+        });
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        self.init_sixlowpan(dst);
+        self.send_next_fragment()
+    }
 }
 
 impl<A: time::Alarm> IP6SendStruct<'a, A> {
@@ -155,13 +215,63 @@ impl<A: time::Alarm> IP6SendStruct<'a, A> {
             radio: radio,
             dst_mac_addr: dst_mac_addr,
             src_mac_addr: src_mac_addr,
+            mesh_table: OptionalCell::empty(),
+            next_hop_lookup: OptionalCell::empty(),
             client: OptionalCell::empty(),
         }
     }
 
-    fn init_packet(&self, dst_addr: IPAddr, transport_header: TransportHeader, payload: &[u8]) {
+    /// Enables mesh-under forwarding for packets sent by this instance. If
+    /// `table` routes the destination MAC address through a different
+    /// neighbor, frames are sent to that neighbor with a mesh addressing
+    /// header naming the destination as the final hop.
+    pub fn set_mesh_table(&self, table: &'a ForwardingTable) {
+        self.mesh_table.set(table);
+    }
+
+    /// Sets the routing component that selects the link-layer destination
+    /// for each packet based on its IPv6 destination address.
+    pub fn set_next_hop_lookup(&self, next_hop_lookup: &'a NextHopLookup) {
+        self.next_hop_lookup.set(next_hop_lookup);
+    }
+
+    fn init_sixlowpan(&self, dst: IPAddr) {
+        let (next_hop, mesh_header) = self.get_next_hop(dst);
+        self.sixlowpan
+            .init(self.src_mac_addr, next_hop, self.radio.get_pan(), None);
+        self.sixlowpan.set_mesh_header(mesh_header);
+    }
+
+    fn get_next_hop(&self, dst: IPAddr) -> (MacAddress, Option<MeshHeader>) {
+        let link_dst = self
+            .next_hop_lookup
+            .and_then(|lookup| lookup.next_hop(dst))
+            .unwrap_or(self.dst_mac_addr);
+        self.mesh_table
+            .map(|table| match table.next_hop(link_dst) {
+                Some(next_hop) if next_hop != link_dst => (
+                    next_hop,
+                    Some(MeshHeader::new(
+                        lowpan_mesh::DEFAULT_HOPS_LEFT,
+                        self.src_mac_addr,
+                        link_dst,
+                    )),
+                ),
+                _ => (link_dst, None),
+            })
+            .unwrap_or((link_dst, None))
+    }
+
+    fn init_packet(
+        &self,
+        dst_addr: IPAddr,
+        ext_headers: &IP6ExtHeaders,
+        transport_header: TransportHeader,
+        payload: &[u8],
+    ) {
         self.ip6_packet.map(|ip6_packet| {
             ip6_packet.header = IP6Header::default();
+            ip6_packet.ext_headers = *ext_headers;
             ip6_packet.header.src_addr = self.src_addr.get();
             ip6_packet.header.dst_addr = dst_addr;
             ip6_packet.set_payload(transport_header, payload);
@@ -1,13 +1,14 @@
 //! Modules for IPv6 over 6LoWPAN stack
 
 pub mod frag_utils;
-pub mod sixlowpan;
 pub mod util;
 #[macro_use]
 pub mod stream;
 pub mod icmpv6;
 pub mod ieee802154;
 pub mod ipv6;
+pub mod rpl;
+pub mod sixlowpan;
 pub mod tcp;
 pub mod thread;
 pub mod udp;
@@ -0,0 +1,2 @@
+pub mod rpl;
+pub mod rpl_node;
@@ -0,0 +1,559 @@
+//! This file contains the structs and methods associated with RPL control
+//! messages and options, as defined in RFC 6550. RPL control messages are
+//! carried in ICMPv6 messages of type 155, with the ICMPv6 code selecting the
+//! message (DIS, DIO, DAO or DAO-ACK). Each message consists of a base object
+//! followed by a sequence of options.
+//!
+//! Every struct in this file provides an `encode` method that serializes the
+//! message (or option, including its type and length bytes) into a buffer, and
+//! a `decode` method that performs the reverse operation. Options following a
+//! base object can be iterated over with
+//! [RplOptionIter](struct.RplOptionIter.html).
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u32, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u32, encode_u8};
+
+/// The ICMPv6 codes of the RPL control messages
+pub mod rpl_code {
+    pub const DIS: u8 = 0x00;
+    pub const DIO: u8 = 0x01;
+    pub const DAO: u8 = 0x02;
+    pub const DAO_ACK: u8 = 0x03;
+}
+
+/// The types of the RPL control message options
+pub mod rpl_opt {
+    pub const PAD1: u8 = 0x00;
+    pub const PADN: u8 = 0x01;
+    pub const DODAG_CONFIG: u8 = 0x04;
+    pub const TARGET: u8 = 0x05;
+    pub const TRANSIT_INFO: u8 = 0x06;
+    pub const PREFIX_INFO: u8 = 0x08;
+}
+
+/// Mode of Operation values carried in DIO messages
+pub mod rpl_mop {
+    pub const NO_DOWNWARD: u8 = 0;
+    pub const NON_STORING: u8 = 1;
+    pub const STORING: u8 = 2;
+}
+
+pub const INFINITE_RANK: u16 = 0xffff;
+pub const DEFAULT_MIN_HOP_RANK_INCREASE: u16 = 256;
+pub const ROOT_RANK: u16 = DEFAULT_MIN_HOP_RANK_INCREASE;
+
+/// Objective Code Point of OF0 (RFC 6552)
+pub const OCP_OF0: u16 = 0;
+
+/// The link-local scope all-RPL-nodes multicast address, ff02::1a
+pub const ALL_RPL_NODES: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1a]);
+
+mod dio_flags {
+    pub const GROUNDED: u8 = 0x80;
+    pub const MOP_SHIFT: u8 = 3;
+    pub const MOP_MASK: u8 = 0x38;
+    pub const PRF_MASK: u8 = 0x07;
+}
+
+mod dao_flags {
+    pub const ACK_REQUESTED: u8 = 0x80;
+    pub const DODAG_ID_PRESENT: u8 = 0x40;
+}
+
+mod pio_flags {
+    pub const ON_LINK: u8 = 0x80;
+    pub const AUTONOMOUS: u8 = 0x40;
+    pub const ROUTER_ADDRESS: u8 = 0x20;
+}
+
+const DAO_ACK_DODAG_ID_PRESENT: u8 = 0x80;
+
+const TRANSIT_EXTERNAL: u8 = 0x80;
+
+/// DODAG Information Solicitation base object. The DIS carries no
+/// information besides (currently unused) flags.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DIS {}
+
+impl DIS {
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        let off = enc_consume!(buf, offset; encode_u8, 0);
+        let off = enc_consume!(buf, off; encode_u8, 0);
+        stream_done!(off, off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<DIS> {
+        stream_len_cond!(buf, 2);
+        stream_done!(2, DIS {});
+    }
+}
+
+/// DODAG Information Object base object
+#[derive(Copy, Clone, Debug)]
+pub struct DIO {
+    pub instance_id: u8,
+    pub version: u8,
+    pub rank: u16,
+    pub grounded: bool,
+    pub mop: u8,
+    pub preference: u8,
+    pub dtsn: u8,
+    pub dodag_id: IPAddr,
+}
+
+impl DIO {
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        stream_len_cond!(buf, offset + 24);
+
+        let mut flags = (self.mop << dio_flags::MOP_SHIFT) & dio_flags::MOP_MASK;
+        flags |= self.preference & dio_flags::PRF_MASK;
+        if self.grounded {
+            flags |= dio_flags::GROUNDED;
+        }
+
+        let mut off = enc_consume!(buf, offset; encode_u8, self.instance_id);
+        off = enc_consume!(buf, off; encode_u8, self.version);
+        off = enc_consume!(buf, off; encode_u16, self.rank);
+        off = enc_consume!(buf, off; encode_u8, flags);
+        off = enc_consume!(buf, off; encode_u8, self.dtsn);
+        // Flags and reserved bytes
+        off = enc_consume!(buf, off; encode_u16, 0);
+        off = enc_consume!(buf, off; encode_bytes, &self.dodag_id.0);
+        stream_done!(off, off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<DIO> {
+        let (off, instance_id) = dec_try!(buf; decode_u8);
+        let (off, version) = dec_try!(buf, off; decode_u8);
+        let (off, rank) = dec_try!(buf, off; decode_u16);
+        let (off, flags) = dec_try!(buf, off; decode_u8);
+        let (off, dtsn) = dec_try!(buf, off; decode_u8);
+        let (off, _) = dec_try!(buf, off; decode_u16);
+        let mut dodag_id = IPAddr::new();
+        let off = dec_consume!(buf, off; decode_bytes, &mut dodag_id.0);
+        stream_done!(
+            off,
+            DIO {
+                instance_id: instance_id,
+                version: version,
+                rank: rank,
+                grounded: flags & dio_flags::GROUNDED != 0,
+                mop: (flags & dio_flags::MOP_MASK) >> dio_flags::MOP_SHIFT,
+                preference: flags & dio_flags::PRF_MASK,
+                dtsn: dtsn,
+                dodag_id: dodag_id,
+            }
+        );
+    }
+}
+
+/// Destination Advertisement Object base object
+#[derive(Copy, Clone, Debug)]
+pub struct DAO {
+    pub instance_id: u8,
+    pub ack_requested: bool,
+    pub sequence: u8,
+    pub dodag_id: Option<IPAddr>,
+}
+
+impl DAO {
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        let mut flags = 0;
+        if self.ack_requested {
+            flags |= dao_flags::ACK_REQUESTED;
+        }
+        if self.dodag_id.is_some() {
+            flags |= dao_flags::DODAG_ID_PRESENT;
+        }
+
+        let mut off = enc_consume!(buf, offset; encode_u8, self.instance_id);
+        off = enc_consume!(buf, off; encode_u8, flags);
+        off = enc_consume!(buf, off; encode_u8, 0);
+        off = enc_consume!(buf, off; encode_u8, self.sequence);
+        if let Some(dodag_id) = self.dodag_id {
+            off = enc_consume!(buf, off; encode_bytes, &dodag_id.0);
+        }
+        stream_done!(off, off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<DAO> {
+        let (off, instance_id) = dec_try!(buf; decode_u8);
+        let (off, flags) = dec_try!(buf, off; decode_u8);
+        let (off, _) = dec_try!(buf, off; decode_u8);
+        let (off, sequence) = dec_try!(buf, off; decode_u8);
+        let (off, dodag_id) = if flags & dao_flags::DODAG_ID_PRESENT != 0 {
+            let mut dodag_id = IPAddr::new();
+            let off = dec_consume!(buf, off; decode_bytes, &mut dodag_id.0);
+            (off, Some(dodag_id))
+        } else {
+            (off, None)
+        };
+        stream_done!(
+            off,
+            DAO {
+                instance_id: instance_id,
+                ack_requested: flags & dao_flags::ACK_REQUESTED != 0,
+                sequence: sequence,
+                dodag_id: dodag_id,
+            }
+        );
+    }
+}
+
+/// Destination Advertisement Object Acknowledgement base object
+#[derive(Copy, Clone, Debug)]
+pub struct DAOAck {
+    pub instance_id: u8,
+    pub sequence: u8,
+    pub status: u8,
+    pub dodag_id: Option<IPAddr>,
+}
+
+impl DAOAck {
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        let flags = if self.dodag_id.is_some() {
+            DAO_ACK_DODAG_ID_PRESENT
+        } else {
+            0
+        };
+        let mut off = enc_consume!(buf, offset; encode_u8, self.instance_id);
+        off = enc_consume!(buf, off; encode_u8, flags);
+        off = enc_consume!(buf, off; encode_u8, self.sequence);
+        off = enc_consume!(buf, off; encode_u8, self.status);
+        if let Some(dodag_id) = self.dodag_id {
+            off = enc_consume!(buf, off; encode_bytes, &dodag_id.0);
+        }
+        stream_done!(off, off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<DAOAck> {
+        let (off, instance_id) = dec_try!(buf; decode_u8);
+        let (off, flags) = dec_try!(buf, off; decode_u8);
+        let (off, sequence) = dec_try!(buf, off; decode_u8);
+        let (off, status) = dec_try!(buf, off; decode_u8);
+        let (off, dodag_id) = if flags & DAO_ACK_DODAG_ID_PRESENT != 0 {
+            let mut dodag_id = IPAddr::new();
+            let off = dec_consume!(buf, off; decode_bytes, &mut dodag_id.0);
+            (off, Some(dodag_id))
+        } else {
+            (off, None)
+        };
+        stream_done!(
+            off,
+            DAOAck {
+                instance_id: instance_id,
+                sequence: sequence,
+                status: status,
+                dodag_id: dodag_id,
+            }
+        );
+    }
+}
+
+/// The DODAG Configuration option, which carries the parameters that every
+/// node in a DODAG must agree on.
+#[derive(Copy, Clone, Debug)]
+pub struct DodagConfig {
+    pub dio_int_doublings: u8,
+    pub dio_int_min: u8,
+    pub dio_redundancy: u8,
+    pub max_rank_increase: u16,
+    pub min_hop_rank_increase: u16,
+    pub ocp: u16,
+    pub default_lifetime: u8,
+    pub lifetime_unit: u16,
+}
+
+impl Default for DodagConfig {
+    // The default values specified in RFC 6550, section 17
+    fn default() -> DodagConfig {
+        DodagConfig {
+            dio_int_doublings: 20,
+            dio_int_min: 3,
+            dio_redundancy: 10,
+            max_rank_increase: 0,
+            min_hop_rank_increase: DEFAULT_MIN_HOP_RANK_INCREASE,
+            ocp: OCP_OF0,
+            default_lifetime: 0xff,
+            lifetime_unit: 0xffff,
+        }
+    }
+}
+
+impl DodagConfig {
+    const LEN: u8 = 14;
+
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        let mut off = enc_consume!(buf, offset; encode_u8, rpl_opt::DODAG_CONFIG);
+        off = enc_consume!(buf, off; encode_u8, Self::LEN);
+        // Flags, A and PCS fields
+        off = enc_consume!(buf, off; encode_u8, 0);
+        off = enc_consume!(buf, off; encode_u8, self.dio_int_doublings);
+        off = enc_consume!(buf, off; encode_u8, self.dio_int_min);
+        off = enc_consume!(buf, off; encode_u8, self.dio_redundancy);
+        off = enc_consume!(buf, off; encode_u16, self.max_rank_increase);
+        off = enc_consume!(buf, off; encode_u16, self.min_hop_rank_increase);
+        off = enc_consume!(buf, off; encode_u16, self.ocp);
+        off = enc_consume!(buf, off; encode_u8, 0);
+        off = enc_consume!(buf, off; encode_u8, self.default_lifetime);
+        off = enc_consume!(buf, off; encode_u16, self.lifetime_unit);
+        stream_done!(off, off);
+    }
+
+    // Decodes the option body, following the type and length bytes
+    fn decode_body(buf: &[u8]) -> SResult<DodagConfig> {
+        stream_len_cond!(buf, Self::LEN as usize);
+        let (off, _) = dec_try!(buf; decode_u8);
+        let (off, dio_int_doublings) = dec_try!(buf, off; decode_u8);
+        let (off, dio_int_min) = dec_try!(buf, off; decode_u8);
+        let (off, dio_redundancy) = dec_try!(buf, off; decode_u8);
+        let (off, max_rank_increase) = dec_try!(buf, off; decode_u16);
+        let (off, min_hop_rank_increase) = dec_try!(buf, off; decode_u16);
+        let (off, ocp) = dec_try!(buf, off; decode_u16);
+        let (off, _) = dec_try!(buf, off; decode_u8);
+        let (off, default_lifetime) = dec_try!(buf, off; decode_u8);
+        let (off, lifetime_unit) = dec_try!(buf, off; decode_u16);
+        stream_done!(
+            off,
+            DodagConfig {
+                dio_int_doublings: dio_int_doublings,
+                dio_int_min: dio_int_min,
+                dio_redundancy: dio_redundancy,
+                max_rank_increase: max_rank_increase,
+                min_hop_rank_increase: min_hop_rank_increase,
+                ocp: ocp,
+                default_lifetime: default_lifetime,
+                lifetime_unit: lifetime_unit,
+            }
+        );
+    }
+}
+
+/// The Prefix Information option, which advertises the prefix nodes use to
+/// configure their global addresses.
+#[derive(Copy, Clone, Debug)]
+pub struct PrefixInfo {
+    pub prefix_len: u8,
+    pub on_link: bool,
+    pub autonomous: bool,
+    pub router_address: bool,
+    pub valid_lifetime: u32,
+    pub preferred_lifetime: u32,
+    pub prefix: IPAddr,
+}
+
+impl PrefixInfo {
+    const LEN: u8 = 30;
+
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        let mut flags = 0;
+        if self.on_link {
+            flags |= pio_flags::ON_LINK;
+        }
+        if self.autonomous {
+            flags |= pio_flags::AUTONOMOUS;
+        }
+        if self.router_address {
+            flags |= pio_flags::ROUTER_ADDRESS;
+        }
+
+        let mut off = enc_consume!(buf, offset; encode_u8, rpl_opt::PREFIX_INFO);
+        off = enc_consume!(buf, off; encode_u8, Self::LEN);
+        off = enc_consume!(buf, off; encode_u8, self.prefix_len);
+        off = enc_consume!(buf, off; encode_u8, flags);
+        off = enc_consume!(buf, off; encode_u32, self.valid_lifetime);
+        off = enc_consume!(buf, off; encode_u32, self.preferred_lifetime);
+        off = enc_consume!(buf, off; encode_u32, 0);
+        off = enc_consume!(buf, off; encode_bytes, &self.prefix.0);
+        stream_done!(off, off);
+    }
+
+    fn decode_body(buf: &[u8]) -> SResult<PrefixInfo> {
+        stream_len_cond!(buf, Self::LEN as usize);
+        let (off, prefix_len) = dec_try!(buf; decode_u8);
+        let (off, flags) = dec_try!(buf, off; decode_u8);
+        let (off, valid_lifetime) = dec_try!(buf, off; decode_u32);
+        let (off, preferred_lifetime) = dec_try!(buf, off; decode_u32);
+        let (off, _) = dec_try!(buf, off; decode_u32);
+        let mut prefix = IPAddr::new();
+        let off = dec_consume!(buf, off; decode_bytes, &mut prefix.0);
+        stream_done!(
+            off,
+            PrefixInfo {
+                prefix_len: prefix_len,
+                on_link: flags & pio_flags::ON_LINK != 0,
+                autonomous: flags & pio_flags::AUTONOMOUS != 0,
+                router_address: flags & pio_flags::ROUTER_ADDRESS != 0,
+                valid_lifetime: valid_lifetime,
+                preferred_lifetime: preferred_lifetime,
+                prefix: prefix,
+            }
+        );
+    }
+}
+
+/// The RPL Target option, which names an address or prefix reachable
+/// through the sender of a DAO.
+#[derive(Copy, Clone, Debug)]
+pub struct Target {
+    pub prefix_len: u8,
+    pub prefix: IPAddr,
+}
+
+impl Target {
+    fn prefix_bytes(prefix_len: u8) -> usize {
+        ((prefix_len as usize) + 7) / 8
+    }
+
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        let prefix_bytes = Self::prefix_bytes(self.prefix_len);
+        let mut off = enc_consume!(buf, offset; encode_u8, rpl_opt::TARGET);
+        off = enc_consume!(buf, off; encode_u8, (2 + prefix_bytes) as u8);
+        off = enc_consume!(buf, off; encode_u8, 0);
+        off = enc_consume!(buf, off; encode_u8, self.prefix_len);
+        off = enc_consume!(buf, off; encode_bytes, &self.prefix.0[..prefix_bytes]);
+        stream_done!(off, off);
+    }
+
+    fn decode_body(buf: &[u8]) -> SResult<Target> {
+        let (off, _) = dec_try!(buf; decode_u8);
+        let (off, prefix_len) = dec_try!(buf, off; decode_u8);
+        stream_cond!(prefix_len <= 128);
+        let prefix_bytes = Self::prefix_bytes(prefix_len);
+        let mut prefix = IPAddr::new();
+        let off = dec_consume!(buf, off; decode_bytes, &mut prefix.0[..prefix_bytes]);
+        stream_done!(
+            off,
+            Target {
+                prefix_len: prefix_len,
+                prefix: prefix,
+            }
+        );
+    }
+}
+
+/// The Transit Information option, which describes the path to the targets
+/// preceding it in a DAO.
+#[derive(Copy, Clone, Debug)]
+pub struct TransitInfo {
+    pub external: bool,
+    pub path_control: u8,
+    pub path_sequence: u8,
+    pub path_lifetime: u8,
+    // Only present in non-storing mode
+    pub parent: Option<IPAddr>,
+}
+
+impl TransitInfo {
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        let len = if self.parent.is_some() { 20 } else { 4 };
+        let flags = if self.external { TRANSIT_EXTERNAL } else { 0 };
+        let mut off = enc_consume!(buf, offset; encode_u8, rpl_opt::TRANSIT_INFO);
+        off = enc_consume!(buf, off; encode_u8, len);
+        off = enc_consume!(buf, off; encode_u8, flags);
+        off = enc_consume!(buf, off; encode_u8, self.path_control);
+        off = enc_consume!(buf, off; encode_u8, self.path_sequence);
+        off = enc_consume!(buf, off; encode_u8, self.path_lifetime);
+        if let Some(parent) = self.parent {
+            off = enc_consume!(buf, off; encode_bytes, &parent.0);
+        }
+        stream_done!(off, off);
+    }
+
+    fn decode_body(buf: &[u8]) -> SResult<TransitInfo> {
+        let (off, flags) = dec_try!(buf; decode_u8);
+        let (off, path_control) = dec_try!(buf, off; decode_u8);
+        let (off, path_sequence) = dec_try!(buf, off; decode_u8);
+        let (off, path_lifetime) = dec_try!(buf, off; decode_u8);
+        let (off, parent) = if buf.len() >= off + 16 {
+            let mut parent = IPAddr::new();
+            let off = dec_consume!(buf, off; decode_bytes, &mut parent.0);
+            (off, Some(parent))
+        } else {
+            (off, None)
+        };
+        stream_done!(
+            off,
+            TransitInfo {
+                external: flags & TRANSIT_EXTERNAL != 0,
+                path_control: path_control,
+                path_sequence: path_sequence,
+                path_lifetime: path_lifetime,
+                parent: parent,
+            }
+        );
+    }
+}
+
+/// The options that can follow a RPL control message base object
+#[derive(Copy, Clone, Debug)]
+pub enum RplOption {
+    Pad,
+    DodagConfig(DodagConfig),
+    PrefixInfo(PrefixInfo),
+    Target(Target),
+    TransitInfo(TransitInfo),
+    Unknown(u8),
+}
+
+impl RplOption {
+    pub fn decode(buf: &[u8]) -> SResult<RplOption> {
+        let (off, opt_type) = dec_try!(buf; decode_u8);
+        if opt_type == rpl_opt::PAD1 {
+            stream_done!(off, RplOption::Pad);
+        }
+        let (off, len) = dec_try!(buf, off; decode_u8);
+        let end = off + len as usize;
+        stream_len_cond!(buf, end);
+        let body = &buf[off..end];
+        let option = match opt_type {
+            rpl_opt::PADN => RplOption::Pad,
+            rpl_opt::DODAG_CONFIG => {
+                RplOption::DodagConfig(dec_try!(DodagConfig::decode_body(body)).1)
+            }
+            rpl_opt::PREFIX_INFO => {
+                RplOption::PrefixInfo(dec_try!(PrefixInfo::decode_body(body)).1)
+            }
+            rpl_opt::TARGET => RplOption::Target(dec_try!(Target::decode_body(body)).1),
+            rpl_opt::TRANSIT_INFO => {
+                RplOption::TransitInfo(dec_try!(TransitInfo::decode_body(body)).1)
+            }
+            _ => RplOption::Unknown(opt_type),
+        };
+        stream_done!(end, option);
+    }
+}
+
+/// Iterates over the options following a RPL base object. Iteration stops at
+/// the end of the buffer or at the first malformed option.
+pub struct RplOptionIter<'a> {
+    buf: &'a [u8],
+}
+
+impl RplOptionIter<'a> {
+    pub fn new(buf: &'a [u8]) -> RplOptionIter<'a> {
+        RplOptionIter { buf: buf }
+    }
+}
+
+impl Iterator for RplOptionIter<'a> {
+    type Item = RplOption;
+
+    fn next(&mut self) -> Option<RplOption> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        match RplOption::decode(self.buf).done() {
+            Some((off, option)) => {
+                self.buf = &self.buf[off..];
+                Some(option)
+            }
+            None => {
+                self.buf = &[];
+                None
+            }
+        }
+    }
+}
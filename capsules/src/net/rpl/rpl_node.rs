@@ -0,0 +1,1033 @@
+//! This file contains an implementation of a storing mode RPL (RFC 6550)
+//! router. A `RplNode` joins (or, when configured as the root, creates) a
+//! DODAG, selects a preferred parent using the Objective Function Zero
+//! (RFC 6552), and maintains downward routes to the nodes in its sub-DODAG
+//! from the DAO messages they send.
+//!
+//! The node sits between the IPv6 receive path and the transport layer. It
+//! consumes RPL control messages (ICMPv6 type 155), forwards packets that are
+//! not addressed to this node, and passes everything else to its client. To
+//! route outgoing traffic, it implements `NextHopLookup`, which is registered
+//! with every `IP6SendStruct` whose packets should follow the DODAG: packets
+//! for destinations in the sub-DODAG go to the child that advertised them, and
+//! all other packets go to the preferred parent (the default route).
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let rpl_node = static_init!(
+//!     RplNode<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     RplNode::new(
+//!         rpl_alarm,
+//!         rpl_ip_send,
+//!         src_mac_addr,
+//!         &mut RPL_SEND_BUF,
+//!         &mut RPL_PARENTS,
+//!         &mut RPL_ROUTES,
+//!     )
+//! );
+//! rpl_alarm.set_client(rpl_node);
+//! rpl_ip_send.set_client(rpl_node);
+//! rpl_ip_send.set_next_hop_lookup(rpl_node);
+//! udp_ip_send.set_next_hop_lookup(rpl_node);
+//! ip_receive.set_client(rpl_node);
+//! rpl_node.set_client(udp_recv);
+//! rpl_node.start();
+//! ```
+//!
+//! A border router instead calls `start_root` with the DODAG ID and the
+//! prefix it advertises to the network. Nodes configure a global address from
+//! that prefix, which can be read with `get_global_addr`.
+//!
+//! Because the node only interacts with the rest of the system through the
+//! `IP6Sender`, `IP6RecvClient` and `Alarm` interfaces, several nodes can be
+//! connected to each other through simulated MAC devices and alarms.
+
+// Known Limitations
+// -----------------
+// - Only a single RPL instance and storing mode (MOP 2) are supported, and
+//   DODAG version numbers are compared with wrapping arithmetic instead of
+//   the lollipop counters of RFC 6550 section 7.2.
+// - Messages are not secured, and only the DIO, DIS, DAO and DAO-ACK messages
+//   are implemented (no DODAG Consistency Check).
+// - The node sends at most one message at a time. Packets forwarded while a
+//   control message is being sent are dropped.
+
+use crate::net::icmpv6::icmpv6::{ICMP6Header, ICMP6Type};
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::{ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6::{IP6Header, TransportHeader};
+use crate::net::ipv6::ipv6_ext::{decode_chain, is_fragmented};
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender, NextHopLookup};
+use crate::net::rpl::rpl::{rpl_code, rpl_mop, DodagConfig, PrefixInfo, RplOption, RplOptionIter};
+use crate::net::rpl::rpl::{DAOAck, Target, TransitInfo, DAO, DIO, DIS};
+use crate::net::rpl::rpl::{ALL_RPL_NODES, INFINITE_RANK, OCP_OF0, ROOT_RANK};
+use crate::net::stream::SResult;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::time::{self, Frequency};
+use kernel::ReturnCode;
+
+/// The default RPL instance created by a root
+pub const DEFAULT_INSTANCE_ID: u8 = 0x1e;
+
+// The DIO trickle parameters used by a root (RFC 6550 recommends a minimum
+// interval of 8ms, which is far too short for low-power networks)
+const ROOT_DIO_INT_MIN: u8 = 12;
+const ROOT_DIO_INT_DOUBLINGS: u8 = 8;
+
+// The OF0 step of rank, applied to every link (RFC 6552 section 6.1)
+const OF0_DEFAULT_STEP_OF_RANK: u16 = 3;
+
+const DIS_INTERVAL_MS: u32 = 10_000;
+const DAO_INTERVAL_MS: u32 = 60_000;
+// Delay before sending a DAO after a parent change, to aggregate routes
+const DAO_DELAY_MS: u32 = 1_000;
+
+// Messages waiting for the sender to become idle
+mod pending {
+    pub const DIO: u8 = 0x01;
+    pub const DIS: u8 = 0x02;
+    pub const DAO: u8 = 0x04;
+    pub const DAO_ACK: u8 = 0x08;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum RplState {
+    Idle,
+    Detached,
+    Joined,
+    Root,
+}
+
+/// A neighbor that advertised a DODAG rank in a DIO
+#[derive(Copy, Clone, Debug)]
+pub struct RplParent {
+    addr: IPAddr,
+    mac: MacAddress,
+    rank: u16,
+    dtsn: u8,
+}
+
+/// A downward route learned from a DAO
+#[derive(Copy, Clone, Debug)]
+pub struct RplRoute {
+    target: IPAddr,
+    prefix_len: u8,
+    next_hop: MacAddress,
+    // Remaining lifetime in seconds, or `None` if the route never expires
+    lifetime: Option<u32>,
+}
+
+/// Derives the MAC address of a neighbor from the interface identifier of
+/// its address. This is the inverse of `IPAddr::generate_from_mac`.
+pub fn mac_from_iid(addr: &IPAddr) -> MacAddress {
+    if addr.0[8..14] == [0, 0, 0, 0xff, 0xfe, 0] {
+        MacAddress::Short(((addr.0[14] as u16) << 8) | (addr.0[15] as u16))
+    } else {
+        let mut long_addr = [0; 8];
+        long_addr.copy_from_slice(&addr.0[8..16]);
+        long_addr[0] ^= 0b00000010;
+        MacAddress::Long(long_addr)
+    }
+}
+
+fn prefix_matches(addr: &IPAddr, prefix: &IPAddr, prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let remaining = prefix_len & 0x7;
+    if addr.0[..full_bytes] != prefix.0[..full_bytes] {
+        return false;
+    }
+    if remaining == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining);
+    (addr.0[full_bytes] & mask) == (prefix.0[full_bytes] & mask)
+}
+
+pub struct RplNode<'a, A: time::Alarm> {
+    alarm: &'a A,
+    ip_sender: &'a IP6Sender<'a>,
+    client: OptionalCell<&'a IP6RecvClient>,
+    send_buf: TakeCell<'static, [u8]>,
+    parents: TakeCell<'a, [Option<RplParent>]>,
+    routes: TakeCell<'a, [Option<RplRoute>]>,
+
+    state: Cell<RplState>,
+    link_local: Cell<IPAddr>,
+    global: OptionalCell<IPAddr>,
+    preferred_parent: OptionalCell<RplParent>,
+
+    // DODAG state
+    instance_id: Cell<u8>,
+    version: Cell<u8>,
+    dodag_id: Cell<IPAddr>,
+    rank: Cell<u16>,
+    dtsn: Cell<u8>,
+    dao_sequence: Cell<u8>,
+    config: Cell<DodagConfig>,
+    prefix: OptionalCell<PrefixInfo>,
+
+    // Trickle timer state (RFC 6206), with intervals in milliseconds
+    trickle_interval: Cell<u32>,
+    trickle_counter: Cell<u8>,
+
+    // Absolute alarm times of the scheduled events
+    dio_deadline: OptionalCell<u32>,
+    interval_deadline: OptionalCell<u32>,
+    dis_deadline: OptionalCell<u32>,
+    dao_deadline: OptionalCell<u32>,
+
+    // Outgoing message state
+    busy: Cell<bool>,
+    pending: Cell<u8>,
+    dio_dst: Cell<IPAddr>,
+    dao_ack_dst: Cell<IPAddr>,
+    dao_ack_sequence: Cell<u8>,
+    // Alarm time at which the downward routes were last aged
+    routes_aged: Cell<u32>,
+    rng: Cell<u32>,
+}
+
+impl<A: time::Alarm> RplNode<'a, A> {
+    pub fn new(
+        alarm: &'a A,
+        ip_sender: &'a IP6Sender<'a>,
+        src_mac_addr: MacAddress,
+        send_buf: &'static mut [u8],
+        parents: &'a mut [Option<RplParent>],
+        routes: &'a mut [Option<RplRoute>],
+    ) -> RplNode<'a, A> {
+        // Seed the random number generator from the MAC address, so that
+        // neighbors do not pick the same trickle timer offsets
+        let seed = match src_mac_addr {
+            MacAddress::Short(addr) => addr as u32,
+            MacAddress::Long(addr) => addr
+                .iter()
+                .fold(0, |acc: u32, &b| acc.rotate_left(5) ^ (b as u32)),
+        };
+        RplNode {
+            alarm: alarm,
+            ip_sender: ip_sender,
+            client: OptionalCell::empty(),
+            send_buf: TakeCell::new(send_buf),
+            parents: TakeCell::new(parents),
+            routes: TakeCell::new(routes),
+            state: Cell::new(RplState::Idle),
+            link_local: Cell::new(IPAddr::generate_from_mac(src_mac_addr)),
+            global: OptionalCell::empty(),
+            preferred_parent: OptionalCell::empty(),
+            instance_id: Cell::new(0),
+            version: Cell::new(0),
+            dodag_id: Cell::new(IPAddr::new()),
+            rank: Cell::new(INFINITE_RANK),
+            dtsn: Cell::new(0),
+            dao_sequence: Cell::new(0),
+            config: Cell::new(DodagConfig::default()),
+            prefix: OptionalCell::empty(),
+            trickle_interval: Cell::new(0),
+            trickle_counter: Cell::new(0),
+            dio_deadline: OptionalCell::empty(),
+            interval_deadline: OptionalCell::empty(),
+            dis_deadline: OptionalCell::empty(),
+            dao_deadline: OptionalCell::empty(),
+            busy: Cell::new(false),
+            pending: Cell::new(0),
+            dio_dst: Cell::new(ALL_RPL_NODES),
+            dao_ack_dst: Cell::new(IPAddr::new()),
+            dao_ack_sequence: Cell::new(0),
+            routes_aged: Cell::new(0),
+            rng: Cell::new(if seed == 0 { 0x2545f491 } else { seed }),
+        }
+    }
+
+    /// Sets the client that receives the packets addressed to this node,
+    /// other than RPL control messages.
+    pub fn set_client(&self, client: &'a IP6RecvClient) {
+        self.client.set(client);
+    }
+
+    /// Starts the node as a router that joins an existing DODAG. The node
+    /// solicits DIOs from its neighbors until it finds a parent.
+    pub fn start(&self) -> ReturnCode {
+        if self.state.get() != RplState::Idle {
+            return ReturnCode::EALREADY;
+        }
+        self.ip_sender.set_addr(self.link_local.get());
+        self.routes_aged.set(self.alarm.now());
+        self.detach();
+        ReturnCode::SUCCESS
+    }
+
+    /// Starts the node as the root of a new DODAG, identified by `dodag_id`.
+    /// The root advertises `prefix` to the DODAG, from which every node
+    /// configures a global address.
+    pub fn start_root(&self, dodag_id: IPAddr, prefix: IPAddr, prefix_len: u8) -> ReturnCode {
+        if self.state.get() != RplState::Idle {
+            return ReturnCode::EALREADY;
+        }
+        if prefix_len > 128 {
+            return ReturnCode::EINVAL;
+        }
+        self.ip_sender.set_addr(self.link_local.get());
+        self.routes_aged.set(self.alarm.now());
+        self.instance_id.set(DEFAULT_INSTANCE_ID);
+        self.dodag_id.set(dodag_id);
+        self.rank.set(ROOT_RANK);
+        self.config.set(DodagConfig {
+            dio_int_min: ROOT_DIO_INT_MIN,
+            dio_int_doublings: ROOT_DIO_INT_DOUBLINGS,
+            ..DodagConfig::default()
+        });
+        let prefix_info = PrefixInfo {
+            prefix_len: prefix_len,
+            on_link: false,
+            autonomous: true,
+            router_address: false,
+            valid_lifetime: 0xffffffff,
+            preferred_lifetime: 0xffffffff,
+            prefix: prefix,
+        };
+        self.prefix.set(prefix_info);
+        self.configure_global_addr(&prefix_info);
+        self.state.set(RplState::Root);
+        self.trickle_reset();
+        self.dao_deadline.set(self.deadline_in(DAO_INTERVAL_MS));
+        self.schedule();
+        ReturnCode::SUCCESS
+    }
+
+    /// Returns whether the node is part of a DODAG, either as its root or
+    /// with a preferred parent.
+    pub fn is_joined(&self) -> bool {
+        match self.state.get() {
+            RplState::Joined | RplState::Root => true,
+            _ => false,
+        }
+    }
+
+    pub fn get_rank(&self) -> u16 {
+        self.rank.get()
+    }
+
+    pub fn get_link_local_addr(&self) -> IPAddr {
+        self.link_local.get()
+    }
+
+    /// Returns the address configured from the prefix advertised by the
+    /// DODAG root, if any.
+    pub fn get_global_addr(&self) -> Option<IPAddr> {
+        self.global.map(|addr| *addr)
+    }
+
+    /// Returns the address of the preferred parent, which is the default
+    /// route of this node.
+    pub fn get_preferred_parent(&self) -> Option<IPAddr> {
+        self.preferred_parent.map(|parent| parent.addr)
+    }
+
+    fn is_local_addr(&self, addr: &IPAddr) -> bool {
+        addr.is_multicast()
+            || *addr == self.link_local.get()
+            || self.global.map_or(false, |global| *addr == *global)
+    }
+
+    fn configure_global_addr(&self, prefix_info: &PrefixInfo) {
+        if !prefix_info.autonomous || prefix_info.prefix_len > 64 {
+            return;
+        }
+        let mut addr = self.link_local.get();
+        addr.set_prefix(&prefix_info.prefix.0, prefix_info.prefix_len);
+        self.global.set(addr);
+    }
+
+    // Time management
+
+    fn random(&self) -> u32 {
+        // xorshift32
+        let mut x = self.rng.get();
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng.set(x);
+        x
+    }
+
+    fn ms_to_tics(ms: u32) -> u32 {
+        ((ms as u64) * (<A::Frequency>::frequency() as u64) / 1000) as u32
+    }
+
+    fn deadline_in(&self, ms: u32) -> u32 {
+        self.alarm.now().wrapping_add(Self::ms_to_tics(ms))
+    }
+
+    fn has_passed(now: u32, deadline: u32) -> bool {
+        now.wrapping_sub(deadline) < (1 << 31)
+    }
+
+    // Arms the alarm for the earliest scheduled event
+    fn schedule(&self) {
+        let now = self.alarm.now();
+        let next = [
+            self.dio_deadline.map(|d| *d),
+            self.interval_deadline.map(|d| *d),
+            self.dis_deadline.map(|d| *d),
+            self.dao_deadline.map(|d| *d),
+        ]
+        .iter()
+        .filter_map(|d| *d)
+        .min_by_key(|d| d.wrapping_sub(now));
+        match next {
+            Some(deadline) => self.alarm.set_alarm(deadline),
+            None => self.alarm.disable(),
+        }
+    }
+
+    fn trickle_imin(&self) -> u32 {
+        1 << (self.config.get().dio_int_min as u32).min(24)
+    }
+
+    fn trickle_imax(&self) -> u32 {
+        let config = self.config.get();
+        1 << ((config.dio_int_min as u32) + (config.dio_int_doublings as u32)).min(24)
+    }
+
+    // Starts a new trickle interval of the current length, and picks the time
+    // within the second half of the interval at which the DIO is sent.
+    fn trickle_start_interval(&self) {
+        let interval = self.trickle_interval.get();
+        let half = interval / 2;
+        self.trickle_counter.set(0);
+        self.dio_deadline
+            .set(self.deadline_in(half + self.random() % half.max(1)));
+        self.interval_deadline.set(self.deadline_in(interval));
+    }
+
+    // Resets the trickle timer after an inconsistency is detected
+    fn trickle_reset(&self) {
+        if self.trickle_interval.get() != self.trickle_imin() || self.dio_deadline.is_none() {
+            self.trickle_interval.set(self.trickle_imin());
+            self.trickle_start_interval();
+        }
+    }
+
+    fn trickle_stop(&self) {
+        self.dio_deadline.clear();
+        self.interval_deadline.clear();
+    }
+
+    // Parent selection
+
+    fn rank_increase(&self) -> u16 {
+        OF0_DEFAULT_STEP_OF_RANK.saturating_mul(self.config.get().min_hop_rank_increase)
+    }
+
+    fn detach(&self) {
+        self.state.set(RplState::Detached);
+        self.rank.set(INFINITE_RANK);
+        self.preferred_parent.clear();
+        self.dao_deadline.clear();
+        self.trickle_stop();
+        self.dis_deadline
+            .set(self.deadline_in(self.random() % DIS_INTERVAL_MS));
+        self.schedule();
+    }
+
+    // Selects the parent that results in the lowest rank of this node, using
+    // the Objective Function Zero. A new parent is only preferred over the
+    // current one if it lowers the rank by at least MinHopRankIncrease.
+    fn select_parent(&self) {
+        let joined = self.state.get() == RplState::Joined;
+        let current_rank = self.rank.get();
+        let current = self.preferred_parent.map(|parent| parent.addr);
+        let min_hop = self.config.get().min_hop_rank_increase;
+        let increase = self.rank_increase();
+
+        let (best, current_entry) = self
+            .parents
+            .map(|parents| {
+                let mut best: Option<RplParent> = None;
+                let mut current_entry: Option<RplParent> = None;
+                for parent in parents.iter().filter_map(|p| *p) {
+                    if Some(parent.addr) == current {
+                        current_entry = Some(parent);
+                    }
+                    // Never choose a neighbor that is deeper in the DODAG,
+                    // as this could create a loop
+                    if parent.rank == INFINITE_RANK || (joined && parent.rank >= current_rank) {
+                        continue;
+                    }
+                    if best.map_or(true, |b| parent.rank < b.rank) {
+                        best = Some(parent);
+                    }
+                }
+                (best, current_entry)
+            })
+            .unwrap_or((None, None));
+
+        let chosen = match (current_entry, best) {
+            (Some(cur), Some(b))
+                if cur.rank != INFINITE_RANK
+                    && cur.rank.saturating_add(increase) <= current_rank
+                    && b.rank.saturating_add(min_hop) > cur.rank =>
+            {
+                Some(cur)
+            }
+            (_, b) => b,
+        };
+
+        match chosen {
+            None => {
+                if self.state.get() == RplState::Joined {
+                    // Advertise an infinite rank so that children detach
+                    // from this node instead of routing through it
+                    self.rank.set(INFINITE_RANK);
+                    self.send_message(pending::DIO);
+                    self.detach();
+                }
+            }
+            Some(parent) => {
+                let rank = parent.rank.saturating_add(increase);
+                let parent_changed = current != Some(parent.addr);
+                let rank_changed = rank != current_rank;
+                self.preferred_parent.set(parent);
+                self.rank.set(rank);
+                if self.state.get() != RplState::Joined {
+                    self.state.set(RplState::Joined);
+                    self.dis_deadline.clear();
+                }
+                if parent_changed {
+                    self.schedule_dao(DAO_DELAY_MS);
+                }
+                if parent_changed || rank_changed {
+                    self.trickle_reset();
+                }
+                self.schedule();
+            }
+        }
+    }
+
+    // Schedules a DAO within `DAO_DELAY_MS` after `delay_ms`, unless one is
+    // already scheduled to be sent earlier
+    fn schedule_dao(&self, delay_ms: u32) {
+        let now = self.alarm.now();
+        let deadline = self.deadline_in(delay_ms + self.random() % DAO_DELAY_MS);
+        let earlier = self
+            .dao_deadline
+            .map_or(true, |d| deadline.wrapping_sub(now) < d.wrapping_sub(now));
+        if earlier {
+            self.dao_deadline.set(deadline);
+        }
+    }
+
+    fn update_parent(&self, addr: IPAddr, rank: u16, dtsn: u8) {
+        let parent = RplParent {
+            addr: addr,
+            mac: mac_from_iid(&addr),
+            rank: rank,
+            dtsn: dtsn,
+        };
+        self.parents.map(|parents| {
+            let mut slot = None;
+            for (i, entry) in parents.iter().enumerate() {
+                match *entry {
+                    Some(p) if p.addr == addr => {
+                        slot = Some(i);
+                        break;
+                    }
+                    None if slot.is_none() => slot = Some(i),
+                    _ => {}
+                }
+            }
+            // If the parent set is full, replace the worst parent if the new
+            // one is better
+            let slot = slot.or_else(|| {
+                parents
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, p)| p.map(|p| (i, p.rank)))
+                    .max_by_key(|&(_, rank)| rank)
+                    .and_then(|(i, worst)| if rank < worst { Some(i) } else { None })
+            });
+            if let Some(i) = slot {
+                parents[i] = Some(parent);
+            }
+        });
+    }
+
+    fn clear_parents(&self) {
+        self.parents.map(|parents| {
+            for parent in parents.iter_mut() {
+                *parent = None;
+            }
+        });
+    }
+
+    // Downward routes
+
+    fn add_route(&self, target: &Target, next_hop: MacAddress, lifetime: Option<u32>) {
+        self.routes.map(|routes| {
+            let mut slot = None;
+            for (i, entry) in routes.iter().enumerate() {
+                match *entry {
+                    Some(r) if r.target == target.prefix && r.prefix_len == target.prefix_len => {
+                        slot = Some(i);
+                        break;
+                    }
+                    None if slot.is_none() => slot = Some(i),
+                    _ => {}
+                }
+            }
+            if let Some(i) = slot {
+                routes[i] = Some(RplRoute {
+                    target: target.prefix,
+                    prefix_len: target.prefix_len,
+                    next_hop: next_hop,
+                    lifetime: lifetime,
+                });
+            }
+        });
+    }
+
+    fn remove_route(&self, target: &Target) {
+        self.routes.map(|routes| {
+            for entry in routes.iter_mut() {
+                if entry.map_or(false, |r| {
+                    r.target == target.prefix && r.prefix_len == target.prefix_len
+                }) {
+                    *entry = None;
+                }
+            }
+        });
+    }
+
+    // Ages the downward routes by the time elapsed since they were last aged
+    fn expire_routes(&self) {
+        let now = self.alarm.now();
+        let elapsed = now.wrapping_sub(self.routes_aged.get()) / <A::Frequency>::frequency();
+        self.routes_aged.set(
+            self.routes_aged
+                .get()
+                .wrapping_add(elapsed * <A::Frequency>::frequency()),
+        );
+        self.routes.map(|routes| {
+            for entry in routes.iter_mut() {
+                let expired = match *entry {
+                    Some(RplRoute {
+                        lifetime: Some(lifetime),
+                        ..
+                    }) => lifetime <= elapsed,
+                    _ => false,
+                };
+                if expired {
+                    *entry = None;
+                } else if let Some(ref mut route) = *entry {
+                    route.lifetime = route.lifetime.map(|l| l - elapsed);
+                }
+            }
+        });
+    }
+
+    fn lookup_route(&self, dst: &IPAddr) -> Option<MacAddress> {
+        self.routes
+            .map(|routes| {
+                routes
+                    .iter()
+                    .filter_map(|r| *r)
+                    .filter(|r| prefix_matches(dst, &r.target, r.prefix_len))
+                    .max_by_key(|r| r.prefix_len)
+                    .map(|r| r.next_hop)
+            })
+            .unwrap_or(None)
+    }
+
+    // Sending messages
+
+    // Requests that a message is sent, deferring it if another message is
+    // currently being sent.
+    fn send_message(&self, message: u8) {
+        self.pending.set(self.pending.get() | message);
+        if !self.busy.get() {
+            self.send_pending();
+        }
+    }
+
+    fn send_pending(&self) {
+        while !self.busy.get() && self.pending.get() != 0 {
+            let pending = self.pending.get();
+            let message = pending & pending.wrapping_neg();
+            self.pending.set(pending & !message);
+
+            let (code, dst) = match message {
+                pending::DIO => (rpl_code::DIO, self.dio_dst.get()),
+                pending::DIS => (rpl_code::DIS, ALL_RPL_NODES),
+                pending::DAO => match self.preferred_parent.map(|parent| parent.addr) {
+                    Some(addr) => (rpl_code::DAO, addr),
+                    None => continue,
+                },
+                _ => (rpl_code::DAO_ACK, self.dao_ack_dst.get()),
+            };
+            if message == pending::DIO {
+                self.dio_dst.set(ALL_RPL_NODES);
+            }
+
+            let result = self.send_buf.take().map_or(ReturnCode::EBUSY, |buf| {
+                let len = match code {
+                    rpl_code::DIO => self.encode_dio(buf),
+                    rpl_code::DIS => DIS {}.encode(buf, 0).done().map(|(off, _)| off),
+                    rpl_code::DAO => self.encode_dao(buf),
+                    _ => DAOAck {
+                        instance_id: self.instance_id.get(),
+                        sequence: self.dao_ack_sequence.get(),
+                        status: 0,
+                        dodag_id: None,
+                    }
+                    .encode(buf, 0)
+                    .done()
+                    .map(|(off, _)| off),
+                };
+                let result = match len {
+                    Some(len) => {
+                        let mut icmp_header = ICMP6Header::new(ICMP6Type::Type155);
+                        icmp_header.set_code(code);
+                        self.ip_sender
+                            .send_to(dst, TransportHeader::ICMP(icmp_header), &buf[..len])
+                    }
+                    None => ReturnCode::ESIZE,
+                };
+                self.send_buf.replace(buf);
+                result
+            });
+            if result == ReturnCode::SUCCESS {
+                self.busy.set(true);
+            }
+        }
+    }
+
+    fn encode_dio(&self, buf: &mut [u8]) -> Option<usize> {
+        let dio = DIO {
+            instance_id: self.instance_id.get(),
+            version: self.version.get(),
+            rank: self.rank.get(),
+            grounded: false,
+            mop: rpl_mop::STORING,
+            preference: 0,
+            dtsn: self.dtsn.get(),
+            dodag_id: self.dodag_id.get(),
+        };
+        let mut off = dio.encode(buf, 0).done()?.0;
+        off = self.config.get().encode(buf, off).done()?.0;
+        if let Some(prefix) = self.prefix.map(|prefix| *prefix) {
+            off = prefix.encode(buf, off).done()?.0;
+        }
+        Some(off)
+    }
+
+    // Encodes a DAO advertising this node's global address and every
+    // destination in the sub-DODAG. Targets that do not fit in the buffer are
+    // left out, and advertised again with the next DAO.
+    fn encode_dao(&self, buf: &mut [u8]) -> Option<usize> {
+        let config = self.config.get();
+        let sequence = self.dao_sequence.get().wrapping_add(1);
+        self.dao_sequence.set(sequence);
+        // DAOs are re-sent periodically, so acknowledgements are not needed
+        let dao = DAO {
+            instance_id: self.instance_id.get(),
+            ack_requested: false,
+            sequence: sequence,
+            dodag_id: None,
+        };
+        let transit = TransitInfo {
+            external: false,
+            path_control: 0,
+            path_sequence: sequence,
+            path_lifetime: config.default_lifetime,
+            parent: None,
+        };
+        let mut off = dao.encode(buf, 0).done()?.0;
+        if let Some(global) = self.global.map(|addr| *addr) {
+            let target = Target {
+                prefix_len: 128,
+                prefix: global,
+            };
+            off = target.encode(buf, off).done()?.0;
+        }
+        let limit = buf.len().saturating_sub(6);
+        self.routes.map(|routes| {
+            for route in routes.iter().filter_map(|r| *r) {
+                let target = Target {
+                    prefix_len: route.prefix_len,
+                    prefix: route.target,
+                };
+                // Leave room for the transit information option
+                match target.encode(&mut buf[..limit], off) {
+                    SResult::Done(new_off, _) => off = new_off,
+                    _ => break,
+                }
+            }
+        });
+        off = transit.encode(buf, off).done()?.0;
+        Some(off)
+    }
+
+    // Receiving messages
+
+    fn receive_rpl(&self, header: &IP6Header, code: u8, body: &[u8]) {
+        let src = header.get_src_addr();
+        match code {
+            rpl_code::DIS => self.receive_dis(header),
+            rpl_code::DIO => {
+                if let Some((off, dio)) = DIO::decode(body).done() {
+                    self.receive_dio(src, &dio, &body[off..]);
+                }
+            }
+            rpl_code::DAO => {
+                if let Some((off, dao)) = DAO::decode(body).done() {
+                    self.receive_dao(src, &dao, &body[off..]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn receive_dis(&self, header: &IP6Header) {
+        if !self.is_joined() {
+            return;
+        }
+        if header.get_dst_addr().is_multicast() {
+            self.trickle_reset();
+            self.schedule();
+        } else {
+            self.dio_dst.set(header.get_src_addr());
+            self.send_message(pending::DIO);
+        }
+    }
+
+    fn receive_dio(&self, src: IPAddr, dio: &DIO, options: &[u8]) {
+        let state = self.state.get();
+        if state == RplState::Idle || state == RplState::Root || dio.mop != rpl_mop::STORING {
+            return;
+        }
+
+        let joined = state == RplState::Joined;
+        if joined {
+            if dio.instance_id != self.instance_id.get() || dio.dodag_id != self.dodag_id.get() {
+                return;
+            }
+            let version_diff = dio.version.wrapping_sub(self.version.get());
+            if version_diff >= 0x80 {
+                // Ignore DIOs from an older DODAG version
+                return;
+            } else if version_diff != 0 {
+                // Global repair: rejoin the new DODAG version from scratch
+                self.clear_parents();
+                self.preferred_parent.clear();
+                self.state.set(RplState::Detached);
+                self.rank.set(INFINITE_RANK);
+                self.version.set(dio.version);
+            }
+        }
+
+        let mut config = None;
+        let mut prefix = None;
+        for option in RplOptionIter::new(options) {
+            match option {
+                RplOption::DodagConfig(c) => config = Some(c),
+                RplOption::PrefixInfo(p) => prefix = Some(p),
+                _ => {}
+            }
+        }
+
+        if self.state.get() == RplState::Detached {
+            // Only join DODAGs using the objective function implemented here
+            let config = config.unwrap_or_default();
+            if config.ocp != OCP_OF0 || dio.rank == INFINITE_RANK {
+                return;
+            }
+            self.instance_id.set(dio.instance_id);
+            self.dodag_id.set(dio.dodag_id);
+            self.version.set(dio.version);
+            self.config.set(config);
+        }
+        if let Some(prefix) = prefix {
+            self.prefix.set(prefix);
+            self.configure_global_addr(&prefix);
+        }
+
+        // A new DTSN from the preferred parent requests new DAOs
+        let from_parent = self.preferred_parent.map_or(false, |p| p.addr == src);
+        let dtsn_changed = self.preferred_parent.map_or(false, |p| p.dtsn != dio.dtsn);
+        if from_parent && dtsn_changed {
+            self.dtsn.set(self.dtsn.get().wrapping_add(1));
+            self.schedule_dao(0);
+        }
+
+        let previous_rank = self.rank.get();
+        self.update_parent(src, dio.rank, dio.dtsn);
+        self.select_parent();
+
+        // DIOs that do not change this node's position are consistent
+        if self.rank.get() == previous_rank && dio.rank != INFINITE_RANK {
+            self.trickle_counter
+                .set(self.trickle_counter.get().saturating_add(1));
+        }
+        self.schedule();
+    }
+
+    fn receive_dao(&self, src: IPAddr, dao: &DAO, options: &[u8]) {
+        if !self.is_joined() || dao.instance_id != self.instance_id.get() {
+            return;
+        }
+        // DAOs are only accepted from children, as routes through the parent
+        // would create a loop
+        if self.preferred_parent.map_or(false, |p| p.addr == src) {
+            return;
+        }
+
+        let next_hop = mac_from_iid(&src);
+        let unit = self.config.get().lifetime_unit as u32;
+        let mut targets: [Option<Target>; 8] = [None; 8];
+        let mut count = 0;
+        for option in RplOptionIter::new(options) {
+            match option {
+                RplOption::Target(target) => {
+                    if count < targets.len() {
+                        targets[count] = Some(target);
+                        count += 1;
+                    }
+                }
+                RplOption::TransitInfo(transit) => {
+                    // The transit information applies to the targets that
+                    // precede it
+                    for target in targets[..count].iter().filter_map(|t| *t) {
+                        match transit.path_lifetime {
+                            0 => self.remove_route(&target),
+                            0xff => self.add_route(&target, next_hop, None),
+                            lifetime => self.add_route(
+                                &target,
+                                next_hop,
+                                Some((lifetime as u32).saturating_mul(unit)),
+                            ),
+                        }
+                    }
+                    count = 0;
+                }
+                _ => {}
+            }
+        }
+
+        if dao.ack_requested {
+            self.dao_ack_dst.set(src);
+            self.dao_ack_sequence.set(dao.sequence);
+            self.send_message(pending::DAO_ACK);
+        }
+        // Propagate the new routes towards the root
+        if self.state.get() == RplState::Joined {
+            self.schedule_dao(0);
+            self.schedule();
+        }
+    }
+
+    fn forward(&self, header: IP6Header, payload: &[u8]) {
+        if header.get_hop_limit() <= 1 || !self.is_joined() || self.busy.get() {
+            return;
+        }
+        let mut header = header;
+        header.set_hop_limit(header.get_hop_limit() - 1);
+        if self.ip_sender.forward(header, payload) == ReturnCode::SUCCESS {
+            self.busy.set(true);
+        }
+    }
+}
+
+impl<A: time::Alarm> time::Client for RplNode<'a, A> {
+    fn fired(&self) {
+        let now = self.alarm.now();
+        let passed = |deadline: &OptionalCell<u32>| {
+            let passed = deadline.map_or(false, |d| Self::has_passed(now, *d));
+            if passed {
+                deadline.clear();
+            }
+            passed
+        };
+
+        if passed(&self.dio_deadline) {
+            let redundancy = self.config.get().dio_redundancy;
+            if redundancy == 0 || self.trickle_counter.get() < redundancy {
+                self.send_message(pending::DIO);
+            }
+        }
+        if passed(&self.interval_deadline) {
+            let interval = (self.trickle_interval.get() * 2).min(self.trickle_imax());
+            self.trickle_interval.set(interval);
+            self.trickle_start_interval();
+        }
+        if passed(&self.dis_deadline) && self.state.get() == RplState::Detached {
+            self.send_message(pending::DIS);
+            self.dis_deadline.set(self.deadline_in(DIS_INTERVAL_MS));
+        }
+        if passed(&self.dao_deadline) {
+            self.expire_routes();
+            if self.state.get() == RplState::Joined {
+                self.send_message(pending::DAO);
+            }
+            if self.is_joined() {
+                self.dao_deadline.set(self.deadline_in(DAO_INTERVAL_MS));
+            }
+        }
+        self.schedule();
+    }
+}
+
+impl<A: time::Alarm> IP6SendClient for RplNode<'a, A> {
+    fn send_done(&self, _result: ReturnCode) {
+        self.busy.set(false);
+        self.send_pending();
+    }
+}
+
+impl<A: time::Alarm> IP6RecvClient for RplNode<'a, A> {
+    fn receive(&self, header: IP6Header, payload: &[u8]) {
+        let dst = header.get_dst_addr();
+        let next_header = header.get_next_header();
+        // Fragments of RPL messages are not reassembled, so they are handled
+        // like any other packet
+        let icmp_payload = match decode_chain(next_header, payload).done() {
+            Some((ext_len, ip6_nh::ICMP)) if !is_fragmented(next_header, payload) => {
+                Some(&payload[ext_len..])
+            }
+            _ => None,
+        };
+        if let Some(icmp_payload) = icmp_payload {
+            if let Some((off, icmp_header)) = ICMP6Header::decode(icmp_payload).done() {
+                if let ICMP6Type::Type155 = icmp_header.get_type() {
+                    if self.is_local_addr(&dst) {
+                        self.receive_rpl(&header, icmp_header.get_code(), &icmp_payload[off..]);
+                    }
+                    return;
+                }
+            }
+        }
+
+        if self.is_local_addr(&dst) {
+            self.client.map(|client| client.receive(header, payload));
+        } else if !dst.is_unicast_link_local() {
+            self.forward(header, payload);
+        }
+    }
+}
+
+impl<A: time::Alarm> NextHopLookup for RplNode<'a, A> {
+    fn next_hop(&self, dst: IPAddr) -> Option<MacAddress> {
+        if dst.is_multicast() {
+            Some(MacAddress::Short(0xffff))
+        } else if dst.is_unicast_link_local() {
+            Some(mac_from_iid(&dst))
+        } else {
+            self.lookup_route(&dst)
+                .or_else(|| self.preferred_parent.map(|parent| parent.mac))
+        }
+    }
+}
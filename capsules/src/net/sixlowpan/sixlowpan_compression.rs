@@ -1,6 +1,7 @@
 use crate::net::ieee802154::MacAddress;
 use crate::net::ipv6::ip_utils::{compute_udp_checksum, ip6_nh, IPAddr};
 use crate::net::ipv6::ipv6::{IP6Header, IP6Packet, TransportHeader};
+use crate::net::ipv6::ipv6_ext::{ip6_opt, is_ext_header, ExtHeader};
 use crate::net::udp::udp::UDPHeader;
 use crate::net::util;
 use crate::net::util::{slice_to_u16, u16_to_slice};
@@ -174,6 +175,33 @@ fn nhc_to_ip6_nh(nhc: u8) -> Result<u8, ()> {
     }
 }
 
+/// Maps an IPv6 extension header type to the EID of its LoWPAN_NHC header
+fn ip6_nh_to_eid(next_header: u8) -> u8 {
+    match next_header {
+        ip6_nh::HOP_OPTS => nhc::HOP_OPTS,
+        ip6_nh::ROUTING => nhc::ROUTING,
+        ip6_nh::FRAGMENT => nhc::FRAGMENT,
+        ip6_nh::DST_OPTS => nhc::DST_OPTS,
+        _ => nhc::MOBILITY,
+    }
+}
+
+/// Returns true if the header of type `next_header` following the IPv6
+/// header or an extension header of type `prev_header` can be compressed
+/// with LoWPAN_NHC. The UDP length is always elided by LoWPAN_NHC, so a UDP
+/// header is only compressed if its length can be inferred, which is not
+/// the case after an IPv6 Fragment header.
+fn is_nhc_compressible(ip6_packet: &IP6Packet, prev_header: u8, next_header: u8) -> bool {
+    if next_header == ip6_nh::UDP {
+        match ip6_packet.payload.header {
+            TransportHeader::UDP(_) => prev_header != ip6_nh::FRAGMENT,
+            _ => false,
+        }
+    } else {
+        is_ext_header(next_header)
+    }
+}
+
 /// Compresses an IPv6 header into a 6loWPAN header
 ///
 /// Constructs a 6LoWPAN header in `buf` from the given IPv6 datagram and
@@ -182,7 +210,8 @@ fn nhc_to_ip6_nh(nhc: u8) -> Result<u8, ()> {
 /// bytes consumed from the IPv6 datagram `written` is the number of
 /// compressed header bytes written into `buf`. Payload bytes and
 /// non-compressed next headers are not written, so the remaining `buf.len()
-/// - consumed` bytes must still be copied over to `buf`.
+/// - consumed` bytes must still be copied over to `buf`. Extension headers
+/// are compressed as long as the headers preceding them are.
 pub fn compress<'a>(
     ctx_store: &ContextStore,
     ip6_packet: &'a IP6Packet<'a>,
@@ -229,8 +258,8 @@ pub fn compress<'a>(
 
     // Next Header
 
-    //let (mut is_nhc, mut nh_len): (bool, u8) = is_ip6_nh_compressible(ip6_packet)?;
-    let is_nhc = ip6_header.next_header == ip6_nh::UDP;
+    let mut next_header = ip6_header.next_header;
+    let mut is_nhc = is_nhc_compressible(ip6_packet, ip6_nh::IP6, next_header);
     compress_nh(&ip6_header, is_nhc, &mut buf, &mut written);
 
     // Hop Limit
@@ -259,10 +288,22 @@ pub fn compress<'a>(
     }
 
     // Next Headers
-    // At each iteration, next_headers begins at the first byte of the
-    // current uncompressed next header.
-    // Since we aren't recursing, we only handle UDP
-    if is_nhc {
+    // At each iteration, next_header is the type of the current compressed
+    // next header. Extension headers are compressed until one of them is
+    // followed by a header that is not compressed, and UDP is always last.
+    let mut ext_headers = ip6_packet.ext_headers.iter();
+    while is_nhc {
+        if next_header != ip6_nh::UDP {
+            let ext_header = ext_headers.next().ok_or(())?;
+            if ext_header.get_type() != next_header {
+                return Err(());
+            }
+            next_header = ext_header.get_next_header();
+            is_nhc = is_nhc_compressible(ip6_packet, ext_header.get_type(), next_header);
+            compress_ext_header(&ext_header, is_nhc, &mut buf, &mut written)?;
+            consumed += ext_header.get_len();
+            continue;
+        }
         match ip6_packet.payload.header {
             TransportHeader::UDP(udp_header) => {
                 let mut nhc_header = nhc::DISPATCH_UDP;
@@ -278,6 +319,7 @@ pub fn compress<'a>(
                 // Write the UDP LoWPAN_NHC byte
                 buf[udp_nh_offset] = nhc_header;
                 consumed += 8;
+                break;
             }
             // Return an error, as there is a conflict between IPv6 next
             // header and actual IPv6 payload
@@ -287,6 +329,68 @@ pub fn compress<'a>(
     Ok((consumed, written))
 }
 
+/// Writes the LoWPAN_NHC encoding of an IPv6 extension header (RFC 6282,
+/// section 4.2). The next header field is elided if the following header is
+/// also compressed, and the length field is replaced by the number of bytes
+/// that follow it. A trailing Pad1 or PadN option in a Hop-by-Hop or
+/// Destination Options header is elided, as it can be recreated from the
+/// length.
+fn compress_ext_header(
+    ext_header: &ExtHeader,
+    next_is_nhc: bool,
+    buf: &mut [u8],
+    written: &mut usize,
+) -> Result<(), ()> {
+    let data = ext_header.get_data();
+    let data_len = match ext_header.get_type() {
+        ip6_nh::HOP_OPTS | ip6_nh::DST_OPTS => trailing_padding_offset(data),
+        _ => data.len(),
+    };
+    // The compressed length field is only 8 bits long
+    if data_len > 255 || *written + 3 + data_len > buf.len() {
+        return Err(());
+    }
+
+    let mut nhc_header = nhc::DISPATCH_NHC | ip6_nh_to_eid(ext_header.get_type());
+    if next_is_nhc {
+        nhc_header |= nhc::NH;
+    }
+    buf[*written] = nhc_header;
+    *written += 1;
+    if !next_is_nhc {
+        buf[*written] = ext_header.get_next_header();
+        *written += 1;
+    }
+    buf[*written] = data_len as u8;
+    *written += 1;
+    buf[*written..*written + data_len].copy_from_slice(&data[..data_len]);
+    *written += data_len;
+    Ok(())
+}
+
+/// Returns the offset of a padding option at the end of `options` that
+/// can be elided, or the length of `options` if there is none. Padding is
+/// only elided if it is shorter than 8 bytes, so that the decompressor
+/// recreates it when rounding the header up to a multiple of 8 bytes.
+fn trailing_padding_offset(options: &[u8]) -> usize {
+    let mut offset = 0;
+    while offset < options.len() {
+        let opt_len = if options[offset] == ip6_opt::PAD1 {
+            1
+        } else if offset + 1 < options.len() {
+            2 + options[offset + 1] as usize
+        } else {
+            return options.len();
+        };
+        let is_padding = options[offset] == ip6_opt::PAD1 || options[offset] == ip6_opt::PADN;
+        if offset + opt_len == options.len() && is_padding && opt_len < 8 {
+            return offset;
+        }
+        offset += opt_len;
+    }
+    options.len()
+}
+
 fn compress_cie(
     src_ctx: &Option<Context>,
     dst_ctx: &Option<Context>,
@@ -750,56 +854,63 @@ pub fn decompress(
                 // True if the next header is also compressed
                 is_nhc = (nhc_header & nhc::NH) != 0;
 
+                // If the next header is not compressed, its type is carried
+                // inline before the length field
+                let inline_next_header = if is_nhc {
+                    None
+                } else {
+                    let nh = *buf.get(consumed).ok_or(())?;
+                    consumed += 1;
+                    Some(nh)
+                };
+
                 // len is the number of octets following the length field
-                let len = buf[consumed] as usize;
+                let len = *buf.get(consumed).ok_or(())? as usize;
                 consumed += 1;
 
-                // Check that there is a next header in the buffer,
-                // which must be the case if the last next header specifies
-                // NH = 1
-                if consumed + len >= buf.len() {
+                // Check that the header is in the buffer, followed by the
+                // LoWPAN NHC header byte of the next header if NH = 1
+                if consumed + len > buf.len() || (is_nhc && consumed + len >= buf.len()) {
                     return Err(());
                 }
 
-                // Length in 8-octet units after the first 8 octets
-                // (per the IPv6 ext hdr spec)
-                let mut hdr_len_field = (len - 6) / 8;
-                if (len - 6) % 8 != 0 {
-                    hdr_len_field += 1;
+                // The uncompressed header is a multiple of 8 octets long,
+                // and its length field is in 8-octet units after the first
+                // 8 octets (per the IPv6 ext hdr spec)
+                let hdr_len = (len + 2 + 7) & !7;
+                if next_headers.len() < hdr_len {
+                    return Err(());
                 }
 
-                // Gets the type of the subsequent next header.  If is_nhc
+                // Gets the type of the subsequent next header. If is_nhc
                 // is true, there must be a LoWPAN NHC header byte,
-                // otherwise there is either an uncompressed next header.
-                next_header = if is_nhc {
-                    // The next header is LoWPAN NHC-compressed
-                    nhc_to_ip6_nh(buf[consumed + len])?
-                } else {
-                    // The next header is uncompressed
-                    buf[consumed + len]
+                // otherwise the next header was carried inline.
+                next_header = match inline_next_header {
+                    Some(nh) => nh,
+                    None => nhc_to_ip6_nh(buf[consumed + len])?,
                 };
 
                 // Fill in the extended header in uncompressed IPv6 format
                 next_headers[0] = next_header;
-                next_headers[1] = hdr_len_field as u8;
+                next_headers[1] = (hdr_len / 8 - 1) as u8;
                 // Copies over the remaining options.
                 next_headers[2..2 + len].copy_from_slice(&buf[consumed..consumed + len]);
 
-                // Fill in padding
-                let pad_bytes = hdr_len_field * 8 - len + 6;
+                // Fill in the padding that was elided from the options
+                let pad_bytes = hdr_len - len - 2;
                 if pad_bytes == 1 {
                     // Pad1
-                    next_headers[2 + len] = 0;
-                } else {
+                    next_headers[2 + len] = ip6_opt::PAD1;
+                } else if pad_bytes > 1 {
                     // PadN, 2 <= pad_bytes <= 7
-                    next_headers[2 + len] = 1;
+                    next_headers[2 + len] = ip6_opt::PADN;
                     next_headers[2 + len + 1] = pad_bytes as u8 - 2;
                     for i in 2..pad_bytes {
                         next_headers[2 + len + i] = 0;
                     }
                 }
 
-                written += 8 + hdr_len_field * 8;
+                written += hdr_len;
                 consumed += len;
             }
             _ => panic!("Unreachable case"),
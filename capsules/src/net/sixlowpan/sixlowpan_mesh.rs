@@ -0,0 +1,539 @@
+//! Implements the 6LoWPAN mesh addressing header and a mesh-under forwarding
+//! path, as defined in RFC 4944 (sections 5.2 and 11).
+//!
+//! With mesh-under routing, a 6LoWPAN datagram is carried across several
+//! 802.15.4 hops without being reassembled or decompressed at intermediate
+//! nodes. The mesh addressing header, which precedes any fragmentation and
+//! LoWPAN_IPHC headers, records the link-layer addresses of the node that
+//! originated the frame and of its final destination, as well as the number
+//! of hops the frame may still be forwarded.
+//!
+//! This module provides:
+//!
+//! - [MeshHeader](struct.MeshHeader.html), which encodes and decodes the
+//!   mesh addressing header.
+//! - [ForwardingTable](trait.ForwardingTable.html), the interface used to
+//!   look up the next hop for a final destination, together with a simple
+//!   [StaticForwardingTable](struct.StaticForwardingTable.html) that can be
+//!   filled in by the board or updated by a routing component.
+//! - [MeshForwarder](struct.MeshForwarder.html), which re-sends datagrams
+//!   destined for other nodes through a `MacDevice`.
+//!
+//! The `Sixlowpan` receive path reassembles datagrams for other nodes like
+//! its own, and hands them to the forwarder instead of its client. The
+//! forwarder then fragments them again with its own `TxState`, so that every
+//! fragment carries the mesh header with a decremented hop count and fits the
+//! MAC header towards the next hop.
+//!
+//! Usage
+//! -----
+//!
+//! The forwarder needs its own `MacUser` so that it can receive its own
+//! transmission callbacks, while received datagrams reach it through the
+//! `Sixlowpan` receive path:
+//!
+//! ```rust
+//! static mut MESH_ROUTES: [Option<MeshRoute>; 4] = [None; 4];
+//! static mut MESH_TX_BUF: [u8; radio::MAX_BUF_SIZE] = [0; radio::MAX_BUF_SIZE];
+//! static mut MESH_DGRAM: [u8; 1280] = [0; 1280];
+//!
+//! let mesh_table = static_init!(
+//!     StaticForwardingTable<'static>,
+//!     StaticForwardingTable::new(&mut MESH_ROUTES)
+//! );
+//! mesh_table.add_route(MacAddress::Short(0x1540), MacAddress::Short(0x1541));
+//!
+//! let mesh_mac = static_init!(
+//!     capsules::ieee802154::virtual_mac::MacUser<'static>,
+//!     capsules::ieee802154::virtual_mac::MacUser::new(mux_mac)
+//! );
+//! mux_mac.add_user(mesh_mac);
+//! let mesh_dgram = static_init!(
+//!     IP6Packet<'static>,
+//!     IP6Packet::new(IPPayload::new(
+//!         TransportHeader::UDP(UDPHeader::new()),
+//!         &mut MESH_DGRAM
+//!     ))
+//! );
+//! let mesh_forwarder = static_init!(
+//!     MeshForwarder<'static>,
+//!     MeshForwarder::new(
+//!         mesh_mac,
+//!         mesh_table,
+//!         TxState::new(sixlowpan_state),
+//!         mesh_dgram,
+//!         &mut MESH_TX_BUF
+//!     )
+//! );
+//! mesh_mac.set_transmit_client(mesh_forwarder);
+//! sixlowpan.set_mesh_forwarder(mesh_forwarder);
+//! ```
+
+// Known Limitations
+// -----------------
+// Datagrams are forwarded one at a time. If a datagram is reassembled while
+// the forwarder is still sending the previous one, it is dropped. Fragments
+// are sent back to back, without the delay that `IP6SendStruct` leaves
+// between them. Datagrams whose final destination is a broadcast address are
+// delivered locally and are not flooded, as doing so would require duplicate
+// suppression through the LOWPAN_BC0 header. Forwarded datagrams are sent
+// without link-layer security.
+
+use crate::ieee802154::device::{MacDevice, TxClient};
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ipv6::{IP6Header, IP6Packet};
+use crate::net::sixlowpan::sixlowpan_state::TxState;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::ReturnCode;
+
+pub mod lowpan_mesh {
+    pub const DISPATCH: u8 = 0b10000000;
+    pub const DISPATCH_MASK: u8 = 0b11000000;
+    pub const ORIG_SHORT: u8 = 0b00100000;
+    pub const FINAL_SHORT: u8 = 0b00010000;
+    pub const HOPS_LEFT_MASK: u8 = 0b00001111;
+    // A hops left value of 0xf indicates that the actual value is carried in
+    // the following byte ("Deep Hops Left", RFC 8025)
+    pub const HOPS_LEFT_EXTENDED: u8 = 0x0f;
+    pub const MAX_HDR_SIZE: usize = 2 + 8 + 8;
+
+    // The default number of hops for packets originated by this node
+    pub const DEFAULT_HOPS_LEFT: u8 = 14;
+}
+
+/// Returns true if the frame payload begins with a mesh addressing header
+pub fn is_mesh(packet: &[u8]) -> bool {
+    packet.len() > 0 && (packet[0] & lowpan_mesh::DISPATCH_MASK) == lowpan_mesh::DISPATCH
+}
+
+fn encode_mesh_addr(buf: &mut [u8], addr: MacAddress) -> SResult {
+    // Unlike the 802.15.4 MAC header, the mesh header carries addresses in
+    // network byte order
+    match addr {
+        MacAddress::Short(short_addr) => encode_u16(buf, short_addr),
+        MacAddress::Long(ref long_addr) => encode_bytes(buf, long_addr),
+    }
+}
+
+fn decode_mesh_addr(buf: &[u8], is_short: bool) -> SResult<MacAddress> {
+    if is_short {
+        let (off, short_addr) = dec_try!(buf; decode_u16);
+        stream_done!(off, MacAddress::Short(short_addr));
+    } else {
+        let mut long_addr = [0u8; 8];
+        let off = dec_consume!(buf; decode_bytes, &mut long_addr);
+        stream_done!(off, MacAddress::Long(long_addr));
+    }
+}
+
+/// The RFC 4944 mesh addressing header.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct MeshHeader {
+    pub hops_left: u8,
+    pub originator: MacAddress,
+    pub final_dst: MacAddress,
+}
+
+impl MeshHeader {
+    pub fn new(hops_left: u8, originator: MacAddress, final_dst: MacAddress) -> MeshHeader {
+        MeshHeader {
+            hops_left: hops_left,
+            originator: originator,
+            final_dst: final_dst,
+        }
+    }
+
+    /// Returns the number of bytes this header occupies when encoded
+    pub fn get_hdr_size(&self) -> usize {
+        let addr_size = |addr: MacAddress| match addr {
+            MacAddress::Short(_) => 2,
+            MacAddress::Long(_) => 8,
+        };
+        let hops_size = if self.hops_left >= lowpan_mesh::HOPS_LEFT_EXTENDED {
+            2
+        } else {
+            1
+        };
+        hops_size + addr_size(self.originator) + addr_size(self.final_dst)
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        stream_len_cond!(buf, self.get_hdr_size());
+
+        let mut dispatch = lowpan_mesh::DISPATCH;
+        if let MacAddress::Short(_) = self.originator {
+            dispatch |= lowpan_mesh::ORIG_SHORT;
+        }
+        if let MacAddress::Short(_) = self.final_dst {
+            dispatch |= lowpan_mesh::FINAL_SHORT;
+        }
+
+        let mut off = if self.hops_left >= lowpan_mesh::HOPS_LEFT_EXTENDED {
+            let dispatch = dispatch | lowpan_mesh::HOPS_LEFT_EXTENDED;
+            let off = enc_consume!(buf, 0; encode_u8, dispatch);
+            enc_consume!(buf, off; encode_u8, self.hops_left)
+        } else {
+            enc_consume!(buf, 0; encode_u8, dispatch | self.hops_left)
+        };
+        off = enc_consume!(buf, off; encode_mesh_addr, self.originator);
+        off = enc_consume!(buf, off; encode_mesh_addr, self.final_dst);
+        stream_done!(off, off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<MeshHeader> {
+        let (off, dispatch) = dec_try!(buf; decode_u8);
+        stream_cond!(dispatch & lowpan_mesh::DISPATCH_MASK == lowpan_mesh::DISPATCH);
+
+        let (off, hops_left) =
+            if dispatch & lowpan_mesh::HOPS_LEFT_MASK == lowpan_mesh::HOPS_LEFT_EXTENDED {
+                dec_try!(buf, off; decode_u8)
+            } else {
+                (off, dispatch & lowpan_mesh::HOPS_LEFT_MASK)
+            };
+        let orig_short = dispatch & lowpan_mesh::ORIG_SHORT != 0;
+        let final_short = dispatch & lowpan_mesh::FINAL_SHORT != 0;
+        let (off, originator) = dec_try!(buf, off; decode_mesh_addr, orig_short);
+        let (off, final_dst) = dec_try!(buf, off; decode_mesh_addr, final_short);
+        stream_done!(off, MeshHeader::new(hops_left, originator, final_dst));
+    }
+}
+
+/// A route from a final destination to the neighbor that frames for that
+/// destination should be sent to.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct MeshRoute {
+    pub final_dst: MacAddress,
+    pub next_hop: MacAddress,
+}
+
+/// Implemented by any component that can determine the next hop for a frame
+/// travelling to a given final destination.
+pub trait ForwardingTable {
+    /// Returns the link-layer address of the neighbor to forward frames for
+    /// `final_dst` to, or `None` if `final_dst` is unreachable.
+    fn next_hop(&self, final_dst: MacAddress) -> Option<MacAddress>;
+}
+
+/// A fixed-size forwarding table. Routes can be filled in statically by the
+/// board at initialization time, or updated at runtime by a routing
+/// component. Destinations without a route are sent to the default route,
+/// if one is set.
+pub struct StaticForwardingTable<'a> {
+    routes: TakeCell<'a, [Option<MeshRoute>]>,
+    default_route: OptionalCell<MacAddress>,
+}
+
+impl StaticForwardingTable<'a> {
+    pub fn new(routes: &'a mut [Option<MeshRoute>]) -> StaticForwardingTable<'a> {
+        StaticForwardingTable {
+            routes: TakeCell::new(routes),
+            default_route: OptionalCell::empty(),
+        }
+    }
+
+    /// Adds a route to `final_dst`, replacing any existing route to the same
+    /// destination. Returns `ENOMEM` if the table is full.
+    pub fn add_route(&self, final_dst: MacAddress, next_hop: MacAddress) -> ReturnCode {
+        self.routes
+            .map(|routes| {
+                let slot = routes
+                    .iter()
+                    .position(|route| route.map_or(false, |r| r.final_dst == final_dst))
+                    .or_else(|| routes.iter().position(|route| route.is_none()));
+                match slot {
+                    Some(index) => {
+                        routes[index] = Some(MeshRoute {
+                            final_dst: final_dst,
+                            next_hop: next_hop,
+                        });
+                        ReturnCode::SUCCESS
+                    }
+                    None => ReturnCode::ENOMEM,
+                }
+            })
+            .unwrap_or(ReturnCode::FAIL)
+    }
+
+    /// Removes the route to `final_dst`, if there is one
+    pub fn remove_route(&self, final_dst: MacAddress) {
+        self.routes.map(|routes| {
+            for route in routes.iter_mut() {
+                if route.map_or(false, |r| r.final_dst == final_dst) {
+                    *route = None;
+                }
+            }
+        });
+    }
+
+    /// Sets the neighbor that frames without a matching route are sent to
+    pub fn set_default_route(&self, next_hop: Option<MacAddress>) {
+        self.default_route.insert(next_hop);
+    }
+}
+
+impl ForwardingTable for StaticForwardingTable<'a> {
+    fn next_hop(&self, final_dst: MacAddress) -> Option<MacAddress> {
+        self.routes
+            .map(|routes| {
+                routes
+                    .iter()
+                    .filter_map(|route| *route)
+                    .find(|route| route.final_dst == final_dst)
+                    .map(|route| route.next_hop)
+            })
+            .unwrap_or(None)
+            .or_else(|| self.default_route.map(|next_hop| *next_hop))
+    }
+}
+
+/// Forwards 6LoWPAN datagrams whose final destination is another node.
+///
+/// The `Sixlowpan` receive path hands every datagram carrying a mesh
+/// addressing header to `MeshForwarder::forward` once it is reassembled,
+/// unless `is_local` reports that the final destination is this node.
+pub struct MeshForwarder<'a> {
+    radio: &'a MacDevice<'a>,
+    table: &'a ForwardingTable,
+    sixlowpan: TxState<'a>,
+    ip6_packet: TakeCell<'static, IP6Packet<'static>>,
+    tx_buf: TakeCell<'static, [u8]>,
+}
+
+impl MeshForwarder<'a> {
+    pub fn new(
+        radio: &'a MacDevice<'a>,
+        table: &'a ForwardingTable,
+        sixlowpan: TxState<'a>,
+        ip6_packet: &'static mut IP6Packet<'static>,
+        tx_buf: &'static mut [u8],
+    ) -> MeshForwarder<'a> {
+        MeshForwarder {
+            radio: radio,
+            table: table,
+            sixlowpan: sixlowpan,
+            ip6_packet: TakeCell::new(ip6_packet),
+            tx_buf: TakeCell::new(tx_buf),
+        }
+    }
+
+    /// Returns true if `addr` refers to this node, or is the broadcast address
+    pub fn is_local(&self, addr: MacAddress) -> bool {
+        match addr {
+            MacAddress::Short(short_addr) => {
+                short_addr == 0xffff || short_addr == self.radio.get_address()
+            }
+            MacAddress::Long(long_addr) => long_addr == self.radio.get_address_long(),
+        }
+    }
+
+    /// Returns the first hop for a frame to `final_dst`, if it is reachable.
+    pub fn next_hop(&self, final_dst: MacAddress) -> Option<MacAddress> {
+        self.table.next_hop(final_dst)
+    }
+
+    /// Sends a reassembled datagram on towards its final destination, with a
+    /// decremented hop count. `packet` holds the uncompressed IPv6 packet that
+    /// arrived with `mesh_header`, which is compressed and fragmented again
+    /// for the next hop. Returns `EBUSY` while a previous datagram is still
+    /// being sent.
+    pub fn forward(&self, mesh_header: MeshHeader, packet: &[u8]) -> ReturnCode {
+        // A datagram that arrives with one hop left cannot be forwarded further
+        if mesh_header.hops_left <= 1 {
+            return ReturnCode::FAIL;
+        }
+        let next_hop = match self.table.next_hop(mesh_header.final_dst) {
+            Some(next_hop) => next_hop,
+            None => return ReturnCode::EINVAL,
+        };
+        // The radio holds the buffer while a datagram is being sent
+        if self.tx_buf.is_none() {
+            return ReturnCode::EBUSY;
+        }
+        let (offset, ip6_header) = match IP6Header::decode(packet).done() {
+            Some(result) => result,
+            None => return ReturnCode::EINVAL,
+        };
+        let result = self.ip6_packet.map_or(ReturnCode::ENOMEM, |ip6_packet| {
+            ip6_packet.set_received(ip6_header, &packet[offset..])
+        });
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+
+        // Use the same addressing mode for ourselves as for the next hop
+        let src_addr = match next_hop {
+            MacAddress::Short(_) => MacAddress::Short(self.radio.get_address()),
+            MacAddress::Long(_) => MacAddress::Long(self.radio.get_address_long()),
+        };
+        let result = self
+            .sixlowpan
+            .init(src_addr, next_hop, self.radio.get_pan(), None);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        self.sixlowpan.set_mesh_header(Some(MeshHeader {
+            hops_left: mesh_header.hops_left - 1,
+            ..mesh_header
+        }));
+        self.send_next_fragment()
+    }
+
+    // Sends the next fragment of the datagram being forwarded. Returns
+    // SUCCESS without sending anything once the last fragment has been sent.
+    fn send_next_fragment(&self) -> ReturnCode {
+        let next_frame = self
+            .ip6_packet
+            .map(|ip6_packet| {
+                self.tx_buf
+                    .take()
+                    .map(|tx_buf| self.sixlowpan.next_fragment(ip6_packet, tx_buf, self.radio))
+            })
+            .unwrap_or(None);
+        let result = match next_frame {
+            None => return ReturnCode::EBUSY,
+            Some(Ok((true, frame))) => {
+                self.tx_buf.replace(frame.into_buf());
+                return ReturnCode::SUCCESS;
+            }
+            Some(Ok((false, frame))) => match self.radio.transmit(frame) {
+                (ReturnCode::SUCCESS, _) => return ReturnCode::SUCCESS,
+                (result, tx_buf) => {
+                    tx_buf.map(|tx_buf| self.tx_buf.replace(tx_buf));
+                    result
+                }
+            },
+            Some(Err((result, tx_buf))) => {
+                self.tx_buf.replace(tx_buf);
+                result
+            }
+        };
+        // The rest of the datagram is dropped, so the next one starts afresh
+        self.sixlowpan.cancel();
+        result
+    }
+}
+
+impl TxClient for MeshForwarder<'a> {
+    fn send_done(&self, tx_buf: &'static mut [u8], _acked: bool, _result: ReturnCode) {
+        self.tx_buf.replace(tx_buf);
+        self.send_next_fragment();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG_A: [u8; 8] = [0x02, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77];
+    const LONG_B: [u8; 8] = [0x02, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee];
+
+    fn round_trip(header: MeshHeader, expected: &[u8]) {
+        let mut buf = [0; lowpan_mesh::MAX_HDR_SIZE + 2];
+        assert_eq!(header.get_hdr_size(), expected.len());
+        assert_eq!(
+            header.encode(&mut buf).done(),
+            Some((expected.len(), expected.len()))
+        );
+        assert_eq!(&buf[..expected.len()], expected);
+        assert!(is_mesh(&buf));
+        assert_eq!(
+            MeshHeader::decode(&buf[..expected.len()]).done(),
+            Some((expected.len(), header))
+        );
+    }
+
+    #[test]
+    fn short_addresses_round_trip() {
+        let header = MeshHeader::new(5, MacAddress::Short(0x1234), MacAddress::Short(0xabcd));
+        round_trip(header, &[0b1011_0101, 0x12, 0x34, 0xab, 0xcd]);
+    }
+
+    #[test]
+    fn long_addresses_round_trip() {
+        let header = MeshHeader::new(14, MacAddress::Long(LONG_A), MacAddress::Long(LONG_B));
+        let mut expected = [0; 17];
+        expected[0] = 0b1000_1110;
+        expected[1..9].copy_from_slice(&LONG_A);
+        expected[9..].copy_from_slice(&LONG_B);
+        round_trip(header, &expected);
+    }
+
+    #[test]
+    fn mixed_addresses_round_trip() {
+        let header = MeshHeader::new(1, MacAddress::Long(LONG_A), MacAddress::Short(0x0042));
+        let mut expected = [0; 11];
+        expected[0] = 0b1001_0001;
+        expected[1..9].copy_from_slice(&LONG_A);
+        expected[9..].copy_from_slice(&[0x00, 0x42]);
+        round_trip(header, &expected);
+
+        let header = MeshHeader::new(2, MacAddress::Short(0x0042), MacAddress::Long(LONG_B));
+        let mut expected = [0; 11];
+        expected[0] = 0b1010_0010;
+        expected[1..3].copy_from_slice(&[0x00, 0x42]);
+        expected[3..].copy_from_slice(&LONG_B);
+        round_trip(header, &expected);
+    }
+
+    #[test]
+    fn large_hop_counts_use_the_extension_byte() {
+        // 14 still fits in the dispatch byte, 15 and up do not
+        let header = MeshHeader::new(15, MacAddress::Short(1), MacAddress::Short(2));
+        round_trip(header, &[0b1011_1111, 15, 0, 1, 0, 2]);
+        let header = MeshHeader::new(200, MacAddress::Short(1), MacAddress::Long(LONG_B));
+        let mut expected = [0; 12];
+        expected[0..4].copy_from_slice(&[0b1010_1111, 200, 0, 1]);
+        expected[4..].copy_from_slice(&LONG_B);
+        round_trip(header, &expected);
+        assert_eq!(
+            MeshHeader::new(200, MacAddress::Long(LONG_A), MacAddress::Long(LONG_B)).get_hdr_size(),
+            lowpan_mesh::MAX_HDR_SIZE
+        );
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        // A LOWPAN_IPHC dispatch, and a header cut short in each field
+        assert!(!is_mesh(&[0b0110_0000]));
+        assert_eq!(MeshHeader::decode(&[0b0110_0000, 0, 1, 0, 2]).done(), None);
+        assert_eq!(MeshHeader::decode(&[]).done(), None);
+        assert_eq!(MeshHeader::decode(&[0b1011_1111]).done(), None);
+        assert_eq!(
+            MeshHeader::decode(&[0b1011_0101, 0x12, 0x34, 0xab]).done(),
+            None
+        );
+        assert_eq!(MeshHeader::decode(&[0b1000_0101, 0x12, 0x34]).done(), None);
+
+        let header = MeshHeader::new(15, MacAddress::Short(1), MacAddress::Short(2));
+        assert_eq!(header.encode(&mut [0; 5]).done(), None);
+    }
+
+    #[test]
+    fn forwarding_table_replaces_routes_and_falls_back_to_the_default() {
+        let mut routes = [None; 2];
+        let table = StaticForwardingTable::new(&mut routes);
+        let (a, b, c) = (
+            MacAddress::Short(0xa),
+            MacAddress::Short(0xb),
+            MacAddress::Short(0xc),
+        );
+        assert_eq!(table.next_hop(a), None);
+
+        assert_eq!(table.add_route(a, b), ReturnCode::SUCCESS);
+        assert_eq!(table.add_route(a, c), ReturnCode::SUCCESS);
+        assert_eq!(table.add_route(b, b), ReturnCode::SUCCESS);
+        assert_eq!(table.add_route(c, c), ReturnCode::ENOMEM);
+        assert_eq!(table.next_hop(a), Some(c));
+        assert_eq!(table.next_hop(c), None);
+
+        table.set_default_route(Some(b));
+        assert_eq!(table.next_hop(c), Some(b));
+        table.remove_route(a);
+        assert_eq!(table.next_hop(a), Some(b));
+        assert_eq!(table.add_route(c, a), ReturnCode::SUCCESS);
+        assert_eq!(table.next_hop(c), Some(a));
+    }
+}
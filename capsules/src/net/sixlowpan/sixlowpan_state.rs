@@ -97,6 +97,19 @@
 //!            +---------+
 //! ```
 //!
+//! Mesh-under Forwarding
+//! ---------------------
+//! Frames that begin with an RFC 4944 mesh addressing header are handled
+//! according to their final destination. If a
+//! [MeshForwarder](../sixlowpan_mesh/struct.MeshForwarder.html) has been set
+//! with `Sixlowpan.set_mesh_forwarder` and the final destination is another
+//! node, the datagram is reassembled and handed to the forwarder rather than
+//! the client, and the forwarder fragments it again towards the next hop. In
+//! either case, the mesh header is stripped and the originator and final destination
+//! addresses take the place of the MAC addresses for decompression and
+//! reassembly. On the transmit path, `TxState.set_mesh_header` makes every
+//! fragment of the current packet carry a mesh header.
+//!
 //! Examples
 //! -----
 //! Examples of how to interface and use this layer are included in the file
@@ -227,14 +240,16 @@
 use crate::ieee802154::device::{MacDevice, RxClient};
 use crate::ieee802154::framer::Frame;
 use crate::net::frag_utils::Bitmap;
-use crate::net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel};
+use crate::net::ieee802154::{FrameType, Header, KeyId, MacAddress, PanID, SecurityLevel};
 use crate::net::ipv6::ipv6::IP6Packet;
+use crate::net::ipv6::ipv6_ext::MAX_EXT_HDRS_LEN;
 use crate::net::sixlowpan::sixlowpan_compression;
 use crate::net::sixlowpan::sixlowpan_compression::{is_lowpan, ContextStore};
+use crate::net::sixlowpan::sixlowpan_mesh::{is_mesh, lowpan_mesh, MeshForwarder, MeshHeader};
 use crate::net::util::{slice_to_u16, u16_to_slice};
 use core::cell::Cell;
 use core::cmp::min;
-use kernel::common::cells::{MapCell, TakeCell};
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::common::list::{List, ListLink, ListNode};
 use kernel::hil::radio;
 use kernel::hil::time;
@@ -317,6 +332,7 @@ pub struct TxState<'a> {
     src_mac_addr: Cell<MacAddress>,
     dst_mac_addr: Cell<MacAddress>,
     security: Cell<Option<(SecurityLevel, KeyId)>>,
+    mesh_header: Cell<Option<MeshHeader>>,
     dgram_tag: Cell<u16>, // Used to identify particular fragment streams
     dgram_size: Cell<u16>,
     dgram_offset: Cell<usize>,
@@ -342,6 +358,7 @@ impl TxState<'a> {
             src_mac_addr: Cell::new(MacAddress::Short(0)),
             dst_mac_addr: Cell::new(MacAddress::Short(0)),
             security: Cell::new(None),
+            mesh_header: Cell::new(None),
 
             // Internal fields
             dgram_tag: Cell::new(0),
@@ -382,6 +399,7 @@ impl TxState<'a> {
             self.src_mac_addr.set(src_mac_addr);
             self.dst_mac_addr.set(dst_mac_addr);
             self.security.set(security);
+            self.mesh_header.set(None);
             self.busy.set(false);
             self.src_pan.set(radio_pan);
             self.dst_pan.set(radio_pan);
@@ -389,6 +407,41 @@ impl TxState<'a> {
         }
     }
 
+    /// Sets the mesh addressing header that is prepended to every fragment of
+    /// the packet being sent. This must be called after `init` and before the
+    /// first call to `next_fragment`; `init` clears any previous mesh header.
+    ///
+    /// # Arguments
+    ///
+    /// `mesh_header` - The mesh header to send, or `None` to send frames
+    /// directly to the MAC destination. When present, its originator and final
+    /// destination addresses are used for header compression in place of the
+    /// MAC addresses, as required by RFC 4944.
+    pub fn set_mesh_header(&self, mesh_header: Option<MeshHeader>) -> ReturnCode {
+        if self.busy.get() {
+            ReturnCode::EBUSY
+        } else {
+            self.mesh_header.set(mesh_header);
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Abandons the packet being sent, so that `init` can start a new one.
+    /// This is needed if a fragment could not be sent, as only producing the
+    /// last fragment ends the transmission otherwise.
+    pub fn cancel(&self) {
+        self.end_transmit();
+    }
+
+    // Returns the (source, destination) link-layer addresses that the IPv6
+    // addresses are compressed against
+    fn lowpan_addrs(&self) -> (MacAddress, MacAddress) {
+        match self.mesh_header.get() {
+            Some(mesh_header) => (mesh_header.originator, mesh_header.final_dst),
+            None => (self.src_mac_addr.get(), self.dst_mac_addr.get()),
+        }
+    }
+
     /// Gets the next 6LoWPAN Fragment (as a MAC frame) to be sent. Note that
     /// this layer **does not** send the frame, and assumes that `init` has
     /// already been called.
@@ -426,9 +479,17 @@ impl TxState<'a> {
             )
             .map_err(|frame| (ReturnCode::FAIL, frame))?;
 
-        // If this is the first fragment
+        // A fragment that cannot be built, whether it is the first or a later
+        // one, ends the whole transmission, so the next packet starts from a
+        // clean state.
         if !self.busy.get() {
-            let frame = self.start_transmit(ip6_packet, frame, self.sixlowpan.get_ctx_store())?;
+            // This is the first fragment
+            let frame = self
+                .start_transmit(ip6_packet, frame, self.sixlowpan.get_ctx_store())
+                .map_err(|error| {
+                    self.end_transmit();
+                    error
+                })?;
             Ok((false, frame))
         } else if self.is_transmit_done() {
             self.end_transmit();
@@ -441,7 +502,12 @@ impl TxState<'a> {
                 return Err((ReturnCode::ENOMEM, frame.into_buf()));
             }
 
-            let frame = self.prepare_next_fragment(ip6_packet, frame)?;
+            let frame = self
+                .prepare_next_fragment(ip6_packet, frame)
+                .map_err(|error| {
+                    self.end_transmit();
+                    error
+                })?;
             Ok((false, frame))
         }
     }
@@ -473,12 +539,13 @@ impl TxState<'a> {
         // Here, we assume that the compressed headers fit in the first MTU
         // fragment. This is consistent with RFC 6282.
         let mut lowpan_packet = [0 as u8; radio::MAX_FRAME_SIZE as usize];
+        let (src_mac_addr, dst_mac_addr) = self.lowpan_addrs();
         let (consumed, written) = {
             match sixlowpan_compression::compress(
                 ctx_store,
                 ip6_packet,
-                self.src_mac_addr.get(),
-                self.dst_mac_addr.get(),
+                src_mac_addr,
+                dst_mac_addr,
                 &mut lowpan_packet,
             ) {
                 Err(_) => return Err((ReturnCode::FAIL, frame.into_buf())),
@@ -492,6 +559,10 @@ impl TxState<'a> {
         // TODO: This -2 is added to account for the FCS; this should be changed
         // in the MAC code
         let mut remaining_capacity = frame.remaining_data_capacity() - 2;
+        match self.write_mesh_hdr(&mut frame) {
+            Ok(len) => remaining_capacity -= len,
+            Err(error) => return Err((error, frame.into_buf())),
+        }
 
         // Need to fragment
         if lowpan_len > remaining_capacity {
@@ -530,6 +601,10 @@ impl TxState<'a> {
     ) -> Result<Frame, (ReturnCode, &'static mut [u8])> {
         let dgram_offset = self.dgram_offset.get();
         let mut remaining_capacity = frame.remaining_data_capacity();
+        match self.write_mesh_hdr(&mut frame) {
+            Ok(len) => remaining_capacity -= len,
+            Err(error) => return Err((error, frame.into_buf())),
+        }
         remaining_capacity -= self.write_frag_hdr(&mut frame, false);
 
         // This rounds payload_len down to the nearest multiple of 8 if it
@@ -574,7 +649,7 @@ impl TxState<'a> {
             // statically allocate room on the stack. However, we do not know
             // how many additional headers we have until runtime. This
             // functionality should be fixed in the future.
-            let mut headers = [0 as u8; 60];
+            let mut headers = [0 as u8; 60 + MAX_EXT_HDRS_LEN];
             ip6_packet.encode(&mut headers);
             frame.append_payload(&mut headers[dgram_offset..dgram_offset + headers_to_write]);
             payload_len -= headers_to_write;
@@ -583,6 +658,21 @@ impl TxState<'a> {
         (payload_len, dgram_offset)
     }
 
+    // The mesh header must precede the fragmentation header (RFC 4944).
+    // Returns the length written, or an error if the header does not fit.
+    fn write_mesh_hdr(&self, frame: &mut Frame) -> Result<usize, ReturnCode> {
+        self.mesh_header.get().map_or(Ok(0), |mesh_header| {
+            let mut mesh_hdr = [0 as u8; lowpan_mesh::MAX_HDR_SIZE];
+            match mesh_header.encode(&mut mesh_hdr).done() {
+                Some((_, len)) => match frame.append_payload(&mesh_hdr[..len]) {
+                    ReturnCode::SUCCESS => Ok(len),
+                    error => Err(error),
+                },
+                None => Err(ReturnCode::FAIL),
+            }
+        })
+    }
+
     fn write_frag_hdr(&self, frame: &mut Frame, first_frag: bool) -> usize {
         if first_frag {
             let mut frag_header = [0 as u8; lowpan_frag::FRAG1_HDR_SIZE];
@@ -754,9 +844,7 @@ impl RxState<'a> {
     }
 
     fn end_receive(&self, client: Option<&'a SixlowpanRxClient>, result: ReturnCode) {
-        self.busy.set(false);
-        self.bitmap.map(|bitmap| bitmap.clear());
-        self.start_time.set(0);
+        self.clear();
         client.map(move |client| {
             // Since packet is borrowed from the upper layer, failing to return it
             // in the callback represents a significant error that should never
@@ -769,6 +857,23 @@ impl RxState<'a> {
                 .expect("Error: `packet` is None in call to end_receive.");
         });
     }
+
+    // Like `end_receive`, but hands a reassembled packet to `forwarder`
+    // instead of the client, to be sent on towards its final destination.
+    fn end_forward(&self, forwarder: &MeshForwarder, mesh_header: MeshHeader, result: ReturnCode) {
+        self.clear();
+        if result == ReturnCode::SUCCESS {
+            self.packet.map(|packet| {
+                forwarder.forward(mesh_header, &packet[..self.dgram_size.get() as usize])
+            });
+        }
+    }
+
+    fn clear(&self) {
+        self.busy.set(false);
+        self.bitmap.map(|bitmap| bitmap.clear());
+        self.start_time.set(0);
+    }
 }
 
 /// Sends a receives IPv6 packets via 6loWPAN compression and fragmentation.
@@ -788,6 +893,7 @@ pub struct Sixlowpan<'a, A: time::Alarm, C: ContextStore> {
     clock: &'a A,
     tx_dgram_tag: Cell<u16>,
     rx_client: Cell<Option<&'a SixlowpanRxClient>>,
+    mesh_forwarder: OptionalCell<&'a MeshForwarder<'a>>,
 
     // Receive state
     rx_states: List<'a, RxState<'a>>,
@@ -795,23 +901,58 @@ pub struct Sixlowpan<'a, A: time::Alarm, C: ContextStore> {
 
 // This function is called after receiving a frame
 impl<A: time::Alarm, C: ContextStore> RxClient for Sixlowpan<'a, A, C> {
-    fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        data_offset: usize,
+        data_len: usize,
+        _quality: radio::LinkQuality,
+    ) {
+        // Beacons and MAC commands are received by every user of the MAC,
+        // but only data frames carry 6LoWPAN packets
+        if header.frame_type != FrameType::Data || data_len == 0 {
+            return;
+        }
         // We return if retcode is not valid, as it does not make sense to issue
         // a callback for an invalid frame reception
         // TODO: Handle the case where the addresses are None/elided - they
         // should not default to the zero address
-        let src_mac_addr = header.src_addr.unwrap_or(MacAddress::Short(0));
-        let dst_mac_addr = header.dst_addr.unwrap_or(MacAddress::Short(0));
-
-        let (rx_state, returncode) = self.receive_frame(
-            &buf[data_offset..data_offset + data_len],
-            data_len,
-            src_mac_addr,
-            dst_mac_addr,
-        );
+        let mut src_mac_addr = header.src_addr.unwrap_or(MacAddress::Short(0));
+        let mut dst_mac_addr = header.dst_addr.unwrap_or(MacAddress::Short(0));
+        let mut payload = &buf[data_offset..data_offset + data_len];
+        let mut forward_header = None;
+
+        if is_mesh(payload) {
+            let (offset, mesh_header) = match MeshHeader::decode(payload).done() {
+                Some(result) => result,
+                None => return,
+            };
+            // When a mesh header is present, its addresses are used in place
+            // of the MAC addresses (RFC 4944, section 11)
+            src_mac_addr = mesh_header.originator;
+            dst_mac_addr = mesh_header.final_dst;
+            payload = &payload[offset..];
+            // Datagrams for other nodes are reassembled like our own, but
+            // then go to the forwarder
+            if self.mesh_forwarder.map_or(false, |forwarder| {
+                !forwarder.is_local(mesh_header.final_dst)
+            }) {
+                forward_header = Some(mesh_header);
+            }
+        }
+
+        let (rx_state, returncode) =
+            self.receive_frame(payload, payload.len(), src_mac_addr, dst_mac_addr);
         // Reception completed if rx_state is not None. Note that this can
         // also occur for some fail states (e.g. dropping an invalid packet)
-        rx_state.map(|state| state.end_receive(self.rx_client.get(), returncode));
+        rx_state.map(|state| match forward_header {
+            Some(mesh_header) => {
+                self.mesh_forwarder
+                    .map(|forwarder| state.end_forward(forwarder, mesh_header, returncode));
+            }
+            None => state.end_receive(self.rx_client.get(), returncode),
+        });
     }
 }
 
@@ -866,11 +1007,20 @@ impl<A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
             clock: clock,
             tx_dgram_tag: Cell::new(0),
             rx_client: Cell::new(None),
+            mesh_forwarder: OptionalCell::empty(),
 
             rx_states: List::new(),
         }
     }
 
+    /// Sets the [MeshForwarder](../sixlowpan_mesh/struct.MeshForwarder.html)
+    /// that relays received datagrams whose mesh header names another node as
+    /// the final destination. Without a forwarder, all frames carrying a mesh
+    /// header are treated as destined for this node.
+    pub fn set_mesh_forwarder(&self, forwarder: &'a MeshForwarder<'a>) {
+        self.mesh_forwarder.set(forwarder);
+    }
+
     fn receive_frame(
         &self,
         packet: &[u8],
@@ -0,0 +1,963 @@
+//! Implements the Mesh Link Establishment (MLE) attach procedure of a
+//! Sleepy End Device (SED), as described in Chapter 4 of the Thread 1.1.1
+//! Specification. MLE messages are carried in UDP datagrams on port 19788,
+//! and consist of a command type followed by a series of TLV parameters (see
+//! the [tlv](../tlv/index.html) module).
+//!
+//! MLE for network attaching comprises a four-step handshake that works
+//! as follows:
+//!
+//!     1. A child device multicasts a Parent Request MLE command.
+//!     2. Each potential parent device on the network unicasts a Parent
+//!        Response MLE command.
+//!     3. The child device selects a parent based on a hierarchy of
+//!        connectivity metrics and unicasts a Child ID Request MLE
+//!        command.
+//!     4. The selected parent unicasts a Child ID Response MLE command.
+//!
+//! The Parent Request is first sent to routers only. If no router responds
+//! within 750 ms, it is sent again to routers and router-eligible end devices.
+//! Once attached, the device periodically sends an IEEE 802.15.4 Data Request
+//! command to its parent to poll for buffered frames. If several consecutive
+//! polls are not acknowledged, the device detaches and attaches again.
+//!
+//! All MLE messages are secured with AES-CCM using the MLE key, as described
+//! in Section 7.2 of the specification. The message is laid out as follows,
+//! where the command and TLVs are encrypted, and the IPv6 source and
+//! destination addresses and the auxiliary security header are
+//! authenticated:
+//!
+//! ```text
+//! [ Security suite (0) | Aux security header | Command | TLVs | MIC (4) ]
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let mle = static_init!(
+//!     Mle<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     Mle::new(
+//!         mle_alarm,
+//!         udp_send,
+//!         mle_mac,
+//!         aes_ccm,
+//!         &mut MLE_CRYPT_BUF,
+//!         &mut MLE_POLL_BUF,
+//!     )
+//! );
+//! mle_alarm.set_client(mle);
+//! udp_send.set_client(mle);
+//! mle_mac.set_transmit_client(mle);
+//! aes_ccm.set_client(mle);
+//! udp_port_table.bind_kernel(mle, None, MLE_PORT);
+//! mle.set_key(key_sequence, mle_key);
+//! mle.start();
+//! ```
+//!
+//! The IPv6 sender beneath `udp_send` must use the link-local address
+//! derived from the device's extended address, which is returned by
+//! `get_link_local_addr`, as MLE messages are authenticated with it.
+
+// Known Limitations
+// -----------------
+// - The MLE key must be derived from the Thread master key
+//   (HMAC-SHA256(master key, key sequence || "Thread")) by the caller, as
+//   there is no SHA-256 implementation available to the kernel. Key rotation
+//   is not supported: messages secured with another key sequence are dropped.
+// - The Address Registration TLV is not implemented, so the parent does not
+//   learn this device's global addresses.
+// - Child Update Requests are not sent, so the network data is not refreshed
+//   after attaching.
+// - The radio is left on between data polls.
+
+use crate::ieee802154::device::{MacDevice, TxClient};
+use crate::net::ieee802154::{MacAddress, MacCommand};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::SResult;
+use crate::net::thread::tlv::{LinkMode, MulticastResponder, Tlv, TlvType};
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM, CCM_NONCE_LENGTH};
+use kernel::hil::time::{self, Frequency};
+use kernel::ReturnCode;
+
+/// The UDP port on which MLE messages are sent and received
+pub const MLE_PORT: u16 = 19788;
+
+/// MLE command types
+pub mod mle_command {
+    pub const LINK_REQUEST: u8 = 0;
+    pub const LINK_ACCEPT: u8 = 1;
+    pub const LINK_ACCEPT_AND_REQUEST: u8 = 2;
+    pub const LINK_REJECT: u8 = 3;
+    pub const ADVERTISEMENT: u8 = 4;
+    pub const UPDATE: u8 = 5;
+    pub const UPDATE_REQUEST: u8 = 6;
+    pub const DATA_REQUEST: u8 = 7;
+    pub const DATA_RESPONSE: u8 = 8;
+    pub const PARENT_REQUEST: u8 = 9;
+    pub const PARENT_RESPONSE: u8 = 10;
+    pub const CHILD_ID_REQUEST: u8 = 11;
+    pub const CHILD_ID_RESPONSE: u8 = 12;
+    pub const CHILD_UPDATE_REQUEST: u8 = 13;
+    pub const CHILD_UPDATE_RESPONSE: u8 = 14;
+}
+
+// The link-local scope all-routers multicast address, ff02::2
+const ALL_ROUTERS: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02]);
+
+const SECURITY_SUITE_154: u8 = 0;
+// Security level 5 (ENC-MIC-32) with key identifier mode 2
+const SECURITY_CONTROL: u8 = 0x15;
+const SECURITY_LEVEL: u8 = 5;
+const AUX_HDR_LEN: usize = 10;
+const MIC_LEN: usize = 4;
+
+// Layout of the crypt buffer: the IPv6 addresses and auxiliary security
+// header (authenticated) are followed by the command and TLVs (encrypted).
+// Once the message is secured, the security suite byte is written to
+// `buf[AUX_HDR_OFFSET - 1]`, overwriting the last byte of the destination
+// address, so that the UDP payload is `buf[AUX_HDR_OFFSET - 1..]`.
+const ADDRS_OFFSET: usize = 1;
+const AUX_HDR_OFFSET: usize = ADDRS_OFFSET + 32;
+const MSG_OFFSET: usize = AUX_HDR_OFFSET + AUX_HDR_LEN;
+
+/// The Thread version advertised in the Version TLV
+pub const THREAD_VERSION: u16 = 2;
+/// The child timeout requested from the parent, in seconds
+pub const CHILD_TIMEOUT_S: u32 = 240;
+/// The default interval between data polls, in milliseconds
+pub const DEFAULT_POLL_PERIOD_MS: u32 = 4_000;
+
+const PARENT_REQUEST_ROUTER_TIMEOUT_MS: u32 = 750;
+const PARENT_REQUEST_REED_TIMEOUT_MS: u32 = 1_250;
+const CHILD_ID_RESPONSE_TIMEOUT_MS: u32 = 1_250;
+const ATTACH_BACKOFF_MS: u32 = 5_000;
+const MAX_ATTACH_ATTEMPTS: u8 = 3;
+const MAX_POLL_FAILURES: u8 = 4;
+
+/// The client of `Mle` is notified when the device attaches to or detaches
+/// from a parent.
+pub trait MleClient {
+    /// Called with `SUCCESS` once the device has attached to a parent, or
+    /// with `FAIL` if no parent could be found after several attempts.
+    fn attach_done(&self, result: ReturnCode);
+
+    /// Called when the device loses the connection to its parent. The device
+    /// then automatically attempts to attach again.
+    fn detached(&self);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum MleState {
+    Disabled,
+    ParentRequestRouters,
+    ParentRequestReeds,
+    ChildIdRequest,
+    AttachBackoff,
+    Attached,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum CryptOp {
+    Idle,
+    Encrypt,
+    Decrypt,
+}
+
+/// A router that answered the Parent Request
+#[derive(Copy, Clone, Debug)]
+struct ParentCandidate {
+    addr: IPAddr,
+    rloc16: u16,
+    challenge: [u8; 8],
+    link_quality: u8,
+    priority: u8,
+    link_quality_3: u8,
+    frame_counter: u32,
+}
+
+impl ParentCandidate {
+    // Parents are compared by two-way link quality, then by the priority
+    // they advertise, then by their number of high quality links
+    fn is_better_than(&self, other: &ParentCandidate) -> bool {
+        (self.link_quality, self.priority, self.link_quality_3)
+            > (other.link_quality, other.priority, other.link_quality_3)
+    }
+}
+
+// Converts the Link Margin TLV value (in dB) into a link quality
+// (Section 4.4.1.1.1)
+fn link_quality_from_margin(margin: u8) -> u8 {
+    if margin > 20 {
+        3
+    } else if margin > 10 {
+        2
+    } else if margin > 2 {
+        1
+    } else {
+        0
+    }
+}
+
+// Converts the parent priority field of the Connectivity TLV into a value
+// that orders High > Medium > Low
+fn priority_rank(parent_priority: u8) -> u8 {
+    match parent_priority >> 6 {
+        0b01 => 2,
+        0b00 => 1,
+        _ => 0,
+    }
+}
+
+// Recovers the extended address of a neighbor from the interface
+// identifier of its link-local address
+fn ext_addr_from_iid(addr: &IPAddr) -> [u8; 8] {
+    let mut ext_addr = [0; 8];
+    ext_addr.copy_from_slice(&addr.0[8..16]);
+    ext_addr[0] ^= 0b00000010;
+    ext_addr
+}
+
+fn get_ccm_nonce(ext_addr: &[u8; 8], frame_counter: u32) -> [u8; CCM_NONCE_LENGTH] {
+    let mut nonce = [0; CCM_NONCE_LENGTH];
+    nonce[..8].copy_from_slice(ext_addr);
+    nonce[8] = (frame_counter >> 24) as u8;
+    nonce[9] = (frame_counter >> 16) as u8;
+    nonce[10] = (frame_counter >> 8) as u8;
+    nonce[11] = frame_counter as u8;
+    nonce[12] = SECURITY_LEVEL;
+    nonce
+}
+
+// Calls `f` on every TLV of a message that can be decoded, skipping those
+// that are unknown or malformed
+fn for_each_tlv<F: FnMut(Tlv)>(buf: &[u8], mut f: F) {
+    let mut off = 0;
+    while off + 2 <= buf.len() {
+        let end = off + 2 + buf[off + 1] as usize;
+        if end > buf.len() {
+            break;
+        }
+        if let Some((_, tlv)) = Tlv::decode(&buf[off..end]).done() {
+            f(tlv);
+        }
+        off = end;
+    }
+}
+
+pub struct Mle<'a, A: time::Alarm> {
+    alarm: &'a A,
+    udp_sender: &'a UDPSender<'a>,
+    radio: &'a MacDevice<'a>,
+    ccm: &'a AES128CCM<'a>,
+    client: OptionalCell<&'a MleClient>,
+
+    crypt_buf: TakeCell<'static, [u8]>,
+    poll_buf: TakeCell<'static, [u8]>,
+    crypt_op: Cell<CryptOp>,
+    crypt_len: Cell<usize>,
+    // Destination of the message being secured, or source of the message
+    // being verified
+    crypt_peer: Cell<IPAddr>,
+    crypt_frame_counter: Cell<u32>,
+    sending: Cell<bool>,
+
+    key: OptionalCell<[u8; 16]>,
+    key_sequence: Cell<u32>,
+    frame_counter: Cell<u32>,
+
+    state: Cell<MleState>,
+    attempts: Cell<u8>,
+    challenge: Cell<[u8; 8]>,
+    candidate: OptionalCell<ParentCandidate>,
+    parent: OptionalCell<ParentCandidate>,
+    rloc16: OptionalCell<u16>,
+    poll_period: Cell<u32>,
+    poll_failures: Cell<u8>,
+    rng: Cell<u32>,
+}
+
+impl<A: time::Alarm> Mle<'a, A> {
+    pub fn new(
+        alarm: &'a A,
+        udp_sender: &'a UDPSender<'a>,
+        radio: &'a MacDevice<'a>,
+        ccm: &'a AES128CCM<'a>,
+        crypt_buf: &'static mut [u8],
+        poll_buf: &'static mut [u8],
+    ) -> Mle<'a, A> {
+        Mle {
+            alarm: alarm,
+            udp_sender: udp_sender,
+            radio: radio,
+            ccm: ccm,
+            client: OptionalCell::empty(),
+            crypt_buf: TakeCell::new(crypt_buf),
+            poll_buf: TakeCell::new(poll_buf),
+            crypt_op: Cell::new(CryptOp::Idle),
+            crypt_len: Cell::new(0),
+            crypt_peer: Cell::new(IPAddr::new()),
+            crypt_frame_counter: Cell::new(0),
+            sending: Cell::new(false),
+            key: OptionalCell::empty(),
+            key_sequence: Cell::new(0),
+            frame_counter: Cell::new(0),
+            state: Cell::new(MleState::Disabled),
+            attempts: Cell::new(0),
+            challenge: Cell::new([0; 8]),
+            candidate: OptionalCell::empty(),
+            parent: OptionalCell::empty(),
+            rloc16: OptionalCell::empty(),
+            poll_period: Cell::new(DEFAULT_POLL_PERIOD_MS),
+            poll_failures: Cell::new(0),
+            rng: Cell::new(0),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a MleClient) {
+        self.client.set(client);
+    }
+
+    /// Sets the MLE key and the key sequence number it was derived with.
+    pub fn set_key(&self, key_sequence: u32, key: [u8; 16]) {
+        self.key_sequence.set(key_sequence);
+        self.key.set(key);
+    }
+
+    /// Sets the interval between data polls once attached, in milliseconds.
+    pub fn set_poll_period(&self, poll_period_ms: u32) {
+        self.poll_period.set(poll_period_ms);
+    }
+
+    /// Starts attaching to a parent. The client is notified through
+    /// `attach_done` once the attach procedure completes.
+    pub fn start(&self) -> ReturnCode {
+        if self.key.is_none() {
+            return ReturnCode::EINVAL;
+        }
+        if self.state.get() != MleState::Disabled {
+            return ReturnCode::EALREADY;
+        }
+        // Seed the challenge generator from the extended address
+        let seed = self
+            .radio
+            .get_address_long()
+            .iter()
+            .fold(self.alarm.now(), |acc, &b| acc.rotate_left(5) ^ (b as u32));
+        self.rng.set(if seed == 0 { 0x2545f491 } else { seed });
+        self.attempts.set(0);
+        self.attach()
+    }
+
+    /// Stops the attach procedure, or detaches from the current parent.
+    pub fn stop(&self) {
+        self.state.set(MleState::Disabled);
+        self.candidate.clear();
+        self.parent.clear();
+        self.rloc16.clear();
+        self.alarm.disable();
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.state.get() == MleState::Attached
+    }
+
+    /// Returns the RLOC16 assigned by the parent, if attached.
+    pub fn get_rloc16(&self) -> Option<u16> {
+        self.rloc16.map(|rloc16| *rloc16)
+    }
+
+    /// Returns the link-local address MLE messages are sent from.
+    pub fn get_link_local_addr(&self) -> IPAddr {
+        IPAddr::generate_from_mac(MacAddress::Long(self.radio.get_address_long()))
+    }
+
+    // Attach state machine
+
+    fn attach(&self) -> ReturnCode {
+        self.candidate.clear();
+        self.parent.clear();
+        self.rloc16.clear();
+        self.send_parent_request(MleState::ParentRequestRouters)
+    }
+
+    fn set_timeout(&self, ms: u32) {
+        let tics = ((ms as u64) * (<A::Frequency>::frequency() as u64) / 1000) as u32;
+        self.alarm.set_alarm(self.alarm.now().wrapping_add(tics));
+    }
+
+    fn attach_failed(&self) {
+        self.attempts.set(self.attempts.get() + 1);
+        if self.attempts.get() >= MAX_ATTACH_ATTEMPTS {
+            self.stop();
+            self.client
+                .map(|client| client.attach_done(ReturnCode::FAIL));
+        } else {
+            self.state.set(MleState::AttachBackoff);
+            self.set_timeout(ATTACH_BACKOFF_MS);
+        }
+    }
+
+    fn detach(&self) {
+        self.state.set(MleState::Disabled);
+        self.client.map(|client| client.detached());
+        self.attempts.set(0);
+        if self.attach() != ReturnCode::SUCCESS {
+            self.attach_failed();
+        }
+    }
+
+    fn send_parent_request(&self, state: MleState) -> ReturnCode {
+        let mut challenge = [0; 8];
+        for chunk in challenge.chunks_mut(4) {
+            let r = self.random();
+            chunk.copy_from_slice(&[(r >> 24) as u8, (r >> 16) as u8, (r >> 8) as u8, r as u8]);
+        }
+        self.challenge.set(challenge);
+        let scan_mask = if state == MleState::ParentRequestRouters {
+            MulticastResponder::Router as u8
+        } else {
+            MulticastResponder::Router as u8 | MulticastResponder::EndDevice as u8
+        };
+        let result = self.send_message(ALL_ROUTERS, |buf| {
+            let mut off = enc_consume!(buf; encode_command, mle_command::PARENT_REQUEST);
+            off = enc_consume!(buf, off; Tlv::Mode(LinkMode::SecureDataRequests as u8); encode);
+            off = enc_consume!(buf, off; Tlv::Challenge(challenge); encode);
+            off = enc_consume!(buf, off; Tlv::ScanMask(scan_mask); encode);
+            off = enc_consume!(buf, off; Tlv::Version(THREAD_VERSION); encode);
+            stream_done!(off, off);
+        });
+        if result == ReturnCode::SUCCESS {
+            self.state.set(state);
+            self.set_timeout(if state == MleState::ParentRequestRouters {
+                PARENT_REQUEST_ROUTER_TIMEOUT_MS
+            } else {
+                PARENT_REQUEST_REED_TIMEOUT_MS
+            });
+        }
+        result
+    }
+
+    fn send_child_id_request(&self) -> ReturnCode {
+        let candidate = match self.candidate.map(|candidate| *candidate) {
+            Some(candidate) => candidate,
+            None => return ReturnCode::FAIL,
+        };
+        let frame_counter = self.frame_counter.get();
+        let result = self.send_message(candidate.addr, |buf| {
+            let mut off = enc_consume!(buf; encode_command, mle_command::CHILD_ID_REQUEST);
+            off = enc_consume!(buf, off; Tlv::Response(candidate.challenge); encode);
+            off = enc_consume!(buf, off; Tlv::LinkLayerFrameCounter(0); encode);
+            off = enc_consume!(buf, off; Tlv::MleFrameCounter(frame_counter); encode);
+            off = enc_consume!(buf, off; Tlv::Mode(LinkMode::SecureDataRequests as u8); encode);
+            off = enc_consume!(buf, off; Tlv::Timeout(CHILD_TIMEOUT_S); encode);
+            off = enc_consume!(buf, off; Tlv::Version(THREAD_VERSION); encode);
+            let requested = [TlvType::Address16 as u8, TlvType::NetworkData as u8];
+            off = enc_consume!(buf, off; Tlv::TlvRequest(&requested); encode);
+            stream_done!(off, off);
+        });
+        if result == ReturnCode::SUCCESS {
+            self.state.set(MleState::ChildIdRequest);
+            self.set_timeout(CHILD_ID_RESPONSE_TIMEOUT_MS);
+        }
+        result
+    }
+
+    fn send_data_poll(&self) {
+        let parent = match self.parent.map(|parent| parent.rloc16) {
+            Some(rloc16) => rloc16,
+            None => return,
+        };
+        let sent = self.poll_buf.take().map_or(false, |buf| {
+            let pan = self.radio.get_pan();
+            let src = MacAddress::Long(self.radio.get_address_long());
+            match self.radio.prepare_command_frame(
+                buf,
+                pan,
+                MacAddress::Short(parent),
+                pan,
+                src,
+                MacCommand::DataRequest as u8,
+                None,
+            ) {
+                Ok(frame) => match self.radio.transmit(frame) {
+                    (ReturnCode::SUCCESS, _) => true,
+                    (_, buf) => {
+                        buf.map(|buf| self.poll_buf.replace(buf));
+                        false
+                    }
+                },
+                Err(buf) => {
+                    self.poll_buf.replace(buf);
+                    false
+                }
+            }
+        });
+        if !sent {
+            self.poll_failed();
+        }
+    }
+
+    fn poll_failed(&self) {
+        self.poll_failures.set(self.poll_failures.get() + 1);
+        if self.poll_failures.get() >= MAX_POLL_FAILURES {
+            self.detach();
+        }
+    }
+
+    fn random(&self) -> u32 {
+        // xorshift32, perturbed by the current time
+        let mut x = self.rng.get() ^ self.alarm.now();
+        if x == 0 {
+            x = 0x2545f491;
+        }
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng.set(x);
+        x
+    }
+
+    // Securing messages
+
+    // Encodes a message into the crypt buffer with `encode`, which must
+    // return the length of the command and TLVs, then starts securing it.
+    // The message is sent to `dst` once it has been secured.
+    fn send_message<F>(&self, dst: IPAddr, encode: F) -> ReturnCode
+    where
+        F: FnOnce(&mut [u8]) -> SResult<usize>,
+    {
+        if self.sending.get() || self.crypt_op.get() != CryptOp::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let key = match self.key.map(|key| *key) {
+            Some(key) => key,
+            None => return ReturnCode::EINVAL,
+        };
+        let buf = match self.crypt_buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        if buf.len() < MSG_OFFSET + MIC_LEN {
+            self.crypt_buf.replace(buf);
+            return ReturnCode::ESIZE;
+        }
+        let msg_len = {
+            let end = buf.len() - MIC_LEN;
+            match encode(&mut buf[MSG_OFFSET..end]).done() {
+                Some((_, len)) => len,
+                None => {
+                    self.crypt_buf.replace(buf);
+                    return ReturnCode::ESIZE;
+                }
+            }
+        };
+
+        let frame_counter = self.frame_counter.get();
+        self.frame_counter.set(frame_counter.wrapping_add(1));
+        let src = self.get_link_local_addr();
+        buf[ADDRS_OFFSET..ADDRS_OFFSET + 16].copy_from_slice(&src.0);
+        buf[ADDRS_OFFSET + 16..AUX_HDR_OFFSET].copy_from_slice(&dst.0);
+        self.encode_aux_hdr(&mut buf[AUX_HDR_OFFSET..MSG_OFFSET], frame_counter);
+
+        let nonce = get_ccm_nonce(&self.radio.get_address_long(), frame_counter);
+        self.crypt(buf, key, nonce, msg_len, dst, CryptOp::Encrypt)
+    }
+
+    fn encode_aux_hdr(&self, buf: &mut [u8], frame_counter: u32) {
+        let key_sequence = self.key_sequence.get();
+        buf[0] = SECURITY_CONTROL;
+        // The frame counter is little-endian, as in the 802.15.4 header
+        buf[1] = frame_counter as u8;
+        buf[2] = (frame_counter >> 8) as u8;
+        buf[3] = (frame_counter >> 16) as u8;
+        buf[4] = (frame_counter >> 24) as u8;
+        buf[5] = (key_sequence >> 24) as u8;
+        buf[6] = (key_sequence >> 16) as u8;
+        buf[7] = (key_sequence >> 8) as u8;
+        buf[8] = key_sequence as u8;
+        buf[9] = ((key_sequence & 0x7f) + 1) as u8;
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        key: [u8; 16],
+        nonce: [u8; CCM_NONCE_LENGTH],
+        msg_len: usize,
+        peer: IPAddr,
+        op: CryptOp,
+    ) -> ReturnCode {
+        if self.ccm.set_key(&key) != ReturnCode::SUCCESS
+            || self.ccm.set_nonce(&nonce) != ReturnCode::SUCCESS
+        {
+            self.crypt_buf.replace(buf);
+            return ReturnCode::FAIL;
+        }
+        let encrypting = op == CryptOp::Encrypt;
+        match self.ccm.crypt(
+            buf,
+            ADDRS_OFFSET,
+            MSG_OFFSET,
+            msg_len,
+            MIC_LEN,
+            true,
+            encrypting,
+        ) {
+            (ReturnCode::SUCCESS, _) => {
+                self.crypt_op.set(op);
+                self.crypt_len.set(msg_len);
+                self.crypt_peer.set(peer);
+                ReturnCode::SUCCESS
+            }
+            (result, buf) => {
+                buf.map(|buf| self.crypt_buf.replace(buf));
+                result
+            }
+        }
+    }
+
+    // Receiving messages
+
+    fn receive_secured(&self, src_addr: IPAddr, dst_addr: IPAddr, payload: &[u8]) {
+        if payload.len() < 1 + AUX_HDR_LEN + 1 + MIC_LEN || payload[0] != SECURITY_SUITE_154 {
+            return;
+        }
+        let aux_hdr = &payload[1..1 + AUX_HDR_LEN];
+        let frame_counter = (aux_hdr[1] as u32)
+            | (aux_hdr[2] as u32) << 8
+            | (aux_hdr[3] as u32) << 16
+            | (aux_hdr[4] as u32) << 24;
+        let key_sequence = (aux_hdr[5] as u32) << 24
+            | (aux_hdr[6] as u32) << 16
+            | (aux_hdr[7] as u32) << 8
+            | (aux_hdr[8] as u32);
+        if aux_hdr[0] != SECURITY_CONTROL || key_sequence != self.key_sequence.get() {
+            return;
+        }
+        let key = match self.key.map(|key| *key) {
+            Some(key) => key,
+            None => return,
+        };
+        if self.crypt_op.get() != CryptOp::Idle {
+            // The message is dropped, and will be retransmitted if needed
+            return;
+        }
+        let buf = match self.crypt_buf.take() {
+            Some(buf) => buf,
+            None => return,
+        };
+        let secured = &payload[1..];
+        if buf.len() < AUX_HDR_OFFSET + secured.len() {
+            self.crypt_buf.replace(buf);
+            return;
+        }
+        buf[ADDRS_OFFSET..ADDRS_OFFSET + 16].copy_from_slice(&src_addr.0);
+        buf[ADDRS_OFFSET + 16..AUX_HDR_OFFSET].copy_from_slice(&dst_addr.0);
+        buf[AUX_HDR_OFFSET..AUX_HDR_OFFSET + secured.len()].copy_from_slice(secured);
+
+        let msg_len = secured.len() - AUX_HDR_LEN - MIC_LEN;
+        let nonce = get_ccm_nonce(&ext_addr_from_iid(&src_addr), frame_counter);
+        self.crypt_frame_counter.set(frame_counter);
+        self.crypt(buf, key, nonce, msg_len, src_addr, CryptOp::Decrypt);
+    }
+
+    fn receive_message(&self, src: IPAddr, frame_counter: u32, msg: &[u8]) {
+        if msg.is_empty() {
+            return;
+        }
+        match (msg[0], self.state.get()) {
+            (mle_command::PARENT_RESPONSE, MleState::ParentRequestRouters)
+            | (mle_command::PARENT_RESPONSE, MleState::ParentRequestReeds) => {
+                self.receive_parent_response(src, frame_counter, &msg[1..])
+            }
+            (mle_command::CHILD_ID_RESPONSE, MleState::ChildIdRequest) => {
+                self.receive_child_id_response(src, frame_counter, &msg[1..])
+            }
+            _ => {}
+        }
+    }
+
+    fn receive_parent_response(&self, src: IPAddr, frame_counter: u32, tlvs: &[u8]) {
+        let mut response_valid = false;
+        let mut rloc16 = None;
+        let mut challenge = None;
+        let mut link_margin = None;
+        let mut connectivity = None;
+        let mut mle_frame_counter = frame_counter;
+        let expected = self.challenge.get();
+        for_each_tlv(tlvs, |tlv| match tlv {
+            Tlv::Response(response) => response_valid = response == expected,
+            Tlv::SourceAddress(addr) => rloc16 = Some(addr),
+            Tlv::Challenge(c) => challenge = Some(c),
+            Tlv::LinkMargin(margin) => link_margin = Some(margin),
+            Tlv::MleFrameCounter(counter) => mle_frame_counter = counter,
+            Tlv::Connectivity {
+                parent_priority,
+                link_quality_3,
+                ..
+            } => connectivity = Some((parent_priority, link_quality_3)),
+            _ => {}
+        });
+        if !response_valid {
+            return;
+        }
+        let (rloc16, challenge, link_margin, (parent_priority, link_quality_3)) =
+            match (rloc16, challenge, link_margin, connectivity) {
+                (Some(r), Some(c), Some(m), Some(conn)) => (r, c, m, conn),
+                _ => return,
+            };
+        let candidate = ParentCandidate {
+            addr: src,
+            rloc16: rloc16,
+            challenge: challenge,
+            link_quality: link_quality_from_margin(link_margin),
+            priority: priority_rank(parent_priority),
+            link_quality_3: link_quality_3,
+            frame_counter: mle_frame_counter,
+        };
+        if candidate.link_quality == 0 {
+            return;
+        }
+        let better = self
+            .candidate
+            .map_or(true, |current| candidate.is_better_than(current));
+        if better {
+            self.candidate.set(candidate);
+        }
+    }
+
+    fn receive_child_id_response(&self, src: IPAddr, frame_counter: u32, tlvs: &[u8]) {
+        let mut candidate = match self.candidate.map(|candidate| *candidate) {
+            Some(candidate) if candidate.addr == src => candidate,
+            _ => return,
+        };
+        let mut rloc16 = None;
+        for_each_tlv(tlvs, |tlv| match tlv {
+            Tlv::Address16(addr) => rloc16 = Some(addr),
+            _ => {}
+        });
+        let rloc16 = match rloc16 {
+            Some(rloc16) => rloc16,
+            None => return,
+        };
+
+        candidate.frame_counter = frame_counter;
+        self.parent.set(candidate);
+        self.candidate.clear();
+        self.rloc16.set(rloc16);
+        self.radio.set_address(rloc16);
+        self.radio.config_commit();
+        self.state.set(MleState::Attached);
+        self.attempts.set(0);
+        self.poll_failures.set(0);
+        self.set_timeout(self.poll_period.get());
+        self.client
+            .map(|client| client.attach_done(ReturnCode::SUCCESS));
+    }
+}
+
+// Writes the MLE command type
+fn encode_command(buf: &mut [u8], command: u8) -> SResult {
+    stream_len_cond!(buf, 1);
+    buf[0] = command;
+    stream_done!(1);
+}
+
+impl<A: time::Alarm> time::Client for Mle<'a, A> {
+    fn fired(&self) {
+        match self.state.get() {
+            MleState::ParentRequestRouters => {
+                let result = if self.candidate.is_some() {
+                    self.send_child_id_request()
+                } else {
+                    self.send_parent_request(MleState::ParentRequestReeds)
+                };
+                if result != ReturnCode::SUCCESS {
+                    self.attach_failed();
+                }
+            }
+            MleState::ParentRequestReeds => {
+                if self.candidate.is_none() || self.send_child_id_request() != ReturnCode::SUCCESS {
+                    self.attach_failed();
+                }
+            }
+            MleState::ChildIdRequest => self.attach_failed(),
+            MleState::AttachBackoff => {
+                if self.attach() != ReturnCode::SUCCESS {
+                    self.attach_failed();
+                }
+            }
+            MleState::Attached => {
+                self.send_data_poll();
+                if self.state.get() == MleState::Attached {
+                    self.set_timeout(self.poll_period.get());
+                }
+            }
+            MleState::Disabled => {}
+        }
+    }
+}
+
+impl<A: time::Alarm> CCMClient for Mle<'a, A> {
+    fn crypt_done(&self, buf: &'static mut [u8], res: ReturnCode, tag_is_valid: bool) {
+        let op = self.crypt_op.get();
+        self.crypt_op.set(CryptOp::Idle);
+        let msg_len = self.crypt_len.get();
+        let peer = self.crypt_peer.get();
+        match op {
+            CryptOp::Encrypt => {
+                if res == ReturnCode::SUCCESS {
+                    buf[AUX_HDR_OFFSET - 1] = SECURITY_SUITE_154;
+                    let end = MSG_OFFSET + msg_len + MIC_LEN;
+                    let result = self.udp_sender.send_to(
+                        peer,
+                        MLE_PORT,
+                        MLE_PORT,
+                        &buf[AUX_HDR_OFFSET - 1..end],
+                    );
+                    self.sending.set(result == ReturnCode::SUCCESS);
+                }
+                self.crypt_buf.replace(buf);
+            }
+            CryptOp::Decrypt => {
+                let frame_counter = self.crypt_frame_counter.get();
+                // Messages from the parent must not be replayed
+                let replayed = self.parent.map_or(false, |parent| {
+                    parent.addr == peer && frame_counter <= parent.frame_counter
+                });
+                if res == ReturnCode::SUCCESS && tag_is_valid && !replayed {
+                    self.receive_message(
+                        peer,
+                        frame_counter,
+                        &buf[MSG_OFFSET..MSG_OFFSET + msg_len],
+                    );
+                }
+                self.crypt_buf.replace(buf);
+            }
+            CryptOp::Idle => {
+                self.crypt_buf.replace(buf);
+            }
+        }
+    }
+}
+
+impl<A: time::Alarm> UDPSendClient for Mle<'a, A> {
+    fn send_done(&self, _result: ReturnCode) {
+        self.sending.set(false);
+    }
+}
+
+impl<A: time::Alarm> UDPRecvClient for Mle<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        dst_addr: IPAddr,
+        src_port: u16,
+        dst_port: u16,
+        payload: &[u8],
+    ) {
+        if dst_port == MLE_PORT && src_port == MLE_PORT && self.state.get() != MleState::Disabled {
+            self.receive_secured(src_addr, dst_addr, payload);
+        }
+    }
+}
+
+impl<A: time::Alarm> TxClient for Mle<'a, A> {
+    fn send_done(&self, tx_buf: &'static mut [u8], acked: bool, _result: ReturnCode) {
+        self.poll_buf.replace(tx_buf);
+        if self.state.get() != MleState::Attached {
+            return;
+        }
+        if acked {
+            self.poll_failures.set(0);
+        } else {
+            self.poll_failed();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    // Encodes each TLV with the given length field, followed by its value
+    fn tlvs(tlvs: Vec<(TlvType, u8, &[u8])>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (tlv_type, len, value) in tlvs {
+            buf.push(tlv_type as u8);
+            buf.push(len);
+            buf.extend_from_slice(value);
+        }
+        buf
+    }
+
+    // The source addresses of the Source Address TLVs found in `buf`, or 0
+    // for any other TLV
+    fn source_addresses(buf: &[u8]) -> Vec<u16> {
+        let mut found = Vec::new();
+        for_each_tlv(buf, |tlv| match tlv {
+            Tlv::SourceAddress(addr) => found.push(addr),
+            _ => found.push(0),
+        });
+        found
+    }
+
+    #[test]
+    fn tlvs_are_read_in_sequence() {
+        let buf = tlvs(vec![
+            (TlvType::SourceAddress, 2, &[0x04, 0x00]),
+            (TlvType::LinkMargin, 1, &[30]),
+            (TlvType::SourceAddress, 2, &[0x08, 0x01]),
+        ]);
+        assert_eq!(source_addresses(&buf), [0x0400, 0, 0x0801]);
+        assert_eq!(source_addresses(&[]), []);
+    }
+
+    #[test]
+    fn tlvs_too_short_for_their_type_are_skipped() {
+        // A one-byte Source Address and an empty Challenge are dropped, but
+        // the TLVs that follow them are still read
+        let buf = tlvs(vec![
+            (TlvType::SourceAddress, 1, &[0x04]),
+            (TlvType::Challenge, 0, &[]),
+            (TlvType::SourceAddress, 2, &[0x08, 0x01]),
+        ]);
+        assert_eq!(source_addresses(&buf), [0x0801]);
+    }
+
+    #[test]
+    fn tlvs_running_past_the_message_end_stop_parsing() {
+        let buf = tlvs(vec![
+            (TlvType::SourceAddress, 2, &[0x04, 0x00]),
+            (TlvType::SourceAddress, 4, &[0x08, 0x01]),
+        ]);
+        assert_eq!(source_addresses(&buf), [0x0400]);
+        assert_eq!(
+            source_addresses(&tlvs(vec![(TlvType::SourceAddress, 255, &[1, 2])])),
+            []
+        );
+
+        // A type byte without a length is ignored
+        let mut buf = tlvs(vec![(TlvType::SourceAddress, 2, &[0x04, 0x00])]);
+        buf.push(TlvType::Mode as u8);
+        assert_eq!(source_addresses(&buf), [0x0400]);
+    }
+
+    #[test]
+    fn link_margins_map_to_link_qualities() {
+        assert_eq!(link_quality_from_margin(0), 0);
+        assert_eq!(link_quality_from_margin(2), 0);
+        assert_eq!(link_quality_from_margin(3), 1);
+        assert_eq!(link_quality_from_margin(11), 2);
+        assert_eq!(link_quality_from_margin(21), 3);
+    }
+}
@@ -2,7 +2,9 @@
 //!
 //! Implements a userspace interface for sending and receiving UDP messages.
 //! Processes use this driver to send UDP packets from a common interface
-//! and bind to UDP ports for receiving packets.
+//! and bind to UDP ports for receiving packets. Ports are bound in the
+//! shared `UDPPortTable`, so processes can hold several sockets each and
+//! cannot bind to ports that the kernel reserves or already uses.
 //! Also exposes a list of interface addresses to the application (currently
 //! hard-coded).
 
@@ -10,7 +12,8 @@ use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::stream::encode_u16;
 use crate::net::stream::encode_u8;
 use crate::net::stream::SResult;
-use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_port_table::{SocketOwner, UDPPortTable};
+use crate::net::udp::udp_recv::UDPRecvClient;
 use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
 use core::cell::Cell;
 use core::{cmp, mem};
@@ -19,7 +22,10 @@ use kernel::{debug, AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared
 /// Syscall number
 pub const DRIVER_NUM: usize = 0x30002;
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// Maximum number of sockets that a single process can have bound at once
+pub const MAX_SOCKETS_PER_APP: usize = 4;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct UDPEndpoint {
     addr: IPAddr,
     port: u16,
@@ -63,7 +69,6 @@ pub struct App {
     app_cfg: Option<AppSlice<Shared, u8>>,
     app_rx_cfg: Option<AppSlice<Shared, u8>>,
     pending_tx: Option<[UDPEndpoint; 2]>,
-    bound_port: Option<UDPEndpoint>,
 }
 
 #[allow(dead_code)]
@@ -71,8 +76,8 @@ pub struct UDPDriver<'a> {
     /// UDP sender
     sender: &'a UDPSender<'a>,
 
-    /// UDP receiver
-    receiver: &'a UDPReceiver<'a>,
+    /// Table of bound ports, shared with the UDP receiver and kernel capsules
+    port_table: &'a UDPPortTable<'a>,
 
     /// Grant of apps that use this radio driver.
     apps: Grant<App>,
@@ -89,14 +94,14 @@ pub struct UDPDriver<'a> {
 impl<'a> UDPDriver<'a> {
     pub fn new(
         sender: &'a UDPSender<'a>,
-        receiver: &'a UDPReceiver<'a>,
+        port_table: &'a UDPPortTable<'a>,
         grant: Grant<App>,
         interface_list: &'static [IPAddr],
         max_tx_pyld_len: usize,
     ) -> UDPDriver<'a> {
         UDPDriver {
             sender: sender,
-            receiver: receiver,
+            port_table: port_table,
             apps: grant,
             current_app: Cell::new(None),
             interface_list: interface_list,
@@ -309,6 +314,68 @@ impl<'a> UDPDriver<'a> {
             Some(pair)
         }
     }
+
+    /// Closes the sockets of processes that no longer exist, so that their
+    /// ports can be bound again.
+    fn release_stale_sockets(&self) {
+        self.port_table.retain(|owner| match owner {
+            SocketOwner::Kernel => true,
+            SocketOwner::App(appid) => self.apps.enter(appid, |_, _| ()).is_ok(),
+        });
+    }
+
+    /// Binds a socket for `appid` to the local endpoint in the second half of
+    /// its rx_cfg buffer, returning the bound port.
+    fn bind(&self, appid: AppId) -> ReturnCode {
+        let owner = SocketOwner::App(appid);
+        let requested = self.apps.enter(appid, |app, _| {
+            app.app_rx_cfg.as_ref().and_then(|cfg| {
+                if cfg.len() != 2 * mem::size_of::<UDPEndpoint>() {
+                    None
+                } else {
+                    self.parse_ip_port_pair(&cfg.as_ref()[mem::size_of::<UDPEndpoint>()..])
+                }
+            })
+        });
+        let requested_addr = match requested {
+            Ok(Some(requested_addr)) => requested_addr,
+            Ok(None) => return ReturnCode::EINVAL,
+            Err(err) => return err.into(),
+        };
+
+        // If zero address, close all of the sockets bound by this app
+        if requested_addr.is_zero() {
+            self.port_table.unbind_all(owner);
+            return self.do_with_app(appid, |app| {
+                app.rx_callback = None;
+                ReturnCode::SUCCESS
+            });
+        }
+
+        // The unspecified address binds to every local interface, any other
+        // address must be that of a local interface
+        let addr = if requested_addr.addr.is_unspecified() {
+            None
+        } else if self.interface_list.contains(&requested_addr.addr) {
+            Some(requested_addr.addr)
+        } else {
+            return ReturnCode::EINVAL;
+        };
+
+        self.release_stale_sockets();
+        if self.port_table.count(owner) >= MAX_SOCKETS_PER_APP {
+            return ReturnCode::ENOMEM;
+        }
+        match self.port_table.bind_app(appid, addr, requested_addr.port) {
+            // Only an ephemeral bind reports the port, as the app already
+            // knows any port it asked for explicitly
+            Ok(port) if requested_addr.port == 0 => ReturnCode::SuccessWithValue {
+                value: port as usize,
+            },
+            Ok(_) => ReturnCode::SUCCESS,
+            Err(err) => err,
+        }
+    }
 }
 
 impl<'a> Driver for UDPDriver<'a> {
@@ -350,7 +417,8 @@ impl<'a> Driver for UDPDriver<'a> {
     ///
     /// - `0`: Setup callback for when packet is received. If no port has
     ///        been bound, return ERESERVE to indicate that port binding is
-    ///        is a prerequisite to reception.
+    ///        is a prerequisite to reception. The callback receives the
+    ///        payload length and the local port the packet was sent to.
     /// - `1`: Setup callback for when packet is transmitted. Notably,
     ///        this callback receives the result of the send_done callback
     ///        from udp_send.rs, which does not currently pass information
@@ -363,7 +431,7 @@ impl<'a> Driver for UDPDriver<'a> {
     ) -> ReturnCode {
         match subscribe_num {
             0 => self.do_with_app(app_id, |app| {
-                if app.bound_port.is_some() {
+                if self.port_table.count(SocketOwner::App(app_id)) > 0 {
                     app.rx_callback = callback;
                     ReturnCode::SUCCESS
                 } else {
@@ -403,27 +471,29 @@ impl<'a> Driver for UDPDriver<'a> {
     ///        packet.
     ///        Currently, only will transmit if the app has bound to the port passed in the tx_cfg
     ///        buf as the source address. If no port is bound, returns ERESERVE, if it tries to
-    ///        send on a port other than the port which is bound, returns EINVALID.
+    ///        send on a port other than the ports which are bound, or from an address that is not
+    ///        a local interface, returns EINVAL.
     ///
     ///        Notably, the currently transmit implementation allows for starvation - an
     ///        an app with a lower app id can send constantly and starve an app with a
     ///        later ID.
-    /// - `3`: Bind to the address in rx_cfg. Returns SUCCESS if that addr/port combo is free,
-    ///        returns EINVAL if the address requested is not a local interface, or if the port
-    ///        requested is 0. Returns EBUSY if that port is already bound to by another app.
+    /// - `3`: Bind a new socket to the address in rx_cfg. Returns SUCCESS if that addr/port
+    ///        combo is free. If the requested port is 0, an ephemeral port is allocated and
+    ///        returned with SuccessWithValue, and if the requested address is the unspecified
+    ///        address, the socket receives packets sent to any local interface. Returns
+    ///        EINVAL if the address requested is not a local interface, ERESERVE if the port
+    ///        is reserved for the kernel, EBUSY if that port is already bound, and ENOMEM if
+    ///        the app already has `MAX_SOCKETS_PER_APP` sockets or no port is free.
     ///        This command should be called after allow() is called on the rx_cfg buffer, and
     ///        before subscribe() is used to set up the recv callback. Additionally, apps can only
     ///        send on ports after they have bound to said port. If this command is called
-    ///        and the address in rx_cfg is 0::0 : 0, this command will reset the option
-    ///        containing the bound port to None and set the rx callback to None. Notably,
-    ///        the current implementation of this only allows for each app to bind to a single
-    ///        port at a time, as such an implementation conserves memory (and is similar
-    ///        to the approach applied by TinyOS and Riot). Further, there is
-    ///        currently no mechanism for anything in the kernel to bind to ports, and there
-    ///        is no distinction between ephemeral ports and reserved ports.
+    ///        and the address in rx_cfg is 0::0 : 0, this command will close all of the app's
+    ///        sockets and set the rx callback to None.
     /// - `4`: Returns the maximum payload that can be transmitted by apps using this driver.
     ///        This represents the size of the payload buffer in the kernel. Apps can use this
     ///        syscall to ensure they do not attempt to send too-large messages.
+    /// - `5`: Close the socket bound to port `arg1`. Returns EINVAL if the app has no socket
+    ///        bound to that port. Closing the app's last socket sets the rx callback to None.
 
     fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
         match command_num {
@@ -451,7 +521,8 @@ impl<'a> Driver for UDPDriver<'a> {
                         // Cannot support more than one pending tx per process.
                         return ReturnCode::EBUSY;
                     }
-                    if app.bound_port.is_none() {
+                    let owner = SocketOwner::App(appid);
+                    if self.port_table.count(owner) == 0 {
                         // Currently, apps need to bind to a port before they can send from said port
                         return ReturnCode::ERESERVE;
                     }
@@ -464,7 +535,11 @@ impl<'a> Driver for UDPDriver<'a> {
                             self.parse_ip_port_pair(&cfg.as_ref()[mem::size_of::<UDPEndpoint>()..]),
                             self.parse_ip_port_pair(&cfg.as_ref()[..mem::size_of::<UDPEndpoint>()]),
                         ) {
-                            if Some(src.clone()) == app.bound_port {
+                            // A socket bound to any local address only
+                            // allows sending from one of the interfaces
+                            if self.interface_list.contains(&src.addr)
+                                && self.port_table.is_bound(owner, src.addr, src.port)
+                            {
                                 Some([src, dst])
                             } else {
                                 None
@@ -480,71 +555,21 @@ impl<'a> Driver for UDPDriver<'a> {
                     self.do_next_tx_immediate(appid)
                 })
             }
-            3 => {
-                self.do_with_app(appid, |app| {
-                    // Move UDPEndpoint into udp.rs?
-                    let mut requested_addr_opt = app.app_rx_cfg.as_ref().and_then(|cfg| {
-                        if cfg.len() != 2 * mem::size_of::<UDPEndpoint>() {
-                            None
-                        } else if let Some(local_iface) =
-                            self.parse_ip_port_pair(&cfg.as_ref()[mem::size_of::<UDPEndpoint>()..])
-                        {
-                            Some(local_iface)
-                        } else {
-                            None
-                        }
-                    });
-                    if requested_addr_opt.is_none() {
-                        return ReturnCode::EINVAL;
-                    }
-                    if requested_addr_opt.is_some() {
-                        let requested_addr = requested_addr_opt.unwrap();
-                        // If zero address, close any already bound socket
-                        if requested_addr.is_zero() {
-                            app.rx_callback = None;
-                            app.bound_port = None;
-                            return ReturnCode::SUCCESS;
-                        }
-                        // Check that requested addr is a local interface
-                        let mut requested_is_local = false;
-                        for i in 0..self.interface_list.len() {
-                            if requested_addr.addr == self.interface_list[i] {
-                                requested_is_local = true;
-                            }
-                        }
-                        if !requested_is_local {
-                            return ReturnCode::EINVAL;
-                        }
-                        let mut addr_already_bound = false;
-                        for app in self.apps.iter() {
-                            app.enter(|other_app, _| {
-                                if other_app.bound_port.is_some() {
-                                    let other_addr_opt = other_app.bound_port.clone();
-                                    let other_addr = other_addr_opt.unwrap();
-                                    if other_addr.port == requested_addr.port {
-                                        if other_addr.addr == requested_addr.addr {
-                                            addr_already_bound = true;
-                                        }
-                                    }
-                                }
-                            });
-                        }
-                        if addr_already_bound {
-                            return ReturnCode::EBUSY;
-                        } else {
-                            requested_addr_opt = Some(requested_addr);
-                            // If this point is reached, the requested addr is free and valid
-                            app.bound_port = requested_addr_opt;
-                            return ReturnCode::SUCCESS;
-                        }
-                    } else {
-                        return ReturnCode::EINVAL;
-                    }
-                })
-            }
+            3 => self.bind(appid),
             4 => ReturnCode::SuccessWithValue {
                 value: self.max_tx_pyld_len,
             },
+            5 => {
+                let owner = SocketOwner::App(appid);
+                let result = self.port_table.unbind(owner, arg1 as u16);
+                if result == ReturnCode::SUCCESS && self.port_table.count(owner) == 0 {
+                    self.do_with_app(appid, |app| {
+                        app.rx_callback = None;
+                        ReturnCode::SUCCESS
+                    });
+                }
+                result
+            }
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -572,42 +597,38 @@ impl<'a> UDPRecvClient for UDPDriver<'a> {
         dst_port: u16,
         payload: &[u8],
     ) {
-        self.apps.each(|app| {
-            if app.bound_port.is_some() {
-                let appid = app.appid();
-                self.do_with_app(app.appid(), |app| {
-                    let mut for_me = false;
-                    app.bound_port.as_ref().map(|requested_addr| {
-                        if requested_addr.addr == dst_addr && requested_addr.port == dst_port {
-                            for_me = true;
-                        }
-                    });
-                    if for_me {
-                        let mut app_read = app.app_read.take();
-                        app_read.as_mut().map(|rbuf| {
-                            let rbuf = rbuf.as_mut();
-                            let len = payload.len();
-                            if rbuf.len() >= len {
-                                // silently ignore packets that don't fit?
-                                rbuf[..len].copy_from_slice(&payload[..len]);
-
-                                // Write address of sender into rx_cfg so it can be read by client
-                                let sender_addr = UDPEndpoint {
-                                    addr: src_addr,
-                                    port: src_port,
-                                };
-                                let cfg_len = 2 * mem::size_of::<UDPEndpoint>();
-                                self.do_with_rx_cfg_mut(appid, cfg_len, |cfg| {
-                                    sender_addr.encode(cfg, 0);
-                                    ReturnCode::SUCCESS
-                                });
-                                app.rx_callback.map(|mut cb| cb.schedule(len, 0, 0));
-                            }
-                        });
-                        app.app_read = app_read;
+        let appid = match self.port_table.lookup(dst_addr, dst_port) {
+            Some(socket) => match socket.get_owner() {
+                SocketOwner::App(appid) => appid,
+                SocketOwner::Kernel => return,
+            },
+            None => return,
+        };
+        let _ = self.apps.enter(appid, |app, _| {
+            let len = payload.len();
+            let copied = app.app_read.as_mut().map_or(false, |rbuf| {
+                let rbuf = rbuf.as_mut();
+                if rbuf.len() >= len {
+                    // silently ignore packets that don't fit?
+                    rbuf[..len].copy_from_slice(&payload[..len]);
+                    true
+                } else {
+                    false
+                }
+            });
+            if copied {
+                // Write address of sender into rx_cfg so it can be read by client
+                let sender_addr = UDPEndpoint {
+                    addr: src_addr,
+                    port: src_port,
+                };
+                app.app_rx_cfg.as_mut().map(|cfg| {
+                    if cfg.len() == 2 * mem::size_of::<UDPEndpoint>() {
+                        sender_addr.encode(cfg.as_mut(), 0);
                     }
-                    ReturnCode::SUCCESS
                 });
+                app.rx_callback
+                    .map(|mut cb| cb.schedule(len, dst_port as usize, 0));
             }
         });
     }
@@ -0,0 +1,430 @@
+//! Table of the UDP ports bound by kernel capsules and userspace processes.
+//!
+//! Every socket in the table associates a local port, and optionally a local
+//! address, with the `UDPRecvClient` that datagrams addressed to it are
+//! delivered to. Kernel capsules bind sockets with their own client, while
+//! the sockets of processes deliver to the app client, which is the UDP
+//! userspace driver. Each owner may hold several sockets at once.
+//!
+//! Ports below `MIN_UNRESERVED_PORT` are reserved for the kernel. Binding to
+//! port 0 allocates an unused port from the ephemeral range
+//! (`MIN_EPHEMERAL_PORT` to `MAX_EPHEMERAL_PORT`, as suggested by RFC 6335).
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! static mut UDP_SOCKETS: [Option<UDPSocket<'static>>; 16] = [None; 16];
+//!
+//! let port_table = static_init!(
+//!     UDPPortTable<'static>,
+//!     UDPPortTable::new(&mut UDP_SOCKETS)
+//! );
+//! let udp_recv = static_init!(UDPReceiver<'static>, UDPReceiver::new(port_table));
+//! ip_receive.set_client(udp_recv);
+//! port_table.set_app_client(udp_driver);
+//!
+//! port_table.bind_kernel(kernel_client, None, 7);
+//! ```
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::udp::udp_recv::UDPRecvClient;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::{AppId, ReturnCode};
+
+/// Ports below this value can only be bound by the kernel.
+pub const MIN_UNRESERVED_PORT: u16 = 1024;
+/// First port of the range that ephemeral ports are allocated from.
+pub const MIN_EPHEMERAL_PORT: u16 = 49152;
+/// Last port of the range that ephemeral ports are allocated from.
+pub const MAX_EPHEMERAL_PORT: u16 = 65535;
+
+/// The entity on whose behalf a socket is bound.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SocketOwner {
+    Kernel,
+    App(AppId),
+}
+
+#[derive(Copy, Clone)]
+pub struct UDPSocket<'a> {
+    owner: SocketOwner,
+    // `None` if the socket accepts datagrams sent to any local address
+    addr: Option<IPAddr>,
+    port: u16,
+    client: &'a UDPRecvClient,
+}
+
+impl UDPSocket<'a> {
+    pub fn get_owner(&self) -> SocketOwner {
+        self.owner
+    }
+
+    pub fn get_addr(&self) -> Option<IPAddr> {
+        self.addr
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn get_client(&self) -> &'a UDPRecvClient {
+        self.client
+    }
+
+    // Whether a datagram sent to `addr` at `port` could be delivered to
+    // this socket
+    fn matches(&self, addr: IPAddr, port: u16) -> bool {
+        self.port == port && self.addr.map_or(true, |a| a == addr)
+    }
+
+    // Whether binding `addr` at `port` would conflict with this socket
+    fn overlaps(&self, addr: Option<IPAddr>, port: u16) -> bool {
+        self.port == port
+            && match (self.addr, addr) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+    }
+}
+
+pub struct UDPPortTable<'a> {
+    sockets: TakeCell<'a, [Option<UDPSocket<'a>>]>,
+    next_ephemeral: Cell<u16>,
+    app_client: OptionalCell<&'a UDPRecvClient>,
+}
+
+impl UDPPortTable<'a> {
+    pub fn new(sockets: &'a mut [Option<UDPSocket<'a>>]) -> UDPPortTable<'a> {
+        UDPPortTable {
+            sockets: TakeCell::new(sockets),
+            next_ephemeral: Cell::new(MIN_EPHEMERAL_PORT),
+            app_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Sets the client that datagrams received on sockets bound by
+    /// processes are delivered to.
+    pub fn set_app_client(&self, client: &'a UDPRecvClient) {
+        self.app_client.set(client);
+    }
+
+    /// Binds a socket for a kernel capsule, delivering the datagrams it
+    /// receives to `client`. Kernel sockets may use reserved ports. See
+    /// `bind` for the meaning of `addr` and `port` and the return value.
+    pub fn bind_kernel(
+        &self,
+        client: &'a UDPRecvClient,
+        addr: Option<IPAddr>,
+        port: u16,
+    ) -> Result<u16, ReturnCode> {
+        self.bind(SocketOwner::Kernel, client, addr, port)
+    }
+
+    /// Binds a socket for the process `appid`, delivering the datagrams it
+    /// receives to the app client. Returns EOFF if no app client is set.
+    pub fn bind_app(
+        &self,
+        appid: AppId,
+        addr: Option<IPAddr>,
+        port: u16,
+    ) -> Result<u16, ReturnCode> {
+        self.app_client.map_or(Err(ReturnCode::EOFF), |client| {
+            self.bind(SocketOwner::App(appid), *client, addr, port)
+        })
+    }
+
+    /// Binds a socket for `owner` to `port`, delivering the datagrams it
+    /// receives to `client`. If `addr` is `None`, the socket receives
+    /// datagrams sent to any local address. If `port` is 0, an unused
+    /// ephemeral port is allocated.
+    ///
+    /// Returns the bound port, or ERESERVE if a process requests a reserved
+    /// port, EBUSY if the port is already bound, and ENOMEM if the table
+    /// is full or no ephemeral port is free.
+    fn bind(
+        &self,
+        owner: SocketOwner,
+        client: &'a UDPRecvClient,
+        addr: Option<IPAddr>,
+        port: u16,
+    ) -> Result<u16, ReturnCode> {
+        if port != 0 && port < MIN_UNRESERVED_PORT && owner != SocketOwner::Kernel {
+            return Err(ReturnCode::ERESERVE);
+        }
+        self.sockets.map_or(Err(ReturnCode::ENOMEM), |sockets| {
+            let port = if port == 0 {
+                self.allocate_ephemeral(sockets, addr)?
+            } else if sockets
+                .iter()
+                .any(|s| s.map_or(false, |s| s.overlaps(addr, port)))
+            {
+                return Err(ReturnCode::EBUSY);
+            } else {
+                port
+            };
+            let slot = sockets
+                .iter_mut()
+                .find(|s| s.is_none())
+                .ok_or(ReturnCode::ENOMEM)?;
+            *slot = Some(UDPSocket {
+                owner: owner,
+                addr: addr,
+                port: port,
+                client: client,
+            });
+            Ok(port)
+        })
+    }
+
+    // Finds an ephemeral port that does not conflict with any bound socket,
+    // starting after the most recently allocated one.
+    fn allocate_ephemeral(
+        &self,
+        sockets: &[Option<UDPSocket<'a>>],
+        addr: Option<IPAddr>,
+    ) -> Result<u16, ReturnCode> {
+        let range = (MAX_EPHEMERAL_PORT - MIN_EPHEMERAL_PORT) as u32 + 1;
+        let mut port = self.next_ephemeral.get();
+        for _ in 0..range {
+            let candidate = port;
+            port = if port == MAX_EPHEMERAL_PORT {
+                MIN_EPHEMERAL_PORT
+            } else {
+                port + 1
+            };
+            if !sockets
+                .iter()
+                .any(|s| s.map_or(false, |s| s.overlaps(addr, candidate)))
+            {
+                self.next_ephemeral.set(port);
+                return Ok(candidate);
+            }
+        }
+        Err(ReturnCode::ENOMEM)
+    }
+
+    /// Closes the socket that `owner` bound to `port`. Returns EINVAL if
+    /// `owner` has no socket bound to that port.
+    pub fn unbind(&self, owner: SocketOwner, port: u16) -> ReturnCode {
+        self.sockets.map_or(ReturnCode::EINVAL, |sockets| {
+            for slot in sockets.iter_mut() {
+                if slot.map_or(false, |s| s.owner == owner && s.port == port) {
+                    *slot = None;
+                    return ReturnCode::SUCCESS;
+                }
+            }
+            ReturnCode::EINVAL
+        })
+    }
+
+    /// Closes every socket bound by `owner`.
+    pub fn unbind_all(&self, owner: SocketOwner) {
+        self.retain(|o| o != owner);
+    }
+
+    /// Closes every socket whose owner does not satisfy `keep`. The UDP
+    /// driver uses this to release the ports of processes that no longer
+    /// exist.
+    pub fn retain<F>(&self, keep: F)
+    where
+        F: Fn(SocketOwner) -> bool,
+    {
+        self.sockets.map(|sockets| {
+            for slot in sockets.iter_mut() {
+                if slot.map_or(false, |s| !keep(s.owner)) {
+                    *slot = None;
+                }
+            }
+        });
+    }
+
+    /// Returns the number of sockets bound by `owner`.
+    pub fn count(&self, owner: SocketOwner) -> usize {
+        self.sockets.map_or(0, |sockets| {
+            sockets
+                .iter()
+                .filter(|s| s.map_or(false, |s| s.owner == owner))
+                .count()
+        })
+    }
+
+    /// Whether `owner` has a socket from which it may send datagrams with
+    /// the given source address and port. A socket bound to any local
+    /// address matches every `addr`, so callers must check that `addr` is
+    /// that of a local interface.
+    pub fn is_bound(&self, owner: SocketOwner, addr: IPAddr, port: u16) -> bool {
+        self.sockets.map_or(false, |sockets| {
+            sockets
+                .iter()
+                .any(|s| s.map_or(false, |s| s.owner == owner && s.matches(addr, port)))
+        })
+    }
+
+    /// Finds the socket that a datagram sent to `addr` at `port` should be
+    /// delivered to. A socket bound to `addr` itself is preferred over one
+    /// bound to any local address.
+    pub fn lookup(&self, addr: IPAddr, port: u16) -> Option<UDPSocket<'a>> {
+        self.sockets.map_or(None, |sockets| {
+            let mut found = None;
+            for socket in sockets.iter().filter_map(|s| *s) {
+                if socket.matches(addr, port) {
+                    if socket.addr.is_some() {
+                        return Some(socket);
+                    }
+                    found = Some(socket);
+                }
+            }
+            found
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR_A: IPAddr = IPAddr([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x0a]);
+    const ADDR_B: IPAddr = IPAddr([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x0b]);
+
+    /// Counts the datagrams delivered to it.
+    struct Client {
+        received: Cell<usize>,
+    }
+
+    impl Client {
+        fn new() -> Client {
+            Client {
+                received: Cell::new(0),
+            }
+        }
+    }
+
+    impl UDPRecvClient for Client {
+        fn receive(
+            &self,
+            _src_addr: IPAddr,
+            _dst_addr: IPAddr,
+            _src_port: u16,
+            _dst_port: u16,
+            _payload: &[u8],
+        ) {
+            self.received.set(self.received.get() + 1);
+        }
+    }
+
+    // Delivers a datagram the way `UDPReceiver` does
+    fn deliver(table: &UDPPortTable, addr: IPAddr, port: u16) -> bool {
+        table.lookup(addr, port).map_or(false, |socket| {
+            socket.get_client().receive(ADDR_B, addr, 1234, port, &[]);
+            true
+        })
+    }
+
+    #[test]
+    fn ports_cannot_be_bound_twice() {
+        let client = Client::new();
+        let mut sockets = [None; 4];
+        let table = UDPPortTable::new(&mut sockets);
+
+        assert_eq!(table.bind_kernel(&client, None, 5000), Ok(5000));
+        assert_eq!(
+            table.bind_kernel(&client, None, 5000),
+            Err(ReturnCode::EBUSY)
+        );
+        assert_eq!(
+            table.bind_kernel(&client, Some(ADDR_A), 5000),
+            Err(ReturnCode::EBUSY)
+        );
+
+        // Sockets bound to different addresses can share a port, but not
+        // with a socket bound to any address
+        assert_eq!(table.bind_kernel(&client, Some(ADDR_A), 6000), Ok(6000));
+        assert_eq!(table.bind_kernel(&client, Some(ADDR_B), 6000), Ok(6000));
+        assert_eq!(
+            table.bind_kernel(&client, None, 6000),
+            Err(ReturnCode::EBUSY)
+        );
+        assert_eq!(table.count(SocketOwner::Kernel), 3);
+
+        assert_eq!(table.unbind(SocketOwner::Kernel, 5000), ReturnCode::SUCCESS);
+        assert_eq!(table.unbind(SocketOwner::Kernel, 5000), ReturnCode::EINVAL);
+        assert_eq!(table.bind_kernel(&client, None, 5000), Ok(5000));
+        assert_eq!(table.bind_kernel(&client, None, 7000), Ok(7000));
+        assert_eq!(
+            table.bind_kernel(&client, None, 8000),
+            Err(ReturnCode::ENOMEM)
+        );
+    }
+
+    #[test]
+    fn port_zero_allocates_unused_ephemeral_ports() {
+        let client = Client::new();
+        let mut sockets = [None; 4];
+        let table = UDPPortTable::new(&mut sockets);
+
+        assert_eq!(
+            table.bind_kernel(&client, None, MIN_EPHEMERAL_PORT + 1),
+            Ok(MIN_EPHEMERAL_PORT + 1)
+        );
+        assert_eq!(table.bind_kernel(&client, None, 0), Ok(MIN_EPHEMERAL_PORT));
+        // The bound port is skipped
+        assert_eq!(
+            table.bind_kernel(&client, None, 0),
+            Ok(MIN_EPHEMERAL_PORT + 2)
+        );
+        assert!(table.is_bound(SocketOwner::Kernel, ADDR_A, MIN_EPHEMERAL_PORT + 2));
+    }
+
+    #[test]
+    fn ephemeral_ports_wrap_around_to_the_start_of_the_range() {
+        let client = Client::new();
+        let mut sockets = [None; 4];
+        let table = UDPPortTable::new(&mut sockets);
+        table.next_ephemeral.set(MAX_EPHEMERAL_PORT);
+
+        assert_eq!(table.bind_kernel(&client, None, 0), Ok(MAX_EPHEMERAL_PORT));
+        assert_eq!(table.bind_kernel(&client, None, 0), Ok(MIN_EPHEMERAL_PORT));
+
+        // Ports freed before the wraparound are reused once it comes round
+        table.next_ephemeral.set(MAX_EPHEMERAL_PORT);
+        assert_eq!(
+            table.unbind(SocketOwner::Kernel, MAX_EPHEMERAL_PORT),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(table.bind_kernel(&client, None, 0), Ok(MAX_EPHEMERAL_PORT));
+        assert_eq!(
+            table.bind_kernel(&client, None, 0),
+            Ok(MIN_EPHEMERAL_PORT + 1)
+        );
+    }
+
+    #[test]
+    fn datagrams_reach_the_client_of_the_matching_socket() {
+        let (any, on_a, app) = (Client::new(), Client::new(), Client::new());
+        let mut sockets = [None; 4];
+        let table = UDPPortTable::new(&mut sockets);
+        table.set_app_client(&app);
+        assert_eq!(table.bind_kernel(&any, None, 7), Ok(7));
+        assert_eq!(table.bind_kernel(&on_a, Some(ADDR_A), 8), Ok(8));
+
+        // Kernel sockets deliver to their own client, never the app client
+        assert!(deliver(&table, ADDR_A, 7));
+        assert!(deliver(&table, ADDR_B, 7));
+        assert!(deliver(&table, ADDR_A, 8));
+        assert!(!deliver(&table, ADDR_B, 8));
+        assert!(!deliver(&table, ADDR_A, 9));
+        assert_eq!(any.received.get(), 2);
+        assert_eq!(on_a.received.get(), 1);
+        assert_eq!(app.received.get(), 0);
+        assert_eq!(
+            table.lookup(ADDR_A, 7).map(|socket| socket.get_owner()),
+            Some(SocketOwner::Kernel)
+        );
+
+        table.unbind_all(SocketOwner::Kernel);
+        assert!(!deliver(&table, ADDR_A, 7));
+        assert!(!deliver(&table, ADDR_A, 8));
+    }
+}
@@ -1,8 +1,9 @@
-use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ip_utils::{ip6_nh, IPAddr};
 use crate::net::ipv6::ipv6::IP6Header;
+use crate::net::ipv6::ipv6_ext::{decode_chain, is_fragmented};
 use crate::net::ipv6::ipv6_recv::IP6RecvClient;
 use crate::net::udp::udp::UDPHeader;
-use kernel::common::cells::OptionalCell;
+use crate::net::udp::udp_port_table::UDPPortTable;
 use kernel::debug;
 
 /// The UDP driver implements this client interface trait to receive
@@ -22,26 +23,32 @@ pub trait UDPRecvClient {
 }
 
 /// This struct is set as the client of an IP6Receiver, and passes
-/// received packets up to whatever app layer client assigns itself
-/// as the UDPRecvClient held by this UDPReciever.
+/// received packets up to the UDPRecvClient of the socket in the
+/// UDPPortTable that is bound to the destination port. Packets sent to
+/// a port that no socket is bound to are dropped, as are fragments of
+/// IPv6 packets, since they are not reassembled.
 pub struct UDPReceiver<'a> {
-    client: OptionalCell<&'a UDPRecvClient>,
+    port_table: &'a UDPPortTable<'a>,
 }
 
 impl<'a> UDPReceiver<'a> {
-    pub fn new() -> UDPReceiver<'a> {
+    pub fn new(port_table: &'a UDPPortTable<'a>) -> UDPReceiver<'a> {
         UDPReceiver {
-            client: OptionalCell::empty(),
+            port_table: port_table,
         }
     }
-
-    pub fn set_client(&self, client: &'a UDPRecvClient) {
-        self.client.set(client);
-    }
 }
 
 impl<'a> IP6RecvClient for UDPReceiver<'a> {
     fn receive(&self, ip_header: IP6Header, payload: &[u8]) {
+        // Skip any extension headers before the UDP header
+        let next_header = ip_header.get_next_header();
+        let payload = match decode_chain(next_header, payload).done() {
+            Some((offset, ip6_nh::UDP)) if !is_fragmented(next_header, payload) => {
+                &payload[offset..]
+            }
+            _ => return,
+        };
         match UDPHeader::decode(payload).done() {
             Some((offset, udp_header)) => {
                 let len = udp_header.get_len() as usize;
@@ -50,12 +57,14 @@ impl<'a> IP6RecvClient for UDPReceiver<'a> {
                     debug!("[UDP_RECV] Error: UDP length too long");
                     return;
                 }
-                self.client.map(|client| {
-                    client.receive(
+                let dst_addr = ip_header.get_dst_addr();
+                let dst_port = udp_header.get_dst_port();
+                self.port_table.lookup(dst_addr, dst_port).map(|socket| {
+                    socket.get_client().receive(
                         ip_header.get_src_addr(),
-                        ip_header.get_dst_addr(),
+                        dst_addr,
                         udp_header.get_src_port(),
-                        udp_header.get_dst_port(),
+                        dst_port,
                         &payload[offset..],
                     );
                 });
@@ -1,9 +1,33 @@
 //! This provides kernel and userspace access to nonvolatile memory.
 //!
-//! This is an initial implementation that does not provide safety for
-//! individual userland applications. Each application has full access to
-//! the entire memory space that has been provided to userland. Future revisions
-//! should update this to limit applications to only their allocated regions.
+//! Each application gets its own region of the memory that has been provided
+//! to userland, and cannot read or write outside of it. Applications see
+//! their region as starting at address 0.
+//!
+//! Regions are assigned by package name. The start of the userspace memory
+//! holds a table of the package names that have been given a region, and the
+//! first time an application accesses its storage the capsule looks up its
+//! name in the table, adding it if it is not there yet. Since the table is
+//! kept in the nonvolatile memory, an application finds the same data when
+//! it is restarted, when the board is reset, and when it is reflashed with
+//! the same package name. Applications without a package name cannot use
+//! the storage. Once every region has been assigned, new applications are
+//! refused; regions are never freed.
+//!
+//! The layout of the userspace memory is:
+//!
+//! ```text
+//! +--------------+----------+----------+-----+----------+
+//! | Region table | Region 0 | Region 1 | ... | Region N |
+//! +--------------+----------+----------+-----+----------+
+//!  \__ 512 bytes _/\_ app_region_size each _____________/
+//! ```
+//!
+//! The table starts with a magic number and the region size. If either does
+//! not match, for example because the memory was never used or because the
+//! board changed the region size, the table is reset and regions are assigned
+//! anew. Each entry holds the length of the package name, a hash of the name
+//! and its first bytes; an entry with a zero length is unused.
 //!
 //! However, the kernel accessible memory does not have to be the same range
 //! as the userspace accessible address space. The kernel memory can overlap
@@ -45,6 +69,7 @@
 //!         3000,                        // The byte start address for the userspace
 //!                                      // accessible memory region.
 //!         2000,                        // The length of the userspace region.
+//!         256,                         // The size of each app's region.
 //!         0,                           // The byte start address of the region
 //!                                      // that is accessible by the kernel.
 //!         3000,                        // The length of the kernel region.
@@ -64,6 +89,18 @@ pub const DRIVER_NUM: usize = driver::NUM::NvmStorage as usize;
 
 pub static mut BUFFER: [u8; 512] = [0; 512];
 
+/// Bytes reserved at the start of the userspace region for the region table.
+pub const REGION_TABLE_SIZE: usize = 512;
+
+const TABLE_MAGIC: [u8; 4] = *b"NVRT";
+const TABLE_HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 32;
+const ENTRY_HASH: usize = 4;
+const ENTRY_NAME: usize = 8;
+
+/// Maximum number of application regions.
+pub const MAX_APP_REGIONS: usize = (REGION_TABLE_SIZE - TABLE_HEADER_SIZE) / ENTRY_SIZE;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum NonvolatileCommand {
     UserspaceRead,
@@ -74,7 +111,13 @@ pub enum NonvolatileCommand {
 
 #[derive(Clone, Copy)]
 pub enum NonvolatileUser {
-    App { app_id: AppId },
+    App {
+        app_id: AppId,
+    },
+    /// Reading or updating the region table to find the region of an app.
+    RegionTable {
+        app_id: AppId,
+    },
     Kernel,
 }
 
@@ -87,6 +130,8 @@ pub struct App {
     length: usize,
     buffer_read: Option<AppSlice<Shared, u8>>,
     buffer_write: Option<AppSlice<Shared, u8>>,
+    // Index of the app's region, once it has been looked up.
+    region: Option<usize>,
 }
 
 impl Default for App {
@@ -100,10 +145,33 @@ impl Default for App {
             length: 0,
             buffer_read: None,
             buffer_write: None,
+            region: None,
         }
     }
 }
 
+/// 32-bit FNV-1a hash of a package name.
+fn name_hash(name: &str) -> u32 {
+    name.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// The region table entry for a package name.
+fn table_entry(name: &str) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    let len = name.len() as u16;
+    entry[0] = len as u8;
+    entry[1] = (len >> 8) as u8;
+    let hash = name_hash(name);
+    for i in 0..4 {
+        entry[ENTRY_HASH + i] = (hash >> (8 * i)) as u8;
+    }
+    let prefix_len = cmp::min(name.len(), ENTRY_SIZE - ENTRY_NAME);
+    entry[ENTRY_NAME..ENTRY_NAME + prefix_len].copy_from_slice(&name.as_bytes()[..prefix_len]);
+    entry
+}
+
 pub struct NonvolatileStorage<'a> {
     // The underlying physical storage device.
     driver: &'a hil::nonvolatile_storage::NonvolatileStorage,
@@ -117,8 +185,10 @@ pub struct NonvolatileStorage<'a> {
 
     // The first byte that is accessible from userspace.
     userspace_start_address: usize,
-    // How many bytes allocated to userspace.
-    userspace_length: usize,
+    // How many bytes each app can access.
+    app_region_size: usize,
+    // How many app regions fit in the userspace memory.
+    app_regions: usize,
     // The first byte that is accessible from the kernel.
     kernel_start_address: usize,
     // How many bytes allocated to kernel.
@@ -145,17 +215,27 @@ impl NonvolatileStorage<'a> {
         grant: Grant<App>,
         userspace_start_address: usize,
         userspace_length: usize,
+        app_region_size: usize,
         kernel_start_address: usize,
         kernel_length: usize,
         buffer: &'static mut [u8],
     ) -> NonvolatileStorage<'a> {
+        let app_regions = if app_region_size == 0 {
+            0
+        } else {
+            cmp::min(
+                MAX_APP_REGIONS,
+                userspace_length.saturating_sub(REGION_TABLE_SIZE) / app_region_size,
+            )
+        };
         NonvolatileStorage {
             driver: driver,
             apps: grant,
             buffer: TakeCell::new(buffer),
             current_user: OptionalCell::empty(),
             userspace_start_address: userspace_start_address,
-            userspace_length: userspace_length,
+            app_region_size: app_region_size,
+            app_regions: app_regions,
             kernel_start_address: kernel_start_address,
             kernel_length: kernel_length,
             kernel_client: OptionalCell::empty(),
@@ -180,11 +260,11 @@ impl NonvolatileStorage<'a> {
         // Do bounds check.
         match command {
             NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
-                // Userspace sees memory that starts at address 0 even if it
-                // is offset in the physical memory.
-                if offset >= self.userspace_length
-                    || length > self.userspace_length
-                    || offset + length > self.userspace_length
+                // Userspace sees its region as starting at address 0, wherever
+                // it is in the physical memory.
+                if offset >= self.app_region_size
+                    || length > self.app_region_size
+                    || offset + length > self.app_region_size
                 {
                     return ReturnCode::EINVAL;
                 }
@@ -225,52 +305,26 @@ impl NonvolatileStorage<'a> {
                                 return ReturnCode::ERESERVE;
                             }
 
+                            // Only one command per app can be outstanding.
+                            if app.pending_command {
+                                return ReturnCode::ENOMEM;
+                            }
+
                             // Shorten the length if the application gave us nowhere to
                             // put it.
-                            let active_len = cmp::min(length, allow_buf_len);
+                            app.command = command;
+                            app.offset = offset;
+                            app.length = cmp::min(length, allow_buf_len);
 
                             // First need to determine if we can execute this or must
                             // queue it.
                             if self.current_user.is_none() {
                                 // No app is currently using the underlying storage.
-                                // Mark this app as active, and then execute the command.
-                                self.current_user
-                                    .set(NonvolatileUser::App { app_id: appid });
-
-                                // Need to copy bytes if this is a write!
-                                if command == NonvolatileCommand::UserspaceWrite {
-                                    app.buffer_write.as_mut().map(|app_buffer| {
-                                        self.buffer.map(|kernel_buffer| {
-                                            // Check that the internal buffer and the buffer that was
-                                            // allowed are long enough.
-                                            let write_len =
-                                                cmp::min(active_len, kernel_buffer.len());
-
-                                            let d = &mut app_buffer.as_mut()[0..write_len];
-                                            for (i, c) in
-                                                kernel_buffer[0..write_len].iter_mut().enumerate()
-                                            {
-                                                *c = d[i];
-                                            }
-                                        });
-                                    });
-                                }
-
-                                self.userspace_call_driver(command, offset, active_len)
+                                self.start_userspace_command(app, appid)
                             } else {
                                 // Some app is using the storage, we must wait.
-                                if app.pending_command == true {
-                                    // No more room in the queue, nowhere to store this
-                                    // request.
-                                    ReturnCode::ENOMEM
-                                } else {
-                                    // We can store this, so lets do it.
-                                    app.pending_command = true;
-                                    app.command = command;
-                                    app.offset = offset;
-                                    app.length = active_len;
-                                    ReturnCode::SUCCESS
-                                }
+                                app.pending_command = true;
+                                ReturnCode::SUCCESS
                             }
                         })
                         .unwrap_or_else(|err| err.into())
@@ -313,22 +367,63 @@ impl NonvolatileStorage<'a> {
         }
     }
 
+    // Run the command stored in `app`. If the app's region is not known yet,
+    // look it up first; the command stays pending and runs afterwards.
+    fn start_userspace_command(&self, app: &mut App, appid: AppId) -> ReturnCode {
+        let result = match app.region {
+            Some(region) => {
+                app.pending_command = false;
+                self.current_user
+                    .set(NonvolatileUser::App { app_id: appid });
+
+                // Need to copy bytes if this is a write!
+                if app.command == NonvolatileCommand::UserspaceWrite {
+                    let length = app.length;
+                    app.buffer_write.as_mut().map(|app_buffer| {
+                        self.buffer.map(|kernel_buffer| {
+                            // Check that the internal buffer and the buffer that was
+                            // allowed are long enough.
+                            let write_len =
+                                cmp::min(cmp::min(length, kernel_buffer.len()), app_buffer.len());
+
+                            let d = &app_buffer.as_ref()[0..write_len];
+                            kernel_buffer[0..write_len].copy_from_slice(d);
+                        });
+                    });
+                }
+
+                self.userspace_call_driver(app.command, region, app.offset, app.length)
+            }
+            None => {
+                app.pending_command = true;
+                self.current_user
+                    .set(NonvolatileUser::RegionTable { app_id: appid });
+                self.read_region_table()
+            }
+        };
+        if result != ReturnCode::SUCCESS {
+            app.pending_command = false;
+            self.current_user.clear();
+        }
+        result
+    }
+
     fn userspace_call_driver(
         &self,
         command: NonvolatileCommand,
+        region: usize,
         offset: usize,
         length: usize,
     ) -> ReturnCode {
         // Calculate where we want to actually read from in the physical
         // storage.
-        let physical_address = offset + self.userspace_start_address;
+        let physical_address = self.region_address(region) + offset;
 
         self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
             // Check that the internal buffer and the buffer that was
             // allowed are long enough.
             let active_len = cmp::min(length, buffer.len());
 
-            // self.current_app.set(Some(appid));
             match command {
                 NonvolatileCommand::UserspaceRead => {
                     self.driver.read(buffer, physical_address, active_len)
@@ -341,7 +436,124 @@ impl NonvolatileStorage<'a> {
         })
     }
 
+    // The physical address of the start of `region`.
+    fn region_address(&self, region: usize) -> usize {
+        self.userspace_start_address + REGION_TABLE_SIZE + region * self.app_region_size
+    }
+
+    fn region_table_length(&self) -> usize {
+        TABLE_HEADER_SIZE + self.app_regions * ENTRY_SIZE
+    }
+
+    fn read_region_table(&self) -> ReturnCode {
+        if self.app_regions == 0 {
+            return ReturnCode::ENOMEM;
+        }
+        let length = self.region_table_length();
+        self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            if buffer.len() < length {
+                self.buffer.replace(buffer);
+                return ReturnCode::ENOMEM;
+            }
+            self.driver
+                .read(buffer, self.userspace_start_address, length)
+        })
+    }
+
+    // Find the region of the app named `name` in the region table in
+    // `buffer`, adding the app to the table if it is not in it. Returns the
+    // region and whether the table was changed and must be written back.
+    fn find_region(&self, buffer: &mut [u8], name: &str) -> Result<(usize, bool), ReturnCode> {
+        if name.is_empty() {
+            return Err(ReturnCode::ENOSUPPORT);
+        }
+        let table = &mut buffer[..self.region_table_length()];
+
+        let mut header = [0; TABLE_HEADER_SIZE];
+        header[..4].copy_from_slice(&TABLE_MAGIC);
+        for i in 0..4 {
+            header[4 + i] = (self.app_region_size >> (8 * i)) as u8;
+        }
+        if table[..TABLE_HEADER_SIZE] != header {
+            // Unused memory or a different layout: start a new table.
+            for byte in table.iter_mut() {
+                *byte = 0;
+            }
+            table[..TABLE_HEADER_SIZE].copy_from_slice(&header);
+        }
+
+        let key = table_entry(name);
+        let mut free = None;
+        for (region, entry) in table[TABLE_HEADER_SIZE..]
+            .chunks_mut(ENTRY_SIZE)
+            .enumerate()
+        {
+            if entry[..] == key[..] {
+                return Ok((region, false));
+            } else if free.is_none() && entry[0] == 0 && entry[1] == 0 {
+                free = Some(region);
+            }
+        }
+        free.map_or(Err(ReturnCode::ENOMEM), |region| {
+            let start = TABLE_HEADER_SIZE + region * ENTRY_SIZE;
+            table[start..start + ENTRY_SIZE].copy_from_slice(&key);
+            Ok((region, true))
+        })
+    }
+
+    // Called when the region table has been read or written for `app_id`.
+    fn region_table_done(&self, buffer: &'static mut [u8], app_id: AppId, written: bool) {
+        match self.find_region(buffer, app_id.get_package_name()) {
+            Ok((region, false)) => {
+                self.buffer.replace(buffer);
+                self.region_found(app_id, Ok(region));
+            }
+            Ok((_, true)) if written => {
+                // The entry we just wrote is not there.
+                self.buffer.replace(buffer);
+                self.region_found(app_id, Err(ReturnCode::FAIL));
+            }
+            Ok((_, true)) => {
+                // Save the new entry before the app uses its region.
+                self.current_user
+                    .set(NonvolatileUser::RegionTable { app_id: app_id });
+                let length = self.region_table_length();
+                let result = self
+                    .driver
+                    .write(buffer, self.userspace_start_address, length);
+                if result != ReturnCode::SUCCESS {
+                    self.current_user.clear();
+                    self.region_found(app_id, Err(result));
+                }
+            }
+            Err(error) => {
+                self.buffer.replace(buffer);
+                self.region_found(app_id, Err(error));
+            }
+        }
+    }
+
+    fn region_found(&self, app_id: AppId, result: Result<usize, ReturnCode>) {
+        let _ = self.apps.enter(app_id, |app, _| match result {
+            Ok(region) => app.region = Some(region),
+            Err(error) => {
+                // The pending command cannot run, report the error instead.
+                app.pending_command = false;
+                let callback = match app.command {
+                    NonvolatileCommand::UserspaceWrite => app.callback_write,
+                    _ => app.callback_read,
+                };
+                callback.map(|mut cb| cb.schedule(0, usize::from(error), 0));
+            }
+        });
+    }
+
     fn check_queue(&self) {
+        // Looking up a region can take more than one operation.
+        if self.current_user.is_some() {
+            return;
+        }
+
         // Check if there are any pending events.
         if self.kernel_pending_command.get() {
             self.kernel_buffer.take().map(|kernel_buffer| {
@@ -367,12 +579,8 @@ impl NonvolatileStorage<'a> {
             for cntr in self.apps.iter() {
                 let started_command = cntr.enter(|app, _| {
                     if app.pending_command {
-                        app.pending_command = false;
-                        self.current_user.set(NonvolatileUser::App {
-                            app_id: app.appid(),
-                        });
-                        self.userspace_call_driver(app.command, app.offset, app.length)
-                            == ReturnCode::SUCCESS
+                        let appid = app.appid();
+                        self.start_userspace_command(app, appid) == ReturnCode::SUCCESS
                     } else {
                         false
                     }
@@ -396,6 +604,9 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStorage<'
                         client.read_done(buffer, length);
                     });
                 }
+                NonvolatileUser::RegionTable { app_id } => {
+                    self.region_table_done(buffer, app_id, false);
+                }
                 NonvolatileUser::App { app_id } => {
                     let _ = self.apps.enter(app_id, move |app, _| {
                         // Need to copy in the contents of the buffer
@@ -430,6 +641,9 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStorage<'
                         client.write_done(buffer, length);
                     });
                 }
+                NonvolatileUser::RegionTable { app_id } => {
+                    self.region_table_done(buffer, app_id, true);
+                }
                 NonvolatileUser::App { app_id } => {
                     let _ = self.apps.enter(app_id, move |app, _| {
                         // Replace the buffer we used to do this write.
@@ -491,6 +705,11 @@ impl Driver for NonvolatileStorage<'a> {
 
     /// Setup callbacks.
     ///
+    /// The callbacks are passed the number of bytes read or written. If the
+    /// app could not be given a region, they are passed 0 and the error:
+    /// ENOSUPPORT if the app has no package name and ENOMEM if all regions
+    /// are in use.
+    ///
     /// ### `subscribe_num`
     ///
     /// - `0`: Setup a read done callback.
@@ -520,9 +739,9 @@ impl Driver for NonvolatileStorage<'a> {
     /// ### `command_num`
     ///
     /// - `0`: Return SUCCESS if this driver is included on the platform.
-    /// - `1`: Return the number of bytes available to userspace.
-    /// - `2`: Start a read from the nonvolatile storage.
-    /// - `3`: Start a write to the nonvolatile_storage.
+    /// - `1`: Return the number of bytes available to each app.
+    /// - `2`: Start a read from the app's region of the nonvolatile storage.
+    /// - `3`: Start a write to the app's region of the nonvolatile_storage.
     fn command(&self, arg0: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
         let command_num = arg0 & 0xFF;
 
@@ -533,9 +752,9 @@ impl Driver for NonvolatileStorage<'a> {
                 ReturnCode::SUCCESS
             }
 
-            // How many bytes are accessible from userspace.
+            // How many bytes are accessible from each app.
             1 => ReturnCode::SuccessWithValue {
-                value: self.userspace_length,
+                value: self.app_region_size,
             },
 
             // Issue a read
@@ -566,3 +785,251 @@ impl Driver for NonvolatileStorage<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::storage_sim::{grant, leak, zeroed, Sim, SimNonvolatileStorage};
+    use kernel::hil::nonvolatile_storage::NonvolatileStorage as _;
+    use std::vec;
+    use std::vec::Vec;
+
+    const STORAGE_SIZE: usize = 4096;
+    const USERSPACE_START: usize = 1024;
+    const USERSPACE_LENGTH: usize = 2048;
+    const REGION_SIZE: usize = 256;
+
+    /// Erased storage, kept across reboots of the drivers booted on it.
+    fn sim() -> &'static Sim<SimNonvolatileStorage<'static>> {
+        Sim::nonvolatile(vec![0xff; STORAGE_SIZE])
+    }
+
+    struct TestClient {
+        buffer: TakeCell<'static, [u8]>,
+        length: Cell<usize>,
+    }
+
+    impl hil::nonvolatile_storage::NonvolatileStorageClient for TestClient {
+        fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.buffer.replace(buffer);
+            self.length.set(length);
+        }
+
+        fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.buffer.replace(buffer);
+            self.length.set(length);
+        }
+    }
+
+    /// The driver over the storage of `sim`, as set up at boot, with the
+    /// kernel given the bytes from `kernel_start` to `kernel_end`.
+    struct Harness {
+        sim: &'static Sim<SimNonvolatileStorage<'static>>,
+        driver: &'static NonvolatileStorage<'static>,
+        client: &'static TestClient,
+    }
+
+    impl Harness {
+        fn boot(
+            sim: &'static Sim<SimNonvolatileStorage<'static>>,
+            kernel_start: usize,
+            kernel_end: usize,
+        ) -> Harness {
+            let grant = grant();
+            let driver = leak(NonvolatileStorage::new(
+                sim.storage,
+                grant,
+                USERSPACE_START,
+                USERSPACE_LENGTH,
+                REGION_SIZE,
+                kernel_start,
+                kernel_end - kernel_start,
+                zeroed(512),
+            ));
+            sim.storage.set_client(driver);
+            let client = leak(TestClient {
+                buffer: TakeCell::new(zeroed(512)),
+                length: Cell::new(0),
+            });
+            driver.set_client(client);
+            Harness {
+                sim: sim,
+                driver: driver,
+                client: client,
+            }
+        }
+
+        fn read(&self, address: usize, length: usize) -> Vec<u8> {
+            let buffer = self.client.buffer.take().unwrap();
+            assert_eq!(
+                self.driver.read(buffer, address, length),
+                ReturnCode::SUCCESS
+            );
+            self.sim.pump();
+            let length = self.client.length.get();
+            self.client
+                .buffer
+                .map(|buffer| buffer[..length].to_vec())
+                .unwrap()
+        }
+
+        fn write(&self, address: usize, data: &[u8]) {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            assert_eq!(
+                self.driver.write(buffer, address, data.len()),
+                ReturnCode::SUCCESS
+            );
+            self.sim.pump();
+            assert_eq!(self.client.length.get(), data.len());
+        }
+
+        /// Looks up the regions of `names` like the driver does when those
+        /// apps first access their storage, and stores the updated table.
+        fn find_regions(&self, names: &[&str]) -> Vec<Result<(usize, bool), ReturnCode>> {
+            let length = self.driver.region_table_length();
+            let mut table = self.read(USERSPACE_START, length);
+            let regions = names
+                .iter()
+                .map(|name| self.driver.find_region(&mut table, name))
+                .collect();
+            self.write(USERSPACE_START, &table);
+            regions
+        }
+    }
+
+    #[test]
+    fn region_table_assigns_a_region_per_package() {
+        let harness = Harness::boot(sim(), 0, STORAGE_SIZE);
+        let regions = USERSPACE_LENGTH / REGION_SIZE - 2;
+        assert_eq!(harness.driver.app_regions, regions);
+
+        // Names longer than the prefix kept in the table are told apart by
+        // their hash.
+        assert_eq!(
+            harness.find_regions(&[
+                "org.tock.examples.sensors",
+                "org.tock.examples.sensors.logger",
+                "org.tock.examples.sensors.display",
+                "org.tock.examples.sensors",
+                "",
+            ]),
+            [
+                Ok((0, true)),
+                Ok((1, true)),
+                Ok((2, true)),
+                Ok((0, false)),
+                Err(ReturnCode::ENOSUPPORT),
+            ]
+        );
+        assert_eq!(
+            harness.find_regions(&["a", "b", "c", "d"]),
+            [
+                Ok((3, true)),
+                Ok((4, true)),
+                Ok((5, true)),
+                Err(ReturnCode::ENOMEM),
+            ]
+        );
+        assert_eq!(harness.find_regions(&["b"]), [Ok((4, false))]);
+    }
+
+    #[test]
+    fn region_table_is_reset_when_the_region_size_changes() {
+        let sim = sim();
+        let harness = Harness::boot(sim, 0, STORAGE_SIZE);
+        assert_eq!(
+            harness.find_regions(&["first", "second"]),
+            [Ok((0, true)), Ok((1, true))]
+        );
+
+        // A new kernel that gives every app less space.
+        let smaller = NonvolatileStorage::new(
+            sim.storage,
+            grant(),
+            USERSPACE_START,
+            USERSPACE_LENGTH,
+            REGION_SIZE / 2,
+            0,
+            STORAGE_SIZE,
+            zeroed(512),
+        );
+        let mut table = harness.read(USERSPACE_START, smaller.region_table_length());
+        assert_eq!(smaller.find_region(&mut table, "second"), Ok((0, true)));
+        assert_eq!(smaller.find_region(&mut table, "first"), Ok((1, true)));
+    }
+
+    #[test]
+    fn rejects_accesses_outside_the_region() {
+        let harness = Harness::boot(sim(), 0, USERSPACE_START);
+        let driver = harness.driver;
+
+        for &command in &[
+            NonvolatileCommand::UserspaceRead,
+            NonvolatileCommand::UserspaceWrite,
+        ] {
+            for &(offset, length) in &[
+                (REGION_SIZE, 1),
+                (REGION_SIZE - 1, 2),
+                (0, REGION_SIZE + 1),
+                (1, usize::max_value()),
+            ] {
+                assert_eq!(
+                    driver.enqueue_command(command, offset, length, None),
+                    ReturnCode::EINVAL
+                );
+            }
+            // Accesses inside the region pass the bounds check, and only
+            // fail here because there is no app.
+            assert_eq!(
+                driver.enqueue_command(command, REGION_SIZE - 16, 16, None),
+                ReturnCode::FAIL
+            );
+        }
+
+        // The kernel may not reach into the userspace memory.
+        for &(address, length) in &[
+            (USERSPACE_START, 1),
+            (USERSPACE_START - 16, 32),
+            (0, USERSPACE_START + 1),
+        ] {
+            let buffer = harness.client.buffer.take().unwrap();
+            assert_eq!(driver.read(buffer, address, length), ReturnCode::EINVAL);
+            let buffer = driver.kernel_buffer.take().unwrap();
+            assert_eq!(driver.write(buffer, address, length), ReturnCode::EINVAL);
+            harness
+                .client
+                .buffer
+                .replace(driver.kernel_buffer.take().unwrap());
+        }
+        harness.write(USERSPACE_START - 16, b"kernel data");
+        assert_eq!(harness.read(USERSPACE_START - 16, 11), b"kernel data");
+    }
+
+    #[test]
+    fn keeps_the_region_of_a_package_across_restarts() {
+        let sim = sim();
+        let harness = Harness::boot(sim, 0, STORAGE_SIZE);
+        assert_eq!(
+            harness.find_regions(&["org.tock.counter", "org.tock.blink"]),
+            [Ok((0, true)), Ok((1, true))]
+        );
+        harness.write(harness.driver.region_address(1), b"blink state");
+
+        let harness = Harness::boot(sim, 0, STORAGE_SIZE);
+        assert_eq!(
+            harness.find_regions(&["org.tock.blink", "org.tock.sensors"]),
+            [Ok((1, false)), Ok((2, true))]
+        );
+        assert_eq!(
+            harness.read(harness.driver.region_address(1), 11),
+            b"blink state"
+        );
+        assert_eq!(
+            harness.driver.region_address(1),
+            USERSPACE_START + REGION_TABLE_SIZE + REGION_SIZE
+        );
+    }
+}
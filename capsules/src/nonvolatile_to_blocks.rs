@@ -0,0 +1,494 @@
+//! Map arbitrary nonvolatile reads and writes to block storage operations.
+//!
+//! This splits reads and writes into reads, writes and erases of whole
+//! blocks of a `hil::block_storage` device, so that users of
+//! `NonvolatileStorage`, such as the nonvolatile storage driver or the FAT
+//! filesystem through `blocks_to_nonvolatile`, can use any block storage.
+//! While it is handling a read or write it returns `EBUSY` to all additional
+//! requests.
+//!
+//! Writes go through a block buffer one write block at a time, or one erase
+//! block at a time on storage that must be erased. A block that is only
+//! partly written is read first. On storage that must be erased, a block is
+//! only erased if the write sets bits that are clear, so appending to erased
+//! storage does not wear it.
+//!
+//! ```plain
+//! hil::nonvolatile_storage::NonvolatileStorage
+//!                ┌─────────────┐
+//!                │             │
+//!                │ This module │
+//!                │             │
+//!                └─────────────┘
+//!       hil::block_storage::BlockStorage
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! The block buffer must hold a read block, and an erase block or, on storage
+//! that is not erased, a write block.
+//!
+//! ```
+//! pub static mut BLOCK_BUFFER: [u8; 4096] = [0; 4096];
+//! let nv_to_blocks = static_init!(
+//!     capsules::nonvolatile_to_blocks::NonvolatileToBlocks<'static, Mx25r6435f>,
+//!     capsules::nonvolatile_to_blocks::NonvolatileToBlocks::new(mx25r6435f, &mut BLOCK_BUFFER)
+//! );
+//! hil::block_storage::BlockStorage::set_client(mx25r6435f, nv_to_blocks);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::hil::block_storage::BlockStorage;
+use kernel::ReturnCode;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    Read,
+    /// Starting to write the next block.
+    Write,
+    /// Reading a block that is partly written.
+    WriteRead,
+    WriteErase,
+    WriteWrite,
+}
+
+pub struct NonvolatileToBlocks<'a, B: BlockStorage> {
+    storage: &'a B,
+    client: OptionalCell<&'static hil::nonvolatile_storage::NonvolatileStorageClient>,
+    block_buffer: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+    /// The user's buffer.
+    buffer: TakeCell<'static, [u8]>,
+    /// Absolute address of where we are reading or writing.
+    address: Cell<usize>,
+    /// Total length to read or write, returned to the client.
+    length: Cell<usize>,
+    /// How many bytes are left to read or write.
+    remaining_length: Cell<usize>,
+    /// Where we are in the user buffer.
+    buffer_index: Cell<usize>,
+    /// Whether the block being written must be erased first.
+    needs_erase: Cell<bool>,
+}
+
+impl<B: BlockStorage> NonvolatileToBlocks<'a, B> {
+    pub fn new(storage: &'a B, block_buffer: &'static mut [u8]) -> NonvolatileToBlocks<'a, B> {
+        NonvolatileToBlocks {
+            storage: storage,
+            client: OptionalCell::empty(),
+            block_buffer: TakeCell::new(block_buffer),
+            state: Cell::new(State::Idle),
+            buffer: TakeCell::empty(),
+            address: Cell::new(0),
+            length: Cell::new(0),
+            remaining_length: Cell::new(0),
+            buffer_index: Cell::new(0),
+            needs_erase: Cell::new(false),
+        }
+    }
+
+    /// Size of the blocks that are written, including their erase.
+    fn write_block_size(&self) -> usize {
+        let geometry = self.storage.geometry();
+        cmp::max(geometry.write_size, geometry.erase_size)
+    }
+
+    fn start(
+        &self,
+        state: State,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if length == 0 || length > buffer.len() {
+            return ReturnCode::EINVAL;
+        }
+        let geometry = self.storage.geometry();
+        let buffer_size = self.block_buffer.map_or(0, |block| block.len());
+        if buffer_size < geometry.read_size || buffer_size < self.write_block_size() {
+            return ReturnCode::ESIZE;
+        }
+
+        self.buffer.replace(buffer);
+        self.state.set(state);
+        self.address.set(address);
+        self.length.set(length);
+        self.remaining_length.set(length);
+        self.buffer_index.set(0);
+        let result = self.step();
+        if result != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+        }
+        result
+    }
+
+    /// Start of the block holding the current address, offset of the
+    /// address in it, and length of the part of the block that is read or
+    /// written.
+    fn next_chunk(&self, block_size: usize) -> (usize, usize, usize) {
+        let address = self.address.get();
+        let offset = address % block_size;
+        let length = cmp::min(block_size - offset, self.remaining_length.get());
+        (address - offset, offset, length)
+    }
+
+    /// Range of whole read blocks covering as much of the rest of a read as
+    /// fits in the block buffer.
+    fn next_read(&self, buffer_size: usize) -> (usize, usize, usize) {
+        let read_size = self.storage.geometry().read_size;
+        let address = self.address.get();
+        let start = address - address % read_size;
+        let end = address + self.remaining_length.get();
+        let end = end + (read_size - end % read_size) % read_size;
+        let span = cmp::min(end - start, buffer_size - buffer_size % read_size);
+        let length = cmp::min(span - (address - start), self.remaining_length.get());
+        (start, span, length)
+    }
+
+    /// Start the next operation on the storage, or finish.
+    fn step(&self) -> ReturnCode {
+        if self.remaining_length.get() == 0 {
+            self.finish(self.length.get());
+            return ReturnCode::SUCCESS;
+        }
+        self.block_buffer
+            .take()
+            .map_or(ReturnCode::ERESERVE, |block| match self.state.get() {
+                State::Read => {
+                    let (start, span, _) = self.next_read(block.len());
+                    self.start_read(block, start, span)
+                }
+                _ => {
+                    let block_size = self.write_block_size();
+                    let (start, _, length) = self.next_chunk(block_size);
+                    if length == block_size {
+                        // A whole block, no need to read it first.
+                        let index = self.buffer_index.get();
+                        self.buffer.map(|buffer| {
+                            block[..length].copy_from_slice(&buffer[index..index + length])
+                        });
+                        let erase = self.storage.geometry().erase_size != 0;
+                        self.needs_erase.set(erase);
+                        self.block_buffer.replace(block);
+                        self.erase_or_write()
+                    } else {
+                        self.state.set(State::WriteRead);
+                        self.start_read(block, start, block_size)
+                    }
+                }
+            })
+    }
+
+    fn start_read(&self, block: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        let (result, block) = self.storage.read(block, address as u64, length);
+        block.map(|block| self.block_buffer.replace(block));
+        result
+    }
+
+    /// Erase the block being written if needed, otherwise write it.
+    fn erase_or_write(&self) -> ReturnCode {
+        let block_size = self.write_block_size();
+        let (start, _, _) = self.next_chunk(block_size);
+        if self.needs_erase.get() {
+            self.state.set(State::WriteErase);
+            return self.storage.erase(start as u64, block_size);
+        }
+        self.state.set(State::WriteWrite);
+        self.block_buffer
+            .take()
+            .map_or(ReturnCode::ERESERVE, |block| {
+                let (result, block) = self.storage.write(block, start as u64, block_size);
+                block.map(|block| self.block_buffer.replace(block));
+                result
+            })
+    }
+
+    fn advance(&self, length: usize) {
+        self.address.set(self.address.get() + length);
+        self.buffer_index.set(self.buffer_index.get() + length);
+        self.remaining_length
+            .set(self.remaining_length.get() - length);
+    }
+
+    /// Continue after an operation on the storage completed.
+    fn proceed(&self, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.finish(0);
+            return;
+        }
+        let result = match self.state.get() {
+            State::WriteErase => {
+                self.needs_erase.set(false);
+                self.erase_or_write()
+            }
+            State::WriteWrite => {
+                let (_, _, length) = self.next_chunk(self.write_block_size());
+                self.advance(length);
+                self.state.set(State::Write);
+                self.step()
+            }
+            _ => self.step(),
+        };
+        if result != ReturnCode::SUCCESS {
+            self.finish(0);
+        }
+    }
+
+    /// Return the user's buffer, with a length of zero after a failure.
+    fn finish(&self, length: usize) {
+        let state = self.state.replace(State::Idle);
+        self.buffer.take().map(move |buffer| {
+            self.client.map(move |client| {
+                if state == State::Read {
+                    client.read_done(buffer, length);
+                } else {
+                    client.write_done(buffer, length);
+                }
+            });
+        });
+    }
+}
+
+impl<B: BlockStorage> hil::block_storage::BlockStorageClient for NonvolatileToBlocks<'a, B> {
+    fn read_done(&self, block: &'static mut [u8], result: ReturnCode) {
+        if result == ReturnCode::SUCCESS {
+            let index = self.buffer_index.get();
+            match self.state.get() {
+                State::Read => {
+                    let (start, _, length) = self.next_read(block.len());
+                    let offset = self.address.get() - start;
+                    self.buffer.map(|buffer| {
+                        buffer[index..index + length]
+                            .copy_from_slice(&block[offset..offset + length])
+                    });
+                    self.advance(length);
+                }
+                State::WriteRead => {
+                    let (_, offset, length) = self.next_chunk(self.write_block_size());
+                    let erase = self.storage.geometry().erase_size != 0;
+                    self.buffer.map(|buffer| {
+                        let old = &mut block[offset..offset + length];
+                        let new = &buffer[index..index + length];
+                        // Erasing is only needed to set bits.
+                        let sets_bits = old.iter().zip(new).any(|(old, new)| new & !old != 0);
+                        self.needs_erase.set(erase && sets_bits);
+                        old.copy_from_slice(new);
+                    });
+                }
+                _ => {}
+            }
+        }
+        self.block_buffer.replace(block);
+        if result == ReturnCode::SUCCESS && self.state.get() == State::WriteRead {
+            if self.erase_or_write() != ReturnCode::SUCCESS {
+                self.finish(0);
+            }
+            return;
+        }
+        self.proceed(result);
+    }
+
+    fn write_done(&self, block: &'static mut [u8], result: ReturnCode) {
+        self.block_buffer.replace(block);
+        self.proceed(result);
+    }
+
+    fn erase_done(&self, result: ReturnCode) {
+        self.proceed(result);
+    }
+}
+
+impl<B: BlockStorage> hil::nonvolatile_storage::NonvolatileStorage for NonvolatileToBlocks<'a, B> {
+    fn set_client(&self, client: &'static hil::nonvolatile_storage::NonvolatileStorageClient) {
+        self.client.set(client);
+    }
+
+    fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        self.start(State::Read, buffer, address, length)
+    }
+
+    fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        self.start(State::Write, buffer, address, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::storage_sim::{leak, zeroed, Sim, SimBlockStorage};
+    use kernel::hil::block_storage::Geometry;
+    use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+    use std::cell::RefCell;
+    use std::vec;
+    use std::vec::Vec;
+
+    const SIZE: usize = 1024;
+
+    /// NOR flash read in blocks of 16 bytes, written in blocks of 64 bytes
+    /// and erased in blocks of 256 bytes.
+    const NOR: Geometry = Geometry {
+        read_size: 16,
+        write_size: 64,
+        erase_size: 256,
+    };
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Read(Vec<u8>),
+        Written(usize),
+    }
+
+    struct TestClient {
+        buffer: TakeCell<'static, [u8]>,
+        events: RefCell<Vec<Event>>,
+    }
+
+    impl NonvolatileStorageClient for TestClient {
+        fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.events
+                .borrow_mut()
+                .push(Event::Read(buffer[..length].to_vec()));
+            self.buffer.replace(buffer);
+        }
+
+        fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.events.borrow_mut().push(Event::Written(length));
+            self.buffer.replace(buffer);
+        }
+    }
+
+    struct Harness {
+        sim: &'static Sim<SimBlockStorage<'static>>,
+        storage: &'static NonvolatileToBlocks<'static, SimBlockStorage<'static>>,
+        client: &'static TestClient,
+    }
+
+    fn pattern(address: usize) -> u8 {
+        (address * 7 + address / 256) as u8
+    }
+
+    impl Harness {
+        fn new(geometry: Geometry, contents: Vec<u8>) -> Harness {
+            let sim = Sim::blocks(contents, geometry);
+            let storage = leak(NonvolatileToBlocks::new(sim.storage, zeroed(512)));
+            sim.storage.set_client(storage);
+            let client = leak(TestClient {
+                buffer: TakeCell::new(zeroed(SIZE)),
+                events: RefCell::new(Vec::new()),
+            });
+            storage.set_client(client);
+            Harness {
+                sim: sim,
+                storage: storage,
+                client: client,
+            }
+        }
+
+        fn event(&self) -> Event {
+            self.sim.pump();
+            let mut events = self.client.events.borrow_mut();
+            assert_eq!(events.len(), 1, "{:?}", *events);
+            events.pop().unwrap()
+        }
+
+        fn read(&self, address: usize, length: usize) -> Vec<u8> {
+            let buffer = self.client.buffer.take().unwrap();
+            assert_eq!(
+                self.storage.read(buffer, address, length),
+                ReturnCode::SUCCESS
+            );
+            match self.event() {
+                Event::Read(data) => data,
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        fn write(&self, address: usize, data: &[u8]) {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            assert_eq!(
+                self.storage.write(buffer, address, data.len()),
+                ReturnCode::SUCCESS
+            );
+            assert_eq!(self.event(), Event::Written(data.len()));
+        }
+
+        fn contents(&self) -> Vec<u8> {
+            self.sim
+                .storage
+                .map_storage(|storage| storage.to_vec())
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn reads_unaligned_ranges() {
+        let h = Harness::new(NOR, (0..SIZE).map(pattern).collect());
+        let expected: Vec<u8> = (10..30).map(pattern).collect();
+        assert_eq!(h.read(10, 20), expected);
+
+        // More than fits in the block buffer at once.
+        let expected: Vec<u8> = (5..SIZE - 3).map(pattern).collect();
+        assert_eq!(h.read(5, SIZE - 8), expected);
+    }
+
+    #[test]
+    fn partial_writes_keep_the_rest_of_the_erase_block() {
+        let h = Harness::new(NOR, (0..SIZE).map(pattern).collect());
+        let data = vec![0x5a; 300];
+        h.write(200, &data);
+
+        let mut expected: Vec<u8> = (0..SIZE).map(pattern).collect();
+        expected[200..500].copy_from_slice(&data);
+        assert_eq!(h.contents(), expected);
+        assert_eq!(h.sim.storage.erase_count(), 2);
+        assert_eq!(h.read(190, 320), &expected[190..510]);
+    }
+
+    #[test]
+    fn writes_that_only_clear_bits_are_not_erased() {
+        let mut contents: Vec<u8> = (0..SIZE).map(pattern).collect();
+        for byte in contents[256..768].iter_mut() {
+            *byte = 0xff;
+        }
+        let h = Harness::new(NOR, contents.clone());
+        h.write(300, &[1, 2, 3, 4, 5]);
+        h.write(305, &[6; 400]);
+        assert_eq!(h.sim.storage.erase_count(), 0);
+
+        // Changing written bytes back needs an erase.
+        h.write(302, &[0xff]);
+        assert_eq!(h.sim.storage.erase_count(), 1);
+
+        contents[300..305].copy_from_slice(&[1, 2, 0xff, 4, 5]);
+        contents[305..705].copy_from_slice(&[6; 400]);
+        assert_eq!(h.contents(), contents);
+    }
+
+    #[test]
+    fn writes_storage_without_erasing() {
+        let geometry = Geometry {
+            read_size: 512,
+            write_size: 512,
+            erase_size: 0,
+        };
+        let h = Harness::new(geometry, (0..2 * SIZE).map(pattern).collect());
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        h.write(100, &data);
+
+        let mut expected: Vec<u8> = (0..2 * SIZE).map(pattern).collect();
+        expected[100..1100].copy_from_slice(&data);
+        assert_eq!(h.contents(), expected);
+        assert_eq!(h.read(0, SIZE), &expected[..SIZE]);
+    }
+}
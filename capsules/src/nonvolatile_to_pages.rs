@@ -262,3 +262,186 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for NonvolatileToPages<'a, F> {
 
     fn erase_complete(&self, _error: hil::flash::Error) {}
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::storage_sim::{leak, zeroed, Sim, SimFlash, SimPage, WriteMode, PAGE_SIZE};
+    use kernel::hil::flash::HasClient;
+    use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+    use std::cell::RefCell;
+    use std::vec;
+    use std::vec::Vec;
+
+    const NUM_PAGES: usize = 4;
+    const SIZE: usize = PAGE_SIZE * NUM_PAGES;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Read(Vec<u8>),
+        Written(usize),
+    }
+
+    struct TestClient {
+        buffer: TakeCell<'static, [u8]>,
+        events: RefCell<Vec<Event>>,
+    }
+
+    impl NonvolatileStorageClient for TestClient {
+        fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.events
+                .borrow_mut()
+                .push(Event::Read(buffer[..length].to_vec()));
+            self.buffer.replace(buffer);
+        }
+
+        fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.events.borrow_mut().push(Event::Written(length));
+            self.buffer.replace(buffer);
+        }
+    }
+
+    /// Storage over flash that erases pages as part of every write, like the
+    /// SAM4L, filled with a known pattern.
+    struct Harness {
+        sim: &'static Sim<SimFlash<'static>>,
+        storage: &'static NonvolatileToPages<'static, SimFlash<'static>>,
+        client: &'static TestClient,
+    }
+
+    fn pattern(address: usize) -> u8 {
+        (address * 7 + address / PAGE_SIZE) as u8
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let sim = Sim::flash((0..SIZE).map(pattern).collect(), WriteMode::EraseOnWrite);
+            let storage = leak(NonvolatileToPages::new(sim.storage, leak(SimPage::new())));
+            sim.storage.set_client(storage);
+            let client = leak(TestClient {
+                buffer: TakeCell::new(zeroed(SIZE)),
+                events: RefCell::new(Vec::new()),
+            });
+            storage.set_client(client);
+            Harness {
+                sim: sim,
+                storage: storage,
+                client: client,
+            }
+        }
+
+        fn event(&self) -> Event {
+            self.sim.pump();
+            let mut events = self.client.events.borrow_mut();
+            assert_eq!(events.len(), 1, "{:?}", *events);
+            events.pop().unwrap()
+        }
+
+        fn read(&self, address: usize, length: usize) -> Vec<u8> {
+            let buffer = self.client.buffer.take().unwrap();
+            assert_eq!(
+                self.storage.read(buffer, address, length),
+                ReturnCode::SUCCESS
+            );
+            match self.event() {
+                Event::Read(data) => data,
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        fn write(&self, address: usize, data: &[u8]) {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            assert_eq!(
+                self.storage.write(buffer, address, data.len()),
+                ReturnCode::SUCCESS
+            );
+            assert_eq!(self.event(), Event::Written(data.len()));
+        }
+
+        fn contents(&self) -> Vec<u8> {
+            self.sim
+                .storage
+                .map_storage(|storage| storage.to_vec())
+                .unwrap()
+        }
+
+        fn erase_counts(&self) -> Vec<u32> {
+            (0..NUM_PAGES)
+                .map(|p| self.sim.storage.erase_count(p))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn reads_within_a_page() {
+        let h = Harness::new();
+        let expected: Vec<u8> = (PAGE_SIZE + 10..PAGE_SIZE + 30).map(pattern).collect();
+        assert_eq!(h.read(PAGE_SIZE + 10, 20), expected);
+    }
+
+    #[test]
+    fn unaligned_reads_span_pages() {
+        let h = Harness::new();
+        let expected: Vec<u8> = (100..100 + 2 * PAGE_SIZE + 50).map(pattern).collect();
+        assert_eq!(h.read(100, 2 * PAGE_SIZE + 50), expected);
+        assert_eq!(h.erase_counts(), vec![0; NUM_PAGES]);
+    }
+
+    #[test]
+    fn aligned_writes_replace_whole_pages() {
+        let h = Harness::new();
+        let data = vec![0x5a; 2 * PAGE_SIZE];
+        h.write(PAGE_SIZE, &data);
+
+        let mut expected: Vec<u8> = (0..SIZE).map(pattern).collect();
+        expected[PAGE_SIZE..3 * PAGE_SIZE].copy_from_slice(&data);
+        assert_eq!(h.contents(), expected);
+        assert_eq!(h.erase_counts(), vec![0, 1, 1, 0]);
+        assert_eq!(h.read(PAGE_SIZE, 2 * PAGE_SIZE), data);
+    }
+
+    #[test]
+    fn unaligned_writes_keep_the_rest_of_each_page() {
+        let h = Harness::new();
+        let data: Vec<u8> = (0..PAGE_SIZE + 100).map(|i| !pattern(i)).collect();
+        let address = PAGE_SIZE - 30;
+        h.write(address, &data);
+
+        let mut expected: Vec<u8> = (0..SIZE).map(pattern).collect();
+        expected[address..address + data.len()].copy_from_slice(&data);
+        assert_eq!(h.contents(), expected);
+        // The first and last pages are partial, the middle one is whole.
+        assert_eq!(h.erase_counts(), vec![1, 1, 1, 0]);
+        assert_eq!(h.read(address, data.len()), data);
+    }
+
+    #[test]
+    fn short_writes_touch_one_page() {
+        let h = Harness::new();
+        h.write(3 * PAGE_SIZE + 5, &[1, 2, 3]);
+
+        let mut expected: Vec<u8> = (0..SIZE).map(pattern).collect();
+        expected[3 * PAGE_SIZE + 5..3 * PAGE_SIZE + 8].copy_from_slice(&[1, 2, 3]);
+        assert_eq!(h.contents(), expected);
+        assert_eq!(h.erase_counts(), vec![0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn operations_are_refused_while_busy() {
+        let h = Harness::new();
+        let buffer = h.client.buffer.take().unwrap();
+        assert_eq!(h.storage.read(buffer, 0, 10), ReturnCode::SUCCESS);
+
+        let other = zeroed(10);
+        assert_eq!(h.storage.write(other, 0, 10), ReturnCode::EBUSY);
+        let other = zeroed(10);
+        assert_eq!(h.storage.read(other, 0, 10), ReturnCode::EBUSY);
+
+        let expected: Vec<u8> = (0..10).map(pattern).collect();
+        assert_eq!(h.event(), Event::Read(expected));
+        assert_eq!(h.read(0, 1), vec![pattern(0)]);
+    }
+}
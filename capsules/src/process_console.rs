@@ -13,6 +13,10 @@
 //!  - 'start n' starts the stopped process with name n
 //!  - 'fault n' forces the process with name n into a fault state
 //!
+//! Another capsule can add its own command by implementing `ConsoleCommand`
+//! and registering with `set_command`; for example, the 802.15.4 sniffer adds
+//! a `sniff` command.
+//!
 //! Setup
 //! -----
 //!
@@ -94,7 +98,7 @@ use core::cell::Cell;
 use core::cmp;
 use core::str;
 use kernel::capabilities::ProcessManagementCapability;
-use kernel::common::cells::TakeCell;
+use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::debug;
 use kernel::hil::uart;
 use kernel::introspection::KernelInfo;
@@ -111,6 +115,17 @@ pub static mut READ_BUF: [u8; 4] = [0; 4];
 // characters, limiting arguments to 25 bytes or so seems fine for now.
 pub static mut COMMAND_BUF: [u8; 32] = [0; 32];
 
+/// A command provided by another capsule, used to configure it from the
+/// console.
+pub trait ConsoleCommand {
+    /// The first word of the command, such as `"sniff"`
+    fn name(&self) -> &'static str;
+    /// A one-line description of the arguments, printed by `help`
+    fn usage(&self) -> &'static str;
+    /// Executes the command with the rest of the line after its name
+    fn execute(&self, args: &str);
+}
+
 pub struct ProcessConsole<'a, C: ProcessManagementCapability> {
     uart: &'a uart::UartData<'a>,
     tx_in_progress: Cell<bool>,
@@ -122,6 +137,7 @@ pub struct ProcessConsole<'a, C: ProcessManagementCapability> {
     running: Cell<bool>,
     kernel: &'static Kernel,
     capability: C,
+    command: OptionalCell<&'a ConsoleCommand>,
 }
 
 impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
@@ -144,9 +160,15 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
             running: Cell::new(false),
             kernel: kernel,
             capability: capability,
+            command: OptionalCell::empty(),
         }
     }
 
+    /// Adds a command provided by another capsule.
+    pub fn set_command(&self, command: &'a ConsoleCommand) {
+        self.command.set(command);
+    }
+
     pub fn start(&self) -> ReturnCode {
         if self.running.get() == false {
             self.rx_buffer.take().map(|buffer| {
@@ -179,9 +201,17 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                 match cmd_str {
                     Ok(s) => {
                         let clean_str = s.trim();
+                        let first_word = clean_str.split_whitespace().next();
+                        let command = self
+                            .command
+                            .map(|c| *c)
+                            .filter(|c| first_word == Some(c.name()));
                         if clean_str.starts_with("help") {
                             debug!("Welcome to the process console.");
                             debug!("Valid commands are: help status list stop start");
+                            self.command.map(|c| debug!("{} {}", c.name(), c.usage()));
+                        } else if let Some(command) = command {
+                            command.execute(clean_str[command.name().len()..].trim());
                         } else if clean_str.starts_with("start") {
                             let argument = clean_str.split_whitespace().nth(1);
                             argument.map(|name| {
@@ -22,10 +22,14 @@ use kernel::hil::spi;
 use kernel::ReturnCode;
 
 use crate::rf233_const::CSMA_SEED_1;
+use crate::rf233_const::CSMA_SEED_1_AACK_DIS_ACK;
+use crate::rf233_const::ED_LEVEL_INVALID;
+use crate::rf233_const::ED_LEVEL_MAX;
 use crate::rf233_const::IRQ_MASK;
 use crate::rf233_const::PHY_CC_CCA_MODE_CS_OR_ED;
 use crate::rf233_const::PHY_RSSI_RX_CRC_VALID;
 use crate::rf233_const::PHY_TX_PWR;
+use crate::rf233_const::RSSI_BASE_VAL;
 use crate::rf233_const::SHORT_ADDR_0;
 use crate::rf233_const::SHORT_ADDR_1;
 use crate::rf233_const::TRX_CTRL_1;
@@ -35,6 +39,7 @@ use crate::rf233_const::TRX_TRAC_CHANNEL_ACCESS_FAILURE;
 use crate::rf233_const::TRX_TRAC_MASK;
 use crate::rf233_const::XAH_CTRL_0;
 use crate::rf233_const::XAH_CTRL_1;
+use crate::rf233_const::XAH_CTRL_1_AACK_PROM_MODE;
 
 const INTERRUPT_ID: usize = 0x2154;
 
@@ -126,8 +131,14 @@ enum InternalState {
     CONFIG_IEEE6_SET,
     CONFIG_IEEE7_SET,
     CONFIG_POWER_SET,
+    CONFIG_CCA_SET,
+    CONFIG_XAH1_SET,
     CONFIG_DONE,
 
+    // Measuring the energy on the channel
+    ED_STARTED,
+    ED_READING,
+
     // RX is a short-lived state for when software has detected
     // the chip is receiving a packet (by internal state) but has
     // not received the interrupt yet. I.e., the SFD has been
@@ -142,6 +153,7 @@ enum InternalState {
     RX_READING_FRAME,      // Reading the packet out of the radio
     RX_READING_FRAME_DONE, // Now read a register to verify FCS
     RX_READING_FRAME_FCS_DONE,
+    RX_READING_ED_DONE,    // Read the energy level of the frame
     RX_ENABLING_RECEPTION, // Re-enabling reception
 }
 
@@ -194,12 +206,17 @@ pub struct RF233<'a, S: spi::SpiMasterDevice> {
     receiving: Cell<bool>,
     spi_busy: Cell<bool>,
     crc_valid: Cell<bool>,
+    rx_lqi_read: Cell<bool>,
+    rx_lqi: Cell<u8>,
+    rx_ed: Cell<u8>,
     interrupt_handling: Cell<bool>,
     interrupt_pending: Cell<bool>,
     config_pending: Cell<bool>,
     sleep_pending: Cell<bool>,
     wake_pending: Cell<bool>,
     power_client_pending: Cell<bool>,
+    ed_pending: Cell<bool>,
+    ed_polls: Cell<u8>,
     reset_pin: &'a gpio::Pin,
     sleep_pin: &'a gpio::Pin,
     irq_pin: &'a gpio::Pin,
@@ -212,11 +229,14 @@ pub struct RF233<'a, S: spi::SpiMasterDevice> {
     rx_client: OptionalCell<&'static radio::RxClient>,
     cfg_client: OptionalCell<&'static radio::ConfigClient>,
     power_client: OptionalCell<&'static radio::PowerClient>,
+    energy_client: OptionalCell<&'static radio::EnergyClient>,
     addr: Cell<u16>,
     addr_long: Cell<[u8; 8]>,
     pan: Cell<u16>,
     tx_power: Cell<i8>,
     channel: Cell<u8>,
+    promiscuous: Cell<bool>,
+    auto_ack: Cell<bool>,
     spi_rx: TakeCell<'static, [u8]>,
     spi_tx: TakeCell<'static, [u8]>,
     spi_buf: TakeCell<'static, [u8]>,
@@ -274,6 +294,29 @@ fn power_to_setting(power: i8) -> u8 {
     }
 }
 
+// The RSSI, in dBm, of a PHY_ED_LEVEL reading
+fn ed_to_rssi(ed: u8) -> i8 {
+    if ed > ED_LEVEL_MAX {
+        -128
+    } else {
+        RSSI_BASE_VAL + ed as i8
+    }
+}
+
+// A PHY_ED_LEVEL reading scaled to 0-255
+fn ed_to_level(ed: u8) -> u8 {
+    if ed > ED_LEVEL_MAX {
+        0
+    } else {
+        (ed as u16 * 255 / ED_LEVEL_MAX as u16) as u8
+    }
+}
+
+// How many times to poll PHY_ED_LEVEL for a finished measurement before
+// giving up. A measurement takes 128us, less than a register read at the
+// SPI clock we use.
+const ED_MAX_POLLS: u8 = 8;
+
 fn interrupt_included(mask: u8, interrupt: InteruptFlags) -> bool {
     let int = interrupt as u8;
     (mask & int) == int
@@ -404,7 +447,10 @@ impl<S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
                 InternalState::RX_TURNING_OFF
                 | InternalState::RX_START_READING
                 | InternalState::RX_READING_FRAME_DONE
-                | InternalState::RX_READING_FRAME_FCS_DONE => {}
+                | InternalState::RX_READING_FRAME_FCS_DONE
+                | InternalState::RX_READING_ED_DONE
+                | InternalState::ED_STARTED
+                | InternalState::ED_READING => {}
                 _ => {
                     self.interrupt_pending.set(false);
                     self.handle_interrupt();
@@ -435,6 +481,20 @@ impl<S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
                         RF233TrxCmd::OFF as u8,
                         InternalState::SLEEP_TRX_OFF,
                     );
+                    if self.ed_pending.get() {
+                        self.ed_pending.set(false);
+                        self.energy_client.map(|c| {
+                            c.energy_detect_done(0, ReturnCode::EOFF);
+                        });
+                    }
+                } else if self.ed_pending.get() {
+                    // Writing any value to PHY_ED_LEVEL starts a measurement
+                    self.ed_polls.set(0);
+                    self.state_transition_write(
+                        RF233Register::PHY_ED_LEVEL,
+                        0,
+                        InternalState::ED_STARTED,
+                    );
                 } else if self.power_client_pending.get() {
                     // fixes bug where client would start transmitting before this state completed
                     self.power_client_pending.set(false);
@@ -516,7 +576,7 @@ impl<S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
             InternalState::START_IRQMASK_SET => {
                 self.state_transition_write(
                     RF233Register::XAH_CTRL_1,
-                    XAH_CTRL_1,
+                    self.xah_ctrl_1(),
                     InternalState::START_XAH1_SET,
                 );
             }
@@ -624,7 +684,7 @@ impl<S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
             InternalState::START_CSMA_0_SEEDED => {
                 self.state_transition_write(
                     RF233Register::CSMA_SEED_1,
-                    CSMA_SEED_1,
+                    self.csma_seed_1(),
                     InternalState::START_CSMA_1_SEEDED,
                 );
             }
@@ -857,7 +917,12 @@ impl<S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
                 {
                     self.state.set(InternalState::RX_READING_FRAME);
                     let rbuf = self.rx_buf.take().unwrap();
-                    self.frame_read(rbuf, frame_len);
+                    // Also read the LQI byte following the frame if it fits
+                    // in both buffers
+                    let with_lqi = (frame_len as usize) < radio::MAX_FRAME_SIZE
+                        && radio::PSDU_OFFSET + frame_len as usize + 1 <= rbuf.len();
+                    self.rx_lqi_read.set(with_lqi);
+                    self.frame_read(rbuf, if with_lqi { frame_len + 1 } else { frame_len });
                 } else if self.transmitting.get() {
                     // Packet was too long and a transmission is pending,
                     // start the transmission
@@ -873,6 +938,11 @@ impl<S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
             }
             InternalState::RX_READING_FRAME => {} // Should never get this state
             InternalState::RX_READING_FRAME_DONE => {
+                if self.rx_lqi_read.get() {
+                    self.rx_buf.map(|rbuf| {
+                        self.rx_lqi.set(rbuf[radio::PSDU_OFFSET + rbuf[1] as usize]);
+                    });
+                }
                 // Now read the PHY_RSSI register to obtain the RX_CRC_VALID bit
                 self.state_transition_read(
                     RF233Register::PHY_RSSI,
@@ -882,6 +952,14 @@ impl<S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
             InternalState::RX_READING_FRAME_FCS_DONE => {
                 // Store whether the CRC was valid, then turn the radio back on.
                 self.crc_valid.set((result & PHY_RSSI_RX_CRC_VALID) != 0);
+                // Then read the energy level measured during reception
+                self.state_transition_read(
+                    RF233Register::PHY_ED_LEVEL,
+                    InternalState::RX_READING_ED_DONE,
+                );
+            }
+            InternalState::RX_READING_ED_DONE => {
+                self.rx_ed.set(result);
                 self.state_transition_write(
                     RF233Register::TRX_STATE,
                     RF233TrxCmd::RX_AACK_ON as u8,
@@ -911,7 +989,23 @@ impl<S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
                 self.rx_client.map(|client| {
                     let rbuf = self.rx_buf.take().unwrap();
                     let frame_len = rbuf[1] as usize - radio::MFR_SIZE;
-                    client.receive(rbuf, frame_len, self.crc_valid.get(), ReturnCode::SUCCESS);
+                    let ed = self.rx_ed.get();
+                    // Without the LQI byte, estimate it from the energy level
+                    let quality = radio::LinkQuality {
+                        rssi: ed_to_rssi(ed),
+                        lqi: if self.rx_lqi_read.get() {
+                            self.rx_lqi.get()
+                        } else {
+                            ed_to_level(ed)
+                        },
+                    };
+                    client.receive(
+                        rbuf,
+                        frame_len,
+                        quality,
+                        self.crc_valid.get(),
+                        ReturnCode::SUCCESS,
+                    );
                 });
             }
 
@@ -1005,6 +1099,20 @@ impl<S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
                 self.state_transition_write(
                     RF233Register::PHY_CC_CCA,
                     val,
+                    InternalState::CONFIG_CCA_SET,
+                );
+            }
+            InternalState::CONFIG_CCA_SET => {
+                self.state_transition_write(
+                    RF233Register::XAH_CTRL_1,
+                    self.xah_ctrl_1(),
+                    InternalState::CONFIG_XAH1_SET,
+                );
+            }
+            InternalState::CONFIG_XAH1_SET => {
+                self.state_transition_write(
+                    RF233Register::CSMA_SEED_1,
+                    self.csma_seed_1(),
                     InternalState::CONFIG_DONE,
                 );
             }
@@ -1015,6 +1123,33 @@ impl<S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
                     c.config_done(ReturnCode::SUCCESS);
                 });
             }
+
+            InternalState::ED_STARTED => {
+                self.state_transition_read(RF233Register::PHY_ED_LEVEL, InternalState::ED_READING);
+            }
+            InternalState::ED_READING => {
+                // PHY_ED_LEVEL reads ED_LEVEL_INVALID until the measurement
+                // completes
+                let polls = self.ed_polls.get() + 1;
+                if result == ED_LEVEL_INVALID && polls < ED_MAX_POLLS {
+                    self.ed_polls.set(polls);
+                    self.state_transition_read(
+                        RF233Register::PHY_ED_LEVEL,
+                        InternalState::ED_READING,
+                    );
+                } else {
+                    self.ed_pending.set(false);
+                    self.state_transition_read(RF233Register::TRX_STATUS, InternalState::READY);
+                    let rval = if result == ED_LEVEL_INVALID {
+                        ReturnCode::FAIL
+                    } else {
+                        ReturnCode::SUCCESS
+                    };
+                    self.energy_client.map(|c| {
+                        c.energy_detect_done(ed_to_level(result), rval);
+                    });
+                }
+            }
         }
     }
 }
@@ -1047,6 +1182,9 @@ impl<S: spi::SpiMasterDevice> RF233<'a, S> {
             receiving: Cell::new(false),
             spi_busy: Cell::new(false),
             crc_valid: Cell::new(false),
+            rx_lqi_read: Cell::new(false),
+            rx_lqi: Cell::new(0),
+            rx_ed: Cell::new(0),
             state: Cell::new(InternalState::START),
             interrupt_handling: Cell::new(false),
             interrupt_pending: Cell::new(false),
@@ -1054,6 +1192,8 @@ impl<S: spi::SpiMasterDevice> RF233<'a, S> {
             sleep_pending: Cell::new(false),
             wake_pending: Cell::new(false),
             power_client_pending: Cell::new(false),
+            ed_pending: Cell::new(false),
+            ed_polls: Cell::new(0),
             tx_buf: TakeCell::empty(),
             rx_buf: TakeCell::empty(),
             tx_len: Cell::new(0),
@@ -1061,17 +1201,36 @@ impl<S: spi::SpiMasterDevice> RF233<'a, S> {
             rx_client: OptionalCell::empty(),
             cfg_client: OptionalCell::empty(),
             power_client: OptionalCell::empty(),
+            energy_client: OptionalCell::empty(),
             addr: Cell::new(0),
             addr_long: Cell::new([0x00; 8]),
             pan: Cell::new(0),
             tx_power: Cell::new(setting_to_power(PHY_TX_PWR)),
             channel: Cell::new(channel),
+            promiscuous: Cell::new(XAH_CTRL_1 & XAH_CTRL_1_AACK_PROM_MODE != 0),
+            auto_ack: Cell::new(CSMA_SEED_1 & CSMA_SEED_1_AACK_DIS_ACK == 0),
             spi_rx: TakeCell::empty(),
             spi_tx: TakeCell::empty(),
             spi_buf: TakeCell::empty(),
         }
     }
 
+    fn xah_ctrl_1(&self) -> u8 {
+        if self.promiscuous.get() {
+            XAH_CTRL_1 | XAH_CTRL_1_AACK_PROM_MODE
+        } else {
+            XAH_CTRL_1 & !XAH_CTRL_1_AACK_PROM_MODE
+        }
+    }
+
+    fn csma_seed_1(&self) -> u8 {
+        if self.auto_ack.get() {
+            CSMA_SEED_1 & !CSMA_SEED_1_AACK_DIS_ACK
+        } else {
+            CSMA_SEED_1 | CSMA_SEED_1_AACK_DIS_ACK
+        }
+    }
+
     fn handle_interrupt(&self) {
         // In most cases, the first thing the driver does on handling an interrupt is
         // read the IRQ status; this pushes most logic to the SPI handler.
@@ -1312,6 +1471,24 @@ impl<S: spi::SpiMasterDevice> radio::RadioConfig for RF233<'a, S> {
         self.channel.get()
     }
 
+    fn get_promiscuous(&self) -> bool {
+        self.promiscuous.get()
+    }
+
+    fn get_auto_ack(&self) -> bool {
+        self.auto_ack.get()
+    }
+
+    fn set_promiscuous(&self, enable: bool) -> ReturnCode {
+        self.promiscuous.set(enable);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_auto_ack(&self, enable: bool) -> ReturnCode {
+        self.auto_ack.set(enable);
+        ReturnCode::SUCCESS
+    }
+
     fn config_commit(&self) {
         let pending = self.config_pending.get();
         if !pending {
@@ -1335,6 +1512,26 @@ impl<S: spi::SpiMasterDevice> radio::RadioConfig for RF233<'a, S> {
     }
 }
 
+impl<S: spi::SpiMasterDevice> radio::RadioEnergyDetect for RF233<'a, S> {
+    fn set_energy_client(&self, client: &'static radio::EnergyClient) {
+        self.energy_client.set(client);
+    }
+
+    fn energy_detect(&self) -> ReturnCode {
+        if !self.radio_on.get() {
+            return ReturnCode::EOFF;
+        } else if self.ed_pending.get() {
+            return ReturnCode::EBUSY;
+        }
+        self.ed_pending.set(true);
+        if self.state.get() == InternalState::READY && !self.spi_busy.get() {
+            self.ed_polls.set(0);
+            self.state_transition_write(RF233Register::PHY_ED_LEVEL, 0, InternalState::ED_STARTED);
+        }
+        ReturnCode::SUCCESS
+    }
+}
+
 impl<S: spi::SpiMasterDevice> radio::RadioData for RF233<'a, S> {
     fn set_transmit_client(&self, client: &'static radio::TxClient) {
         self.tx_client.set(client);
@@ -79,6 +79,7 @@ pub const XAH_CTRL_1_AACK_PROM_MODE: u8 = 1 << 1;
 pub const XAH_CTRL_1_AACK_UPLD_RES_FT: u8 = 1 << 4;
 pub const XAH_CTRL_1_AACK_FLTR_RES_FT: u8 = 1 << 5;
 pub const AACK_FVN_MODE: u8 = 3 << 6;
+pub const CSMA_SEED_1_AACK_DIS_ACK: u8 = 1 << 4;
 
 // Flag combinations that are used in initialization.
 pub const TRX_CTRL_1: u8 =
@@ -97,6 +98,12 @@ pub const TRX_TRAC_MASK: u8 = 0xE0;
 pub const TRX_TRAC_SUCCESS_DATA_PENDING: u8 = 1 << 5;
 pub const TRX_TRAC_CHANNEL_ACCESS_FAILURE: u8 = 3 << 5;
 
+// Energy detection. PHY_ED_LEVEL reads 0 to ED_LEVEL_MAX in 1 dB steps above
+// RSSI_BASE_VAL, or ED_LEVEL_INVALID while a measurement is running.
+pub const RSSI_BASE_VAL: i8 = -94;
+pub const ED_LEVEL_MAX: u8 = 84;
+pub const ED_LEVEL_INVALID: u8 = 0xFF;
+
 // Default address settings.
 pub const PAN_ID_0: u8 = 0x22;
 pub const PAN_ID_1: u8 = 0x22;
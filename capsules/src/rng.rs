@@ -453,9 +453,8 @@ pub struct SynchronousRandom<'a> {
     seed: Cell<u32>,
 }
 
-#[allow(dead_code)]
 impl SynchronousRandom<'a> {
-    fn new(rgen: &'a Rng<'a>) -> SynchronousRandom {
+    pub fn new(rgen: &'a Rng<'a>) -> SynchronousRandom {
         SynchronousRandom {
             rgen: rgen,
             seed: Cell::new(0),
@@ -1,6 +1,17 @@
 //! Provides driver for accessing an SD Card and a userspace Driver.
 //!
 //! This allows initialization and block reads or writes on top of SPI.
+//! Multiple blocks are transferred with a single command (CMD18 and CMD25).
+//! Standard capacity cards are addressed in bytes and high and extended
+//! capacity (SDHC and SDXC) cards in blocks, which is handled here, so callers
+//! always pass block numbers. The card can optionally be asked to check CRCs
+//! on commands and written data, in which case the CRCs of read data are
+//! checked as well.
+//!
+//! `SDCardNonvolatileStorage` provides `hil::nonvolatile_storage` on top of an
+//! SD card, so that it can back the nonvolatile storage driver, and
+//! `SDCardBlockStorage` provides `hil::block_storage`, for example for the
+//! FAT filesystem capsule.
 //!
 //! Usage
 //! -----
@@ -30,6 +41,25 @@
 //!     capsules::sdcard::SDCardDriver::new(sdcard, &mut capsules::sdcard::KERNEL_BUFFER));
 //! sdcard.set_client(sdcard_driver);
 //! ```
+//!
+//! To use the card as nonvolatile storage instead of from userspace:
+//!
+//! ```rust
+//! let sdcard_storage = static_init!(
+//!     capsules::sdcard::SDCardNonvolatileStorage<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::sdcard::SDCardNonvolatileStorage::new(sdcard,
+//!                                                     &mut capsules::sdcard::KERNEL_BUFFER));
+//! sdcard.set_client(sdcard_storage);
+//! ```
+//!
+//! Or as block storage:
+//!
+//! ```rust
+//! let sdcard_block_storage = static_init!(
+//!     capsules::sdcard::SDCardBlockStorage<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::sdcard::SDCardBlockStorage::new(sdcard));
+//! sdcard.set_client(sdcard_block_storage);
+//! ```
 
 // Resources for SD Card API:
 //  * elm-chan.org/docs/mmc/mmc_e.html
@@ -68,6 +98,9 @@ pub struct SDCard<'a, A: hil::time::Alarm> {
 
     is_initialized: Cell<bool>,
     card_type: Cell<SDCardType>,
+    block_count: Cell<u32>,
+    crc_enabled: Cell<bool>,
+    write_multiple: Cell<bool>,
 
     detect_pin: Cell<Option<&'static hil::gpio::Pin>>,
 
@@ -95,6 +128,7 @@ enum SDCmd {
     CMD25_WriteMultiple = 25,             //        Write multiple blocks
     CMD55_ManufSpecificCommand = 55,      // Next command will be manufacturer specific
     CMD58_ReadOCR = 58,                   //              Read operation condition register (OCR)
+    CMD59_CrcOnOff = 59,                  //             Turn CRC checking on or off
     ACMD41_ManufSpecificInit = 0x80 + 41, // Manufacturer specific Init
 }
 
@@ -123,6 +157,7 @@ enum SpiState {
     InitRepeatAppSpecificInit,
     InitRepeatGenericInit,
     InitSetBlocksize,
+    InitEnableCrc,
     InitComplete,
 
     StartReadBlocks { count: u32 },
@@ -131,11 +166,13 @@ enum SpiState {
     WaitReadBlocks { count: u32 },
     ReceivedBlock { count: u32 },
     ReadBlocksComplete,
+    ReadBlocksAborted,
 
     StartWriteBlocks { count: u32 },
-    WriteBlockResponse,
-    WriteBlockBusy,
-    WaitWriteBlockBusy,
+    WriteBlockResponse { count: u32 },
+    WriteBlockBusy { count: u32 },
+    WaitWriteBlockBusy { count: u32 },
+    WriteStopToken,
 }
 
 /// Alarm states
@@ -152,7 +189,7 @@ enum AlarmState {
     WaitForDataBlock,
     WaitForDataBlocks { count: u32 },
 
-    WaitForWriteBusy,
+    WaitForWriteBusy { count: u32 },
 }
 
 /// Error codes returned if an SD card transaction fails
@@ -163,6 +200,7 @@ enum ErrorCode {
     ReadFailure = -3,
     WriteFailure = -4,
     TimeoutFailure = -5,
+    CrcFailure = -6,
 }
 
 /// SD card types, determined during initialization
@@ -179,6 +217,41 @@ enum SDCardType {
 const SUCCESS_STATUS: u8 = 0x00;
 const INITIALIZING_STATUS: u8 = 0x01;
 const DATA_TOKEN: u8 = 0xFE;
+const WRITE_MULTIPLE_TOKEN: u8 = 0xFC;
+const STOP_TRANSMISSION_TOKEN: u8 = 0xFD;
+const BLOCK_SIZE: usize = 512;
+
+/// CRC7 of a command, shifted into place with the end bit set.
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for &byte in data {
+        for bit in (0..8).rev() {
+            let input = (byte >> bit) & 1;
+            let msb = (crc >> 6) & 1;
+            crc = (crc << 1) & 0x7F;
+            if input ^ msb != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    (crc << 1) | 1
+}
+
+/// CRC16-CCITT of a data block, as used by SD cards.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
 
 /// Callback functions from SDCard
 pub trait SDCardClient {
@@ -186,7 +259,9 @@ pub trait SDCardClient {
     fn init_done(&self, block_size: u32, total_size: u64);
     fn read_done(&self, data: &'static mut [u8], len: usize);
     fn write_done(&self, buffer: &'static mut [u8]);
-    fn error(&self, error: u32);
+    /// An operation failed. The buffer of a failed read or write is
+    /// returned.
+    fn error(&self, error: u32, buffer: Option<&'static mut [u8]>);
 }
 
 /// Functions for initializing and accessing an SD card
@@ -232,6 +307,9 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
             alarm_count: Cell::new(0),
             is_initialized: Cell::new(false),
             card_type: Cell::new(SDCardType::Uninitialized),
+            block_count: Cell::new(0),
+            crc_enabled: Cell::new(false),
+            write_multiple: Cell::new(false),
             detect_pin: Cell::new(pin),
             txbuffer: TakeCell::new(txbuffer),
             rxbuffer: TakeCell::new(rxbuffer),
@@ -303,14 +381,9 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
         write_buffer[5] = ((arg >> 8) & 0xFF) as u8;
         write_buffer[6] = ((arg >> 0) & 0xFF) as u8;
 
-        // CRC is ignored except for CMD0 and maybe CMD8
-        // Established practice it to just always use the CMD0 CRC unless we
-        // are sending a CMD8
-        if cmd == SDCmd::CMD8_CheckVoltage {
-            write_buffer[7] = 0x87; // valid crc for CMD8(0x1AA)
-        } else {
-            write_buffer[7] = 0x95; // valid crc for CMD0
-        }
+        // CRC is ignored except for CMD0 and CMD8, unless CRC checking has
+        // been turned on
+        write_buffer[7] = crc7(&write_buffer[2..7]);
 
         // append dummy bytes to transmission after command bytes
         // Limit to minimum length between write_buffer and recv_len
@@ -402,6 +475,90 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
         (r1, r2, r3)
     }
 
+    /// last step of initialization, turns on CRC checking if requested and
+    /// then reads the card specific data (CSD) register
+    fn read_card_specific_data(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: &'static mut [u8],
+    ) {
+        if self.crc_enabled.get() {
+            self.state.set(SpiState::InitEnableCrc);
+            self.send_command(SDCmd::CMD59_CrcOnOff, 0x1, write_buffer, read_buffer, 10);
+        } else {
+            // Note that the receive length needs to be increased here
+            //  to capture the 16-byte register (plus some slack)
+            self.state.set(SpiState::InitComplete);
+            self.send_command(SDCmd::CMD9_ReadCSD, 0x0, write_buffer, read_buffer, 28);
+        }
+    }
+
+    /// whether the CRC of a block received into the read buffer is correct,
+    /// or CRC checking is off
+    fn block_crc_valid(&self, read_buffer: &[u8]) -> bool {
+        if !self.crc_enabled.get() {
+            return true;
+        }
+        let crc = (read_buffer[BLOCK_SIZE] as u16) << 8 | read_buffer[BLOCK_SIZE + 1] as u16;
+        crc16(&read_buffer[..BLOCK_SIZE]) == crc
+    }
+
+    /// send the next block of a write from the client buffer
+    fn send_data_block(
+        &self,
+        count: u32,
+        write_buffer: &'static mut [u8],
+        read_buffer: &'static mut [u8],
+    ) {
+        let offset = self.client_offset.get();
+        let bytes_written = self.client_buffer.map_or(0, |buffer| {
+            // copy over data from client buffer
+            // Limit to minimum length between write_buffer, buffer, and 512
+            // (block size)
+            for (write_byte, &client_byte) in write_buffer
+                .iter_mut()
+                .skip(1)
+                .zip(buffer.iter().skip(offset))
+                .take(BLOCK_SIZE)
+            {
+                *write_byte = client_byte;
+            }
+
+            // calculate number of bytes written
+            cmp::min(buffer.len().saturating_sub(offset), BLOCK_SIZE)
+        });
+
+        // set a known value for remaining bytes
+        for write_byte in write_buffer
+            .iter_mut()
+            .skip(1)
+            .skip(bytes_written)
+            .take(BLOCK_SIZE - bytes_written)
+        {
+            *write_byte = 0xFF;
+        }
+
+        // set up remainder of data packet
+        write_buffer[0] = if self.write_multiple.get() {
+            WRITE_MULTIPLE_TOKEN
+        } else {
+            DATA_TOKEN
+        };
+        let crc = if self.crc_enabled.get() {
+            crc16(&write_buffer[1..BLOCK_SIZE + 1])
+        } else {
+            0xFFFF // dummy CRC
+        };
+        write_buffer[BLOCK_SIZE + 1] = (crc >> 8) as u8;
+        write_buffer[BLOCK_SIZE + 2] = crc as u8;
+
+        // write data packet
+        self.client_offset.set(offset + BLOCK_SIZE);
+        self.state
+            .set(SpiState::WriteBlockResponse { count: count });
+        self.write_bytes(write_buffer, read_buffer, BLOCK_SIZE + 3);
+    }
+
     /// updates SD card state on SPI transaction returns
     fn process_spi_states(
         &self,
@@ -446,7 +603,10 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
+                        client.error(
+                            ErrorCode::InitializationFailure as u32,
+                            self.client_buffer.take(),
+                        );
                     });
                 }
             }
@@ -517,7 +677,10 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
+                        client.error(
+                            ErrorCode::InitializationFailure as u32,
+                            self.client_buffer.take(),
+                        );
                     });
                 }
             }
@@ -528,16 +691,21 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
 
                 if r1 == SUCCESS_STATUS {
                     if (r7 & 0x40000000) != 0x00000000 {
+                        // high capacity cards always use 512 byte blocks
                         self.card_type.set(SDCardType::SDv2BlockAddressable);
+                        self.read_card_specific_data(write_buffer, read_buffer);
                     } else {
+                        // standard capacity cards need their blocksize set
                         self.card_type.set(SDCardType::SDv2);
+                        self.state.set(SpiState::InitSetBlocksize);
+                        self.send_command(
+                            SDCmd::CMD16_SetBlockSize,
+                            512,
+                            write_buffer,
+                            read_buffer,
+                            10,
+                        );
                     }
-
-                    // Read CSD register
-                    // Note that the receive length needs to be increased here
-                    //  to capture the 16-byte register (plus some slack)
-                    self.state.set(SpiState::InitComplete);
-                    self.send_command(SDCmd::CMD9_ReadCSD, 0x0, write_buffer, read_buffer, 28);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -546,7 +714,10 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
+                        client.error(
+                            ErrorCode::InitializationFailure as u32,
+                            self.client_buffer.take(),
+                        );
                     });
                 }
             }
@@ -614,7 +785,10 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
+                        client.error(
+                            ErrorCode::InitializationFailure as u32,
+                            self.client_buffer.take(),
+                        );
                     });
                 }
             }
@@ -653,7 +827,10 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
+                        client.error(
+                            ErrorCode::InitializationFailure as u32,
+                            self.client_buffer.take(),
+                        );
                     });
                 }
             }
@@ -662,10 +839,30 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                 // check response
                 let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
 
+                if r1 == SUCCESS_STATUS {
+                    self.read_card_specific_data(write_buffer, read_buffer);
+                } else {
+                    // error, send callback and quit
+                    self.txbuffer.replace(write_buffer);
+                    self.rxbuffer.replace(read_buffer);
+                    self.state.set(SpiState::Idle);
+                    self.alarm_state.set(AlarmState::Idle);
+                    self.alarm_count.set(0);
+                    self.client.map(move |client| {
+                        client.error(
+                            ErrorCode::InitializationFailure as u32,
+                            self.client_buffer.take(),
+                        );
+                    });
+                }
+            }
+
+            SpiState::InitEnableCrc => {
+                // check response
+                let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
+
                 if r1 == SUCCESS_STATUS {
                     // Read CSD register
-                    // Note that the receive length needs to be increased here
-                    //  to capture the 16-byte register (plus some slack)
                     self.state.set(SpiState::InitComplete);
                     self.send_command(SDCmd::CMD9_ReadCSD, 0x0, write_buffer, read_buffer, 28);
                 } else {
@@ -676,7 +873,10 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
+                        client.error(
+                            ErrorCode::InitializationFailure as u32,
+                            self.client_buffer.take(),
+                        );
                     });
                 }
             }
@@ -724,6 +924,8 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
 
                     // initialization complete
                     self.state.set(SpiState::Idle);
+                    self.block_count
+                        .set(cmp::min(total_size / 512, u32::max_value() as u64) as u32);
                     self.is_initialized.set(true);
 
                     // perform callback
@@ -738,7 +940,10 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
+                        client.error(
+                            ErrorCode::InitializationFailure as u32,
+                            self.client_buffer.take(),
+                        );
                     });
                 }
             }
@@ -765,7 +970,7 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
+                        client.error(ErrorCode::ReadFailure as u32, self.client_buffer.take());
                     });
                 }
             }
@@ -796,18 +1001,26 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
+                        client.error(ErrorCode::ReadFailure as u32, self.client_buffer.take());
                     });
                 }
             }
 
             SpiState::ReadBlockComplete => {
+                let crc_valid = self.block_crc_valid(read_buffer);
+
                 // replace buffers
                 self.txbuffer.replace(write_buffer);
                 self.rxbuffer.replace(read_buffer);
 
                 // read finished, perform callback
                 self.state.set(SpiState::Idle);
+                if !crc_valid {
+                    self.client.map(move |client| {
+                        client.error(ErrorCode::CrcFailure as u32, self.client_buffer.take());
+                    });
+                    return;
+                }
                 self.rxbuffer.map(|read_buffer| {
                     self.client_buffer.take().map(move |buffer| {
                         // copy data to user buffer
@@ -855,12 +1068,19 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
+                        client.error(ErrorCode::ReadFailure as u32, self.client_buffer.take());
                     });
                 }
             }
 
             SpiState::ReceivedBlock { count } => {
+                if !self.block_crc_valid(read_buffer) {
+                    // stop the transfer before reporting the error
+                    self.state.set(SpiState::ReadBlocksAborted);
+                    self.send_command(SDCmd::CMD12_StopRead, 0x0, write_buffer, read_buffer, 10);
+                    return;
+                }
+
                 // copy block over to client buffer
                 self.client_buffer.map(|buffer| {
                     // copy block into client buffer
@@ -895,7 +1115,9 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
 
             SpiState::ReadBlocksComplete => {
                 // check response
-                let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
+                // The card keeps sending data while the command is sent, and
+                //  the byte following it is a stuff byte, so skip both
+                let (r1, _, _) = self.get_response(SDResponse::R1_Status, &read_buffer[9..]);
 
                 if r1 == SUCCESS_STATUS {
                     // replace buffers
@@ -917,62 +1139,29 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
+                        client.error(ErrorCode::ReadFailure as u32, self.client_buffer.take());
                     });
                 }
             }
 
+            SpiState::ReadBlocksAborted => {
+                // replace buffers
+                self.txbuffer.replace(write_buffer);
+                self.rxbuffer.replace(read_buffer);
+                self.state.set(SpiState::Idle);
+                self.alarm_state.set(AlarmState::Idle);
+                self.alarm_count.set(0);
+                self.client.map(move |client| {
+                    client.error(ErrorCode::CrcFailure as u32, self.client_buffer.take());
+                });
+            }
+
             SpiState::StartWriteBlocks { count } => {
                 // check response
                 let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
 
                 if r1 == SUCCESS_STATUS {
-                    if count <= 1 {
-                        let bytes_written = self.client_buffer.map_or(0, |buffer| {
-                            // copy over data from client buffer
-                            // Limit to minimum length between write_buffer,
-                            // buffer, and 512 (block size)
-                            for (write_byte, &client_byte) in
-                                write_buffer.iter_mut().skip(1).zip(buffer.iter()).take(512)
-                            {
-                                *write_byte = client_byte;
-                            }
-
-                            // calculate number of bytes written
-                            cmp::min(write_buffer.len(), cmp::min(buffer.len(), 512))
-                        });
-
-                        // set a known value for remaining bytes
-                        for write_byte in write_buffer
-                            .iter_mut()
-                            .skip(1)
-                            .skip(bytes_written)
-                            .take(512)
-                        {
-                            *write_byte = 0xFF;
-                        }
-
-                        // set up remainder of data packet
-                        write_buffer[0] = DATA_TOKEN; // Data token
-                        write_buffer[513] = 0xFF; // dummy CRC
-                        write_buffer[514] = 0xFF; // dummy CRC
-
-                        // write data packet
-                        self.state.set(SpiState::WriteBlockResponse);
-                        self.write_bytes(write_buffer, read_buffer, 515);
-                    } else {
-                        // multi-block SD card writes are unimplemented
-                        // This should have returned an error already, but if
-                        // we got here somehow, return an error and quit now
-                        self.txbuffer.replace(write_buffer);
-                        self.rxbuffer.replace(read_buffer);
-                        self.state.set(SpiState::Idle);
-                        self.alarm_state.set(AlarmState::Idle);
-                        self.alarm_count.set(0);
-                        self.client.map(move |client| {
-                            client.error(ErrorCode::WriteFailure as u32);
-                        });
-                    }
+                    self.send_data_block(count, write_buffer, read_buffer);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -981,23 +1170,33 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::WriteFailure as u32);
+                        client.error(ErrorCode::WriteFailure as u32, self.client_buffer.take());
                     });
                 }
             }
 
-            SpiState::WriteBlockResponse => {
+            SpiState::WriteBlockResponse { count } => {
                 // Get data packet
-                self.state.set(SpiState::WriteBlockBusy);
+                self.state.set(SpiState::WriteBlockBusy { count: count });
                 self.read_bytes(write_buffer, read_buffer, 1);
             }
 
-            SpiState::WriteBlockBusy => {
-                if (read_buffer[0] & 0x1F) == 0x05 {
+            SpiState::WriteBlockBusy { count } => {
+                let response = read_buffer[0] & 0x1F;
+                if response == 0x05 {
                     // check if sd card is busy
-                    self.state.set(SpiState::WaitWriteBlockBusy);
+                    self.state
+                        .set(SpiState::WaitWriteBlockBusy { count: count });
                     self.read_bytes(write_buffer, read_buffer, 1);
                 } else {
+                    // data rejected, either because of a CRC error (0x0B) or
+                    //  a write error (0x0D)
+                    let error = if response == 0x0B {
+                        ErrorCode::CrcFailure
+                    } else {
+                        ErrorCode::WriteFailure
+                    };
+
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
@@ -1005,39 +1204,58 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(ErrorCode::WriteFailure as u32);
+                        client.error(error as u32, self.client_buffer.take());
                     });
                 }
             }
 
-            SpiState::WaitWriteBlockBusy => {
+            SpiState::WaitWriteBlockBusy { count } => {
                 // check if line is still held low (busy state)
                 if read_buffer[0] != 0x00 {
-                    // replace buffers
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-
-                    // read finished, perform callback
-                    self.state.set(SpiState::Idle);
                     self.alarm_count.set(0);
-                    self.client_buffer.take().map(move |buffer| {
-                        self.client.map(move |client| {
-                            client.write_done(buffer);
+                    if count > 1 {
+                        // send the next block
+                        self.send_data_block(count - 1, write_buffer, read_buffer);
+                    } else if count == 1 && self.write_multiple.get() {
+                        // all blocks written. Terminate multiple write, which
+                        //  keeps the card busy once more
+                        write_buffer[0] = STOP_TRANSMISSION_TOKEN;
+                        write_buffer[1] = 0xFF;
+                        self.state.set(SpiState::WriteStopToken);
+                        self.write_bytes(write_buffer, read_buffer, 2);
+                    } else {
+                        // replace buffers
+                        self.txbuffer.replace(write_buffer);
+                        self.rxbuffer.replace(read_buffer);
+
+                        // write finished, perform callback
+                        self.state.set(SpiState::Idle);
+                        self.client_buffer.take().map(move |buffer| {
+                            self.client.map(move |client| {
+                                client.write_done(buffer);
+                            });
                         });
-                    });
+                    }
                 } else {
                     // replace buffers
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
 
                     // try again after 1 ms
-                    self.alarm_state.set(AlarmState::WaitForWriteBusy);
+                    self.alarm_state
+                        .set(AlarmState::WaitForWriteBusy { count: count });
                     let interval = (1 as u32) * <A::Frequency>::frequency() / 1000;
                     let tics = self.alarm.now().wrapping_add(interval);
                     self.alarm.set_alarm(tics);
                 }
             }
 
+            SpiState::WriteStopToken => {
+                // wait for the card to finish
+                self.state.set(SpiState::WaitWriteBlockBusy { count: 0 });
+                self.read_bytes(write_buffer, read_buffer, 1);
+            }
+
             SpiState::Idle => {
                 // receiving an event from Idle means something was killed
 
@@ -1058,7 +1276,7 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
             self.alarm_state.set(AlarmState::Idle);
             self.alarm_count.set(0);
             self.client.map(move |client| {
-                client.error(ErrorCode::TimeoutFailure as u32);
+                client.error(ErrorCode::TimeoutFailure as u32, self.client_buffer.take());
             });
         } else {
             self.alarm_count.set(repeats + 1);
@@ -1162,12 +1380,13 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                 self.alarm_state.set(AlarmState::Idle);
             }
 
-            AlarmState::WaitForWriteBusy => {
+            AlarmState::WaitForWriteBusy { count } => {
                 // check card initialization again
                 self.txbuffer.take().map(|write_buffer| {
                     self.rxbuffer.take().map(move |read_buffer| {
                         // check if sd card is busy
-                        self.state.set(SpiState::WaitWriteBlockBusy);
+                        self.state
+                            .set(SpiState::WaitWriteBlockBusy { count: count });
                         self.read_bytes(write_buffer, read_buffer, 1);
                     });
                 });
@@ -1229,104 +1448,144 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
         }
     }
 
-    pub fn read_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
-        // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer.take().map_or(ReturnCode::ENOMEM, |txbuffer| {
-                    self.rxbuffer
-                        .take()
-                        .map_or(ReturnCode::ENOMEM, move |rxbuffer| {
-                            // save the user buffer for later
-                            self.client_buffer.replace(buffer);
-                            self.client_offset.set(0);
-
-                            // convert block address to byte address for non-block
-                            //  access cards
-                            let mut address = sector;
-                            if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                                address *= 512;
-                            }
+    /// Ask the card to check CRCs of commands and written data, and check the
+    /// CRCs of read data. Takes effect when the card is next initialized.
+    pub fn set_crc_enabled(&self, enabled: bool) {
+        self.crc_enabled.set(enabled);
+    }
 
-                            self.state.set(SpiState::StartReadBlocks { count: count });
-                            if count == 1 {
-                                self.send_command(
-                                    SDCmd::CMD17_ReadSingle,
-                                    address,
-                                    txbuffer,
-                                    rxbuffer,
-                                    10,
-                                );
-                            } else {
-                                self.send_command(
-                                    SDCmd::CMD18_ReadMultiple,
-                                    address,
-                                    txbuffer,
-                                    rxbuffer,
-                                    10,
-                                );
-                            }
+    /// Number of 512 byte blocks on the card, once initialized
+    pub fn block_count(&self) -> u32 {
+        self.block_count.get()
+    }
 
-                            // command started successfully
-                            ReturnCode::SUCCESS
-                        })
-                })
-            } else {
-                // sd card not initialized
-                ReturnCode::ERESERVE
-            }
-        } else {
+    /// check that a transfer can start now and fits on the card
+    fn check_transfer(&self, buffer_len: usize, sector: u32, count: u32) -> ReturnCode {
+        if !self.is_installed() {
             // sd card not installed
             ReturnCode::EUNINSTALLED
+        } else if !self.is_initialized() {
+            // sd card not initialized
+            ReturnCode::ERESERVE
+        } else if count == 0
+            || (buffer_len as u64) < count as u64 * BLOCK_SIZE as u64
+            || sector as u64 + count as u64 > self.block_count.get() as u64
+        {
+            ReturnCode::EINVAL
+        } else if self.state.get() != SpiState::Idle || self.txbuffer.is_none() {
+            ReturnCode::ENOMEM
+        } else {
+            ReturnCode::SUCCESS
         }
     }
 
-    pub fn write_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
-        // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer.take().map_or(ReturnCode::ENOMEM, |txbuffer| {
-                    self.rxbuffer
-                        .take()
-                        .map_or(ReturnCode::ENOMEM, move |rxbuffer| {
-                            // save the user buffer for later
-                            self.client_buffer.replace(buffer);
-                            self.client_offset.set(0);
-
-                            // convert block address to byte address for non-block
-                            //  access cards
-                            let mut address = sector;
-                            if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                                address *= 512;
-                            }
-
-                            self.state.set(SpiState::StartWriteBlocks { count: count });
-                            if count == 1 {
-                                self.send_command(
-                                    SDCmd::CMD24_WriteSingle,
-                                    address,
-                                    txbuffer,
-                                    rxbuffer,
-                                    10,
-                                );
-
-                                // command started successfully
-                                ReturnCode::SUCCESS
-                            } else {
-                                // can't write multiple blocks yet
-                                ReturnCode::ENOSUPPORT
-                            }
-                        })
-                })
-            } else {
-                // sd card not initialized
-                ReturnCode::ERESERVE
-            }
+    /// convert block address to byte address for non-block access cards
+    fn card_address(&self, sector: u32) -> u32 {
+        if self.card_type.get() == SDCardType::SDv2BlockAddressable {
+            sector
         } else {
-            // sd card not installed
-            ReturnCode::EUNINSTALLED
+            sector * BLOCK_SIZE as u32
         }
     }
+
+    /// Read `count` blocks starting at block `sector` into `buffer`. The
+    /// buffer is returned if the read could not start.
+    pub fn read_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let result = self.check_transfer(buffer.len(), sector, count);
+        if result != ReturnCode::SUCCESS {
+            return (result, Some(buffer));
+        }
+
+        self.txbuffer
+            .take()
+            .map_or((ReturnCode::ENOMEM, None), |txbuffer| {
+                self.rxbuffer
+                    .take()
+                    .map_or((ReturnCode::ENOMEM, None), move |rxbuffer| {
+                        // save the user buffer for later
+                        self.client_buffer.replace(buffer);
+                        self.client_offset.set(0);
+
+                        let address = self.card_address(sector);
+                        self.state.set(SpiState::StartReadBlocks { count: count });
+                        if count == 1 {
+                            self.send_command(
+                                SDCmd::CMD17_ReadSingle,
+                                address,
+                                txbuffer,
+                                rxbuffer,
+                                10,
+                            );
+                        } else {
+                            self.send_command(
+                                SDCmd::CMD18_ReadMultiple,
+                                address,
+                                txbuffer,
+                                rxbuffer,
+                                10,
+                            );
+                        }
+
+                        // command started successfully
+                        (ReturnCode::SUCCESS, None)
+                    })
+            })
+    }
+
+    /// Write `count` blocks from `buffer` starting at block `sector`. The
+    /// buffer is returned if the write could not start.
+    pub fn write_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let result = self.check_transfer(buffer.len(), sector, count);
+        if result != ReturnCode::SUCCESS {
+            return (result, Some(buffer));
+        }
+
+        self.txbuffer
+            .take()
+            .map_or((ReturnCode::ENOMEM, None), |txbuffer| {
+                self.rxbuffer
+                    .take()
+                    .map_or((ReturnCode::ENOMEM, None), move |rxbuffer| {
+                        // save the user buffer for later
+                        self.client_buffer.replace(buffer);
+                        self.client_offset.set(0);
+
+                        let address = self.card_address(sector);
+                        self.state.set(SpiState::StartWriteBlocks { count: count });
+                        self.write_multiple.set(count > 1);
+                        if count == 1 {
+                            self.send_command(
+                                SDCmd::CMD24_WriteSingle,
+                                address,
+                                txbuffer,
+                                rxbuffer,
+                                10,
+                            );
+                        } else {
+                            self.send_command(
+                                SDCmd::CMD25_WriteMultiple,
+                                address,
+                                txbuffer,
+                                rxbuffer,
+                                10,
+                            );
+                        }
+
+                        // command started successfully
+                        (ReturnCode::SUCCESS, None)
+                    })
+            })
+    }
 }
 
 /// Handle callbacks from the SPI peripheral
@@ -1361,7 +1620,10 @@ impl<A: hil::time::Alarm> hil::gpio::Client for SDCard<'a, A> {
             self.state.set(SpiState::Idle);
             self.alarm_state.set(AlarmState::Idle);
             self.client.map(move |client| {
-                client.error(ErrorCode::CardStateChanged as u32);
+                client.error(
+                    ErrorCode::CardStateChanged as u32,
+                    self.client_buffer.take(),
+                );
             });
         }
 
@@ -1478,7 +1740,8 @@ impl<A: hil::time::Alarm> SDCardClient for SDCardDriver<'a, A> {
         });
     }
 
-    fn error(&self, error: u32) {
+    fn error(&self, error: u32, buffer: Option<&'static mut [u8]>) {
+        buffer.map(|buffer| self.kernel_buf.replace(buffer));
         self.app.map(|app| {
             app.callback.map(|mut cb| {
                 cb.schedule(4, error as usize, 0);
@@ -1548,7 +1811,9 @@ impl<A: hil::time::Alarm> Driver for SDCardDriver<'a, A> {
                 .kernel_buf
                 .take()
                 .map_or(ReturnCode::EBUSY, |kernel_buf| {
-                    self.sdcard.read_blocks(kernel_buf, data as u32, 1)
+                    let (result, buffer) = self.sdcard.read_blocks(kernel_buf, data as u32, 1);
+                    buffer.map(|buffer| self.kernel_buf.replace(buffer));
+                    result
                 }),
 
             // write_block
@@ -1570,7 +1835,10 @@ impl<A: hil::time::Alarm> Driver for SDCardDriver<'a, A> {
                                     }
 
                                     // begin writing
-                                    self.sdcard.write_blocks(kernel_buf, data as u32, 1)
+                                    let (result, buffer) =
+                                        self.sdcard.write_blocks(kernel_buf, data as u32, 1);
+                                    buffer.map(|buffer| self.kernel_buf.replace(buffer));
+                                    result
                                 })
                         })
                 })
@@ -1580,3 +1848,472 @@ impl<A: hil::time::Alarm> Driver for SDCardDriver<'a, A> {
         }
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StorageState {
+    Idle,
+    Initializing,
+    ReadBlock,
+    ModifyBlock,
+    WriteBlock,
+    Direct,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StorageOperation {
+    Read,
+    Write,
+}
+
+/// Nonvolatile storage on an SD card, layers on top of SD Card capsule
+///
+/// Reads and writes are split into blocks. Partial blocks are read into the
+/// block buffer, and for writes modified and written back. Runs of whole
+/// blocks are transferred directly to or from the client buffer with a single
+/// command where possible. The card is initialized on first use. Addresses
+/// are limited to the first 4 GiB of the card.
+pub struct SDCardNonvolatileStorage<'a, A: hil::time::Alarm> {
+    sdcard: &'a SDCard<'a, A>,
+    client: OptionalCell<&'static hil::nonvolatile_storage::NonvolatileStorageClient>,
+    block_buffer: TakeCell<'static, [u8]>,
+    buffer: TakeCell<'static, [u8]>,
+    state: Cell<StorageState>,
+    operation: Cell<StorageOperation>,
+    /// Absolute address of where we are reading or writing.
+    address: Cell<usize>,
+    /// Total length to read or write, returned to the client.
+    length: Cell<usize>,
+    /// How many bytes are left to read or write.
+    remaining_length: Cell<usize>,
+    /// Where we are in the client buffer.
+    buffer_index: Cell<usize>,
+}
+
+impl<A: hil::time::Alarm> SDCardNonvolatileStorage<'a, A> {
+    /// Create nonvolatile storage on an SD card
+    ///
+    /// sdcard - SDCard interface to store data on
+    /// block_buffer - buffer used to hold partially read or written blocks
+    pub fn new(
+        sdcard: &'a SDCard<'a, A>,
+        block_buffer: &'static mut [u8; 512],
+    ) -> SDCardNonvolatileStorage<'a, A> {
+        SDCardNonvolatileStorage {
+            sdcard: sdcard,
+            client: OptionalCell::empty(),
+            block_buffer: TakeCell::new(block_buffer),
+            buffer: TakeCell::empty(),
+            state: Cell::new(StorageState::Idle),
+            operation: Cell::new(StorageOperation::Read),
+            address: Cell::new(0),
+            length: Cell::new(0),
+            remaining_length: Cell::new(0),
+            buffer_index: Cell::new(0),
+        }
+    }
+
+    fn start(
+        &self,
+        operation: StorageOperation,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> ReturnCode {
+        if self.state.get() != StorageState::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if length == 0 || length > buffer.len() {
+            return ReturnCode::EINVAL;
+        }
+        if self.block_buffer.is_none() {
+            return ReturnCode::ERESERVE;
+        }
+
+        self.buffer.replace(buffer);
+        self.operation.set(operation);
+        self.address.set(address);
+        self.length.set(length);
+        self.remaining_length.set(length);
+        self.buffer_index.set(0);
+
+        let result = if self.sdcard.is_initialized() {
+            self.step()
+        } else {
+            self.state.set(StorageState::Initializing);
+            self.sdcard.initialize()
+        };
+        if result != ReturnCode::SUCCESS {
+            self.state.set(StorageState::Idle);
+        }
+        result
+    }
+
+    /// block, offset in the block, and length of the part of the next block
+    /// that is read or written
+    fn next_chunk(&self) -> (u32, usize, usize) {
+        let address = self.address.get();
+        let offset = address % BLOCK_SIZE;
+        let length = cmp::min(BLOCK_SIZE - offset, self.remaining_length.get());
+        ((address / BLOCK_SIZE) as u32, offset, length)
+    }
+
+    fn advance(&self, length: usize) {
+        self.address.set(self.address.get() + length);
+        self.buffer_index.set(self.buffer_index.get() + length);
+        self.remaining_length
+            .set(self.remaining_length.get() - length);
+    }
+
+    /// start the next transfer with the SD card, or finish
+    fn step(&self) -> ReturnCode {
+        let remaining = self.remaining_length.get();
+        if remaining == 0 {
+            self.finish(self.length.get());
+            return ReturnCode::SUCCESS;
+        }
+
+        let (sector, offset, length) = self.next_chunk();
+        if offset == 0 && self.buffer_index.get() == 0 && remaining >= BLOCK_SIZE {
+            // whole blocks straight from or into the client buffer
+            let count = (remaining / BLOCK_SIZE) as u32;
+            self.state.set(StorageState::Direct);
+            return self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+                let (result, buffer) = match self.operation.get() {
+                    StorageOperation::Read => self.sdcard.read_blocks(buffer, sector, count),
+                    StorageOperation::Write => self.sdcard.write_blocks(buffer, sector, count),
+                };
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                result
+            });
+        }
+
+        self.block_buffer
+            .take()
+            .map_or(ReturnCode::ERESERVE, |block| {
+                let (result, block) = match self.operation.get() {
+                    StorageOperation::Write if length == BLOCK_SIZE => {
+                        // a whole block, no need to read it first
+                        let index = self.buffer_index.get();
+                        self.buffer.map(|buffer| {
+                            block[..BLOCK_SIZE].copy_from_slice(&buffer[index..index + BLOCK_SIZE])
+                        });
+                        self.state.set(StorageState::WriteBlock);
+                        self.sdcard.write_blocks(block, sector, 1)
+                    }
+                    StorageOperation::Write => {
+                        self.state.set(StorageState::ModifyBlock);
+                        self.sdcard.read_blocks(block, sector, 1)
+                    }
+                    StorageOperation::Read => {
+                        self.state.set(StorageState::ReadBlock);
+                        self.sdcard.read_blocks(block, sector, 1)
+                    }
+                };
+                block.map(|block| self.block_buffer.replace(block));
+                result
+            })
+    }
+
+    /// continue with the next transfer after one completed
+    fn proceed(&self) {
+        if self.step() != ReturnCode::SUCCESS {
+            self.finish(0);
+        }
+    }
+
+    /// return the client buffer, with a length of zero after a failure
+    fn finish(&self, length: usize) {
+        self.state.set(StorageState::Idle);
+        self.buffer.take().map(move |buffer| {
+            self.client.map(move |client| match self.operation.get() {
+                StorageOperation::Read => client.read_done(buffer, length),
+                StorageOperation::Write => client.write_done(buffer, length),
+            });
+        });
+    }
+}
+
+/// Handle callbacks from SDCard
+impl<A: hil::time::Alarm> SDCardClient for SDCardNonvolatileStorage<'a, A> {
+    fn card_detection_changed(&self, _installed: bool) {}
+
+    fn init_done(&self, _block_size: u32, _total_size: u64) {
+        if self.state.get() == StorageState::Initializing {
+            self.proceed();
+        }
+    }
+
+    fn read_done(&self, data: &'static mut [u8], _len: usize) {
+        let (sector, offset, length) = self.next_chunk();
+        let index = self.buffer_index.get();
+        match self.state.get() {
+            StorageState::ReadBlock => {
+                self.buffer.map(|buffer| {
+                    buffer[index..index + length].copy_from_slice(&data[offset..offset + length])
+                });
+                self.block_buffer.replace(data);
+                self.advance(length);
+                self.proceed();
+            }
+            StorageState::ModifyBlock => {
+                self.buffer.map(|buffer| {
+                    data[offset..offset + length].copy_from_slice(&buffer[index..index + length])
+                });
+                self.state.set(StorageState::WriteBlock);
+                let (result, buffer) = self.sdcard.write_blocks(data, sector, 1);
+                buffer.map(|buffer| self.block_buffer.replace(buffer));
+                if result != ReturnCode::SUCCESS {
+                    self.finish(0);
+                }
+            }
+            StorageState::Direct => {
+                self.buffer.replace(data);
+                let remaining = self.remaining_length.get();
+                self.advance(remaining - remaining % BLOCK_SIZE);
+                self.proceed();
+            }
+            _ => {}
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8]) {
+        match self.state.get() {
+            StorageState::WriteBlock => {
+                self.block_buffer.replace(buffer);
+                let (_, _, length) = self.next_chunk();
+                self.advance(length);
+                self.proceed();
+            }
+            StorageState::Direct => {
+                self.buffer.replace(buffer);
+                let remaining = self.remaining_length.get();
+                self.advance(remaining - remaining % BLOCK_SIZE);
+                self.proceed();
+            }
+            _ => {}
+        }
+    }
+
+    fn error(&self, _error: u32, buffer: Option<&'static mut [u8]>) {
+        buffer.map(|buffer| {
+            if self.state.get() == StorageState::Direct {
+                self.buffer.replace(buffer);
+            } else {
+                self.block_buffer.replace(buffer);
+            }
+        });
+        if self.state.get() != StorageState::Idle {
+            self.finish(0);
+        }
+    }
+}
+
+impl<A: hil::time::Alarm> hil::nonvolatile_storage::NonvolatileStorage
+    for SDCardNonvolatileStorage<'a, A>
+{
+    fn set_client(&self, client: &'static hil::nonvolatile_storage::NonvolatileStorageClient) {
+        self.client.set(client);
+    }
+
+    fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        self.start(StorageOperation::Read, buffer, address, length)
+    }
+
+    fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        self.start(StorageOperation::Write, buffer, address, length)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BlockState {
+    Idle,
+    Initializing,
+    Read,
+    Write,
+}
+
+/// Block storage on an SD card, layers on top of SD Card capsule
+///
+/// Blocks of 512 bytes are read and written, several at a time with a single
+/// command. SD cards do not need to be erased. The card is initialized on
+/// first use, after which its capacity is known.
+pub struct SDCardBlockStorage<'a, A: hil::time::Alarm> {
+    sdcard: &'a SDCard<'a, A>,
+    client: OptionalCell<&'static hil::block_storage::BlockStorageClient>,
+    state: Cell<BlockState>,
+    /// Operation waiting for initialization, and its blocks.
+    operation: Cell<BlockState>,
+    sector: Cell<u32>,
+    count: Cell<u32>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<A: hil::time::Alarm> SDCardBlockStorage<'a, A> {
+    pub fn new(sdcard: &'a SDCard<'a, A>) -> SDCardBlockStorage<'a, A> {
+        SDCardBlockStorage {
+            sdcard: sdcard,
+            client: OptionalCell::empty(),
+            state: Cell::new(BlockState::Idle),
+            operation: Cell::new(BlockState::Idle),
+            sector: Cell::new(0),
+            count: Cell::new(0),
+            buffer: TakeCell::empty(),
+        }
+    }
+
+    fn start(
+        &self,
+        operation: BlockState,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.state.get() != BlockState::Idle {
+            return (ReturnCode::EBUSY, Some(buffer));
+        }
+        let block_size = BLOCK_SIZE as u64;
+        if address % block_size != 0
+            || length % BLOCK_SIZE != 0
+            || length == 0
+            || length > buffer.len()
+            || address / block_size + (length / BLOCK_SIZE) as u64 > u32::max_value() as u64
+        {
+            return (ReturnCode::EINVAL, Some(buffer));
+        }
+        let sector = (address / block_size) as u32;
+        let count = (length / BLOCK_SIZE) as u32;
+        if self.sdcard.is_initialized() {
+            if sector + count > self.sdcard.block_count() {
+                return (ReturnCode::EINVAL, Some(buffer));
+            }
+            return self.transfer(operation, buffer, sector, count);
+        }
+
+        let result = self.sdcard.initialize();
+        if result != ReturnCode::SUCCESS {
+            return (result, Some(buffer));
+        }
+        self.state.set(BlockState::Initializing);
+        self.operation.set(operation);
+        self.sector.set(sector);
+        self.count.set(count);
+        self.buffer.replace(buffer);
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn transfer(
+        &self,
+        operation: BlockState,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let (result, buffer) = if operation == BlockState::Write {
+            self.sdcard.write_blocks(buffer, sector, count)
+        } else {
+            self.sdcard.read_blocks(buffer, sector, count)
+        };
+        self.state.set(if result == ReturnCode::SUCCESS {
+            operation
+        } else {
+            BlockState::Idle
+        });
+        (result, buffer)
+    }
+
+    fn finish(&self, operation: BlockState, buffer: &'static mut [u8], result: ReturnCode) {
+        self.state.set(BlockState::Idle);
+        self.client.map(move |client| {
+            if operation == BlockState::Write {
+                client.write_done(buffer, result);
+            } else {
+                client.read_done(buffer, result);
+            }
+        });
+    }
+}
+
+/// Handle callbacks from SDCard
+impl<A: hil::time::Alarm> SDCardClient for SDCardBlockStorage<'a, A> {
+    fn card_detection_changed(&self, _installed: bool) {}
+
+    fn init_done(&self, _block_size: u32, _total_size: u64) {
+        if self.state.get() != BlockState::Initializing {
+            return;
+        }
+        let operation = self.operation.get();
+        let (sector, count) = (self.sector.get(), self.count.get());
+        self.buffer.take().map(|buffer| {
+            let (result, buffer) = if sector + count > self.sdcard.block_count() {
+                (ReturnCode::EINVAL, Some(buffer))
+            } else {
+                self.transfer(operation, buffer, sector, count)
+            };
+            buffer.map(|buffer| self.finish(operation, buffer, result));
+        });
+    }
+
+    fn read_done(&self, data: &'static mut [u8], _len: usize) {
+        self.finish(BlockState::Read, data, ReturnCode::SUCCESS);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8]) {
+        self.finish(BlockState::Write, buffer, ReturnCode::SUCCESS);
+    }
+
+    fn error(&self, _error: u32, buffer: Option<&'static mut [u8]>) {
+        let operation = match self.state.get() {
+            BlockState::Idle => return,
+            BlockState::Initializing => self.operation.get(),
+            operation => operation,
+        };
+        // Failed initialization leaves the buffer here.
+        buffer
+            .or_else(|| self.buffer.take())
+            .map(|buffer| self.finish(operation, buffer, ReturnCode::FAIL));
+    }
+}
+
+impl<A: hil::time::Alarm> hil::block_storage::BlockStorage for SDCardBlockStorage<'a, A> {
+    fn set_client(&self, client: &'static hil::block_storage::BlockStorageClient) {
+        self.client.set(client);
+    }
+
+    fn geometry(&self) -> hil::block_storage::Geometry {
+        hil::block_storage::Geometry {
+            read_size: BLOCK_SIZE,
+            write_size: BLOCK_SIZE,
+            erase_size: 0,
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        if self.sdcard.is_initialized() {
+            self.sdcard.block_count() as u64 * BLOCK_SIZE as u64
+        } else {
+            0
+        }
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.start(BlockState::Read, buffer, address, length)
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.start(BlockState::Write, buffer, address, length)
+    }
+
+    fn erase(&self, _address: u64, _length: usize) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+}
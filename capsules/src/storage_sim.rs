@@ -0,0 +1,992 @@
+//! Simulated flash and nonvolatile storage in RAM.
+//!
+//! `SimFlash` implements `hil::flash::Flash`, `SimNonvolatileStorage`
+//! implements `hil::nonvolatile_storage::NonvolatileStorage` and
+//! `SimBlockStorage` implements `hil::block_storage::BlockStorage` on top of
+//! a buffer in RAM, so that the capsules built on them can be tested on the
+//! host, for example in `cargo test`.
+//!
+//! Operations complete asynchronously, like on hardware: each one schedules
+//! a deferred call on a `DynamicDeferredCall`, and completes with its
+//! callback when the test calls `pump` on that instance.
+//!
+//! The tests of the capsules built on this storage share their fixture:
+//! `Sim` holds simulated storage that is kept across reboots of the capsules
+//! under test, together with the deferred calls that complete its operations
+//! and theirs. `leak`, `zeroed` and `grant` make the `'static` references and
+//! grants that capsules are built from.
+//!
+//! `SimFlash` models NOR flash. Erasing a page sets all of its bits, and
+//! writing can only clear bits, so a write that would set a bit fails with
+//! `Error::FlashError` and leaves the page unchanged. Flash that erases a page
+//! as part of every write, like the SAM4L, can be modeled with
+//! `WriteMode::EraseOnWrite` instead. The number of erases of every page is
+//! counted. Power can be cut in the middle of a write or erase, which then
+//! only changes the first half of the page and never completes.
+//!
+//! `SimFlash` also implements `hil::flash::WriteProtect` for its first 64
+//! pages, in lock regions of `set_lock_region_pages` pages. Like on the
+//! SAM4L, writes and erases of protected pages fail with `EINVAL`, and the
+//! protection persists across `power_on` until it is removed.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let sim = Sim::flash(vec![0xff; 4 * PAGE_SIZE], WriteMode::Nor);
+//!
+//! // Set up the capsules that use `sim.storage`, registering them with
+//! // `sim.deferred_caller` if they need deferred calls, and start an
+//! // operation
+//!
+//! sim.pump();
+//! ```
+
+extern crate std;
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+    DynamicDeferredCallClientState,
+};
+use kernel::hil;
+use kernel::{Grant, ReturnCode};
+use std::boxed::Box;
+use std::vec;
+use std::vec::Vec;
+
+/// Size of a page of `SimFlash`, the same as on the SAM4L.
+pub const PAGE_SIZE: usize = 512;
+
+/// Runs deferred calls until none are pending, completing all operations of
+/// the simulated storage and everything they start in turn.
+pub fn pump(deferred_caller: &DynamicDeferredCall) {
+    while deferred_caller.has_pending() {
+        deferred_caller.call_pending_for_test();
+    }
+}
+
+/// Number of clients of the deferred calls of a `Sim`, including its storage.
+const MAX_DEFERRED_CALLS: usize = 8;
+
+/// Leaks `value`, for the `'static` references that capsules are built from.
+pub fn leak<T>(value: T) -> &'static mut T {
+    Box::leak(Box::new(value))
+}
+
+/// A `'static` buffer of `length` zeros.
+pub fn zeroed(length: usize) -> &'static mut [u8] {
+    Box::leak(vec![0; length].into_boxed_slice())
+}
+
+/// A grant of a new kernel without processes.
+pub fn grant<T: Default>() -> Grant<T> {
+    leak(kernel::Kernel::new(&[])).create_grant(&kernel::create_capability!(
+        kernel::capabilities::MemoryAllocationCapability
+    ))
+}
+
+/// Deferred calls for up to `MAX_DEFERRED_CALLS` clients.
+pub fn deferred_caller() -> &'static DynamicDeferredCall {
+    let states: Vec<DynamicDeferredCallClientState> = (0..MAX_DEFERRED_CALLS)
+        .map(|_| Default::default())
+        .collect();
+    leak(DynamicDeferredCall::new(Box::leak(
+        states.into_boxed_slice(),
+    )))
+}
+
+/// Simulated storage that is kept across reboots, and the deferred calls
+/// that complete its operations and those of the capsules booted on it.
+pub struct Sim<S: 'static> {
+    pub storage: &'static S,
+    pub deferred_caller: &'static DynamicDeferredCall,
+}
+
+impl<S> Sim<S> {
+    /// Runs the deferred calls of the storage and of the capsules on it, see
+    /// `pump`.
+    pub fn pump(&self) {
+        pump(self.deferred_caller)
+    }
+}
+
+impl Sim<SimFlash<'static>> {
+    /// Flash holding `contents`, which is a whole number of pages.
+    pub fn flash(contents: Vec<u8>, mode: WriteMode) -> &'static Sim<SimFlash<'static>> {
+        let deferred_caller = deferred_caller();
+        let num_pages = contents.len() / PAGE_SIZE;
+        let flash = leak(SimFlash::new(
+            Box::leak(contents.into_boxed_slice()),
+            Box::leak(vec![0; num_pages].into_boxed_slice()),
+            mode,
+            deferred_caller,
+        ));
+        flash.set_deferred_call_handle(deferred_caller.register(flash).unwrap());
+        leak(Sim {
+            storage: flash,
+            deferred_caller: deferred_caller,
+        })
+    }
+}
+
+impl Sim<SimNonvolatileStorage<'static>> {
+    /// Nonvolatile storage holding `contents`.
+    pub fn nonvolatile(contents: Vec<u8>) -> &'static Sim<SimNonvolatileStorage<'static>> {
+        let deferred_caller = deferred_caller();
+        let storage = leak(SimNonvolatileStorage::new(
+            Box::leak(contents.into_boxed_slice()),
+            deferred_caller,
+        ));
+        storage.set_deferred_call_handle(deferred_caller.register(storage).unwrap());
+        leak(Sim {
+            storage: storage,
+            deferred_caller: deferred_caller,
+        })
+    }
+}
+
+impl Sim<SimBlockStorage<'static>> {
+    /// Block storage with the block sizes of `geometry`, holding `contents`.
+    pub fn blocks(
+        contents: Vec<u8>,
+        geometry: hil::block_storage::Geometry,
+    ) -> &'static Sim<SimBlockStorage<'static>> {
+        let deferred_caller = deferred_caller();
+        let blocks = leak(SimBlockStorage::new(
+            Box::leak(contents.into_boxed_slice()),
+            geometry,
+            deferred_caller,
+        ));
+        blocks.set_deferred_call_handle(deferred_caller.register(blocks).unwrap());
+        leak(Sim {
+            storage: blocks,
+            deferred_caller: deferred_caller,
+        })
+    }
+}
+
+/// A page of `SimFlash`.
+pub struct SimPage(pub [u8; PAGE_SIZE]);
+
+impl SimPage {
+    pub const fn new() -> SimPage {
+        SimPage([0; PAGE_SIZE])
+    }
+}
+
+impl Default for SimPage {
+    fn default() -> SimPage {
+        SimPage::new()
+    }
+}
+
+impl AsMut<[u8]> for SimPage {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// How writes change a page of `SimFlash`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteMode {
+    /// Writes can only clear bits, so pages must be erased before they are
+    /// written again.
+    Nor,
+    /// Every write erases the page first, and counts as an erase.
+    EraseOnWrite,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FlashOp {
+    Read(usize),
+    Write(usize),
+    Erase(usize),
+}
+
+pub struct SimFlash<'a> {
+    storage: TakeCell<'a, [u8]>,
+    erase_counts: TakeCell<'a, [u32]>,
+    mode: WriteMode,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+    client: OptionalCell<&'a hil::flash::Client<SimFlash<'a>>>,
+    pending: Cell<Option<FlashOp>>,
+    buffer: TakeCell<'static, SimPage>,
+    // Number of writes and erases left before power is cut.
+    cut_after: Cell<Option<usize>>,
+    powered: Cell<bool>,
+    // Bit `n` is set if page `n` is protected.
+    protected: Cell<u64>,
+    lock_region_pages: Cell<usize>,
+}
+
+impl SimFlash<'a> {
+    /// Creates flash with `storage.len() / PAGE_SIZE` pages, whose erase
+    /// counts are kept in `erase_counts`. The storage starts out with the
+    /// content of `storage`.
+    pub fn new(
+        storage: &'a mut [u8],
+        erase_counts: &'a mut [u32],
+        mode: WriteMode,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> SimFlash<'a> {
+        SimFlash {
+            storage: TakeCell::new(storage),
+            erase_counts: TakeCell::new(erase_counts),
+            mode: mode,
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+            pending: Cell::new(None),
+            buffer: TakeCell::empty(),
+            cut_after: Cell::new(None),
+            powered: Cell::new(true),
+            protected: Cell::new(0),
+            lock_region_pages: Cell::new(1),
+        }
+    }
+
+    pub fn set_deferred_call_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    pub fn num_pages(&self) -> usize {
+        self.storage.map_or(0, |storage| storage.len() / PAGE_SIZE)
+    }
+
+    /// Calls `f` with the content of the flash.
+    pub fn map_storage<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        self.storage.map(|storage| f(storage))
+    }
+
+    /// Number of times `page` has been erased.
+    pub fn erase_count(&self, page: usize) -> u32 {
+        self.erase_counts
+            .map_or(0, |counts| counts.get(page).map_or(0, |count| *count))
+    }
+
+    /// Sets the number of pages in each lock region, which are protected
+    /// together. Defaults to 1.
+    pub fn set_lock_region_pages(&self, pages: usize) {
+        self.lock_region_pages.set(pages);
+    }
+
+    /// Whether `page` is protected against writes and erases.
+    pub fn is_protected(&self, page: usize) -> bool {
+        page < 64 && self.protected.get() & 1 << page != 0
+    }
+
+    // The pages of the lock regions entirely within `start_page` up to, but
+    // not including, `end_page`, as a mask.
+    fn region_mask(&self, start_page: usize, end_page: usize) -> u64 {
+        let region_pages = self.lock_region_pages.get();
+        let first = (start_page + region_pages - 1) / region_pages * region_pages;
+        let end = core::cmp::min(end_page / region_pages * region_pages, 64);
+        (first..end).fold(0, |mask, page| mask | 1 << page)
+    }
+
+    /// Cuts power during the write or erase that follows the next
+    /// `operations` ones.
+    pub fn cut_power_after(&self, operations: usize) {
+        self.cut_after.set(Some(operations));
+    }
+
+    /// Whether the operation in progress was interrupted by a loss of power.
+    pub fn is_powered(&self) -> bool {
+        self.powered.get()
+    }
+
+    /// Restores power after a cut, as after a reboot. The operation that was
+    /// in progress is forgotten, along with its buffer.
+    pub fn power_on(&self) {
+        self.pending.set(None);
+        self.buffer.take();
+        self.cut_after.set(None);
+        self.powered.set(true);
+    }
+
+    fn start(&self, op: FlashOp, page: usize, buffer: Option<&'static mut SimPage>) -> ReturnCode {
+        if !self.powered.get() {
+            return ReturnCode::FAIL;
+        }
+        if self.pending.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if page >= self.num_pages() {
+            return ReturnCode::EINVAL;
+        }
+        match op {
+            FlashOp::Write(_) | FlashOp::Erase(_) if self.is_protected(page) => {
+                return ReturnCode::EINVAL;
+            }
+            _ => {}
+        }
+        self.handle.map_or(ReturnCode::FAIL, move |handle| {
+            buffer.map(|buffer| self.buffer.replace(buffer));
+            self.pending.set(Some(op));
+            self.deferred_caller.set(*handle);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    // Whether power is cut during this write or erase.
+    fn torn(&self) -> bool {
+        match self.cut_after.get() {
+            Some(0) => {
+                self.cut_after.set(None);
+                self.powered.set(false);
+                true
+            }
+            Some(n) => {
+                self.cut_after.set(Some(n - 1));
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn erase(&self, page: usize, end: usize) {
+        self.storage.map(|storage| {
+            for byte in storage[page * PAGE_SIZE..page * PAGE_SIZE + end].iter_mut() {
+                *byte = 0xff;
+            }
+        });
+        self.erase_counts.map(|counts| counts[page] += 1);
+    }
+
+    // Program the first `end` bytes of `page`. Returns false if a bit would
+    // have to be set.
+    fn program(&self, page: usize, data: &[u8], end: usize) -> bool {
+        self.storage.map_or(false, |storage| {
+            let stored = &mut storage[page * PAGE_SIZE..(page + 1) * PAGE_SIZE];
+            if stored
+                .iter()
+                .zip(data.iter())
+                .any(|(old, new)| !old & new != 0)
+            {
+                return false;
+            }
+            for i in 0..end {
+                stored[i] &= data[i];
+            }
+            true
+        })
+    }
+}
+
+impl hil::flash::Flash for SimFlash<'a> {
+    type Page = SimPage;
+
+    fn read_page(&self, page_number: usize, buf: &'static mut SimPage) -> ReturnCode {
+        self.start(FlashOp::Read(page_number), page_number, Some(buf))
+    }
+
+    fn write_page(&self, page_number: usize, buf: &'static mut SimPage) -> ReturnCode {
+        self.start(FlashOp::Write(page_number), page_number, Some(buf))
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        self.start(FlashOp::Erase(page_number), page_number, None)
+    }
+}
+
+impl hil::flash::WriteProtect for SimFlash<'a> {
+    fn protect(&self, start_page: usize, end_page: usize) -> ReturnCode {
+        let mask = self.region_mask(start_page, end_page);
+        self.protected.set(self.protected.get() | mask);
+        ReturnCode::SUCCESS
+    }
+
+    fn unprotect(&self, start_page: usize, end_page: usize) -> ReturnCode {
+        let mask = self.region_mask(start_page, end_page);
+        self.protected.set(self.protected.get() & !mask);
+        ReturnCode::SUCCESS
+    }
+}
+
+impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'a, C> for SimFlash<'a> {
+    fn set_client(&'a self, client: &'a C) {
+        self.client.set(client);
+    }
+}
+
+impl DynamicDeferredCallClient for SimFlash<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        let op = match self.pending.take() {
+            Some(op) => op,
+            None => return,
+        };
+        match op {
+            FlashOp::Read(page) => {
+                self.buffer.take().map(|buffer| {
+                    self.storage.map(|storage| {
+                        buffer
+                            .0
+                            .copy_from_slice(&storage[page * PAGE_SIZE..(page + 1) * PAGE_SIZE])
+                    });
+                    self.client.map(move |client| {
+                        client.read_complete(buffer, hil::flash::Error::CommandComplete)
+                    });
+                });
+            }
+            FlashOp::Write(page) => {
+                self.buffer.take().map(|buffer| {
+                    let torn = self.torn();
+                    let end = if torn { PAGE_SIZE / 2 } else { PAGE_SIZE };
+                    if self.mode == WriteMode::EraseOnWrite {
+                        self.erase(page, PAGE_SIZE);
+                    }
+                    let error = if self.program(page, &buffer.0, end) {
+                        hil::flash::Error::CommandComplete
+                    } else {
+                        hil::flash::Error::FlashError
+                    };
+                    if !torn {
+                        self.client
+                            .map(move |client| client.write_complete(buffer, error));
+                    }
+                });
+            }
+            FlashOp::Erase(page) => {
+                let torn = self.torn();
+                self.erase(page, if torn { PAGE_SIZE / 2 } else { PAGE_SIZE });
+                if !torn {
+                    self.client
+                        .map(|client| client.erase_complete(hil::flash::Error::CommandComplete));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StorageOp {
+    Read,
+    Write,
+}
+
+/// Byte-addressed storage, such as FRAM, that can be overwritten freely.
+pub struct SimNonvolatileStorage<'a> {
+    storage: TakeCell<'a, [u8]>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+    client: OptionalCell<&'static hil::nonvolatile_storage::NonvolatileStorageClient>,
+    pending: Cell<Option<StorageOp>>,
+    buffer: TakeCell<'static, [u8]>,
+    address: Cell<usize>,
+    length: Cell<usize>,
+}
+
+impl SimNonvolatileStorage<'a> {
+    /// Creates storage holding the content of `storage`.
+    pub fn new(
+        storage: &'a mut [u8],
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> SimNonvolatileStorage<'a> {
+        SimNonvolatileStorage {
+            storage: TakeCell::new(storage),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+            pending: Cell::new(None),
+            buffer: TakeCell::empty(),
+            address: Cell::new(0),
+            length: Cell::new(0),
+        }
+    }
+
+    pub fn set_deferred_call_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Calls `f` with the content of the storage.
+    pub fn map_storage<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        self.storage.map(|storage| f(storage))
+    }
+
+    fn start(
+        &self,
+        op: StorageOp,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> ReturnCode {
+        if self.pending.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let size = self.storage.map_or(0, |storage| storage.len());
+        if length > buffer.len() || address + length > size {
+            return ReturnCode::EINVAL;
+        }
+        self.handle.map_or(ReturnCode::FAIL, move |handle| {
+            self.buffer.replace(buffer);
+            self.address.set(address);
+            self.length.set(length);
+            self.pending.set(Some(op));
+            self.deferred_caller.set(*handle);
+            ReturnCode::SUCCESS
+        })
+    }
+}
+
+impl hil::nonvolatile_storage::NonvolatileStorage for SimNonvolatileStorage<'a> {
+    fn set_client(&self, client: &'static hil::nonvolatile_storage::NonvolatileStorageClient) {
+        self.client.set(client);
+    }
+
+    fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        self.start(StorageOp::Read, buffer, address, length)
+    }
+
+    fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        self.start(StorageOp::Write, buffer, address, length)
+    }
+}
+
+impl DynamicDeferredCallClient for SimNonvolatileStorage<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        let op = match self.pending.take() {
+            Some(op) => op,
+            None => return,
+        };
+        let address = self.address.get();
+        let length = self.length.get();
+        self.buffer.take().map(|buffer| {
+            self.storage.map(|storage| {
+                let stored = &mut storage[address..address + length];
+                match op {
+                    StorageOp::Read => buffer[..length].copy_from_slice(stored),
+                    StorageOp::Write => stored.copy_from_slice(&buffer[..length]),
+                }
+            });
+            self.client.map(move |client| match op {
+                StorageOp::Read => client.read_done(buffer, length),
+                StorageOp::Write => client.write_done(buffer, length),
+            });
+        });
+    }
+}
+
+/// Storage read, written and erased in blocks, such as an SD card or a NOR
+/// flash chip.
+///
+/// Storage with an erase size behaves like NOR flash: erasing sets every bit
+/// and a write that would set a bit fails with FAIL, leaving the storage
+/// unchanged.
+pub struct SimBlockStorage<'a> {
+    storage: TakeCell<'a, [u8]>,
+    geometry: hil::block_storage::Geometry,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+    client: OptionalCell<&'static hil::block_storage::BlockStorageClient>,
+    pending: Cell<Option<BlockOp>>,
+    buffer: TakeCell<'static, [u8]>,
+    address: Cell<usize>,
+    length: Cell<usize>,
+    erases: Cell<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BlockOp {
+    Read,
+    Write,
+    Erase,
+}
+
+impl SimBlockStorage<'a> {
+    /// Creates storage with the block sizes of `geometry`, holding the
+    /// content of `storage`.
+    pub fn new(
+        storage: &'a mut [u8],
+        geometry: hil::block_storage::Geometry,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> SimBlockStorage<'a> {
+        SimBlockStorage {
+            storage: TakeCell::new(storage),
+            geometry: geometry,
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+            pending: Cell::new(None),
+            buffer: TakeCell::empty(),
+            address: Cell::new(0),
+            length: Cell::new(0),
+            erases: Cell::new(0),
+        }
+    }
+
+    pub fn set_deferred_call_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Calls `f` with the content of the storage.
+    pub fn map_storage<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        self.storage.map(|storage| f(storage))
+    }
+
+    /// Number of blocks erased so far.
+    pub fn erase_count(&self) -> usize {
+        self.erases.get()
+    }
+
+    fn start(&self, op: BlockOp, address: u64, length: usize, block_size: usize) -> ReturnCode {
+        if self.pending.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let size = self.storage.map_or(0, |storage| storage.len()) as u64;
+        if address % block_size as u64 != 0
+            || length % block_size != 0
+            || address + length as u64 > size
+        {
+            return ReturnCode::EINVAL;
+        }
+        self.handle.map_or(ReturnCode::FAIL, |handle| {
+            self.address.set(address as usize);
+            self.length.set(length);
+            self.pending.set(Some(op));
+            self.deferred_caller.set(*handle);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn start_transfer(
+        &self,
+        op: BlockOp,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+        block_size: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if length > buffer.len() {
+            return (ReturnCode::EINVAL, Some(buffer));
+        }
+        match self.start(op, address, length, block_size) {
+            ReturnCode::SUCCESS => {
+                self.buffer.replace(buffer);
+                (ReturnCode::SUCCESS, None)
+            }
+            error => (error, Some(buffer)),
+        }
+    }
+}
+
+impl hil::block_storage::BlockStorage for SimBlockStorage<'a> {
+    fn set_client(&self, client: &'static hil::block_storage::BlockStorageClient) {
+        self.client.set(client);
+    }
+
+    fn geometry(&self) -> hil::block_storage::Geometry {
+        self.geometry
+    }
+
+    fn capacity(&self) -> u64 {
+        self.storage.map_or(0, |storage| storage.len()) as u64
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let block_size = self.geometry.read_size;
+        self.start_transfer(BlockOp::Read, buffer, address, length, block_size)
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let block_size = self.geometry.write_size;
+        self.start_transfer(BlockOp::Write, buffer, address, length, block_size)
+    }
+
+    fn erase(&self, address: u64, length: usize) -> ReturnCode {
+        if self.geometry.erase_size == 0 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        self.start(BlockOp::Erase, address, length, self.geometry.erase_size)
+    }
+}
+
+impl DynamicDeferredCallClient for SimBlockStorage<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        let op = match self.pending.take() {
+            Some(op) => op,
+            None => return,
+        };
+        let address = self.address.get();
+        let length = self.length.get();
+        let nor = self.geometry.erase_size != 0;
+        let result = self.storage.map_or(ReturnCode::FAIL, |storage| {
+            let stored = &mut storage[address..address + length];
+            match op {
+                BlockOp::Read => {
+                    self.buffer
+                        .map(|buffer| buffer[..length].copy_from_slice(stored));
+                }
+                BlockOp::Write => {
+                    let result = self.buffer.map_or(ReturnCode::FAIL, |buffer| {
+                        let data = &buffer[..length];
+                        if nor && stored.iter().zip(data).any(|(old, new)| new & !old != 0) {
+                            return ReturnCode::FAIL;
+                        }
+                        stored.copy_from_slice(data);
+                        ReturnCode::SUCCESS
+                    });
+                    if result != ReturnCode::SUCCESS {
+                        return result;
+                    }
+                }
+                BlockOp::Erase => {
+                    for byte in stored.iter_mut() {
+                        *byte = 0xff;
+                    }
+                    self.erases
+                        .set(self.erases.get() + length / self.geometry.erase_size);
+                }
+            }
+            ReturnCode::SUCCESS
+        });
+        match op {
+            BlockOp::Read => self.buffer.take().map(|buffer| {
+                self.client
+                    .map(move |client| client.read_done(buffer, result));
+            }),
+            BlockOp::Write => self.buffer.take().map(|buffer| {
+                self.client
+                    .map(move |client| client.write_done(buffer, result));
+            }),
+            BlockOp::Erase => self.client.map(|client| client.erase_done(result)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use kernel::hil::flash::{Client, Error, Flash, HasClient, WriteProtect};
+    use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+    use std::cell::RefCell;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Read(Vec<u8>),
+        Written(Error),
+        Erased,
+    }
+
+    struct TestClient {
+        page: TakeCell<'static, SimPage>,
+        events: RefCell<Vec<Event>>,
+    }
+
+    impl Client<SimFlash<'static>> for TestClient {
+        fn read_complete(&self, page: &'static mut SimPage, _error: Error) {
+            self.events.borrow_mut().push(Event::Read(page.0.to_vec()));
+            self.page.replace(page);
+        }
+
+        fn write_complete(&self, page: &'static mut SimPage, error: Error) {
+            self.events.borrow_mut().push(Event::Written(error));
+            self.page.replace(page);
+        }
+
+        fn erase_complete(&self, _error: Error) {
+            self.events.borrow_mut().push(Event::Erased);
+        }
+    }
+
+    fn flash(
+        mode: WriteMode,
+    ) -> (
+        &'static SimFlash<'static>,
+        &'static DynamicDeferredCall,
+        &'static TestClient,
+    ) {
+        let sim = Sim::flash(vec![0xff; 2 * PAGE_SIZE], mode);
+        let flash = sim.storage;
+        let client = leak(TestClient {
+            page: TakeCell::new(leak(SimPage::new())),
+            events: RefCell::new(Vec::new()),
+        });
+        flash.set_client(client);
+        (flash, sim.deferred_caller, client)
+    }
+
+    fn write(
+        flash: &SimFlash,
+        deferred_caller: &DynamicDeferredCall,
+        client: &TestClient,
+        page: usize,
+        value: u8,
+    ) -> Event {
+        let buffer = client.page.take().unwrap();
+        buffer.0 = [value; PAGE_SIZE];
+        assert_eq!(flash.write_page(page, buffer), ReturnCode::SUCCESS);
+        assert!(client.events.borrow().is_empty());
+        pump(deferred_caller);
+        client.events.borrow_mut().pop().unwrap()
+    }
+
+    #[test]
+    fn nor_writes_only_clear_bits() {
+        let (flash, deferred_caller, client) = flash(WriteMode::Nor);
+        let written = Event::Written(Error::CommandComplete);
+        assert_eq!(write(flash, deferred_caller, client, 0, 0xf0), written);
+        assert_eq!(write(flash, deferred_caller, client, 0, 0x30), written);
+        assert_eq!(
+            write(flash, deferred_caller, client, 0, 0x0f),
+            Event::Written(Error::FlashError)
+        );
+        assert_eq!(
+            flash.map_storage(|storage| storage[..PAGE_SIZE].to_vec()),
+            Some(vec![0x30; PAGE_SIZE])
+        );
+
+        assert_eq!(flash.erase_page(0), ReturnCode::SUCCESS);
+        assert_eq!(flash.erase_page(1), ReturnCode::EBUSY);
+        pump(deferred_caller);
+        assert_eq!(client.events.borrow_mut().pop(), Some(Event::Erased));
+        assert_eq!(write(flash, deferred_caller, client, 0, 0x0f), written);
+        assert_eq!(flash.erase_count(0), 1);
+        assert_eq!(flash.erase_count(1), 0);
+
+        let buffer = client.page.take().unwrap();
+        assert_eq!(flash.read_page(0, buffer), ReturnCode::SUCCESS);
+        pump(deferred_caller);
+        assert_eq!(
+            client.events.borrow_mut().pop(),
+            Some(Event::Read(vec![0x0f; PAGE_SIZE]))
+        );
+        assert_eq!(flash.erase_page(2), ReturnCode::EINVAL);
+    }
+
+    #[test]
+    fn erase_on_write_counts_every_write() {
+        let (flash, deferred_caller, client) = flash(WriteMode::EraseOnWrite);
+        let written = Event::Written(Error::CommandComplete);
+        assert_eq!(write(flash, deferred_caller, client, 1, 0x00), written);
+        assert_eq!(write(flash, deferred_caller, client, 1, 0xa5), written);
+        assert_eq!(
+            flash.map_storage(|storage| storage[PAGE_SIZE..].to_vec()),
+            Some(vec![0xa5; PAGE_SIZE])
+        );
+        assert_eq!(flash.erase_count(1), 2);
+    }
+
+    #[test]
+    fn power_cuts_tear_the_operation() {
+        let (flash, deferred_caller, client) = flash(WriteMode::Nor);
+        flash.cut_power_after(1);
+        let written = Event::Written(Error::CommandComplete);
+        assert_eq!(write(flash, deferred_caller, client, 0, 0x11), written);
+
+        let buffer = leak(SimPage([0; PAGE_SIZE]));
+        assert_eq!(flash.write_page(1, buffer), ReturnCode::SUCCESS);
+        pump(deferred_caller);
+        assert!(client.events.borrow().is_empty());
+        assert!(!flash.is_powered());
+        assert_eq!(flash.erase_page(0), ReturnCode::FAIL);
+
+        flash.power_on();
+        let mut expected = vec![0x11; PAGE_SIZE];
+        expected.extend_from_slice(&[0; PAGE_SIZE / 2]);
+        expected.extend_from_slice(&[0xff; PAGE_SIZE / 2]);
+        assert_eq!(
+            flash.map_storage(|storage| storage.to_vec()),
+            Some(expected)
+        );
+        assert_eq!(flash.erase_page(0), ReturnCode::SUCCESS);
+    }
+
+    #[test]
+    fn protection_covers_whole_regions_and_survives_power_cuts() {
+        let (flash, deferred_caller, client) = flash(WriteMode::Nor);
+        flash.set_lock_region_pages(2);
+        assert_eq!(flash.protect(0, 1), ReturnCode::SUCCESS);
+        assert!(!flash.is_protected(0));
+        assert_eq!(flash.protect(0, 2), ReturnCode::SUCCESS);
+        assert!(flash.is_protected(0) && flash.is_protected(1));
+
+        assert_eq!(flash.erase_page(1), ReturnCode::EINVAL);
+        let buffer = client.page.take().unwrap();
+        assert_eq!(flash.read_page(1, buffer), ReturnCode::SUCCESS);
+        pump(deferred_caller);
+        assert_eq!(
+            client.events.borrow_mut().pop(),
+            Some(Event::Read(vec![0xff; PAGE_SIZE]))
+        );
+
+        flash.power_on();
+        assert!(flash.is_protected(0));
+        assert_eq!(flash.unprotect(0, 2), ReturnCode::SUCCESS);
+        let written = Event::Written(Error::CommandComplete);
+        assert_eq!(write(flash, deferred_caller, client, 0, 0x00), written);
+    }
+
+    struct StorageClient {
+        events: RefCell<Vec<(bool, Vec<u8>)>>,
+    }
+
+    impl NonvolatileStorageClient for StorageClient {
+        fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.events
+                .borrow_mut()
+                .push((false, buffer[..length].to_vec()));
+        }
+
+        fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.events
+                .borrow_mut()
+                .push((true, buffer[..length].to_vec()));
+        }
+    }
+
+    #[test]
+    fn nonvolatile_storage_overwrites_bytes() {
+        let sim = Sim::nonvolatile(vec![0; 64]);
+        let storage = sim.storage;
+        let client = leak(StorageClient {
+            events: RefCell::new(Vec::new()),
+        });
+        storage.set_client(client);
+
+        let buffer = leak([1, 2, 3, 4]);
+        assert_eq!(storage.write(buffer, 10, 3), ReturnCode::SUCCESS);
+        let other = zeroed(4);
+        assert_eq!(storage.read(other, 0, 4), ReturnCode::EBUSY);
+        assert!(client.events.borrow().is_empty());
+        sim.pump();
+        assert_eq!(
+            client.events.borrow_mut().pop(),
+            Some((true, vec![1, 2, 3]))
+        );
+
+        let buffer = zeroed(8);
+        assert_eq!(storage.read(buffer, 8, 8), ReturnCode::SUCCESS);
+        sim.pump();
+        assert_eq!(
+            client.events.borrow_mut().pop(),
+            Some((false, vec![0, 0, 1, 2, 3, 0, 0, 0]))
+        );
+
+        let buffer = zeroed(8);
+        assert_eq!(storage.read(buffer, 60, 8), ReturnCode::EINVAL);
+    }
+}
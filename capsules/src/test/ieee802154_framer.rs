@@ -0,0 +1,481 @@
+//! Test the IEEE 802.15.4 frame security procedures of the `Framer`.
+//!
+//! The test stands in for the MAC layer below the `Framer`. It first feeds
+//! the secured frames of IEEE 802.15.4-2015 Annex C into the reception
+//! pipeline and checks that they are authenticated, decrypted and protected
+//! from replay. It then transmits a frame at every security level, loops the
+//! secured frame back into the reception pipeline and checks that the
+//! original payload is recovered.
+
+use crate::ieee802154::device::{MacDevice, RxClient, TxClient};
+use crate::ieee802154::framer::{DeviceProcedure, FrameCounterClient, KeyProcedure};
+use crate::ieee802154::mac::Mac;
+use crate::net::ieee802154::{Header, KeyId, MacAddress, SecurityLevel};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::debug;
+use kernel::hil::radio;
+use kernel::hil::symmetric_encryption::AES128_KEY_SIZE;
+use kernel::ReturnCode;
+
+#[derive(Copy, Clone)]
+enum TestCase {
+    /// Receive a secured frame, expecting it to be accepted and decrypted
+    /// into the unsecured frame if `accept` is true, and dropped otherwise.
+    Receive {
+        secured: &'static [u8],
+        unsecured: &'static [u8],
+        accept: bool,
+    },
+    /// Transmit a frame at the given security level and receive it back.
+    Loopback(SecurityLevel),
+}
+
+// (test case, whether to reset the frame counter of the peer device first)
+static TESTS: [(TestCase, bool); 10] = [
+    (
+        TestCase::Receive {
+            secured: &BEACON_SECURED,
+            unsecured: &BEACON_UNSECURED,
+            accept: true,
+        },
+        true,
+    ),
+    // Replayed frame
+    (
+        TestCase::Receive {
+            secured: &BEACON_SECURED,
+            unsecured: &BEACON_UNSECURED,
+            accept: false,
+        },
+        false,
+    ),
+    (
+        TestCase::Receive {
+            secured: &MAC_SECURED,
+            unsecured: &MAC_UNSECURED,
+            accept: true,
+        },
+        true,
+    ),
+    // Frame with corrupted ciphertext
+    (
+        TestCase::Receive {
+            secured: &MAC_CORRUPTED,
+            unsecured: &MAC_UNSECURED,
+            accept: false,
+        },
+        true,
+    ),
+    (TestCase::Loopback(SecurityLevel::Mic32), true),
+    (TestCase::Loopback(SecurityLevel::Mic64), false),
+    (TestCase::Loopback(SecurityLevel::Mic128), false),
+    (TestCase::Loopback(SecurityLevel::EncMic32), false),
+    (TestCase::Loopback(SecurityLevel::EncMic64), false),
+    (TestCase::Loopback(SecurityLevel::EncMic128), false),
+];
+
+pub struct Test<'a> {
+    mac_device: OptionalCell<&'a MacDevice<'a>>,
+
+    // Clients of the MAC layer that this test stands in for
+    radio_tx_client: OptionalCell<&'static radio::TxClient>,
+    radio_rx_client: OptionalCell<&'static radio::RxClient>,
+    address: Cell<u16>,
+    address_long: Cell<[u8; 8]>,
+    pan: Cell<u16>,
+
+    tx_buf: TakeCell<'static, [u8]>,
+    rx_buf: TakeCell<'static, [u8]>,
+
+    current_test: Cell<usize>,
+    running: Cell<bool>,
+    received: Cell<bool>,
+    received_matches: Cell<bool>,
+    sent_matches: Cell<bool>,
+
+    // DeviceDescriptor FrameCounter of the only known device
+    peer_frame_counter: Cell<u32>,
+    // Last outgoing frame counter reservation reported by the framer
+    frame_counter_reserved: Cell<u32>,
+}
+
+impl Test<'a> {
+    pub fn new(tx_buf: &'static mut [u8], rx_buf: &'static mut [u8]) -> Test<'a> {
+        Test {
+            mac_device: OptionalCell::empty(),
+            radio_tx_client: OptionalCell::empty(),
+            radio_rx_client: OptionalCell::empty(),
+            address: Cell::new(0),
+            address_long: Cell::new(PEER_ADDR_LONG),
+            pan: Cell::new(PAN),
+            tx_buf: TakeCell::new(tx_buf),
+            rx_buf: TakeCell::new(rx_buf),
+            current_test: Cell::new(0),
+            running: Cell::new(false),
+            received: Cell::new(false),
+            received_matches: Cell::new(false),
+            sent_matches: Cell::new(true),
+            peer_frame_counter: Cell::new(0),
+            frame_counter_reserved: Cell::new(0),
+        }
+    }
+
+    pub fn set_mac_device(&self, mac_device: &'a MacDevice<'a>) {
+        self.mac_device.set(mac_device);
+    }
+
+    pub fn run(&self) {
+        debug!("IEEE 802.15.4 framer security tests");
+        self.trigger_test();
+    }
+
+    fn trigger_test(&self) {
+        let (test, reset) = TESTS[self.current_test.get()];
+        if reset {
+            self.peer_frame_counter.set(0);
+        }
+        self.running.set(true);
+        self.received.set(false);
+        self.received_matches.set(false);
+        self.sent_matches.set(true);
+
+        match test {
+            TestCase::Receive { secured, .. } => {
+                let buf = match self.rx_buf.take() {
+                    None => panic!("ieee802154_framer_test failed: buffer is not present."),
+                    Some(buf) => buf,
+                };
+                buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + secured.len()]
+                    .copy_from_slice(secured);
+                self.radio_rx_client.map(move |client| {
+                    client.receive(
+                        buf,
+                        secured.len(),
+                        radio::LinkQuality::UNKNOWN,
+                        true,
+                        ReturnCode::SUCCESS,
+                    );
+                });
+            }
+            TestCase::Loopback(level) => {
+                let buf = match self.tx_buf.take() {
+                    None => panic!("ieee802154_framer_test failed: buffer is not present."),
+                    Some(buf) => buf,
+                };
+                self.mac_device.map(move |mac_device| {
+                    let addr = MacAddress::Long(PEER_ADDR_LONG);
+                    let security = Some((level, KeyId::Index(KEY_INDEX)));
+                    match mac_device.prepare_data_frame(buf, PAN, addr, PAN, addr, security) {
+                        Err(buf) => {
+                            debug!("ieee802154_framer_test failed: could not prepare frame");
+                            self.tx_buf.replace(buf);
+                        }
+                        Ok(mut frame) => {
+                            if frame.append_payload(&PAYLOAD) != ReturnCode::SUCCESS {
+                                debug!("ieee802154_framer_test failed: could not append payload");
+                            }
+                            let (res, opt_buf) = mac_device.transmit(frame);
+                            if res != ReturnCode::SUCCESS {
+                                debug!(
+                                    "ieee802154_framer_test failed: transmit returned {:?}",
+                                    res
+                                );
+                            }
+                            if let Some(buf) = opt_buf {
+                                self.tx_buf.replace(buf);
+                            }
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    fn check_test(&self) {
+        let (test, _) = TESTS[self.current_test.get()];
+        let accept = match test {
+            TestCase::Receive { accept, .. } => accept,
+            TestCase::Loopback(_) => true,
+        };
+        let passed = if accept {
+            self.received.get() && self.received_matches.get() && self.sent_matches.get()
+        } else {
+            !self.received.get()
+        };
+        if passed {
+            debug!(
+                "ieee802154_framer_test passed: (current_test={})",
+                self.current_test.get()
+            );
+        } else {
+            debug!(
+                "ieee802154_framer_test failed: (current_test={}, received={}, received_matches={}, sent_matches={})",
+                self.current_test.get(),
+                self.received.get(),
+                self.received_matches.get(),
+                self.sent_matches.get()
+            );
+        }
+    }
+
+    // Checks the auxiliary security header of a frame sent by the framer,
+    // and that its payload was encrypted if required.
+    fn check_sent_frame(&self, buf: &[u8], frame_len: usize, level: SecurityLevel) -> bool {
+        let frame = &buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len];
+        match Header::decode(frame, false).done() {
+            Some((data_offset, (header, _))) => header.security.map_or(false, |security| {
+                let counter_reserved = security
+                    .frame_counter
+                    .map_or(false, |counter| counter < self.frame_counter_reserved.get());
+                let payload = &frame[data_offset..data_offset + PAYLOAD.len()];
+                let payload_secured = if level.encryption_needed() {
+                    payload != PAYLOAD
+                } else {
+                    payload == PAYLOAD
+                };
+                security.level == level
+                    && security.key_id == KeyId::Index(KEY_INDEX)
+                    && frame_len == data_offset + PAYLOAD.len() + level.mic_len()
+                    && counter_reserved
+                    && payload_secured
+            }),
+            None => false,
+        }
+    }
+}
+
+impl Mac for Test<'a> {
+    fn initialize(&self, _mac_buf: &'static mut [u8]) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+
+    fn set_config_client(&self, _client: &'static radio::ConfigClient) {}
+
+    fn set_transmit_client(&self, client: &'static radio::TxClient) {
+        self.radio_tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'static radio::RxClient) {
+        self.radio_rx_client.set(client);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        // The framer returns the receive buffer once it has finished
+        // processing a received frame, which completes the current test.
+        self.rx_buf.replace(buffer);
+        if self.running.get() {
+            self.running.set(false);
+            self.check_test();
+            self.current_test.set(self.current_test.get() + 1);
+            if self.current_test.get() < TESTS.len() {
+                self.trigger_test();
+            }
+        }
+    }
+
+    fn get_address(&self) -> u16 {
+        self.address.get()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.address_long.get()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.pan.get()
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.address.set(addr);
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.address_long.set(addr);
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.pan.set(id);
+    }
+
+    fn get_channel(&self) -> u8 {
+        26
+    }
+
+    fn set_channel(&self, _chan: u8) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+
+    fn get_promiscuous(&self) -> bool {
+        false
+    }
+
+    fn set_promiscuous(&self, _enable: bool) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+
+    fn get_auto_ack(&self) -> bool {
+        true
+    }
+
+    fn set_auto_ack(&self, _enable: bool) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+
+    fn config_commit(&self) {}
+
+    fn is_on(&self) -> bool {
+        true
+    }
+
+    fn transmit(
+        &self,
+        full_mac_frame: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let rx_buf = match self.rx_buf.take() {
+            None => return (ReturnCode::EBUSY, Some(full_mac_frame)),
+            Some(buf) => buf,
+        };
+        if let (TestCase::Loopback(level), _) = TESTS[self.current_test.get()] {
+            self.sent_matches
+                .set(self.check_sent_frame(full_mac_frame, frame_len, level));
+        }
+
+        // Loop the secured frame back to the receive pipeline. Unlike a
+        // radio, this completes the transmission before returning.
+        let frame_end = radio::PSDU_OFFSET + frame_len;
+        rx_buf[..frame_end].copy_from_slice(&full_mac_frame[..frame_end]);
+        self.radio_tx_client.map(move |client| {
+            client.send_done(full_mac_frame, true, ReturnCode::SUCCESS);
+        });
+        self.radio_rx_client.map(move |client| {
+            client.receive(
+                rx_buf,
+                frame_len,
+                radio::LinkQuality::UNKNOWN,
+                true,
+                ReturnCode::SUCCESS,
+            );
+        });
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+impl TxClient for Test<'a> {
+    fn send_done(&self, buf: &'static mut [u8], _acked: bool, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            debug!(
+                "ieee802154_framer_test failed: send_done returned {:?}",
+                result
+            );
+        }
+        self.tx_buf.replace(buf);
+    }
+}
+
+impl RxClient for Test<'a> {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        _header: Header<'b>,
+        data_offset: usize,
+        data_len: usize,
+        _quality: radio::LinkQuality,
+    ) {
+        self.received.set(true);
+        let matches = match TESTS[self.current_test.get()] {
+            (TestCase::Receive { unsecured, .. }, _) => {
+                data_offset + data_len == radio::PSDU_OFFSET + unsecured.len()
+                    && buf[radio::PSDU_OFFSET..data_offset + data_len] == *unsecured
+            }
+            (TestCase::Loopback(_), _) => buf[data_offset..data_offset + data_len] == PAYLOAD,
+        };
+        self.received_matches.set(matches);
+    }
+}
+
+impl KeyProcedure for Test<'a> {
+    fn lookup_key(&self, _level: SecurityLevel, key_id: KeyId) -> Option<([u8; 16])> {
+        match key_id {
+            KeyId::Implicit | KeyId::Index(KEY_INDEX) => Some(KEY),
+            _ => None,
+        }
+    }
+}
+
+impl DeviceProcedure for Test<'a> {
+    fn lookup_addr_long(&self, addr: MacAddress) -> Option<([u8; 8])> {
+        match addr {
+            MacAddress::Long(addr) if addr == PEER_ADDR_LONG => Some(addr),
+            _ => None,
+        }
+    }
+
+    fn lookup_frame_counter(&self, addr_long: [u8; 8]) -> Option<u32> {
+        if addr_long == PEER_ADDR_LONG {
+            Some(self.peer_frame_counter.get())
+        } else {
+            None
+        }
+    }
+
+    fn update_frame_counter(&self, addr_long: [u8; 8], frame_counter: u32) {
+        if addr_long == PEER_ADDR_LONG {
+            self.peer_frame_counter.set(frame_counter);
+        }
+    }
+}
+
+impl FrameCounterClient for Test<'a> {
+    fn frame_counter_reserved(&self, frame_counter: u32) {
+        self.frame_counter_reserved.set(frame_counter);
+    }
+}
+
+const PAN: u16 = 0x4321;
+const KEY_INDEX: u8 = 1;
+const PAYLOAD: [u8; 4] = [0x61, 0x62, 0x63, 0x64];
+
+// IEEE 802.15.4-2015, Annex C.2.1, extended address of the sending device
+const PEER_ADDR_LONG: [u8; 8] = [0xAC, 0xDE, 0x48, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+static KEY: [u8; AES128_KEY_SIZE] = [
+    0xC0, 0xC1, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xCB, 0xCC, 0xCD, 0xCE, 0xCF,
+];
+
+// IEEE 802.15.4-2015, Annex C.2.1.1, Secured beacon frame
+static BEACON_SECURED: [u8; 34] = [
+    0x08, 0xD0, 0x84, 0x21, 0x43, 0x01, 0x00, 0x00, 0x00, 0x00, 0x48, 0xDE, 0xAC, 0x02, 0x05, 0x00,
+    0x00, 0x00, 0x55, 0xCF, 0x00, 0x00, 0x51, 0x52, 0x53, 0x54, 0x22, 0x3B, 0xC1, 0xEC, 0x84, 0x1A,
+    0xB5, 0x53,
+];
+
+// IEEE 802.15.4-2015, Annex C.2.1.2, Unsecured beacon frame with auxiliary
+// security header included and the security bits set
+static BEACON_UNSECURED: [u8; 26] = [
+    0x08, 0xD0, 0x84, 0x21, 0x43, 0x01, 0x00, 0x00, 0x00, 0x00, 0x48, 0xDE, 0xAC, 0x02, 0x05, 0x00,
+    0x00, 0x00, 0x55, 0xCF, 0x00, 0x00, 0x51, 0x52, 0x53, 0x54,
+];
+
+// IEEE 802.15.4-2015, Annex C.2.3.1, Secured MAC command frame
+static MAC_SECURED: [u8; 38] = [
+    0x2B, 0xDC, 0x84, 0x21, 0x43, 0x02, 0x00, 0x00, 0x00, 0x00, 0x48, 0xDE, 0xAC, 0xFF, 0xFF, 0x01,
+    0x00, 0x00, 0x00, 0x00, 0x48, 0xDE, 0xAC, 0x06, 0x05, 0x00, 0x00, 0x00, 0x01, 0xD8, 0x4F, 0xDE,
+    0x52, 0x90, 0x61, 0xF9, 0xC6, 0xF1,
+];
+
+// The secured MAC command frame above with one bit of the encrypted command
+// payload flipped
+static MAC_CORRUPTED: [u8; 38] = [
+    0x2B, 0xDC, 0x84, 0x21, 0x43, 0x02, 0x00, 0x00, 0x00, 0x00, 0x48, 0xDE, 0xAC, 0xFF, 0xFF, 0x01,
+    0x00, 0x00, 0x00, 0x00, 0x48, 0xDE, 0xAC, 0x06, 0x05, 0x00, 0x00, 0x00, 0x01, 0xD9, 0x4F, 0xDE,
+    0x52, 0x90, 0x61, 0xF9, 0xC6, 0xF1,
+];
+
+// IEEE 802.15.4-2015, Annex C.2.3.2, Unsecured MAC command frame with auxiliary
+// security header included and the security bits set
+static MAC_UNSECURED: [u8; 30] = [
+    0x2B, 0xDC, 0x84, 0x21, 0x43, 0x02, 0x00, 0x00, 0x00, 0x00, 0x48, 0xDE, 0xAC, 0xFF, 0xFF, 0x01,
+    0x00, 0x00, 0x00, 0x00, 0x48, 0xDE, 0xAC, 0x06, 0x05, 0x00, 0x00, 0x00, 0x01, 0xCE,
+];
@@ -1,4 +1,5 @@
 pub mod aes;
 pub mod aes_ccm;
+pub mod ieee802154_framer;
 pub mod rng;
 pub mod virtual_uart;
@@ -1,6 +1,9 @@
 //! A bare-bones client of the USB hardware interface
 //!
 //! It responds to standard device requests and can be enumerated.
+//!
+//! By default it echoes the data received on its bulk OUT endpoint back on
+//! its bulk IN endpoint. A `BulkClient` can take over both endpoints instead.
 
 use crate::usb::ConfigurationDescriptor;
 use crate::usb::Descriptor;
@@ -17,7 +20,7 @@ use crate::usb::TransferDirection;
 use crate::usb::TransferType;
 use core::cell::Cell;
 use core::cmp::min;
-use kernel::common::cells::VolatileCell;
+use kernel::common::cells::{OptionalCell, VolatileCell};
 use kernel::debug;
 use kernel::hil;
 
@@ -41,6 +44,26 @@ const DESCRIPTOR_BUFLEN: usize = 32;
 
 const N_ENDPOINTS: usize = 3;
 
+/// Handles the packets of the bulk endpoints.
+pub trait BulkClient {
+    /// A packet arrived from the host. Returns false if the client cannot take
+    /// it yet, in which case the packet is offered again after `resume_out`.
+    fn packet_out(&self, packet: &[u8]) -> bool;
+
+    /// The host asks for a packet. Returns the number of bytes written to
+    /// `packet`, or 0 if there is nothing to send until `resume_in`.
+    fn packet_in(&self, packet: &mut [u8]) -> usize;
+}
+
+/// Lets a `BulkClient` resume the bulk endpoints.
+pub trait BulkResume {
+    /// The client can take packets from the host again.
+    fn resume_out(&self);
+
+    /// The client has a packet for the host.
+    fn resume_in(&self);
+}
+
 pub struct Client<'a, C: 'a> {
     // The hardware controller
     controller: &'a C,
@@ -60,6 +83,8 @@ pub struct Client<'a, C: 'a> {
     echo_len: Cell<usize>,
     delayed_in: Cell<bool>,
     delayed_out: Cell<bool>,
+
+    bulk_client: OptionalCell<&'a BulkClient>,
 }
 
 #[derive(Copy, Clone)]
@@ -95,11 +120,17 @@ impl<C: hil::usb::UsbController> Client<'a, C> {
             echo_len: Cell::new(0),
             delayed_in: Cell::new(false),
             delayed_out: Cell::new(false),
+            bulk_client: OptionalCell::empty(),
         }
     }
 
+    /// Hand the bulk endpoints to `client` instead of echoing their data.
+    pub fn set_bulk_client(&self, client: &'a BulkClient) {
+        self.bulk_client.set(client);
+    }
+
     #[inline]
-    fn descriptor_buf(&'a self) -> &'a [Cell<u8>] {
+    fn descriptor_buf(&self) -> &[Cell<u8>] {
         &self.descriptor_storage
     }
 
@@ -120,6 +151,16 @@ impl<C: hil::usb::UsbController> Client<'a, C> {
     }
 }
 
+impl<C: hil::usb::UsbController> BulkResume for Client<'a, C> {
+    fn resume_out(&self) {
+        self.alert_empty();
+    }
+
+    fn resume_in(&self) {
+        self.alert_full();
+    }
+}
+
 impl<C: hil::usb::UsbController> hil::usb::Client for Client<'a, C> {
     fn enable(&self) {
         // Set up the default control endpoint
@@ -410,6 +451,23 @@ impl<C: hil::usb::UsbController> hil::usb::Client for Client<'a, C> {
     fn bulk_in(&self, endpoint: usize) -> hil::usb::BulkInResult {
         // Write a packet into the endpoint buffer
 
+        if let Some(result) = self.bulk_client.map(|client| {
+            let mut packet = [0; 8];
+            let packet_bytes = min(client.packet_in(&mut packet), packet.len());
+            if packet_bytes > 0 {
+                let buf = &self.buffers[endpoint];
+                for i in 0..packet_bytes {
+                    buf[i].set(packet[i]);
+                }
+                hil::usb::BulkInResult::Packet(packet_bytes)
+            } else {
+                self.delayed_in.set(true);
+                hil::usb::BulkInResult::Delay
+            }
+        }) {
+            return result;
+        }
+
         let packet_bytes = self.echo_len.get();
         if packet_bytes > 0 {
             // Copy the entire echo buffer into the packet
@@ -435,6 +493,24 @@ impl<C: hil::usb::UsbController> hil::usb::Client for Client<'a, C> {
         // Consume a packet from the endpoint buffer
 
         let new_len = packet_bytes as usize;
+
+        if let Some(result) = self.bulk_client.map(|client| {
+            let mut packet = [0; 8];
+            let new_len = min(new_len, packet.len());
+            let buf = &self.buffers[endpoint];
+            for i in 0..new_len {
+                packet[i] = buf[i].get();
+            }
+            if client.packet_out(&packet[..new_len]) {
+                hil::usb::BulkOutResult::Ok
+            } else {
+                self.delayed_out.set(true);
+                hil::usb::BulkOutResult::Delay
+            }
+        }) {
+            return result;
+        }
+
         let current_len = self.echo_len.get();
         let total_len = current_len + new_len as usize;
 
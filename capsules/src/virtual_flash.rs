@@ -8,6 +8,23 @@
 //! must use a `FlashUser` instance to contain the per-user state for the
 //! virtualization.
 //!
+//! The mux also decides which pages each user may access:
+//!
+//! - A `FlashUser` can be limited to a range of pages with
+//!   `set_allowed_pages()`. Reads, writes and erases outside it fail.
+//! - Pages made read only with `MuxFlash::set_read_only()`, such as the
+//!   bootloader and kernel code, cannot be written or erased by any user.
+//! - After `MuxFlash::protect_app_headers()` the pages holding the TBF headers
+//!   of the loaded apps cannot be written or erased either. The apps are
+//!   found through `AppHeaders`, which `ProcessHeaders` implements for the
+//!   processes of the kernel.
+//!
+//! Requests that break these rules return `EINVAL`. Like the flash itself,
+//! the buffer of a refused write or read is not returned. On flash that
+//! implements `hil::flash::WriteProtect`, `MuxFlash::lock()` additionally
+//! protects the read only pages and app headers in hardware, until reset or,
+//! on flash that keeps its protection across resets, `MuxFlash::unlock()`.
+//!
 //! Usage
 //! -----
 //!
@@ -22,14 +39,67 @@
 //! let virtual_flash = static_init!(
 //!     capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
 //!     capsules::virtual_flash::FlashUser::new(mux_flash));
+//!
+//! // Keep the bootloader and kernel code, and the app headers, safe from all
+//! // users, and keep this user to the last 64 pages of the flash.
+//! let app_headers = static_init!(
+//!     capsules::virtual_flash::ProcessHeaders<'static>,
+//!     capsules::virtual_flash::ProcessHeaders::new(&PROCESSES));
+//! mux_flash.set_read_only(0, kernel_end_page);
+//! mux_flash.protect_app_headers(app_headers, 0, 512);
+//! mux_flash.lock();
+//! virtual_flash.set_allowed_pages(960, 1024);
 //! ```
 
 use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::common::{List, ListLink, ListNode};
 use kernel::hil;
+use kernel::procs::ProcessType;
 use kernel::ReturnCode;
 
+/// The apps whose TBF headers `MuxFlash` protects.
+pub trait AppHeaders {
+    /// The number of apps that may be loaded.
+    fn count(&self) -> usize;
+
+    /// The address in flash of app `index`, and the address just past its TBF
+    /// header, if it is loaded.
+    fn header(&self, index: usize) -> Option<(usize, usize)>;
+}
+
+/// The TBF headers of the processes of the kernel, including those that are
+/// loaded later.
+pub struct ProcessHeaders<'a> {
+    processes: &'a [Option<&'a ProcessType>],
+}
+
+impl ProcessHeaders<'a> {
+    pub const fn new(processes: &'a [Option<&'a ProcessType>]) -> ProcessHeaders<'a> {
+        ProcessHeaders {
+            processes: processes,
+        }
+    }
+}
+
+impl AppHeaders for ProcessHeaders<'a> {
+    fn count(&self) -> usize {
+        self.processes.len()
+    }
+
+    fn header(&self, index: usize) -> Option<(usize, usize)> {
+        self.processes
+            .get(index)
+            .and_then(|process| *process)
+            .map(|process| {
+                (
+                    process.flash_start() as usize,
+                    process.flash_non_protected_start() as usize,
+                )
+            })
+    }
+}
+
 /// Handle keeping a list of active users of flash hardware and serialize their
 /// requests. After each completed request the list is checked to see if there
 /// is another flash user with an outstanding read, write, or erase request.
@@ -37,6 +107,13 @@ pub struct MuxFlash<'a, F: hil::flash::Flash + 'static> {
     flash: &'a F,
     users: List<'a, FlashUser<'a, F>>,
     inflight: OptionalCell<&'a FlashUser<'a, F>>,
+    /// Pages that no user may write or erase, from the first up to the second.
+    read_only: Cell<(usize, usize)>,
+    /// Apps whose TBF headers are protected.
+    app_headers: OptionalCell<&'a AppHeaders>,
+    /// Address of page 0 and size of pages, to find the pages of headers.
+    flash_address: Cell<usize>,
+    page_size: Cell<usize>,
 }
 
 impl<F: hil::flash::Flash> hil::flash::Client<F> for MuxFlash<'a, F> {
@@ -68,7 +145,69 @@ impl<F: hil::flash::Flash> MuxFlash<'a, F> {
             flash: flash,
             users: List::new(),
             inflight: OptionalCell::empty(),
+            read_only: Cell::new((0, 0)),
+            app_headers: OptionalCell::empty(),
+            flash_address: Cell::new(0),
+            page_size: Cell::new(0),
+        }
+    }
+
+    /// Make the pages from `start_page` up to, but not including, `end_page`
+    /// read only for all users. This is meant for the kernel and bootloader.
+    pub fn set_read_only(&self, start_page: usize, end_page: usize) {
+        self.read_only.set((start_page, end_page));
+    }
+
+    /// Keep all users from writing or erasing the pages holding the TBF
+    /// headers of `app_headers`, which the kernel keeps apps from changing
+    /// too. The flash starts at `flash_address` and has pages of `page_size`
+    /// bytes.
+    pub fn protect_app_headers(
+        &self,
+        app_headers: &'a AppHeaders,
+        flash_address: usize,
+        page_size: usize,
+    ) {
+        self.app_headers.set(app_headers);
+        self.flash_address.set(flash_address);
+        self.page_size.set(page_size);
+    }
+
+    /// Call `f` with the pages holding each TBF header, until it returns
+    /// false. Returns whether it never did.
+    fn each_header_pages<G: FnMut((usize, usize)) -> bool>(&self, mut f: G) -> bool {
+        self.app_headers.map_or(true, |app_headers| {
+            let flash_address = self.flash_address.get();
+            let page_size = self.page_size.get();
+            (0..app_headers.count())
+                .filter_map(|index| app_headers.header(index))
+                .all(|(start, end)| {
+                    let start = start - flash_address;
+                    let end = end - flash_address;
+                    f((start / page_size, (end + page_size - 1) / page_size))
+                })
+        })
+    }
+
+    /// Whether `user` may do `operation`.
+    fn allows(&self, user: &FlashUser<'a, F>, operation: Op) -> bool {
+        let (page_number, modifies) = match operation {
+            Op::Idle => return true,
+            Op::Read(page_number) => (page_number, false),
+            Op::Write(page_number) | Op::Erase(page_number) => (page_number, true),
+        };
+        let within = |(start, end): (usize, usize)| page_number >= start && page_number < end;
+
+        if !within(user.allowed_pages.get()) {
+            return false;
+        }
+        if !modifies {
+            return true;
+        }
+        if within(self.read_only.get()) {
+            return false;
         }
+        self.each_header_pages(|pages| !within(pages))
     }
 
     /// Scan the list of users and find the first user that has a pending
@@ -112,6 +251,42 @@ impl<F: hil::flash::Flash> MuxFlash<'a, F> {
     }
 }
 
+impl<F: hil::flash::Flash + hil::flash::WriteProtect> MuxFlash<'a, F> {
+    /// Also protect the read only pages and the headers of the processes
+    /// loaded so far in hardware. The flash may only be able to protect some
+    /// of them. Depending on the flash, they stay protected until reset or,
+    /// as on the SAM4L, until `unlock` is called. Call this while booting,
+    /// before any user starts an operation.
+    pub fn lock(&self) -> ReturnCode {
+        self.each_protected_range(|start, end| self.flash.protect(start, end))
+    }
+
+    /// Remove the hardware protection that `lock` added, for example so that
+    /// a bootloader can reflash the kernel on flash that stays protected
+    /// across resets. Users of the mux still cannot write or erase the pages.
+    pub fn unlock(&self) -> ReturnCode {
+        self.each_protected_range(|start, end| self.flash.unprotect(start, end))
+    }
+
+    // Call `f` with the read only pages and the header pages of each process,
+    // until it fails.
+    fn each_protected_range<G: Fn(usize, usize) -> ReturnCode>(&self, f: G) -> ReturnCode {
+        let (start, end) = self.read_only.get();
+        if start < end {
+            let result = f(start, end);
+            if result != ReturnCode::SUCCESS {
+                return result;
+            }
+        }
+        let mut result = ReturnCode::SUCCESS;
+        self.each_header_pages(|(start, end)| {
+            result = f(start, end);
+            result == ReturnCode::SUCCESS
+        });
+        result
+    }
+}
+
 #[derive(Copy, Clone, PartialEq)]
 enum Op {
     Idle,
@@ -130,6 +305,8 @@ pub struct FlashUser<'a, F: hil::flash::Flash + 'static> {
     operation: Cell<Op>,
     next: ListLink<'a, FlashUser<'a, F>>,
     client: OptionalCell<&'a hil::flash::Client<FlashUser<'a, F>>>,
+    /// Pages this user may access, from the first up to the second.
+    allowed_pages: Cell<(usize, usize)>,
 }
 
 impl<F: hil::flash::Flash> FlashUser<'a, F> {
@@ -140,7 +317,24 @@ impl<F: hil::flash::Flash> FlashUser<'a, F> {
             operation: Cell::new(Op::Idle),
             next: ListLink::empty(),
             client: OptionalCell::empty(),
+            allowed_pages: Cell::new((0, usize::max_value())),
+        }
+    }
+
+    /// Limit this user to the pages from `start_page` up to, but not
+    /// including, `end_page`. By default a user may access all pages.
+    pub fn set_allowed_pages(&self, start_page: usize, end_page: usize) {
+        self.allowed_pages.set((start_page, end_page));
+    }
+
+    fn request(&self, operation: Op, buf: Option<&'static mut F::Page>) -> ReturnCode {
+        if !self.mux.allows(self, operation) {
+            return ReturnCode::EINVAL;
         }
+        buf.map(|buf| self.buffer.replace(buf));
+        self.operation.set(operation);
+        self.mux.do_next_op();
+        ReturnCode::SUCCESS
     }
 }
 
@@ -183,22 +377,196 @@ impl<F: hil::flash::Flash> hil::flash::Flash for FlashUser<'a, F> {
     type Page = F::Page;
 
     fn read_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
-        self.buffer.replace(buf);
-        self.operation.set(Op::Read(page_number));
-        self.mux.do_next_op();
-        ReturnCode::SUCCESS
+        self.request(Op::Read(page_number), Some(buf))
     }
 
     fn write_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
-        self.buffer.replace(buf);
-        self.operation.set(Op::Write(page_number));
-        self.mux.do_next_op();
-        ReturnCode::SUCCESS
+        self.request(Op::Write(page_number), Some(buf))
     }
 
     fn erase_page(&self, page_number: usize) -> ReturnCode {
-        self.operation.set(Op::Erase(page_number));
-        self.mux.do_next_op();
-        ReturnCode::SUCCESS
+        self.request(Op::Erase(page_number), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::storage_sim::{leak, pump, Sim, SimFlash, SimPage, WriteMode, PAGE_SIZE};
+    use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
+    use kernel::hil::flash::{Flash, HasClient};
+    use std::cell::RefCell;
+    use std::vec;
+    use std::vec::Vec;
+
+    type User = FlashUser<'static, SimFlash<'static>>;
+
+    /// Address of page 0 of the simulated flash.
+    const FLASH_ADDRESS: usize = 0x40000;
+
+    /// Apps at the given pages, with TBF headers of the given lengths.
+    struct TestHeaders(Vec<(usize, usize)>);
+
+    impl AppHeaders for TestHeaders {
+        fn count(&self) -> usize {
+            self.0.len() + 1
+        }
+
+        // The last app is not loaded.
+        fn header(&self, index: usize) -> Option<(usize, usize)> {
+            self.0.get(index).map(|&(page, header_length)| {
+                let start = FLASH_ADDRESS + page * PAGE_SIZE;
+                (start, start + header_length)
+            })
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Read(u8),
+        Written,
+        Erased,
+    }
+
+    struct TestClient {
+        events: RefCell<Vec<Event>>,
+    }
+
+    impl hil::flash::Client<User> for TestClient {
+        fn read_complete(&self, buffer: &'static mut SimPage, _error: hil::flash::Error) {
+            self.events.borrow_mut().push(Event::Read(buffer.0[0]));
+        }
+
+        fn write_complete(&self, _buffer: &'static mut SimPage, _error: hil::flash::Error) {
+            self.events.borrow_mut().push(Event::Written);
+        }
+
+        fn erase_complete(&self, _error: hil::flash::Error) {
+            self.events.borrow_mut().push(Event::Erased);
+        }
+    }
+
+    fn setup() -> (
+        &'static MuxFlash<'static, SimFlash<'static>>,
+        &'static User,
+        &'static DynamicDeferredCall,
+        &'static TestClient,
+    ) {
+        let sim = Sim::flash(vec![0xff; PAGE_SIZE * 16], WriteMode::EraseOnWrite);
+        let mux = leak(MuxFlash::new(sim.storage));
+        sim.storage.set_client(mux);
+        let user = leak(FlashUser::new(mux));
+        let client = leak(TestClient {
+            events: RefCell::new(Vec::new()),
+        });
+        user.set_client(client);
+        (mux, user, sim.deferred_caller, client)
+    }
+
+    #[test]
+    fn keeps_users_to_their_pages() {
+        let (_, user, deferred_caller, client) = setup();
+        user.set_allowed_pages(4, 8);
+        assert_eq!(user.read_page(3, leak(SimPage::new())), ReturnCode::EINVAL);
+        assert_eq!(user.erase_page(8), ReturnCode::EINVAL);
+
+        let page = leak(SimPage([0x5a; PAGE_SIZE]));
+        assert_eq!(user.write_page(4, page), ReturnCode::SUCCESS);
+        pump(deferred_caller);
+        assert_eq!(user.read_page(4, leak(SimPage::new())), ReturnCode::SUCCESS);
+        pump(deferred_caller);
+        assert_eq!(
+            *client.events.borrow(),
+            vec![Event::Written, Event::Read(0x5a)]
+        );
+    }
+
+    #[test]
+    fn read_only_pages_can_only_be_read() {
+        let (mux, user, deferred_caller, client) = setup();
+        mux.set_read_only(0, 2);
+        assert_eq!(user.write_page(1, leak(SimPage::new())), ReturnCode::EINVAL);
+        assert_eq!(user.erase_page(0), ReturnCode::EINVAL);
+
+        assert_eq!(user.read_page(1, leak(SimPage::new())), ReturnCode::SUCCESS);
+        pump(deferred_caller);
+        assert_eq!(user.erase_page(2), ReturnCode::SUCCESS);
+        pump(deferred_caller);
+        assert_eq!(
+            *client.events.borrow(),
+            vec![Event::Read(0xff), Event::Erased]
+        );
+    }
+
+    #[test]
+    fn lock_protects_read_only_pages_until_unlocked() {
+        let (mux, user, _, _) = setup();
+        mux.flash.set_lock_region_pages(2);
+        mux.set_read_only(0, 3);
+        assert_eq!(mux.lock(), ReturnCode::SUCCESS);
+        assert!(mux.flash.is_protected(0) && mux.flash.is_protected(1));
+        assert!(!mux.flash.is_protected(2));
+
+        assert_eq!(mux.unlock(), ReturnCode::SUCCESS);
+        assert!((0..8).all(|page| !mux.flash.is_protected(page)));
+        assert_eq!(user.erase_page(0), ReturnCode::EINVAL);
+    }
+
+    /// Protects the header of an app at page 4 that fits in its first page,
+    /// and of one at page 6 that spills into page 7.
+    fn protect_app_headers(mux: &'static MuxFlash<'static, SimFlash<'static>>) {
+        let app_headers = leak(TestHeaders(vec![(4, 0x30), (6, PAGE_SIZE + 0x58)]));
+        mux.protect_app_headers(app_headers, FLASH_ADDRESS, PAGE_SIZE);
+    }
+
+    #[test]
+    fn app_headers_cannot_be_written_or_erased() {
+        let (mux, user, deferred_caller, client) = setup();
+        protect_app_headers(mux);
+        assert_eq!(user.write_page(4, leak(SimPage::new())), ReturnCode::EINVAL);
+        assert_eq!(user.erase_page(6), ReturnCode::EINVAL);
+        assert_eq!(user.erase_page(7), ReturnCode::EINVAL);
+
+        assert_eq!(
+            user.write_page(5, leak(SimPage::new())),
+            ReturnCode::SUCCESS
+        );
+        pump(deferred_caller);
+        assert_eq!(user.erase_page(8), ReturnCode::SUCCESS);
+        pump(deferred_caller);
+        assert_eq!(user.read_page(4, leak(SimPage::new())), ReturnCode::SUCCESS);
+        pump(deferred_caller);
+        assert_eq!(
+            *client.events.borrow(),
+            vec![Event::Written, Event::Erased, Event::Read(0xff)]
+        );
+    }
+
+    #[test]
+    fn lock_protects_app_headers_in_hardware() {
+        let (mux, _, _, _) = setup();
+        protect_app_headers(mux);
+        assert_eq!(mux.lock(), ReturnCode::SUCCESS);
+        let protected: Vec<usize> = (0..16)
+            .filter(|&page| mux.flash.is_protected(page))
+            .collect();
+        assert_eq!(protected, vec![4, 6, 7]);
+
+        assert_eq!(mux.unlock(), ReturnCode::SUCCESS);
+        assert!((0..16).all(|page| !mux.flash.is_protected(page)));
+    }
+
+    #[test]
+    fn lock_skips_headers_that_share_a_lock_region() {
+        let (mux, _, _, _) = setup();
+        mux.flash.set_lock_region_pages(2);
+        protect_app_headers(mux);
+        assert_eq!(mux.lock(), ReturnCode::SUCCESS);
+        let protected: Vec<usize> = (0..16)
+            .filter(|&page| mux.flash.is_protected(page))
+            .collect();
+        assert_eq!(protected, vec![6, 7]);
     }
 }
@@ -268,6 +268,12 @@ impl Ficr {
         }
     }
 
+    /// Whether this is an nRF52832, as opposed to a later part such as the
+    /// nRF52840.
+    pub fn is_nrf52832(&self) -> bool {
+        self.part() == Part::N52832
+    }
+
     fn variant(&self) -> Variant {
         let regs = &*self.registers;
         match regs.info_variant.get() {
@@ -311,6 +317,17 @@ impl Ficr {
             _ => Flash::Unspecified,
         }
     }
+
+    /// The factory programmed device address as a BLE static random address,
+    /// least significant byte first.
+    pub fn ble_address(&self) -> [u8; 6] {
+        let regs = &*self.registers;
+        let low = regs.deviceaddr0.get().to_le_bytes();
+        let high = (regs.deviceaddr1.get() as u16).to_le_bytes();
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 1.3.2.1
+        // The two most significant bits of a static address are set
+        [low[0], low[1], low[2], low[3], high[0], high[1] | 0xc0]
+    }
 }
 
 impl fmt::Display for Ficr {
@@ -1,6 +1,11 @@
 //! Non-Volatile Memory Controller
 //!
 //! Used in order read and write to internal flash.
+//!
+//! On the nRF52832 the block protection (BPROT) peripheral backs
+//! `hil::flash::WriteProtect`. A protected page stays protected until reset,
+//! and writing or erasing it would fault, so this driver refuses to with
+//! `EINVAL`. Other parts do not have BPROT and return `ENOSUPPORT`.
 
 use core::cell::Cell;
 use core::ops::{Index, IndexMut};
@@ -14,10 +19,14 @@ use kernel::hil;
 use kernel::ReturnCode;
 
 use crate::deferred_call_tasks::DeferredCallTask;
+use crate::ficr;
 
 const NVMC_BASE: StaticRef<NvmcRegisters> =
     unsafe { StaticRef::new(0x4001E400 as *const NvmcRegisters) };
 
+const BPROT_BASE: StaticRef<BprotRegisters> =
+    unsafe { StaticRef::new(0x40000600 as *const BprotRegisters) };
+
 #[repr(C)]
 struct NvmcRegisters {
     /// Ready flag
@@ -53,6 +62,40 @@ struct NvmcRegisters {
     pub imiss: ReadWrite<u32, CacheMiss::Register>,
 }
 
+#[repr(C)]
+struct BprotRegisters {
+    /// Block protect configuration for pages 0 to 31, one bit each
+    /// Address: 0x600 - 0x604
+    pub config0: ReadWrite<u32>,
+    /// Block protect configuration for pages 32 to 63
+    /// Address: 0x604 - 0x608
+    pub config1: ReadWrite<u32>,
+    /// Disable protection mechanism in debug interface mode
+    /// Address: 0x608 - 0x60C
+    pub disableindebug: ReadWrite<u32>,
+    _reserved1: u32,
+    /// Block protect configuration for pages 64 to 95
+    /// Address: 0x610 - 0x614
+    pub config2: ReadWrite<u32>,
+    /// Block protect configuration for pages 96 to 127
+    /// Address: 0x614 - 0x618
+    pub config3: ReadWrite<u32>,
+}
+
+impl BprotRegisters {
+    fn config(&self, page_number: usize) -> &ReadWrite<u32> {
+        match page_number / 32 {
+            0 => &self.config0,
+            1 => &self.config1,
+            2 => &self.config2,
+            _ => &self.config3,
+        }
+    }
+}
+
+/// Number of 4 kB pages the BPROT peripheral can protect.
+const BPROT_PAGES: usize = 128;
+
 register_bitfields! [u32,
     /// Ready flag
     Ready [
@@ -197,6 +240,7 @@ pub static mut NVMC: Nvmc = Nvmc::new();
 
 pub struct Nvmc {
     registers: StaticRef<NvmcRegisters>,
+    bprot_registers: StaticRef<BprotRegisters>,
     client: OptionalCell<&'static hil::flash::Client<Nvmc>>,
     buffer: TakeCell<'static, NrfPage>,
     state: Cell<FlashState>,
@@ -206,6 +250,7 @@ impl Nvmc {
     pub const fn new() -> Nvmc {
         Nvmc {
             registers: NVMC_BASE,
+            bprot_registers: BPROT_BASE,
             client: OptionalCell::empty(),
             buffer: TakeCell::empty(),
             state: Cell::new(FlashState::Ready),
@@ -237,6 +282,19 @@ impl Nvmc {
         regs.ready.is_set(Ready::READY)
     }
 
+    fn has_bprot(&self) -> bool {
+        unsafe { ficr::FICR_INSTANCE.is_nrf52832() }
+    }
+
+    /// Check if a page is protected by BPROT.
+    fn is_protected(&self, page_number: usize) -> bool {
+        if !self.has_bprot() || page_number >= BPROT_PAGES {
+            return false;
+        }
+        let config = self.bprot_registers.config(page_number);
+        config.get() & (1 << (page_number % 32)) != 0
+    }
+
     pub fn handle_interrupt(&self) {
         let state = self.state.get();
         self.state.set(FlashState::Ready);
@@ -305,6 +363,10 @@ impl Nvmc {
     fn write_page(&self, page_number: usize, data: &'static mut NrfPage) -> ReturnCode {
         let regs = &*self.registers;
 
+        if self.is_protected(page_number) {
+            return ReturnCode::EINVAL;
+        }
+
         // Need to erase the page first.
         self.erase_page_helper(page_number);
 
@@ -338,6 +400,10 @@ impl Nvmc {
     }
 
     fn erase_page(&self, page_number: usize) -> ReturnCode {
+        if self.is_protected(page_number) {
+            return ReturnCode::EINVAL;
+        }
+
         // Do the basic erase.
         self.erase_page_helper(page_number);
 
@@ -371,3 +437,23 @@ impl hil::flash::Flash for Nvmc {
         self.erase_page(page_number)
     }
 }
+
+impl hil::flash::WriteProtect for Nvmc {
+    fn protect(&self, start_page: usize, end_page: usize) -> ReturnCode {
+        if !self.has_bprot() {
+            return ReturnCode::ENOSUPPORT;
+        }
+
+        // BPROT protects single pages, and its bits can only be set.
+        for page_number in start_page..core::cmp::min(end_page, BPROT_PAGES) {
+            let config = self.bprot_registers.config(page_number);
+            config.set(1 << (page_number % 32));
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn unprotect(&self, _start_page: usize, _end_page: usize) -> ReturnCode {
+        // BPROT only clears its bits on reset.
+        ReturnCode::ENOSUPPORT
+    }
+}
@@ -34,6 +34,7 @@
 //! * CRC - 3 bytes
 
 use core::cell::Cell;
+use core::cmp;
 use core::convert::TryFrom;
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
@@ -528,11 +529,51 @@ register_bitfields! [u32,
 static mut PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
     [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
 
+// Response to a received data channel PDU, filled in by the connection client
+// while the radio turns around
+static mut RESPONSE: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
+    [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.1 Timing Requirements
+const BLE_T_IFS_US: u32 = 150;
+
+/// What the radio is currently doing, used to dispatch END events.
+#[derive(Copy, Clone, PartialEq)]
+enum Operation {
+    /// Advertising or passive scanning, reported through `TxClient`
+    /// and `RxClient`
+    Advertising,
+    /// Transmitting an advertisement before listening for requests that are
+    /// offered to the `ResponseClient`
+    ListenAfterTx,
+    /// Listening for a packet to offer to the `ResponseClient`. When scanning,
+    /// the radio receives again after the response has been sent
+    Listening { scanning: bool },
+    /// Transmitting the response of the `ResponseClient`
+    RespondingTx { scanning: bool },
+    /// Receiving the answer to a response sent while scanning, reported
+    /// through `RxClient`
+    ResponseRx,
+    /// Transmitting a connectable advertisement before listening for requests
+    ConnectableAdvertisingTx,
+    /// Listening for requests after a connectable advertisement
+    ConnectableAdvertisingRx,
+    /// Listening for a data channel PDU from the master
+    ConnectionRx,
+    /// Transmitting the response to a data channel PDU
+    ConnectionTx,
+}
+
 pub struct Radio {
     registers: StaticRef<RadioRegisters>,
     tx_power: Cell<TxPower>,
     rx_client: OptionalCell<&'static ble_advertising::RxClient>,
     tx_client: OptionalCell<&'static ble_advertising::TxClient>,
+    response_client: OptionalCell<&'static ble_advertising::ResponseClient>,
+    connection_client: OptionalCell<&'static ble_advertising::ConnectionClient>,
+    operation: Cell<Operation>,
+    access_address: Cell<u32>,
+    crc_init: Cell<u32>,
 }
 
 pub static mut RADIO: Radio = Radio::new();
@@ -544,6 +585,11 @@ impl Radio {
             tx_power: Cell::new(TxPower::ZerodBm),
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
+            response_client: OptionalCell::empty(),
+            connection_client: OptionalCell::empty(),
+            operation: Cell::new(Operation::Advertising),
+            access_address: Cell::new(nrf5x::constants::RADIO_ACCESS_ADDRESS_BLE),
+            crc_init: Cell::new(nrf5x::constants::RADIO_CRCINIT_BLE),
         }
     }
 
@@ -598,12 +644,6 @@ impl Radio {
         let regs = &*self.registers;
         self.disable_all_interrupts();
 
-        if regs.event_ready.is_set(Event::READY) {
-            regs.event_ready.write(Event::READY::CLEAR);
-            regs.event_end.write(Event::READY::CLEAR);
-            regs.task_start.write(Task::ENABLE::SET);
-        }
-
         if regs.event_address.is_set(Event::READY) {
             regs.event_address.write(Event::READY::CLEAR);
         }
@@ -612,6 +652,10 @@ impl Radio {
         }
 
         // tx or rx finished!
+        //
+        // This is handled before READY, because a connection response is
+        // already ramping up when the END of the received packet is handled
+        // and must not be started before its buffer is in place
         if regs.event_end.is_set(Event::READY) {
             regs.event_end.write(Event::READY::CLEAR);
 
@@ -621,33 +665,163 @@ impl Radio {
                 ReturnCode::FAIL
             };
 
-            match regs.state.get() {
-                nrf5x::constants::RADIO_STATE_TXRU
-                | nrf5x::constants::RADIO_STATE_TXIDLE
-                | nrf5x::constants::RADIO_STATE_TXDISABLE
-                | nrf5x::constants::RADIO_STATE_TX => {
-                    self.radio_off();
+            match self.operation.get() {
+                Operation::Advertising | Operation::ResponseRx => self.advertising_end(result),
+                Operation::ListenAfterTx => {
+                    // The radio is turning around to listen, and turns around
+                    // again to transmit a response
+                    regs.shorts.write(
+                        Shortcut::READY_START::SET
+                            + Shortcut::END_DISABLE::SET
+                            + Shortcut::DISABLED_TXEN::SET,
+                    );
+                    self.operation.set(Operation::Listening { scanning: false });
                     self.tx_client.map(|client| client.transmit_event(result));
                 }
-                nrf5x::constants::RADIO_STATE_RXRU
-                | nrf5x::constants::RADIO_STATE_RXIDLE
-                | nrf5x::constants::RADIO_STATE_RXDISABLE
-                | nrf5x::constants::RADIO_STATE_RX => {
+                Operation::Listening { scanning } => {
+                    let len = if result == ReturnCode::SUCCESS {
+                        unsafe {
+                            let len = cmp::min(PAYLOAD[1] as usize + 2, PAYLOAD.len());
+                            self.response_client
+                                .map_or(0, |client| client.respond(&PAYLOAD[..len], &mut RESPONSE))
+                        }
+                    } else {
+                        0
+                    };
+                    if len > 0 {
+                        // The transmitter is already ramping up
+                        if scanning {
+                            regs.shorts.write(
+                                Shortcut::READY_START::SET
+                                    + Shortcut::END_DISABLE::SET
+                                    + Shortcut::DISABLED_RXEN::SET,
+                            );
+                        } else {
+                            regs.shorts
+                                .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+                        }
+                        unsafe {
+                            regs.packetptr.set(RESPONSE.as_ptr() as u32);
+                        }
+                        self.operation.set(Operation::RespondingTx { scanning });
+                        self.enable_connection_interrupts();
+                        return;
+                    }
+                    // No response, stop the transmitter that is ramping up
+                    regs.shorts.set(0);
+                    regs.task_disable.write(Task::ENABLE::SET);
+                    self.radio_off();
+                    self.operation.set(Operation::Advertising);
+                    if scanning {
+                        unsafe {
+                            self.rx_client.map(|client| {
+                                client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 2, result)
+                            });
+                        }
+                    }
+                }
+                Operation::RespondingTx { scanning } => {
+                    if scanning {
+                        // Receive the answer into the regular buffer and keep
+                        // the radio enabled so that its END is reported like
+                        // any other received advertisement
+                        regs.shorts.write(Shortcut::READY_START::SET);
+                        self.set_dma_ptr();
+                        self.operation.set(Operation::ResponseRx);
+                    } else {
+                        self.radio_off();
+                        self.operation.set(Operation::Advertising);
+                    }
+                }
+                Operation::ConnectableAdvertisingTx => {
+                    // The radio is turning around to listen for requests
+                    regs.shorts
+                        .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+                    self.operation.set(Operation::ConnectableAdvertisingRx);
+                }
+                Operation::ConnectableAdvertisingRx => {
                     self.radio_off();
+                    self.operation.set(Operation::Advertising);
                     unsafe {
-                        self.rx_client.map(|client| {
-                            // Length is: S0 (1 Byte) + Length (1 Byte) + S1 (0 Bytes) + Payload
-                            // And because the length field is directly read from the packet
-                            // We need to add 2 to length to get the total length
-                            client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 2, result)
+                        let len = cmp::min(PAYLOAD[1] as usize + 2, PAYLOAD.len());
+                        self.connection_client
+                            .map(|client| client.advertising_request(&PAYLOAD[..len], result));
+                    }
+                }
+                Operation::ConnectionRx => {
+                    // The DISABLED_TXEN shortcut is already ramping up the
+                    // transmitter, so only the response has to be provided
+                    regs.shorts
+                        .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+                    unsafe {
+                        let len = cmp::min(PAYLOAD[1] as usize + 2, PAYLOAD.len());
+                        self.connection_client.map(|client| {
+                            client.data_received(&PAYLOAD[..len], result, &mut RESPONSE)
                         });
+                        regs.packetptr.set(RESPONSE.as_ptr() as u32);
                     }
+                    self.operation.set(Operation::ConnectionTx);
+                    self.enable_connection_interrupts();
+                    return;
+                }
+                Operation::ConnectionTx => {
+                    self.radio_off();
+                    self.operation.set(Operation::Advertising);
+                    self.connection_client
+                        .map(|client| client.response_sent(ReturnCode::SUCCESS));
+                    return;
                 }
-                // Radio state - Disabled
-                _ => (),
             }
         }
-        self.enable_interrupts();
+
+        if regs.event_ready.is_set(Event::READY) {
+            regs.event_ready.write(Event::READY::CLEAR);
+            if self.operation.get() == Operation::Advertising {
+                regs.event_end.write(Event::READY::CLEAR);
+                regs.task_start.write(Task::ENABLE::SET);
+            }
+        }
+
+        match self.operation.get() {
+            Operation::Advertising => self.enable_interrupts(),
+            _ => self.enable_connection_interrupts(),
+        }
+    }
+
+    fn advertising_end(&self, result: ReturnCode) {
+        let regs = &*self.registers;
+        match regs.state.get() {
+            nrf5x::constants::RADIO_STATE_TXRU
+            | nrf5x::constants::RADIO_STATE_TXIDLE
+            | nrf5x::constants::RADIO_STATE_TXDISABLE
+            | nrf5x::constants::RADIO_STATE_TX => {
+                self.radio_off();
+                self.tx_client.map(|client| client.transmit_event(result));
+            }
+            nrf5x::constants::RADIO_STATE_RXRU
+            | nrf5x::constants::RADIO_STATE_RXIDLE
+            | nrf5x::constants::RADIO_STATE_RXDISABLE
+            | nrf5x::constants::RADIO_STATE_RX => {
+                self.radio_off();
+                unsafe {
+                    self.rx_client.map(|client| {
+                        // Length is: S0 (1 Byte) + Length (1 Byte) + S1 (0 Bytes) + Payload
+                        // And because the length field is directly read from the packet
+                        // We need to add 2 to length to get the total length
+                        client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 2, result)
+                    });
+                }
+            }
+            // Radio state - Disabled
+            _ => (),
+        }
+    }
+
+    // Connection mode starts the radio through the READY_START shortcut, so
+    // only the end of a packet is of interest
+    fn enable_connection_interrupts(&self) {
+        let regs = &*self.registers;
+        regs.intenset.write(Interrupt::END::SET);
     }
 
     pub fn enable_interrupts(&self) {
@@ -700,28 +874,37 @@ impl Radio {
         self.set_rx_address();
 
         self.ble_set_packet_config();
-        self.ble_set_advertising_access_address();
+        self.ble_set_access_address(nrf5x::constants::RADIO_ACCESS_ADDRESS_BLE);
 
-        self.ble_set_crc_config();
+        self.ble_set_crc_config(nrf5x::constants::RADIO_CRCINIT_BLE);
 
         self.set_dma_ptr();
     }
 
+    // Same as `ble_initialize` but with the access address and CRC
+    // initialization value of the current connection
+    fn ble_initialize_connection(&self, channel: RadioChannel) {
+        self.ble_initialize(channel);
+        self.ble_set_access_address(self.access_address.get());
+        self.ble_set_crc_config(self.crc_init.get());
+    }
+
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 3.1.1 CRC Generation
-    fn ble_set_crc_config(&self) {
+    fn ble_set_crc_config(&self, crc_init: u32) {
         let regs = &*self.registers;
         regs.crccnf
             .write(CrcConfiguration::LEN::THREE + CrcConfiguration::SKIPADDR::EXCLUDE);
-        regs.crcinit.set(nrf5x::constants::RADIO_CRCINIT_BLE);
+        regs.crcinit.set(crc_init & 0xffffff);
         regs.crcpoly.set(nrf5x::constants::RADIO_CRCPOLY_BLE);
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
-    // Set access address to 0x8E89BED6
-    fn ble_set_advertising_access_address(&self) {
+    // The most significant byte goes into the prefix and the remaining three
+    // bytes into the base address, e.g. 0x8E89BED6 for advertising
+    fn ble_set_access_address(&self, access_address: u32) {
         let regs = &*self.registers;
-        regs.prefix0.set(0x0000008e);
-        regs.base0.set(0x89bed600);
+        regs.prefix0.set(access_address >> 24);
+        regs.base0.set(access_address << 8);
     }
 
     // Packet configuration
@@ -798,6 +981,7 @@ impl ble_advertising::BleAdvertisementDriver for Radio {
         channel: RadioChannel,
     ) -> &'static mut [u8] {
         let res = self.replace_radio_buffer(buf);
+        self.operation.set(Operation::Advertising);
         self.ble_initialize(channel);
         self.tx();
         self.enable_interrupts();
@@ -805,6 +989,7 @@ impl ble_advertising::BleAdvertisementDriver for Radio {
     }
 
     fn receive_advertisement(&self, channel: RadioChannel) {
+        self.operation.set(Operation::Advertising);
         self.ble_initialize(channel);
         self.rx();
         self.enable_interrupts();
@@ -819,6 +1004,102 @@ impl ble_advertising::BleAdvertisementDriver for Radio {
     }
 }
 
+impl ble_advertising::BleAdvertisementResponder for Radio {
+    fn set_response_client(&self, client: &'static ble_advertising::ResponseClient) {
+        self.response_client.set(client);
+    }
+
+    fn transmit_advertisement_and_listen(
+        &self,
+        buf: &'static mut [u8],
+        _len: usize,
+        channel: RadioChannel,
+    ) -> &'static mut [u8] {
+        let regs = &*self.registers;
+        let res = self.replace_radio_buffer(buf);
+        self.operation.set(Operation::ListenAfterTx);
+        self.ble_initialize(channel);
+        // Turn around to receive T_IFS after the advertisement has been sent
+        regs.tifs.write(InterFrameSpacing::TIFS.val(BLE_T_IFS_US));
+        regs.shorts.write(
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_RXEN::SET,
+        );
+        self.tx();
+        self.enable_connection_interrupts();
+        res
+    }
+
+    fn receive_advertisement_and_respond(&self, channel: RadioChannel) {
+        let regs = &*self.registers;
+        self.operation.set(Operation::Listening { scanning: true });
+        self.ble_initialize(channel);
+        // Turn around to transmit the response T_IFS after the received packet
+        regs.tifs.write(InterFrameSpacing::TIFS.val(BLE_T_IFS_US));
+        regs.shorts.write(
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_TXEN::SET,
+        );
+        self.rx();
+        self.enable_connection_interrupts();
+    }
+
+    fn stop_listening(&self) {
+        ble_advertising::BleConnectionDriver::stop(self);
+    }
+}
+
+impl ble_advertising::BleConnectionDriver for Radio {
+    fn set_connection_client(&self, client: &'static ble_advertising::ConnectionClient) {
+        self.connection_client.set(client);
+    }
+
+    fn transmit_connectable_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        _len: usize,
+        channel: RadioChannel,
+    ) -> &'static mut [u8] {
+        let regs = &*self.registers;
+        let res = self.replace_radio_buffer(buf);
+        self.operation.set(Operation::ConnectableAdvertisingTx);
+        self.ble_initialize(channel);
+        // Turn around to receive T_IFS after the advertisement has been sent
+        regs.tifs.write(InterFrameSpacing::TIFS.val(BLE_T_IFS_US));
+        regs.shorts.write(
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_RXEN::SET,
+        );
+        self.tx();
+        self.enable_connection_interrupts();
+        res
+    }
+
+    fn set_access_address(&self, access_address: u32, crc_init: u32) {
+        self.access_address.set(access_address);
+        self.crc_init.set(crc_init);
+    }
+
+    fn receive_data(&self, channel: RadioChannel) {
+        let regs = &*self.registers;
+        self.operation.set(Operation::ConnectionRx);
+        self.ble_initialize_connection(channel);
+        // Turn around to transmit the response T_IFS after the received packet
+        regs.tifs.write(InterFrameSpacing::TIFS.val(BLE_T_IFS_US));
+        regs.shorts.write(
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_TXEN::SET,
+        );
+        self.rx();
+        self.enable_connection_interrupts();
+    }
+
+    fn stop(&self) {
+        let regs = &*self.registers;
+        self.disable_all_interrupts();
+        regs.shorts.set(0);
+        regs.task_disable.write(Task::ENABLE::SET);
+        self.radio_off();
+        self.operation.set(Operation::Advertising);
+    }
+}
+
 impl ble_advertising::BleConfig for Radio {
     // The BLE Advertising Driver validates that the `tx_power` is between -20 to 10 dBm but then
     // underlying chip must validate if the current `tx_power` is supported as well
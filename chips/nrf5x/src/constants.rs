@@ -30,6 +30,9 @@ pub const RADIO_CRCCNF_LEN_3BYTES: u32 = 3;
 pub const RADIO_CRCINIT_BLE: u32 = 0x555555;
 pub const RADIO_CRCPOLY_BLE: u32 = 0x00065B;
 
+// ACCESS ADDRESS
+pub const RADIO_ACCESS_ADDRESS_BLE: u32 = 0x8E89BED6;
+
 // MODE
 pub const RADIO_MODE_BLE_1MBIT: u32 = 3;
 
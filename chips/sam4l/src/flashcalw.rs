@@ -15,6 +15,20 @@
 //! The driver should be `configure()`'d before use, and a Client should be set
 //! to enable a callback after a command is completed.
 //!
+//! Besides `hil::flash`, which erases each page before writing it, the flash
+//! implements `hil::block_storage`. It is then read in bytes and written and
+//! erased in pages, and writes do not erase first. Both interfaces cover the
+//! whole flash, including the kernel and apps, so boards should only give
+//! capsules a region of it.
+//!
+//! With `hil::flash::WriteProtect` the lock regions covering, for example, the
+//! kernel can be locked. Writes and erases of pages in a region locked since
+//! the last reset then fail with `EINVAL`, rather than this driver unlocking
+//! the region as it does otherwise before each write and erase. The lock bits
+//! are kept in the general purpose fuses, so a region stays locked across
+//! resets, and a bootloader cannot write it either, until it is unlocked with
+//! `unprotect` or the whole flash is erased through the debug interface.
+//!
 //! Almost all of the flash controller functionality is implemented (except for
 //! general purpose fuse bits, and more granular control of the cache).
 //!
@@ -357,6 +371,14 @@ enum FlashState {
     WriteWriting,                 // Waiting on the page to actually be written.
     EraseUnlocking { page: i32 }, // Started an erase operation.
     EraseErasing,                 // Waiting on the erase to finish.
+
+    // States of `hil::block_storage` operations, which cover the pages from
+    // `first` up to `end` one at a time.
+    BlockRead,
+    BlockWriteUnlocking { first: i32, page: i32, end: i32 },
+    BlockWriteWriting { first: i32, page: i32, end: i32 },
+    BlockEraseUnlocking { page: i32, end: i32 },
+    BlockEraseErasing { page: i32, end: i32 },
 }
 
 /// This is a wrapper around a u8 array that is sized to a single page for the
@@ -409,6 +431,10 @@ pub struct FLASHCALW {
     client: OptionalCell<&'static hil::flash::Client<FLASHCALW>>,
     current_state: Cell<FlashState>,
     buffer: TakeCell<'static, Sam4lPage>,
+    block_client: OptionalCell<&'static hil::block_storage::BlockStorageClient>,
+    block_buffer: TakeCell<'static, [u8]>,
+    /// Lock regions locked through `hil::flash::WriteProtect`, one bit each.
+    protected_regions: Cell<u16>,
 }
 
 // static instance for the board. Only one FLASHCALW on chip.
@@ -421,6 +447,7 @@ pub static mut FLASH_CONTROLLER: FLASHCALW = FLASHCALW::new(
 
 // Few constants relating to module configuration.
 const PAGE_SIZE: u32 = 512;
+const LOCK_REGIONS: usize = 16;
 
 #[cfg(CONFIG_FLASH_READ_MODE_HIGH_SPEED_DISABLE)]
 const FREQ_PS1_FWS_1_FWU_MAX_FREQ: u32 = 12000000;
@@ -449,6 +476,9 @@ impl FLASHCALW {
             client: OptionalCell::empty(),
             current_state: Cell::new(FlashState::Unconfigured),
             buffer: TakeCell::empty(),
+            block_client: OptionalCell::empty(),
+            block_buffer: TakeCell::empty(),
+            protected_regions: Cell::new(0),
         }
     }
 
@@ -501,6 +531,28 @@ impl FLASHCALW {
             // Reset state now that we are ready to do a new operation.
             self.current_state.set(FlashState::Ready);
 
+            match attempted_operation {
+                FlashState::BlockRead
+                | FlashState::BlockWriteUnlocking { .. }
+                | FlashState::BlockWriteWriting { .. } => {
+                    self.block_buffer.take().map(|buffer| {
+                        self.block_client.map(move |client| {
+                            if attempted_operation == FlashState::BlockRead {
+                                client.read_done(buffer, ReturnCode::FAIL);
+                            } else {
+                                client.write_done(buffer, ReturnCode::FAIL);
+                            }
+                        });
+                    });
+                }
+                FlashState::BlockEraseUnlocking { .. } | FlashState::BlockEraseErasing { .. } => {
+                    self.block_client.map(|client| {
+                        client.erase_done(ReturnCode::FAIL);
+                    });
+                }
+                _ => {}
+            }
+
             self.client.map(|client| match attempted_operation {
                 FlashState::Read => {
                     self.buffer.take().map(|buffer| {
@@ -543,7 +595,9 @@ impl FLASHCALW {
                 //  I'm combining these with an actual command, write_page,
                 //  which generates and interrupt and saves the page.
                 self.clear_page_buffer();
-                self.write_to_page_buffer(page as usize * PAGE_SIZE as usize);
+                self.buffer.map(|buffer| {
+                    self.write_to_page_buffer(page as usize * PAGE_SIZE as usize, &buffer.0);
+                });
 
                 self.current_state.set(FlashState::WriteWriting);
                 self.flashcalw_write_page(page);
@@ -571,6 +625,74 @@ impl FLASHCALW {
                     client.erase_complete(hil::flash::Error::CommandComplete);
                 });
             }
+            FlashState::BlockRead => {
+                self.current_state.set(FlashState::Ready);
+
+                self.block_buffer.take().map(|buffer| {
+                    self.block_client.map(move |client| {
+                        client.read_done(buffer, ReturnCode::SUCCESS);
+                    });
+                });
+            }
+            FlashState::BlockWriteUnlocking { first, page, end } => {
+                // As for `hil::flash`, fill the page buffer before the
+                // command that generates an interrupt.
+                self.clear_page_buffer();
+                self.block_buffer.map(|buffer| {
+                    let start = (page - first) as usize * PAGE_SIZE as usize;
+                    self.write_to_page_buffer(
+                        page as usize * PAGE_SIZE as usize,
+                        &buffer[start..start + PAGE_SIZE as usize],
+                    );
+                });
+
+                self.current_state
+                    .set(FlashState::BlockWriteWriting { first, page, end });
+                self.flashcalw_write_page(page);
+            }
+            FlashState::BlockWriteWriting { first, page, end } => {
+                if page + 1 < end {
+                    self.current_state.set(FlashState::BlockWriteUnlocking {
+                        first,
+                        page: page + 1,
+                        end,
+                    });
+                    self.lock_page_region(page + 1, false);
+                } else {
+                    // Flush the cache
+                    self.invalidate_cache();
+
+                    self.current_state.set(FlashState::Ready);
+
+                    self.block_buffer.take().map(|buffer| {
+                        self.block_client.map(move |client| {
+                            client.write_done(buffer, ReturnCode::SUCCESS);
+                        });
+                    });
+                }
+            }
+            FlashState::BlockEraseUnlocking { page, end } => {
+                self.current_state
+                    .set(FlashState::BlockEraseErasing { page, end });
+                self.flashcalw_erase_page(page);
+            }
+            FlashState::BlockEraseErasing { page, end } => {
+                if page + 1 < end {
+                    self.current_state.set(FlashState::BlockEraseUnlocking {
+                        page: page + 1,
+                        end,
+                    });
+                    self.lock_page_region(page + 1, false);
+                } else {
+                    self.invalidate_cache();
+
+                    self.current_state.set(FlashState::Ready);
+
+                    self.block_client.map(|client| {
+                        client.erase_done(ReturnCode::SUCCESS);
+                    });
+                }
+            }
             _ => {
                 self.current_state.set(FlashState::Ready);
             }
@@ -587,6 +709,28 @@ impl FLASHCALW {
         flash_sizes[regs.fpr.read(FlashParameter::FSZ) as usize] << 10
     }
 
+    /// Number of pages in each of the 16 lock regions.
+    fn region_pages(&self) -> usize {
+        self.get_flash_size() as usize / PAGE_SIZE as usize / LOCK_REGIONS
+    }
+
+    /// The lock regions entirely within the pages from `start_page` up to
+    /// `end_page`.
+    fn regions_within(&self, start_page: usize, end_page: usize) -> core::ops::Range<usize> {
+        let region_pages = self.region_pages();
+        let first = (start_page + region_pages - 1) / region_pages;
+        let end = core::cmp::min(end_page / region_pages, LOCK_REGIONS);
+        first..end
+    }
+
+    /// Whether any of the pages from `page` up to `end` is in a region locked
+    /// through `hil::flash::WriteProtect`.
+    fn is_protected(&self, page: i32, end: i32) -> bool {
+        let region_pages = self.region_pages() as i32;
+        (page / region_pages..(end + region_pages - 1) / region_pages)
+            .any(|region| self.protected_regions.get() & (1 << region) != 0)
+    }
+
     /// FLASHC Control
     pub fn set_wait_state(&self, wait_state: u32) {
         let regs: &FlashcalwRegisters = &*self.registers;
@@ -682,11 +826,14 @@ impl FLASHCALW {
         // fast/rarely used commands or commands that don't generate interrupts
         // it is better to wait (or at least that is how this driver was
         // originally implemented).
-        if command != FlashCMD::QPRUP
-            && command != FlashCMD::QPR
-            && command != FlashCMD::CPB
-            && command != FlashCMD::HSEN
-        {
+        // Locking, and unlocking outside of a write or erase, are only done
+        // while the flash is otherwise idle.
+        let wait = match command {
+            FlashCMD::QPRUP | FlashCMD::QPR | FlashCMD::CPB | FlashCMD::HSEN | FlashCMD::LP => true,
+            FlashCMD::UP => self.current_state.get() == FlashState::Ready,
+            _ => false,
+        };
+        if !wait {
             // Enable ready interrupt.
             regs.fcr.modify(FlashControl::FRDY::SET);
         }
@@ -705,11 +852,7 @@ impl FLASHCALW {
         // Since we don't enable interrupts for these commands, spin wait
         // until they are finished. In particular, QPR and QPRUP will not issue
         // interrupts (see datasheet 14.6 paragraph 2).
-        if command == FlashCMD::QPRUP
-            || command == FlashCMD::QPR
-            || command == FlashCMD::CPB
-            || command == FlashCMD::HSEN
-        {
+        if wait {
             while !regs.fsr.is_set(FlashStatus::FRDY) {}
         }
     }
@@ -766,7 +909,7 @@ impl FLASHCALW {
 
     // Instead of having several memset/memcpy functions as Atmel's ASF
     // implementation will only have one to write to the page buffer.
-    fn write_to_page_buffer(&self, pg_buff_addr: usize) {
+    fn write_to_page_buffer(&self, pg_buff_addr: usize, data: &[u8]) {
         let mut page_buffer: *mut u8 = pg_buff_addr as *mut u8;
 
         // Errata 45.1.7 - Need to write a 64-bit all one word for every write
@@ -774,24 +917,22 @@ impl FLASHCALW {
         let cleared_double_word: [u8; 8] = [255; 8];
         let clr_ptr: *const u8 = &cleared_double_word[0] as *const u8;
 
-        self.buffer.map(|buffer| {
-            unsafe {
-                use core::ptr;
-
-                let mut start_buffer: *const u8 = &buffer[0] as *const u8;
-                let mut data_transfered: u32 = 0;
-                while data_transfered < PAGE_SIZE {
-                    // errata copy..
-                    ptr::copy(clr_ptr, page_buffer, 8);
-
-                    // real copy
-                    ptr::copy(start_buffer, page_buffer, 8);
-                    page_buffer = page_buffer.offset(8);
-                    start_buffer = start_buffer.offset(8);
-                    data_transfered += 8;
-                }
+        unsafe {
+            use core::ptr;
+
+            let mut start_buffer: *const u8 = &data[0] as *const u8;
+            let mut data_transfered: u32 = 0;
+            while data_transfered < PAGE_SIZE {
+                // errata copy..
+                ptr::copy(clr_ptr, page_buffer, 8);
+
+                // real copy
+                ptr::copy(start_buffer, page_buffer, 8);
+                page_buffer = page_buffer.offset(8);
+                start_buffer = start_buffer.offset(8);
+                data_transfered += 8;
             }
-        });
+        }
     }
 }
 
@@ -882,6 +1023,9 @@ impl FLASHCALW {
             // If we're not ready don't take the command
             _ => return ReturnCode::EBUSY,
         }
+        if self.is_protected(page_num, page_num + 1) {
+            return ReturnCode::EINVAL;
+        }
 
         // Save the buffer for the future write.
         self.buffer.replace(data);
@@ -898,12 +1042,33 @@ impl FLASHCALW {
         if self.current_state.get() != FlashState::Ready {
             return ReturnCode::EBUSY;
         }
+        if self.is_protected(page_num, page_num + 1) {
+            return ReturnCode::EINVAL;
+        }
 
         self.current_state
             .set(FlashState::EraseUnlocking { page: page_num });
         self.lock_page_region(page_num, false);
         ReturnCode::SUCCESS
     }
+
+    /// Check a `hil::block_storage` operation, which must be non-empty,
+    /// aligned to `block_size` and within the flash.
+    fn check_block_operation(&self, address: u64, length: usize, block_size: usize) -> ReturnCode {
+        match self.current_state.get() {
+            FlashState::Unconfigured => return ReturnCode::FAIL,
+            FlashState::Ready => {}
+            _ => return ReturnCode::EBUSY,
+        }
+        if length == 0
+            || address % block_size as u64 != 0
+            || length % block_size != 0
+            || address + length as u64 > self.get_flash_size() as u64
+        {
+            return ReturnCode::EINVAL;
+        }
+        ReturnCode::SUCCESS
+    }
 }
 
 impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for FLASHCALW {
@@ -927,3 +1092,142 @@ impl hil::flash::Flash for FLASHCALW {
         self.erase_page(page_number as i32)
     }
 }
+
+impl hil::block_storage::BlockStorage for FLASHCALW {
+    fn set_client(&self, client: &'static hil::block_storage::BlockStorageClient) {
+        self.block_client.set(client);
+    }
+
+    fn geometry(&self) -> hil::block_storage::Geometry {
+        hil::block_storage::Geometry {
+            read_size: 1,
+            write_size: PAGE_SIZE as usize,
+            erase_size: PAGE_SIZE as usize,
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.get_flash_size() as u64
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        // Enable clock in case it's off.
+        pm::enable_clock(self.ahb_clock);
+
+        let mut result = self.check_block_operation(address, length, 1);
+        if result == ReturnCode::SUCCESS && length > buffer.len() {
+            result = ReturnCode::EINVAL;
+        }
+        if result != ReturnCode::SUCCESS {
+            return (result, Some(buffer));
+        }
+
+        // The flash is memory mapped, so copy it now and report the read in
+        // a deferred call, like `hil::flash` reads.
+        let mut byte: *const u8 = address as usize as *const u8;
+        unsafe {
+            for i in 0..length {
+                buffer[i] = *byte;
+                byte = byte.offset(1);
+            }
+        }
+
+        self.current_state.set(FlashState::BlockRead);
+        self.block_buffer.replace(buffer);
+        DEFERRED_CALL.set();
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        // Enable clock in case it's off.
+        pm::enable_clock(self.ahb_clock);
+
+        let mut result = self.check_block_operation(address, length, PAGE_SIZE as usize);
+        if result == ReturnCode::SUCCESS && length > buffer.len() {
+            result = ReturnCode::EINVAL;
+        }
+        if result != ReturnCode::SUCCESS {
+            return (result, Some(buffer));
+        }
+
+        let first = (address / PAGE_SIZE as u64) as i32;
+        let end = first + (length / PAGE_SIZE as usize) as i32;
+        if self.is_protected(first, end) {
+            return (ReturnCode::EINVAL, Some(buffer));
+        }
+        self.block_buffer.replace(buffer);
+        self.current_state.set(FlashState::BlockWriteUnlocking {
+            first,
+            page: first,
+            end: end,
+        });
+        self.lock_page_region(first, false);
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn erase(&self, address: u64, length: usize) -> ReturnCode {
+        // Enable AHB clock (in case it was off).
+        pm::enable_clock(self.ahb_clock);
+
+        let result = self.check_block_operation(address, length, PAGE_SIZE as usize);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+
+        let page = (address / PAGE_SIZE as u64) as i32;
+        let end = page + (length / PAGE_SIZE as usize) as i32;
+        if self.is_protected(page, end) {
+            return ReturnCode::EINVAL;
+        }
+        self.current_state
+            .set(FlashState::BlockEraseUnlocking { page, end });
+        self.lock_page_region(page, false);
+        ReturnCode::SUCCESS
+    }
+}
+
+impl hil::flash::WriteProtect for FLASHCALW {
+    fn protect(&self, start_page: usize, end_page: usize) -> ReturnCode {
+        // Enable AHB clock (in case it was off).
+        pm::enable_clock(self.ahb_clock);
+        match self.current_state.get() {
+            FlashState::Unconfigured => return ReturnCode::FAIL,
+            FlashState::Ready => {}
+            _ => return ReturnCode::EBUSY,
+        }
+
+        for region in self.regions_within(start_page, end_page) {
+            self.lock_page_region((region * self.region_pages()) as i32, true);
+            self.protected_regions
+                .set(self.protected_regions.get() | 1 << region);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn unprotect(&self, start_page: usize, end_page: usize) -> ReturnCode {
+        // Enable AHB clock (in case it was off).
+        pm::enable_clock(self.ahb_clock);
+        match self.current_state.get() {
+            FlashState::Unconfigured => return ReturnCode::FAIL,
+            FlashState::Ready => {}
+            _ => return ReturnCode::EBUSY,
+        }
+
+        for region in self.regions_within(start_page, end_page) {
+            self.lock_page_region((region * self.region_pages()) as i32, false);
+            self.protected_regions
+                .set(self.protected_regions.get() & !(1 << region));
+        }
+        ReturnCode::SUCCESS
+    }
+}
@@ -50,6 +50,15 @@ impl AppId {
             (start, end)
         })
     }
+
+    /// Returns the package name from the app's TBF header, or an empty string
+    /// if the app does not have one. The name stays the same when the app is
+    /// restarted or reflashed, so capsules can use it to find state they
+    /// stored for the app.
+    pub fn get_package_name(&self) -> &'static str {
+        self.kernel
+            .process_map_or("", self.idx, |process| process.get_process_name())
+    }
 }
 
 /// Type for calling a callback in a process.
@@ -219,6 +219,16 @@ impl DynamicDeferredCall {
         self.call_while(|| true)
     }
 
+    /// Call all scheduled deferred calls of this instance, in place of the
+    /// kernel scheduler
+    ///
+    /// Only available with the `test_helpers` feature, for host tests that
+    /// cannot share the global instance and run their own.
+    #[cfg(feature = "test_helpers")]
+    pub fn call_pending_for_test(&self) {
+        self.call()
+    }
+
     /// Call all registered and to-be-scheduled deferred calls while the supplied
     /// predicate returns `true`.
     ///
@@ -73,6 +73,94 @@ pub trait TxClient {
     fn transmit_event(&self, result: ReturnCode);
 }
 
+/// Advertising channel operations that answer a received packet within the
+/// inter frame space T_IFS (150 us), needed for scan responses and active
+/// scanning.
+pub trait BleAdvertisementResponder {
+    fn set_response_client(&self, client: &'static ResponseClient);
+
+    /// Like `BleAdvertisementDriver::transmit_advertisement`, but keep
+    /// listening on the channel afterwards and offer a received packet to
+    /// `ResponseClient::respond`. `TxClient::transmit_event` is still called
+    /// once the advertisement has been sent. Listening ends when a packet has
+    /// been handled, another operation is started or `stop_listening` is
+    /// called.
+    fn transmit_advertisement_and_listen(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        channel: RadioChannel,
+    ) -> &'static mut [u8];
+
+    /// Like `BleAdvertisementDriver::receive_advertisement`, but first offer
+    /// the received packet to `ResponseClient::respond`. If a response is
+    /// sent, the radio keeps listening and reports the next received packet
+    /// (e.g. a `SCAN_RSP`) through `RxClient::receive_event`. Otherwise the
+    /// received packet itself is reported there.
+    fn receive_advertisement_and_respond(&self, channel: RadioChannel);
+
+    /// Stop listening and turn the radio off.
+    fn stop_listening(&self);
+}
+
+pub trait ResponseClient {
+    /// A packet with a valid CRC was received. The client writes the response
+    /// PDU, including its 2-byte header, to `response` and returns its
+    /// length, or returns 0 to not respond.
+    fn respond(&self, pdu: &[u8], response: &mut [u8]) -> usize;
+}
+
+/// Radio operations needed by a link layer to accept and maintain connections
+/// in the peripheral (slave) role.
+///
+/// The inter frame space T_IFS (150 us) between a received packet and its
+/// response is too short to be handled by a capsule, so the radio turns around
+/// on its own and asks its `ConnectionClient` for the response while it is
+/// ramping up the transmitter.
+pub trait BleConnectionDriver {
+    fn set_connection_client(&self, client: &'static ConnectionClient);
+
+    /// Transmit a connectable advertisement on `channel` and afterwards keep
+    /// listening on the same channel for a request (e.g. a `CONNECT_IND`).
+    /// A received request is reported through
+    /// `ConnectionClient::advertising_request`. Listening continues until a
+    /// packet is received or `stop` is called.
+    fn transmit_connectable_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        channel: RadioChannel,
+    ) -> &'static mut [u8];
+
+    /// Set the access address and CRC initialization value used for data
+    /// channel PDUs, as assigned by the master in its `CONNECT_IND`.
+    fn set_access_address(&self, access_address: u32, crc_init: u32);
+
+    /// Listen for a data channel PDU from the master on `channel`. Once one is
+    /// received, the radio calls `ConnectionClient::data_received` to fill in
+    /// the response and transmits it T_IFS after the received packet.
+    fn receive_data(&self, channel: RadioChannel);
+
+    /// Abort any ongoing reception or transmission and turn the radio off.
+    fn stop(&self);
+}
+
+pub trait ConnectionClient {
+    /// A packet was received after a connectable advertisement. `pdu` starts
+    /// with the 2-byte advertising channel PDU header.
+    fn advertising_request(&self, pdu: &[u8], result: ReturnCode);
+
+    /// A data channel PDU was received, or its CRC check failed if `result`
+    /// is `FAIL`. The client writes the response PDU, including its 2-byte
+    /// header, to `response` and returns its length, which must be at least
+    /// 2.
+    fn data_received(&self, pdu: &[u8], result: ReturnCode, response: &mut [u8]) -> usize;
+
+    /// The response to the last data channel PDU has been transmitted, ending
+    /// the exchange.
+    fn response_sent(&self, result: ReturnCode);
+}
+
 // Bluetooth Core Specification:Vol. 6. Part B, section 1.4.1 Advertising and Data Channel Indices
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub enum RadioChannel {
@@ -119,6 +207,53 @@ pub enum RadioChannel {
 }
 
 impl RadioChannel {
+    /// Returns the channel with the given channel index (0-39).
+    pub fn from_channel_index(index: u8) -> Option<RadioChannel> {
+        match index {
+            0 => Some(RadioChannel::DataChannel0),
+            1 => Some(RadioChannel::DataChannel1),
+            2 => Some(RadioChannel::DataChannel2),
+            3 => Some(RadioChannel::DataChannel3),
+            4 => Some(RadioChannel::DataChannel4),
+            5 => Some(RadioChannel::DataChannel5),
+            6 => Some(RadioChannel::DataChannel6),
+            7 => Some(RadioChannel::DataChannel7),
+            8 => Some(RadioChannel::DataChannel8),
+            9 => Some(RadioChannel::DataChannel9),
+            10 => Some(RadioChannel::DataChannel10),
+            11 => Some(RadioChannel::DataChannel11),
+            12 => Some(RadioChannel::DataChannel12),
+            13 => Some(RadioChannel::DataChannel13),
+            14 => Some(RadioChannel::DataChannel14),
+            15 => Some(RadioChannel::DataChannel15),
+            16 => Some(RadioChannel::DataChannel16),
+            17 => Some(RadioChannel::DataChannel17),
+            18 => Some(RadioChannel::DataChannel18),
+            19 => Some(RadioChannel::DataChannel19),
+            20 => Some(RadioChannel::DataChannel20),
+            21 => Some(RadioChannel::DataChannel21),
+            22 => Some(RadioChannel::DataChannel22),
+            23 => Some(RadioChannel::DataChannel23),
+            24 => Some(RadioChannel::DataChannel24),
+            25 => Some(RadioChannel::DataChannel25),
+            26 => Some(RadioChannel::DataChannel26),
+            27 => Some(RadioChannel::DataChannel27),
+            28 => Some(RadioChannel::DataChannel28),
+            29 => Some(RadioChannel::DataChannel29),
+            30 => Some(RadioChannel::DataChannel30),
+            31 => Some(RadioChannel::DataChannel31),
+            32 => Some(RadioChannel::DataChannel32),
+            33 => Some(RadioChannel::DataChannel33),
+            34 => Some(RadioChannel::DataChannel34),
+            35 => Some(RadioChannel::DataChannel35),
+            36 => Some(RadioChannel::DataChannel36),
+            37 => Some(RadioChannel::AdvertisingChannel37),
+            38 => Some(RadioChannel::AdvertisingChannel38),
+            39 => Some(RadioChannel::AdvertisingChannel39),
+            _ => None,
+        }
+    }
+
     pub fn get_channel_index(&self) -> u32 {
         match *self {
             RadioChannel::DataChannel0 => 0,
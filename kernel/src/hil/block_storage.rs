@@ -0,0 +1,74 @@
+//! Interface for storage that is read, written and erased in blocks.
+//!
+//! Storage such as SD cards and flash memory is accessed in blocks, whose
+//! size can be different for each operation. A NOR flash chip, for example,
+//! may be read in pages of 256 bytes, written in pages of 256 bytes and
+//! erased in sectors of 4 kB. Addresses and lengths are in bytes, and must be
+//! multiples of the block size of the operation.
+//!
+//! Storage with an erase size must be erased before it is written: erasing
+//! sets every bit of a block, and writing can only clear bits. Storage
+//! without one, such as an SD card, can be written at any time and does not
+//! support `erase`.
+//!
+//! Layers that need byte addressing or do not want to deal with erasing can
+//! use this storage through `capsules::nonvolatile_to_blocks`.
+
+use crate::returncode::ReturnCode;
+
+/// Block sizes of a storage device, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Geometry {
+    /// Reads start at and are a multiple of this size.
+    pub read_size: usize,
+    /// Writes start at and are a multiple of this size.
+    pub write_size: usize,
+    /// Erases start at and are a multiple of this size, or 0 if the storage
+    /// does not need to be erased.
+    pub erase_size: usize,
+}
+
+/// Read, write and erase blocks of storage.
+pub trait BlockStorage {
+    fn set_client(&self, client: &'static BlockStorageClient);
+
+    /// The block sizes of the storage.
+    fn geometry(&self) -> Geometry;
+
+    /// Size of the storage in bytes. Storage that is detected when it is
+    /// first used, such as an SD card, has a capacity of 0 until then.
+    fn capacity(&self) -> u64;
+
+    /// Read `length` bytes from `address` into `buffer`. Returns EINVAL if
+    /// the range is not aligned to the read size or not in the storage. If
+    /// the read cannot start, the error is returned with the buffer.
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Write the first `length` bytes of `buffer` to `address`. Returns
+    /// EINVAL if the range is not aligned to the write size or not in the
+    /// storage. If the write cannot start, the error is returned with the
+    /// buffer.
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: u64,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Erase `length` bytes from `address`. Returns EINVAL if the range is
+    /// not aligned to the erase size or not in the storage, and ENOSUPPORT if
+    /// the storage does not need to be erased.
+    fn erase(&self, address: u64, length: usize) -> ReturnCode;
+}
+
+/// Receive the results of `BlockStorage` operations.
+pub trait BlockStorageClient {
+    fn read_done(&self, buffer: &'static mut [u8], result: ReturnCode);
+    fn write_done(&self, buffer: &'static mut [u8], result: ReturnCode);
+    fn erase_done(&self, result: ReturnCode);
+}
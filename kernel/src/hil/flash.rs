@@ -120,6 +120,28 @@ pub trait Flash {
     fn erase_page(&self, page_number: usize) -> ReturnCode;
 }
 
+/// Flash that can protect pages against writes and erases in hardware.
+///
+/// Hardware usually protects regions larger than a page. Only regions that are
+/// entirely within the requested pages are protected, so pages at either end
+/// that share a region with other pages stay writable. Protected pages cannot
+/// be written or erased through this interface.
+///
+/// How long the protection lasts depends on the chip. On some, such as the
+/// nRF52832, it lasts until the next reset. Others, such as the SAM4L, keep
+/// it in flash, so it lasts across resets, and also applies to a bootloader,
+/// until it is removed with `unprotect`.
+pub trait WriteProtect {
+    /// Protect the regions within pages `start_page` up to, but not including,
+    /// `end_page`. Returns `ENOSUPPORT` if the chip cannot protect flash.
+    fn protect(&self, start_page: usize, end_page: usize) -> ReturnCode;
+
+    /// Remove the protection of the regions within pages `start_page` up to,
+    /// but not including, `end_page`. Returns `ENOSUPPORT` if only a reset
+    /// removes the protection.
+    fn unprotect(&self, start_page: usize, end_page: usize) -> ReturnCode;
+}
+
 /// Implement `Client` to receive callbacks from `Flash`.
 pub trait Client<F: Flash> {
     /// Flash read complete.
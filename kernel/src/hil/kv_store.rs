@@ -0,0 +1,46 @@
+//! Interface for persistent key-value stores.
+//!
+//! Keys are short byte strings and values are byte arrays. Every update is
+//! atomic: after a loss of power, a key holds either its old or its new
+//! value. Kernel users share one namespace, separate from the keys of
+//! processes.
+
+use crate::returncode::ReturnCode;
+
+/// Store and retrieve values by key.
+pub trait KVStore {
+    fn set_client(&self, client: &'static KVStoreClient);
+
+    /// Read the value of `key` into `value`. Returns ESIZE if the key is too
+    /// long. If the operation cannot start, the error is returned with the
+    /// buffer.
+    fn get(&self, key: &[u8], value: &'static mut [u8]) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Set `key` to the first `length` bytes of `value`. Returns ESIZE if the
+    /// key or value is too long. If the operation cannot start, the error is
+    /// returned with the buffer.
+    fn set(
+        &self,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Remove `key` from the store.
+    fn delete(&self, key: &[u8]) -> ReturnCode;
+}
+
+/// Receive the results of `KVStore` operations.
+pub trait KVStoreClient {
+    /// The value has been read. `length` is the length of the value, which is
+    /// also given with ESIZE if it did not fit in the buffer. ENOSUPPORT
+    /// means that the key is not in the store.
+    fn get_complete(&self, value: &'static mut [u8], length: usize, result: ReturnCode);
+
+    /// The value has been stored. ENOMEM means the store is full.
+    fn set_complete(&self, value: &'static mut [u8], result: ReturnCode);
+
+    /// The key has been removed. ENOSUPPORT means that the key was not in
+    /// the store.
+    fn delete_complete(&self, result: ReturnCode);
+}
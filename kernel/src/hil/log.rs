@@ -0,0 +1,92 @@
+//! Interface for append-only logs in nonvolatile storage.
+//!
+//! A log is a sequence of entries. Entries are appended at the end of the log
+//! and read back in order from a read cursor. Each entry is identified by an
+//! `EntryId`, which increases along the log; entry IDs are only meaningful
+//! for the log that returned them.
+//!
+//! Appended entries may be buffered before they reach the storage, and are
+//! only guaranteed to survive a loss of power once `sync` completes. Logs
+//! have a fixed capacity: when they are full, appending either fails or
+//! discards the oldest entries, depending on the implementation.
+
+use crate::returncode::ReturnCode;
+
+/// Position of an entry in a log.
+pub type EntryId = usize;
+
+/// Read entries from a log.
+pub trait LogRead {
+    fn set_read_client(&self, client: &'static LogReadClient);
+
+    /// Read the entry at the read cursor into `buffer` and advance the
+    /// cursor to the next entry. At most `length` bytes are read. Returns
+    /// FAIL if there are no more entries to read and ESIZE if the entry does
+    /// not fit in `length` bytes, in which case the cursor does not move.
+    /// If the read cannot start, the error is returned with the buffer.
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Move the read cursor to `entry`, which must be the ID of an entry
+    /// still in the log or the end of the log. Returns EINVAL otherwise.
+    fn seek(&self, entry: EntryId) -> ReturnCode;
+
+    /// The entry the next read returns.
+    fn next_read_entry_id(&self) -> EntryId;
+
+    /// The oldest entry in the log.
+    fn log_start(&self) -> EntryId;
+
+    /// The end of the log. Reading from here returns FAIL until more entries
+    /// are appended.
+    fn log_end(&self) -> EntryId;
+
+    /// Capacity of the log, in bytes of storage.
+    fn get_size(&self) -> usize;
+}
+
+/// Receive the results of `LogRead` operations.
+pub trait LogReadClient {
+    /// The read started with `LogRead::read` has completed. `length` is the
+    /// length of the entry that was read.
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, error: ReturnCode);
+}
+
+/// Append entries to a log.
+pub trait LogWrite {
+    fn set_append_client(&self, client: &'static LogWriteClient);
+
+    /// Append the first `length` bytes of `buffer` to the log as one entry.
+    /// Returns ESIZE with the buffer if the entry is too large for the log.
+    fn append(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Write all appended entries to the storage.
+    fn sync(&self) -> ReturnCode;
+
+    /// Remove all entries from the log.
+    fn erase(&self) -> ReturnCode;
+}
+
+/// Receive the results of `LogWrite` operations.
+pub trait LogWriteClient {
+    /// The entry has been appended. `records_lost` is true if the oldest
+    /// entries were discarded to make room for it.
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        records_lost: bool,
+        error: ReturnCode,
+    );
+
+    fn sync_done(&self, error: ReturnCode);
+
+    fn erase_done(&self, error: ReturnCode);
+}
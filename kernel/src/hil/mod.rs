@@ -3,6 +3,7 @@
 pub mod adc;
 pub mod analog_comparator;
 pub mod ble_advertising;
+pub mod block_storage;
 pub mod crc;
 pub mod dac;
 pub mod entropy;
@@ -10,7 +11,9 @@ pub mod flash;
 pub mod gpio;
 pub mod gpio_async;
 pub mod i2c;
+pub mod kv_store;
 pub mod led;
+pub mod log;
 pub mod nonvolatile_storage;
 pub mod pwm;
 pub mod radio;
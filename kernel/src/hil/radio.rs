@@ -17,11 +17,27 @@ pub trait RxClient {
         &self,
         buf: &'static mut [u8],
         frame_len: usize,
+        quality: LinkQuality,
         crc_valid: bool,
         result: ReturnCode,
     );
 }
 
+/// Link quality of a received frame, as measured by the radio.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LinkQuality {
+    /// Received signal strength, in dBm
+    pub rssi: i8,
+    /// Link quality indicator, from 0 (lowest quality) to 255 (highest
+    /// quality). How it is computed is radio specific.
+    pub lqi: u8,
+}
+
+impl LinkQuality {
+    /// The link quality reported by radios that cannot measure it.
+    pub const UNKNOWN: LinkQuality = LinkQuality { rssi: -128, lqi: 0 };
+}
+
 pub trait ConfigClient {
     fn config_done(&self, result: ReturnCode);
 }
@@ -30,6 +46,14 @@ pub trait PowerClient {
     fn changed(&self, on: bool);
 }
 
+pub trait EnergyClient {
+    /// Called when an energy detection measurement started with
+    /// `RadioEnergyDetect::energy_detect` completes. `level` is the peak
+    /// received signal energy on the channel, scaled from 0 (no measurable
+    /// energy) to 255.
+    fn energy_detect_done(&self, level: u8, result: ReturnCode);
+}
+
 /// These constants are used for interacting with the SPI buffer, which contains
 /// a 1-byte SPI command, a 1-byte PHY header, and then the 802.15.4 frame. In
 /// theory, the number of extra bytes in front of the frame can depend on the
@@ -82,8 +106,9 @@ pub trait RadioConfig {
     fn set_power_client(&self, client: &'static PowerClient);
 
     /// Commit the config calls to hardware, changing the address,
-    /// PAN ID, TX power, and channel to the specified values, issues
-    /// a callback to the config client when done.
+    /// PAN ID, TX power, channel, promiscuous mode and automatic
+    /// acknowledgements to the specified values, issues a callback to the
+    /// config client when done.
     fn config_commit(&self);
     fn set_config_client(&self, client: &'static ConfigClient);
 
@@ -98,6 +123,21 @@ pub trait RadioConfig {
     fn set_pan(&self, id: u16);
     fn set_tx_power(&self, power: i8) -> ReturnCode;
     fn set_channel(&self, chan: u8) -> ReturnCode;
+
+    /// Whether frames are received regardless of their destination.
+    fn get_promiscuous(&self) -> bool;
+    /// Whether frames requesting an acknowledgement are acknowledged by the
+    /// radio.
+    fn get_auto_ack(&self) -> bool;
+
+    /// Receive all frames with a valid PHY header, rather than only frames
+    /// addressed to this radio. Returns ENOSUPPORT if the radio cannot change
+    /// its address filtering.
+    fn set_promiscuous(&self, enable: bool) -> ReturnCode;
+    /// Acknowledge received frames that request it in hardware. Returns
+    /// ENOSUPPORT if the radio cannot change whether it sends
+    /// acknowledgements.
+    fn set_auto_ack(&self, enable: bool) -> ReturnCode;
 }
 
 pub trait RadioData {
@@ -111,3 +151,15 @@ pub trait RadioData {
         frame_len: usize,
     ) -> (ReturnCode, Option<&'static mut [u8]>);
 }
+
+/// Measure the received signal energy on the current channel, as used by
+/// 802.15.4 energy detection scans. This is not part of `Radio` because not
+/// every radio supports it.
+pub trait RadioEnergyDetect {
+    fn set_energy_client(&self, client: &'static EnergyClient);
+
+    /// Start an energy detection measurement on the current channel. Returns
+    /// EOFF if the radio is off and EBUSY if a measurement is already
+    /// running.
+    fn energy_detect(&self) -> ReturnCode;
+}
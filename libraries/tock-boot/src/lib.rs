@@ -0,0 +1,431 @@
+//! Kernel images in two flash slots, and the choice of the one to boot.
+//!
+//! Each slot starts with a header page, followed by a kernel image linked to
+//! run right after that page. The header holds the version, length, CRC-32
+//! and, optionally, the signature of the image, followed by its state:
+//!
+//! ```text
+//!  0 magic "TKRN"
+//!  4 version, higher for newer images
+//!  8 length of the image
+//! 12 CRC-32 of the image
+//! 16 length of the signature
+//! 20 CRC-32 of the header and the signature, without the state
+//! 24 state
+//! 28 signature
+//! ```
+//!
+//! A new image is `Pending`. The bootloader boots the newest image that is
+//! not `Rejected`, but gives up on a `Pending` one after it has been booted
+//! `MAX_ATTEMPTS` times without the kernel marking it `Confirmed`. It then
+//! boots the other image and tells the kernel, which marks the failed image
+//! `Rejected`. Each state can be written over the previous one by clearing
+//! bits.
+//!
+//! The bootloader and the kernel share a `Handoff` in RAM that is kept
+//! across resets. It is lost when power is lost, so a `Pending` image is
+//! tried again up to `MAX_ATTEMPTS` times after power returns.
+
+#![no_std]
+
+const MAGIC: [u8; 4] = *b"TKRN";
+const HANDOFF_MAGIC: u32 = 0x544b_4844;
+const STATE_OFFSET: usize = 24;
+const SIGNATURE_OFFSET: usize = 28;
+
+/// Longest signature of an image, in bytes.
+pub const MAX_SIGNATURE_LENGTH: usize = 64;
+
+/// Bytes of the header at the start of a slot, with the longest signature.
+pub const HEADER_LENGTH: usize = SIGNATURE_OFFSET + MAX_SIGNATURE_LENGTH;
+
+/// Times a `Pending` image is booted before the bootloader gives up on it.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/// CRC-32 (IEEE 802.3) of `data`, continuing from `crc`. Start with 0.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    buf[0] as u32 | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24
+}
+
+fn write_u32(buf: &mut [u8], value: u32) {
+    for i in 0..4 {
+        buf[i] = (value >> (8 * i)) as u8;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    /// Installed, but not yet confirmed by a kernel booted from it.
+    Pending,
+    /// Booted and confirmed.
+    Confirmed,
+    /// Failed to boot, or its state was not written completely.
+    Rejected,
+}
+
+impl State {
+    fn to_u32(self) -> u32 {
+        match self {
+            State::Pending => 0xffff_ffff,
+            State::Confirmed => 0x0000_ffff,
+            State::Rejected => 0,
+        }
+    }
+
+    fn from_u32(value: u32) -> State {
+        match value {
+            0xffff_ffff => State::Pending,
+            0x0000_ffff => State::Confirmed,
+            _ => State::Rejected,
+        }
+    }
+}
+
+/// The header of a slot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub version: u32,
+    pub length: u32,
+    pub crc: u32,
+    pub state: State,
+    pub signature_length: usize,
+}
+
+impl Header {
+    /// Parses the header at the start of `page`, the first page of a slot.
+    /// Returns `None` if there is no valid header.
+    pub fn parse(page: &[u8]) -> Option<Header> {
+        if page.len() < HEADER_LENGTH || page[0..4] != MAGIC {
+            return None;
+        }
+        let signature_length = read_u32(&page[16..20]) as usize;
+        if signature_length > MAX_SIGNATURE_LENGTH {
+            return None;
+        }
+        let signature = &page[SIGNATURE_OFFSET..SIGNATURE_OFFSET + signature_length];
+        if read_u32(&page[20..24]) != crc32(crc32(0, &page[0..20]), signature) {
+            return None;
+        }
+        Some(Header {
+            version: read_u32(&page[4..8]),
+            length: read_u32(&page[8..12]),
+            crc: read_u32(&page[12..16]),
+            state: State::from_u32(read_u32(&page[STATE_OFFSET..STATE_OFFSET + 4])),
+            signature_length: signature_length,
+        })
+    }
+
+    /// The signature in `page`, the page this header was parsed from.
+    pub fn signature<'a>(&self, page: &'a [u8]) -> &'a [u8] {
+        &page[SIGNATURE_OFFSET..SIGNATURE_OFFSET + self.signature_length]
+    }
+
+    /// Writes the header with `signature` to the start of `page`. The
+    /// signature must not be longer than `MAX_SIGNATURE_LENGTH`.
+    pub fn write(&self, page: &mut [u8], signature: &[u8]) {
+        page[0..4].copy_from_slice(&MAGIC);
+        write_u32(&mut page[4..8], self.version);
+        write_u32(&mut page[8..12], self.length);
+        write_u32(&mut page[12..16], self.crc);
+        write_u32(&mut page[16..20], signature.len() as u32);
+        page[SIGNATURE_OFFSET..SIGNATURE_OFFSET + signature.len()].copy_from_slice(signature);
+        let crc = crc32(crc32(0, &page[0..20]), signature);
+        write_u32(&mut page[20..24], crc);
+        set_state(page, self.state);
+    }
+
+    /// Whether this header is of a newer image than `other`.
+    pub fn is_newer_than(&self, other: &Header) -> bool {
+        (self.version.wrapping_sub(other.version) as i32) > 0
+    }
+}
+
+/// Changes the state in `page`, which holds a header.
+pub fn set_state(page: &mut [u8], state: State) {
+    write_u32(&mut page[STATE_OFFSET..STATE_OFFSET + 4], state.to_u32());
+}
+
+/// Checks the signatures of images, for example with a public key built into
+/// the bootloader or the kernel. The image is fed in pieces, so the kernel can
+/// check it while it is written.
+pub trait Verifier {
+    /// Start checking a new image.
+    fn reset(&self);
+
+    /// Add the next bytes of the image.
+    fn update(&self, data: &[u8]);
+
+    /// Whether `signature` is a valid signature of the image.
+    fn verify(&self, signature: &[u8]) -> bool;
+}
+
+/// Whether `image`, linked to run from `address`, starts with a vector table
+/// whose reset handler is in the image.
+pub fn valid_vectors(image: &[u8], address: usize) -> bool {
+    if image.len() < 8 {
+        return false;
+    }
+    let reset_handler = read_u32(&image[4..8]) as usize & !1;
+    reset_handler >= address && reset_handler < address + image.len()
+}
+
+/// Checks the slot that starts at `address` and holds `slot`: its header,
+/// the CRC of its image, the vector table of the image and, if there is a
+/// verifier, the signature of the image. Returns the header if they are
+/// valid.
+pub fn check_slot(
+    slot: &[u8],
+    address: usize,
+    page_size: usize,
+    verifier: Option<&Verifier>,
+) -> Option<Header> {
+    let header = Header::parse(&slot[..page_size])?;
+    let length = header.length as usize;
+    if length > slot.len() - page_size {
+        return None;
+    }
+    let image = &slot[page_size..page_size + length];
+    if crc32(0, image) != header.crc || !valid_vectors(image, address + page_size) {
+        return None;
+    }
+    let signature = header.signature(slot);
+    if let Some(verifier) = verifier {
+        verifier.reset();
+        verifier.update(image);
+        if !verifier.verify(signature) {
+            return None;
+        }
+    }
+    Some(header)
+}
+
+/// What the bootloader tells the kernel, kept in RAM across resets.
+#[repr(C)]
+pub struct Handoff {
+    magic: u32,
+    /// The slot that was booted.
+    pub slot: u32,
+    /// Bit `n` is set if the `Pending` image in slot `n` failed to boot.
+    pub failed: u32,
+    // The version of the `Pending` image in each slot, and the times it was
+    // booted.
+    versions: [u32; 2],
+    attempts: [u32; 2],
+}
+
+impl Handoff {
+    pub const fn new() -> Handoff {
+        Handoff {
+            magic: 0,
+            slot: 0,
+            failed: 0,
+            versions: [0; 2],
+            attempts: [0; 2],
+        }
+    }
+
+    /// Whether the bootloader filled in this handoff. It did not if the
+    /// kernel was not booted by it.
+    pub fn is_valid(&self) -> bool {
+        self.magic == HANDOFF_MAGIC
+    }
+}
+
+/// Chooses the slot to boot given the headers of the slots that `check_slot`
+/// accepted, and records the choice in `handoff`. Returns `None` if there is
+/// none to boot, in which case the bootloader must not boot either slot.
+pub fn select(headers: &[Option<Header>; 2], handoff: &mut Handoff) -> Option<usize> {
+    if !handoff.is_valid() {
+        *handoff = Handoff::new();
+        handoff.magic = HANDOFF_MAGIC;
+    }
+    handoff.failed = 0;
+    let mut selected: Option<usize> = None;
+    for (slot, header) in headers.iter().enumerate() {
+        let header = match header {
+            Some(header) => header,
+            None => continue,
+        };
+        if header.version != handoff.versions[slot] {
+            handoff.versions[slot] = header.version;
+            handoff.attempts[slot] = 0;
+        }
+        let usable = match header.state {
+            State::Confirmed => true,
+            State::Pending if handoff.attempts[slot] < MAX_ATTEMPTS => true,
+            State::Pending => {
+                handoff.failed |= 1 << slot;
+                false
+            }
+            State::Rejected => false,
+        };
+        if usable
+            && selected.map_or(true, |other| {
+                headers[other].map_or(true, |other| header.is_newer_than(&other))
+            })
+        {
+            selected = Some(slot);
+        }
+    }
+    if let Some(slot) = selected {
+        if headers[slot].map(|header| header.state) == Some(State::Pending) {
+            handoff.attempts[slot] += 1;
+        }
+    }
+    handoff.slot = selected.unwrap_or(0) as u32;
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::cell::Cell;
+    use std::vec;
+    use std::vec::Vec;
+
+    const PAGE_SIZE: usize = 256;
+    const ADDRESS: usize = 0x10000;
+
+    fn header(version: u32, state: State) -> Header {
+        Header {
+            version: version,
+            length: 64,
+            crc: 0,
+            state: state,
+            signature_length: 0,
+        }
+    }
+
+    // A slot with an image whose reset handler is at `reset_handler`.
+    fn slot(version: u32, reset_handler: u32, signature: &[u8]) -> Vec<u8> {
+        let mut slot = vec![0xff; 4 * PAGE_SIZE];
+        let image: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        slot[PAGE_SIZE..PAGE_SIZE + image.len()].copy_from_slice(&image);
+        write_u32(&mut slot[PAGE_SIZE + 4..], reset_handler);
+        let header = Header {
+            version: version,
+            length: image.len() as u32,
+            crc: crc32(0, &slot[PAGE_SIZE..PAGE_SIZE + image.len()]),
+            state: State::Pending,
+            signature_length: signature.len(),
+        };
+        header.write(&mut slot, signature);
+        slot
+    }
+
+    struct FirstByte(Cell<Option<u8>>);
+
+    impl Verifier for FirstByte {
+        fn reset(&self) {
+            self.0.set(None);
+        }
+
+        fn update(&self, data: &[u8]) {
+            if self.0.get().is_none() {
+                self.0.set(data.first().cloned());
+            }
+        }
+
+        fn verify(&self, signature: &[u8]) -> bool {
+            self.0.get().map_or(false, |first| signature == [first])
+        }
+    }
+
+    #[test]
+    fn checks_slots() {
+        let verifier = FirstByte(Cell::new(None));
+        let reset_handler = (ADDRESS + PAGE_SIZE + 0x41) as u32;
+        let mut good = slot(7, reset_handler, &[0]);
+        let header = check_slot(&good, ADDRESS, PAGE_SIZE, Some(&verifier)).unwrap();
+        assert_eq!((header.version, header.length), (7, 200));
+        assert_eq!(header.state, State::Pending);
+        assert_eq!(header.signature(&good), [0]);
+
+        // The state can change without rewriting the header.
+        set_state(&mut good, State::Confirmed);
+        assert_eq!(
+            check_slot(&good, ADDRESS, PAGE_SIZE, None).map(|header| header.state),
+            Some(State::Confirmed)
+        );
+
+        let mut corrupt = good.clone();
+        corrupt[PAGE_SIZE + 100] ^= 1;
+        let mut torn_header = good.clone();
+        torn_header[8] ^= 1;
+        let elsewhere = slot(7, (ADDRESS + 0x41) as u32, &[]);
+        let forged = slot(7, reset_handler, &[1]);
+        for slot in [corrupt, torn_header, elsewhere, forged].iter() {
+            assert_eq!(check_slot(slot, ADDRESS, PAGE_SIZE, Some(&verifier)), None);
+        }
+        assert_eq!(check_slot(&[0xff; 1024], ADDRESS, PAGE_SIZE, None), None);
+    }
+
+    #[test]
+    fn selects_the_newest_image() {
+        let mut handoff = Handoff::new();
+        let confirmed = Some(header(1, State::Confirmed));
+        assert_eq!(select(&[None, None], &mut handoff), None);
+        assert!(handoff.is_valid());
+        assert_eq!(select(&[confirmed, None], &mut handoff), Some(0));
+        assert_eq!(
+            select(
+                &[confirmed, Some(header(2, State::Confirmed))],
+                &mut handoff
+            ),
+            Some(1)
+        );
+        assert_eq!(
+            select(&[confirmed, Some(header(2, State::Rejected))], &mut handoff),
+            Some(0)
+        );
+        // Versions wrap around.
+        assert_eq!(
+            select(
+                &[
+                    Some(header(0xffff_ffff, State::Confirmed)),
+                    Some(header(0, State::Pending))
+                ],
+                &mut handoff
+            ),
+            Some(1)
+        );
+        assert_eq!(handoff.slot, 1);
+    }
+
+    #[test]
+    fn gives_up_on_pending_images() {
+        let mut handoff = Handoff::new();
+        let headers = [
+            Some(header(1, State::Confirmed)),
+            Some(header(2, State::Pending)),
+        ];
+        for _ in 0..MAX_ATTEMPTS {
+            assert_eq!(select(&headers, &mut handoff), Some(1));
+            assert_eq!(handoff.failed, 0);
+        }
+        assert_eq!(select(&headers, &mut handoff), Some(0));
+        assert_eq!((handoff.slot, handoff.failed), (0, 2));
+
+        // A new image in the slot gets its own attempts.
+        let headers = [headers[0], Some(header(3, State::Pending))];
+        assert_eq!(select(&headers, &mut handoff), Some(1));
+        assert_eq!(handoff.failed, 0);
+    }
+}